@@ -1 +1,1306 @@
-// TODO: adbfs
+// adbfs: Exposes storage of an Android device through an adb server
+// NOTE: File transfers use the `sync:` service; operations the sync protocol
+//       lacks (mkdir, rm, mv, free space) fall back to `shell:` commands.
+//       STAT v2 and LIST v2 are used if the device supports them, which report
+//       64-bit sizes and actual errors.
+//       Reference: https://android.googlesource.com/platform/packages/modules/adb/+/refs/heads/main/SYNC.TXT
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use serde::{Deserialize, Serialize};
+use uuid::{uuid, Uuid};
+
+use super::{
+    FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
+    FileSystemError,
+};
+use crate::util::TempFile;
+
+pub const ADBFS_ID: Uuid = uuid!("5E2A7C41-0D38-4B6F-A1E9-C47F3B8D2E16");
+
+const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:5037";
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SYNC_DATA_MAX: usize = 64 * 1024;
+const SYNC_PATH_MAX: usize = 1024;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const DEFAULT_FILE_MODE: u32 = 0o644;
+
+// Errno values reported by STAT v2, as seen on the wire
+const WIRE_ENOENT: u32 = 2;
+const WIRE_EACCES: u32 = 13;
+const WIRE_ENOTDIR: u32 = 20;
+
+fn io_error_to_fs_error(e: std::io::Error) -> FileSystemError {
+    FileSystemError::Other(e.into())
+}
+
+fn protocol_error(what: &str) -> FileSystemError {
+    FileSystemError::Other(anyhow::anyhow!("adb protocol error: {what}"))
+}
+
+// NOTE: adbd only reports errno strings, so we do our best to translate them
+fn fail_message_to_fs_error(msg: String) -> FileSystemError {
+    if msg.contains("No such file or directory") {
+        FileSystemError::ObjectNameNotFound
+    } else if msg.contains("Permission denied") || msg.contains("Read-only file system") {
+        FileSystemError::AccessDenied
+    } else if msg.contains("Is a directory") {
+        FileSystemError::FileIsADirectory
+    } else if msg.contains("Not a directory") {
+        FileSystemError::NotADirectory
+    } else {
+        FileSystemError::Other(anyhow::anyhow!("adb request failed: {msg}"))
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn unix_time_to_system_time(t: u32) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(t as _)
+}
+
+fn system_time_to_unix_time(t: SystemTime) -> u32 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_secs().min(u32::MAX as _) as _)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AdbStat {
+    mode: u32,
+    size: u64,
+    mtime: u32,
+}
+
+impl AdbStat {
+    // NOTE: STAT (v1) replies with all zeros if lstat fails on device, which
+    //       is also used for missing files with STAT v2
+    const MISSING: AdbStat = AdbStat {
+        mode: 0,
+        size: 0,
+        mtime: 0,
+    };
+    fn exists(&self) -> bool {
+        self.mode != 0
+    }
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+    fn to_file_stat_info(&self, path: &str) -> super::FileStatInfo {
+        let mtime = unix_time_to_system_time(self.mtime);
+        super::FileStatInfo {
+            index: crate::util::calculate_hash(&path),
+            size: if self.is_dir() { 0 } else { self.size as _ },
+            is_dir: self.is_dir(),
+            attributes: FileAttributes::empty(),
+            creation_time: mtime,
+            last_access_time: mtime,
+            last_write_time: mtime,
+        }
+    }
+}
+
+// Optional protocol features of the device
+#[derive(Debug, Clone, Copy, Default)]
+struct AdbFeatures {
+    stat_v2: bool,
+    ls_v2: bool,
+}
+
+impl AdbFeatures {
+    fn query(server_addr: &str, serial: Option<&str>) -> super::FileSystemResult<Self> {
+        let mut conn = AdbConnection::connect(server_addr)?;
+        match serial {
+            Some(serial) => conn.send_host_request(&format!("host-serial:{serial}:features"))?,
+            None => conn.send_host_request("host:features")?,
+        }
+        let features = conn.read_host_string()?;
+        let features = String::from_utf8_lossy(&features);
+        let has_feature = |name| features.split(',').any(|x| x == name);
+        Ok(AdbFeatures {
+            stat_v2: has_feature("stat_v2"),
+            ls_v2: has_feature("ls_v2"),
+        })
+    }
+}
+
+// A connection to the adb server, routed to a device
+struct AdbConnection {
+    stream: TcpStream,
+}
+
+impl AdbConnection {
+    // Connects to the adb server, without selecting a device
+    fn connect(server_addr: &str) -> super::FileSystemResult<Self> {
+        let stream = TcpStream::connect(server_addr).map_err(io_error_to_fs_error)?;
+        stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .map_err(io_error_to_fs_error)?;
+        stream
+            .set_write_timeout(Some(IO_TIMEOUT))
+            .map_err(io_error_to_fs_error)?;
+        Ok(AdbConnection { stream })
+    }
+    fn open(server_addr: &str, serial: Option<&str>) -> super::FileSystemResult<Self> {
+        let mut conn = AdbConnection::connect(server_addr)?;
+        match serial {
+            Some(serial) => conn.send_host_request(&format!("host:transport:{serial}"))?,
+            None => conn.send_host_request("host:transport-any")?,
+        }
+        Ok(conn)
+    }
+    fn send_host_request(&mut self, request: &str) -> super::FileSystemResult<()> {
+        if request.len() > 0xffff {
+            return Err(FileSystemError::InvalidParameter);
+        }
+        let request = format!("{:04x}{request}", request.len());
+        self.stream
+            .write_all(request.as_bytes())
+            .map_err(io_error_to_fs_error)?;
+        self.read_host_status()
+    }
+    fn read_host_status(&mut self) -> super::FileSystemResult<()> {
+        let mut id = [0; 4];
+        self.stream
+            .read_exact(&mut id)
+            .map_err(io_error_to_fs_error)?;
+        match &id {
+            b"OKAY" => Ok(()),
+            b"FAIL" => {
+                let msg = self.read_host_string()?;
+                Err(fail_message_to_fs_error(
+                    String::from_utf8_lossy(&msg).into_owned(),
+                ))
+            }
+            _ => Err(protocol_error("unexpected host status")),
+        }
+    }
+    // Reads a string prefixed with its length in hex
+    fn read_host_string(&mut self) -> super::FileSystemResult<Vec<u8>> {
+        let mut len = [0; 4];
+        self.stream
+            .read_exact(&mut len)
+            .map_err(io_error_to_fs_error)?;
+        let len = std::str::from_utf8(&len)
+            .ok()
+            .and_then(|x| usize::from_str_radix(x, 16).ok())
+            .ok_or_else(|| protocol_error("invalid string length"))?;
+        let mut data = vec![0; len];
+        self.stream
+            .read_exact(&mut data)
+            .map_err(io_error_to_fs_error)?;
+        Ok(data)
+    }
+}
+
+// A connection in sync mode; must be discarded after any error
+struct AdbSyncConnection {
+    conn: AdbConnection,
+    features: AdbFeatures,
+}
+
+impl AdbSyncConnection {
+    fn open(
+        server_addr: &str,
+        serial: Option<&str>,
+        features: AdbFeatures,
+    ) -> super::FileSystemResult<Self> {
+        let mut conn = AdbConnection::open(server_addr, serial)?;
+        conn.send_host_request("sync:")?;
+        Ok(AdbSyncConnection { conn, features })
+    }
+    fn send_request(&mut self, id: &[u8; 4], data: &[u8]) -> super::FileSystemResult<()> {
+        let stream = &mut self.conn.stream;
+        let mut buf = Vec::with_capacity(8 + data.len());
+        buf.extend_from_slice(id);
+        buf.write_u32::<LE>(data.len() as _).unwrap();
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).map_err(io_error_to_fs_error)
+    }
+    fn send_path_request(&mut self, id: &[u8; 4], path: &str) -> super::FileSystemResult<()> {
+        if path.len() > SYNC_PATH_MAX {
+            return Err(FileSystemError::ObjectNameInvalid);
+        }
+        self.send_request(id, path.as_bytes())
+    }
+    fn read_header(&mut self) -> super::FileSystemResult<([u8; 4], u32)> {
+        let stream = &mut self.conn.stream;
+        let mut id = [0; 4];
+        stream.read_exact(&mut id).map_err(io_error_to_fs_error)?;
+        let arg = stream.read_u32::<LE>().map_err(io_error_to_fs_error)?;
+        Ok((id, arg))
+    }
+    fn read_fail_message(&mut self, len: u32) -> super::FileSystemError {
+        let mut msg = vec![0; len as usize];
+        if let Err(e) = self.conn.stream.read_exact(&mut msg) {
+            return io_error_to_fs_error(e);
+        }
+        fail_message_to_fs_error(String::from_utf8_lossy(&msg).into_owned())
+    }
+    fn read_stat_body(&mut self) -> super::FileSystemResult<AdbStat> {
+        let stream = &mut self.conn.stream;
+        let mut read_fn = || -> std::io::Result<AdbStat> {
+            Ok(AdbStat {
+                mode: stream.read_u32::<LE>()?,
+                size: stream.read_u32::<LE>()? as _,
+                mtime: stream.read_u32::<LE>()?,
+            })
+        };
+        read_fn().map_err(io_error_to_fs_error)
+    }
+    // Reads the error and stat of a STAT v2 reply or LIST v2 entry
+    fn read_stat_v2_body(&mut self) -> super::FileSystemResult<(u32, AdbStat)> {
+        let stream = &mut self.conn.stream;
+        let mut read_fn = || -> std::io::Result<(u32, AdbStat)> {
+            let error = stream.read_u32::<LE>()?;
+            let _dev = stream.read_u64::<LE>()?;
+            let _ino = stream.read_u64::<LE>()?;
+            let mode = stream.read_u32::<LE>()?;
+            let _nlink = stream.read_u32::<LE>()?;
+            let _uid = stream.read_u32::<LE>()?;
+            let _gid = stream.read_u32::<LE>()?;
+            let size = stream.read_u64::<LE>()?;
+            let _atime = stream.read_i64::<LE>()?;
+            let mtime = stream.read_i64::<LE>()?;
+            let _ctime = stream.read_i64::<LE>()?;
+            let mtime = mtime.clamp(0, u32::MAX as _) as _;
+            Ok((error, AdbStat { mode, size, mtime }))
+        };
+        read_fn().map_err(io_error_to_fs_error)
+    }
+    fn stat(&mut self, path: &str) -> super::FileSystemResult<AdbStat> {
+        // NOTE: LST2 rather than STA2, as STAT (v1) doesn't follow symlinks either
+        let id = if self.features.stat_v2 {
+            b"LST2"
+        } else {
+            b"STAT"
+        };
+        self.send_path_request(id, path)?;
+        let mut reply_id = [0; 4];
+        self.conn
+            .stream
+            .read_exact(&mut reply_id)
+            .map_err(io_error_to_fs_error)?;
+        if &reply_id != id {
+            return Err(protocol_error("unexpected STAT reply"));
+        }
+        if !self.features.stat_v2 {
+            return self.read_stat_body();
+        }
+        match self.read_stat_v2_body()? {
+            (0, stat) => Ok(stat),
+            (WIRE_ENOENT | WIRE_ENOTDIR, _) => Ok(AdbStat::MISSING),
+            (WIRE_EACCES, _) => Err(FileSystemError::AccessDenied),
+            (error, _) => Err(FileSystemError::Other(anyhow::anyhow!(
+                "adb stat failed with errno {error}"
+            ))),
+        }
+    }
+    fn list(
+        &mut self,
+        path: &str,
+        mut entry_fn: impl FnMut(&str, &AdbStat),
+    ) -> super::FileSystemResult<()> {
+        let is_v2 = self.features.ls_v2;
+        let (id, entry_id) = if is_v2 {
+            (b"LIS2", b"DNT2")
+        } else {
+            (b"LIST", b"DENT")
+        };
+        self.send_path_request(id, path)?;
+        loop {
+            let mut id = [0; 4];
+            self.conn
+                .stream
+                .read_exact(&mut id)
+                .map_err(io_error_to_fs_error)?;
+            // NOTE: Entries which failed lstat on device are skipped, as LIST (v1)
+            //       leaves them out as well
+            let (error, stat) = if is_v2 {
+                self.read_stat_v2_body()?
+            } else {
+                (0, self.read_stat_body()?)
+            };
+            let name_len = self
+                .conn
+                .stream
+                .read_u32::<LE>()
+                .map_err(io_error_to_fs_error)?;
+            match &id {
+                _ if &id == entry_id => {
+                    if name_len as usize > SYNC_PATH_MAX {
+                        return Err(protocol_error("oversized entry name"));
+                    }
+                    let mut name = vec![0; name_len as usize];
+                    self.conn
+                        .stream
+                        .read_exact(&mut name)
+                        .map_err(io_error_to_fs_error)?;
+                    let name = String::from_utf8_lossy(&name);
+                    if name != "." && name != ".." && error == 0 {
+                        entry_fn(&name, &stat);
+                    }
+                }
+                b"DONE" => return Ok(()),
+                _ => return Err(protocol_error("unexpected LIST reply")),
+            }
+        }
+    }
+    // Receives the file in chunks, which are passed to `data_fn` in order
+    fn recv(
+        &mut self,
+        path: &str,
+        mut data_fn: impl FnMut(&[u8]) -> super::FileSystemResult<()>,
+    ) -> super::FileSystemResult<()> {
+        self.send_path_request(b"RECV", path)?;
+        let mut buf = vec![0; SYNC_DATA_MAX];
+        loop {
+            let (id, len) = self.read_header()?;
+            match &id {
+                b"DATA" => {
+                    if len as usize > SYNC_DATA_MAX {
+                        return Err(protocol_error("oversized DATA chunk"));
+                    }
+                    let chunk = &mut buf[..len as usize];
+                    self.conn
+                        .stream
+                        .read_exact(chunk)
+                        .map_err(io_error_to_fs_error)?;
+                    data_fn(chunk)?;
+                }
+                b"DONE" => return Ok(()),
+                b"FAIL" => return Err(self.read_fail_message(len)),
+                _ => return Err(protocol_error("unexpected RECV reply")),
+            }
+        }
+    }
+    // Sends the file, whose data is read by `read_fn` from the given offset until
+    // it returns 0
+    fn send(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u32,
+        mut read_fn: impl FnMut(u64, &mut [u8]) -> super::FileSystemResult<usize>,
+    ) -> super::FileSystemResult<()> {
+        let path_and_mode = format!("{path},{mode}");
+        self.send_path_request(b"SEND", &path_and_mode)?;
+        let mut buf = vec![0; SYNC_DATA_MAX];
+        let mut pos = 0;
+        loop {
+            let len = read_fn(pos, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            self.send_request(b"DATA", &buf[..len])?;
+            pos += len as u64;
+        }
+        let mut done = Vec::with_capacity(8);
+        done.extend_from_slice(b"DONE");
+        done.write_u32::<LE>(mtime).unwrap();
+        self.conn
+            .stream
+            .write_all(&done)
+            .map_err(io_error_to_fs_error)?;
+        let (id, len) = self.read_header()?;
+        match &id {
+            b"OKAY" => Ok(()),
+            b"FAIL" => Err(self.read_fail_message(len)),
+            _ => Err(protocol_error("unexpected SEND reply")),
+        }
+    }
+}
+
+pub struct AdbFsHandler {
+    server_addr: String,
+    serial: Option<String>,
+    // NOTE: Absolute device path without trailing slash (empty for `/`)
+    root_path: String,
+    features: AdbFeatures,
+    idle_conns: Mutex<Vec<AdbSyncConnection>>,
+}
+
+impl AdbFsHandler {
+    fn with_sync<R>(
+        &self,
+        op: impl FnOnce(&mut AdbSyncConnection) -> super::FileSystemResult<R>,
+    ) -> super::FileSystemResult<R> {
+        let idle_conn = self.idle_conns.lock().unwrap().pop();
+        let mut conn = match idle_conn {
+            Some(conn) => conn,
+            None => {
+                AdbSyncConnection::open(&self.server_addr, self.serial.as_deref(), self.features)?
+            }
+        };
+        let result = op(&mut conn);
+        // NOTE: adbd closes the sync session after a failure, so only
+        //       successful connections are kept
+        if result.is_ok() {
+            self.idle_conns.lock().unwrap().push(conn);
+        }
+        result
+    }
+    fn run_shell(&self, command: &str) -> super::FileSystemResult<String> {
+        let mut conn = AdbConnection::open(&self.server_addr, self.serial.as_deref())?;
+        conn.send_host_request(&format!("shell:{command}"))?;
+        let mut output = Vec::new();
+        conn.stream
+            .read_to_end(&mut output)
+            .map_err(io_error_to_fs_error)?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+    fn to_device_path(&self, path: super::SegPath) -> super::FileSystemResult<String> {
+        let mut device_path = self.root_path.clone();
+        for segment in path.into_iter().filter(|x| !x.is_empty()) {
+            if segment == "." || segment == ".." || segment.contains(['/', '\\']) {
+                return Err(FileSystemError::ObjectNameInvalid);
+            }
+            device_path.push('/');
+            device_path.push_str(segment);
+        }
+        if device_path.is_empty() {
+            device_path.push('/');
+        }
+        Ok(device_path)
+    }
+    fn get_root_path(&self) -> &str {
+        if self.root_path.is_empty() {
+            "/"
+        } else {
+            &self.root_path
+        }
+    }
+    fn stat_path(&self, path: &str) -> super::FileSystemResult<Option<AdbStat>> {
+        let stat = self.with_sync(|conn| conn.stat(path))?;
+        Ok(stat.exists().then_some(stat))
+    }
+    fn parent_path(path: &str) -> &str {
+        match path.rsplit_once('/') {
+            Some(("", _)) | None => "/",
+            Some((parent, _)) => parent,
+        }
+    }
+    fn make_dir(&self, path: &str) -> super::FileSystemResult<()> {
+        self.run_shell(&format!("mkdir {}", shell_quote(path)))?;
+        match self.stat_path(path)? {
+            Some(stat) if stat.is_dir() => Ok(()),
+            _ => Err(FileSystemError::AccessDenied),
+        }
+    }
+    fn remove(&self, path: &str, is_dir: bool) -> super::FileSystemResult<()> {
+        let command = if is_dir { "rmdir" } else { "rm -f" };
+        self.run_shell(&format!("{command} {}", shell_quote(path)))?;
+        if self.stat_path(path)?.is_some() {
+            return Err(FileSystemError::CannotDelete);
+        }
+        Ok(())
+    }
+}
+
+impl super::FileSystemHandler for AdbFsHandler {
+    fn create_file(
+        &self,
+        filename: super::SegPath,
+        desired_access: FileDesiredAccess,
+        file_attributes: FileAttributes,
+        share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<super::CreateFileInfo<'_>> {
+        use FileCreateDisposition::*;
+
+        let expects_dir = create_options.contains(FileCreateOptions::DirectoryFile);
+        let expects_nondir = create_options.contains(FileCreateOptions::NonDirectoryFile);
+        let delete_on_close = create_options.contains(FileCreateOptions::DeleteOnClose);
+
+        let path = self.to_device_path(filename)?;
+        let mut new_file_created = false;
+        let mut contents = AdbFileContents::default();
+
+        let stat = if let Some(stat) = self.stat_path(&path)? {
+            if expects_dir && !stat.is_dir() {
+                return Err(FileSystemError::NotADirectory);
+            }
+            if expects_nondir && stat.is_dir() {
+                return Err(FileSystemError::FileIsADirectory);
+            }
+            match create_disposition {
+                CreateNew => return Err(FileSystemError::ObjectNameCollision),
+                CreateAlways | TruncateExisting if !stat.is_dir() => {
+                    self.with_sync(|conn| {
+                        conn.send(
+                            &path,
+                            stat.mode & 0o7777,
+                            system_time_to_unix_time(SystemTime::now()),
+                            |_, _| Ok(0),
+                        )
+                    })?;
+                    contents.data = Some(AdbFileData::new()?);
+                }
+                _ => {}
+            }
+            stat
+        } else {
+            match create_disposition {
+                CreateAlways | CreateNew | OpenAlways => {}
+                OpenExisting | TruncateExisting => {
+                    let parent = Self::parent_path(&path);
+                    return Err(match self.stat_path(parent)? {
+                        Some(stat) if stat.is_dir() => FileSystemError::ObjectNameNotFound,
+                        _ => FileSystemError::ObjectPathNotFound,
+                    });
+                }
+            }
+            // NOTE: SEND creates missing parent folders, which we don't want
+            match self.stat_path(Self::parent_path(&path))? {
+                Some(stat) if stat.is_dir() => {}
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            }
+            if expects_dir {
+                self.make_dir(&path)?;
+            } else {
+                self.with_sync(|conn| {
+                    conn.send(
+                        &path,
+                        DEFAULT_FILE_MODE,
+                        system_time_to_unix_time(SystemTime::now()),
+                        |_, _| Ok(0),
+                    )
+                })?;
+                contents.data = Some(AdbFileData::new()?);
+            }
+            new_file_created = true;
+            self.stat_path(&path)?
+                .ok_or(FileSystemError::ObjectNameNotFound)?
+        };
+
+        if delete_on_close && path == self.get_root_path() {
+            return Err(FileSystemError::AccessDenied);
+        }
+
+        Ok(super::CreateFileInfo {
+            context: Box::new(AdbFsFile {
+                fs_handler: self,
+                path: RwLock::new(path),
+                is_dir: stat.is_dir(),
+                mode: stat.mode & 0o7777,
+                can_write: desired_access
+                    .intersects(FileDesiredAccess::Write | FileDesiredAccess::Full),
+                contents: Mutex::new(contents),
+                delete_on_close: AtomicBool::new(delete_on_close),
+            }),
+            is_dir: stat.is_dir(),
+            new_file_created,
+        })
+    }
+    fn get_fs_free_space(&self) -> super::FileSystemResult<super::FileSystemSpaceInfo> {
+        // Fundamental block size, total blocks, free blocks, available blocks
+        let output = self.run_shell(&format!(
+            "stat -f -c '%S %b %f %a' {}",
+            shell_quote(self.get_root_path())
+        ))?;
+        let values = output
+            .split_whitespace()
+            .map(|x| x.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|x| x.len() == 4)
+            .ok_or_else(|| protocol_error("unexpected output of `stat -f`"))?;
+        Ok(super::FileSystemSpaceInfo {
+            bytes_count: values[0] * values[1],
+            free_bytes_count: values[0] * values[2],
+            available_bytes_count: values[0] * values[3],
+        })
+    }
+    fn get_fs_characteristics(&self) -> super::FileSystemResult<super::FileSystemCharacteristics> {
+        Ok(super::FileSystemCharacteristics::CaseSensitive)
+    }
+}
+
+// File data staged in a temporary file, as it may be too large to keep in memory
+struct AdbFileData {
+    file: TempFile,
+    size: u64,
+}
+
+impl AdbFileData {
+    fn new() -> super::FileSystemResult<Self> {
+        Ok(AdbFileData {
+            file: TempFile::new().map_err(io_error_to_fs_error)?,
+            size: 0,
+        })
+    }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> super::FileSystemResult<usize> {
+        let len = buffer.len().min(self.size.saturating_sub(offset) as _);
+        self.file
+            .read_at(offset, &mut buffer[..len])
+            .map_err(io_error_to_fs_error)
+    }
+    // NOTE: Writing past the end also clears the hole between old data and new data
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> super::FileSystemResult<()> {
+        let end = offset
+            .checked_add(buffer.len() as _)
+            .ok_or(FileSystemError::InvalidParameter)?;
+        self.file
+            .write_all_at(offset, buffer)
+            .map_err(io_error_to_fs_error)?;
+        self.size = self.size.max(end);
+        Ok(())
+    }
+    fn set_len(&mut self, len: u64) -> super::FileSystemResult<()> {
+        self.file.set_len(len).map_err(io_error_to_fs_error)?;
+        self.size = len;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct AdbFileContents {
+    // NOTE: Fetched lazily as RECV always transfers the whole file
+    data: Option<AdbFileData>,
+    // Whether data must be written back with SEND
+    is_dirty: bool,
+    mtime: Option<u32>,
+}
+
+struct AdbFsFile<'h> {
+    fs_handler: &'h AdbFsHandler,
+    path: RwLock<String>,
+    is_dir: bool,
+    mode: u32,
+    can_write: bool,
+    contents: Mutex<AdbFileContents>,
+    delete_on_close: AtomicBool,
+}
+
+impl AdbFsFile<'_> {
+    fn load<'c>(
+        &self,
+        path: &str,
+        contents: &'c mut AdbFileContents,
+    ) -> super::FileSystemResult<&'c mut AdbFileData> {
+        if self.is_dir {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        if contents.data.is_none() {
+            let mut data = AdbFileData::new()?;
+            self.fs_handler
+                .with_sync(|conn| conn.recv(path, |chunk| data.write_at(data.size, chunk)))?;
+            contents.data = Some(data);
+        }
+        Ok(contents.data.as_mut().unwrap())
+    }
+    fn write_back(
+        &self,
+        path: &str,
+        contents: &mut AdbFileContents,
+    ) -> super::FileSystemResult<()> {
+        if !contents.is_dirty {
+            return Ok(());
+        }
+        let data = contents.data.as_ref();
+        let mtime = contents
+            .mtime
+            .unwrap_or_else(|| system_time_to_unix_time(SystemTime::now()));
+        self.fs_handler.with_sync(|conn| {
+            conn.send(path, self.mode, mtime, |offset, buffer| match data {
+                Some(data) => data.read_at(offset, buffer),
+                None => Ok(0),
+            })
+        })?;
+        contents.is_dirty = false;
+        contents.mtime = None;
+        Ok(())
+    }
+}
+
+impl super::File for AdbFsFile<'_> {
+    fn get_path(&self) -> Option<String> {
+        Some(self.path.read().unwrap().clone())
+    }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> super::FileSystemResult<u64> {
+        let path = self.path.read().unwrap();
+        let mut contents = self.contents.lock().unwrap();
+        let data = self.load(&path, &mut contents)?;
+        Ok(data.read_at(offset, buffer)? as _)
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> super::FileSystemResult<u64> {
+        if !self.can_write {
+            return Err(FileSystemError::AccessDenied);
+        }
+        let path = self.path.read().unwrap();
+        let mut contents = self.contents.lock().unwrap();
+        let data = self.load(&path, &mut contents)?;
+        let offset = offset.unwrap_or(data.size);
+        let real_len = if constrain_size {
+            buffer.len().min(data.size.saturating_sub(offset) as _)
+        } else {
+            buffer.len()
+        };
+        if real_len == 0 {
+            return Ok(0);
+        }
+        data.write_at(offset, &buffer[..real_len])?;
+        contents.is_dirty = true;
+        Ok(real_len as _)
+    }
+    fn flush_buffers(&self) -> super::FileSystemResult<()> {
+        let path = self.path.read().unwrap();
+        let mut contents = self.contents.lock().unwrap();
+        self.write_back(&path, &mut contents)
+    }
+    fn get_stat(&self) -> super::FileSystemResult<super::FileStatInfo> {
+        let path = self.path.read().unwrap();
+        let stat = self
+            .fs_handler
+            .stat_path(&path)?
+            .ok_or(FileSystemError::ObjectNameNotFound)?;
+        let mut stat_info = stat.to_file_stat_info(&path);
+        let contents = self.contents.lock().unwrap();
+        if contents.is_dirty {
+            stat_info.size = contents.data.as_ref().map(|x| x.size).unwrap_or(0);
+        }
+        if let Some(mtime) = contents.mtime {
+            stat_info.last_write_time = unix_time_to_system_time(mtime);
+        }
+        Ok(stat_info)
+    }
+    fn set_end_of_file(&self, offset: u64) -> super::FileSystemResult<()> {
+        if !self.can_write {
+            return Err(FileSystemError::AccessDenied);
+        }
+        let path = self.path.read().unwrap();
+        let mut contents = self.contents.lock().unwrap();
+        let data = self.load(&path, &mut contents)?;
+        data.set_len(offset)?;
+        contents.is_dirty = true;
+        Ok(())
+    }
+    fn set_file_times(
+        &self,
+        creation_time: SystemTime,
+        last_access_time: SystemTime,
+        last_write_time: SystemTime,
+    ) -> super::FileSystemResult<()> {
+        // NOTE: Only the modification time of pending writes can be set, as
+        //       the sync protocol transfers it along with the file contents
+        let zero_t = unsafe { std::mem::zeroed() };
+        if last_write_time != zero_t {
+            let mut contents = self.contents.lock().unwrap();
+            if contents.is_dirty {
+                contents.mtime = Some(system_time_to_unix_time(last_write_time));
+            } else {
+                log::trace!("Ignoring file times of unmodified file");
+            }
+        }
+        Ok(())
+    }
+    fn set_delete(&self, delete_on_close: bool) -> super::FileSystemResult<()> {
+        if delete_on_close {
+            let path = self.path.read().unwrap();
+            if *path == self.fs_handler.get_root_path() {
+                return Err(FileSystemError::AccessDenied);
+            }
+            if self.is_dir {
+                let mut is_empty = true;
+                self.fs_handler
+                    .with_sync(|conn| conn.list(&path, |_, _| is_empty = false))?;
+                if !is_empty {
+                    return Err(FileSystemError::DirectoryNotEmpty);
+                }
+            }
+        }
+        self.delete_on_close
+            .store(delete_on_close, Ordering::Release);
+        Ok(())
+    }
+    fn move_to(
+        &self,
+        new_path: super::SegPath,
+        replace_if_exists: bool,
+    ) -> super::FileSystemResult<()> {
+        let fs = self.fs_handler;
+        let new_path = fs.to_device_path(new_path)?;
+        let mut path = self.path.write().unwrap();
+        let mut contents = self.contents.lock().unwrap();
+        self.write_back(&path, &mut contents)?;
+        if let Some(stat) = fs.stat_path(&new_path)? {
+            if !replace_if_exists {
+                return Err(FileSystemError::ObjectNameCollision);
+            }
+            if stat.is_dir() {
+                return Err(FileSystemError::AccessDenied);
+            }
+        }
+        fs.run_shell(&format!(
+            "mv {} {}",
+            shell_quote(&path),
+            shell_quote(&new_path)
+        ))?;
+        if fs.stat_path(&new_path)?.is_none() {
+            return Err(FileSystemError::AccessDenied);
+        }
+        *path = new_path;
+        Ok(())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn super::FilePattern,
+        filler: &mut dyn super::FindFilesDataFiller,
+    ) -> super::FileSystemResult<()> {
+        if !self.is_dir {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let path = self.path.read().unwrap();
+        self.fs_handler.with_sync(|conn| {
+            conn.list(&path, |name, stat| {
+                if !pattern.check_name(name) {
+                    return;
+                }
+                let child_path = format!("{}/{name}", path.trim_end_matches('/'));
+                if filler
+                    .fill_data(name, &stat.to_file_stat_info(&child_path))
+                    .is_err()
+                {
+                    log::warn!("Failed to fill object data");
+                }
+            })
+        })
+    }
+}
+
+impl Drop for AdbFsFile<'_> {
+    fn drop(&mut self) {
+        let path = std::mem::take(self.path.get_mut().unwrap());
+        let mut contents = std::mem::take(self.contents.get_mut().unwrap());
+        if self.delete_on_close.load(Ordering::Acquire) {
+            if let Err(e) = self.fs_handler.remove(&path, self.is_dir) {
+                log::warn!("Failed to delete `{path}`: {e}");
+            }
+            return;
+        }
+        if let Err(e) = self.write_back(&path, &mut contents) {
+            log::warn!("Failed to write back `{path}`: {e}");
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AdbFsConfig {
+    /// Address of the adb server.
+    server_address: String,
+    /// Serial of the target device; uses the only connected device if absent.
+    serial: Option<String>,
+    /// Absolute path on device to be exposed as root.
+    root_path: String,
+}
+
+pub struct AdbFsProvider {}
+impl super::FsProvider for AdbFsProvider {
+    fn get_id(&self) -> Uuid {
+        ADBFS_ID
+    }
+    fn get_name(&self) -> &'static str {
+        "adbfs"
+    }
+    fn get_version(&self) -> (u32, u32, u32) {
+        (0, 1, 0)
+    }
+    fn construct(
+        &self,
+        config: serde_json::Value,
+        ctx: &mut dyn super::FileSystemCreationContext,
+    ) -> Result<Arc<dyn super::FileSystemHandler>, super::FileSystemCreationError> {
+        let config: AdbFsConfig = serde_json::from_value(config)
+            .map_err(|e| super::FileSystemCreationError::Other(e.into()))?;
+        if !config.root_path.starts_with('/') {
+            return Err(super::FileSystemCreationError::InvalidConfig(
+                "root path must be absolute".to_owned(),
+            ));
+        }
+        // NOTE: Servers too old to report features don't know STAT v2 either
+        let features = AdbFeatures::query(&config.server_address, config.serial.as_deref())
+            .unwrap_or_else(|e| {
+                log::debug!("adb: failed to query device features: {e}");
+                AdbFeatures::default()
+            });
+        let handler = AdbFsHandler {
+            server_addr: config.server_address,
+            serial: config.serial,
+            root_path: config.root_path.trim_end_matches('/').to_owned(),
+            features,
+            idle_conns: Mutex::new(Vec::new()),
+        };
+        // Fail early if the device is unreachable
+        match handler.stat_path(handler.get_root_path()) {
+            Ok(Some(stat)) if stat.is_dir() => {}
+            Ok(_) => {
+                return Err(super::FileSystemCreationError::InvalidConfig(
+                    "root path is not a directory".to_owned(),
+                ))
+            }
+            Err(e) => return Err(super::FileSystemCreationError::Other(e.into())),
+        }
+        Ok(Arc::new(handler))
+    }
+    fn get_template_config(&self) -> serde_json::Value {
+        serde_json::to_value(AdbFsConfig {
+            server_address: DEFAULT_SERVER_ADDRESS.to_owned(),
+            serial: None,
+            root_path: "/sdcard".to_owned(),
+        })
+        .unwrap()
+    }
+}
+
+impl AdbFsProvider {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::TcpListener};
+
+    use super::*;
+    use crate::fs_provider::{
+        AcceptAllFilePattern, FileStatInfo, FileSystemHandler, FindFilesDataFiller, FsProvider,
+        PathDelimiter, SegPath,
+    };
+
+    struct FakeEntry {
+        mode: u32,
+        data: Vec<u8>,
+        mtime: u32,
+    }
+
+    // Files of the fake device along with the sync requests it received
+    #[derive(Default)]
+    struct FakeDevice {
+        entries: BTreeMap<String, FakeEntry>,
+        requests: Vec<String>,
+    }
+
+    // Serves the sync service of a device backed by memory, like an adb server
+    // with exactly one device connected would
+    fn start_fake_server(features: &'static str) -> (String, Arc<Mutex<FakeDevice>>) {
+        let mut device = FakeDevice::default();
+        for (path, mode) in [("/sdcard", 0o40771), ("/sdcard/sub", 0o40771)] {
+            let entry = FakeEntry {
+                mode,
+                data: Vec::new(),
+                mtime: 1_600_000_000,
+            };
+            device.entries.insert(path.to_owned(), entry);
+        }
+        let entry = FakeEntry {
+            mode: 0o100660,
+            data: big_data(),
+            mtime: 1_600_000_000,
+        };
+        device.entries.insert("/sdcard/big.bin".to_owned(), entry);
+        let device = Arc::new(Mutex::new(device));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_device = Arc::clone(&device);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let device = Arc::clone(&server_device);
+                std::thread::spawn(move || serve(stream?, features, &device));
+            }
+            std::io::Result::Ok(())
+        });
+        (addr, device)
+    }
+
+    fn big_data() -> Vec<u8> {
+        (0..200_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect()
+    }
+
+    fn serve(
+        mut stream: TcpStream,
+        features: &str,
+        device: &Mutex<FakeDevice>,
+    ) -> std::io::Result<()> {
+        loop {
+            let mut len = [0; 4];
+            stream.read_exact(&mut len)?;
+            let len = usize::from_str_radix(std::str::from_utf8(&len).unwrap(), 16).unwrap();
+            let mut request = vec![0; len];
+            stream.read_exact(&mut request)?;
+            match &request[..] {
+                b"host:features" => {
+                    write!(stream, "OKAY{:04x}{features}", features.len())?;
+                    return Ok(());
+                }
+                b"host:transport-any" => stream.write_all(b"OKAY")?,
+                b"sync:" => {
+                    stream.write_all(b"OKAY")?;
+                    return serve_sync(stream, device);
+                }
+                _ => {
+                    let msg = "unknown host service";
+                    write!(stream, "FAIL{:04x}{msg}", msg.len())?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn write_stat(stream: &mut TcpStream, entry: Option<&FakeEntry>) -> std::io::Result<()> {
+        let (mode, size, mtime) = entry.map_or((0, 0, 0), |x| (x.mode, x.data.len(), x.mtime));
+        stream.write_u32::<LE>(mode)?;
+        stream.write_u32::<LE>(size as _)?;
+        stream.write_u32::<LE>(mtime)
+    }
+
+    fn write_stat_v2(stream: &mut TcpStream, entry: Option<&FakeEntry>) -> std::io::Result<()> {
+        stream.write_u32::<LE>(if entry.is_some() { 0 } else { WIRE_ENOENT })?;
+        let (mode, size, mtime) = entry.map_or((0, 0, 0), |x| (x.mode, x.data.len(), x.mtime));
+        // Device and inode
+        stream.write_u64::<LE>(0)?;
+        stream.write_u64::<LE>(0)?;
+        stream.write_u32::<LE>(mode)?;
+        // Links, owner and group
+        stream.write_u32::<LE>(1)?;
+        stream.write_u32::<LE>(0)?;
+        stream.write_u32::<LE>(0)?;
+        stream.write_u64::<LE>(size as _)?;
+        for _ in 0..3 {
+            stream.write_i64::<LE>(mtime as _)?;
+        }
+        Ok(())
+    }
+
+    fn serve_sync(mut stream: TcpStream, device: &Mutex<FakeDevice>) -> std::io::Result<()> {
+        loop {
+            let mut id = [0; 4];
+            if stream.read_exact(&mut id).is_err() {
+                return Ok(());
+            }
+            let len = stream.read_u32::<LE>()?;
+            let mut arg = vec![0; len as usize];
+            stream.read_exact(&mut arg)?;
+            let arg = String::from_utf8(arg).unwrap();
+            let mut device = device.lock().unwrap();
+            device
+                .requests
+                .push(String::from_utf8_lossy(&id).into_owned());
+            match &id {
+                b"STAT" => {
+                    stream.write_all(b"STAT")?;
+                    write_stat(&mut stream, device.entries.get(&arg))?;
+                }
+                b"LST2" => {
+                    stream.write_all(b"LST2")?;
+                    write_stat_v2(&mut stream, device.entries.get(&arg))?;
+                }
+                b"LIST" | b"LIS2" => {
+                    let is_v2 = &id == b"LIS2";
+                    let prefix = format!("{arg}/");
+                    for (path, entry) in &device.entries {
+                        let Some(name) = path.strip_prefix(&prefix) else {
+                            continue;
+                        };
+                        if name.contains('/') {
+                            continue;
+                        }
+                        if is_v2 {
+                            stream.write_all(b"DNT2")?;
+                            write_stat_v2(&mut stream, Some(entry))?;
+                        } else {
+                            stream.write_all(b"DENT")?;
+                            write_stat(&mut stream, Some(entry))?;
+                        }
+                        stream.write_u32::<LE>(name.len() as _)?;
+                        stream.write_all(name.as_bytes())?;
+                    }
+                    stream.write_all(b"DONE")?;
+                    if is_v2 {
+                        stream.write_all(&[0; 68])?;
+                    } else {
+                        stream.write_all(&[0; 12])?;
+                    }
+                    stream.write_u32::<LE>(0)?;
+                }
+                b"RECV" => match device.entries.get(&arg) {
+                    Some(entry) => {
+                        for chunk in entry.data.chunks(SYNC_DATA_MAX) {
+                            stream.write_all(b"DATA")?;
+                            stream.write_u32::<LE>(chunk.len() as _)?;
+                            stream.write_all(chunk)?;
+                        }
+                        stream.write_all(b"DONE")?;
+                        stream.write_u32::<LE>(0)?;
+                    }
+                    None => {
+                        let msg = "No such file or directory";
+                        stream.write_all(b"FAIL")?;
+                        stream.write_u32::<LE>(msg.len() as _)?;
+                        stream.write_all(msg.as_bytes())?;
+                        return Ok(());
+                    }
+                },
+                b"SEND" => {
+                    let (path, mode) = arg.rsplit_once(',').unwrap();
+                    let mut data = Vec::new();
+                    let mtime = loop {
+                        let mut id = [0; 4];
+                        stream.read_exact(&mut id)?;
+                        let len = stream.read_u32::<LE>()?;
+                        match &id {
+                            b"DATA" => {
+                                assert!(len as usize <= SYNC_DATA_MAX);
+                                let pos = data.len();
+                                data.resize(pos + len as usize, 0);
+                                stream.read_exact(&mut data[pos..])?;
+                            }
+                            b"DONE" => break len,
+                            _ => panic!("unexpected SEND request"),
+                        }
+                    };
+                    let entry = FakeEntry {
+                        mode: 0o100000 | mode.parse::<u32>().unwrap(),
+                        data,
+                        mtime,
+                    };
+                    device.entries.insert(path.to_owned(), entry);
+                    stream.write_all(b"OKAY")?;
+                    stream.write_u32::<LE>(0)?;
+                }
+                b"QUIT" => return Ok(()),
+                _ => panic!("unexpected sync request"),
+            }
+        }
+    }
+
+    struct NoFsCtx;
+
+    impl super::super::FileSystemCreationContext for NoFsCtx {
+        fn get_or_run_fs(
+            &mut self,
+            _: &Uuid,
+            _: &str,
+        ) -> Result<Arc<dyn FileSystemHandler>, super::super::FileSystemCreationError> {
+            Err(super::super::FileSystemCreationError::NotFound)
+        }
+    }
+
+    struct Collect(Vec<(String, FileStatInfo)>);
+
+    impl FindFilesDataFiller for Collect {
+        fn fill_data(&mut self, name: &str, stat: &FileStatInfo) -> Result<(), ()> {
+            self.0.push((name.to_owned(), *stat));
+            Ok(())
+        }
+    }
+
+    fn open<'a>(
+        handler: &'a dyn FileSystemHandler,
+        path: &str,
+        create_disposition: FileCreateDisposition,
+    ) -> super::super::FileSystemResult<super::super::CreateFileInfo<'a>> {
+        handler.create_file(
+            SegPath::new(path, PathDelimiter::BackSlash),
+            FileDesiredAccess::ReadWrite,
+            FileAttributes::empty(),
+            FileShareAccess::all(),
+            create_disposition,
+            FileCreateOptions::empty(),
+        )
+    }
+
+    fn check_sync_requests(features: &'static str) -> Vec<String> {
+        let (addr, device) = start_fake_server(features);
+        let mut config = AdbFsProvider::new().get_template_config();
+        config["server_address"] = addr.into();
+        let handler = AdbFsProvider::new()
+            .construct(config, &mut NoFsCtx)
+            .unwrap();
+        let handler = &*handler;
+
+        // LIST
+        let root = open(handler, "\\", FileCreateDisposition::OpenExisting).unwrap();
+        let mut entries = Collect(Vec::new());
+        root.context
+            .find_files_with_pattern(&AcceptAllFilePattern::new(), &mut entries)
+            .unwrap();
+        let entries: Vec<_> = (entries.0.iter())
+            .map(|(name, stat)| (name.as_str(), stat.is_dir, stat.size))
+            .collect();
+        assert_eq!(entries, [("big.bin", false, 200_000), ("sub", true, 0)]);
+
+        // STAT and RECV
+        let expected = big_data();
+        let file = open(handler, "\\big.bin", FileCreateDisposition::OpenExisting).unwrap();
+        assert_eq!(file.context.get_stat().unwrap().size, 200_000);
+        let mut buf = vec![0; 100_000];
+        assert_eq!(file.context.read_at(150_000, &mut buf).unwrap(), 50_000);
+        assert_eq!(buf[..50_000], expected[150_000..]);
+        assert!(matches!(
+            open(handler, "\\missing", FileCreateDisposition::OpenExisting),
+            Err(FileSystemError::ObjectNameNotFound)
+        ));
+
+        // SEND, once modified data is flushed
+        file.context
+            .write_at(Some(199_998), b"abcd", false)
+            .unwrap();
+        file.context.flush_buffers().unwrap();
+        drop(file);
+        let mut expected = expected;
+        expected.truncate(199_998);
+        expected.extend_from_slice(b"abcd");
+        assert!(device.lock().unwrap().entries["/sdcard/big.bin"].data == expected);
+
+        let file = open(handler, "\\sub\\new.txt", FileCreateDisposition::CreateNew).unwrap();
+        assert!(file.new_file_created);
+        file.context.write_at(None, b"new file", false).unwrap();
+        drop(file);
+        let device = device.lock().unwrap();
+        let entry = &device.entries["/sdcard/sub/new.txt"];
+        assert_eq!((entry.mode, &entry.data[..]), (0o100644, &b"new file"[..]));
+        device.requests.clone()
+    }
+
+    #[test]
+    fn sync_v1() {
+        let requests = check_sync_requests("shell_v2,cmd");
+        for id in ["STAT", "LIST", "RECV", "SEND"] {
+            assert!(requests.iter().any(|x| x == id), "{id} is not used");
+        }
+        assert!(!requests.iter().any(|x| x == "LST2" || x == "LIS2"));
+    }
+
+    #[test]
+    fn sync_v2() {
+        let requests = check_sync_requests("shell_v2,cmd,stat_v2,ls_v2");
+        for id in ["LST2", "LIS2", "RECV", "SEND"] {
+            assert!(requests.iter().any(|x| x == id), "{id} is not used");
+        }
+        assert!(!requests.iter().any(|x| x == "STAT" || x == "LIST"));
+    }
+}
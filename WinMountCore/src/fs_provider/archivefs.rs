@@ -1,9 +1,21 @@
+mod cache;
+mod crypto;
+mod index_cache;
+mod inflate;
+mod info;
+mod iso;
+mod rar;
+mod sanitize;
+mod sevenzip;
+mod stream;
+mod tar;
+mod tree;
+mod volume;
 mod zip;
 
 use std::{
     borrow::Cow,
     collections::{BTreeMap, HashMap},
-    convert::Infallible,
     ptr::NonNull,
     sync::{
         atomic::{AtomicU64, Ordering},
@@ -17,6 +29,9 @@ use uuid::{uuid, Uuid};
 
 use crate::util::{CaselessStr, CaselessString};
 
+use cache::{ArchiveCache, ArchiveCacheHandle};
+use index_cache::ArchiveIndexCache;
+
 use super::{
     FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
     FileSystemError, FsWithPath, FsWithPathConfig,
@@ -32,6 +47,37 @@ trait ArchiveFile {
         pattern: &dyn super::FilePattern,
         filler: &mut dyn super::FindFilesDataFiller,
     ) -> super::FileSystemResult<()>;
+    // NOTE: Only files from writable archives can be modified
+    fn write_at(
+        &self,
+        _offset: Option<u64>,
+        _buffer: &[u8],
+        _constrain_size: bool,
+    ) -> super::FileSystemResult<u64> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_end_of_file(&self, _offset: u64) -> super::FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_file_times(
+        &self,
+        _creation_time: std::time::SystemTime,
+        _last_access_time: std::time::SystemTime,
+        _last_write_time: std::time::SystemTime,
+    ) -> super::FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_delete(&self, _delete_on_close: bool) -> super::FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    // NOTE: new_path is relative to the root of the same archive
+    fn move_to(
+        &self,
+        _new_path: super::SegPath,
+        _replace_if_exists: bool,
+    ) -> super::FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
 }
 
 type OwnedArchiveFile<'a> = Box<dyn ArchiveFile + 'a>;
@@ -46,6 +92,52 @@ trait ArchiveHandler: Send {
         &self,
         filename: super::SegPath,
     ) -> super::FileSystemResult<ArchiveHandlerOpenFileInfo<'_>>;
+    /// Opens or creates a file according to `create_disposition`, also returning whether
+    /// a new file was created.
+    // NOTE: Read-only archives ignore the disposition and only open existing files
+    fn create_file(
+        &self,
+        filename: super::SegPath,
+        _create_disposition: FileCreateDisposition,
+        _create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<(ArchiveHandlerOpenFileInfo<'_>, bool)> {
+        self.open_file(filename).map(|x| (x, false))
+    }
+    /// Whether there are modifications not yet written back into the archive.
+    fn has_pending_changes(&self) -> bool {
+        false
+    }
+    /// Writes pending modifications back into the archive. `is_final` is set on unmount,
+    /// when no file inside the archive is open anymore.
+    fn commit(&self, _is_final: bool) -> super::FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    /// Reads every entry through, returning the ones failing verification.
+    fn verify_integrity(&self) -> super::FileSystemResult<Vec<super::IntegrityIssue>> {
+        Err(FileSystemError::NotImplemented)
+    }
+    /// Problems found while loading the archive, such as entries with unsafe or
+    /// colliding names.
+    fn get_warnings(&self) -> Vec<super::ContainerWarning> {
+        Vec::new()
+    }
+    /// Names of the JSON files with metadata listed inside the info folder.
+    fn get_info_file_names(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Contents of an info file, generated only when the file is opened or listed.
+    /// Handlers should cache files which are costly to generate.
+    fn get_info_file(&self, _name: &str) -> super::FileSystemResult<Option<Arc<[u8]>>> {
+        Ok(None)
+    }
+    /// Approximate memory taken by the parsed index, charged against the cache while
+    /// the archive is idle.
+    fn get_index_size(&self) -> u64 {
+        64 * 1024
+    }
+    /// Called once no file inside the archive is open anymore, to drop state only
+    /// needed while reading (such as live decoders) before the archive is kept idle.
+    fn on_idle(&self) {}
 }
 
 struct ArchiveHandlerWithFilesDepFilesInfo<'a> {
@@ -53,6 +145,7 @@ struct ArchiveHandlerWithFilesDepFilesInfo<'a> {
     is_dir: bool,
     ref_count: u64,
 }
+type ArchiveDepFiles<'a> = Mutex<BTreeMap<CaselessString, ArchiveHandlerWithFilesDepFilesInfo<'a>>>;
 struct ArchiveHandlerWithFilesChildFilesInfo {
     file: OwnedArchiveFile<'static>,
     is_dir: bool,
@@ -64,12 +157,16 @@ unsafe impl Send for ArchiveHandlerWithFiles<'_> {}
 struct ArchiveHandlerWithFiles<'a> {
     handler: Option<NonNull<dyn ArchiveHandler>>,
     // Files used by handler
-    dep_files: Mutex<BTreeMap<CaselessString, ArchiveHandlerWithFilesDepFilesInfo<'a>>>,
+    dep_files: ArchiveDepFiles<'a>,
     // Files created from handler
     files: Mutex<BTreeMap<CaselessString, ArchiveHandlerWithFilesChildFilesInfo>>,
     // NOTE: base_path is already combined with FsWithPath.path
     base_path: CaselessString,
     path: CaselessString,
+    // Owner id of everything cached for the archive
+    cache_owner: u64,
+    // Size and last write time of the archive file when it was opened
+    root_stat: Option<(u64, std::time::SystemTime)>,
 }
 impl<'a> ArchiveHandlerWithFiles<'a> {
     unsafe fn new(
@@ -78,7 +175,9 @@ impl<'a> ArchiveHandlerWithFiles<'a> {
         filename: CaselessString,
         file: super::OwnedFile<'a>,
         is_dir: bool,
+        cache_owner: u64,
     ) -> Self {
+        let root_stat = file.get_stat().ok().map(|x| (x.size, x.last_write_time));
         let mut dep_files = BTreeMap::new();
         dep_files.insert(
             filename.clone(),
@@ -94,6 +193,26 @@ impl<'a> ArchiveHandlerWithFiles<'a> {
             files: Mutex::new(BTreeMap::new()),
             base_path,
             path: filename,
+            cache_owner,
+            root_stat,
+        }
+    }
+    // Whether the archive is only kept around for its parsed index
+    fn is_idle(&mut self) -> bool {
+        self.files.get_mut().unwrap().is_empty()
+            && !self
+                .handler
+                .is_some_and(|x| unsafe { x.as_ref() }.has_pending_changes())
+    }
+    // Whether the archive file was changed since the archive was opened
+    fn is_stale(&mut self) -> bool {
+        let Some(root_stat) = self.root_stat else {
+            return false;
+        };
+        let dep_files = self.dep_files.get_mut().unwrap();
+        match dep_files.get(&self.path).map(|x| x.file.get_stat()) {
+            Some(Ok(stat)) => (stat.size, stat.last_write_time) != root_stat,
+            _ => true,
         }
     }
 }
@@ -110,13 +229,27 @@ impl Drop for ArchiveHandlerWithFiles<'_> {
     }
 }
 
-// NOTE: ArchiveFs is primaily read-only
+// NOTE: ArchiveFs is primaily read-only, only archives matched by writable rules
+//       can be modified
 struct ArchiveFsHandler {
     in_path: FsWithPath,
     // TODO: Correctly annonate lifetime of ArchiveHandlerWithFiles
+    // NOTE: Archives with pending changes stay open even if no file inside is open
     open_archives: Mutex<BTreeMap<CaselessString, Box<ArchiveHandlerWithFiles<'static>>>>,
+    // Used to key files from writable archives, which are not shared between opens
+    open_counter: AtomicU64,
     archive_rules: Vec<ArchiveOpenRuleConfig>,
     non_unicode_compat: ArchiveGlobalNonUnicodeCompatConfig,
+    encodings: ArchiveNameEncodings,
+    passwords: ArchiveGlobalPasswordConfig,
+    crc_check: ArchiveCrcCheckMode,
+    // NOTE: Idle archives are archives with no open file, kept while within the
+    //       cache budget to avoid parsing them again
+    cache: ArchiveCache,
+    keep_idle_archives: bool,
+    index_cache: Option<ArchiveIndexCache>,
+    // Name of the virtual folder with metadata inside every archive
+    info_folder: Option<String>,
 }
 
 #[derive(Clone)]
@@ -171,7 +304,7 @@ impl Default for ArchiveNonUnicodeCompatConfig {
 
 #[derive(Serialize, Deserialize, Clone)]
 struct ArchiveNonUnicodeCompatConfigEntry {
-    // TODO: Describe haystack format
+    /// Matched against the path of the archive, as for `path_pattern` of archive rules.
     #[serde(with = "serde_regex")]
     path_pattern: Regex,
     #[serde(flatten)]
@@ -181,6 +314,10 @@ struct ArchiveNonUnicodeCompatConfigEntry {
 #[derive(Serialize, Deserialize, Clone)]
 struct ArchiveGlobalNonUnicodeCompatConfig {
     encoding: ArchiveNonUnicodeEncoding,
+    /// Code page used by the system (`""`) encoding. Defaults to the ANSI code page
+    /// on Windows, and to 1252 on other hosts.
+    #[serde(default)]
+    system_code_page: Option<u16>,
     entries: Vec<ArchiveNonUnicodeCompatConfigEntry>,
 }
 
@@ -188,14 +325,68 @@ impl Default for ArchiveGlobalNonUnicodeCompatConfig {
     fn default() -> Self {
         Self {
             encoding: ArchiveNonUnicodeEncoding::System,
+            system_code_page: None,
             entries: Vec::new(),
         }
     }
 }
 
+impl ArchiveGlobalNonUnicodeCompatConfig {
+    // Checks that every configured encoding can be converted from
+    fn validate(&self) -> anyhow::Result<()> {
+        std::iter::once(&self.encoding)
+            .chain(self.entries.iter().map(|x| &x.config.encoding_override))
+            .filter(|x| !matches!(x, ArchiveNonUnicodeEncoding::AutoDetect))
+            .try_for_each(|x| EncodingConverter::new(x, self.system_code_page).map(|_| ()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ArchiveCrcCheckMode {
+    // Fails the read which reaches the end of a mismatched entry
+    #[default]
+    Strict,
+    // Only logs mismatches
+    Warn,
+    Off,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ArchivePasswordConfigEntry {
+    /// Matched against the path of the archive, as for `path_pattern` of archive rules.
+    #[serde(with = "serde_regex")]
+    path_pattern: Regex,
+    passwords: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct ArchiveGlobalPasswordConfig {
+    /// Passwords tried for every encrypted archive, after the matched entry ones.
+    passwords: Vec<String>,
+    entries: Vec<ArchivePasswordConfigEntry>,
+}
+
+impl ArchiveGlobalPasswordConfig {
+    fn get_passwords_for(&self, path: &str) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|x| x.path_pattern.is_match(path))
+            .next()
+            .into_iter()
+            .flat_map(|x| x.passwords.iter())
+            .chain(self.passwords.iter())
+            .cloned()
+            .collect()
+    }
+}
+
 enum EncodingConverterEngine {
-    Win32MBWC { from_cp: u32, to_cp: u32 },
-    Win32ICU(Infallible),
+    #[cfg(windows)]
+    Win32MBWC {
+        from_cp: u32,
+        to_cp: u32,
+    },
     RustEncoding(&'static encoding_rs::Encoding),
 }
 
@@ -204,87 +395,103 @@ struct EncodingConverter {
 }
 
 impl EncodingConverter {
-    fn new(encoding: &ArchiveNonUnicodeEncoding) -> Self {
-        use windows::Win32::Globalization::*;
-
-        if matches!(encoding, ArchiveNonUnicodeEncoding::AutoDetect) {
-            panic!("AutoDetect is not a valid encoding for EncodingConverter")
-        }
+    fn new(
+        encoding: &ArchiveNonUnicodeEncoding,
+        system_code_page: Option<u16>,
+    ) -> anyhow::Result<Self> {
         let engine = match encoding {
-            ArchiveNonUnicodeEncoding::System => EncodingConverterEngine::Win32MBWC {
-                from_cp: CP_ACP,
-                to_cp: CP_UTF8,
+            ArchiveNonUnicodeEncoding::System => match system_code_page {
+                Some(cp) => EncodingConverterEngine::RustEncoding(
+                    codepage::to_encoding(cp)
+                        .ok_or_else(|| anyhow::anyhow!("unsupported code page {cp}"))?,
+                ),
+                #[cfg(windows)]
+                None => {
+                    use windows::Win32::Globalization::*;
+                    EncodingConverterEngine::Win32MBWC {
+                        from_cp: CP_ACP,
+                        to_cp: CP_UTF8,
+                    }
+                }
+                #[cfg(not(windows))]
+                None => EncodingConverterEngine::RustEncoding(encoding_rs::WINDOWS_1252),
             },
             ArchiveNonUnicodeEncoding::AutoDetect => {
-                panic!("AutoDetect is not a valid encoding for EncodingConverter");
-            }
-            ArchiveNonUnicodeEncoding::Specified(s) => {
-                let encoding =
-                    encoding_rs::Encoding::for_label(s.as_bytes()).expect("invalid encoding name");
-                EncodingConverterEngine::RustEncoding(encoding)
+                anyhow::bail!("encoding must be detected before creating a converter")
             }
+            ArchiveNonUnicodeEncoding::Specified(s) => EncodingConverterEngine::RustEncoding(
+                encoding_rs::Encoding::for_label(s.as_bytes())
+                    .ok_or_else(|| anyhow::anyhow!("unknown encoding `{s}`"))?,
+            ),
         };
-        Self { engine }
+        Ok(Self { engine })
+    }
+    fn with_encoding(encoding: &'static encoding_rs::Encoding) -> Self {
+        Self {
+            engine: EncodingConverterEngine::RustEncoding(encoding),
+        }
+    }
+    /// Guesses the encoding of a set of non-UTF-8 names.
+    fn detect<'n>(names: impl Iterator<Item = &'n [u8]>) -> &'static encoding_rs::Encoding {
+        let mut detector = chardetng::EncodingDetector::new();
+        for name in names {
+            detector.feed(name, false);
+        }
+        detector.feed(&[], true);
+        detector.guess(None, false)
     }
     fn convert<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
-        use windows::Win32::Globalization::*;
-
         match self.engine {
+            #[cfg(windows)]
             EncodingConverterEngine::Win32MBWC { from_cp, to_cp } => unsafe {
+                use windows::Win32::Globalization::*;
+
+                if bytes.is_empty() {
+                    return Cow::Borrowed("");
+                }
+                // NOTE: Conversion failures are not fatal, names are shown lossily instead
                 let wide_buf = {
                     let size =
                         MultiByteToWideChar(from_cp, MULTI_BYTE_TO_WIDE_CHAR_FLAGS(0), bytes, None);
                     if size == 0 {
-                        panic!("MultiByteToWideChar returned 0");
+                        log::warn!("MultiByteToWideChar failed for {bytes:?}");
+                        return String::from_utf8_lossy(bytes);
                     }
-                    let mut wide_buf = Vec::with_capacity(size as _);
-                    // TODO: Is it safe to form a mut slice into uninitialized memory?
-                    //       (https://github.com/rust-lang/miri/issues/1240)
+                    let mut wide_buf = vec![0; size as _];
                     let size2 = MultiByteToWideChar(
                         from_cp,
                         MULTI_BYTE_TO_WIDE_CHAR_FLAGS(0),
                         bytes,
-                        Some(std::slice::from_raw_parts_mut(
-                            wide_buf.as_mut_ptr(),
-                            size as _,
-                        )),
+                        Some(&mut wide_buf),
                     );
-                    if size != size2 {
-                        panic!("MultiByteToWideChar returned different size");
-                    }
-                    wide_buf.set_len(size as _);
+                    wide_buf.truncate(size2.max(0) as _);
                     wide_buf
                 };
-                {
-                    let size = WideCharToMultiByte(to_cp, 0, &wide_buf, None, None, None);
-                    if size == 0 {
-                        panic!("WideCharToMultiByte returned 0");
-                    }
-                    let mut buf = Vec::with_capacity(size as _);
-                    let size2 = WideCharToMultiByte(
-                        to_cp,
-                        0,
-                        &wide_buf,
-                        Some(std::slice::from_raw_parts_mut(buf.as_mut_ptr(), size as _)),
-                        None,
-                        None,
-                    );
-                    if size != size2 {
-                        panic!("WideCharToMultiByte returned different size");
-                    }
-                    buf.set_len(size as _);
-                    Cow::Owned(String::from_utf8_unchecked(buf))
+                let size = WideCharToMultiByte(to_cp, 0, &wide_buf, None, None, None);
+                let mut buf = vec![0; size.max(0) as _];
+                let size2 = WideCharToMultiByte(to_cp, 0, &wide_buf, Some(&mut buf), None, None);
+                buf.truncate(size2.max(0) as _);
+                match String::from_utf8(buf) {
+                    Ok(s) => Cow::Owned(s),
+                    Err(_) => Cow::Owned(String::from_utf16_lossy(&wide_buf)),
                 }
             },
             EncodingConverterEngine::RustEncoding(encoding) => {
                 encoding.decode_without_bom_handling(bytes).0
             }
-            _ => todo!("not implemented"),
         }
     }
 }
 
-// NOTE: Honors neither handles_file nor handles_file
+// Encoding state shared by all archives of a handler
+struct ArchiveNameEncodings {
+    system_code_page: Option<u16>,
+    // NOTE: Archives are reopened after their last file is closed, so detected encodings
+    //       are kept by archive path to keep names stable across reopens
+    detected: Mutex<BTreeMap<CaselessString, &'static encoding_rs::Encoding>>,
+}
+
+// NOTE: Honors neither handles_file nor handles_folder, nor sniff_content
 fn is_name_archive<'a>(
     name: &str,
     rules: &'a Vec<ArchiveOpenRuleConfig>,
@@ -295,6 +502,15 @@ fn is_name_archive<'a>(
         .next()
 }
 
+// Archive files are listed as folders
+fn get_archive_folder_stat(stat: &super::FileStatInfo) -> super::FileStatInfo {
+    let mut stat = *stat;
+    stat.is_dir = true;
+    stat.attributes |= FileAttributes::DirectoryFile;
+    stat.size = 0;
+    stat
+}
+
 fn split_archive_path<'a, 'b>(
     path: super::SegPath<'b>,
     rules: &'a Vec<ArchiveOpenRuleConfig>,
@@ -334,18 +550,28 @@ struct ArchiveHandlerOpenContextOpenInfo<'a> {
     is_dir: bool,
 }
 
+// NOTE: Only holds the dep_files mutex of the handler rather than the handler itself,
+//       which is not Sync, so that the guard is Send + Sync like the file it derefs to
 struct ArchiveOpenDepFileGuard<'a> {
     file: &'a dyn super::File,
     path: CaselessString,
-    ctx: &'a ArchiveHandlerWithFiles<'a>,
+    dep_files: &'a ArchiveDepFiles<'a>,
 }
 impl<'a> ArchiveOpenDepFileGuard<'a> {
     fn new(
         file: &'a dyn super::File,
         path: CaselessString,
-        ctx: &'a ArchiveHandlerWithFiles<'a>,
+        dep_files: &'a ArchiveDepFiles<'a>,
     ) -> Self {
-        Self { file, path, ctx }
+        Self {
+            file,
+            path,
+            dep_files,
+        }
+    }
+    // NOTE: The file stays valid as long as the guard is alive
+    fn get_file(&self) -> &'a dyn super::File {
+        self.file
     }
 }
 impl<'a> std::ops::Deref for ArchiveOpenDepFileGuard<'a> {
@@ -357,7 +583,7 @@ impl<'a> std::ops::Deref for ArchiveOpenDepFileGuard<'a> {
 impl Drop for ArchiveOpenDepFileGuard<'_> {
     fn drop(&mut self) {
         use std::collections::btree_map::Entry::*;
-        let mut dep_files = self.ctx.dep_files.lock().unwrap();
+        let mut dep_files = self.dep_files.lock().unwrap();
         match dep_files.entry(self.path.clone()) {
             Occupied(mut e) => {
                 let info = e.get_mut();
@@ -379,6 +605,9 @@ struct ArchiveHandlerOpenContext<'a> {
     is_dir: bool,
     ctx: &'a ArchiveHandlerWithFiles<'a>,
     fs: &'a FsWithPath,
+    encodings: &'a ArchiveNameEncodings,
+    cache: &'a ArchiveCache,
+    index_cache: Option<&'a ArchiveIndexCache>,
 }
 impl<'a> ArchiveHandlerOpenContext<'a> {
     // NOTE: The base path will be automatically added
@@ -386,9 +615,26 @@ impl<'a> ArchiveHandlerOpenContext<'a> {
         &self,
         filename: super::SegPath,
     ) -> super::FileSystemResult<ArchiveHandlerOpenContextOpenInfo<'a>> {
-        use std::collections::btree_map::Entry::*;
         let filename = super::concat_path(self.ctx.base_path.as_str(), filename);
-        let filename = filename.as_non_owned();
+        self.open_dep_file(filename.as_non_owned())
+    }
+    /// Opens a file in the same folder as the archive, such as another volume of it.
+    fn open_sibling_file(
+        &self,
+        name: &str,
+    ) -> super::FileSystemResult<ArchiveHandlerOpenContextOpenInfo<'a>> {
+        let parent = self.get_path().rsplit_once('\\').map_or("", |x| x.0);
+        let filename = super::concat_path(
+            parent,
+            super::SegPath::new(name, super::PathDelimiter::BackSlash),
+        );
+        self.open_dep_file(filename.as_non_owned())
+    }
+    fn open_dep_file(
+        &self,
+        filename: super::SegPath,
+    ) -> super::FileSystemResult<ArchiveHandlerOpenContextOpenInfo<'a>> {
+        use std::collections::btree_map::Entry::*;
         let filename_str: CaselessString = filename.get_path().into();
         let mut dep_files = self.ctx.dep_files.lock().unwrap();
         let info = match dep_files.entry(filename_str.clone()) {
@@ -416,7 +662,7 @@ impl<'a> ArchiveHandlerOpenContext<'a> {
         // TODO: SAFETY statement
         let file = unsafe { std::mem::transmute(&*info.file) };
         Ok(ArchiveHandlerOpenContextOpenInfo {
-            context: ArchiveOpenDepFileGuard::new(file, filename_str, self.ctx),
+            context: ArchiveOpenDepFileGuard::new(file, filename_str, &self.ctx.dep_files),
             is_dir: info.is_dir,
         })
     }
@@ -426,17 +672,143 @@ impl<'a> ArchiveHandlerOpenContext<'a> {
     pub fn get_is_dir(&self) -> bool {
         self.is_dir
     }
+    // NOTE: This is the full path of the archive, including the base path
+    pub fn get_path(&self) -> &'a str {
+        self.ctx.path.as_str()
+    }
+    pub fn get_file_name(&self) -> &'a str {
+        let path = self.get_path();
+        path.rsplit_once('\\').map_or(path, |x| x.1)
+    }
+    // NOTE: Returns None if caching is disabled
+    fn get_cache(&self) -> Option<ArchiveCacheHandle<'a>> {
+        (self.cache.get_max_size() > 0)
+            .then(|| ArchiveCacheHandle::new(self.cache, self.ctx.cache_owner))
+    }
+    fn get_index_cache(&self) -> Option<&'a ArchiveIndexCache> {
+        self.index_cache
+    }
+    // Describes how non-Unicode names are decoded, as cached indexes depend on it
+    fn get_name_settings(&self, non_unicode_compat: &ArchiveNonUnicodeCompatConfig) -> String {
+        format!(
+            "{}/{:?}",
+            serde_json::to_string(non_unicode_compat).unwrap_or_default(),
+            self.encodings.system_code_page
+        )
+    }
+    /// Creates the converter for non-Unicode names of the archive. For `AutoDetect`,
+    /// `names` is only sampled the first time the archive is opened.
+    fn get_name_converter<'n>(
+        &self,
+        encoding: &ArchiveNonUnicodeEncoding,
+        names: impl Iterator<Item = &'n [u8]>,
+    ) -> anyhow::Result<EncodingConverter> {
+        if !matches!(encoding, ArchiveNonUnicodeEncoding::AutoDetect) {
+            return EncodingConverter::new(encoding, self.encodings.system_code_page);
+        }
+        let mut detected = self.encodings.detected.lock().unwrap();
+        let encoding = *detected.entry(self.ctx.path.clone()).or_insert_with(|| {
+            let encoding = EncodingConverter::detect(names);
+            log::debug!(
+                "Picked encoding {} for `{}`",
+                encoding.name(),
+                self.get_path()
+            );
+            encoding
+        });
+        Ok(EncodingConverter::with_encoding(encoding))
+    }
 }
 
+// NOTE: write_config is only set if the archive file was opened for writing
 fn open_archive_from_file<'a>(
     open_ctx: ArchiveHandlerOpenContext<'a>,
     archive_rule: &ArchiveOpenRuleConfig,
     non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    passwords: Vec<String>,
+    write_config: Option<&ArchiveWriteConfig>,
+    crc_check: ArchiveCrcCheckMode,
 ) -> anyhow::Result<Box<dyn ArchiveHandler + 'a>> {
-    Ok(Box::new(match archive_rule.handler_kind {
-        ArchiveHandlerKind::Zip => zip::ZipArchive::new(open_ctx, non_unicode_compat)?,
-        _ => anyhow::bail!("unsupported archive handler type"),
-    }))
+    Ok(match archive_rule.handler_kind {
+        ArchiveHandlerKind::Zip => Box::new(zip::ZipArchive::new(
+            open_ctx,
+            non_unicode_compat,
+            passwords,
+            write_config,
+            crc_check,
+        )?),
+        ArchiveHandlerKind::Tar => Box::new(tar::TarArchive::new(open_ctx, non_unicode_compat)?),
+        ArchiveHandlerKind::CompressedTar => Box::new(tar::TarArchive::new_compressed(
+            open_ctx,
+            non_unicode_compat,
+        )?),
+        ArchiveHandlerKind::Compressed => Box::new(stream::CompressedArchive::new(open_ctx)?),
+        ArchiveHandlerKind::SevenZip => Box::new(sevenzip::SevenZipArchive::new(open_ctx)?),
+        ArchiveHandlerKind::Rar => Box::new(rar::RarArchive::new(open_ctx, non_unicode_compat)?),
+        ArchiveHandlerKind::Iso => Box::new(iso::IsoArchive::new(open_ctx)?),
+    })
+}
+
+impl ArchiveFsHandler {
+    // Charges an archive with no open file against the cache, returning whether the
+    // archive should be kept open
+    fn keep_idle_archive(&self, entry: &ArchiveHandlerWithFiles) -> bool {
+        self.keep_idle_archives
+            && entry.handler.is_some_and(|x| {
+                let handler = unsafe { x.as_ref() };
+                handler.on_idle();
+                let index_size = handler.get_index_size();
+                self.cache
+                    .insert_idle_archive(entry.cache_owner, index_size)
+            })
+    }
+    // Checks whether a file matched by a rule sniffing content looks like an archive
+    fn sniff_archive(&self, path: super::SegPath, rule: &ArchiveOpenRuleConfig) -> bool {
+        self.in_path
+            .handler
+            .create_file(
+                path,
+                FileDesiredAccess::Read,
+                FileAttributes::empty(),
+                FileShareAccess::Read,
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::NonDirectoryFile,
+            )
+            .is_ok_and(|x| rule.handler_kind.sniff(x.context.as_ref()))
+    }
+    // Opens a file inside an archive, or inside its info folder if enabled
+    fn create_archive_file<'a>(
+        &self,
+        archive: &'a dyn ArchiveHandler,
+        filename: super::SegPath,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<(ArchiveHandlerOpenFileInfo<'a>, bool)> {
+        match &self.info_folder {
+            Some(folder_name) => info::create_file(
+                archive,
+                folder_name,
+                filename,
+                create_disposition,
+                create_options,
+            ),
+            None => archive.create_file(filename, create_disposition, create_options),
+        }
+    }
+    // Closes idle archives which were evicted from the cache
+    fn sweep_idle_archives(
+        &self,
+        entries: &mut BTreeMap<CaselessString, Box<ArchiveHandlerWithFiles<'static>>>,
+    ) {
+        entries.retain(|path, entry| {
+            if !entry.is_idle() || self.cache.contains_idle_archive(entry.cache_owner) {
+                return true;
+            }
+            log::debug!("Closing idle archive `{}`", path.as_str());
+            self.cache.remove_owner(entry.cache_owner);
+            false
+        });
+    }
 }
 
 impl super::FileSystemHandler for ArchiveFsHandler {
@@ -451,8 +823,6 @@ impl super::FileSystemHandler for ArchiveFsHandler {
     ) -> super::FileSystemResult<super::CreateFileInfo<'_>> {
         use std::collections::btree_map::Entry::*;
 
-        let orig_filename = filename;
-
         let filename = super::concat_path(&self.in_path.path, filename);
         let filename = filename.as_non_owned();
 
@@ -486,7 +856,11 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                 return Err(FileSystemError::AccessDenied);
             }
             Ok(super::CreateFileInfo {
-                context: Box::new(ArchiveFsFile::new_raw(self, raw_create_result.context)),
+                context: Box::new(ArchiveFsFile::new_raw(
+                    self,
+                    raw_create_result.context,
+                    filename.get_path().to_owned(),
+                )),
                 is_dir: raw_create_result.is_dir,
                 new_file_created: false,
             })
@@ -498,31 +872,61 @@ impl super::FileSystemHandler for ArchiveFsHandler {
             // Handle archive
             let mut entries = self.open_archives.lock().unwrap();
 
-            match entries.entry(front_path.get_path().into()) {
+            // NOTE: Files from writable archives are never shared between opens, as
+            //       entries may be renamed or replaced while being open
+            let file_key: CaselessString = if archive_rule.writable.is_some() {
+                let id = self.open_counter.fetch_add(1, Ordering::Relaxed);
+                format!("{}\0{id}", back_path.get_path()).into()
+            } else {
+                back_path.get_path().into()
+            };
+
+            // Idle archives are opened again if the archive file was changed meanwhile
+            self.sweep_idle_archives(&mut entries);
+            let archive_key: CaselessString = front_path.get_path().into();
+            if let Occupied(mut e) = entries.entry(archive_key.clone()) {
+                let entry = e.get_mut();
+                if entry.is_idle() && entry.is_stale() {
+                    log::debug!("Archive `{}` changed, reopening", front_path.get_path());
+                    self.cache.remove_owner(entry.cache_owner);
+                    e.remove();
+                }
+            }
+
+            match entries.entry(archive_key) {
                 Occupied(e) => {
                     let entry = e.into_mut();
+                    let cache_owner = entry.cache_owner;
                     let files = entry.files.get_mut().unwrap();
-                    let file_info = match files.entry(back_path.get_path().into()) {
+                    let (file_info, new_file_created) = match files.entry(file_key.clone()) {
                         Occupied(e) => {
                             // let key: &str = unsafe { std::mem::transmute(e.key().as_str()) };
                             let info = e.into_mut();
                             ensure_file_kind_fn(info.is_dir)?;
                             // log::debug!("File `{}` inc refcnt = {}", key, info.ref_count + 1);
                             info.ref_count += 1;
-                            info
+                            (info, false)
                         }
                         Vacant(e) => unsafe {
                             let archive = entry.handler.unwrap_unchecked().as_ref();
-                            let open_result = archive.open_file(back_path)?;
+                            let (open_result, new_file_created) = self.create_archive_file(
+                                archive,
+                                back_path,
+                                create_disposition,
+                                create_options,
+                            )?;
                             ensure_file_kind_fn(open_result.is_dir)?;
                             // log::debug!("Adding file `{}` to cache...", e.key().as_str());
-                            e.insert(ArchiveHandlerWithFilesChildFilesInfo {
+                            let info = e.insert(ArchiveHandlerWithFilesChildFilesInfo {
                                 file: open_result.context,
                                 is_dir: open_result.is_dir,
                                 ref_count: 1,
-                            })
+                            });
+                            (info, new_file_created)
                         },
                     };
+                    // The archive is in use again, so stop charging it as idle
+                    self.cache.remove_idle_archive(cache_owner);
                     let index = front_path.get_path().into();
                     Ok(super::CreateFileInfo {
                         context: unsafe {
@@ -530,29 +934,50 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                                 self,
                                 index,
                                 &self.open_archives,
-                                back_path.get_path().into(),
+                                file_key,
                                 file_info.file.as_ref(),
                             ))
                         },
                         is_dir: file_info.is_dir,
-                        new_file_created: false,
+                        new_file_created,
                     })
                 }
                 Vacant(e) => {
-                    let raw_create_result = self.in_path.handler.create_file(
-                        front_path,
-                        FileDesiredAccess::Read,
-                        FileAttributes::empty(),
-                        FileShareAccess::Read,
-                        FileCreateDisposition::OpenExisting,
-                        FileCreateOptions::empty(),
-                    )?;
-
-                    // Check whether rule covers current file kind
+                    let open_raw_fn = |desired_access| {
+                        self.in_path.handler.create_file(
+                            front_path,
+                            desired_access,
+                            FileAttributes::empty(),
+                            FileShareAccess::Read,
+                            FileCreateDisposition::OpenExisting,
+                            FileCreateOptions::empty(),
+                        )
+                    };
+                    let mut write_config = archive_rule.writable.as_ref();
+                    let raw_create_result = match write_config {
+                        Some(_) => match open_raw_fn(FileDesiredAccess::ReadWrite) {
+                            Err(FileSystemError::AccessDenied) => {
+                                log::warn!(
+                                    "Archive `{}` cannot be written, opening as read-only",
+                                    front_path.get_path()
+                                );
+                                write_config = None;
+                                open_raw_fn(FileDesiredAccess::Read)
+                            }
+                            result => result,
+                        },
+                        None => open_raw_fn(FileDesiredAccess::Read),
+                    }?;
+
+                    // Check whether rule covers current file kind (and content)
                     let rule_covers = if raw_create_result.is_dir {
                         archive_rule.handles_folder
                     } else {
                         archive_rule.handles_file
+                            && (!archive_rule.sniff_content
+                                || archive_rule
+                                    .handler_kind
+                                    .sniff(raw_create_result.context.as_ref()))
                     };
                     if !rule_covers {
                         // Treat as raw creation instead
@@ -565,7 +990,7 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                         .non_unicode_compat
                         .entries
                         .iter()
-                        .filter(|x| x.path_pattern.is_match(orig_filename.get_path()))
+                        .filter(|x| x.path_pattern.is_match(front_path.get_path()))
                         .next()
                         .map(|x| Cow::Borrowed(&x.config))
                         .unwrap_or_else(|| {
@@ -582,8 +1007,10 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                             front_path.get_path().into(),
                             raw_create_result.context,
                             raw_create_result.is_dir,
+                            self.cache.new_owner(),
                         ))
                     };
+                    let cache_owner = archive_with_files.cache_owner;
                     let root_file = unsafe {
                         std::mem::transmute(
                             archive_with_files
@@ -603,13 +1030,26 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                         is_dir: raw_create_result.is_dir,
                         ctx: unsafe { std::mem::transmute(archive_with_files.as_ref()) },
                         fs: &self.in_path,
+                        encodings: &self.encodings,
+                        cache: &self.cache,
+                        index_cache: self.index_cache.as_ref(),
                     };
-                    let archive =
-                        open_archive_from_file(open_context, archive_rule, &non_unicode_compat)
-                            .map_err(|e| {
-                                log::warn!("Open archive `{}` failed: {e}", front_path.get_path());
-                                FileSystemError::FileCorruptError
-                            })?;
+                    let passwords = self.passwords.get_passwords_for(front_path.get_path());
+
+                    let archive = open_archive_from_file(
+                        open_context,
+                        archive_rule,
+                        &non_unicode_compat,
+                        passwords,
+                        write_config,
+                        self.crc_check,
+                    )
+                    .map_err(|e| {
+                        log::warn!("Open archive `{}` failed: {e}", front_path.get_path());
+                        // Drop data cached while parsing
+                        self.cache.remove_owner(cache_owner);
+                        FileSystemError::FileCorruptError
+                    })?;
                     let archive = unsafe {
                         let archive: Box<dyn ArchiveHandler> = std::mem::transmute(archive);
                         archive_with_files.handler =
@@ -617,8 +1057,21 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                         &*archive_with_files.handler.unwrap_unchecked().as_ptr()
                     };
 
-                    let open_result = archive.open_file(back_path)?;
-                    ensure_file_kind_fn(open_result.is_dir)?;
+                    let open_result = self
+                        .create_archive_file(archive, back_path, create_disposition, create_options)
+                        .and_then(|x| ensure_file_kind_fn(x.0.is_dir).map(|_| x));
+                    let (open_result, new_file_created) = match open_result {
+                        Ok(x) => x,
+                        Err(err) => {
+                            // Keep the parsed archive for later opens anyway
+                            if self.keep_idle_archive(&archive_with_files) {
+                                e.insert(unsafe { std::mem::transmute(archive_with_files) });
+                            } else {
+                                self.cache.remove_owner(cache_owner);
+                            }
+                            return Err(err);
+                        }
+                    };
 
                     // SAFETY: File is behind Box (points to heap)
                     let open_result: ArchiveHandlerOpenFileInfo<'static> =
@@ -629,7 +1082,7 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                         .files
                         .get_mut()
                         .unwrap()
-                        .entry(back_path.get_path().into())
+                        .entry(file_key.clone())
                     {
                         Occupied(_) => {
                             panic!("unexpected file found in empty map");
@@ -650,7 +1103,7 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                             self,
                             index,
                             &self.open_archives,
-                            back_path.get_path().into(),
+                            file_key,
                             open_result_context,
                         ))
                     };
@@ -658,7 +1111,7 @@ impl super::FileSystemHandler for ArchiveFsHandler {
                     Ok(super::CreateFileInfo {
                         context,
                         is_dir: open_result.is_dir,
-                        new_file_created: false,
+                        new_file_created,
                     })
                 }
             }
@@ -672,20 +1125,46 @@ impl super::FileSystemHandler for ArchiveFsHandler {
     }
     fn get_fs_characteristics(&self) -> super::FileSystemResult<super::FileSystemCharacteristics> {
         let mut chars = self.in_path.handler.get_fs_characteristics()?;
-        chars |= super::FileSystemCharacteristics::ReadOnly;
+        if self.archive_rules.iter().all(|x| x.writable.is_none()) {
+            chars |= super::FileSystemCharacteristics::ReadOnly;
+        }
         Ok(chars)
     }
 }
 
 impl Drop for ArchiveFsHandler {
     fn drop(&mut self) {
+        let mut entries = self.open_archives.lock().unwrap();
+        // Write back pending changes before unmounting
+        for (path, entry) in entries.iter() {
+            let Some(handler) = entry.handler else {
+                continue;
+            };
+            let handler = unsafe { handler.as_ref() };
+            if !handler.has_pending_changes() {
+                continue;
+            }
+            if let Err(e) = handler.commit(true) {
+                log::error!("Failed to write back archive `{}`: {e}", path.as_str());
+            }
+        }
         // Avoid lifetime issues
-        self.open_archives.lock().unwrap().clear();
+        entries.clear();
+        let stats = self.cache.get_stats();
+        log::info!(
+            "Archive cache: {} hits, {} misses, {} evictions ({} bytes, {} idle archives)",
+            stats.hits,
+            stats.misses,
+            stats.evictions,
+            stats.evicted_size,
+            stats.idle_archive_evictions
+        );
     }
 }
 
 enum ArchiveFsFileContext<'a> {
-    Raw(super::OwnedFile<'a>),
+    // Along with the path within the input
+    Raw(super::OwnedFile<'a>, String),
     Archive {
         index: CaselessString,
         entries: &'a Mutex<BTreeMap<CaselessString, Box<ArchiveHandlerWithFiles<'static>>>>,
@@ -704,10 +1183,10 @@ unsafe impl Send for ArchiveFsFile<'_, '_> {}
 unsafe impl Sync for ArchiveFsFile<'_, '_> {}
 
 impl<'a, 'h: 'a> ArchiveFsFile<'a, 'h> {
-    fn new_raw(handler: &'h ArchiveFsHandler, file: super::OwnedFile<'a>) -> Self {
+    fn new_raw(handler: &'h ArchiveFsHandler, file: super::OwnedFile<'a>, path: String) -> Self {
         ArchiveFsFile {
             handler,
-            context: ArchiveFsFileContext::Raw(file),
+            context: ArchiveFsFileContext::Raw(file, path),
         }
     }
     // WARN: Do NOT drop ArchiveHandlerWithFiles outside, this is fully covered by
@@ -764,15 +1243,24 @@ impl Drop for ArchiveFsFile<'_, '_> {
                             panic!("file missing in open files list");
                         }
                     }
-                    if files.is_empty() {
+                    // NOTE: Archives with pending changes are kept until they are committed
+                    let has_pending_changes = entry
+                        .handler
+                        .is_some_and(|x| unsafe { x.as_ref() }.has_pending_changes());
+                    if files.is_empty()
+                        && !has_pending_changes
+                        && !self.handler.keep_idle_archive(entry)
+                    {
                         // The last file from the archive has been closed, remove the
                         // archive entry now
                         // log::debug!("Removing archive `{}` from cache...", e.key().as_str());
+                        self.handler.cache.remove_owner(entry.cache_owner);
                         e.remove();
                     }
                 }
                 Vacant(_) => panic!("archive entry missing while inner file is open"),
             }
+            self.handler.sweep_idle_archives(&mut entries);
         }
     }
 }
@@ -780,7 +1268,7 @@ impl Drop for ArchiveFsFile<'_, '_> {
 impl super::File for ArchiveFsFile<'_, '_> {
     fn read_at(&self, offset: u64, buffer: &mut [u8]) -> super::FileSystemResult<u64> {
         match &self.context {
-            ArchiveFsFileContext::Raw(f) => f.read_at(offset, buffer),
+            ArchiveFsFileContext::Raw(f, _) => f.read_at(offset, buffer),
             ArchiveFsFileContext::Archive { file, .. } => {
                 let file = unsafe { &**file };
                 file.read_at(offset, buffer)
@@ -789,44 +1277,128 @@ impl super::File for ArchiveFsFile<'_, '_> {
     }
     fn write_at(
         &self,
-        _offset: Option<u64>,
-        _buffer: &[u8],
-        _constrain_size: bool,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
     ) -> super::FileSystemResult<u64> {
-        Err(FileSystemError::AccessDenied)
+        match &self.context {
+            ArchiveFsFileContext::Raw(..) => Err(FileSystemError::AccessDenied),
+            ArchiveFsFileContext::Archive { file, .. } => {
+                let file = unsafe { &**file };
+                file.write_at(offset, buffer, constrain_size)
+            }
+        }
     }
     fn flush_buffers(&self) -> super::FileSystemResult<()> {
-        Err(FileSystemError::AccessDenied)
+        match &self.context {
+            ArchiveFsFileContext::Raw(..) => Err(FileSystemError::AccessDenied),
+            ArchiveFsFileContext::Archive { index, entries, .. } => {
+                // Commit changes of the whole archive
+                // NOTE: The entry outlives this file, so committing without holding
+                //       the lock is fine
+                let handler = entries
+                    .lock()
+                    .unwrap()
+                    .get(index)
+                    .expect("archive entry missing while inner file is open")
+                    .handler;
+                let handler = unsafe { handler.unwrap_unchecked().as_ref() };
+                handler.commit(false)
+            }
+        }
+    }
+    fn verify_integrity(&self) -> super::FileSystemResult<Vec<super::IntegrityIssue>> {
+        match &self.context {
+            ArchiveFsFileContext::Raw(f, _) => f.verify_integrity(),
+            ArchiveFsFileContext::Archive { index, entries, .. } => {
+                // Verify the whole archive, without blocking other archives meanwhile
+                let handler = entries
+                    .lock()
+                    .unwrap()
+                    .get(index)
+                    .expect("archive entry missing while inner file is open")
+                    .handler;
+                let handler = unsafe { handler.unwrap_unchecked().as_ref() };
+                handler.verify_integrity()
+            }
+        }
+    }
+    fn get_container_warnings(&self) -> super::FileSystemResult<Vec<super::ContainerWarning>> {
+        match &self.context {
+            ArchiveFsFileContext::Raw(f, _) => f.get_container_warnings(),
+            ArchiveFsFileContext::Archive { index, entries, .. } => {
+                let entries = entries.lock().unwrap();
+                let handler = entries
+                    .get(index)
+                    .expect("archive entry missing while inner file is open")
+                    .handler;
+                let handler = unsafe { handler.unwrap_unchecked().as_ref() };
+                Ok(handler.get_warnings())
+            }
+        }
     }
     fn get_stat(&self) -> super::FileSystemResult<super::FileStatInfo> {
         match &self.context {
-            ArchiveFsFileContext::Raw(f) => f.get_stat(),
+            ArchiveFsFileContext::Raw(f, _) => f.get_stat(),
             ArchiveFsFileContext::Archive { file, .. } => {
                 let file = unsafe { &**file };
                 file.get_stat()
             }
         }
     }
-    fn set_end_of_file(&self, _offset: u64) -> super::FileSystemResult<()> {
-        Err(FileSystemError::AccessDenied)
+    fn set_end_of_file(&self, offset: u64) -> super::FileSystemResult<()> {
+        match &self.context {
+            ArchiveFsFileContext::Raw(..) => Err(FileSystemError::AccessDenied),
+            ArchiveFsFileContext::Archive { file, .. } => {
+                let file = unsafe { &**file };
+                file.set_end_of_file(offset)
+            }
+        }
     }
     fn set_file_times(
         &self,
-        _creation_time: std::time::SystemTime,
-        _last_access_time: std::time::SystemTime,
-        _last_write_time: std::time::SystemTime,
+        creation_time: std::time::SystemTime,
+        last_access_time: std::time::SystemTime,
+        last_write_time: std::time::SystemTime,
     ) -> super::FileSystemResult<()> {
-        Err(FileSystemError::AccessDenied)
+        match &self.context {
+            ArchiveFsFileContext::Raw(..) => Err(FileSystemError::AccessDenied),
+            ArchiveFsFileContext::Archive { file, .. } => {
+                let file = unsafe { &**file };
+                file.set_file_times(creation_time, last_access_time, last_write_time)
+            }
+        }
     }
-    fn set_delete(&self, _delete_on_close: bool) -> super::FileSystemResult<()> {
-        Err(FileSystemError::AccessDenied)
+    fn set_delete(&self, delete_on_close: bool) -> super::FileSystemResult<()> {
+        match &self.context {
+            ArchiveFsFileContext::Raw(..) => Err(FileSystemError::AccessDenied),
+            ArchiveFsFileContext::Archive { file, .. } => {
+                let file = unsafe { &**file };
+                file.set_delete(delete_on_close)
+            }
+        }
     }
     fn move_to(
         &self,
-        _new_path: super::SegPath,
-        _replace_if_exists: bool,
+        new_path: super::SegPath,
+        replace_if_exists: bool,
     ) -> super::FileSystemResult<()> {
-        Err(FileSystemError::AccessDenied)
+        match &self.context {
+            ArchiveFsFileContext::Raw(..) => Err(FileSystemError::AccessDenied),
+            ArchiveFsFileContext::Archive { index, file, .. } => {
+                let new_path = super::concat_path(&self.handler.in_path.path, new_path);
+                let new_path = new_path.as_non_owned();
+                // Entries can only be moved inside the same archive
+                let (front_path, back_path, _) =
+                    split_archive_path(new_path, &self.handler.archive_rules)
+                        .ok_or(FileSystemError::AccessDenied)?;
+                if CaselessStr::new(front_path.get_path()) != &**index {
+                    return Err(FileSystemError::AccessDenied);
+                }
+                let file = unsafe { &**file };
+                file.move_to(back_path, replace_if_exists)
+            }
+        }
     }
     fn find_files_with_pattern(
         &self,
@@ -834,10 +1406,12 @@ impl super::File for ArchiveFsFile<'_, '_> {
         filler: &mut dyn super::FindFilesDataFiller,
     ) -> super::FileSystemResult<()> {
         match &self.context {
-            ArchiveFsFileContext::Raw(f) => {
+            ArchiveFsFileContext::Raw(f, path) => {
                 struct ArchiveFsFiller<'a, 'b, 'h> {
                     this: &'a ArchiveFsFile<'b, 'h>,
                     filler: &'a mut dyn super::FindFilesDataFiller,
+                    // Files whose content decides whether they are archives
+                    sniff_pending: Vec<(String, super::FileStatInfo, &'h ArchiveOpenRuleConfig)>,
                 }
                 impl super::FindFilesDataFiller for ArchiveFsFiller<'_, '_, '_> {
                     fn fill_data(
@@ -856,19 +1430,40 @@ impl super::File for ArchiveFsFile<'_, '_> {
                             }
                         };
                         match is_name_archive(name, &self.this.handler.archive_rules) {
+                            // NOTE: Files are only opened once listing is done, as the
+                            //       folder may be locked meanwhile
+                            Some(rule) if check_file_kind_fn(rule) && rule.sniff_content => {
+                                self.sniff_pending.push((name.to_owned(), *stat, rule));
+                                Ok(())
+                            }
                             Some(rule) if check_file_kind_fn(rule) => {
-                                // We need to transform files into folders here
-                                let mut stat = *stat;
-                                stat.is_dir = true;
-                                stat.attributes |= FileAttributes::DirectoryFile;
-                                stat.size = 0;
-                                self.filler.fill_data(name, &stat)
+                                self.filler.fill_data(name, &get_archive_folder_stat(stat))
                             }
                             _ => self.filler.fill_data(name, stat),
                         }
                     }
                 }
-                f.find_files_with_pattern(pattern, &mut ArchiveFsFiller { this: self, filler })
+                let mut archive_filler = ArchiveFsFiller {
+                    this: self,
+                    filler,
+                    sniff_pending: Vec::new(),
+                };
+                f.find_files_with_pattern(pattern, &mut archive_filler)?;
+                for (name, stat, rule) in archive_filler.sniff_pending {
+                    let file_path = super::concat_path(
+                        path,
+                        super::SegPath::new(&name, super::PathDelimiter::BackSlash),
+                    );
+                    let stat = if self.handler.sniff_archive(file_path.as_non_owned(), rule) {
+                        get_archive_folder_stat(&stat)
+                    } else {
+                        stat
+                    };
+                    if archive_filler.filler.fill_data(&name, &stat).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
             }
             ArchiveFsFileContext::Archive { file, .. } => {
                 let file = unsafe { &**file };
@@ -882,11 +1477,37 @@ impl super::File for ArchiveFsFile<'_, '_> {
 #[serde(rename_all = "snake_case")]
 enum ArchiveHandlerKind {
     Zip,
+    Tar,
+    // Tarballs compressed with gzip, bzip2, xz or zstd
+    CompressedTar,
+    // Lone compressed files (gzip, bzip2, xz or zstd)
+    Compressed,
+    SevenZip,
+    // RAR 4 and RAR 5 archives, read-only
+    Rar,
+    // Optical disc images (ISO 9660 with Joliet / Rock Ridge, or UDF)
+    Iso,
+}
+
+impl ArchiveHandlerKind {
+    // Checks whether the content looks like an archive of this kind
+    fn sniff(&self, file: &dyn super::File) -> bool {
+        match self {
+            Self::Zip => zip::sniff(file),
+            Self::Tar => tar::sniff(file),
+            Self::CompressedTar | Self::Compressed => stream::sniff(file),
+            Self::SevenZip => sevenzip::sniff(file),
+            Self::Rar => rar::sniff(file),
+            Self::Iso => iso::sniff(file),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ArchiveOpenRuleConfig {
-    // TODO: Describe haystack format
+    /// Matched against paths on the input file system, which start with the input
+    /// path and use `\` as delimiter without a leading one (e.g. `data\sub\a.zip` for
+    /// an input path of `data`). Every prefix ending at a delimiter is tried in turn.
     // WARN: Should only be used to check against file suffix;
     //       won't be applied if path is already a folder
     #[serde(with = "serde_regex")]
@@ -894,6 +1515,61 @@ struct ArchiveOpenRuleConfig {
     handler_kind: ArchiveHandlerKind,
     handles_file: bool,
     handles_folder: bool,
+    /// Only treats matched files as archives if their content looks like one (e.g.
+    /// magic bytes), which allows broad patterns such as `\.exe$` for SFX archives.
+    ///
+    /// Not enabled for such patterns by default, as every matched file gets read. For
+    /// example, SFX executables and Java / Android packages can be opened as zips with
+    /// `{"path_pattern": "\\.(?i)(exe|jar|apk)$", "handler_kind": "zip",
+    /// "handles_file": true, "handles_folder": false, "sniff_content": true}`.
+    #[serde(default)]
+    sniff_content: bool,
+    /// Allows modifying matched archives (zip only).
+    #[serde(default)]
+    writable: Option<ArchiveWriteConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ArchiveWriteConfig {
+    /// Reclaims space taken by deleted or replaced entries when committing on unmount.
+    #[serde(default)]
+    compact_on_unmount: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+struct ArchiveCacheConfig {
+    /// Memory budget in MiB shared by decompressed data, live decoders and parsed
+    /// indexes of idle archives (`0` disables caching).
+    max_size_mb: u64,
+    /// Whether archives with no open file are kept open within the budget, instead of
+    /// being parsed again on the next access. Their files stay open on the host, and
+    /// cannot be deleted or replaced there until evicted.
+    keep_idle_archives: bool,
+}
+
+impl Default for ArchiveCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 256,
+            keep_idle_archives: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ArchiveIndexCacheConfig {
+    /// Folder on the host where parsed indexes are stored.
+    path: String,
+    /// Minimum number of entries for an archive index to be stored.
+    #[serde(default = "ArchiveIndexCacheConfig::default_min_entries")]
+    min_entries: u64,
+}
+
+impl ArchiveIndexCacheConfig {
+    fn default_min_entries() -> u64 {
+        10000
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -904,6 +1580,23 @@ struct ArchiveFsConfig {
     archive_rules: Vec<ArchiveOpenRuleConfig>,
     /// Non-Unicode compatibility configurations for archives.
     non_unicode_compat: ArchiveGlobalNonUnicodeCompatConfig,
+    /// Passwords for encrypted archives.
+    #[serde(default)]
+    passwords: ArchiveGlobalPasswordConfig,
+    /// How CRC-32 of zip entries is verified once they are read through
+    /// (`strict`, `warn` or `off`).
+    #[serde(default)]
+    crc_check: ArchiveCrcCheckMode,
+    /// Cache shared by all archives.
+    #[serde(default)]
+    cache: ArchiveCacheConfig,
+    /// On-disk cache of parsed indexes of large archives (zip only).
+    #[serde(default)]
+    index_cache: Option<ArchiveIndexCacheConfig>,
+    /// Name of a hidden virtual folder inside every opened archive, listing metadata
+    /// such as the archive comment as JSON files (e.g. `.archive-info`).
+    #[serde(default)]
+    info_folder: Option<String>,
 }
 
 impl ArchiveFsHandler {
@@ -911,12 +1604,28 @@ impl ArchiveFsHandler {
         in_path: FsWithPath,
         archive_rules: Vec<ArchiveOpenRuleConfig>,
         non_unicode_compat: ArchiveGlobalNonUnicodeCompatConfig,
+        passwords: ArchiveGlobalPasswordConfig,
+        crc_check: ArchiveCrcCheckMode,
+        cache_config: ArchiveCacheConfig,
+        index_cache: Option<ArchiveIndexCache>,
+        info_folder: Option<String>,
     ) -> Self {
         ArchiveFsHandler {
             in_path,
             open_archives: Mutex::new(BTreeMap::new()),
+            open_counter: AtomicU64::new(0),
             archive_rules,
+            encodings: ArchiveNameEncodings {
+                system_code_page: non_unicode_compat.system_code_page,
+                detected: Mutex::new(BTreeMap::new()),
+            },
             non_unicode_compat,
+            passwords,
+            crc_check,
+            cache: ArchiveCache::new(cache_config.max_size_mb.saturating_mul(1024 * 1024)),
+            keep_idle_archives: cache_config.keep_idle_archives,
+            index_cache,
+            info_folder,
         }
     }
 }
@@ -939,6 +1648,37 @@ impl super::FsProvider for ArchiveFsProvider {
     ) -> Result<std::sync::Arc<dyn super::FileSystemHandler>, super::FileSystemCreationError> {
         let mut config: ArchiveFsConfig = serde_json::from_value(config)
             .map_err(|e| super::FileSystemCreationError::Other(e.into()))?;
+        config
+            .non_unicode_compat
+            .validate()
+            .map_err(|e| super::FileSystemCreationError::InvalidConfig(e.to_string()))?;
+        if let Some(rule) = config
+            .archive_rules
+            .iter()
+            .find(|x| x.writable.is_some() && !matches!(x.handler_kind, ArchiveHandlerKind::Zip))
+        {
+            return Err(super::FileSystemCreationError::InvalidConfig(format!(
+                "archive rule `{}` cannot be writable, only zip archives support writing",
+                rule.path_pattern
+            )));
+        }
+        if let Some(name) = &config.info_folder {
+            if matches!(name.as_str(), "" | "." | "..") || name.contains(['/', '\\']) {
+                return Err(super::FileSystemCreationError::InvalidConfig(format!(
+                    "invalid archive info folder name `{name}`"
+                )));
+            }
+        }
+        let index_cache = config
+            .index_cache
+            .as_ref()
+            .map(ArchiveIndexCache::new)
+            .transpose()
+            .map_err(|e| {
+                super::FileSystemCreationError::InvalidConfig(format!(
+                    "cannot use index cache folder: {e}"
+                ))
+            })?;
         // Translate slashes
         super::make_uniform_path(&mut config.input_path.path);
         let in_path = FsWithPath {
@@ -949,18 +1689,93 @@ impl super::FsProvider for ArchiveFsProvider {
             in_path,
             config.archive_rules,
             config.non_unicode_compat,
+            config.passwords,
+            config.crc_check,
+            config.cache,
+            index_cache,
+            config.info_folder,
         )))
     }
     fn get_template_config(&self) -> serde_json::Value {
         serde_json::to_value(ArchiveFsConfig {
             input_path: Default::default(),
-            archive_rules: vec![ArchiveOpenRuleConfig {
-                path_pattern: Regex::new(r"\.(?i)zip$").unwrap(),
-                handler_kind: ArchiveHandlerKind::Zip,
-                handles_file: true,
-                handles_folder: false,
-            }],
+            archive_rules: vec![
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)zip$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::Zip,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+                // NOTE: Only the first piece of a split zip is treated as the archive
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)zip\.001$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::Zip,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)7z$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::SevenZip,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+                // NOTE: Sniffing skips volumes other than the first one (`x.part2.rar`),
+                //       which are opened along with it
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)rar$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::Rar,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: true,
+                    writable: None,
+                },
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)iso$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::Iso,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)tar$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::Tar,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+                // NOTE: Must come before the rule for lone compressed files
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)(tar\.(gz|bz2|xz|zst)|tgz|tbz2?|txz|tzst)$")
+                        .unwrap(),
+                    handler_kind: ArchiveHandlerKind::CompressedTar,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+                ArchiveOpenRuleConfig {
+                    path_pattern: Regex::new(r"\.(?i)(gz|bz2|xz|zst)$").unwrap(),
+                    handler_kind: ArchiveHandlerKind::Compressed,
+                    handles_file: true,
+                    handles_folder: false,
+                    sniff_content: false,
+                    writable: None,
+                },
+            ],
             non_unicode_compat: Default::default(),
+            passwords: Default::default(),
+            crc_check: Default::default(),
+            cache: Default::default(),
+            index_cache: None,
+            info_folder: None,
         })
         .unwrap()
     }
@@ -971,3 +1786,66 @@ impl ArchiveFsProvider {
         ArchiveFsProvider {}
     }
 }
+
+// Helpers shared by tests of archive handlers
+#[cfg(test)]
+mod test_util {
+    use std::time::SystemTime;
+
+    use crate::fs_provider::{
+        File, FileAttributes, FilePattern, FileStatInfo, FileSystemError, FileSystemResult,
+        FindFilesDataFiller, SegPath,
+    };
+
+    pub(super) struct MemFile(pub(super) Vec<u8>);
+
+    impl File for MemFile {
+        fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+            let start = (offset as usize).min(self.0.len());
+            let len = buffer.len().min(self.0.len() - start);
+            buffer[..len].copy_from_slice(&self.0[start..start + len]);
+            Ok(len as _)
+        }
+        fn write_at(&self, _: Option<u64>, _: &[u8], _: bool) -> FileSystemResult<u64> {
+            Err(FileSystemError::AccessDenied)
+        }
+        fn flush_buffers(&self) -> FileSystemResult<()> {
+            Ok(())
+        }
+        fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+            Ok(FileStatInfo {
+                index: 0,
+                size: self.0.len() as _,
+                is_dir: false,
+                attributes: FileAttributes::empty(),
+                creation_time: SystemTime::UNIX_EPOCH,
+                last_access_time: SystemTime::UNIX_EPOCH,
+                last_write_time: SystemTime::UNIX_EPOCH,
+            })
+        }
+        fn set_end_of_file(&self, _: u64) -> FileSystemResult<()> {
+            Err(FileSystemError::AccessDenied)
+        }
+        fn set_file_times(
+            &self,
+            _: SystemTime,
+            _: SystemTime,
+            _: SystemTime,
+        ) -> FileSystemResult<()> {
+            Err(FileSystemError::AccessDenied)
+        }
+        fn set_delete(&self, _: bool) -> FileSystemResult<()> {
+            Err(FileSystemError::AccessDenied)
+        }
+        fn move_to(&self, _: SegPath, _: bool) -> FileSystemResult<()> {
+            Err(FileSystemError::AccessDenied)
+        }
+        fn find_files_with_pattern(
+            &self,
+            _: &dyn FilePattern,
+            _: &mut dyn FindFilesDataFiller,
+        ) -> FileSystemResult<()> {
+            Err(FileSystemError::NotADirectory)
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// (owner, item, block), where owner identifies one opened archive instance
+type ArchiveCacheKey = (u64, u64, u64);
+
+// Key of the entry charging the parsed index of an idle archive
+const IDLE_ARCHIVE_ITEM: (u64, u64) = (u64::MAX, u64::MAX);
+// Item of the entries charging live decoders, keyed by decoder id
+const DECODER_ITEM: u64 = u64::MAX - 1;
+// Live decoders kept per archive, even if the cache has room for more
+const MAX_LIVE_DECODERS: usize = 4;
+
+struct ArchiveCacheEntry {
+    // NOTE: Entries of idle archives and live decoders only charge their size, the
+    //       data stays with the archive
+    data: Option<Arc<[u8]>>,
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct ArchiveCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub evicted_size: u64,
+    pub idle_archive_evictions: u64,
+    pub used_size: u64,
+}
+
+#[derive(Default)]
+struct ArchiveCacheState {
+    entries: BTreeMap<ArchiveCacheKey, ArchiveCacheEntry>,
+    // Keys ordered by last use, oldest first
+    lru: BTreeMap<u64, ArchiveCacheKey>,
+    tick: u64,
+    stats: ArchiveCacheStats,
+}
+
+impl ArchiveCacheState {
+    fn touch(&mut self, key: ArchiveCacheKey) -> Option<&ArchiveCacheEntry> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&key)?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(tick, key);
+        entry.last_used = tick;
+        Some(entry)
+    }
+    fn remove(&mut self, key: ArchiveCacheKey) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.lru.remove(&entry.last_used);
+            self.stats.used_size -= entry.size;
+        }
+    }
+    fn insert(&mut self, key: ArchiveCacheKey, data: Option<Arc<[u8]>>, size: u64, max_size: u64) {
+        self.remove(key);
+        if size > max_size {
+            return;
+        }
+        // Evict least recently used entries until the new one fits
+        while self.stats.used_size + size > max_size {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            let Some(entry) = self.entries.remove(&oldest) else {
+                continue;
+            };
+            self.stats.used_size -= entry.size;
+            self.stats.evictions += 1;
+            self.stats.evicted_size += entry.size;
+            if (oldest.1, oldest.2) == IDLE_ARCHIVE_ITEM {
+                self.stats.idle_archive_evictions += 1;
+            }
+        }
+        self.tick += 1;
+        let last_used = self.tick;
+        self.entries.insert(
+            key,
+            ArchiveCacheEntry {
+                data,
+                size,
+                last_used,
+            },
+        );
+        self.lru.insert(last_used, key);
+        self.stats.used_size += size;
+    }
+}
+
+// Size-capped LRU cache shared by all archives of a handler, holding decompressed
+// data blocks and charging the parsed indexes of idle archives and live decoders
+pub(super) struct ArchiveCache {
+    max_size: u64,
+    next_owner: AtomicU64,
+    state: Mutex<ArchiveCacheState>,
+}
+
+impl ArchiveCache {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            max_size,
+            next_owner: AtomicU64::new(0),
+            state: Mutex::new(Default::default()),
+        }
+    }
+    // Allocates the owner id of a newly opened archive
+    pub fn new_owner(&self) -> u64 {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+    pub fn get_max_size(&self) -> u64 {
+        self.max_size
+    }
+    pub fn get_stats(&self) -> ArchiveCacheStats {
+        self.state.lock().unwrap().stats
+    }
+    // Drops everything cached for an archive, called once the archive is closed
+    pub fn remove_owner(&self, owner: u64) {
+        let mut state = self.state.lock().unwrap();
+        let keys = state
+            .entries
+            .range((owner, 0, 0)..=(owner, u64::MAX, u64::MAX))
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        for key in keys {
+            state.remove(key);
+        }
+    }
+    // Charges the parsed index of an archive with no open file, returning false if
+    // it does not fit into the cache at all
+    pub fn insert_idle_archive(&self, owner: u64, size: u64) -> bool {
+        if size > self.max_size {
+            return false;
+        }
+        let key = (owner, IDLE_ARCHIVE_ITEM.0, IDLE_ARCHIVE_ITEM.1);
+        let mut state = self.state.lock().unwrap();
+        state.insert(key, None, size, self.max_size);
+        true
+    }
+    // Whether an idle archive is still within the budget
+    pub fn contains_idle_archive(&self, owner: u64) -> bool {
+        let key = (owner, IDLE_ARCHIVE_ITEM.0, IDLE_ARCHIVE_ITEM.1);
+        self.state.lock().unwrap().entries.contains_key(&key)
+    }
+    pub fn remove_idle_archive(&self, owner: u64) {
+        let key = (owner, IDLE_ARCHIVE_ITEM.0, IDLE_ARCHIVE_ITEM.1);
+        self.state.lock().unwrap().remove(key);
+    }
+}
+
+// The part of the cache belonging to one archive
+#[derive(Clone, Copy)]
+pub(super) struct ArchiveCacheHandle<'a> {
+    cache: &'a ArchiveCache,
+    owner: u64,
+}
+
+impl<'a> ArchiveCacheHandle<'a> {
+    pub fn new(cache: &'a ArchiveCache, owner: u64) -> Self {
+        Self { cache, owner }
+    }
+    pub fn get(&self, item: u64, block: u64) -> Option<Arc<[u8]>> {
+        let mut state = self.cache.state.lock().unwrap();
+        let data = state
+            .touch((self.owner, item, block))
+            .and_then(|x| x.data.clone());
+        match data {
+            Some(_) => state.stats.hits += 1,
+            None => state.stats.misses += 1,
+        }
+        data
+    }
+    pub fn insert(&self, item: u64, block: u64, data: Arc<[u8]>) {
+        let size = data.len() as u64;
+        let mut state = self.cache.state.lock().unwrap();
+        state.insert(
+            (self.owner, item, block),
+            Some(data),
+            size,
+            self.cache.max_size,
+        );
+    }
+    // Drops all data of the archive, e.g. after its contents were moved around
+    pub fn clear(&self) {
+        self.cache.remove_owner(self.owner);
+    }
+    // Charges the memory of a live decoder, returning false if it does not fit into
+    // the cache at all
+    fn insert_decoder(&self, id: u64, size: u64) -> bool {
+        if size > self.cache.max_size {
+            return false;
+        }
+        let mut state = self.cache.state.lock().unwrap();
+        state.insert(
+            (self.owner, DECODER_ITEM, id),
+            None,
+            size,
+            self.cache.max_size,
+        );
+        true
+    }
+    // Whether a live decoder is still within the budget
+    fn contains_decoder(&self, id: u64) -> bool {
+        let key = (self.owner, DECODER_ITEM, id);
+        self.cache.state.lock().unwrap().entries.contains_key(&key)
+    }
+    fn remove_decoder(&self, id: u64) {
+        let key = (self.owner, DECODER_ITEM, id);
+        self.cache.state.lock().unwrap().remove(key);
+    }
+}
+
+struct LiveDecoder<D> {
+    id: u64,
+    stream: usize,
+    decoder: D,
+    out_pos: u64,
+    // Whether the decoder fits into the cache and is charged against it
+    is_charged: bool,
+}
+
+// Decoders of solid streams kept between reads, so that reading on does not decode
+// the stream from its start again. Their memory is charged against the cache, and
+// decoders evicted from it are dropped.
+// NOTE: A decoder larger than the whole cache is still kept (only one of them), as
+//       every read would have to decode from the start otherwise. Like all other
+//       decoders, it is dropped once the archive becomes idle.
+pub(super) struct LiveDecoderPool<'a, D> {
+    cache: Option<ArchiveCacheHandle<'a>>,
+    decoders: Mutex<Vec<LiveDecoder<D>>>,
+    next_id: AtomicU64,
+}
+
+impl<'a, D> LiveDecoderPool<'a, D> {
+    pub fn new(cache: Option<ArchiveCacheHandle<'a>>) -> Self {
+        Self {
+            cache,
+            decoders: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+    // Takes the decoder of `stream` furthest along without having passed `pos`,
+    // returning it with its output position
+    pub fn take(&self, stream: usize, pos: u64) -> Option<(D, u64)> {
+        let mut decoders = self.decoders.lock().unwrap();
+        if let Some(cache) = self.cache {
+            decoders.retain(|x| !x.is_charged || cache.contains_decoder(x.id));
+        }
+        let (idx, _) = decoders
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.stream == stream && x.out_pos <= pos)
+            .max_by_key(|(_, x)| x.out_pos)?;
+        let live = decoders.swap_remove(idx);
+        self.uncharge(&live);
+        Some((live.decoder, live.out_pos))
+    }
+    // Keeps a decoder for later reads, `size` being the memory it holds
+    pub fn put(&self, stream: usize, decoder: D, out_pos: u64, size: u64) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let is_charged = self.cache.is_some_and(|x| x.insert_decoder(id, size));
+        let mut decoders = self.decoders.lock().unwrap();
+        if self.cache.is_some() && !is_charged {
+            decoders.retain(|x| x.is_charged);
+        }
+        decoders.push(LiveDecoder {
+            id,
+            stream,
+            decoder,
+            out_pos,
+            is_charged,
+        });
+        if decoders.len() > MAX_LIVE_DECODERS {
+            // NOTE: Ids grow with each put, so the smallest one was put back first
+            let oldest = decoders
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, x)| x.id)
+                .map(|(i, _)| i);
+            if let Some(oldest) = oldest {
+                let live = decoders.swap_remove(oldest);
+                self.uncharge(&live);
+            }
+        }
+    }
+    // Drops all decoders, e.g. once the archive became idle
+    pub fn clear(&self) {
+        let mut decoders = self.decoders.lock().unwrap();
+        for live in decoders.iter() {
+            self.uncharge(live);
+        }
+        decoders.clear();
+    }
+    fn uncharge(&self, live: &LiveDecoder<D>) {
+        if let Some(cache) = self.cache.filter(|_| live.is_charged) {
+            cache.remove_decoder(live.id);
+        }
+    }
+}
+
+// Reads `buffer` at `offset` of a decompressed item through the cache, in blocks of
+// `block_size`. `read_fn` fills a whole block (or up to the end of the item).
+pub(super) fn read_cached_blocks<E>(
+    cache: ArchiveCacheHandle,
+    item: u64,
+    item_size: u64,
+    block_size: u64,
+    offset: u64,
+    buffer: &mut [u8],
+    mut read_fn: impl FnMut(u64, &mut [u8]) -> Result<u64, E>,
+) -> Result<u64, E> {
+    let mut total = 0;
+    while total < buffer.len() {
+        let pos = offset + total as u64;
+        if pos >= item_size {
+            break;
+        }
+        let block = pos / block_size;
+        let block_start = block * block_size;
+        let data = match cache.get(item, block) {
+            Some(data) => data,
+            None => {
+                let mut data = vec![0; block_size.min(item_size - block_start) as usize];
+                let len = read_fn(block_start, &mut data)? as usize;
+                let is_short = len < data.len();
+                data.truncate(len);
+                let data: Arc<[u8]> = data.into();
+                // NOTE: Short blocks mean corrupted data, so never keep them around
+                if !is_short {
+                    cache.insert(item, block, Arc::clone(&data));
+                }
+                data
+            }
+        };
+        let block_offset = (pos - block_start) as usize;
+        if block_offset >= data.len() {
+            break;
+        }
+        let len = (buffer.len() - total).min(data.len() - block_offset);
+        buffer[total..total + len].copy_from_slice(&data[block_offset..block_offset + len]);
+        total += len;
+    }
+    Ok(total as _)
+}
@@ -0,0 +1,171 @@
+// Decryption of encrypted zip entries (traditional PKWARE and WinZip AES)
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+pub const ZIP_CRYPTO_HEADER_SIZE: u64 = 12;
+
+pub const ZIP_AES_VERIFIER_SIZE: u64 = 2;
+pub const ZIP_AES_MAC_SIZE: u64 = 10;
+const ZIP_AES_KEY_ITERATIONS: u32 = 1000;
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize]
+}
+
+// Traditional PKWARE encryption (aka ZipCrypto)
+// NOTE: This is a stream cipher, so data can only be decrypted sequentially
+#[derive(Clone)]
+pub struct ZipCryptoKeys {
+    keys: [u32; 3],
+}
+
+impl ZipCryptoKeys {
+    fn new(password: &[u8]) -> Self {
+        let mut keys = ZipCryptoKeys {
+            keys: [0x12345678, 0x23456789, 0x34567890],
+        };
+        for &byte in password {
+            keys.update(byte);
+        }
+        keys
+    }
+    fn update(&mut self, byte: u8) {
+        let [key0, key1, key2] = &mut self.keys;
+        *key0 = crc32_update(*key0, byte);
+        *key1 = key1
+            .wrapping_add(*key0 & 0xff)
+            .wrapping_mul(134775813)
+            .wrapping_add(1);
+        *key2 = crc32_update(*key2, (*key1 >> 24) as u8);
+    }
+    fn stream_byte(&self) -> u8 {
+        let temp = (self.keys[2] | 2) & 0xffff;
+        ((temp * (temp ^ 1)) >> 8) as u8
+    }
+    pub fn decrypt(&mut self, buffer: &mut [u8]) {
+        for byte in buffer {
+            *byte ^= self.stream_byte();
+            self.update(*byte);
+        }
+    }
+    /// Decrypts the encryption header with `password`, returning the keys for
+    /// the following data if the header check byte matches.
+    pub fn with_header(
+        password: &[u8],
+        header: &[u8; ZIP_CRYPTO_HEADER_SIZE as usize],
+        check_byte: u8,
+    ) -> Option<Self> {
+        let mut keys = Self::new(password);
+        let mut header = *header;
+        keys.decrypt(&mut header);
+        (header[header.len() - 1] == check_byte).then_some(keys)
+    }
+}
+
+#[derive(Clone)]
+enum AesCipher {
+    Aes128(aes::Aes128),
+    Aes192(aes::Aes192),
+    Aes256(aes::Aes256),
+}
+
+impl AesCipher {
+    fn new(key: &[u8]) -> Self {
+        match key.len() {
+            16 => Self::Aes128(aes::Aes128::new(GenericArray::from_slice(key))),
+            24 => Self::Aes192(aes::Aes192::new(GenericArray::from_slice(key))),
+            _ => Self::Aes256(aes::Aes256::new(GenericArray::from_slice(key))),
+        }
+    }
+    fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Self::Aes128(c) => c.encrypt_block(block),
+            Self::Aes192(c) => c.encrypt_block(block),
+            Self::Aes256(c) => c.encrypt_block(block),
+        }
+    }
+}
+
+pub type ZipAesMac = Hmac<Sha1>;
+
+// WinZip AES encryption (AE-1 / AE-2)
+// NOTE: CTR mode with a little-endian counter starting at 1, which allows
+//       decrypting at arbitrary offsets
+#[derive(Clone)]
+pub struct ZipAesDecryptor {
+    cipher: AesCipher,
+}
+
+impl ZipAesDecryptor {
+    /// Returns the key length for the strength value stored in the extra field.
+    pub fn get_key_len(strength: u8) -> Option<usize> {
+        match strength {
+            1 => Some(16),
+            2 => Some(24),
+            3 => Some(32),
+            _ => None,
+        }
+    }
+    pub fn get_salt_len(key_len: usize) -> usize {
+        key_len / 2
+    }
+    /// Derives the keys from `password` and `salt`, returning the decryptor
+    /// and a MAC for authenticating the encrypted data if the verifier matches.
+    pub fn new(
+        password: &[u8],
+        key_len: usize,
+        salt: &[u8],
+        verifier: [u8; ZIP_AES_VERIFIER_SIZE as usize],
+    ) -> Option<(Self, ZipAesMac)> {
+        let mut derived = vec![0u8; key_len * 2 + ZIP_AES_VERIFIER_SIZE as usize];
+        pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, ZIP_AES_KEY_ITERATIONS, &mut derived);
+        if derived[key_len * 2..] != verifier {
+            return None;
+        }
+        let mac = <ZipAesMac as Mac>::new_from_slice(&derived[key_len..key_len * 2]).ok()?;
+        Some((
+            ZipAesDecryptor {
+                cipher: AesCipher::new(&derived[..key_len]),
+            },
+            mac,
+        ))
+    }
+    /// Decrypts `buffer`, which starts at `offset` within the encrypted data.
+    pub fn decrypt_at(&self, offset: u64, buffer: &mut [u8]) {
+        let mut keystream = [0u8; 16];
+        let mut block_index = u64::MAX;
+        for (pos, byte) in (offset..).zip(buffer.iter_mut()) {
+            if pos / 16 != block_index {
+                block_index = pos / 16;
+                keystream = (block_index as u128 + 1).to_le_bytes();
+                self.cipher.encrypt_block(&mut keystream);
+            }
+            *byte ^= keystream[(pos % 16) as usize];
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// On-disk cache of parsed archive indexes, so that large archives are opened
+// quickly again after a restart
+// NOTE: Every entry records what the archive looked like when it was parsed, and
+//       is ignored (then overwritten) once it no longer matches
+
+use std::{
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::ArchiveIndexCacheConfig;
+
+// NOTE: Bump when the layout of any cached index changes
+const INDEX_CACHE_VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub(super) struct ArchiveIndexKey {
+    pub path: String,
+    pub size: u64,
+    pub last_write_time: SystemTime,
+    pub file_index: u64,
+    // Checksum of archive headers, catching rewrites keeping size and time
+    pub header_hash: u32,
+    // Settings the index depends on, such as how names are decoded
+    pub settings: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveIndexHeader {
+    version: u32,
+    kind: String,
+    key: ArchiveIndexKey,
+}
+
+pub(super) struct ArchiveIndexCache {
+    dir: PathBuf,
+    min_entries: u64,
+}
+
+impl ArchiveIndexCache {
+    pub fn new(config: &ArchiveIndexCacheConfig) -> anyhow::Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            min_entries: config.min_entries,
+        })
+    }
+    // Archives with fewer entries are parsed quickly enough anyway
+    pub fn should_cache(&self, entries: u64) -> bool {
+        entries >= self.min_entries
+    }
+    fn get_file_path(&self, kind: &str, path: &str) -> PathBuf {
+        // NOTE: Colliding names are fine, as keys are compared on load
+        let mut crc = flate2::Crc::new();
+        crc.update(path.as_bytes());
+        self.dir.join(format!(
+            "{kind}-{:08x}{:08x}.idx",
+            crc.sum(),
+            path.len() as u32
+        ))
+    }
+    pub fn load<T: DeserializeOwned>(&self, kind: &str, key: &ArchiveIndexKey) -> Option<T> {
+        let file_path = self.get_file_path(kind, &key.path);
+        let file = std::fs::File::open(&file_path).ok()?;
+        // NOTE: Limit allocations to the file size, in case the entry is corrupted
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(file.metadata().ok()?.len());
+        let mut reader = BufReader::new(file);
+        let header: ArchiveIndexHeader = options.deserialize_from(&mut reader).ok()?;
+        if header.version != INDEX_CACHE_VERSION || header.kind != kind || header.key != *key {
+            return None;
+        }
+        match options.deserialize_from(&mut reader) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                log::warn!("Ignoring broken index cache `{}`: {e}", file_path.display());
+                None
+            }
+        }
+    }
+    pub fn store<T: Serialize>(&self, kind: &str, key: ArchiveIndexKey, index: &T) {
+        let file_path = self.get_file_path(kind, &key.path);
+        let header = ArchiveIndexHeader {
+            version: INDEX_CACHE_VERSION,
+            kind: kind.to_owned(),
+            key,
+        };
+        // Write into a temporary file first, so that no partial entry is ever loaded
+        // NOTE: The temporary file is removed on drop if anything fails
+        let result = (|| -> anyhow::Result<()> {
+            let options = bincode::DefaultOptions::new().with_fixint_encoding();
+            let mut writer = BufWriter::new(tempfile::NamedTempFile::new_in(&self.dir)?);
+            options.serialize_into(&mut writer, &header)?;
+            options.serialize_into(&mut writer, index)?;
+            writer.into_inner()?.persist(&file_path)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            log::warn!("Failed to write index cache `{}`: {e}", file_path.display());
+        }
+    }
+}
@@ -0,0 +1,568 @@
+// Resumable raw deflate (and Deflate64) decoder with checkpoint support for random access
+// NOTE: Output is produced incrementally and the decoder always stops at block
+//       boundaries, so that callers can snapshot the sliding window there
+
+use std::io::Read;
+
+const INPUT_BUFFER_SIZE: usize = 32 * 1024;
+
+// NOTE: Checkpoints are thinned out (and the interval doubled) once the limit
+//       is reached, keeping index memory bounded regardless of the stream size
+const CHECKPOINT_INITIAL_INTERVAL: u64 = 4 * 1024 * 1024;
+const CHECKPOINT_MAX_COUNT: usize = 64;
+
+const MAX_CODE_BITS: usize = 15;
+const FAST_BITS: u32 = 10;
+
+const MAX_LITLEN_CODES: usize = 288;
+const MAX_DIST_CODES: usize = 32;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// NOTE: The last two distance codes are only valid for Deflate64
+const DIST_BASE: [u16; 32] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577, 32769, 49153,
+];
+const DIST_EXTRA: [u8; 32] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13, 14, 14,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateFormat {
+    Deflate,
+    // Enhanced Deflate (zip method 9), with a 64KB window and longer matches
+    Deflate64,
+}
+
+impl InflateFormat {
+    fn window_size(self) -> usize {
+        match self {
+            Self::Deflate => 32 * 1024,
+            Self::Deflate64 => 64 * 1024,
+        }
+    }
+    fn dist_code_count(self) -> usize {
+        match self {
+            Self::Deflate => 30,
+            Self::Deflate64 => 32,
+        }
+    }
+    // Returns the base and number of extra bits for a length symbol (minus 257)
+    fn length_code(self, symbol: usize) -> Option<(u16, u8)> {
+        match (self, symbol) {
+            (Self::Deflate64, 28) => Some((3, 16)),
+            _ if symbol < LENGTH_BASE.len() => Some((LENGTH_BASE[symbol], LENGTH_EXTRA[symbol])),
+            _ => None,
+        }
+    }
+}
+
+// Canonical Huffman decoding table
+// NOTE: Short codes are resolved with a single lookup, longer ones fall back
+//       to walking the code lengths bit by bit
+struct Huffman {
+    counts: [u16; MAX_CODE_BITS + 1],
+    symbols: Vec<u16>,
+    // (symbol << 4) | length, 0 means not present
+    fast: Box<[u16; 1 << FAST_BITS]>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> anyhow::Result<Self> {
+        let mut counts = [0u16; MAX_CODE_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left: i32 = 1;
+        for len in 1..=MAX_CODE_BITS {
+            left = (left << 1) - counts[len] as i32;
+            if left < 0 {
+                anyhow::bail!("over-subscribed huffman code");
+            }
+        }
+
+        let mut offsets = [0u16; MAX_CODE_BITS + 2];
+        for len in 1..=MAX_CODE_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as _;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        // Fill the lookup table with bit-reversed canonical codes
+        let mut fast = Box::new([0u16; 1 << FAST_BITS]);
+        let mut next_code = [0u32; MAX_CODE_BITS + 1];
+        let mut code = 0u32;
+        for len in 1..=MAX_CODE_BITS {
+            code = (code + counts[len - 1] as u32) << 1;
+            next_code[len] = code;
+        }
+        for (symbol, &len) in lengths.iter().enumerate() {
+            let len = len as u32;
+            if len == 0 {
+                continue;
+            }
+            let code = next_code[len as usize];
+            next_code[len as usize] += 1;
+            if len > FAST_BITS {
+                continue;
+            }
+            let reversed = code.reverse_bits() >> (32 - len);
+            let entry = ((symbol as u16) << 4) | len as u16;
+            let mut idx = reversed as usize;
+            while idx < fast.len() {
+                fast[idx] = entry;
+                idx += 1 << len;
+            }
+        }
+
+        Ok(Huffman {
+            counts,
+            symbols,
+            fast,
+        })
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; MAX_LITLEN_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let litlen = Huffman::new(&lengths).unwrap();
+    let dist = Huffman::new(&[5u8; MAX_DIST_CODES]).unwrap();
+    (litlen, dist)
+}
+
+struct BitReader<R> {
+    reader: R,
+    buffer: Box<[u8]>,
+    buffer_pos: usize,
+    buffer_len: usize,
+    bits: u64,
+    bit_count: u32,
+    // Number of bytes moved into `bits` so far, relative to the stream start
+    byte_pos: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(reader: R, byte_pos: u64) -> Self {
+        BitReader {
+            reader,
+            buffer: vec![0; INPUT_BUFFER_SIZE].into_boxed_slice(),
+            buffer_pos: 0,
+            buffer_len: 0,
+            bits: 0,
+            bit_count: 0,
+            byte_pos,
+        }
+    }
+    fn bit_pos(&self) -> u64 {
+        self.byte_pos * 8 - self.bit_count as u64
+    }
+    fn next_byte(&mut self) -> std::io::Result<Option<u8>> {
+        if self.buffer_pos == self.buffer_len {
+            self.buffer_pos = 0;
+            self.buffer_len = loop {
+                match self.reader.read(&mut self.buffer) {
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    result => break result?,
+                }
+            };
+            if self.buffer_len == 0 {
+                return Ok(None);
+            }
+        }
+        let byte = self.buffer[self.buffer_pos];
+        self.buffer_pos += 1;
+        self.byte_pos += 1;
+        Ok(Some(byte))
+    }
+    // Fills up to `count` bits, returning false on end of stream
+    fn fill(&mut self, count: u32) -> std::io::Result<bool> {
+        while self.bit_count < count {
+            match self.next_byte()? {
+                Some(byte) => {
+                    self.bits |= (byte as u64) << self.bit_count;
+                    self.bit_count += 8;
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+    fn read_bits(&mut self, count: u32) -> anyhow::Result<u32> {
+        if count == 0 {
+            return Ok(0);
+        }
+        if !self.fill(count)? {
+            anyhow::bail!("unexpected end of deflate stream");
+        }
+        let value = (self.bits & ((1u64 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+    fn align_to_byte(&mut self) {
+        let count = self.bit_count % 8;
+        self.bits >>= count;
+        self.bit_count -= count;
+    }
+    fn decode(&mut self, table: &Huffman) -> anyhow::Result<u16> {
+        // NOTE: Near the end of stream fewer bits may be available than the
+        //       longest code, which is fine as long as the code itself fits
+        self.fill(MAX_CODE_BITS as _)?;
+        let entry = table.fast[(self.bits & ((1 << FAST_BITS) - 1)) as usize];
+        let len = (entry & 0xf) as u32;
+        if entry != 0 && len <= self.bit_count {
+            self.bits >>= len;
+            self.bit_count -= len;
+            return Ok(entry >> 4);
+        }
+
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_CODE_BITS as u32 {
+            if len > self.bit_count {
+                break;
+            }
+            code |= ((self.bits >> (len - 1)) & 1) as i32;
+            let count = table.counts[len as usize] as i32;
+            if code - count < first {
+                self.bits >>= len;
+                self.bit_count -= len;
+                return Ok(table.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        anyhow::bail!("invalid huffman code in deflate stream");
+    }
+}
+
+enum InflateState {
+    BlockHeader,
+    Stored { remaining: usize },
+    Huffman { copy_len: usize, copy_dist: usize },
+    Done,
+}
+
+/// Snapshot of the decoder taken at a block boundary.
+pub struct InflateCheckpoint {
+    format: InflateFormat,
+    in_bit_pos: u64,
+    out_pos: u64,
+    // The last `min(out_pos, window size)` bytes of output
+    window: Box<[u8]>,
+}
+
+impl InflateCheckpoint {
+    pub fn get_out_pos(&self) -> u64 {
+        self.out_pos
+    }
+    /// Returns the byte offset (relative to the stream start) where the input
+    /// reader must be positioned when resuming from this checkpoint.
+    pub fn get_in_byte_pos(&self) -> u64 {
+        self.in_bit_pos / 8
+    }
+}
+
+pub struct Inflater<R> {
+    format: InflateFormat,
+    input: BitReader<R>,
+    state: InflateState,
+    is_final_block: bool,
+    tables: Option<(Huffman, Huffman)>,
+    window: Box<[u8]>,
+    window_mask: usize,
+    out_pos: u64,
+}
+
+impl<R: Read> Inflater<R> {
+    pub fn new(reader: R, format: InflateFormat) -> Self {
+        Inflater {
+            format,
+            input: BitReader::new(reader, 0),
+            state: InflateState::BlockHeader,
+            is_final_block: false,
+            tables: None,
+            window: vec![0; format.window_size()].into_boxed_slice(),
+            window_mask: format.window_size() - 1,
+            out_pos: 0,
+        }
+    }
+    /// Resumes decoding from `checkpoint`. `reader` must be positioned at
+    /// `checkpoint.get_in_byte_pos()`.
+    pub fn with_checkpoint(reader: R, checkpoint: &InflateCheckpoint) -> anyhow::Result<Self> {
+        let mut inflater = Inflater::new(reader, checkpoint.format);
+        inflater.input.byte_pos = checkpoint.get_in_byte_pos();
+        inflater.out_pos = checkpoint.out_pos;
+        inflater.input.read_bits((checkpoint.in_bit_pos % 8) as _)?;
+        let window_mask = inflater.window_mask;
+        let window_len = checkpoint.window.len();
+        let window_start = (checkpoint.out_pos as usize).wrapping_sub(window_len) & window_mask;
+        for (i, &byte) in checkpoint.window.iter().enumerate() {
+            inflater.window[(window_start + i) & window_mask] = byte;
+        }
+        Ok(inflater)
+    }
+    pub fn get_out_pos(&self) -> u64 {
+        self.out_pos
+    }
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, InflateState::Done)
+    }
+    /// Returns the number of input bytes consumed so far, rounded up to a
+    /// whole byte (i.e. where trailing data starts once the stream is done).
+    pub fn get_in_byte_pos(&self) -> u64 {
+        (self.input.bit_pos() + 7) / 8
+    }
+    /// Returns whether a checkpoint can be taken at the current position.
+    pub fn is_at_block_boundary(&self) -> bool {
+        matches!(self.state, InflateState::BlockHeader)
+    }
+    pub fn checkpoint(&self) -> Option<InflateCheckpoint> {
+        if !self.is_at_block_boundary() {
+            return None;
+        }
+        let window_len = self.out_pos.min(self.window.len() as _) as usize;
+        let window_start = (self.out_pos as usize).wrapping_sub(window_len) & self.window_mask;
+        let window = (0..window_len)
+            .map(|i| self.window[(window_start + i) & self.window_mask])
+            .collect();
+        Some(InflateCheckpoint {
+            format: self.format,
+            in_bit_pos: self.input.bit_pos(),
+            out_pos: self.out_pos,
+            window,
+        })
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.window[self.out_pos as usize & self.window_mask] = byte;
+        self.out_pos += 1;
+    }
+    fn end_block(&mut self) {
+        self.tables = None;
+        self.state = if self.is_final_block {
+            InflateState::Done
+        } else {
+            InflateState::BlockHeader
+        };
+    }
+    fn read_block_header(&mut self) -> anyhow::Result<()> {
+        self.is_final_block = self.input.read_bits(1)? != 0;
+        match self.input.read_bits(2)? {
+            0 => {
+                self.input.align_to_byte();
+                let len = self.input.read_bits(16)?;
+                let nlen = self.input.read_bits(16)?;
+                if len != !nlen & 0xffff {
+                    anyhow::bail!("stored block length mismatch in deflate stream");
+                }
+                self.state = InflateState::Stored {
+                    remaining: len as _,
+                };
+            }
+            1 => {
+                self.tables = Some(fixed_tables());
+                self.state = InflateState::Huffman {
+                    copy_len: 0,
+                    copy_dist: 0,
+                };
+            }
+            2 => {
+                self.tables = Some(self.read_dynamic_tables()?);
+                self.state = InflateState::Huffman {
+                    copy_len: 0,
+                    copy_dist: 0,
+                };
+            }
+            _ => anyhow::bail!("invalid block type in deflate stream"),
+        }
+        Ok(())
+    }
+    fn read_dynamic_tables(&mut self) -> anyhow::Result<(Huffman, Huffman)> {
+        let litlen_count = self.input.read_bits(5)? as usize + 257;
+        let dist_count = self.input.read_bits(5)? as usize + 1;
+        let code_length_count = self.input.read_bits(4)? as usize + 4;
+        if litlen_count > 286 || dist_count > self.format.dist_code_count() {
+            anyhow::bail!("too many codes in deflate stream");
+        }
+
+        let mut lengths = [0u8; 19];
+        for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+            lengths[i] = self.input.read_bits(3)? as _;
+        }
+        let code_length_table = Huffman::new(&lengths)?;
+
+        let mut lengths = [0u8; MAX_LITLEN_CODES + MAX_DIST_CODES];
+        let total = litlen_count + dist_count;
+        let mut i = 0;
+        while i < total {
+            let symbol = self.input.decode(&code_length_table)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    if i == 0 {
+                        anyhow::bail!("repeat with no previous length in deflate stream");
+                    }
+                    (lengths[i - 1], 3 + self.input.read_bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.read_bits(3)? as usize),
+                _ => (0, 11 + self.input.read_bits(7)? as usize),
+            };
+            if i + repeat > total {
+                anyhow::bail!("too many code lengths in deflate stream");
+            }
+            lengths[i..i + repeat].fill(value);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            anyhow::bail!("missing end-of-block code in deflate stream");
+        }
+
+        let litlen = Huffman::new(&lengths[..litlen_count])?;
+        let dist = Huffman::new(&lengths[litlen_count..total])?;
+        Ok((litlen, dist))
+    }
+
+    /// Decompresses into `buffer`, returning the number of bytes produced.
+    /// Stops early at block boundaries; returns 0 only at the end of stream.
+    pub fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let mut written = 0;
+        while written < buffer.len() {
+            match self.state {
+                InflateState::BlockHeader => {
+                    if written > 0 {
+                        break;
+                    }
+                    self.read_block_header()?;
+                }
+                InflateState::Done => break,
+                InflateState::Stored { remaining } => {
+                    if remaining == 0 {
+                        self.end_block();
+                        continue;
+                    }
+                    let byte = self.input.read_bits(8)? as u8;
+                    self.push_byte(byte);
+                    buffer[written] = byte;
+                    written += 1;
+                    self.state = InflateState::Stored {
+                        remaining: remaining - 1,
+                    };
+                }
+                InflateState::Huffman {
+                    mut copy_len,
+                    copy_dist,
+                } => {
+                    if copy_len > 0 {
+                        while copy_len > 0 && written < buffer.len() {
+                            let byte = self.window[(self.out_pos as usize).wrapping_sub(copy_dist)
+                                & self.window_mask];
+                            self.push_byte(byte);
+                            buffer[written] = byte;
+                            written += 1;
+                            copy_len -= 1;
+                        }
+                        self.state = InflateState::Huffman {
+                            copy_len,
+                            copy_dist,
+                        };
+                        continue;
+                    }
+
+                    // SAFETY: Tables are always present in this state
+                    let (litlen, dist) = unsafe { self.tables.as_ref().unwrap_unchecked() };
+                    let symbol = self.input.decode(litlen)? as usize;
+                    if symbol < 256 {
+                        self.push_byte(symbol as _);
+                        buffer[written] = symbol as _;
+                        written += 1;
+                        continue;
+                    }
+                    if symbol == 256 {
+                        self.end_block();
+                        continue;
+                    }
+                    let Some((len_base, len_extra)) = self.format.length_code(symbol - 257) else {
+                        anyhow::bail!("invalid length code in deflate stream");
+                    };
+                    let len = len_base as usize + self.input.read_bits(len_extra as _)? as usize;
+                    let symbol = self.input.decode(dist)? as usize;
+                    if symbol >= self.format.dist_code_count() {
+                        anyhow::bail!("invalid distance code in deflate stream");
+                    }
+                    let distance = DIST_BASE[symbol] as usize
+                        + self.input.read_bits(DIST_EXTRA[symbol] as _)? as usize;
+                    if distance as u64 > self.out_pos {
+                        anyhow::bail!("distance too far back in deflate stream");
+                    }
+                    self.state = InflateState::Huffman {
+                        copy_len: len,
+                        copy_dist: distance,
+                    };
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Sparse list of checkpoints collected while decompressing a stream.
+pub struct InflateIndex {
+    checkpoints: Vec<InflateCheckpoint>,
+    interval: u64,
+}
+
+impl InflateIndex {
+    pub fn new() -> Self {
+        InflateIndex {
+            checkpoints: Vec::new(),
+            interval: CHECKPOINT_INITIAL_INTERVAL,
+        }
+    }
+    /// Returns the closest checkpoint at or before `offset`.
+    pub fn find(&self, offset: u64) -> Option<&InflateCheckpoint> {
+        let idx = self
+            .checkpoints
+            .partition_point(|x| x.get_out_pos() <= offset);
+        idx.checked_sub(1).map(|idx| &self.checkpoints[idx])
+    }
+    /// Records a checkpoint if the decoder has advanced far enough since the last one.
+    pub fn update<R: Read>(&mut self, inflater: &Inflater<R>) {
+        let last_pos = self.checkpoints.last().map_or(0, |x| x.get_out_pos());
+        if inflater.get_out_pos() < last_pos + self.interval {
+            return;
+        }
+        let Some(checkpoint) = inflater.checkpoint() else {
+            return;
+        };
+        self.checkpoints.push(checkpoint);
+        if self.checkpoints.len() > CHECKPOINT_MAX_COUNT {
+            let mut idx = 0;
+            self.checkpoints.retain(|_| {
+                idx += 1;
+                idx % 2 == 0
+            });
+            self.interval *= 2;
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// Hidden virtual folder inside every opened archive, listing metadata provided by
+// the archive handler (such as the archive comment) as JSON files
+// NOTE: Entries of the archive with the same name as the folder are shadowed
+
+use std::sync::Arc;
+
+use crate::{
+    fs_provider::{
+        FileAttributes, FileCreateDisposition, FileCreateOptions, FilePattern, FileStatInfo,
+        FileSystemError, FileSystemResult, FindFilesDataFiller, PathDelimiter, SegPath,
+    },
+    util::{calculate_hash, CaselessStr},
+};
+
+use super::{ArchiveFile, ArchiveHandler, ArchiveHandlerOpenFileInfo, OwnedArchiveFile};
+
+// The archive root, also listing the info folder
+struct ArchiveInfoRoot<'a> {
+    inner: OwnedArchiveFile<'a>,
+    folder_name: String,
+}
+
+impl ArchiveFile for ArchiveInfoRoot<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        self.inner.read_at(offset, buffer)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        self.inner.get_stat()
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn FilePattern,
+        filler: &mut dyn FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        self.inner.find_files_with_pattern(pattern, filler)?;
+        if pattern.check_name(&self.folder_name) {
+            let stat = get_folder_stat(&self.inner.get_stat()?, &self.folder_name);
+            if filler.fill_data(&self.folder_name, &stat).is_err() {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> FileSystemResult<u64> {
+        self.inner.write_at(offset, buffer, constrain_size)
+    }
+    fn set_end_of_file(&self, offset: u64) -> FileSystemResult<()> {
+        self.inner.set_end_of_file(offset)
+    }
+    fn set_file_times(
+        &self,
+        creation_time: std::time::SystemTime,
+        last_access_time: std::time::SystemTime,
+        last_write_time: std::time::SystemTime,
+    ) -> FileSystemResult<()> {
+        self.inner
+            .set_file_times(creation_time, last_access_time, last_write_time)
+    }
+    fn set_delete(&self, delete_on_close: bool) -> FileSystemResult<()> {
+        self.inner.set_delete(delete_on_close)
+    }
+    fn move_to(&self, new_path: SegPath, replace_if_exists: bool) -> FileSystemResult<()> {
+        self.inner.move_to(new_path, replace_if_exists)
+    }
+}
+
+// The info folder or a file inside
+// NOTE: Contents are generated only when a file is opened or listed
+enum ArchiveInfoEntry<'a> {
+    Folder {
+        archive: &'a dyn ArchiveHandler,
+        stat: FileStatInfo,
+    },
+    File {
+        data: Arc<[u8]>,
+        stat: FileStatInfo,
+    },
+}
+
+impl ArchiveFile for ArchiveInfoEntry<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let data = match self {
+            Self::Folder { .. } => return Err(FileSystemError::FileIsADirectory),
+            Self::File { data, .. } => data,
+        };
+        let offset = (offset as usize).min(data.len());
+        let len = buffer.len().min(data.len() - offset);
+        buffer[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len as _)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        match self {
+            Self::Folder { stat, .. } | Self::File { stat, .. } => Ok(*stat),
+        }
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn FilePattern,
+        filler: &mut dyn FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let (archive, folder_stat) = match self {
+            Self::Folder { archive, stat } => (archive, stat),
+            Self::File { .. } => return Err(FileSystemError::NotADirectory),
+        };
+        for name in archive.get_info_file_names() {
+            if !pattern.check_name(name) {
+                continue;
+            }
+            let Some(data) = archive.get_info_file(name)? else {
+                continue;
+            };
+            let stat = get_file_stat(folder_stat, name, &data);
+            if filler.fill_data(name, &stat).is_err() {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_folder_stat(root_stat: &FileStatInfo, folder_name: &str) -> FileStatInfo {
+    FileStatInfo {
+        index: calculate_hash(&(root_stat.index, folder_name)),
+        size: 0,
+        is_dir: true,
+        attributes: FileAttributes::DirectoryFile
+            | FileAttributes::Hidden
+            | FileAttributes::Readonly,
+        ..*root_stat
+    }
+}
+
+fn get_file_stat(folder_stat: &FileStatInfo, name: &str, data: &[u8]) -> FileStatInfo {
+    FileStatInfo {
+        index: calculate_hash(&(folder_stat.index, name)),
+        size: data.len() as _,
+        is_dir: false,
+        attributes: FileAttributes::Readonly,
+        ..*folder_stat
+    }
+}
+
+// Opens a file inside an archive, serving paths inside the info folder itself
+pub(super) fn create_file<'a>(
+    archive: &'a dyn ArchiveHandler,
+    folder_name: &str,
+    filename: SegPath,
+    create_disposition: FileCreateDisposition,
+    create_options: FileCreateOptions,
+) -> FileSystemResult<(ArchiveHandlerOpenFileInfo<'a>, bool)> {
+    let mut it = filename.into_iter();
+    let Some(first) = it.next() else {
+        let (root, new_file_created) =
+            archive.create_file(filename, create_disposition, create_options)?;
+        let root = ArchiveHandlerOpenFileInfo {
+            context: Box::new(ArchiveInfoRoot {
+                inner: root.context,
+                folder_name: folder_name.to_owned(),
+            }),
+            is_dir: root.is_dir,
+        };
+        return Ok((root, new_file_created));
+    };
+    if CaselessStr::new(first) != CaselessStr::new(folder_name) {
+        return archive.create_file(filename, create_disposition, create_options);
+    }
+
+    let root_stat = archive
+        .open_file(SegPath::new("", PathDelimiter::BackSlash))?
+        .context
+        .get_stat()?;
+    let folder_stat = get_folder_stat(&root_stat, folder_name);
+    let entry = match (it.next(), it.next()) {
+        (None, _) => ArchiveInfoEntry::Folder {
+            archive,
+            stat: folder_stat,
+        },
+        (Some(name), None) => {
+            let name = archive
+                .get_info_file_names()
+                .iter()
+                .find(|x| CaselessStr::new(x) == CaselessStr::new(name))
+                .ok_or(FileSystemError::ObjectNameNotFound)?;
+            let data = archive
+                .get_info_file(name)?
+                .ok_or(FileSystemError::ObjectNameNotFound)?;
+            let stat = get_file_stat(&folder_stat, name, &data);
+            ArchiveInfoEntry::File { data, stat }
+        }
+        _ => return Err(FileSystemError::ObjectPathNotFound),
+    };
+    let is_dir = matches!(entry, ArchiveInfoEntry::Folder { .. });
+    // NOTE: Nothing can be created inside the folder
+    Ok((
+        ArchiveHandlerOpenFileInfo {
+            context: Box::new(entry),
+            is_dir,
+        },
+        false,
+    ))
+}
@@ -0,0 +1,1395 @@
+// Optical disc image handler (ISO 9660 with Joliet / Rock Ridge, and UDF)
+// NOTE: Files in disc images are stored uncompressed, so reads are mapped
+//       directly onto extents of the image file without any caching.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Read,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    fs_provider::{
+        ContainerWarning, CursorFile, FileAttributes, FileStatInfo, FileSystemError,
+        FileSystemResult, SegPath,
+    },
+    util::{calculate_hash, CaselessStr, CaselessString},
+};
+
+use super::{
+    sanitize::sanitize_name,
+    tree::{insert_entry, join_path, TreeFolder, TreeWarnings},
+    ArchiveHandlerOpenContext,
+};
+
+const SECTOR_SIZE: u64 = 2048;
+const VOLUME_DESCRIPTOR_START: u64 = 16;
+// NOTE: Guards against images without a descriptor set terminator
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+const MAX_DIRECTORY_DEPTH: usize = 256;
+// NOTE: Directories are loaded into memory, so guard against bogus sizes
+const MAX_DIRECTORY_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_DESCRIPTOR_CONTINUATIONS: usize = 64;
+
+const ISO_DESCRIPTOR_PRIMARY: u8 = 1;
+const ISO_DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+
+const ISO_FLAG_HIDDEN: u8 = 0x01;
+const ISO_FLAG_DIRECTORY: u8 = 0x02;
+const ISO_FLAG_MULTI_EXTENT: u8 = 0x80;
+
+const UDF_ANCHOR_SECTOR: u64 = 256;
+
+const UDF_TAG_ANCHOR: u16 = 2;
+const UDF_TAG_PARTITION: u16 = 5;
+const UDF_TAG_LOGICAL_VOLUME: u16 = 6;
+const UDF_TAG_TERMINATING: u16 = 8;
+const UDF_TAG_FILE_SET: u16 = 256;
+const UDF_TAG_FILE_IDENTIFIER: u16 = 257;
+const UDF_TAG_ALLOCATION_EXTENT: u16 = 258;
+const UDF_TAG_FILE_ENTRY: u16 = 261;
+const UDF_TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const UDF_FILE_TYPE_UNSPECIFIED: u8 = 0;
+const UDF_FILE_TYPE_DIRECTORY: u8 = 4;
+const UDF_FILE_TYPE_REGULAR: u8 = 5;
+
+const UDF_FID_HIDDEN: u8 = 0x01;
+const UDF_FID_DIRECTORY: u8 = 0x02;
+const UDF_FID_DELETED: u8 = 0x04;
+const UDF_FID_PARENT: u8 = 0x08;
+
+const UDF_AD_SHORT: u16 = 0;
+const UDF_AD_LONG: u16 = 1;
+const UDF_AD_EMBEDDED: u16 = 3;
+
+const UDF_EXTENT_RECORDED: u32 = 0;
+const UDF_EXTENT_NEXT: u32 = 3;
+
+fn read_exact_at(
+    file: &dyn crate::fs_provider::File,
+    offset: u64,
+    buf: &mut [u8],
+) -> anyhow::Result<()> {
+    CursorFile::with_position(file, offset).read_exact(buf)?;
+    Ok(())
+}
+
+fn read_vec_at(
+    file: &dyn crate::fs_provider::File,
+    offset: u64,
+    len: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len.try_into()?];
+    read_exact_at(file, offset, &mut buf)?;
+    Ok(buf)
+}
+
+fn field(data: &[u8], offset: usize, len: usize) -> anyhow::Result<&[u8]> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| anyhow::anyhow!("truncated disc image structure"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> anyhow::Result<u16> {
+    Ok(u16::from_le_bytes(
+        field(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    Ok(u32::from_le_bytes(
+        field(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> anyhow::Result<u64> {
+    Ok(u64::from_le_bytes(
+        field(data, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+fn make_time(
+    year: i32,
+    month: u32,
+    day: u32,
+    hms: (u32, u32, u32),
+    nanos: u64,
+    tz_minutes: i32,
+) -> Option<SystemTime> {
+    use chrono::TimeZone;
+    let (hour, minute, second) = hms;
+    chrono::FixedOffset::east_opt(tz_minutes * 60)?
+        .with_ymd_and_hms(year, month, day, hour, minute, second)
+        .single()
+        .map(|t| SystemTime::from(t) + Duration::from_nanos(nanos))
+}
+
+#[derive(Clone, Copy)]
+struct IsoTimes {
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IsoExtent {
+    // Offset within the file
+    offset: u64,
+    size: u64,
+    // Offset of the data within the image, or `None` for unrecorded extents
+    // (which read as zeros)
+    data_offset: Option<u64>,
+}
+
+enum IsoFileData {
+    Extents(Vec<IsoExtent>),
+    // Data embedded in UDF file entries
+    Inline(Box<[u8]>),
+}
+
+struct IsoFolderEntry {
+    children: BTreeMap<CaselessString, IsoEntry>,
+    index: u64,
+    attributes: FileAttributes,
+    times: IsoTimes,
+}
+
+struct IsoFileEntry {
+    data: IsoFileData,
+    size: u64,
+    index: u64,
+    attributes: FileAttributes,
+    times: IsoTimes,
+}
+
+enum IsoEntry {
+    Folder(IsoFolderEntry),
+    File(IsoFileEntry),
+}
+
+impl IsoFolderEntry {
+    fn new(index: u64, attributes: FileAttributes, times: IsoTimes) -> Self {
+        IsoFolderEntry {
+            children: BTreeMap::new(),
+            index,
+            attributes,
+            times,
+        }
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: 0,
+            is_dir: true,
+            attributes: self.attributes | FileAttributes::DirectoryFile,
+            creation_time: self.times.creation_time,
+            last_access_time: self.times.last_access_time,
+            last_write_time: self.times.last_write_time,
+        }
+    }
+    // NOTE: Names are case-sensitive in Rock Ridge and UDF, but not here, so
+    //       colliding names are made unique
+    fn insert_child(
+        &mut self,
+        path: &str,
+        name: &str,
+        entry: IsoEntry,
+        warnings: &mut TreeWarnings,
+    ) {
+        if matches!(name, "" | "." | "..") {
+            warnings.add(path, "is skipped as its name is invalid".to_owned());
+            return;
+        }
+        let sanitized = sanitize_name(&name.replace(['/', '\\'], "_")).into_owned();
+        if sanitized != name {
+            warnings.add(path, format!("is renamed to `{sanitized}`"));
+        }
+        insert_entry(self, &sanitized, entry, path, warnings);
+    }
+}
+
+impl TreeFolder for IsoFolderEntry {
+    type Entry = IsoEntry;
+
+    fn children_mut(&mut self) -> &mut BTreeMap<CaselessString, IsoEntry> {
+        &mut self.children
+    }
+    fn new_implied_child(&self, index: u64) -> IsoEntry {
+        IsoEntry::Folder(IsoFolderEntry::new(
+            index,
+            FileAttributes::empty(),
+            self.times,
+        ))
+    }
+    fn entry_as_folder_mut(entry: &mut IsoEntry) -> Option<&mut Self> {
+        match entry {
+            IsoEntry::Folder(folder) => Some(folder),
+            IsoEntry::File(_) => None,
+        }
+    }
+    fn entry_is_folder(entry: &IsoEntry) -> bool {
+        matches!(entry, IsoEntry::Folder(_))
+    }
+}
+
+impl IsoFileEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: self.size,
+            is_dir: false,
+            attributes: self.attributes,
+            creation_time: self.times.creation_time,
+            last_access_time: self.times.last_access_time,
+            last_write_time: self.times.last_write_time,
+        }
+    }
+}
+
+impl IsoEntry {
+    fn as_borrowed(&self) -> BorrowedIsoEntry<'_> {
+        match self {
+            Self::Folder(e) => BorrowedIsoEntry::Folder(e),
+            Self::File(e) => BorrowedIsoEntry::File(e),
+        }
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        self.as_borrowed().get_file_stat_info()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum BorrowedIsoEntry<'a> {
+    Folder(&'a IsoFolderEntry),
+    File(&'a IsoFileEntry),
+}
+
+impl BorrowedIsoEntry<'_> {
+    fn is_dir(&self) -> bool {
+        matches!(self, Self::Folder(_))
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        match self {
+            Self::Folder(e) => e.get_file_stat_info(),
+            Self::File(e) => e.get_file_stat_info(),
+        }
+    }
+}
+
+// State shared while walking the directory tree of either file system
+struct IsoTreeBuilder {
+    root_index: u64,
+    // NOTE: Used for "unique" index generation
+    counter: u64,
+    default_times: IsoTimes,
+    warnings: TreeWarnings,
+}
+
+impl IsoTreeBuilder {
+    fn make_index(&mut self) -> u64 {
+        self.counter += 1;
+        calculate_hash(&(self.root_index, self.counter))
+    }
+}
+
+// ----- ISO 9660 -----
+
+struct IsoDirRecord<'r> {
+    extent: u32,
+    size: u32,
+    recording_time: &'r [u8],
+    flags: u8,
+    name: &'r [u8],
+    system_use: &'r [u8],
+}
+
+impl<'r> IsoDirRecord<'r> {
+    fn parse(data: &'r [u8]) -> anyhow::Result<Self> {
+        let name_len = *field(data, 32, 1)?.first().unwrap() as usize;
+        // NOTE: A padding byte keeps the system use area at an even offset
+        let system_use_start = 33 + name_len + (name_len + 1) % 2;
+        Ok(IsoDirRecord {
+            extent: read_u32(data, 2)?,
+            size: read_u32(data, 10)?,
+            recording_time: field(data, 18, 7)?,
+            flags: data[25],
+            name: field(data, 33, name_len)?,
+            system_use: data.get(system_use_start..).unwrap_or(&[]),
+        })
+    }
+    fn is_self_or_parent(&self) -> bool {
+        matches!(self.name, [0] | [1])
+    }
+}
+
+// Splits directory data into records, which never cross sector boundaries
+fn split_dir_records(data: &[u8], block_size: u64) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data[pos] as usize;
+        if len == 0 {
+            // Skip the remaining padding of the sector
+            pos = ((pos as u64 / block_size + 1) * block_size) as usize;
+            continue;
+        }
+        let Some(record) = data.get(pos..pos + len) else {
+            break;
+        };
+        records.push(record);
+        pos += len;
+    }
+    records
+}
+
+fn parse_iso_short_time(data: &[u8]) -> Option<SystemTime> {
+    let &[year, month, day, hour, minute, second, tz] = data else {
+        return None;
+    };
+    if month == 0 || day == 0 {
+        return None;
+    }
+    make_time(
+        1900 + year as i32,
+        month as _,
+        day as _,
+        (hour as _, minute as _, second as _),
+        0,
+        tz as i8 as i32 * 15,
+    )
+}
+
+// The 17 byte "YYYYMMDDHHMMSScc" form with a time zone byte
+fn parse_iso_long_time(data: &[u8]) -> Option<SystemTime> {
+    let digits = std::str::from_utf8(data.get(..16)?).ok()?;
+    let num = |range: std::ops::Range<usize>| digits.get(range)?.parse::<u32>().ok();
+    let year = num(0..4)?;
+    if year == 0 {
+        return None;
+    }
+    make_time(
+        year as _,
+        num(4..6)?,
+        num(6..8)?,
+        (num(8..10)?, num(10..12)?, num(12..14)?),
+        num(14..16)? as u64 * 10_000_000,
+        *data.get(16)? as i8 as i32 * 15,
+    )
+}
+
+fn decode_iso_name(name: &[u8], joliet: bool) -> String {
+    let name = if joliet {
+        let chars: Vec<_> = name
+            .chunks_exact(2)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]))
+            .collect();
+        String::from_utf16_lossy(&chars)
+    } else {
+        String::from_utf8_lossy(name).into_owned()
+    };
+    // Strip the version suffix, and the dot of names without an extension
+    let name = match name.rfind(';') {
+        Some(pos) if name[pos + 1..].bytes().all(|x| x.is_ascii_digit()) => &name[..pos],
+        _ => &name,
+    };
+    name.strip_suffix('.').unwrap_or(name).to_owned()
+}
+
+#[derive(Default)]
+struct RockRidgeInfo {
+    name: Option<String>,
+    is_relocated: bool,
+    child_link: Option<u32>,
+    is_symlink: bool,
+    creation_time: Option<SystemTime>,
+    last_access_time: Option<SystemTime>,
+    last_write_time: Option<SystemTime>,
+}
+
+// Collects the System Use Sharing Protocol entries of a record, following
+// continuation areas
+fn read_susp_entries(
+    file: &dyn crate::fs_provider::File,
+    block_size: u64,
+    area: &[u8],
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut entries = Vec::new();
+    let mut area = area.to_vec();
+    for _ in 0..MAX_DESCRIPTOR_CONTINUATIONS {
+        let mut continuation = None;
+        let mut pos = 0;
+        while pos + 4 <= area.len() {
+            let len = area[pos + 2] as usize;
+            if len < 4 || pos + len > area.len() {
+                break;
+            }
+            let entry = &area[pos..pos + len];
+            match &entry[..2] {
+                b"ST" => break,
+                b"CE" if len >= 28 => {
+                    continuation = Some((
+                        read_u32(entry, 4)? as u64,
+                        read_u32(entry, 12)? as u64,
+                        read_u32(entry, 20)? as u64,
+                    ))
+                }
+                _ => entries.push(entry.to_vec()),
+            }
+            pos += len;
+        }
+        let Some((block, offset, len)) = continuation else {
+            break;
+        };
+        area = read_vec_at(file, block * block_size + offset, len.min(block_size))?;
+    }
+    Ok(entries)
+}
+
+fn parse_rock_ridge(entries: &[Vec<u8>]) -> RockRidgeInfo {
+    let mut info = RockRidgeInfo::default();
+    let mut name = Vec::new();
+    let mut has_name = false;
+    for entry in entries {
+        let data = &entry[4..];
+        match &entry[..2] {
+            // NOTE: Names may be split across multiple entries
+            b"NM" if !data.is_empty() && data[0] & 0x06 == 0 => {
+                name.extend_from_slice(&data[1..]);
+                has_name = true;
+            }
+            b"RE" => info.is_relocated = true,
+            b"CL" => info.child_link = read_u32(data, 0).ok(),
+            b"SL" => info.is_symlink = true,
+            b"TF" if !data.is_empty() => {
+                let flags = data[0];
+                let long_form = flags & 0x80 != 0;
+                let time_len = if long_form { 17 } else { 7 };
+                let mut times = data[1..].chunks_exact(time_len).map(|x| match long_form {
+                    true => parse_iso_long_time(x),
+                    false => parse_iso_short_time(x),
+                });
+                if flags & 0x01 != 0 {
+                    info.creation_time = times.next().flatten();
+                }
+                if flags & 0x02 != 0 {
+                    info.last_write_time = times.next().flatten();
+                }
+                if flags & 0x04 != 0 {
+                    info.last_access_time = times.next().flatten();
+                }
+            }
+            _ => (),
+        }
+    }
+    if has_name {
+        info.name = Some(String::from_utf8_lossy(&name).into_owned());
+    }
+    info
+}
+
+struct Iso9660Reader<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    block_size: u64,
+    joliet: bool,
+    // Whether system use areas carry Rock Ridge entries
+    rock_ridge: bool,
+    builder: IsoTreeBuilder,
+    visited: HashSet<u32>,
+}
+
+impl Iso9660Reader<'_> {
+    fn read_dir_data(&self, extent: u32, size: u32) -> anyhow::Result<Vec<u8>> {
+        read_vec_at(
+            self.file,
+            extent as u64 * self.block_size,
+            (size as u64).min(MAX_DIRECTORY_SIZE),
+        )
+    }
+    fn read_directory(
+        &mut self,
+        extent: u32,
+        size: u32,
+        folder: &mut IsoFolderEntry,
+        folder_path: &str,
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        if depth > MAX_DIRECTORY_DEPTH || !self.visited.insert(extent) {
+            log::warn!("iso: skipping directory loop at block {extent}");
+            return Ok(());
+        }
+        let data = self.read_dir_data(extent, size)?;
+        // Extents of a file split into multiple records
+        let mut pending_extents = Vec::new();
+        let mut pending_size = 0;
+        for record in split_dir_records(&data, self.block_size) {
+            let record = IsoDirRecord::parse(record)?;
+            if record.is_self_or_parent() {
+                continue;
+            }
+            let rr = if self.rock_ridge {
+                let entries = read_susp_entries(self.file, self.block_size, record.system_use)?;
+                parse_rock_ridge(&entries)
+            } else {
+                Default::default()
+            };
+            // NOTE: Relocated directories are reached through their child links
+            if rr.is_relocated {
+                continue;
+            }
+
+            let name = rr
+                .name
+                .clone()
+                .unwrap_or_else(|| decode_iso_name(record.name, self.joliet));
+            let path = join_path(folder_path, &name);
+            let recording_time = parse_iso_short_time(record.recording_time);
+            let default_times = self.builder.default_times;
+            let last_write_time = rr
+                .last_write_time
+                .or(recording_time)
+                .unwrap_or(default_times.last_write_time);
+            let times = IsoTimes {
+                creation_time: rr.creation_time.unwrap_or(last_write_time),
+                last_access_time: rr.last_access_time.unwrap_or(last_write_time),
+                last_write_time,
+            };
+            let mut attributes = FileAttributes::empty();
+            if record.flags & ISO_FLAG_HIDDEN != 0 {
+                attributes |= FileAttributes::Hidden;
+            }
+
+            if record.flags & ISO_FLAG_DIRECTORY != 0 || rr.child_link.is_some() {
+                let (extent, size) = match rr.child_link {
+                    Some(location) => {
+                        // Use the "." record of the relocated directory
+                        let data = self.read_dir_data(location, self.block_size as _)?;
+                        let first = split_dir_records(&data, self.block_size)
+                            .first()
+                            .map(|x| IsoDirRecord::parse(x))
+                            .transpose()?;
+                        match first {
+                            Some(x) => (x.extent, x.size),
+                            None => anyhow::bail!("bad relocated directory at block {location}"),
+                        }
+                    }
+                    None => (record.extent, record.size),
+                };
+                let mut child = IsoFolderEntry::new(self.builder.make_index(), attributes, times);
+                if let Err(e) = self.read_directory(extent, size, &mut child, &path, depth + 1) {
+                    log::warn!("iso: failed to read directory `{path}`: {e}");
+                }
+                let warnings = &mut self.builder.warnings;
+                folder.insert_child(&path, &name, IsoEntry::Folder(child), warnings);
+                continue;
+            }
+
+            if rr.is_symlink {
+                // TODO: Support symbolic links
+                log::debug!("iso: skipping symbolic link `{name}`");
+                continue;
+            }
+            if record.size > 0 {
+                pending_extents.push(IsoExtent {
+                    offset: pending_size,
+                    size: record.size as _,
+                    data_offset: Some(record.extent as u64 * self.block_size),
+                });
+                pending_size += record.size as u64;
+            }
+            if record.flags & ISO_FLAG_MULTI_EXTENT != 0 {
+                continue;
+            }
+            let file = IsoFileEntry {
+                data: IsoFileData::Extents(std::mem::take(&mut pending_extents)),
+                size: std::mem::take(&mut pending_size),
+                index: self.builder.make_index(),
+                attributes,
+                times,
+            };
+            let warnings = &mut self.builder.warnings;
+            folder.insert_child(&path, &name, IsoEntry::File(file), warnings);
+        }
+        Ok(())
+    }
+}
+
+struct IsoVolumeInfo {
+    block_size: u64,
+    primary_root: Option<Vec<u8>>,
+    joliet_root: Option<Vec<u8>>,
+    has_udf: bool,
+}
+
+fn read_volume_descriptors(file: &dyn crate::fs_provider::File) -> anyhow::Result<IsoVolumeInfo> {
+    let mut info = IsoVolumeInfo {
+        block_size: SECTOR_SIZE,
+        primary_root: None,
+        joliet_root: None,
+        has_udf: false,
+    };
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    for i in 0..MAX_VOLUME_DESCRIPTORS {
+        if read_exact_at(
+            file,
+            (VOLUME_DESCRIPTOR_START + i) * SECTOR_SIZE,
+            &mut sector,
+        )
+        .is_err()
+        {
+            break;
+        }
+        match &sector[1..6] {
+            b"CD001" => match sector[0] {
+                ISO_DESCRIPTOR_PRIMARY if info.primary_root.is_none() => {
+                    info.block_size = read_u16(&sector, 128)? as _;
+                    info.primary_root = Some(sector[156..190].to_vec());
+                }
+                ISO_DESCRIPTOR_SUPPLEMENTARY if info.joliet_root.is_none() => {
+                    // Joliet is identified by its UCS-2 escape sequences
+                    if matches!(&sector[88..91], b"%/@" | b"%/C" | b"%/E") {
+                        info.joliet_root = Some(sector[156..190].to_vec());
+                    }
+                }
+                // NOTE: UDF recognition sequences follow the set terminator, so
+                //       keep scanning past it
+                _ => (),
+            },
+            b"NSR02" | b"NSR03" => info.has_udf = true,
+            b"BEA01" | b"TEA01" | b"BOOT2" | b"CDW02" => (),
+            _ => break,
+        }
+    }
+    if !matches!(info.block_size, 512 | 1024 | 2048) {
+        anyhow::bail!("unsupported ISO 9660 block size {}", info.block_size);
+    }
+    Ok(info)
+}
+
+// Checks the "." record of the root directory for a SUSP indicator
+fn detect_rock_ridge(
+    file: &dyn crate::fs_provider::File,
+    block_size: u64,
+    root: &IsoDirRecord,
+) -> anyhow::Result<bool> {
+    let data = read_vec_at(file, root.extent as u64 * block_size, block_size)?;
+    let Some(first) = split_dir_records(&data, block_size).first().copied() else {
+        return Ok(false);
+    };
+    let su = IsoDirRecord::parse(first)?.system_use;
+    // NOTE: Skipping bytes before SUSP entries (`LEN_SKP`) is not supported
+    Ok(su.len() >= 7 && &su[..2] == b"SP" && su[4..6] == [0xbe, 0xef] && su[6] == 0)
+}
+
+fn read_iso9660_tree(
+    file: &dyn crate::fs_provider::File,
+    info: &IsoVolumeInfo,
+    builder: IsoTreeBuilder,
+    root: &mut IsoFolderEntry,
+) -> anyhow::Result<Vec<ContainerWarning>> {
+    let Some(primary_root) = &info.primary_root else {
+        anyhow::bail!("no ISO 9660 primary volume descriptor");
+    };
+    let primary_root = IsoDirRecord::parse(primary_root)?;
+    // Prefer Rock Ridge names, which are the most complete, then Joliet
+    let rock_ridge = detect_rock_ridge(file, info.block_size, &primary_root)?;
+    let (root_record, joliet) = match &info.joliet_root {
+        Some(joliet_root) if !rock_ridge => (IsoDirRecord::parse(joliet_root)?, true),
+        _ => (primary_root, false),
+    };
+    let mut reader = Iso9660Reader {
+        file,
+        block_size: info.block_size,
+        joliet,
+        rock_ridge,
+        builder,
+        visited: HashSet::new(),
+    };
+    reader.read_directory(root_record.extent, root_record.size, root, "", 0)?;
+    Ok(reader.builder.warnings.finish())
+}
+
+// ----- UDF -----
+
+fn check_udf_tag(data: &[u8]) -> anyhow::Result<u16> {
+    let tag = field(data, 0, 16)?;
+    let checksum = tag
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 4)
+        .fold(0u8, |acc, (_, &x)| acc.wrapping_add(x));
+    if checksum != tag[4] {
+        anyhow::bail!("bad UDF descriptor tag checksum");
+    }
+    read_u16(tag, 0)
+}
+
+fn parse_udf_timestamp(data: &[u8]) -> Option<SystemTime> {
+    let data = data.get(..12)?;
+    let type_and_tz = u16::from_le_bytes([data[0], data[1]]);
+    let year = i16::from_le_bytes([data[2], data[3]]);
+    if data[4] == 0 || data[5] == 0 {
+        return None;
+    }
+    // Sign extend the 12 bit offset, where -2047 means unspecified
+    let tz = ((type_and_tz << 4) as i16) >> 4;
+    let tz = if type_and_tz >> 12 == 1 && tz != -2047 {
+        tz as i32
+    } else {
+        0
+    };
+    let nanos = data[9] as u64 * 10_000_000 + data[10] as u64 * 100_000 + data[11] as u64 * 1000;
+    make_time(
+        year as _,
+        data[4] as _,
+        data[5] as _,
+        (data[6] as _, data[7] as _, data[8] as _),
+        nanos,
+        tz,
+    )
+}
+
+// OSTA compressed Unicode
+fn decode_udf_name(data: &[u8]) -> Option<String> {
+    let (&compression_id, rest) = data.split_first()?;
+    match compression_id {
+        8 | 254 => Some(rest.iter().map(|&x| x as char).collect()),
+        16 | 255 => {
+            let chars: Vec<_> = rest
+                .chunks_exact(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .collect();
+            Some(String::from_utf16_lossy(&chars))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UdfLocation {
+    partition: u16,
+    block: u32,
+}
+
+fn parse_long_ad(data: &[u8]) -> anyhow::Result<(u32, UdfLocation)> {
+    Ok((
+        read_u32(data, 0)?,
+        UdfLocation {
+            block: read_u32(data, 4)?,
+            partition: read_u16(data, 8)?,
+        },
+    ))
+}
+
+enum UdfPartitionMap {
+    Physical { start: u64 },
+    // UDF 2.50+ metadata partitions live inside the metadata file
+    Metadata { extents: Vec<IsoExtent> },
+}
+
+struct UdfAllocation {
+    location: Option<UdfLocation>,
+    size: u64,
+}
+
+enum UdfNodeData {
+    Allocations(Vec<UdfAllocation>),
+    Inline(Vec<u8>),
+}
+
+struct UdfNode {
+    file_type: u8,
+    size: u64,
+    data: UdfNodeData,
+    creation_time: Option<SystemTime>,
+    last_access_time: Option<SystemTime>,
+    last_write_time: Option<SystemTime>,
+}
+
+struct UdfReader<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    block_size: u64,
+    partition_maps: Vec<UdfPartitionMap>,
+    builder: IsoTreeBuilder,
+    visited: HashSet<(u16, u32)>,
+}
+
+impl UdfReader<'_> {
+    // Maps a range of a partition to ranges of the image
+    fn map_range(&self, location: UdfLocation, size: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+        let offset = location.block as u64 * self.block_size;
+        match self.partition_maps.get(location.partition as usize) {
+            Some(UdfPartitionMap::Physical { start }) => Ok(vec![(start + offset, size)]),
+            Some(UdfPartitionMap::Metadata { extents }) => {
+                let mut ranges = Vec::new();
+                let mut pos = offset;
+                while pos < offset + size {
+                    let Some(extent) = extents
+                        .iter()
+                        .find(|x| (x.offset..x.offset + x.size).contains(&pos))
+                    else {
+                        anyhow::bail!("UDF metadata block {} is out of range", location.block);
+                    };
+                    let Some(data_offset) = extent.data_offset else {
+                        anyhow::bail!("UDF metadata block {} is not recorded", location.block);
+                    };
+                    let len = (extent.offset + extent.size - pos).min(offset + size - pos);
+                    ranges.push((data_offset + (pos - extent.offset), len));
+                    pos += len;
+                }
+                Ok(ranges)
+            }
+            None => anyhow::bail!("invalid UDF partition reference {}", location.partition),
+        }
+    }
+    fn read_range(&self, location: UdfLocation, size: u64) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(size.try_into()?);
+        for (offset, len) in self.map_range(location, size)? {
+            data.extend_from_slice(&read_vec_at(self.file, offset, len)?);
+        }
+        Ok(data)
+    }
+    fn read_allocations(
+        &self,
+        mut ads: Vec<u8>,
+        ad_type: u16,
+        partition: u16,
+    ) -> anyhow::Result<Vec<UdfAllocation>> {
+        let ad_len = match ad_type {
+            UDF_AD_SHORT => 8,
+            UDF_AD_LONG => 16,
+            _ => anyhow::bail!("unsupported UDF allocation descriptor type {ad_type}"),
+        };
+        let mut allocations = Vec::new();
+        for _ in 0..MAX_DESCRIPTOR_CONTINUATIONS {
+            let mut next = None;
+            for ad in ads.chunks_exact(ad_len) {
+                let raw_len = read_u32(ad, 0)?;
+                let (kind, size) = (raw_len >> 30, (raw_len & 0x3fff_ffff) as u64);
+                if size == 0 {
+                    break;
+                }
+                let location = match ad_type {
+                    UDF_AD_SHORT => UdfLocation {
+                        partition,
+                        block: read_u32(ad, 4)?,
+                    },
+                    _ => parse_long_ad(ad)?.1,
+                };
+                match kind {
+                    UDF_EXTENT_NEXT => {
+                        next = Some((location, size));
+                        break;
+                    }
+                    UDF_EXTENT_RECORDED => allocations.push(UdfAllocation {
+                        location: Some(location),
+                        size,
+                    }),
+                    // Allocated or not, unrecorded extents read as zeros
+                    _ => allocations.push(UdfAllocation {
+                        location: None,
+                        size,
+                    }),
+                }
+            }
+            let Some((location, size)) = next else {
+                return Ok(allocations);
+            };
+            let data = self.read_range(location, size.min(self.block_size))?;
+            if check_udf_tag(&data)? != UDF_TAG_ALLOCATION_EXTENT {
+                anyhow::bail!("bad UDF allocation extent descriptor");
+            }
+            let len = read_u32(&data, 20)? as usize;
+            ads = field(&data, 24, len)?.to_vec();
+        }
+        anyhow::bail!("too many UDF allocation extents")
+    }
+    fn read_node(&self, location: UdfLocation) -> anyhow::Result<UdfNode> {
+        let data = self.read_range(location, self.block_size)?;
+        // Offsets of (access time, modify time, creation time, EA length)
+        let (atime_pos, mtime_pos, ctime_pos, ea_len_pos) = match check_udf_tag(&data)? {
+            UDF_TAG_FILE_ENTRY => (72, 84, None, 168),
+            UDF_TAG_EXTENDED_FILE_ENTRY => (80, 92, Some(104), 208),
+            tag => anyhow::bail!("unexpected UDF descriptor {tag} (expected a file entry)"),
+        };
+        let file_type = data[27];
+        let ad_type = read_u16(&data, 34)? & 0x7;
+        let size = read_u64(&data, 56)?;
+        let ea_len = read_u32(&data, ea_len_pos)? as usize;
+        let ad_len = read_u32(&data, ea_len_pos + 4)? as usize;
+        let ads = field(&data, ea_len_pos + 8 + ea_len, ad_len)?;
+        let data_kind = match ad_type {
+            UDF_AD_EMBEDDED => UdfNodeData::Inline(ads[..ads.len().min(size as _)].to_vec()),
+            _ => UdfNodeData::Allocations(self.read_allocations(
+                ads.to_vec(),
+                ad_type,
+                location.partition,
+            )?),
+        };
+        Ok(UdfNode {
+            file_type,
+            size,
+            data: data_kind,
+            creation_time: ctime_pos.and_then(|x| parse_udf_timestamp(&data[x..])),
+            last_access_time: parse_udf_timestamp(&data[atime_pos..]),
+            last_write_time: parse_udf_timestamp(&data[mtime_pos..]),
+        })
+    }
+    fn make_extents(&self, allocations: &[UdfAllocation]) -> anyhow::Result<Vec<IsoExtent>> {
+        let mut extents = Vec::new();
+        let mut offset = 0;
+        for allocation in allocations {
+            match allocation.location {
+                Some(location) => {
+                    for (data_offset, size) in self.map_range(location, allocation.size)? {
+                        extents.push(IsoExtent {
+                            offset,
+                            size,
+                            data_offset: Some(data_offset),
+                        });
+                        offset += size;
+                    }
+                }
+                None => {
+                    extents.push(IsoExtent {
+                        offset,
+                        size: allocation.size,
+                        data_offset: None,
+                    });
+                    offset += allocation.size;
+                }
+            }
+        }
+        Ok(extents)
+    }
+    fn read_node_data(&self, node: &UdfNode) -> anyhow::Result<Vec<u8>> {
+        let size = node.size.min(MAX_DIRECTORY_SIZE);
+        let allocations = match &node.data {
+            UdfNodeData::Inline(data) => return Ok(data.clone()),
+            UdfNodeData::Allocations(x) => x,
+        };
+        let mut data = Vec::new();
+        for extent in self.make_extents(allocations)? {
+            if extent.offset >= size {
+                break;
+            }
+            let len = extent.size.min(size - extent.offset);
+            match extent.data_offset {
+                Some(offset) => data.extend_from_slice(&read_vec_at(self.file, offset, len)?),
+                None => data.resize(data.len() + len as usize, 0),
+            }
+        }
+        Ok(data)
+    }
+    fn get_times(&self, node: &UdfNode) -> IsoTimes {
+        let last_write_time = node
+            .last_write_time
+            .unwrap_or(self.builder.default_times.last_write_time);
+        IsoTimes {
+            creation_time: node.creation_time.unwrap_or(last_write_time),
+            last_access_time: node.last_access_time.unwrap_or(last_write_time),
+            last_write_time,
+        }
+    }
+    fn read_directory(
+        &mut self,
+        node: &UdfNode,
+        folder: &mut IsoFolderEntry,
+        folder_path: &str,
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        let data = self.read_node_data(node)?;
+        let mut pos = 0;
+        while pos + 38 <= data.len() {
+            let fid = &data[pos..];
+            if check_udf_tag(fid)? != UDF_TAG_FILE_IDENTIFIER {
+                anyhow::bail!("bad UDF file identifier descriptor");
+            }
+            let characteristics = fid[18];
+            let name_len = fid[19] as usize;
+            let (_, location) = parse_long_ad(&fid[20..])?;
+            let impl_use_len = read_u16(fid, 36)? as usize;
+            let name = field(fid, 38 + impl_use_len, name_len)?;
+            pos += (38 + impl_use_len + name_len + 3) & !3;
+            if characteristics & (UDF_FID_DELETED | UDF_FID_PARENT) != 0 {
+                continue;
+            }
+
+            let Some(name) = decode_udf_name(name) else {
+                log::warn!("udf: skipping entry with bad name encoding");
+                continue;
+            };
+            let path = join_path(folder_path, &name);
+            let child = match self.read_node(location) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("udf: failed to read file entry of `{path}`: {e}");
+                    continue;
+                }
+            };
+            let times = self.get_times(&child);
+            let mut attributes = FileAttributes::empty();
+            if characteristics & UDF_FID_HIDDEN != 0 {
+                attributes |= FileAttributes::Hidden;
+            }
+
+            if characteristics & UDF_FID_DIRECTORY != 0
+                || child.file_type == UDF_FILE_TYPE_DIRECTORY
+            {
+                let key = (location.partition, location.block);
+                if depth >= MAX_DIRECTORY_DEPTH || !self.visited.insert(key) {
+                    log::warn!("udf: skipping directory loop at `{path}`");
+                    continue;
+                }
+                let mut folder_entry =
+                    IsoFolderEntry::new(self.builder.make_index(), attributes, times);
+                if let Err(e) = self.read_directory(&child, &mut folder_entry, &path, depth + 1) {
+                    log::warn!("udf: failed to read directory `{path}`: {e}");
+                }
+                let warnings = &mut self.builder.warnings;
+                folder.insert_child(&path, &name, IsoEntry::Folder(folder_entry), warnings);
+                continue;
+            }
+            if !matches!(
+                child.file_type,
+                UDF_FILE_TYPE_REGULAR | UDF_FILE_TYPE_UNSPECIFIED
+            ) {
+                // TODO: Support symbolic links
+                log::debug!("udf: skipping `{path}` of type {}", child.file_type);
+                continue;
+            }
+            let data = match &child.data {
+                UdfNodeData::Inline(data) => IsoFileData::Inline(data.clone().into()),
+                UdfNodeData::Allocations(x) => IsoFileData::Extents(self.make_extents(x)?),
+            };
+            let file = IsoFileEntry {
+                data,
+                size: child.size,
+                index: self.builder.make_index(),
+                attributes,
+                times,
+            };
+            let warnings = &mut self.builder.warnings;
+            folder.insert_child(&path, &name, IsoEntry::File(file), warnings);
+        }
+        Ok(())
+    }
+}
+
+fn find_udf_anchor(file: &dyn crate::fs_provider::File) -> anyhow::Result<Vec<u8>> {
+    let last_sector = (file.get_stat()?.size / SECTOR_SIZE).saturating_sub(1);
+    for sector in [
+        UDF_ANCHOR_SECTOR,
+        last_sector,
+        last_sector.saturating_sub(256),
+    ] {
+        let Ok(data) = read_vec_at(file, sector * SECTOR_SIZE, SECTOR_SIZE) else {
+            continue;
+        };
+        if matches!(check_udf_tag(&data), Ok(UDF_TAG_ANCHOR)) {
+            return Ok(data);
+        }
+    }
+    anyhow::bail!("no UDF anchor volume descriptor")
+}
+
+fn read_udf_tree(
+    file: &dyn crate::fs_provider::File,
+    builder: IsoTreeBuilder,
+    root: &mut IsoFolderEntry,
+) -> anyhow::Result<Vec<ContainerWarning>> {
+    let anchor = find_udf_anchor(file)?;
+    let vds_len = read_u32(&anchor, 16)? as u64;
+    let vds_start = read_u32(&anchor, 20)? as u64;
+
+    // Partition descriptors by number, and the logical volume descriptor
+    let mut partitions = Vec::new();
+    let mut logical_volume = None;
+    for i in 0..(vds_len / SECTOR_SIZE).min(MAX_VOLUME_DESCRIPTORS) {
+        let data = read_vec_at(file, (vds_start + i) * SECTOR_SIZE, SECTOR_SIZE)?;
+        match check_udf_tag(&data)? {
+            UDF_TAG_PARTITION => {
+                let number = read_u16(&data, 22)?;
+                let start = read_u32(&data, 188)? as u64;
+                partitions.push((number, start * SECTOR_SIZE));
+            }
+            UDF_TAG_LOGICAL_VOLUME if logical_volume.is_none() => logical_volume = Some(data),
+            UDF_TAG_TERMINATING => break,
+            _ => (),
+        }
+    }
+    let Some(lvd) = logical_volume else {
+        anyhow::bail!("no UDF logical volume descriptor");
+    };
+    let block_size = read_u32(&lvd, 212)? as u64;
+    if block_size != SECTOR_SIZE {
+        anyhow::bail!("unsupported UDF block size {block_size}");
+    }
+    let (_, file_set_location) = parse_long_ad(field(&lvd, 248, 16)?)?;
+    let map_table_len = read_u32(&lvd, 264)? as usize;
+    let map_count = read_u32(&lvd, 268)?;
+    let map_table = field(&lvd, 440, map_table_len)?;
+
+    let find_partition = |number: u16| {
+        partitions
+            .iter()
+            .find(|x| x.0 == number)
+            .map(|x| x.1)
+            .ok_or_else(|| anyhow::anyhow!("missing UDF partition {number}"))
+    };
+    let mut partition_maps = Vec::new();
+    // Metadata partitions as (map index, partition start, metadata file block)
+    let mut metadata_maps = Vec::new();
+    let mut pos = 0;
+    for _ in 0..map_count {
+        let map_type = *field(map_table, pos, 1)?.first().unwrap();
+        let map_len = *field(map_table, pos + 1, 1)?.first().unwrap() as usize;
+        let map = field(map_table, pos, map_len)?;
+        pos += map_len.max(2);
+        match map_type {
+            1 => partition_maps.push(UdfPartitionMap::Physical {
+                start: find_partition(read_u16(map, 4)?)?,
+            }),
+            2 => {
+                let start = find_partition(read_u16(map, 38)?)?;
+                let identifier = field(map, 5, 23)?;
+                if identifier.starts_with(b"*UDF Metadata Partition") {
+                    metadata_maps.push((partition_maps.len(), start, read_u32(map, 40)?));
+                    partition_maps.push(UdfPartitionMap::Metadata {
+                        extents: Vec::new(),
+                    });
+                } else if identifier.starts_with(b"*UDF Sparable Partition") {
+                    // NOTE: Sparing only matters for defective rewritable media,
+                    //       which images are read back from successfully
+                    partition_maps.push(UdfPartitionMap::Physical { start });
+                } else {
+                    // TODO: Support virtual partitions of incrementally written discs
+                    anyhow::bail!(
+                        "unsupported UDF partition map `{}`",
+                        String::from_utf8_lossy(identifier).trim_end_matches('\0')
+                    );
+                }
+            }
+            _ => anyhow::bail!("unknown UDF partition map type {map_type}"),
+        }
+    }
+
+    let mut reader = UdfReader {
+        file,
+        block_size,
+        partition_maps,
+        builder,
+        visited: HashSet::new(),
+    };
+    // Resolve metadata partitions through their metadata files, which are
+    // stored in the underlying physical partitions
+    for (map_idx, start, metadata_block) in metadata_maps {
+        let physical_idx = reader.partition_maps.len();
+        reader
+            .partition_maps
+            .push(UdfPartitionMap::Physical { start });
+        let node = reader.read_node(UdfLocation {
+            partition: physical_idx as _,
+            block: metadata_block,
+        })?;
+        let UdfNodeData::Allocations(allocations) = &node.data else {
+            anyhow::bail!("embedded UDF metadata file is not supported");
+        };
+        let extents = reader.make_extents(allocations)?;
+        reader.partition_maps[map_idx] = UdfPartitionMap::Metadata { extents };
+    }
+
+    let file_set = reader.read_range(file_set_location, block_size)?;
+    if check_udf_tag(&file_set)? != UDF_TAG_FILE_SET {
+        anyhow::bail!("bad UDF file set descriptor");
+    }
+    let (_, root_location) = parse_long_ad(field(&file_set, 400, 16)?)?;
+    let root_node = reader.read_node(root_location)?;
+    reader
+        .visited
+        .insert((root_location.partition, root_location.block));
+    reader.read_directory(&root_node, root, "", 0)?;
+    Ok(reader.builder.warnings.finish())
+}
+
+// Checks whether the first volume descriptor is one of ISO 9660 or UDF
+pub(super) fn sniff(file: &dyn crate::fs_provider::File) -> bool {
+    let mut sector = [0u8; 6];
+    read_exact_at(file, VOLUME_DESCRIPTOR_START * SECTOR_SIZE, &mut sector).is_ok()
+        && matches!(&sector[1..6], b"CD001" | b"BEA01")
+}
+
+pub struct IsoArchive<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    root: IsoFolderEntry,
+    // Entries renamed or skipped while building the tree
+    warnings: Vec<ContainerWarning>,
+}
+
+impl<'a> IsoArchive<'a> {
+    pub(super) fn new(open_ctx: ArchiveHandlerOpenContext<'a>) -> FileSystemResult<Self> {
+        // We only handle files instead of folders
+        if open_ctx.get_is_dir() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let file = open_ctx.get_file();
+        let file_stat = file.get_stat()?;
+        let default_times = IsoTimes {
+            creation_time: file_stat.creation_time,
+            last_access_time: file_stat.last_access_time,
+            last_write_time: file_stat.last_write_time,
+        };
+        let new_builder = |handler| IsoTreeBuilder {
+            root_index: file_stat.index,
+            counter: 0,
+            default_times,
+            warnings: TreeWarnings::new(handler),
+        };
+        let new_root =
+            || IsoFolderEntry::new(file_stat.index, FileAttributes::empty(), default_times);
+
+        let info = read_volume_descriptors(file)?;
+        // Prefer UDF, as it is authoritative on DVD / BD media, and fall back
+        // to ISO 9660 on bridge discs
+        if info.has_udf || info.primary_root.is_none() {
+            let mut root = new_root();
+            match read_udf_tree(file, new_builder("udf"), &mut root) {
+                Ok(warnings) => {
+                    return Ok(IsoArchive {
+                        file,
+                        root,
+                        warnings,
+                    })
+                }
+                Err(e) if info.primary_root.is_some() => {
+                    log::warn!("udf: falling back to ISO 9660: {e}");
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut root = new_root();
+        let warnings = read_iso9660_tree(file, &info, new_builder("iso"), &mut root)?;
+        Ok(IsoArchive {
+            file,
+            root,
+            warnings,
+        })
+    }
+    fn find_entry(&self, path: SegPath) -> FileSystemResult<BorrowedIsoEntry<'_>> {
+        let mut cur_dir = &self.root;
+        let mut it = path.into_iter().peekable();
+        while let Some(segment) = it.next() {
+            let entry = cur_dir.children.get(CaselessStr::new(segment));
+            match (entry, it.peek()) {
+                (Some(entry), None) => return Ok(entry.as_borrowed()),
+                (Some(IsoEntry::Folder(folder)), Some(_)) => cur_dir = folder,
+                (None, None) => return Err(FileSystemError::ObjectNameNotFound),
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            }
+        }
+        Ok(BorrowedIsoEntry::Folder(cur_dir))
+    }
+}
+
+impl super::ArchiveHandler for IsoArchive<'_> {
+    fn open_file(
+        &self,
+        filename: SegPath,
+    ) -> FileSystemResult<super::ArchiveHandlerOpenFileInfo<'_>> {
+        let entry = self.find_entry(filename)?;
+        Ok(super::ArchiveHandlerOpenFileInfo {
+            context: Box::new(IsoFile { root: self, entry }),
+            is_dir: entry.is_dir(),
+        })
+    }
+    fn get_warnings(&self) -> Vec<ContainerWarning> {
+        self.warnings.clone()
+    }
+}
+
+pub struct IsoFile<'a> {
+    root: &'a IsoArchive<'a>,
+    entry: BorrowedIsoEntry<'a>,
+}
+
+impl super::ArchiveFile for IsoFile<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let entry = match self.entry {
+            BorrowedIsoEntry::File(e) => e,
+            BorrowedIsoEntry::Folder(_) => return Err(FileSystemError::FileIsADirectory),
+        };
+        let read_len = buffer.len().min(entry.size.saturating_sub(offset) as _);
+        let buffer = &mut buffer[..read_len];
+        let extents = match &entry.data {
+            IsoFileData::Extents(extents) => extents,
+            IsoFileData::Inline(data) => {
+                let start = (offset as usize).min(data.len());
+                let len = read_len.min(data.len() - start);
+                buffer[..len].copy_from_slice(&data[start..start + len]);
+                return Ok(len as _);
+            }
+        };
+        // Copy recorded data and fill the rest with zeros
+        let file = self.root.file;
+        let mut total = 0;
+        while total < buffer.len() {
+            let pos = offset + total as u64;
+            let remaining = &mut buffer[total..];
+            let idx = extents.partition_point(|x| x.offset + x.size <= pos);
+            let len = match extents.get(idx) {
+                Some(extent) if extent.offset <= pos => {
+                    let len = remaining
+                        .len()
+                        .min((extent.offset + extent.size - pos) as _);
+                    match extent.data_offset {
+                        Some(data_offset) => {
+                            let data_pos = data_offset + (pos - extent.offset);
+                            let len = file.read_at(data_pos, &mut remaining[..len])? as usize;
+                            if len == 0 {
+                                break;
+                            }
+                            len
+                        }
+                        None => {
+                            remaining[..len].fill(0);
+                            len
+                        }
+                    }
+                }
+                next => {
+                    let hole_end = next.map_or(entry.size, |x| x.offset);
+                    let len = remaining.len().min((hole_end - pos) as _);
+                    remaining[..len].fill(0);
+                    len
+                }
+            };
+            total += len;
+        }
+        Ok(total as _)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(self.entry.get_file_stat_info())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn crate::fs_provider::FilePattern,
+        filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let entry = match self.entry {
+            BorrowedIsoEntry::Folder(e) => e,
+            BorrowedIsoEntry::File(_) => return Err(FileSystemError::NotADirectory),
+        };
+        for (name, child) in entry
+            .children
+            .iter()
+            .filter(|(name, _)| pattern.check_name(name.as_str()))
+        {
+            if filler
+                .fill_data(name.as_str(), &child.get_file_stat_info())
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1806 @@
+// RAR archive handler (RAR 4 and RAR 5 formats, read-only)
+// NOTE: Like 7z, files of solid archives are compressed as a single stream, so
+//       decoded output is cached in chunks and a few decoders are kept alive.
+//       Stored files are read directly from the archive, volumes included.
+// NOTE: Unsupported, and failing with an error naming the feature: encryption,
+//       files of unknown size, RAR 1.5 and 2.x compression, dictionaries above
+//       1 GiB, and RAR 2.9 PPMd blocks going on with the model of the block before.
+
+mod unpack;
+mod unpack30;
+mod unpack50;
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    fs_provider::{
+        ContainerWarning, CursorFile, FileAttributes, FileStatInfo, FileSystemError,
+        FileSystemResult, SegPath,
+    },
+    util::{calculate_hash, CaselessStr, CaselessString},
+};
+
+use super::{
+    cache::{ArchiveCacheHandle, LiveDecoderPool},
+    sanitize::split_entry_path,
+    tree::{
+        filetime_to_system_time, get_or_create_folder, insert_entry, normalize_entry_path,
+        TreeFolder, TreeWarnings,
+    },
+    volume::{open_sibling_volumes, MultiVolumeFile, VolumeFile},
+    ArchiveHandlerOpenContext, ArchiveNonUnicodeCompatConfig,
+};
+
+use unpack::{BoxedReader, UnpackVersion, Unpacker};
+
+const SIGNATURE_V4: [u8; 7] = *b"Rar!\x1a\x07\x00";
+const SIGNATURE_V5: [u8; 8] = *b"Rar!\x1a\x07\x01\x00";
+// NOTE: Self-extracting archives start with an executable, which is skipped
+const MAX_SFX_SIZE: u64 = 0x400000;
+const MAX_HEADER_SIZE_V5: u64 = 0x200000;
+
+const HEAD5_MAIN: u64 = 1;
+const HEAD5_FILE: u64 = 2;
+const HEAD5_CRYPT: u64 = 4;
+const HEAD5_END: u64 = 5;
+
+const HFL5_EXTRA: u64 = 0x0001;
+const HFL5_DATA: u64 = 0x0002;
+const HFL5_SPLIT_BEFORE: u64 = 0x0008;
+const HFL5_SPLIT_AFTER: u64 = 0x0010;
+const MHFL5_VOLUME: u64 = 0x0001;
+const MHFL5_VOLUME_NUMBER: u64 = 0x0002;
+const FHFL5_DIRECTORY: u64 = 0x0001;
+const FHFL5_UTIME: u64 = 0x0002;
+const FHFL5_CRC32: u64 = 0x0004;
+const FHFL5_UNKNOWN_SIZE: u64 = 0x0008;
+const EHFL5_NEXT_VOLUME: u64 = 0x0001;
+
+const FHEXTRA5_CRYPT: u64 = 0x01;
+const FHEXTRA5_HTIME: u64 = 0x03;
+const FHEXTRA5_REDIR: u64 = 0x05;
+const HTIME5_UNIX: u64 = 0x01;
+const HTIME5_MTIME: u64 = 0x02;
+const HTIME5_UNIX_NS: u64 = 0x10;
+const REDIR5_HARD_LINK: u64 = 4;
+const REDIR5_FILE_COPY: u64 = 5;
+
+const HOST5_UNIX: u64 = 1;
+
+const HEAD4_MAIN: u8 = 0x73;
+const HEAD4_FILE: u8 = 0x74;
+const HEAD4_SERVICE: u8 = 0x7a;
+const HEAD4_END: u8 = 0x7b;
+
+const HFL4_LONG_BLOCK: u16 = 0x8000;
+const MHD4_VOLUME: u16 = 0x0001;
+const MHD4_NEW_NUMBERING: u16 = 0x0010;
+const MHD4_PASSWORD: u16 = 0x0080;
+const MHD4_FIRST_VOLUME: u16 = 0x0100;
+const LHD4_SPLIT_BEFORE: u16 = 0x0001;
+const LHD4_SPLIT_AFTER: u16 = 0x0002;
+const LHD4_PASSWORD: u16 = 0x0004;
+const LHD4_SOLID: u16 = 0x0010;
+const LHD4_WINDOW_MASK: u16 = 0x00e0;
+const LHD4_DIRECTORY: u16 = 0x00e0;
+const LHD4_LARGE: u16 = 0x0100;
+const LHD4_UNICODE: u16 = 0x0200;
+const LHD4_SALT: u16 = 0x0400;
+const LHD4_EXT_TIME: u16 = 0x1000;
+const EARC4_NEXT_VOLUME: u16 = 0x0001;
+
+const HOST4_UNIX: u8 = 3;
+const METHOD4_STORE: u8 = 0x30;
+
+const FILE_ATTRIBUTE_READONLY: u32 = 0x01;
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
+const UNIX_WRITE_BITS: u32 = 0o222;
+
+const SOLID_CHUNK_SIZE: u64 = 1024 * 1024;
+// NOTE: RAR 7 allows dictionaries of up to 64 GiB, far beyond what can be allocated
+//       safely. WinRAR before 7.0 never went past 1 GiB.
+const MAX_DICT_SIZE: u64 = 1024 * 1024 * 1024;
+
+// NOTE: DOS timestamps have no time zone, so they are taken as local time
+fn dos_time_to_system_time(dos_time: u32) -> Option<SystemTime> {
+    use chrono::TimeZone;
+    let date = chrono::NaiveDate::from_ymd_opt(
+        (dos_time >> 25) as i32 + 1980,
+        (dos_time >> 21) & 0xf,
+        (dos_time >> 16) & 0x1f,
+    )?;
+    let time = date.and_hms_opt(
+        (dos_time >> 11) & 0x1f,
+        (dos_time >> 5) & 0x3f,
+        (dos_time & 0x1f) * 2,
+    )?;
+    chrono::Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(SystemTime::from)
+}
+
+struct HeaderReader<'h> {
+    data: &'h [u8],
+}
+
+impl<'h> HeaderReader<'h> {
+    fn read_bytes(&mut self, len: u64) -> anyhow::Result<&'h [u8]> {
+        if len > self.data.len() as u64 {
+            anyhow::bail!("unexpected end of RAR header");
+        }
+        let (bytes, rest) = self.data.split_at(len as usize);
+        self.data = rest;
+        Ok(bytes)
+    }
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+    // RAR 5 variable-length integer, 7 bits per byte with the high bit as continuation
+    fn read_vint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0;
+        for shift in (0..70).step_by(7) {
+            let byte = self.read_u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("invalid RAR variable-length integer")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RarFormat {
+    V4,
+    V5,
+}
+
+// Part of the packed data of a file, as an offset into the stitched volumes
+#[derive(Clone, Copy)]
+struct RarSegment {
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Clone, Copy)]
+enum RarMethod {
+    Store,
+    Compressed {
+        version: UnpackVersion,
+        dict_size: u64,
+    },
+    // Files which can be listed but not read, with the reason
+    Unsupported(&'static str),
+}
+
+enum RarRedirect {
+    // Symbolic links and junctions, kept as files holding the target
+    Link(Vec<u8>),
+    // Hard links and file copies, which share the data of an earlier file
+    Copy(Vec<u8>),
+}
+
+struct RarName {
+    bytes: Vec<u8>,
+    is_utf8: bool,
+}
+
+// A file header, possibly only one part of a file split across volumes
+struct RarFileRecord {
+    name: RarName,
+    is_dir: bool,
+    size: u64,
+    attributes: FileAttributes,
+    creation_time: Option<SystemTime>,
+    last_access_time: Option<SystemTime>,
+    last_write_time: Option<SystemTime>,
+    method: RarMethod,
+    solid: bool,
+    redirect: Option<RarRedirect>,
+    segments: Vec<RarSegment>,
+    split_before: bool,
+    split_after: bool,
+}
+
+// Headers of a single volume
+#[derive(Default)]
+struct RarVolumeInfo {
+    is_volume: bool,
+    is_first_volume: bool,
+    new_numbering: bool,
+    has_next_volume: bool,
+    records: Vec<RarFileRecord>,
+}
+
+fn convert_attributes(is_unix: bool, attributes: u32) -> FileAttributes {
+    let mut result = FileAttributes::empty();
+    if is_unix {
+        if attributes & UNIX_WRITE_BITS == 0 {
+            result |= FileAttributes::Readonly;
+        }
+        return result;
+    }
+    if attributes & FILE_ATTRIBUTE_READONLY != 0 {
+        result |= FileAttributes::Readonly;
+    }
+    if attributes & FILE_ATTRIBUTE_HIDDEN != 0 {
+        result |= FileAttributes::Hidden;
+    }
+    result
+}
+
+// Finds the RAR signature, which may follow an SFX module, returning the format and
+// the offset of the first header
+fn find_signature(
+    file: &dyn crate::fs_provider::File,
+) -> FileSystemResult<Option<(RarFormat, u64)>> {
+    const PREFIX: &[u8] = b"Rar!\x1a\x07";
+    let scan_size = file.get_stat()?.size.min(MAX_SFX_SIZE);
+    let mut buf = vec![0u8; 0x10000];
+    let mut offset = 0;
+    while offset < scan_size {
+        let len = file.read_at(offset, &mut buf)? as usize;
+        let data = &buf[..len];
+        let mut start = 0;
+        while let Some(pos) = data[start..]
+            .windows(PREFIX.len())
+            .position(|x| x == PREFIX)
+        {
+            let pos = start + pos;
+            if data[pos..].starts_with(&SIGNATURE_V5) {
+                return Ok(Some((
+                    RarFormat::V5,
+                    offset + (pos + SIGNATURE_V5.len()) as u64,
+                )));
+            }
+            if data[pos..].starts_with(&SIGNATURE_V4) {
+                return Ok(Some((
+                    RarFormat::V4,
+                    offset + (pos + SIGNATURE_V4.len()) as u64,
+                )));
+            }
+            start = pos + 1;
+        }
+        if len < buf.len() {
+            break;
+        }
+        // NOTE: Overlap reads, so that signatures crossing the buffer end are found
+        offset += (len - SIGNATURE_V5.len()) as u64;
+    }
+    Ok(None)
+}
+
+// Reads a RAR 5 header at `offset`, returning it (without the CRC and size fields)
+// and the offset after it, or None at the end of the volume
+fn read_header_v5(
+    file: &dyn crate::fs_provider::File,
+    offset: u64,
+    file_size: u64,
+) -> anyhow::Result<Option<(Vec<u8>, u64)>> {
+    let mut prefix = [0u8; 7];
+    let len = prefix.len().min(file_size.saturating_sub(offset) as usize);
+    // NOTE: Archives without an end header just stop
+    if len < 6 {
+        return Ok(None);
+    }
+    file.read_at_exact(offset, &mut prefix[..len])?;
+    let crc = u32::from_le_bytes(prefix[..4].try_into().unwrap());
+    let mut reader = HeaderReader {
+        data: &prefix[4..len],
+    };
+    let size = reader.read_vint()?;
+    let size_len = len - 4 - reader.data.len();
+    if size == 0 || size > MAX_HEADER_SIZE_V5 {
+        anyhow::bail!("invalid RAR header size {size}");
+    }
+    let mut header = vec![0u8; size_len + size as usize];
+    file.read_at_exact(offset + 4, &mut header)?;
+    let mut hasher = flate2::Crc::new();
+    hasher.update(&header);
+    if hasher.sum() != crc {
+        anyhow::bail!("RAR header CRC mismatch at offset {offset}");
+    }
+    let header_end = offset + 4 + header.len() as u64;
+    header.drain(..size_len);
+    Ok(Some((header, header_end)))
+}
+
+fn get_method_v5(comp_info: u64) -> RarMethod {
+    if (comp_info >> 7) & 7 == 0 {
+        return RarMethod::Store;
+    }
+    let (version, dict_bits) = match comp_info & 0x3f {
+        0 => (UnpackVersion::V50, (comp_info >> 10) & 0xf),
+        1 => (UnpackVersion::V70, (comp_info >> 10) & 0x1f),
+        _ => return RarMethod::Unsupported("unknown RAR compression version"),
+    };
+    let mut dict_size = 0x20000u64 << dict_bits;
+    // NOTE: RAR 7 dictionaries need not be powers of 2
+    if version == UnpackVersion::V70 {
+        dict_size += dict_size / 32 * ((comp_info >> 15) & 0x1f);
+    }
+    if dict_size > MAX_DICT_SIZE {
+        return RarMethod::Unsupported("RAR dictionaries larger than 1 GiB are not supported");
+    }
+    RarMethod::Compressed { version, dict_size }
+}
+
+// High precision times, replacing the DOS/Unix time of the file header
+fn read_times_v5(field: &mut HeaderReader, record: &mut RarFileRecord) -> anyhow::Result<()> {
+    let flags = field.read_vint()?;
+    let is_unix = flags & HTIME5_UNIX != 0;
+    // Modification, creation and access time, in that order
+    let mut times = [None; 3];
+    for (i, time) in times.iter_mut().enumerate() {
+        if flags & (HTIME5_MTIME << i) == 0 {
+            continue;
+        }
+        *time = Some(if is_unix {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(field.read_u32()? as u64)
+        } else {
+            filetime_to_system_time(field.read_u64()?)
+        });
+    }
+    if is_unix && flags & HTIME5_UNIX_NS != 0 {
+        for time in times.iter_mut().flatten() {
+            let nanos = field.read_u32()? & 0x3fffffff;
+            if nanos < 1_000_000_000 {
+                *time += Duration::from_nanos(nanos as u64);
+            }
+        }
+    }
+    let [last_write_time, creation_time, last_access_time] = times;
+    record.last_write_time = last_write_time.or(record.last_write_time);
+    record.creation_time = creation_time;
+    record.last_access_time = last_access_time;
+    Ok(())
+}
+
+fn parse_file_header_v5(
+    reader: &mut HeaderReader,
+    extra: &[u8],
+    flags: u64,
+    data: RarSegment,
+) -> anyhow::Result<RarFileRecord> {
+    let file_flags = reader.read_vint()?;
+    let size = reader.read_vint()?;
+    let attributes = reader.read_vint()?;
+    let mtime = if file_flags & FHFL5_UTIME != 0 {
+        Some(reader.read_u32()?)
+    } else {
+        None
+    };
+    if file_flags & FHFL5_CRC32 != 0 {
+        reader.read_u32()?;
+    }
+    let comp_info = reader.read_vint()?;
+    let host_os = reader.read_vint()?;
+    let name_len = reader.read_vint()?;
+    let name = reader.read_bytes(name_len)?.to_vec();
+
+    let mut record = RarFileRecord {
+        name: RarName {
+            bytes: name,
+            is_utf8: true,
+        },
+        is_dir: file_flags & FHFL5_DIRECTORY != 0,
+        size,
+        attributes: convert_attributes(host_os == HOST5_UNIX, attributes as u32),
+        creation_time: None,
+        last_access_time: None,
+        last_write_time: mtime.map(|x| SystemTime::UNIX_EPOCH + Duration::from_secs(x as u64)),
+        method: get_method_v5(comp_info),
+        solid: comp_info & 0x40 != 0,
+        redirect: None,
+        segments: vec![data],
+        split_before: flags & HFL5_SPLIT_BEFORE != 0,
+        split_after: flags & HFL5_SPLIT_AFTER != 0,
+    };
+    if file_flags & FHFL5_UNKNOWN_SIZE != 0 {
+        record.method = RarMethod::Unsupported("RAR files of unknown size are not supported");
+    }
+    let mut extra = HeaderReader { data: extra };
+    while !extra.data.is_empty() {
+        let size = extra.read_vint()?;
+        let mut field = HeaderReader {
+            data: extra.read_bytes(size)?,
+        };
+        match field.read_vint()? {
+            FHEXTRA5_CRYPT => {
+                record.method = RarMethod::Unsupported("encrypted RAR files are not supported")
+            }
+            FHEXTRA5_HTIME => read_times_v5(&mut field, &mut record)?,
+            FHEXTRA5_REDIR => {
+                let kind = field.read_vint()?;
+                let _flags = field.read_vint()?;
+                let len = field.read_vint()?;
+                let target = field.read_bytes(len)?.to_vec();
+                record.redirect = Some(match kind {
+                    REDIR5_HARD_LINK | REDIR5_FILE_COPY => RarRedirect::Copy(target),
+                    _ => RarRedirect::Link(target),
+                });
+            }
+            // NOTE: Ownership, version numbers and hashes are of no use here
+            _ => {}
+        }
+    }
+    Ok(record)
+}
+
+fn parse_volume_v5(
+    file: &dyn crate::fs_provider::File,
+    start: u64,
+) -> anyhow::Result<RarVolumeInfo> {
+    let file_size = file.get_stat()?.size;
+    let mut info = RarVolumeInfo {
+        is_first_volume: true,
+        new_numbering: true,
+        ..Default::default()
+    };
+    let mut offset = start;
+    while let Some((header, header_end)) = read_header_v5(file, offset, file_size)? {
+        let mut reader = HeaderReader { data: &header };
+        let kind = reader.read_vint()?;
+        let flags = reader.read_vint()?;
+        let extra_size = if flags & HFL5_EXTRA != 0 {
+            reader.read_vint()?
+        } else {
+            0
+        };
+        let data_size = if flags & HFL5_DATA != 0 {
+            reader.read_vint()?
+        } else {
+            0
+        };
+        // Extra records are stored at the end of the header
+        let Some(extra) = header.len().checked_sub(extra_size as usize) else {
+            anyhow::bail!("invalid RAR extra area size");
+        };
+        match kind {
+            HEAD5_MAIN => {
+                let archive_flags = reader.read_vint()?;
+                info.is_volume = archive_flags & MHFL5_VOLUME != 0;
+                info.is_first_volume = archive_flags & MHFL5_VOLUME_NUMBER == 0;
+            }
+            HEAD5_FILE => {
+                let data = RarSegment {
+                    offset: header_end,
+                    size: data_size,
+                };
+                let record = parse_file_header_v5(&mut reader, &header[extra..], flags, data)?;
+                info.records.push(record);
+            }
+            HEAD5_CRYPT => anyhow::bail!("RAR archives with encrypted headers are not supported"),
+            HEAD5_END => {
+                info.has_next_volume = reader.read_vint()? & EHFL5_NEXT_VOLUME != 0;
+                break;
+            }
+            // NOTE: Service headers (comments, recovery records, ...) are skipped
+            _ => {}
+        }
+        offset = header_end
+            .checked_add(data_size)
+            .ok_or_else(|| anyhow::anyhow!("invalid RAR data size"))?;
+    }
+    Ok(info)
+}
+
+// Names with non-ASCII characters are stored twice: in the OEM code page, followed by
+// UTF-16 compactly encoded against it
+fn decode_name_v4(raw: &[u8]) -> RarName {
+    let Some(nul) = raw.iter().position(|&x| x == 0) else {
+        // NOTE: Without the encoded part, the name is UTF-8
+        return RarName {
+            bytes: raw.to_vec(),
+            is_utf8: true,
+        };
+    };
+    let (name, encoded) = (&raw[..nul], &raw[nul + 1..]);
+    let mut result: Vec<u16> = Vec::new();
+    let high_byte = (encoded.first().copied().unwrap_or(0) as u16) << 8;
+    let mut pos = 1;
+    let (mut flags, mut flag_bits) = (0u8, 0);
+    while pos < encoded.len() {
+        if flag_bits == 0 {
+            flags = encoded[pos];
+            pos += 1;
+            flag_bits = 8;
+        }
+        match flags >> 6 {
+            0 => {
+                let Some(&x) = encoded.get(pos) else { break };
+                result.push(x as u16);
+                pos += 1;
+            }
+            1 => {
+                let Some(&x) = encoded.get(pos) else { break };
+                result.push(x as u16 | high_byte);
+                pos += 1;
+            }
+            2 => {
+                let Some(x) = encoded.get(pos..pos + 2) else {
+                    break;
+                };
+                result.push(u16::from_le_bytes([x[0], x[1]]));
+                pos += 2;
+            }
+            _ => {
+                // Runs of characters taken from the OEM name
+                let Some(&len) = encoded.get(pos) else { break };
+                pos += 1;
+                if len & 0x80 != 0 {
+                    let Some(&correction) = encoded.get(pos) else {
+                        break;
+                    };
+                    pos += 1;
+                    for _ in 0..(len & 0x7f) + 2 {
+                        let Some(&x) = name.get(result.len()) else {
+                            break;
+                        };
+                        result.push(x.wrapping_add(correction) as u16 | high_byte);
+                    }
+                } else {
+                    for _ in 0..len + 2 {
+                        let Some(&x) = name.get(result.len()) else {
+                            break;
+                        };
+                        result.push(x as u16);
+                    }
+                }
+            }
+        }
+        flags <<= 2;
+        flag_bits -= 2;
+    }
+    RarName {
+        bytes: String::from_utf16_lossy(&result).into_bytes(),
+        is_utf8: true,
+    }
+}
+
+// Extended times: modification, creation, access and archiving time, with precision
+// beyond the 2 seconds of DOS time
+fn read_times_v4(
+    reader: &mut HeaderReader,
+    dos_mtime: u32,
+) -> anyhow::Result<[Option<SystemTime>; 3]> {
+    let flags = reader.read_u16()?;
+    let mut times = [dos_time_to_system_time(dos_mtime), None, None];
+    for i in 0..4 {
+        let mode = flags >> ((3 - i) * 4);
+        if mode & 8 == 0 {
+            continue;
+        }
+        let dos_time = if i == 0 {
+            dos_mtime
+        } else {
+            reader.read_u32()?
+        };
+        let count = (mode & 3) as usize;
+        let mut remainder = 0;
+        for j in 0..count {
+            remainder |= (reader.read_u8()? as u64) << ((j + 3 - count) * 8);
+        }
+        let mut extra = Duration::from_nanos(remainder * 100);
+        if mode & 4 != 0 {
+            extra += Duration::from_secs(1);
+        }
+        if let Some(time) = times.get_mut(i) {
+            *time = dos_time_to_system_time(dos_time).map(|x| x + extra);
+        }
+    }
+    Ok(times)
+}
+
+// Parses a file (or service) header, its packed data following the header
+fn parse_file_header_v4(
+    reader: &mut HeaderReader,
+    flags: u16,
+    data_offset: u64,
+) -> anyhow::Result<RarFileRecord> {
+    let pack_size = reader.read_u32()? as u64;
+    let size = reader.read_u32()? as u64;
+    let host_os = reader.read_u8()?;
+    let _crc = reader.read_u32()?;
+    let dos_mtime = reader.read_u32()?;
+    let version = reader.read_u8()?;
+    let method = reader.read_u8()?;
+    let name_size = reader.read_u16()?;
+    let attributes = reader.read_u32()?;
+    let (pack_size, size) = if flags & LHD4_LARGE != 0 {
+        (
+            pack_size | (reader.read_u32()? as u64) << 32,
+            size | (reader.read_u32()? as u64) << 32,
+        )
+    } else {
+        (pack_size, size)
+    };
+    let name = reader.read_bytes(name_size as u64)?;
+    let name = if flags & LHD4_UNICODE != 0 {
+        decode_name_v4(name)
+    } else {
+        RarName {
+            bytes: name.to_vec(),
+            is_utf8: false,
+        }
+    };
+    if flags & LHD4_SALT != 0 {
+        reader.read_bytes(8)?;
+    }
+    let [last_write_time, creation_time, last_access_time] = if flags & LHD4_EXT_TIME != 0 {
+        read_times_v4(reader, dos_mtime)?
+    } else {
+        [dos_time_to_system_time(dos_mtime), None, None]
+    };
+
+    let is_dir = flags & LHD4_WINDOW_MASK == LHD4_DIRECTORY;
+    let method = if flags & LHD4_PASSWORD != 0 {
+        RarMethod::Unsupported("encrypted RAR files are not supported")
+    } else if size == u64::MAX || (flags & LHD4_LARGE == 0 && size == u32::MAX as u64) {
+        RarMethod::Unsupported("RAR files of unknown size are not supported")
+    } else if method == METHOD4_STORE || is_dir {
+        RarMethod::Store
+    } else {
+        match version {
+            29 | 36 => RarMethod::Compressed {
+                version: UnpackVersion::V29,
+                dict_size: 0x10000 << ((flags & LHD4_WINDOW_MASK) >> 5),
+            },
+            // TODO: Support RAR 1.5 and 2.x compression
+            13..=28 => RarMethod::Unsupported("RAR 1.5 and RAR 2.x compression is not supported"),
+            _ => RarMethod::Unsupported("unknown RAR compression version"),
+        }
+    };
+    Ok(RarFileRecord {
+        name,
+        is_dir,
+        size,
+        attributes: convert_attributes(host_os == HOST4_UNIX, attributes),
+        creation_time,
+        last_access_time,
+        last_write_time,
+        method,
+        solid: flags & LHD4_SOLID != 0,
+        redirect: None,
+        segments: vec![RarSegment {
+            offset: data_offset,
+            size: pack_size,
+        }],
+        split_before: flags & LHD4_SPLIT_BEFORE != 0,
+        split_after: flags & LHD4_SPLIT_AFTER != 0,
+    })
+}
+
+fn parse_volume_v4(
+    file: &dyn crate::fs_provider::File,
+    start: u64,
+) -> anyhow::Result<RarVolumeInfo> {
+    let file_size = file.get_stat()?.size;
+    let mut info = RarVolumeInfo {
+        is_first_volume: true,
+        ..Default::default()
+    };
+    let mut offset = start;
+    while file_size.saturating_sub(offset) >= 7 {
+        let mut base = [0u8; 7];
+        file.read_at_exact(offset, &mut base)?;
+        let mut reader = HeaderReader { data: &base };
+        let crc = reader.read_u16()?;
+        let kind = reader.read_u8()?;
+        let flags = reader.read_u16()?;
+        let size = reader.read_u16()? as u64;
+        if size < 7 {
+            anyhow::bail!("invalid RAR header size {size}");
+        }
+        let mut header = vec![0u8; size as usize];
+        file.read_at_exact(offset, &mut header)?;
+        let header_end = offset + size;
+        let mut reader = HeaderReader { data: &header[7..] };
+        let mut data_size = 0;
+        match kind {
+            HEAD4_MAIN => {
+                if flags & MHD4_PASSWORD != 0 {
+                    anyhow::bail!("RAR archives with encrypted headers are not supported");
+                }
+                info.is_volume = flags & MHD4_VOLUME != 0;
+                info.new_numbering = flags & MHD4_NEW_NUMBERING != 0;
+                // NOTE: Only archives with new numbering flag the first volume
+                info.is_first_volume =
+                    !info.is_volume || !info.new_numbering || flags & MHD4_FIRST_VOLUME != 0;
+            }
+            HEAD4_FILE | HEAD4_SERVICE => {
+                let mut hasher = flate2::Crc::new();
+                hasher.update(&header[2..]);
+                if hasher.sum() as u16 != crc {
+                    anyhow::bail!("RAR header CRC mismatch at offset {offset}");
+                }
+                let record = parse_file_header_v4(&mut reader, flags, header_end)?;
+                data_size = record.segments[0].size;
+                if kind == HEAD4_FILE {
+                    info.records.push(record);
+                }
+            }
+            HEAD4_END => {
+                info.has_next_volume = flags & EARC4_NEXT_VOLUME != 0;
+                break;
+            }
+            _ => {
+                if flags & HFL4_LONG_BLOCK != 0 {
+                    data_size = reader.read_u32()? as u64;
+                }
+            }
+        }
+        offset = header_end
+            .checked_add(data_size)
+            .ok_or_else(|| anyhow::anyhow!("invalid RAR data size"))?;
+    }
+    Ok(info)
+}
+
+fn parse_volume(file: &dyn crate::fs_provider::File) -> FileSystemResult<RarVolumeInfo> {
+    let Some((format, start)) = find_signature(file)? else {
+        return Err(FileSystemError::FileCorruptError);
+    };
+    let mut info = match format {
+        RarFormat::V4 => parse_volume_v4(file, start)?,
+        RarFormat::V5 => parse_volume_v5(file, start)?,
+    };
+    // NOTE: Data of a truncated volume would run into the next one once stitched
+    let file_size = file.get_stat()?.size;
+    for record in &mut info.records {
+        let is_cut = record.segments.iter().any(|x| {
+            x.offset
+                .checked_add(x.size)
+                .is_none_or(|end| end > file_size)
+        });
+        if is_cut {
+            record.method =
+                RarMethod::Unsupported("RAR file data is cut off at the end of the volume");
+        }
+    }
+    Ok(info)
+}
+
+// Name of the volume following `name`: `x.part1.rar` is followed by `x.part2.rar`
+// with new numbering, `x.rar` by `x.r00`, `x.r01`, ... with old numbering
+fn get_next_volume_name(name: &str, new_numbering: bool) -> Option<String> {
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if !new_numbering {
+        let ext = match *ext.as_bytes() {
+            [letter, a, b] if a.is_ascii_digit() && b.is_ascii_digit() => {
+                match (a - b'0') * 10 + (b - b'0') {
+                    99 => format!("{}00", letter.checked_add(1)? as char),
+                    number => format!("{}{:02}", letter as char, number + 1),
+                }
+            }
+            [b'R', ..] => "R00".to_owned(),
+            _ => "r00".to_owned(),
+        };
+        return Some(format!("{stem}.{ext}"));
+    }
+    // NOTE: Volumes after an SFX module are regular archives
+    let ext =
+        if ext.is_empty() || ext.eq_ignore_ascii_case("exe") || ext.eq_ignore_ascii_case("sfx") {
+            "rar"
+        } else {
+            ext
+        };
+    let digits_end = stem.rfind(|x: char| x.is_ascii_digit())? + 1;
+    let digits_start = stem[..digits_end]
+        .rfind(|x: char| !x.is_ascii_digit())
+        .map_or(0, |x| x + 1);
+    let number: u64 = stem[digits_start..digits_end].parse().ok()?;
+    let width = digits_end - digits_start;
+    Some(format!(
+        "{}{:0width$}{}.{ext}",
+        &stem[..digits_start],
+        number + 1,
+        &stem[digits_end..],
+    ))
+}
+
+// Whether the volume following `info` belongs to the archive
+fn has_next_volume(info: &RarVolumeInfo) -> bool {
+    // NOTE: Old archives may lack the end header, so also follow split files
+    info.is_volume && (info.has_next_volume || info.records.last().is_some_and(|x| x.split_after))
+}
+
+// Joins the files of all volumes, with offsets made relative to the stitched volumes
+fn join_volumes(volumes: Vec<RarVolumeInfo>, volume_starts: &[u64]) -> Vec<RarFileRecord> {
+    let records = volumes
+        .into_iter()
+        .zip(volume_starts)
+        .flat_map(|(info, &start)| {
+            info.records.into_iter().map(move |mut record| {
+                for segment in &mut record.segments {
+                    segment.offset += start;
+                }
+                record
+            })
+        })
+        .collect();
+    merge_split_records(records)
+}
+
+// Joins the parts of files split across volumes
+fn merge_split_records(records: Vec<RarFileRecord>) -> Vec<RarFileRecord> {
+    let mut result: Vec<RarFileRecord> = Vec::with_capacity(records.len());
+    for record in records {
+        if !record.split_before {
+            result.push(record);
+            continue;
+        }
+        match result.last_mut().filter(|x| x.split_after) {
+            Some(last) => {
+                last.segments.extend(record.segments);
+                last.split_after = record.split_after;
+                if let RarMethod::Unsupported(_) = record.method {
+                    last.method = record.method;
+                }
+            }
+            None => log::warn!(
+                "rar: skipping `{}` as its first part is missing",
+                String::from_utf8_lossy(&record.name.bytes)
+            ),
+        }
+    }
+    // NOTE: Files still split after all volumes lack their later parts
+    for record in result.iter_mut().filter(|x| x.split_after) {
+        record.method = RarMethod::Unsupported("RAR file is missing parts from later volumes");
+    }
+    result
+}
+
+// Files of a solid stream, decoded one after another
+struct RarBlockMember {
+    segments: Vec<RarSegment>,
+    size: u64,
+}
+
+struct RarBlock {
+    version: UnpackVersion,
+    dict_size: u64,
+    members: Vec<RarBlockMember>,
+    // Total decoded size
+    size: u64,
+}
+
+#[derive(Clone)]
+enum RarFileData {
+    Empty,
+    Stored(Vec<RarSegment>),
+    // Location of file data within the decoded output of a block
+    Packed { block: usize, offset: u64 },
+    // Target of a symbolic link, kept as the file content
+    Inline(Arc<[u8]>),
+    Unsupported(&'static str),
+}
+
+// Groups compressed files into blocks, each decoded as a single stream
+fn assign_blocks(records: &[RarFileRecord]) -> anyhow::Result<(Vec<RarBlock>, Vec<RarFileData>)> {
+    let mut blocks: Vec<RarBlock> = Vec::new();
+    let mut data = Vec::with_capacity(records.len());
+    // NOTE: Solid files depend on all files before them in the stream
+    let mut cur_block: Option<usize> = None;
+    let mut is_broken = false;
+    for record in records {
+        data.push(match record.method {
+            _ if record.is_dir => RarFileData::Empty,
+            RarMethod::Store if record.size == 0 => RarFileData::Empty,
+            RarMethod::Store
+                if record
+                    .segments
+                    .iter()
+                    .try_fold(0u64, |sum, x| sum.checked_add(x.size))
+                    != Some(record.size) =>
+            {
+                RarFileData::Unsupported("RAR stored file size does not match its data")
+            }
+            RarMethod::Store => RarFileData::Stored(record.segments.clone()),
+            RarMethod::Unsupported(reason) => {
+                cur_block = None;
+                is_broken = true;
+                RarFileData::Unsupported(reason)
+            }
+            RarMethod::Compressed { .. } if record.solid && is_broken => RarFileData::Unsupported(
+                "RAR files following an unsupported file in a solid stream are not supported",
+            ),
+            RarMethod::Compressed { version, dict_size } => {
+                let block_idx = match cur_block {
+                    Some(idx) if record.solid && blocks[idx].version == version => idx,
+                    _ => {
+                        blocks.push(RarBlock {
+                            version,
+                            dict_size,
+                            members: Vec::new(),
+                            size: 0,
+                        });
+                        blocks.len() - 1
+                    }
+                };
+                cur_block = Some(block_idx);
+                is_broken = false;
+                let block = &mut blocks[block_idx];
+                block.dict_size = block.dict_size.max(dict_size);
+                block.members.push(RarBlockMember {
+                    segments: record.segments.clone(),
+                    size: record.size,
+                });
+                let offset = block.size;
+                block.size = block
+                    .size
+                    .checked_add(record.size)
+                    .ok_or_else(|| anyhow::anyhow!("invalid RAR solid stream size"))?;
+                RarFileData::Packed {
+                    block: block_idx,
+                    offset,
+                }
+            }
+        });
+    }
+    Ok((blocks, data))
+}
+
+// Decoded output of all files of a block, back to back
+struct RarBlockReader<'a> {
+    source: Arc<dyn crate::fs_provider::File + 'a>,
+    block: Arc<RarBlock>,
+    unpacker: Unpacker<'a>,
+    next_member: usize,
+}
+
+impl<'a> RarBlockReader<'a> {
+    fn new(
+        source: Arc<dyn crate::fs_provider::File + 'a>,
+        block: Arc<RarBlock>,
+    ) -> anyhow::Result<Self> {
+        let unpacker = Unpacker::new(block.version, block.dict_size, block.size)?;
+        Ok(Self {
+            source,
+            block,
+            unpacker,
+            next_member: 0,
+        })
+    }
+}
+
+impl RarBlockReader<'_> {
+    // Memory held by the decoder, mostly its window
+    fn get_memory_size(&self) -> u64 {
+        self.unpacker.get_memory_size()
+    }
+}
+
+impl Read for RarBlockReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let to_io_error = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.next_member > 0 {
+                let len = self.unpacker.read(buf).map_err(to_io_error)?;
+                if len > 0 {
+                    return Ok(len);
+                }
+            }
+            let Some(member) = self.block.members.get(self.next_member) else {
+                return Ok(0);
+            };
+            let input = member.segments.iter().fold(
+                Box::new(std::io::empty()) as BoxedReader,
+                |input, x| {
+                    let part = CursorFile::with_position(self.source.clone(), x.offset);
+                    Box::new(input.chain(part.take(x.size)))
+                },
+            );
+            self.unpacker
+                .start_file(input, member.size)
+                .map_err(to_io_error)?;
+            self.next_member += 1;
+        }
+    }
+}
+
+struct RarFolderEntry {
+    children: BTreeMap<CaselessString, RarEntry>,
+    index: u64,
+    attributes: FileAttributes,
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+}
+
+struct RarFileEntry {
+    data: RarFileData,
+    size: u64,
+    index: u64,
+    attributes: FileAttributes,
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+}
+
+enum RarEntry {
+    Folder(RarFolderEntry),
+    File(RarFileEntry),
+}
+
+impl RarFolderEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: 0,
+            is_dir: true,
+            attributes: self.attributes | FileAttributes::DirectoryFile,
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+        }
+    }
+}
+
+impl RarFileEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: self.size,
+            is_dir: false,
+            attributes: self.attributes,
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+        }
+    }
+}
+
+impl RarEntry {
+    fn as_borrowed(&self) -> BorrowedRarEntry<'_> {
+        match self {
+            Self::Folder(e) => BorrowedRarEntry::Folder(e),
+            Self::File(e) => BorrowedRarEntry::File(e),
+        }
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        self.as_borrowed().get_file_stat_info()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum BorrowedRarEntry<'a> {
+    Folder(&'a RarFolderEntry),
+    File(&'a RarFileEntry),
+}
+
+impl BorrowedRarEntry<'_> {
+    fn is_dir(&self) -> bool {
+        matches!(self, Self::Folder(_))
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        match self {
+            Self::Folder(e) => e.get_file_stat_info(),
+            Self::File(e) => e.get_file_stat_info(),
+        }
+    }
+}
+
+impl TreeFolder for RarFolderEntry {
+    type Entry = RarEntry;
+
+    fn children_mut(&mut self) -> &mut BTreeMap<CaselessString, RarEntry> {
+        &mut self.children
+    }
+    fn new_implied_child(&self, index: u64) -> RarEntry {
+        RarEntry::Folder(RarFolderEntry {
+            children: BTreeMap::new(),
+            index,
+            attributes: FileAttributes::empty(),
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+        })
+    }
+    fn entry_as_folder_mut(entry: &mut RarEntry) -> Option<&mut Self> {
+        match entry {
+            RarEntry::Folder(folder) => Some(folder),
+            RarEntry::File(_) => None,
+        }
+    }
+    fn entry_is_folder(entry: &RarEntry) -> bool {
+        matches!(entry, RarEntry::Folder(_))
+    }
+}
+
+fn build_file_tree(
+    records: Vec<RarFileRecord>,
+    data: Vec<RarFileData>,
+    root_stat: &FileStatInfo,
+    non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    converter: &super::EncodingConverter,
+) -> (RarFolderEntry, Vec<ContainerWarning>) {
+    let root_index = root_stat.index;
+    let mut root = RarFolderEntry {
+        children: BTreeMap::new(),
+        index: root_index,
+        attributes: FileAttributes::empty(),
+        creation_time: root_stat.creation_time,
+        last_access_time: root_stat.last_access_time,
+        last_write_time: root_stat.last_write_time,
+    };
+    // NOTE: Used for "unique" index generation
+    let mut counter: u64 = 0;
+    // Data of files seen so far, for hard links and file copies
+    let mut files_by_path: HashMap<String, (RarFileData, u64)> = HashMap::new();
+    let mut warnings = TreeWarnings::new("rar");
+
+    for (record_idx, (record, data)) in records.into_iter().zip(data).enumerate() {
+        let name = &record.name.bytes;
+        let path = if record.name.is_utf8 {
+            String::from_utf8_lossy(name)
+        } else if non_unicode_compat.allow_utf8_mix {
+            simdutf8::basic::from_utf8(name)
+                .map(Cow::Borrowed)
+                .unwrap_or_else(|_| converter.convert(name))
+        } else {
+            converter.convert(name)
+        };
+        // NOTE: Paths are normalized, so that entries never escape the archive
+        let Some(components) = normalize_entry_path(&path, &mut warnings) else {
+            continue;
+        };
+        let (filename, parents) = components.split_last().unwrap();
+        let (data, size) = match record.redirect {
+            Some(RarRedirect::Link(target)) => {
+                let size = target.len() as u64;
+                (RarFileData::Inline(target.into()), size)
+            }
+            Some(RarRedirect::Copy(target)) => {
+                let target = split_entry_path(&String::from_utf8_lossy(&target)).join("/");
+                match files_by_path.get(&target) {
+                    Some(x) => x.clone(),
+                    None => {
+                        warnings.add(&path, "is skipped as its link target is missing".to_owned());
+                        continue;
+                    }
+                }
+            }
+            None => (data, record.size),
+        };
+        if !record.is_dir {
+            files_by_path.insert(components.join("/"), (data.clone(), size));
+        }
+
+        let make_index = || {
+            counter += 1;
+            calculate_hash(&(root_index, counter))
+        };
+        let attributes = record.attributes;
+        let creation_time = record.creation_time.unwrap_or(root_stat.creation_time);
+        let last_access_time = record
+            .last_access_time
+            .unwrap_or(root_stat.last_access_time);
+        let last_write_time = record.last_write_time.unwrap_or(root_stat.last_write_time);
+        let index = calculate_hash(&(root_index, u64::MAX - record_idx as u64));
+        if record.is_dir {
+            // Update implicitly created folders with actual metadata
+            let folder = get_or_create_folder(&mut root, &components, make_index, &mut warnings);
+            folder.index = index;
+            folder.attributes = attributes;
+            folder.creation_time = creation_time;
+            folder.last_access_time = last_access_time;
+            folder.last_write_time = last_write_time;
+        } else {
+            let file = RarEntry::File(RarFileEntry {
+                data,
+                size,
+                index,
+                attributes,
+                creation_time,
+                last_access_time,
+                last_write_time,
+            });
+            let cur_dir = get_or_create_folder(&mut root, parents, make_index, &mut warnings);
+            insert_entry(cur_dir, filename, file, &path, &mut warnings);
+        }
+    }
+    (root, warnings.finish())
+}
+
+// (block, chunk index)
+type RarChunkKey = (usize, u64);
+
+// Checks whether the file is a RAR archive, and not a volume other than the first one
+pub(super) fn sniff(file: &dyn crate::fs_provider::File) -> bool {
+    let Ok(Some((format, start))) = find_signature(file) else {
+        return false;
+    };
+    let is_first_volume = || -> anyhow::Result<bool> {
+        Ok(match format {
+            RarFormat::V4 => {
+                let mut header = [0u8; 7];
+                file.read_at_exact(start, &mut header)?;
+                let flags = u16::from_le_bytes([header[3], header[4]]);
+                header[2] != HEAD4_MAIN
+                    || flags & MHD4_VOLUME == 0
+                    || flags & MHD4_NEW_NUMBERING == 0
+                    || flags & MHD4_FIRST_VOLUME != 0
+            }
+            RarFormat::V5 => {
+                let file_size = file.get_stat()?.size;
+                let Some((header, _)) = read_header_v5(file, start, file_size)? else {
+                    return Ok(false);
+                };
+                let mut reader = HeaderReader { data: &header };
+                let kind = reader.read_vint()?;
+                let flags = reader.read_vint()?;
+                if flags & HFL5_EXTRA != 0 {
+                    reader.read_vint()?;
+                }
+                kind != HEAD5_MAIN || reader.read_vint()? & MHFL5_VOLUME_NUMBER == 0
+            }
+        })
+    };
+    is_first_volume().unwrap_or(false)
+}
+
+pub struct RarArchive<'a> {
+    // All volumes, stitched together
+    source: Arc<dyn crate::fs_provider::File + 'a>,
+    blocks: Vec<Arc<RarBlock>>,
+    root: RarFolderEntry,
+    decoders: LiveDecoderPool<'a, RarBlockReader<'a>>,
+    // Last decoded chunk, only kept if the cache is disabled
+    last_chunk: Mutex<Option<(RarChunkKey, Arc<[u8]>)>>,
+    // Decoded chunks of blocks
+    cache: Option<ArchiveCacheHandle<'a>>,
+    index_size: u64,
+    // Entries renamed or skipped while building the tree
+    warnings: Vec<ContainerWarning>,
+}
+
+impl<'a> RarArchive<'a> {
+    pub(super) fn new(
+        open_ctx: ArchiveHandlerOpenContext<'a>,
+        non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    ) -> FileSystemResult<Self> {
+        // We only handle files instead of folders
+        if open_ctx.get_is_dir() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let file = open_ctx.get_file();
+        let file_stat = file.get_stat()?;
+        let mut name = open_ctx.get_file_name().to_owned();
+        if name.to_ascii_lowercase().ends_with(".rev") {
+            return Err(anyhow::anyhow!("RAR recovery volumes are not supported").into());
+        }
+
+        let info = parse_volume(file)?;
+        if !info.is_first_volume {
+            return Err(anyhow::anyhow!("RAR volume `{name}` is not the first volume").into());
+        }
+        let mut volumes = vec![VolumeFile::Main(file)];
+        let mut infos = vec![info];
+        while let Some(info) = infos.last().filter(|x| has_next_volume(x)) {
+            name = get_next_volume_name(&name, info.new_numbering)
+                .ok_or_else(|| anyhow::anyhow!("cannot name the RAR volume after `{name}`"))?;
+            let Some(volume) = open_sibling_volumes(open_ctx, std::iter::once(name.clone()))?.pop()
+            else {
+                return Err(anyhow::anyhow!("RAR volume `{name}` is missing").into());
+            };
+            infos.push(match &volume {
+                VolumeFile::Main(x) => parse_volume(*x)?,
+                VolumeFile::Sibling(x) => parse_volume(x.get_file())?,
+            });
+            volumes.push(volume);
+        }
+        let source = MultiVolumeFile::new(volumes)?;
+        let volume_starts: Vec<_> = (0..source.get_volume_count())
+            .map(|x| source.get_volume_start(x).unwrap_or(0))
+            .collect();
+        let records = join_volumes(infos, &volume_starts);
+
+        let converter = open_ctx.get_name_converter(
+            &non_unicode_compat.encoding_override,
+            records
+                .iter()
+                .filter(|x| !x.name.is_utf8)
+                .map(|x| &x.name.bytes[..]),
+        )?;
+        Self::from_records(
+            Arc::new(source),
+            records,
+            &file_stat,
+            non_unicode_compat,
+            &converter,
+            open_ctx.get_cache(),
+        )
+    }
+    fn from_records(
+        source: Arc<dyn crate::fs_provider::File + 'a>,
+        records: Vec<RarFileRecord>,
+        root_stat: &FileStatInfo,
+        non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+        converter: &super::EncodingConverter,
+        cache: Option<ArchiveCacheHandle<'a>>,
+    ) -> FileSystemResult<Self> {
+        // NOTE: Rough estimate, names are counted twice as they also key the tree
+        let index_size = records
+            .iter()
+            .map(|x| (std::mem::size_of::<RarFileRecord>() + x.name.bytes.len() * 2) as u64)
+            .sum();
+        let (blocks, data) = assign_blocks(&records)?;
+        let (root, warnings) =
+            build_file_tree(records, data, root_stat, non_unicode_compat, converter);
+
+        Ok(RarArchive {
+            source,
+            blocks: blocks.into_iter().map(Arc::new).collect(),
+            root,
+            decoders: LiveDecoderPool::new(cache),
+            last_chunk: Mutex::new(None),
+            cache,
+            index_size,
+            warnings,
+        })
+    }
+    fn find_entry(&self, path: SegPath) -> FileSystemResult<BorrowedRarEntry<'_>> {
+        let mut cur_dir = &self.root;
+        let mut it = path.into_iter().peekable();
+        while let Some(segment) = it.next() {
+            let entry = cur_dir.children.get(CaselessStr::new(segment));
+            match (entry, it.peek()) {
+                (Some(entry), None) => return Ok(entry.as_borrowed()),
+                (Some(RarEntry::Folder(folder)), Some(_)) => cur_dir = folder,
+                (None, None) => return Err(FileSystemError::ObjectNameNotFound),
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            }
+        }
+        Ok(BorrowedRarEntry::Folder(cur_dir))
+    }
+    fn get_cached_chunk(&self, key: RarChunkKey) -> Option<Arc<[u8]>> {
+        match self.cache {
+            Some(cache) => cache.get(key.0 as _, key.1),
+            None => self
+                .last_chunk
+                .lock()
+                .unwrap()
+                .as_ref()
+                .filter(|x| x.0 == key)
+                .map(|x| x.1.clone()),
+        }
+    }
+    fn insert_cached_chunk(&self, key: RarChunkKey, data: Arc<[u8]>) {
+        match self.cache {
+            Some(cache) => cache.insert(key.0 as _, key.1, data),
+            None => *self.last_chunk.lock().unwrap() = Some((key, data)),
+        }
+    }
+    // Returns the decoded chunk `chunk` of block `block_idx`, decoding (and caching)
+    // everything before it when necessary
+    fn get_chunk(&self, block_idx: usize, chunk: u64) -> anyhow::Result<Arc<[u8]>> {
+        if let Some(data) = self.get_cached_chunk((block_idx, chunk)) {
+            return Ok(data);
+        }
+
+        let block = &self.blocks[block_idx];
+        let (mut decoder, mut out_pos) =
+            match self.decoders.take(block_idx, chunk * SOLID_CHUNK_SIZE) {
+                Some(live) => live,
+                None => (RarBlockReader::new(self.source.clone(), block.clone())?, 0),
+            };
+        let result = loop {
+            let cur_chunk = out_pos / SOLID_CHUNK_SIZE;
+            let len = SOLID_CHUNK_SIZE.min(block.size - out_pos);
+            let mut data = vec![0u8; len as usize];
+            decoder.read_exact(&mut data)?;
+            out_pos += len;
+            let data: Arc<[u8]> = data.into();
+            self.insert_cached_chunk((block_idx, cur_chunk), data.clone());
+            if cur_chunk == chunk {
+                break data;
+            }
+        };
+
+        if out_pos < block.size {
+            let memory_size = decoder.get_memory_size();
+            self.decoders.put(block_idx, decoder, out_pos, memory_size);
+        }
+        Ok(result)
+    }
+    fn read_block(&self, block_idx: usize, offset: u64, buffer: &mut [u8]) -> anyhow::Result<u64> {
+        let block_size = self.blocks[block_idx].size;
+        let mut total = 0;
+        while total < buffer.len() {
+            let pos = offset + total as u64;
+            if pos >= block_size {
+                break;
+            }
+            let chunk = self.get_chunk(block_idx, pos / SOLID_CHUNK_SIZE)?;
+            let chunk_offset = (pos % SOLID_CHUNK_SIZE) as usize;
+            let len = (buffer.len() - total).min(chunk.len() - chunk_offset);
+            buffer[total..total + len].copy_from_slice(&chunk[chunk_offset..chunk_offset + len]);
+            total += len;
+        }
+        Ok(total as _)
+    }
+    fn read_stored(
+        &self,
+        segments: &[RarSegment],
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<u64> {
+        let mut total = 0;
+        let mut segment_start = 0u64;
+        for segment in segments {
+            let pos = offset + total as u64;
+            let segment_end = segment_start
+                .checked_add(segment.size)
+                .ok_or(FileSystemError::FileCorruptError)?;
+            if total < buffer.len() && pos < segment_end {
+                let segment_offset = pos - segment_start;
+                let len = (buffer.len() - total).min((segment.size - segment_offset) as usize);
+                self.source.read_at_exact(
+                    segment.offset + segment_offset,
+                    &mut buffer[total..total + len],
+                )?;
+                total += len;
+            }
+            segment_start = segment_end;
+        }
+        Ok(total as _)
+    }
+}
+
+impl super::ArchiveHandler for RarArchive<'_> {
+    fn open_file(
+        &self,
+        filename: SegPath,
+    ) -> FileSystemResult<super::ArchiveHandlerOpenFileInfo<'_>> {
+        let entry = self.find_entry(filename)?;
+        Ok(super::ArchiveHandlerOpenFileInfo {
+            context: Box::new(RarFile { root: self, entry }),
+            is_dir: entry.is_dir(),
+        })
+    }
+    fn get_index_size(&self) -> u64 {
+        self.index_size
+    }
+    fn get_warnings(&self) -> Vec<ContainerWarning> {
+        self.warnings.clone()
+    }
+    fn on_idle(&self) {
+        self.decoders.clear();
+    }
+}
+
+// NOTE: The archive lifetime is kept separate, as the archive is invariant over it
+pub struct RarFile<'a, 'b> {
+    root: &'a RarArchive<'b>,
+    entry: BorrowedRarEntry<'a>,
+}
+
+impl super::ArchiveFile for RarFile<'_, '_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let entry = match self.entry {
+            BorrowedRarEntry::File(e) => e,
+            BorrowedRarEntry::Folder(_) => return Err(FileSystemError::FileIsADirectory),
+        };
+        let read_len = buffer.len().min(entry.size.saturating_sub(offset) as _);
+        let buffer = &mut buffer[..read_len];
+        match &entry.data {
+            RarFileData::Empty => Ok(0),
+            RarFileData::Stored(segments) => self.root.read_stored(segments, offset, buffer),
+            RarFileData::Packed {
+                block,
+                offset: data_offset,
+            } => Ok(self.root.read_block(*block, data_offset + offset, buffer)?),
+            RarFileData::Inline(data) => {
+                let start = (offset as usize).min(data.len());
+                buffer.copy_from_slice(&data[start..start + read_len]);
+                Ok(read_len as _)
+            }
+            RarFileData::Unsupported(reason) => Err(anyhow::anyhow!(*reason).into()),
+        }
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(self.entry.get_file_stat_info())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn crate::fs_provider::FilePattern,
+        filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let entry = match self.entry {
+            BorrowedRarEntry::Folder(e) => e,
+            BorrowedRarEntry::File(_) => return Err(FileSystemError::NotADirectory),
+        };
+        for (name, child) in entry
+            .children
+            .iter()
+            .filter(|(name, _)| pattern.check_name(name.as_str()))
+        {
+            if filler
+                .fill_data(name.as_str(), &child.get_file_stat_info())
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_util::MemFile, ArchiveFile, EncodingConverter};
+    use super::*;
+    use crate::fs_provider::{File, PathDelimiter};
+
+    type Volumes = &'static [(&'static str, &'static [u8])];
+
+    // Small archives made by a test generator, checked against libarchive
+    const FIXTURES: &[Volumes] = &[
+        &[("store5.rar", include_bytes!("rar/testdata/store5.rar"))],
+        &[("lz5.rar", include_bytes!("rar/testdata/lz5.rar"))],
+        &[("solid5.rar", include_bytes!("rar/testdata/solid5.rar"))],
+        &[
+            (
+                "multi5.part1.rar",
+                include_bytes!("rar/testdata/multi5.part1.rar"),
+            ),
+            (
+                "multi5.part2.rar",
+                include_bytes!("rar/testdata/multi5.part2.rar"),
+            ),
+            (
+                "multi5.part3.rar",
+                include_bytes!("rar/testdata/multi5.part3.rar"),
+            ),
+        ],
+        &[("store4.rar", include_bytes!("rar/testdata/store4.rar"))],
+        &[("lz4.rar", include_bytes!("rar/testdata/lz4.rar"))],
+        &[("solid4.rar", include_bytes!("rar/testdata/solid4.rar"))],
+        &[
+            ("multi4.rar", include_bytes!("rar/testdata/multi4.rar")),
+            ("multi4.r00", include_bytes!("rar/testdata/multi4.r00")),
+        ],
+        &[("ppm4.rar", include_bytes!("rar/testdata/ppm4.rar"))],
+    ];
+
+    const HELLO: &[u8] = b"Hello, RAR world!\n";
+
+    fn text() -> Vec<u8> {
+        (0..120)
+            .map(|i| format!("line {i}: the quick brown fox jumps over the lazy dog\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn random() -> Vec<u8> {
+        (0..600u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect()
+    }
+
+    // Readable files of each fixture, by the name of its first volume
+    fn expected_files(name: &str) -> Vec<(&'static str, Vec<u8>)> {
+        let hello = HELLO.to_vec();
+        match name {
+            "store5.rar" => vec![
+                ("hello.txt", hello),
+                ("dir\\text.txt", text()),
+                ("empty.txt", Vec::new()),
+            ],
+            "lz5.rar" => vec![
+                ("hello.txt", hello),
+                ("dir\\text.txt", text()),
+                ("rand.bin", random()),
+                ("link", b"hello.txt".to_vec()),
+                ("hard.txt", text()),
+            ],
+            "solid5.rar" | "solid4.rar" => vec![
+                ("hello.txt", hello),
+                ("dir\\text.txt", text()),
+                ("rand.bin", random()),
+            ],
+            "multi5.part1.rar" => vec![
+                ("hello.txt", hello),
+                ("text.txt", text()),
+                ("tail.txt", b"tail\n".to_vec()),
+            ],
+            "store4.rar" => vec![
+                ("hello.txt", hello),
+                ("café.txt", b"unicode!\n".to_vec()),
+                ("dir\\text.txt", text()),
+            ],
+            "lz4.rar" => vec![("hello.txt", hello), ("dir\\text.txt", text())],
+            "multi4.rar" | "ppm4.rar" => vec![("text.txt", text())],
+            _ => unreachable!(),
+        }
+    }
+
+    // Opens an archive like RarArchive::new does, with volumes looked up by name
+    fn open_archive(volumes: &[(&str, Vec<u8>)]) -> FileSystemResult<RarArchive<'static>> {
+        let mut name = volumes[0].0.to_owned();
+        let mut data = volumes[0].1.clone();
+        let mut infos = vec![parse_volume(&MemFile(data.clone()))?];
+        let mut volume_starts = vec![0];
+        while let Some(info) = infos.last().filter(|x| has_next_volume(x)) {
+            name = get_next_volume_name(&name, info.new_numbering).unwrap();
+            let (_, volume) = volumes
+                .iter()
+                .find(|x| x.0 == name)
+                .ok_or(FileSystemError::ObjectNameNotFound)?;
+            infos.push(parse_volume(&MemFile(volume.clone()))?);
+            volume_starts.push(data.len() as u64);
+            data.extend_from_slice(volume);
+        }
+        let records = join_volumes(infos, &volume_starts);
+        let source = MemFile(data);
+        let root_stat = source.get_stat()?;
+        RarArchive::from_records(
+            Arc::new(source),
+            records,
+            &root_stat,
+            &Default::default(),
+            &EncodingConverter::with_encoding(encoding_rs::WINDOWS_1252),
+            None,
+        )
+    }
+
+    fn open_fixture(volumes: Volumes) -> RarArchive<'static> {
+        let volumes: Vec<_> = volumes.iter().map(|x| (x.0, x.1.to_vec())).collect();
+        open_archive(&volumes).unwrap()
+    }
+
+    fn read_file(archive: &RarArchive, path: &str) -> FileSystemResult<Vec<u8>> {
+        let entry = archive.find_entry(SegPath::new(path, PathDelimiter::BackSlash))?;
+        let file = RarFile {
+            root: archive,
+            entry,
+        };
+        let mut buffer = vec![0; file.get_stat()?.size as usize];
+        let len = file.read_at(0, &mut buffer)?;
+        buffer.truncate(len as usize);
+        Ok(buffer)
+    }
+
+    // Paths of all files in the archive
+    fn list_files(folder: &RarFolderEntry, prefix: &str, files: &mut Vec<String>) {
+        for (name, entry) in &folder.children {
+            let path = format!("{prefix}{}", name.as_str());
+            match entry {
+                RarEntry::Folder(folder) => list_files(folder, &format!("{path}\\"), files),
+                RarEntry::File(_) => files.push(path),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_fixtures() {
+        for volumes in FIXTURES {
+            let archive = open_fixture(volumes);
+            for (path, expected) in expected_files(volumes[0].0) {
+                let data = read_file(&archive, path).unwrap();
+                assert!(data == expected, "{}: {path}", volumes[0].0);
+            }
+            // Reading again goes through the kept decoders and cached chunks
+            for (path, expected) in expected_files(volumes[0].0).into_iter().rev() {
+                assert!(read_file(&archive, path).unwrap() == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_metadata() {
+        let archive = open_fixture(FIXTURES[0]);
+        let mut files = Vec::new();
+        list_files(&archive.root, "", &mut files);
+        assert_eq!(files, ["dir\\text.txt", "empty.txt", "hello.txt"]);
+        let stat = archive
+            .find_entry(SegPath::new("hello.txt", PathDelimiter::BackSlash))
+            .unwrap()
+            .get_file_stat_info();
+        assert_eq!(stat.size, HELLO.len() as u64);
+        assert!(stat.attributes.contains(FileAttributes::Readonly));
+        assert_eq!(
+            stat.last_write_time,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1600000000)
+        );
+        let dir = archive
+            .find_entry(SegPath::new("dir", PathDelimiter::BackSlash))
+            .unwrap();
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    fn rejects_unsupported_features() {
+        let archive = open_fixture(FIXTURES[8]);
+        assert!(read_file(&archive, "text.txt").unwrap() == text());
+        let err = read_file(&archive, "continued.txt").unwrap_err();
+        assert!(format!("{err:?}").contains("continuing a previous model"));
+        assert!(matches!(
+            get_method_v5((3 << 7) | (14 << 10)),
+            RarMethod::Unsupported(_)
+        ));
+    }
+
+    #[test]
+    fn fails_on_missing_volume() {
+        let volumes: Vec<_> = FIXTURES[3]
+            .iter()
+            .filter(|x| x.0 != "multi5.part2.rar")
+            .map(|x| (x.0, x.1.to_vec()))
+            .collect();
+        assert!(open_archive(&volumes).is_err());
+    }
+
+    // Reads every file of a damaged archive, which must not panic
+    fn read_damaged(volumes: &[(&str, Vec<u8>)]) -> Vec<(String, FileSystemResult<Vec<u8>>)> {
+        let Ok(archive) = open_archive(volumes) else {
+            return Vec::new();
+        };
+        let mut files = Vec::new();
+        list_files(&archive.root, "", &mut files);
+        files
+            .into_iter()
+            .map(|path| {
+                let result = read_file(&archive, &path);
+                (path, result)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fails_on_truncated_input() {
+        for fixture in FIXTURES {
+            let expected = expected_files(fixture[0].0);
+            for (idx, (_, volume)) in fixture.iter().enumerate() {
+                for len in 0..volume.len() {
+                    let mut volumes: Vec<_> = fixture.iter().map(|x| (x.0, x.1.to_vec())).collect();
+                    volumes[idx].1.truncate(len);
+                    for (path, result) in read_damaged(&volumes) {
+                        // NOTE: Files before the cut are still fine
+                        let Ok(data) = result else {
+                            continue;
+                        };
+                        if let Some((_, expected)) = expected.iter().find(|x| x.0 == path) {
+                            assert!(data == *expected, "{}: {path} cut at {len}", fixture[idx].0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn survives_corrupt_input() {
+        for fixture in FIXTURES {
+            for (idx, (_, volume)) in fixture.iter().enumerate() {
+                for pos in 0..volume.len() {
+                    let mut volumes: Vec<_> = fixture.iter().map(|x| (x.0, x.1.to_vec())).collect();
+                    volumes[idx].1[pos] ^= 0x55;
+                    read_damaged(&volumes);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,749 @@
+// Parts shared by the RAR decoders: bit input, Huffman tables, the sliding window
+// and the filters applied to its output
+// NOTE: Positions in the window are absolute (counted from the start of the solid
+//       stream), which keeps filters simple as they cannot wrap around
+
+use std::{collections::VecDeque, io::Read};
+
+use super::{unpack30::Unpack30, unpack50::Unpack50};
+
+pub(super) type BoxedReader<'a> = Box<dyn Read + Send + 'a>;
+
+const INPUT_BUFFER_SIZE: usize = 0x10000;
+// NOTE: A single decoding step never reads more bytes than this
+const INPUT_LOOKAHEAD: usize = 64;
+
+// MSB-first bit input over the packed data of one file
+pub(super) struct BitInput<'a> {
+    reader: BoxedReader<'a>,
+    buf: Vec<u8>,
+    pos: usize,
+    bit: u32,
+    // Offset of buf[0] within the packed data
+    base: u64,
+    // Size of the packed data, known once the reader is exhausted
+    end: Option<u64>,
+}
+
+impl<'a> BitInput<'a> {
+    pub fn new(reader: BoxedReader<'a>) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            pos: 0,
+            bit: 0,
+            base: 0,
+            end: None,
+        }
+    }
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        let end = data.len() as u64;
+        let mut input = Self::new(Box::new(std::io::empty()));
+        input.buf = data;
+        input.end = Some(end);
+        input
+    }
+    // Makes sure that at least INPUT_LOOKAHEAD bytes can be peeked
+    // NOTE: Reads past the end see zeros, decoders check `is_exhausted` instead
+    pub fn fill(&mut self) -> std::io::Result<()> {
+        if self.buf.len() - self.pos >= INPUT_LOOKAHEAD {
+            return Ok(());
+        }
+        self.buf.drain(..self.pos);
+        self.base += self.pos as u64;
+        self.pos = 0;
+        let mut len = self.buf.len();
+        self.buf.resize(INPUT_BUFFER_SIZE, 0);
+        while self.end.is_none() {
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => self.end = Some(self.base + len as u64),
+                Ok(n) => {
+                    len += n;
+                    if len >= INPUT_LOOKAHEAD {
+                        self.buf.truncate(len);
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    // Absolute byte position and the bit position within that byte
+    pub fn position(&self) -> (u64, u32) {
+        (self.base + self.pos as u64, self.bit)
+    }
+    // Whether more bits were consumed than the packed data has
+    pub fn is_exhausted(&self) -> bool {
+        let (pos, bit) = self.position();
+        self.end
+            .is_some_and(|end| pos > end || (pos == end && bit > 0))
+    }
+    // Whether all of the packed data was consumed (e.g. there was none at all)
+    pub fn is_at_end(&self) -> bool {
+        self.end.is_some_and(|end| self.position().0 >= end)
+    }
+    pub fn peek16(&self) -> u32 {
+        let b = &self.buf[self.pos..];
+        let value = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        (value >> (8 - self.bit)) & 0xffff
+    }
+    pub fn peek32(&self) -> u32 {
+        let b = &self.buf[self.pos..];
+        let value = u32::from_be_bytes(b[..4].try_into().unwrap());
+        (value << self.bit) | ((b[4] as u32) >> (8 - self.bit))
+    }
+    pub fn peek64(&self) -> u64 {
+        let b = &self.buf[self.pos..];
+        let value = u64::from_be_bytes(b[..8].try_into().unwrap());
+        (value << self.bit) | ((b[8] as u64) >> (8 - self.bit))
+    }
+    pub fn skip(&mut self, bits: u32) {
+        let bits = self.bit + bits;
+        self.pos += (bits / 8) as usize;
+        self.bit = bits % 8;
+    }
+    // Reads up to 16 bits
+    pub fn read_bits(&mut self, bits: u32) -> u32 {
+        let value = self.peek16() >> (16 - bits);
+        self.skip(bits);
+        value
+    }
+    pub fn align(&mut self) {
+        if self.bit > 0 {
+            self.skip(8 - self.bit);
+        }
+    }
+}
+
+// Byte-aligned input, as used by the PPMd decoder of RAR 3
+impl Read for BitInput<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.fill()?;
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+const MAX_CODE_LENGTH: usize = 15;
+const QUICK_BITS: u32 = 10;
+
+// Canonical Huffman code, decoded by comparing left-aligned 16 bit codes against the
+// upper limit of each code length
+pub(super) struct HuffmanTable {
+    // Upper limit (exclusive) of left-aligned codes for each length
+    limits: [u32; MAX_CODE_LENGTH + 1],
+    // Index into `symbols` of the first code for each length
+    offsets: [u32; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+    quick_bits: u32,
+    // (length, symbol) for each value of the first quick_bits bits
+    quick: Vec<(u8, u16)>,
+}
+
+impl HuffmanTable {
+    pub fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u32; MAX_CODE_LENGTH + 1];
+        for &len in lengths {
+            counts[(len & 0xf) as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut limits = [0u32; MAX_CODE_LENGTH + 1];
+        let mut offsets = [0u32; MAX_CODE_LENGTH + 1];
+        let mut upper_limit = 0;
+        for len in 1..=MAX_CODE_LENGTH {
+            upper_limit += counts[len];
+            limits[len] = upper_limit << (16 - len);
+            upper_limit *= 2;
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        let mut next = offsets;
+        for (symbol, &len) in lengths.iter().enumerate() {
+            let len = (len & 0xf) as usize;
+            if len != 0 {
+                symbols[next[len] as usize] = symbol as u16;
+                next[len] += 1;
+            }
+        }
+
+        // NOTE: Smaller alphabets get smaller lookup tables
+        let quick_bits = if lengths.len() > 256 {
+            QUICK_BITS
+        } else {
+            QUICK_BITS - 3
+        };
+        let mut table = Self {
+            limits,
+            offsets,
+            symbols,
+            quick_bits,
+            quick: Vec::with_capacity(1 << quick_bits),
+        };
+        let mut len = 1;
+        for code in 0..1u32 << quick_bits {
+            let bit_field = code << (16 - quick_bits);
+            while len < MAX_CODE_LENGTH && bit_field >= table.limits[len] {
+                len += 1;
+            }
+            let symbol = table.lookup(bit_field, len);
+            table.quick.push((len as u8, symbol));
+        }
+        table
+    }
+    fn lookup(&self, bit_field: u32, len: usize) -> u16 {
+        let dist = (bit_field - self.limits[len - 1]) >> (16 - len);
+        let pos = (self.offsets[len] + dist) as usize;
+        // NOTE: Incomplete codes may point past the symbols, unrar takes the first one
+        self.symbols.get(pos).copied().unwrap_or(self.symbols[0])
+    }
+    pub fn decode(&self, input: &mut BitInput) -> u32 {
+        let bit_field = input.peek16() & 0xfffe;
+        if bit_field < self.limits[self.quick_bits as usize] {
+            let (len, symbol) = self.quick[(bit_field >> (16 - self.quick_bits)) as usize];
+            input.skip(len as u32);
+            return symbol as u32;
+        }
+        let len = (self.quick_bits as usize + 1..MAX_CODE_LENGTH)
+            .find(|&len| bit_field < self.limits[len])
+            .unwrap_or(MAX_CODE_LENGTH);
+        input.skip(len as u32);
+        self.lookup(bit_field, len) as u32
+    }
+}
+
+// Reads the code lengths of the main tables (after the lengths of the bit length
+// code) as RAR 3 and RAR 5 both store them
+pub(super) fn read_code_lengths(
+    input: &mut BitInput,
+    lengths: &mut [u8],
+    old_lengths: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    const BC: usize = 20;
+    let mut bit_lengths = [0u8; BC];
+    input.fill()?;
+    let mut i = 0;
+    while i < BC {
+        let len = input.read_bits(4) as u8;
+        if len == 15 {
+            let zero_count = input.read_bits(4) as usize;
+            if zero_count == 0 {
+                bit_lengths[i] = 15;
+            } else {
+                let end = (i + zero_count + 2).min(BC);
+                bit_lengths[i..end].fill(0);
+                i = end;
+                continue;
+            }
+        } else {
+            bit_lengths[i] = len;
+        }
+        i += 1;
+    }
+    let bit_table = HuffmanTable::new(&bit_lengths);
+
+    let mut i = 0;
+    while i < lengths.len() {
+        input.fill()?;
+        let number = bit_table.decode(input);
+        if number < 16 {
+            let old = old_lengths.map_or(0, |x| x[i]);
+            lengths[i] = (number as u8 + old) & 0xf;
+            i += 1;
+            continue;
+        }
+        let count = match number {
+            16 | 18 => input.read_bits(3) as usize + 3,
+            _ => input.read_bits(7) as usize + 11,
+        };
+        let end = (i + count).min(lengths.len());
+        if number < 18 {
+            if i == 0 {
+                anyhow::bail!("invalid RAR Huffman table");
+            }
+            let prev = lengths[i - 1];
+            lengths[i..end].fill(prev);
+        } else {
+            lengths[i..end].fill(0);
+        }
+        i = end;
+    }
+    if input.is_exhausted() {
+        anyhow::bail!("unexpected end of RAR data");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum FilterKind {
+    // Bytes of each channel are stored apart, as deltas
+    Delta { channels: usize },
+    // Absolute addresses of x86 CALL (and JMP) instructions, made relative again
+    // NOTE: RAR 5 takes file offsets modulo 16 MiB, RAR 3 does not
+    E8 { e9: bool, wrap_offset: bool },
+    Arm,
+    Itanium,
+    Rgb { width: usize, pos_r: usize },
+    Audio { channels: usize },
+}
+
+struct PendingFilter {
+    start: u64,
+    len: usize,
+    kind: FilterKind,
+}
+
+fn filter_e8(data: &mut [u8], file_offset: u64, e9: bool, wrap_offset: bool) {
+    const FILE_SIZE: u32 = 0x1000000;
+    let mut pos = 0;
+    while pos + 4 < data.len() {
+        let byte = data[pos];
+        pos += 1;
+        if byte != 0xe8 && !(e9 && byte == 0xe9) {
+            continue;
+        }
+        let mut offset = (pos as u32).wrapping_add(file_offset as u32);
+        if wrap_offset {
+            offset = (pos as u64 + file_offset) as u32 % FILE_SIZE;
+        }
+        let addr = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        // Addresses within the file were made absolute by the encoder
+        if addr & 0x80000000 != 0 {
+            if addr.wrapping_add(offset) & 0x80000000 == 0 {
+                data[pos..pos + 4].copy_from_slice(&addr.wrapping_add(FILE_SIZE).to_le_bytes());
+            }
+        } else if addr.wrapping_sub(FILE_SIZE) & 0x80000000 != 0 {
+            data[pos..pos + 4].copy_from_slice(&addr.wrapping_sub(offset).to_le_bytes());
+        }
+        pos += 4;
+    }
+}
+
+fn filter_arm(data: &mut [u8], file_offset: u64) {
+    for pos in (0..data.len().saturating_sub(3)).step_by(4) {
+        let d = &mut data[pos..pos + 4];
+        // BL instruction with the always condition
+        if d[3] == 0xeb {
+            let offset = u32::from_le_bytes([d[0], d[1], d[2], 0])
+                .wrapping_sub((file_offset as u32).wrapping_add(pos as u32) / 4);
+            d[..3].copy_from_slice(&offset.to_le_bytes()[..3]);
+        }
+    }
+}
+
+fn filter_itanium(data: &mut [u8], file_offset: u64) {
+    const MASKS: [u8; 16] = [4, 4, 6, 6, 0, 0, 7, 7, 4, 4, 0, 0, 4, 4, 0, 0];
+    let get_bits = |d: &[u8], pos: usize, count: u32| {
+        let value = u32::from_le_bytes(d[pos / 8..pos / 8 + 4].try_into().unwrap());
+        (value >> (pos % 8)) & (u32::MAX >> (32 - count))
+    };
+    let set_bits = |d: &mut [u8], value: u32, pos: usize, count: u32| {
+        let mask = !((u32::MAX >> (32 - count)) << (pos % 8));
+        let old = u32::from_le_bytes(d[pos / 8..pos / 8 + 4].try_into().unwrap());
+        let new = (old & mask) | (value << (pos % 8));
+        d[pos / 8..pos / 8 + 4].copy_from_slice(&new.to_le_bytes());
+    };
+    let mut file_offset = file_offset as u32 >> 4;
+    let mut pos = 0;
+    while pos + 21 < data.len() {
+        let bundle = &mut data[pos..];
+        let template = (bundle[0] & 0x1f) as i32 - 0x10;
+        if template >= 0 {
+            let mask = MASKS[template as usize];
+            for slot in (0..3).filter(|i| mask & (1 << i) != 0) {
+                let start = slot * 41 + 5;
+                if get_bits(bundle, start + 37, 4) == 5 {
+                    let offset = get_bits(bundle, start + 13, 20);
+                    set_bits(
+                        bundle,
+                        offset.wrapping_sub(file_offset) & 0xfffff,
+                        start + 13,
+                        20,
+                    );
+                }
+            }
+        }
+        pos += 16;
+        file_offset = file_offset.wrapping_add(1);
+    }
+}
+
+fn filter_delta(data: &[u8], channels: usize) -> Vec<u8> {
+    let mut result = vec![0u8; data.len()];
+    let mut src = data.iter();
+    for channel in 0..channels {
+        let mut prev = 0u8;
+        for dest in result.iter_mut().skip(channel).step_by(channels) {
+            prev = prev.wrapping_sub(*src.next().unwrap());
+            *dest = prev;
+        }
+    }
+    result
+}
+
+fn filter_rgb(data: &[u8], width: usize, pos_r: usize) -> Vec<u8> {
+    let mut result = vec![0u8; data.len()];
+    let mut src = data.iter();
+    for channel in 0..3 {
+        let mut prev = 0u8;
+        for i in (channel..data.len()).step_by(3) {
+            let mut predicted = prev;
+            if i >= width + 3 {
+                let upper = result[i - width] as i32;
+                let upper_left = result[i - width - 3] as i32;
+                let full = prev as i32 + upper - upper_left;
+                let pa = (full - prev as i32).abs();
+                let pb = (full - upper).abs();
+                let pc = (full - upper_left).abs();
+                predicted = if pa <= pb && pa <= pc {
+                    prev
+                } else if pb <= pc {
+                    upper as u8
+                } else {
+                    upper_left as u8
+                };
+            }
+            prev = predicted.wrapping_sub(*src.next().unwrap());
+            result[i] = prev;
+        }
+    }
+    for i in (pos_r..data.len().saturating_sub(2)).step_by(3) {
+        let green = result[i + 1];
+        result[i] = result[i].wrapping_add(green);
+        result[i + 2] = result[i + 2].wrapping_add(green);
+    }
+    result
+}
+
+fn filter_audio(data: &[u8], channels: usize) -> Vec<u8> {
+    let mut result = vec![0u8; data.len()];
+    let mut src = data.iter();
+    for channel in 0..channels {
+        let (mut prev_byte, mut prev_delta) = (0u32, 0i32);
+        let (mut d1, mut d2) = (0i32, 0i32);
+        let (mut k1, mut k2, mut k3) = (0i32, 0i32, 0i32);
+        let mut dif = [0u32; 7];
+        for (count, i) in (channel..data.len()).step_by(channels).enumerate() {
+            let d3 = d2;
+            d2 = prev_delta - d1;
+            d1 = prev_delta;
+            let predicted = (8 * prev_byte as i32 + k1 * d1 + k2 * d2 + k3 * d3) as u32;
+            let predicted = (predicted >> 3) & 0xff;
+            let cur = *src.next().unwrap() as u32;
+            let value = predicted.wrapping_sub(cur) as u8;
+            result[i] = value;
+            prev_delta = value.wrapping_sub(prev_byte as u8) as i8 as i32;
+            prev_byte = value as u32;
+
+            let d = (cur as u8 as i8 as i32) << 3;
+            for (j, x) in [d, d - d1, d + d1, d - d2, d + d2, d - d3, d + d3]
+                .into_iter()
+                .enumerate()
+            {
+                dif[j] += x.unsigned_abs();
+            }
+            if count & 0x1f == 0 {
+                let min = (0..dif.len()).min_by_key(|&j| dif[j]).unwrap();
+                dif = [0; 7];
+                match min {
+                    1 if k1 >= -16 => k1 -= 1,
+                    2 if k1 < 16 => k1 += 1,
+                    3 if k2 >= -16 => k2 -= 1,
+                    4 if k2 < 16 => k2 += 1,
+                    5 if k3 >= -16 => k3 -= 1,
+                    6 if k3 < 16 => k3 += 1,
+                    _ => {}
+                }
+            }
+        }
+    }
+    result
+}
+
+impl FilterKind {
+    fn apply(&self, mut data: Vec<u8>, file_offset: u64) -> Vec<u8> {
+        match *self {
+            Self::Delta { channels } => return filter_delta(&data, channels),
+            Self::E8 { e9, wrap_offset } => filter_e8(&mut data, file_offset, e9, wrap_offset),
+            Self::Arm => filter_arm(&mut data, file_offset),
+            Self::Itanium => filter_itanium(&mut data, file_offset),
+            Self::Rgb { width, pos_r } => return filter_rgb(&data, width, pos_r),
+            Self::Audio { channels } => return filter_audio(&data, channels),
+        }
+        data
+    }
+}
+
+// NOTE: Longer than any match, so that a decoding step never overwrites data which
+//       is not flushed yet
+const WINDOW_MARGIN: u64 = 0x2000;
+// NOTE: Enough to hold the largest filter block of RAR 5
+const MIN_WINDOW_SIZE: u64 = 0x800000;
+// Output is flushed at least this often
+const MAX_STEP_SIZE: u64 = 0x100000;
+
+pub(super) struct LzWindow {
+    data: Vec<u8>,
+    mask: usize,
+    // Bytes decoded so far, and the part of it already flushed
+    pos: u64,
+    flushed: u64,
+    // Where the current file starts
+    file_start: u64,
+    filters: VecDeque<PendingFilter>,
+    // RAR 3 runs filters with the same block one after another
+    chain_filters: bool,
+}
+
+impl LzWindow {
+    // NOTE: The window never needs to be larger than the whole decoded stream
+    fn new(dict_size: u64, total_size: u64, chain_filters: bool) -> anyhow::Result<Self> {
+        let size = dict_size.max(MIN_WINDOW_SIZE).min(total_size) + 2 * WINDOW_MARGIN;
+        let size = usize::try_from(size.next_power_of_two())
+            .map_err(|_| anyhow::anyhow!("RAR dictionary is too large"))?;
+        // NOTE: Fail the read instead of aborting if there is not enough memory
+        let mut data = Vec::new();
+        data.try_reserve_exact(size)
+            .map_err(|_| anyhow::anyhow!("not enough memory for the RAR dictionary"))?;
+        data.resize(size, 0);
+        Ok(Self {
+            data,
+            mask: size - 1,
+            pos: 0,
+            flushed: 0,
+            file_start: 0,
+            filters: VecDeque::new(),
+            chain_filters,
+        })
+    }
+    pub fn get_pos(&self) -> u64 {
+        self.pos
+    }
+    // Position up to which a decoding step may go
+    pub fn get_limit(&self) -> anyhow::Result<u64> {
+        let limit =
+            (self.flushed + self.data.len() as u64 - WINDOW_MARGIN).min(self.pos + MAX_STEP_SIZE);
+        if limit <= self.pos {
+            anyhow::bail!("RAR filter block is larger than the dictionary");
+        }
+        Ok(limit)
+    }
+    pub fn put(&mut self, byte: u8) {
+        self.data[self.pos as usize & self.mask] = byte;
+        self.pos += 1;
+    }
+    pub fn copy_match(&mut self, len: u32, dist: u64) -> anyhow::Result<()> {
+        if dist == 0 || dist > self.pos || dist > self.data.len() as u64 {
+            anyhow::bail!("invalid RAR match distance");
+        }
+        let src = (self.pos - dist) as usize;
+        let dest = self.pos as usize;
+        for i in 0..len as usize {
+            self.data[(dest + i) & self.mask] = self.data[(src + i) & self.mask];
+        }
+        self.pos += len as u64;
+        Ok(())
+    }
+    // NOTE: Starts are relative to the current position
+    pub fn add_filter(&mut self, start: u64, len: usize, kind: FilterKind) {
+        self.filters.push_back(PendingFilter {
+            start: self.pos + start,
+            len,
+            kind,
+        });
+    }
+    pub fn get_filter_count(&self) -> usize {
+        self.filters.len()
+    }
+    pub fn clear_filters(&mut self) {
+        self.filters.clear();
+    }
+    fn start_file(&mut self) {
+        self.file_start = self.pos;
+        self.filters.clear();
+    }
+    fn read_range(&self, start: u64, end: u64, out: &mut Vec<u8>) {
+        let (start, end) = (start as usize & self.mask, end as usize & self.mask);
+        if start <= end {
+            out.extend_from_slice(&self.data[start..end]);
+        } else {
+            out.extend_from_slice(&self.data[start..]);
+            out.extend_from_slice(&self.data[..end]);
+        }
+    }
+    // Appends decoded data to `out`, running filters whose blocks are complete
+    // NOTE: With `is_final`, filters exceeding the decoded data are dropped
+    fn flush(&mut self, out: &mut Vec<u8>, is_final: bool) {
+        while let Some(filter) = self.filters.front() {
+            let end = filter.start + filter.len as u64;
+            // NOTE: Filters pointing back into flushed data are corrupt
+            if filter.start < self.flushed || (end > self.pos && is_final) {
+                self.filters.pop_front();
+                continue;
+            }
+            if filter.start >= self.pos {
+                break;
+            }
+            self.read_range(self.flushed, filter.start, out);
+            self.flushed = filter.start;
+            if end > self.pos {
+                return;
+            }
+
+            let filter = self.filters.pop_front().unwrap();
+            let mut data = Vec::with_capacity(filter.len);
+            self.read_range(filter.start, end, &mut data);
+            let file_offset = filter.start - self.file_start;
+            data = filter.kind.apply(data, file_offset);
+            while self.chain_filters {
+                let Some(next) = self.filters.front() else {
+                    break;
+                };
+                if next.start != filter.start || next.len != data.len() {
+                    break;
+                }
+                data = next.kind.apply(data, file_offset);
+                self.filters.pop_front();
+            }
+            out.extend_from_slice(&data);
+            self.flushed = end;
+        }
+        self.read_range(self.flushed, self.pos, out);
+        self.flushed = self.pos;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum UnpackVersion {
+    // RAR 2.9 to 4.x
+    V29,
+    // RAR 5.0, and 7.0 with its larger dictionaries
+    V50,
+    V70,
+}
+
+enum UnpackState<'a> {
+    V30(Box<Unpack30<'a>>),
+    V50(Box<Unpack50<'a>>),
+}
+
+// Decodes a solid stream one file at a time, keeping the state of the decoder from
+// one file to the next
+pub(super) struct Unpacker<'a> {
+    window: LzWindow,
+    state: UnpackState<'a>,
+    has_file: bool,
+    // Decoded data of the current file, not read yet
+    out: Vec<u8>,
+    out_pos: usize,
+    file_size: u64,
+    // Bytes of the current file decoded so far
+    file_out: u64,
+}
+
+// NOTE: Files in solid streams should end exactly with their data, but if they do not,
+//       give up after decoding this much more
+const MAX_FILE_OVERRUN: u64 = 0x10000;
+
+impl<'a> Unpacker<'a> {
+    pub fn new(version: UnpackVersion, dict_size: u64, total_size: u64) -> anyhow::Result<Self> {
+        let (window, state) = match version {
+            UnpackVersion::V29 => (
+                LzWindow::new(dict_size, total_size, true)?,
+                UnpackState::V30(Box::new(Unpack30::new())),
+            ),
+            UnpackVersion::V50 | UnpackVersion::V70 => (
+                LzWindow::new(dict_size, total_size, false)?,
+                UnpackState::V50(Box::new(Unpack50::new(version == UnpackVersion::V70))),
+            ),
+        };
+        Ok(Self {
+            window,
+            state,
+            has_file: false,
+            out: Vec::new(),
+            out_pos: 0,
+            file_size: 0,
+            file_out: 0,
+        })
+    }
+    // Memory held by the window and the PPMd model
+    pub fn get_memory_size(&self) -> u64 {
+        let model_size = match &self.state {
+            UnpackState::V30(x) => x.get_model_size(),
+            UnpackState::V50(_) => 0,
+        };
+        self.window.data.len() as u64 + model_size
+    }
+    fn is_file_done(&self) -> bool {
+        match &self.state {
+            UnpackState::V30(x) => x.is_file_done(),
+            UnpackState::V50(x) => x.is_file_done(),
+        }
+    }
+    // Decodes more of the current file into `out`
+    fn decode_step(&mut self) -> anyhow::Result<()> {
+        let limit = self.window.get_limit()?;
+        match &mut self.state {
+            UnpackState::V30(x) => x.decode(&mut self.window, limit)?,
+            UnpackState::V50(x) => x.decode(&mut self.window, limit)?,
+        }
+        let is_final = self.is_file_done();
+        if self.out_pos == self.out.len() {
+            self.out.clear();
+            self.out_pos = 0;
+        }
+        let old_len = self.out.len();
+        self.window.flush(&mut self.out, is_final);
+        // Drop anything past the end of the file
+        let len = (self.out.len() - old_len) as u64;
+        let keep = len.min(self.file_size.saturating_sub(self.file_out));
+        self.out.truncate(old_len + keep as usize);
+        self.file_out += len;
+        Ok(())
+    }
+    // Starts the next file of the stream, whose packed data is read from `input`
+    pub fn start_file(&mut self, input: BoxedReader<'a>, size: u64) -> anyhow::Result<()> {
+        // NOTE: The previous file may need to be decoded to its end, as it may
+        //       change the state used by the next one
+        while self.has_file
+            && !self.is_file_done()
+            && self.file_out < self.file_size + MAX_FILE_OVERRUN
+        {
+            self.decode_step()?;
+        }
+        let solid = self.has_file;
+        self.has_file = true;
+        self.out.clear();
+        self.out_pos = 0;
+        self.file_size = size;
+        self.file_out = 0;
+        self.window.start_file();
+        match &mut self.state {
+            UnpackState::V30(x) => x.start_file(BitInput::new(input), solid),
+            UnpackState::V50(x) => x.start_file(BitInput::new(input), solid),
+        }
+        Ok(())
+    }
+    // Reads decoded data of the current file, returning 0 at its end
+    pub fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        while self.out_pos == self.out.len() {
+            if self.file_out >= self.file_size {
+                return Ok(0);
+            }
+            if self.is_file_done() {
+                anyhow::bail!("RAR data ends before the end of the file");
+            }
+            self.decode_step()?;
+        }
+        let len = buf.len().min(self.out.len() - self.out_pos);
+        buf[..len].copy_from_slice(&self.out[self.out_pos..self.out_pos + len]);
+        self.out_pos += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,614 @@
+// RAR 2.9 decoding (used up to RAR 4.x): LZ blocks, PPMd blocks and the standard
+// filters of the RAR virtual machine
+
+use std::io::Read;
+
+use ppmd_rust::Ppmd7aDecoder;
+
+use super::unpack::{read_code_lengths, BitInput, FilterKind, HuffmanTable, LzWindow};
+
+const NC: usize = 299;
+const DC: usize = 60;
+const LDC: usize = 17;
+const RC: usize = 28;
+const HUFF_TABLE_SIZE: usize = NC + DC + LDC + RC;
+
+const LOW_DIST_REP_COUNT: u32 = 16;
+
+const LENGTH_BASES: [u8; 28] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 32, 40, 48, 56, 64, 80, 96, 112, 128,
+    160, 192, 224,
+];
+const LENGTH_BITS: [u8; 28] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5,
+];
+// Number of distance slots for each count of extra bits
+const DIST_BIT_LENGTH_COUNTS: [u8; 19] =
+    [4, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 14, 0, 12];
+const SHORT_DIST_BASES: [u8; 8] = [0, 4, 8, 16, 32, 64, 128, 192];
+const SHORT_DIST_BITS: [u8; 8] = [2, 2, 3, 4, 5, 6, 6, 6];
+
+const MAX_UNPACK_FILTERS: usize = 8192;
+// NOTE: Filters ran inside the memory of the virtual machine, and those producing
+//       new data kept it in its second half
+const VM_MEMORY_SIZE: u32 = 0x40000;
+const MAX_DELTA_CHANNELS: u32 = 1024;
+const MAX_AUDIO_CHANNELS: u32 = 128;
+
+// Filter programs are recognized by their size and CRC, as unrar does
+#[derive(Clone, Copy)]
+enum StandardFilter {
+    E8,
+    E8E9,
+    Itanium,
+    Delta,
+    Rgb,
+    Audio,
+}
+
+const STANDARD_FILTERS: [(usize, u32, StandardFilter); 6] = [
+    (53, 0xad576887, StandardFilter::E8),
+    (57, 0x3cd7e57e, StandardFilter::E8E9),
+    (120, 0x3769893f, StandardFilter::Itanium),
+    (29, 0x0e06077d, StandardFilter::Delta),
+    (149, 0x1c2c5dc8, StandardFilter::Rgb),
+    (216, 0xbc85e701, StandardFilter::Audio),
+];
+
+fn identify_filter(code: &[u8]) -> Option<StandardFilter> {
+    let xor = code.iter().skip(1).fold(0, |x, b| x ^ b);
+    if code.first() != Some(&xor) {
+        return None;
+    }
+    let mut crc = flate2::Crc::new();
+    crc.update(code);
+    STANDARD_FILTERS
+        .iter()
+        .find(|x| x.0 == code.len() && x.1 == crc.sum())
+        .map(|x| x.2)
+}
+
+// Reads a number as stored in filter parameters
+fn read_vm_data(input: &mut BitInput) -> u32 {
+    let data = input.peek16();
+    match data & 0xc000 {
+        0 => {
+            input.skip(6);
+            (data >> 10) & 0xf
+        }
+        0x4000 if data & 0x3c00 == 0 => {
+            input.skip(14);
+            0xffffff00 | ((data >> 2) & 0xff)
+        }
+        0x4000 => {
+            input.skip(10);
+            (data >> 6) & 0xff
+        }
+        0x8000 => {
+            input.skip(2);
+            input.read_bits(16)
+        }
+        _ => {
+            input.skip(2);
+            input.read_bits(16) << 16 | input.read_bits(16)
+        }
+    }
+}
+
+struct Tables {
+    main: HuffmanTable,
+    dist: HuffmanTable,
+    low_dist: HuffmanTable,
+    rep_len: HuffmanTable,
+}
+
+// Blocks are either LZ or PPMd, the PPMd decoder reading the same input
+enum Input<'a> {
+    Lz(BitInput<'a>),
+    Ppm(Box<Ppmd7aDecoder<BitInput<'a>>>),
+}
+
+pub(super) struct Unpack30<'a> {
+    input: Input<'a>,
+    tables: Option<Tables>,
+    // NOTE: New tables may be stored as a delta to the previous ones
+    old_lengths: [u8; HUFF_TABLE_SIZE],
+    // Whether the next file of a solid stream can go on with the current tables
+    tables_read: bool,
+    need_tables: bool,
+    ppm_esc_char: u8,
+    // Memory of the PPMd model, allocated once a PPMd block is met
+    ppm_size: u64,
+    old_dist: [u64; 4],
+    last_length: u32,
+    prev_low_dist: u32,
+    low_dist_rep_count: u32,
+    dist_bases: [u32; DC],
+    dist_bits: [u8; DC],
+    // Filters defined so far, with the block length they were last used with
+    filters: Vec<StandardFilter>,
+    filter_lengths: Vec<u32>,
+    last_filter: usize,
+    file_done: bool,
+}
+
+impl<'a> Unpack30<'a> {
+    pub fn new() -> Self {
+        let mut dist_bases = [0; DC];
+        let mut dist_bits = [0; DC];
+        let mut slot = 0;
+        let mut dist = 0;
+        for (bits, &count) in DIST_BIT_LENGTH_COUNTS.iter().enumerate() {
+            for _ in 0..count {
+                dist_bases[slot] = dist;
+                dist_bits[slot] = bits as u8;
+                dist += 1 << bits;
+                slot += 1;
+            }
+        }
+        Self {
+            input: Input::Lz(BitInput::from_bytes(Vec::new())),
+            tables: None,
+            old_lengths: [0; HUFF_TABLE_SIZE],
+            tables_read: false,
+            need_tables: true,
+            ppm_esc_char: 2,
+            ppm_size: 0,
+            old_dist: [0; 4],
+            last_length: 0,
+            prev_low_dist: 0,
+            low_dist_rep_count: 0,
+            dist_bases,
+            dist_bits,
+            filters: Vec::new(),
+            filter_lengths: Vec::new(),
+            last_filter: 0,
+            file_done: true,
+        }
+    }
+    pub fn start_file(&mut self, input: BitInput<'a>, solid: bool) {
+        if !solid {
+            self.input = Input::Lz(input);
+            self.tables = None;
+            self.old_lengths = [0; HUFF_TABLE_SIZE];
+            self.tables_read = false;
+            self.ppm_esc_char = 2;
+            self.old_dist = [0; 4];
+            self.last_length = 0;
+            self.filters.clear();
+            self.filter_lengths.clear();
+            self.last_filter = 0;
+        } else {
+            // NOTE: A PPMd block may go on in the next file of a solid stream
+            match &mut self.input {
+                Input::Lz(x) => *x = input,
+                Input::Ppm(x) => *x.get_mut() = input,
+            }
+        }
+        self.need_tables = !solid || !self.tables_read;
+        self.file_done = false;
+    }
+    pub fn get_model_size(&self) -> u64 {
+        self.ppm_size
+    }
+    pub fn is_file_done(&self) -> bool {
+        self.file_done
+    }
+    fn leave_ppm(&mut self) {
+        let input = std::mem::replace(&mut self.input, Input::Lz(BitInput::from_bytes(Vec::new())));
+        self.input = match input {
+            Input::Ppm(x) => {
+                self.ppm_size = 0;
+                Input::Lz(x.into_inner())
+            }
+            x => x,
+        };
+    }
+    fn lz_input(&mut self) -> &mut BitInput<'a> {
+        match &mut self.input {
+            Input::Lz(x) => x,
+            Input::Ppm(_) => unreachable!(),
+        }
+    }
+    fn read_tables(&mut self) -> anyhow::Result<()> {
+        self.leave_ppm();
+        let Input::Lz(input) = &mut self.input else {
+            unreachable!()
+        };
+        input.fill()?;
+        input.align();
+        let bit_field = input.peek16();
+        if bit_field & 0x8000 != 0 {
+            return self.start_ppm();
+        }
+        if bit_field & 0x4000 == 0 {
+            self.old_lengths = [0; HUFF_TABLE_SIZE];
+        }
+        input.skip(2);
+        let mut lengths = [0; HUFF_TABLE_SIZE];
+        read_code_lengths(input, &mut lengths, Some(&self.old_lengths))?;
+        let (main, rest) = lengths.split_at(NC);
+        let (dist, rest) = rest.split_at(DC);
+        let (low_dist, rep_len) = rest.split_at(LDC);
+        self.tables = Some(Tables {
+            main: HuffmanTable::new(main),
+            dist: HuffmanTable::new(dist),
+            low_dist: HuffmanTable::new(low_dist),
+            rep_len: HuffmanTable::new(rep_len),
+        });
+        self.old_lengths = lengths;
+        self.tables_read = true;
+        self.prev_low_dist = 0;
+        self.low_dist_rep_count = 0;
+        Ok(())
+    }
+    fn start_ppm(&mut self) -> anyhow::Result<()> {
+        let input = self.lz_input();
+        let flags = input.read_bits(8);
+        // NOTE: Blocks without the reset flag go on with the model of the previous
+        //       block, which ppmd_rust cannot restart a range decoder for. They are
+        //       listed as unsupported in rar.rs.
+        if flags & 0x20 == 0 {
+            anyhow::bail!("RAR PPMd blocks continuing a previous model are not supported");
+        }
+        let max_mb = input.read_bits(8);
+        if flags & 0x40 != 0 {
+            self.ppm_esc_char = input.read_bits(8) as u8;
+        }
+        let mut order = (flags & 0x1f) + 1;
+        if order > 16 {
+            order = 16 + (order - 16) * 3;
+        }
+        if order == 1 {
+            anyhow::bail!("invalid RAR PPMd order");
+        }
+        let Input::Lz(input) =
+            std::mem::replace(&mut self.input, Input::Lz(BitInput::from_bytes(Vec::new())))
+        else {
+            unreachable!()
+        };
+        let decoder = Ppmd7aDecoder::new(input, order, (max_mb + 1) << 20)?;
+        self.input = Input::Ppm(Box::new(decoder));
+        self.ppm_size = ((max_mb + 1) << 20) as u64;
+        Ok(())
+    }
+    // Reads the next byte of a PPMd block, None at its end
+    fn read_ppm_byte(&mut self) -> anyhow::Result<Option<u8>> {
+        let Input::Ppm(decoder) = &mut self.input else {
+            return Ok(None);
+        };
+        let mut byte = [0];
+        match decoder.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn add_filter(
+        &mut self,
+        window: &mut LzWindow,
+        first_byte: u32,
+        code: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let code_len = code.len() as u64;
+        let mut input = BitInput::from_bytes(code);
+        input.fill()?;
+        let pos = if first_byte & 0x80 != 0 {
+            match read_vm_data(&mut input) {
+                0 => {
+                    self.filters.clear();
+                    self.filter_lengths.clear();
+                    window.clear_filters();
+                    0
+                }
+                x => x as usize - 1,
+            }
+        } else {
+            self.last_filter
+        };
+        if pos > self.filters.len() || pos > MAX_UNPACK_FILTERS {
+            anyhow::bail!("invalid RAR filter");
+        }
+        self.last_filter = pos;
+        let is_new = pos == self.filters.len();
+        if is_new {
+            self.filter_lengths.push(0);
+        }
+
+        let mut block_start = read_vm_data(&mut input);
+        if first_byte & 0x40 != 0 {
+            block_start = block_start.wrapping_add(258);
+        }
+        let block_len = if first_byte & 0x20 != 0 {
+            self.filter_lengths[pos] = read_vm_data(&mut input);
+            self.filter_lengths[pos]
+        } else {
+            self.filter_lengths[pos]
+        };
+        let mut regs = [0u32; 7];
+        regs[4] = block_len;
+        if first_byte & 0x10 != 0 {
+            let mask = input.read_bits(7);
+            for (i, reg) in regs.iter_mut().enumerate() {
+                if mask & (1 << i) != 0 {
+                    *reg = read_vm_data(&mut input);
+                }
+            }
+        }
+        if is_new {
+            let size = read_vm_data(&mut input) as u64;
+            if size == 0 || size >= 0x10000 || input.position().0 + size > code_len {
+                anyhow::bail!("invalid RAR filter");
+            }
+            let code: Vec<_> = (0..size).map(|_| input.read_bits(8) as u8).collect();
+            let filter =
+                identify_filter(&code).ok_or_else(|| anyhow::anyhow!("unsupported RAR filter"))?;
+            self.filters.push(filter);
+        }
+
+        let (kind, max_len) = match self.filters[pos] {
+            StandardFilter::E8 | StandardFilter::E8E9 => (
+                FilterKind::E8 {
+                    e9: matches!(self.filters[pos], StandardFilter::E8E9),
+                    wrap_offset: false,
+                },
+                VM_MEMORY_SIZE,
+            ),
+            StandardFilter::Itanium => (FilterKind::Itanium, VM_MEMORY_SIZE),
+            StandardFilter::Delta => {
+                if regs[0] == 0 || regs[0] > MAX_DELTA_CHANNELS {
+                    anyhow::bail!("invalid RAR filter");
+                }
+                let channels = regs[0] as usize;
+                (FilterKind::Delta { channels }, VM_MEMORY_SIZE / 2)
+            }
+            StandardFilter::Rgb => {
+                let width = regs[0].wrapping_sub(3);
+                if block_len < 3 || width > block_len || regs[2] > 2 {
+                    anyhow::bail!("invalid RAR filter");
+                }
+                let (width, pos_r) = (width as usize, regs[2] as usize);
+                (FilterKind::Rgb { width, pos_r }, VM_MEMORY_SIZE / 2)
+            }
+            StandardFilter::Audio => {
+                if regs[0] == 0 || regs[0] > MAX_AUDIO_CHANNELS {
+                    anyhow::bail!("invalid RAR filter");
+                }
+                let channels = regs[0] as usize;
+                (FilterKind::Audio { channels }, VM_MEMORY_SIZE / 2)
+            }
+        };
+        if block_len > max_len {
+            anyhow::bail!("RAR filter block is too large");
+        }
+        if window.get_filter_count() >= MAX_UNPACK_FILTERS {
+            anyhow::bail!("too many RAR filters");
+        }
+        window.add_filter(block_start as u64, block_len as usize, kind);
+        Ok(())
+    }
+    fn read_filter_lz(&mut self, window: &mut LzWindow) -> anyhow::Result<()> {
+        let input = self.lz_input();
+        let first_byte = input.read_bits(8);
+        let len = match (first_byte & 7) + 1 {
+            7 => input.read_bits(8) + 7,
+            8 => input.read_bits(16),
+            x => x,
+        };
+        input.fill()?;
+        let mut code = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            code.push(input.read_bits(8) as u8);
+            input.fill()?;
+        }
+        if len == 0 || input.is_exhausted() {
+            anyhow::bail!("invalid RAR filter");
+        }
+        self.add_filter(window, first_byte, code)
+    }
+    fn read_filter_ppm(&mut self, window: &mut LzWindow) -> anyhow::Result<bool> {
+        let Some(first_byte) = self.read_ppm_byte()? else {
+            return Ok(false);
+        };
+        let len = match (first_byte & 7) + 1 {
+            7 => match self.read_ppm_byte()? {
+                Some(x) => x as u32 + 7,
+                None => return Ok(false),
+            },
+            8 => match (self.read_ppm_byte()?, self.read_ppm_byte()?) {
+                (Some(x), Some(y)) => (x as u32) << 8 | y as u32,
+                _ => return Ok(false),
+            },
+            x => x as u32,
+        };
+        if len == 0 {
+            anyhow::bail!("invalid RAR filter");
+        }
+        let mut code = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let Some(x) = self.read_ppm_byte()? else {
+                return Ok(false);
+            };
+            code.push(x);
+        }
+        self.add_filter(window, first_byte as u32, code)?;
+        Ok(true)
+    }
+    // Decodes one symbol of a PPMd block, returning false at the end of the file
+    fn decode_ppm(&mut self, window: &mut LzWindow) -> anyhow::Result<bool> {
+        let Some(byte) = self.read_ppm_byte()? else {
+            self.leave_ppm();
+            return Ok(false);
+        };
+        if byte != self.ppm_esc_char {
+            window.put(byte);
+            return Ok(true);
+        }
+        let Some(code) = self.read_ppm_byte()? else {
+            return Ok(false);
+        };
+        match code {
+            0 => self.read_tables()?,
+            2 => return Ok(false),
+            3 => return self.read_filter_ppm(window),
+            4 => {
+                let mut bytes = [0; 4];
+                for x in bytes.iter_mut() {
+                    let Some(byte) = self.read_ppm_byte()? else {
+                        return Ok(false);
+                    };
+                    *x = byte;
+                }
+                let dist = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+                window.copy_match(bytes[3] as u32 + 32, dist as u64 + 2)?;
+            }
+            5 => {
+                let Some(len) = self.read_ppm_byte()? else {
+                    return Ok(false);
+                };
+                window.copy_match(len as u32 + 4, 1)?;
+            }
+            // NOTE: 1 stands for the escape character itself
+            _ => window.put(byte),
+        }
+        Ok(true)
+    }
+    fn read_length(input: &mut BitInput, slot: usize, base: u32) -> u32 {
+        let bits = LENGTH_BITS[slot] as u32;
+        LENGTH_BASES[slot] as u32 + base + input.read_bits(bits)
+    }
+    fn read_distance(&mut self, slot: usize) -> u64 {
+        let Input::Lz(input) = &mut self.input else {
+            unreachable!()
+        };
+        let tables = self.tables.as_ref().unwrap();
+        let bits = self.dist_bits[slot] as u32;
+        let mut dist = self.dist_bases[slot] + 1;
+        if slot <= 9 {
+            return (dist + input.read_bits(bits)) as u64;
+        }
+        if bits > 4 {
+            dist += (input.peek32() >> (36 - bits)) << 4;
+            input.skip(bits - 4);
+        }
+        if self.low_dist_rep_count > 0 {
+            self.low_dist_rep_count -= 1;
+            dist += self.prev_low_dist;
+        } else {
+            let low_dist = tables.low_dist.decode(input);
+            if low_dist == 16 {
+                self.low_dist_rep_count = LOW_DIST_REP_COUNT - 1;
+                dist += self.prev_low_dist;
+            } else {
+                dist += low_dist;
+                self.prev_low_dist = low_dist;
+            }
+        }
+        dist as u64
+    }
+    fn insert_old_dist(&mut self, dist: u64) {
+        self.old_dist.copy_within(0..3, 1);
+        self.old_dist[0] = dist;
+    }
+    // Decodes one symbol of an LZ block, returning false at the end of the file
+    fn decode_lz(&mut self, window: &mut LzWindow) -> anyhow::Result<bool> {
+        let Input::Lz(input) = &mut self.input else {
+            unreachable!()
+        };
+        let Some(tables) = &self.tables else {
+            anyhow::bail!("RAR block has no Huffman tables");
+        };
+        let symbol = tables.main.decode(input) as usize;
+        match symbol {
+            0..=255 => window.put(symbol as u8),
+            256 => {
+                // 1: new tables, 00: end of file, 01: end of file with new tables
+                if input.read_bits(1) == 1 {
+                    self.tables_read = false;
+                    self.read_tables()?;
+                } else {
+                    self.tables_read = input.read_bits(1) == 0;
+                    return Ok(false);
+                }
+            }
+            257 => self.read_filter_lz(window)?,
+            258 => {
+                if self.last_length != 0 {
+                    window.copy_match(self.last_length, self.old_dist[0])?;
+                }
+            }
+            259..=262 => {
+                let index = symbol - 259;
+                let dist = self.old_dist[index];
+                self.old_dist.copy_within(0..index, 1);
+                self.old_dist[0] = dist;
+                let slot = tables.rep_len.decode(input) as usize;
+                let len = Self::read_length(input, slot, 2);
+                self.last_length = len;
+                window.copy_match(len, dist)?;
+            }
+            263..=270 => {
+                let slot = symbol - 263;
+                let bits = SHORT_DIST_BITS[slot] as u32;
+                let dist = (SHORT_DIST_BASES[slot] as u32 + 1 + input.read_bits(bits)) as u64;
+                self.insert_old_dist(dist);
+                self.last_length = 2;
+                window.copy_match(2, dist)?;
+            }
+            _ => {
+                let mut len = Self::read_length(input, symbol - 271, 3);
+                let slot = tables.dist.decode(input) as usize;
+                let dist = self.read_distance(slot);
+                if dist >= 0x2000 {
+                    len += 1;
+                    if dist >= 0x40000 {
+                        len += 1;
+                    }
+                }
+                self.insert_old_dist(dist);
+                self.last_length = len;
+                window.copy_match(len, dist)?;
+            }
+        }
+        Ok(true)
+    }
+    // Decodes until the window reaches `limit` or the file ends
+    pub fn decode(&mut self, window: &mut LzWindow, limit: u64) -> anyhow::Result<()> {
+        if self.need_tables && !self.file_done {
+            self.need_tables = false;
+            // NOTE: Empty files of solid streams may have no packed data at all
+            let input = match &mut self.input {
+                Input::Lz(x) => x,
+                Input::Ppm(x) => x.get_mut(),
+            };
+            input.fill()?;
+            if input.is_at_end() {
+                self.file_done = true;
+                return Ok(());
+            }
+            self.read_tables()?;
+        }
+        while !self.file_done && window.get_pos() < limit {
+            let has_more = match &mut self.input {
+                Input::Lz(input) => {
+                    input.fill()?;
+                    !input.is_at_end() && self.decode_lz(window)?
+                }
+                Input::Ppm(decoder) => {
+                    !decoder.get_ref().is_exhausted() && self.decode_ppm(window)?
+                }
+            };
+            self.file_done = !has_more;
+        }
+        // NOTE: Symbols read past the end of truncated data decode to garbage
+        let is_exhausted = match &self.input {
+            Input::Lz(x) => x.is_exhausted(),
+            Input::Ppm(x) => x.get_ref().is_exhausted(),
+        };
+        if is_exhausted {
+            anyhow::bail!("unexpected end of RAR data");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+// RAR 5 decoding (also used by RAR 7, which only adds longer distances)
+
+use super::unpack::{read_code_lengths, BitInput, FilterKind, HuffmanTable, LzWindow};
+
+const NC: usize = 306;
+const DC: usize = 64;
+// NOTE: RAR 7 allows dictionaries up to 1 TiB
+const DC_EXTRA: usize = 80;
+const LDC: usize = 16;
+const RC: usize = 44;
+
+const MAX_FILTER_BLOCK_SIZE: u64 = 0x400000;
+const MAX_UNPACK_FILTERS: usize = 8192;
+
+const FILTER_DELTA: u32 = 0;
+const FILTER_E8: u32 = 1;
+const FILTER_E8E9: u32 = 2;
+const FILTER_ARM: u32 = 3;
+
+struct Tables {
+    main: HuffmanTable,
+    dist: HuffmanTable,
+    low_dist: HuffmanTable,
+    rep_len: HuffmanTable,
+}
+
+// Compressed data is split into blocks, each possibly coming with new tables
+#[derive(Default)]
+struct BlockHeader {
+    // Absolute position of the first byte after the header
+    start: u64,
+    size: u64,
+    // Bits used in the last byte of the block
+    bit_size: u32,
+    is_last: bool,
+    has_tables: bool,
+}
+
+pub(super) struct Unpack50<'a> {
+    input: BitInput<'a>,
+    extra_dist: bool,
+    tables: Option<Tables>,
+    block: Option<BlockHeader>,
+    old_dist: [u64; 4],
+    last_length: u32,
+    file_done: bool,
+}
+
+fn read_block_header(input: &mut BitInput) -> anyhow::Result<BlockHeader> {
+    input.fill()?;
+    input.align();
+    let flags = input.read_bits(8);
+    let byte_count = ((flags >> 3) & 3) + 1;
+    if byte_count == 4 {
+        anyhow::bail!("invalid RAR block header");
+    }
+    let checksum = input.read_bits(8);
+    let mut size = 0;
+    for i in 0..byte_count {
+        size += input.read_bits(8) << (i * 8);
+    }
+    if checksum != (0x5a ^ flags ^ size ^ (size >> 8) ^ (size >> 16)) & 0xff {
+        anyhow::bail!("RAR block header checksum mismatch");
+    }
+    Ok(BlockHeader {
+        start: input.position().0,
+        size: size as u64,
+        bit_size: (flags & 7) + 1,
+        is_last: flags & 0x40 != 0,
+        has_tables: flags & 0x80 != 0,
+    })
+}
+
+fn read_tables(input: &mut BitInput, extra_dist: bool) -> anyhow::Result<Tables> {
+    let dc = if extra_dist { DC_EXTRA } else { DC };
+    let mut lengths = [0u8; NC + DC_EXTRA + LDC + RC];
+    let lengths = &mut lengths[..NC + dc + LDC + RC];
+    input.fill()?;
+    read_code_lengths(input, lengths, None)?;
+    let (main, rest) = lengths.split_at(NC);
+    let (dist, rest) = rest.split_at(dc);
+    let (low_dist, rep_len) = rest.split_at(LDC);
+    Ok(Tables {
+        main: HuffmanTable::new(main),
+        dist: HuffmanTable::new(dist),
+        low_dist: HuffmanTable::new(low_dist),
+        rep_len: HuffmanTable::new(rep_len),
+    })
+}
+
+fn slot_to_length(input: &mut BitInput, slot: u32) -> u32 {
+    if slot < 8 {
+        return slot + 2;
+    }
+    let bits = slot / 4 - 1;
+    2 + ((4 | (slot & 3)) << bits) + input.read_bits(bits)
+}
+
+// Reads a number stored in 1 to 4 bytes, as used by filter parameters
+fn read_filter_data(input: &mut BitInput) -> u64 {
+    let byte_count = input.read_bits(2) + 1;
+    (0..byte_count).fold(0, |data, i| data | (input.read_bits(8) as u64) << (i * 8))
+}
+
+impl<'a> Unpack50<'a> {
+    pub fn new(extra_dist: bool) -> Self {
+        Self {
+            input: BitInput::from_bytes(Vec::new()),
+            extra_dist,
+            tables: None,
+            block: None,
+            old_dist: [0; 4],
+            last_length: 0,
+            file_done: true,
+        }
+    }
+    pub fn start_file(&mut self, input: BitInput<'a>, solid: bool) {
+        self.input = input;
+        self.block = None;
+        self.file_done = false;
+        if !solid {
+            self.tables = None;
+            self.old_dist = [0; 4];
+            self.last_length = 0;
+        }
+    }
+    pub fn is_file_done(&self) -> bool {
+        self.file_done
+    }
+    // Moves on to the next block once the current one is consumed, returning false at
+    // the end of the file
+    fn next_block(&mut self) -> anyhow::Result<bool> {
+        loop {
+            if let Some(block) = &self.block {
+                let (pos, bit) = self.input.position();
+                let end = block.start + block.size;
+                if pos + 1 < end || (pos + 1 == end && bit < block.bit_size) {
+                    return Ok(true);
+                }
+                if block.is_last {
+                    return Ok(false);
+                }
+            }
+            if self.input.is_at_end() {
+                return Ok(false);
+            }
+            let block = read_block_header(&mut self.input)?;
+            if block.has_tables {
+                self.tables = Some(read_tables(&mut self.input, self.extra_dist)?);
+            } else if self.tables.is_none() {
+                anyhow::bail!("RAR block has no Huffman tables");
+            }
+            self.block = Some(block);
+        }
+    }
+    fn read_distance(&mut self, slot: u32) -> u64 {
+        let tables = self.tables.as_ref().unwrap();
+        if slot < 4 {
+            return slot as u64 + 1;
+        }
+        let bits = slot / 2 - 1;
+        let mut dist = 1 + ((2 | (slot as u64 & 1)) << bits);
+        if bits >= 4 {
+            if bits > 4 {
+                let high = self.input.peek64() >> (68 - bits);
+                dist += high << 4;
+                self.input.skip(bits - 4);
+            }
+            dist += tables.low_dist.decode(&mut self.input) as u64;
+        } else {
+            dist += self.input.read_bits(bits) as u64;
+        }
+        dist
+    }
+    fn read_filter(&mut self, window: &mut LzWindow) -> anyhow::Result<()> {
+        let start = read_filter_data(&mut self.input);
+        let mut len = read_filter_data(&mut self.input);
+        if len > MAX_FILTER_BLOCK_SIZE {
+            len = 0;
+        }
+        let kind = match self.input.read_bits(3) {
+            FILTER_DELTA => FilterKind::Delta {
+                channels: self.input.read_bits(5) as usize + 1,
+            },
+            FILTER_E8 => FilterKind::E8 {
+                e9: false,
+                wrap_offset: true,
+            },
+            FILTER_E8E9 => FilterKind::E8 {
+                e9: true,
+                wrap_offset: true,
+            },
+            FILTER_ARM => FilterKind::Arm,
+            kind => anyhow::bail!("unsupported RAR filter {kind}"),
+        };
+        if window.get_filter_count() >= MAX_UNPACK_FILTERS {
+            anyhow::bail!("too many RAR filters");
+        }
+        window.add_filter(start, len as usize, kind);
+        Ok(())
+    }
+    // Decodes until the window reaches `limit` or the file ends
+    pub fn decode(&mut self, window: &mut LzWindow, limit: u64) -> anyhow::Result<()> {
+        while !self.file_done && window.get_pos() < limit {
+            self.input.fill()?;
+            if !self.next_block()? || self.input.is_exhausted() {
+                self.file_done = true;
+                break;
+            }
+            let tables = self.tables.as_ref().unwrap();
+            let symbol = tables.main.decode(&mut self.input);
+            if symbol < 256 {
+                window.put(symbol as u8);
+            } else if symbol >= 262 {
+                let mut len = slot_to_length(&mut self.input, symbol - 262);
+                let dist_slot = tables.dist.decode(&mut self.input);
+                let dist = self.read_distance(dist_slot);
+                if dist > 0x100 {
+                    len += 1;
+                    if dist > 0x2000 {
+                        len += 1;
+                        if dist > 0x40000 {
+                            len += 1;
+                        }
+                    }
+                }
+                self.old_dist.copy_within(0..3, 1);
+                self.old_dist[0] = dist;
+                self.last_length = len;
+                window.copy_match(len, dist)?;
+            } else if symbol == 256 {
+                self.read_filter(window)?;
+            } else if symbol == 257 {
+                if self.last_length != 0 {
+                    window.copy_match(self.last_length, self.old_dist[0])?;
+                }
+            } else {
+                let index = (symbol - 258) as usize;
+                let dist = self.old_dist[index];
+                self.old_dist.copy_within(0..index, 1);
+                self.old_dist[0] = dist;
+                let len_slot = tables.rep_len.decode(&mut self.input);
+                let len = slot_to_length(&mut self.input, len_slot);
+                self.last_length = len;
+                window.copy_match(len, dist)?;
+            }
+        }
+        // NOTE: Symbols read past the end of truncated data decode to garbage
+        if self.input.is_exhausted() {
+            anyhow::bail!("unexpected end of RAR data");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+// Normalization of entry paths stored in archives, so that entries always stay
+// inside the archive and get names which are valid on Windows
+
+use std::borrow::Cow;
+
+// NOTE: Reserved with any extension as well
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_bad_char(c: char) -> bool {
+    c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*')
+}
+
+// Replaces characters not allowed in file names, and renames reserved device names
+pub(super) fn sanitize_name(name: &str) -> Cow<'_, str> {
+    // NOTE: Trailing dots and spaces would be silently dropped by Windows
+    let trimmed_len = name.trim_end_matches(['.', ' ']).len();
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let is_reserved = RESERVED_NAMES.iter().any(|x| x.eq_ignore_ascii_case(stem));
+    if !is_reserved && trimmed_len == name.len() && !name.contains(is_bad_char) {
+        return Cow::Borrowed(name);
+    }
+    let mut result = String::with_capacity(name.len() + 1);
+    if is_reserved {
+        result.push('_');
+    }
+    result.extend(
+        name[..trimmed_len]
+            .chars()
+            .map(|c| if is_bad_char(c) { '_' } else { c }),
+    );
+    result.push_str(&"_".repeat(name.len() - trimmed_len));
+    Cow::Owned(result)
+}
+
+// Splits an entry path into sanitized components, dropping empty, `.` and `..`
+// ones as well as drive letters, so that absolute paths become relative ones
+// NOTE: Backslashes are separators as well, as some archivers store them
+pub(super) fn split_entry_path(path: &str) -> Vec<Cow<'_, str>> {
+    let mut components = Vec::new();
+    for (i, segment) in path.split(['/', '\\']).enumerate() {
+        let is_drive = i == 0
+            && segment.len() == 2
+            && segment.as_bytes()[0].is_ascii_alphabetic()
+            && segment.ends_with(':');
+        if is_drive || matches!(segment, "" | "." | "..") {
+            continue;
+        }
+        components.push(sanitize_name(segment));
+    }
+    components
+}
+
+// Appends ` (n)` to the stem of `name`, taking the lowest n which is not taken yet
+pub(super) fn make_unique_name(name: &str, is_taken: impl Fn(&str) -> bool) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(pos) if pos > 0 => name.split_at(pos),
+        _ => (name, ""),
+    };
+    (1..)
+        .map(|n| format!("{stem} ({n}){ext}"))
+        .find(|x| !is_taken(x))
+        .unwrap()
+}
@@ -0,0 +1,1248 @@
+// 7z archive handler
+// NOTE: Files in a solid block (folder) are stored back to back in a single
+//       compressed stream, so reading a file means decoding everything in
+//       front of it. Decoded output is cached in chunks (up to a size cap) and
+//       a few decoders are kept alive, so that sequential reads through a
+//       solid block only decode it once.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufReader, Read},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{
+    fs_provider::{
+        ContainerWarning, CursorFile, FileAttributes, FileStatInfo, FileSystemError,
+        FileSystemResult, SegPath,
+    },
+    util::{calculate_hash, CaselessStr, CaselessString},
+};
+
+use super::{
+    cache::{ArchiveCacheHandle, LiveDecoderPool},
+    inflate::{InflateFormat, Inflater},
+    tree::{
+        filetime_to_system_time, get_or_create_folder, insert_entry, normalize_entry_path,
+        TreeFolder, TreeWarnings,
+    },
+    ArchiveHandlerOpenContext,
+};
+
+const SIGNATURE: [u8; 6] = [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c];
+const SIGNATURE_HEADER_SIZE: u64 = 32;
+
+const ID_END: u64 = 0x00;
+const ID_HEADER: u64 = 0x01;
+const ID_ARCHIVE_PROPERTIES: u64 = 0x02;
+const ID_ADDITIONAL_STREAMS_INFO: u64 = 0x03;
+const ID_MAIN_STREAMS_INFO: u64 = 0x04;
+const ID_FILES_INFO: u64 = 0x05;
+const ID_PACK_INFO: u64 = 0x06;
+const ID_UNPACK_INFO: u64 = 0x07;
+const ID_SUBSTREAMS_INFO: u64 = 0x08;
+const ID_SIZE: u64 = 0x09;
+const ID_CRC: u64 = 0x0a;
+const ID_FOLDER: u64 = 0x0b;
+const ID_CODERS_UNPACK_SIZE: u64 = 0x0c;
+const ID_NUM_UNPACK_STREAM: u64 = 0x0d;
+const ID_EMPTY_STREAM: u64 = 0x0e;
+const ID_EMPTY_FILE: u64 = 0x0f;
+const ID_ANTI: u64 = 0x10;
+const ID_NAME: u64 = 0x11;
+const ID_CTIME: u64 = 0x12;
+const ID_ATIME: u64 = 0x13;
+const ID_MTIME: u64 = 0x14;
+const ID_WIN_ATTRIBUTES: u64 = 0x15;
+const ID_ENCODED_HEADER: u64 = 0x17;
+
+const METHOD_COPY: u64 = 0x00;
+const METHOD_DELTA: u64 = 0x03;
+const METHOD_ARM64: u64 = 0x0a;
+const METHOD_RISCV: u64 = 0x0b;
+const METHOD_LZMA2: u64 = 0x21;
+const METHOD_LZMA: u64 = 0x030101;
+const METHOD_BCJ_X86: u64 = 0x03030103;
+const METHOD_BCJ2: u64 = 0x0303011b;
+const METHOD_BCJ_PPC: u64 = 0x03030205;
+const METHOD_BCJ_IA64: u64 = 0x03030401;
+const METHOD_BCJ_ARM: u64 = 0x03030501;
+const METHOD_BCJ_ARMT: u64 = 0x03030701;
+const METHOD_BCJ_SPARC: u64 = 0x03030805;
+const METHOD_PPMD: u64 = 0x030401;
+const METHOD_DEFLATE: u64 = 0x040108;
+const METHOD_DEFLATE64: u64 = 0x040109;
+const METHOD_BZIP2: u64 = 0x040202;
+const METHOD_ZSTD: u64 = 0x04f71101;
+const METHOD_AES: u64 = 0x06f10701;
+
+const FILE_ATTRIBUTE_READONLY: u32 = 0x01;
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+
+const SOLID_CHUNK_SIZE: u64 = 1024 * 1024;
+
+struct HeaderReader<'h> {
+    data: &'h [u8],
+}
+
+impl<'h> HeaderReader<'h> {
+    fn read_bytes(&mut self, len: u64) -> anyhow::Result<&'h [u8]> {
+        if len > self.data.len() as u64 {
+            anyhow::bail!("unexpected end of 7z header");
+        }
+        let (bytes, rest) = self.data.split_at(len as usize);
+        self.data = rest;
+        Ok(bytes)
+    }
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+    // Variable length number, where the leading one bits of the first byte
+    // give the count of extra bytes
+    fn read_number(&mut self) -> anyhow::Result<u64> {
+        let first = self.read_u8()?;
+        let mut mask = 0x80u8;
+        let mut value = 0u64;
+        for i in 0..8 {
+            if first & mask == 0 {
+                let high = (first & mask.wrapping_sub(1)) as u64;
+                return Ok(value | (high << (8 * i)));
+            }
+            value |= (self.read_u8()? as u64) << (8 * i);
+            mask >>= 1;
+        }
+        Ok(value)
+    }
+    fn read_count(&mut self) -> anyhow::Result<usize> {
+        let value = self.read_number()?;
+        // NOTE: Sanity check, as every item takes at least a bit
+        if value > self.data.len() as u64 * 8 + 8 {
+            anyhow::bail!("too many items in 7z header");
+        }
+        Ok(value as _)
+    }
+    fn expect(&mut self, id: u64) -> anyhow::Result<()> {
+        let actual = self.read_number()?;
+        if actual != id {
+            anyhow::bail!("unexpected 7z property {actual:#x} (expected {id:#x})");
+        }
+        Ok(())
+    }
+    fn read_bit_vector(&mut self, len: usize) -> anyhow::Result<Vec<bool>> {
+        let bytes = self.read_bytes(((len + 7) / 8) as _)?;
+        Ok((0..len)
+            .map(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect())
+    }
+    // Bit vector preceded by an "all defined" flag
+    fn read_defined_vector(&mut self, len: usize) -> anyhow::Result<Vec<bool>> {
+        if self.read_u8()? != 0 {
+            return Ok(vec![true; len]);
+        }
+        self.read_bit_vector(len)
+    }
+    fn read_digests(&mut self, len: usize) -> anyhow::Result<Vec<Option<u32>>> {
+        self.read_defined_vector(len)?
+            .into_iter()
+            .map(|defined| defined.then(|| self.read_u32()).transpose())
+            .collect()
+    }
+    fn skip_external(&mut self) -> anyhow::Result<()> {
+        if self.read_u8()? != 0 {
+            anyhow::bail!("external 7z header data is not supported");
+        }
+        Ok(())
+    }
+}
+
+struct SevenZipCoder {
+    method: u64,
+    num_in_streams: usize,
+    num_out_streams: usize,
+    props: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+struct SevenZipBindPair {
+    in_index: usize,
+    out_index: usize,
+}
+
+// A solid block, made of coders connected with bind pairs
+struct SevenZipFolder {
+    coders: Vec<SevenZipCoder>,
+    bind_pairs: Vec<SevenZipBindPair>,
+    // Coder input streams which are fed from pack streams
+    packed_streams: Vec<usize>,
+    // Index of the first pack stream used by this folder
+    first_pack_stream: usize,
+    // Sizes of all coder output streams
+    unpack_sizes: Vec<u64>,
+    crc: Option<u32>,
+}
+
+impl SevenZipFolder {
+    fn get_main_out_stream(&self) -> anyhow::Result<usize> {
+        (0..self.unpack_sizes.len())
+            .find(|&i| !self.bind_pairs.iter().any(|x| x.out_index == i))
+            .ok_or_else(|| anyhow::anyhow!("7z folder has no main output stream"))
+    }
+    fn get_unpack_size(&self) -> u64 {
+        self.get_main_out_stream()
+            .map_or(0, |i| self.unpack_sizes[i])
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SevenZipPackStream {
+    start: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct SevenZipStreamsInfo {
+    pack_streams: Vec<SevenZipPackStream>,
+    folders: Vec<SevenZipFolder>,
+    // Number of files stored in each folder
+    num_unpack_streams: Vec<usize>,
+    // Sizes of files in all folders
+    unpack_stream_sizes: Vec<u64>,
+}
+
+fn read_pack_info(reader: &mut HeaderReader, info: &mut SevenZipStreamsInfo) -> anyhow::Result<()> {
+    let mut pos = SIGNATURE_HEADER_SIZE
+        .checked_add(reader.read_number()?)
+        .ok_or_else(|| anyhow::anyhow!("invalid 7z header"))?;
+    let num_pack_streams = reader.read_count()?;
+    loop {
+        match reader.read_number()? {
+            ID_END => break,
+            ID_SIZE => {
+                for _ in 0..num_pack_streams {
+                    let size = reader.read_number()?;
+                    info.pack_streams
+                        .push(SevenZipPackStream { start: pos, size });
+                    pos = pos
+                        .checked_add(size)
+                        .ok_or_else(|| anyhow::anyhow!("invalid 7z header"))?;
+                }
+            }
+            ID_CRC => {
+                reader.read_digests(num_pack_streams)?;
+            }
+            id => anyhow::bail!("unexpected 7z pack info property {id:#x}"),
+        }
+    }
+    Ok(())
+}
+
+fn read_folder(reader: &mut HeaderReader) -> anyhow::Result<SevenZipFolder> {
+    let num_coders = reader.read_count()?;
+    let mut coders = Vec::with_capacity(num_coders);
+    for _ in 0..num_coders {
+        let flags = reader.read_u8()?;
+        if flags & 0x80 != 0 {
+            anyhow::bail!("7z alternative coder methods are not supported");
+        }
+        let id_size = (flags & 0x0f) as u64;
+        if id_size > 8 {
+            anyhow::bail!("7z coder method id is too long");
+        }
+        let method = reader
+            .read_bytes(id_size)?
+            .iter()
+            .fold(0u64, |acc, &x| (acc << 8) | x as u64);
+        let (num_in_streams, num_out_streams) = if flags & 0x10 != 0 {
+            (reader.read_count()?, reader.read_count()?)
+        } else {
+            (1, 1)
+        };
+        let props = if flags & 0x20 != 0 {
+            let size = reader.read_number()?;
+            reader.read_bytes(size)?.to_vec()
+        } else {
+            Vec::new()
+        };
+        coders.push(SevenZipCoder {
+            method,
+            num_in_streams,
+            num_out_streams,
+            props,
+        });
+    }
+
+    let total_in_streams: usize = coders.iter().map(|x| x.num_in_streams).sum();
+    let total_out_streams: usize = coders.iter().map(|x| x.num_out_streams).sum();
+    let Some(num_bind_pairs) = total_out_streams.checked_sub(1) else {
+        anyhow::bail!("7z folder has no coders");
+    };
+    let mut bind_pairs = Vec::with_capacity(num_bind_pairs);
+    for _ in 0..num_bind_pairs {
+        bind_pairs.push(SevenZipBindPair {
+            in_index: reader.read_count()?,
+            out_index: reader.read_count()?,
+        });
+    }
+    let Some(num_packed_streams) = total_in_streams.checked_sub(num_bind_pairs) else {
+        anyhow::bail!("invalid 7z bind pairs");
+    };
+    let packed_streams = if num_packed_streams == 1 {
+        let unbound = (0..total_in_streams)
+            .find(|&i| !bind_pairs.iter().any(|x| x.in_index == i))
+            .ok_or_else(|| anyhow::anyhow!("7z folder has no packed stream"))?;
+        vec![unbound]
+    } else {
+        (0..num_packed_streams)
+            .map(|_| reader.read_count())
+            .collect::<anyhow::Result<_>>()?
+    };
+
+    Ok(SevenZipFolder {
+        coders,
+        bind_pairs,
+        packed_streams,
+        first_pack_stream: 0,
+        unpack_sizes: Vec::new(),
+        crc: None,
+    })
+}
+
+fn read_unpack_info(
+    reader: &mut HeaderReader,
+    info: &mut SevenZipStreamsInfo,
+) -> anyhow::Result<()> {
+    reader.expect(ID_FOLDER)?;
+    let num_folders = reader.read_count()?;
+    reader.skip_external()?;
+    let mut first_pack_stream = 0;
+    for _ in 0..num_folders {
+        let mut folder = read_folder(reader)?;
+        folder.first_pack_stream = first_pack_stream;
+        first_pack_stream += folder.packed_streams.len();
+        info.folders.push(folder);
+    }
+    reader.expect(ID_CODERS_UNPACK_SIZE)?;
+    for folder in &mut info.folders {
+        let total_out_streams = folder.coders.iter().map(|x| x.num_out_streams).sum();
+        folder.unpack_sizes = (0..total_out_streams)
+            .map(|_| reader.read_number())
+            .collect::<anyhow::Result<_>>()?;
+    }
+    loop {
+        match reader.read_number()? {
+            ID_END => break,
+            ID_CRC => {
+                let digests = reader.read_digests(num_folders)?;
+                for (folder, crc) in info.folders.iter_mut().zip(digests) {
+                    folder.crc = crc;
+                }
+            }
+            id => anyhow::bail!("unexpected 7z unpack info property {id:#x}"),
+        }
+    }
+    Ok(())
+}
+
+fn read_substreams_info(
+    reader: &mut HeaderReader,
+    info: &mut SevenZipStreamsInfo,
+) -> anyhow::Result<()> {
+    info.num_unpack_streams = vec![1; info.folders.len()];
+    let mut id = reader.read_number()?;
+    if id == ID_NUM_UNPACK_STREAM {
+        for count in &mut info.num_unpack_streams {
+            *count = reader.read_count()?;
+        }
+        id = reader.read_number()?;
+    }
+
+    // NOTE: The size of the last file in a folder is implied
+    let has_sizes = id == ID_SIZE;
+    info.unpack_stream_sizes.clear();
+    for (folder, &count) in info.folders.iter().zip(&info.num_unpack_streams) {
+        if count == 0 {
+            continue;
+        }
+        let mut sum = 0u64;
+        if has_sizes {
+            for _ in 1..count {
+                let size = reader.read_number()?;
+                info.unpack_stream_sizes.push(size);
+                sum = sum
+                    .checked_add(size)
+                    .ok_or_else(|| anyhow::anyhow!("invalid 7z header"))?;
+            }
+        }
+        let Some(last_size) = folder.get_unpack_size().checked_sub(sum) else {
+            anyhow::bail!("7z substream sizes exceed folder size");
+        };
+        info.unpack_stream_sizes.push(last_size);
+    }
+    if has_sizes {
+        id = reader.read_number()?;
+    }
+
+    loop {
+        match id {
+            ID_END => break,
+            ID_CRC => {
+                let num_digests = info
+                    .folders
+                    .iter()
+                    .zip(&info.num_unpack_streams)
+                    .map(|(folder, &count)| match (count, folder.crc) {
+                        (1, Some(_)) => 0,
+                        (count, _) => count,
+                    })
+                    .sum();
+                reader.read_digests(num_digests)?;
+            }
+            id => anyhow::bail!("unexpected 7z substreams info property {id:#x}"),
+        }
+        id = reader.read_number()?;
+    }
+    Ok(())
+}
+
+fn read_streams_info(reader: &mut HeaderReader) -> anyhow::Result<SevenZipStreamsInfo> {
+    let mut info = SevenZipStreamsInfo::default();
+    let mut has_substreams_info = false;
+    loop {
+        match reader.read_number()? {
+            ID_END => break,
+            ID_PACK_INFO => read_pack_info(reader, &mut info)?,
+            ID_UNPACK_INFO => read_unpack_info(reader, &mut info)?,
+            ID_SUBSTREAMS_INFO => {
+                read_substreams_info(reader, &mut info)?;
+                has_substreams_info = true;
+            }
+            id => anyhow::bail!("unexpected 7z streams info property {id:#x}"),
+        }
+    }
+    if !has_substreams_info {
+        info.num_unpack_streams = vec![1; info.folders.len()];
+        info.unpack_stream_sizes = info.folders.iter().map(|x| x.get_unpack_size()).collect();
+    }
+    Ok(info)
+}
+
+#[derive(Default)]
+struct SevenZipFileRecord {
+    name: String,
+    has_stream: bool,
+    is_dir: bool,
+    is_anti: bool,
+    attributes: Option<u32>,
+    creation_time: Option<SystemTime>,
+    last_access_time: Option<SystemTime>,
+    last_write_time: Option<SystemTime>,
+}
+
+fn read_files_info(reader: &mut HeaderReader) -> anyhow::Result<Vec<SevenZipFileRecord>> {
+    let num_files = reader.read_count()?;
+    let mut records: Vec<_> = (0..num_files)
+        .map(|_| SevenZipFileRecord {
+            has_stream: true,
+            ..Default::default()
+        })
+        .collect();
+    let mut empty_files = Vec::new();
+    let mut anti_files = Vec::new();
+    loop {
+        let id = reader.read_number()?;
+        if id == ID_END {
+            break;
+        }
+        let size = reader.read_number()?;
+        let mut prop = HeaderReader {
+            data: reader.read_bytes(size)?,
+        };
+        let num_empty_streams = records.iter().filter(|x| !x.has_stream).count();
+        match id {
+            ID_EMPTY_STREAM => {
+                let empty_streams = prop.read_bit_vector(num_files)?;
+                for (record, is_empty) in records.iter_mut().zip(empty_streams) {
+                    record.has_stream = !is_empty;
+                }
+            }
+            ID_EMPTY_FILE => empty_files = prop.read_bit_vector(num_empty_streams)?,
+            ID_ANTI => anti_files = prop.read_bit_vector(num_empty_streams)?,
+            ID_NAME => {
+                prop.skip_external()?;
+                let mut names = prop
+                    .data
+                    .chunks_exact(2)
+                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                for record in &mut records {
+                    let name: Vec<_> = names.by_ref().take_while(|&x| x != 0).collect();
+                    record.name = String::from_utf16_lossy(&name);
+                }
+            }
+            ID_CTIME | ID_ATIME | ID_MTIME | ID_WIN_ATTRIBUTES => {
+                let defined = prop.read_defined_vector(num_files)?;
+                prop.skip_external()?;
+                for (record, defined) in records.iter_mut().zip(defined) {
+                    if !defined {
+                        continue;
+                    }
+                    match id {
+                        ID_CTIME => {
+                            record.creation_time = Some(filetime_to_system_time(prop.read_u64()?))
+                        }
+                        ID_ATIME => {
+                            record.last_access_time =
+                                Some(filetime_to_system_time(prop.read_u64()?))
+                        }
+                        ID_MTIME => {
+                            record.last_write_time = Some(filetime_to_system_time(prop.read_u64()?))
+                        }
+                        _ => record.attributes = Some(prop.read_u32()?),
+                    }
+                }
+            }
+            // Padding, start positions, comments, etc.
+            _ => (),
+        }
+    }
+
+    for (empty_idx, record) in records.iter_mut().filter(|x| !x.has_stream).enumerate() {
+        let is_empty_file = empty_files.get(empty_idx).copied().unwrap_or(false);
+        record.is_anti = anti_files.get(empty_idx).copied().unwrap_or(false);
+        record.is_dir = !is_empty_file;
+    }
+    for record in &mut records {
+        if record
+            .attributes
+            .is_some_and(|x| x & FILE_ATTRIBUTE_DIRECTORY != 0)
+        {
+            record.is_dir = true;
+        }
+    }
+    Ok(records)
+}
+
+// Adapts the deflate decoder for use in coder chains
+struct InflateReader<R>(Inflater<R>);
+
+impl<R: Read> Read for InflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .read(buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn read_props_u32(props: &[u8], offset: usize) -> anyhow::Result<u32> {
+    props
+        .get(offset..offset + 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| anyhow::anyhow!("7z coder properties are too short"))
+}
+
+type BoxedReader<'a> = Box<dyn Read + Send + 'a>;
+
+fn create_decoder<'a>(
+    coder: &SevenZipCoder,
+    mut inputs: Vec<BoxedReader<'a>>,
+    unpack_size: u64,
+) -> anyhow::Result<BoxedReader<'a>> {
+    use lzma_rust2::filter::{bcj::BcjReader, bcj2::Bcj2Reader, delta::DeltaReader};
+
+    if coder.method == METHOD_BCJ2 {
+        return Ok(Box::new(Bcj2Reader::new(inputs, unpack_size)));
+    }
+    let (Some(input), 1) = (inputs.pop(), coder.num_in_streams) else {
+        anyhow::bail!("unexpected input streams for 7z coder {:#x}", coder.method);
+    };
+    let props = coder.props.as_slice();
+    Ok(match coder.method {
+        METHOD_COPY => input,
+        METHOD_LZMA => {
+            let props_byte = *props
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("missing LZMA properties"))?;
+            let dict_size = read_props_u32(props, 1)?;
+            Box::new(lzma_rust2::LzmaReader::new_with_props(
+                input,
+                unpack_size,
+                props_byte,
+                dict_size,
+                None,
+            )?)
+        }
+        METHOD_LZMA2 => {
+            let dict_size = match props.first() {
+                Some(&x) if x < 40 => (2 | (x as u32 & 1)) << (x / 2 + 11),
+                Some(40) => u32::MAX,
+                _ => anyhow::bail!("invalid LZMA2 properties"),
+            };
+            // NOTE: The dictionary never needs to be larger than the decoded data
+            let dict_size = dict_size.min(unpack_size.try_into().unwrap_or(u32::MAX));
+            Box::new(lzma_rust2::Lzma2Reader::new(input, dict_size, None))
+        }
+        METHOD_PPMD => {
+            let order = *props
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("missing PPMd properties"))?;
+            let mem_size = read_props_u32(props, 1)?;
+            // NOTE: The stream has no end marker, so it must be bounded
+            Box::new(ppmd_rust::Ppmd7Decoder::new(input, order as _, mem_size)?.take(unpack_size))
+        }
+        METHOD_DELTA => {
+            let distance = props.first().map_or(1, |&x| x as usize + 1);
+            Box::new(DeltaReader::new(input, distance))
+        }
+        METHOD_BCJ_X86 => Box::new(BcjReader::new_x86(input, 0)),
+        METHOD_BCJ_PPC => Box::new(BcjReader::new_ppc(input, 0)),
+        METHOD_BCJ_IA64 => Box::new(BcjReader::new_ia64(input, 0)),
+        METHOD_BCJ_ARM => Box::new(BcjReader::new_arm(input, 0)),
+        METHOD_BCJ_ARMT => Box::new(BcjReader::new_arm_thumb(input, 0)),
+        METHOD_BCJ_SPARC => Box::new(BcjReader::new_sparc(input, 0)),
+        METHOD_ARM64 => Box::new(BcjReader::new_arm64(input, 0)),
+        METHOD_RISCV => Box::new(BcjReader::new_riscv(input, 0)),
+        METHOD_DEFLATE => Box::new(InflateReader(Inflater::new(input, InflateFormat::Deflate))),
+        METHOD_DEFLATE64 => Box::new(InflateReader(Inflater::new(
+            input,
+            InflateFormat::Deflate64,
+        ))),
+        METHOD_BZIP2 => Box::new(bzip2::read::BzDecoder::new(input)),
+        METHOD_ZSTD => Box::new(zstd::stream::read::Decoder::new(input)?),
+        // TODO: Support encrypted archives with the configured passwords
+        METHOD_AES => anyhow::bail!("encrypted 7z archives are not supported"),
+        method => anyhow::bail!("unsupported 7z coder {method:#x}"),
+    })
+}
+
+// Builds the decoder chain for the output stream `out_index` of `folder`
+fn open_folder_stream<'a>(
+    file: &'a dyn crate::fs_provider::File,
+    folder: &SevenZipFolder,
+    pack_streams: &[SevenZipPackStream],
+    out_index: usize,
+    depth: usize,
+) -> anyhow::Result<BoxedReader<'a>> {
+    if depth > folder.coders.len() {
+        anyhow::bail!("7z coders form a cycle");
+    }
+    let (mut in_base, mut out_base) = (0, 0);
+    let coder = folder
+        .coders
+        .iter()
+        .find(|x| {
+            if (out_base..out_base + x.num_out_streams).contains(&out_index) {
+                return true;
+            }
+            in_base += x.num_in_streams;
+            out_base += x.num_out_streams;
+            false
+        })
+        .ok_or_else(|| anyhow::anyhow!("invalid 7z coder output stream {out_index}"))?;
+    if coder.num_out_streams != 1 {
+        anyhow::bail!("7z coders with multiple outputs are not supported");
+    }
+
+    let mut inputs = Vec::with_capacity(coder.num_in_streams);
+    for in_index in in_base..in_base + coder.num_in_streams {
+        let bind_pair = folder.bind_pairs.iter().find(|x| x.in_index == in_index);
+        let input: BoxedReader = match bind_pair {
+            Some(x) => open_folder_stream(file, folder, pack_streams, x.out_index, depth + 1)?,
+            None => {
+                let pack_idx = folder
+                    .packed_streams
+                    .iter()
+                    .position(|&x| x == in_index)
+                    .ok_or_else(|| anyhow::anyhow!("unbound 7z coder input {in_index}"))?;
+                let pack = pack_streams
+                    .get(folder.first_pack_stream + pack_idx)
+                    .ok_or_else(|| anyhow::anyhow!("missing 7z pack stream"))?;
+                Box::new(BufReader::new(
+                    CursorFile::with_position(file, pack.start).take(pack.size),
+                ))
+            }
+        };
+        inputs.push(input);
+    }
+    create_decoder(coder, inputs, folder.unpack_sizes[out_index])
+}
+
+// Rough estimate of the memory held by the decoders of a folder, which is dominated
+// by the dictionaries of LZMA and the model of PPMd
+fn get_decoder_memory_size(folder: &SevenZipFolder) -> u64 {
+    // NOTE: Accounts for buffers of the other coders
+    const BASE_SIZE: u64 = 256 * 1024;
+    let unpack_size = folder.get_unpack_size();
+    folder
+        .coders
+        .iter()
+        .map(|coder| {
+            let props = coder.props.as_slice();
+            let dict_size = match coder.method {
+                METHOD_LZMA => read_props_u32(props, 1).map_or(0, |x| x as u64),
+                METHOD_LZMA2 => match props.first() {
+                    Some(&x) if x < 40 => (2 | (x as u64 & 1)) << (x / 2 + 11),
+                    _ => u32::MAX as u64,
+                },
+                METHOD_PPMD => return read_props_u32(props, 1).map_or(0, |x| x as u64),
+                _ => 0,
+            };
+            // NOTE: Dictionaries are never filled beyond the decoded data
+            dict_size.min(unpack_size)
+        })
+        .sum::<u64>()
+        + BASE_SIZE
+}
+
+fn open_folder<'a>(
+    file: &'a dyn crate::fs_provider::File,
+    folder: &SevenZipFolder,
+    pack_streams: &[SevenZipPackStream],
+) -> anyhow::Result<BoxedReader<'a>> {
+    let out_index = folder.get_main_out_stream()?;
+    let decoder = open_folder_stream(file, folder, pack_streams, out_index, 0)?;
+    Ok(Box::new(decoder.take(folder.unpack_sizes[out_index])))
+}
+
+fn read_header(file: &dyn crate::fs_provider::File) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut signature_header = [0u8; SIGNATURE_HEADER_SIZE as usize];
+    CursorFile::new(file).read_exact(&mut signature_header)?;
+    if signature_header[..6] != SIGNATURE {
+        anyhow::bail!("invalid 7z signature");
+    }
+    let mut reader = HeaderReader {
+        data: &signature_header[12..],
+    };
+    let next_header_offset = reader.read_u64()?;
+    let next_header_size = reader.read_u64()?;
+    let next_header_crc = reader.read_u32()?;
+    if next_header_size == 0 {
+        return Ok(None);
+    }
+
+    // NOTE: Both values are untrusted, so check them before allocating anything
+    let file_size = file.get_stat()?.size;
+    let next_header_start = SIGNATURE_HEADER_SIZE
+        .checked_add(next_header_offset)
+        .filter(|x| {
+            x.checked_add(next_header_size)
+                .is_some_and(|end| end <= file_size)
+        })
+        .ok_or_else(|| anyhow::anyhow!("7z header lies outside of the file"))?;
+    let mut header = vec![0u8; next_header_size.try_into()?];
+    CursorFile::with_position(file, next_header_start).read_exact(&mut header)?;
+    let mut crc = flate2::Crc::new();
+    crc.update(&header);
+    if crc.sum() != next_header_crc {
+        anyhow::bail!("7z header checksum mismatch");
+    }
+
+    // The header itself may be compressed (possibly more than once)
+    loop {
+        let mut reader = HeaderReader { data: &header };
+        match reader.read_number()? {
+            ID_HEADER => return Ok(Some(header)),
+            ID_ENCODED_HEADER => {
+                let info = read_streams_info(&mut reader)?;
+                let mut decoded = Vec::new();
+                for folder in &info.folders {
+                    let start = decoded.len();
+                    open_folder(file, folder, &info.pack_streams)?.read_to_end(&mut decoded)?;
+                    let mut crc = flate2::Crc::new();
+                    crc.update(&decoded[start..]);
+                    if folder.crc.is_some_and(|x| x != crc.sum()) {
+                        anyhow::bail!("7z encoded header checksum mismatch");
+                    }
+                }
+                header = decoded;
+            }
+            id => anyhow::bail!("unexpected 7z header type {id:#x}"),
+        }
+    }
+}
+
+fn skip_archive_properties(reader: &mut HeaderReader) -> anyhow::Result<()> {
+    while reader.read_number()? != ID_END {
+        let size = reader.read_number()?;
+        reader.read_bytes(size)?;
+    }
+    Ok(())
+}
+
+struct SevenZipFolderEntry {
+    children: BTreeMap<CaselessString, SevenZipEntry>,
+    index: u64,
+    attributes: FileAttributes,
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+}
+
+// Location of file data within the decoded output of a folder
+#[derive(Clone, Copy)]
+struct SevenZipFileData {
+    folder: usize,
+    offset: u64,
+}
+
+struct SevenZipFileEntry {
+    data: Option<SevenZipFileData>,
+    size: u64,
+    index: u64,
+    attributes: FileAttributes,
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+}
+
+enum SevenZipEntry {
+    Folder(SevenZipFolderEntry),
+    File(SevenZipFileEntry),
+}
+
+impl SevenZipFolderEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: 0,
+            is_dir: true,
+            attributes: self.attributes | FileAttributes::DirectoryFile,
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+        }
+    }
+}
+
+impl SevenZipFileEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: self.size,
+            is_dir: false,
+            attributes: self.attributes,
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+        }
+    }
+}
+
+impl SevenZipEntry {
+    fn as_borrowed(&self) -> BorrowedSevenZipEntry<'_> {
+        match self {
+            Self::Folder(e) => BorrowedSevenZipEntry::Folder(e),
+            Self::File(e) => BorrowedSevenZipEntry::File(e),
+        }
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        self.as_borrowed().get_file_stat_info()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum BorrowedSevenZipEntry<'a> {
+    Folder(&'a SevenZipFolderEntry),
+    File(&'a SevenZipFileEntry),
+}
+
+impl BorrowedSevenZipEntry<'_> {
+    fn is_dir(&self) -> bool {
+        matches!(self, Self::Folder(_))
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        match self {
+            Self::Folder(e) => e.get_file_stat_info(),
+            Self::File(e) => e.get_file_stat_info(),
+        }
+    }
+}
+
+fn convert_attributes(attributes: Option<u32>) -> FileAttributes {
+    let attributes = attributes.unwrap_or(0);
+    let mut result = FileAttributes::empty();
+    if attributes & FILE_ATTRIBUTE_READONLY != 0 {
+        result |= FileAttributes::Readonly;
+    }
+    if attributes & FILE_ATTRIBUTE_HIDDEN != 0 {
+        result |= FileAttributes::Hidden;
+    }
+    result
+}
+
+impl TreeFolder for SevenZipFolderEntry {
+    type Entry = SevenZipEntry;
+
+    fn children_mut(&mut self) -> &mut BTreeMap<CaselessString, SevenZipEntry> {
+        &mut self.children
+    }
+    fn new_implied_child(&self, index: u64) -> SevenZipEntry {
+        SevenZipEntry::Folder(SevenZipFolderEntry {
+            children: BTreeMap::new(),
+            index,
+            attributes: FileAttributes::empty(),
+            creation_time: self.creation_time,
+            last_access_time: self.last_access_time,
+            last_write_time: self.last_write_time,
+        })
+    }
+    fn entry_as_folder_mut(entry: &mut SevenZipEntry) -> Option<&mut Self> {
+        match entry {
+            SevenZipEntry::Folder(folder) => Some(folder),
+            SevenZipEntry::File(_) => None,
+        }
+    }
+    fn entry_is_folder(entry: &SevenZipEntry) -> bool {
+        matches!(entry, SevenZipEntry::Folder(_))
+    }
+}
+
+fn build_file_tree(
+    records: Vec<SevenZipFileRecord>,
+    streams: &SevenZipStreamsInfo,
+    root_stat: &FileStatInfo,
+) -> anyhow::Result<(SevenZipFolderEntry, Vec<ContainerWarning>)> {
+    let root_index = root_stat.index;
+    let mut root = SevenZipFolderEntry {
+        children: BTreeMap::new(),
+        index: root_index,
+        attributes: FileAttributes::empty(),
+        creation_time: root_stat.creation_time,
+        last_access_time: root_stat.last_access_time,
+        last_write_time: root_stat.last_write_time,
+    };
+    // NOTE: Used for "unique" index generation
+    let mut counter: u64 = 0;
+    let mut warnings = TreeWarnings::new("7z");
+
+    // Assign files with data to folders in order
+    let mut folder_idx = 0;
+    let mut stream_idx = 0;
+    let mut stream_in_folder = 0;
+    let mut folder_offset = 0;
+
+    for (record_idx, record) in records.into_iter().enumerate() {
+        let data = if record.has_stream {
+            while streams
+                .num_unpack_streams
+                .get(folder_idx)
+                .is_some_and(|&x| stream_in_folder >= x)
+            {
+                folder_idx += 1;
+                stream_in_folder = 0;
+                folder_offset = 0;
+            }
+            let Some(&size) = streams.unpack_stream_sizes.get(stream_idx) else {
+                anyhow::bail!("7z archive has more files than streams");
+            };
+            let data = SevenZipFileData {
+                folder: folder_idx,
+                offset: folder_offset,
+            };
+            stream_idx += 1;
+            stream_in_folder += 1;
+            folder_offset += size;
+            Some((data, size))
+        } else {
+            None
+        };
+        if record.is_anti {
+            continue;
+        }
+
+        let path = &record.name;
+        // NOTE: Paths are normalized, so that entries never escape the archive
+        let Some(components) = normalize_entry_path(path, &mut warnings) else {
+            continue;
+        };
+        let (filename, parents) = components.split_last().unwrap();
+        let make_index = || {
+            counter += 1;
+            calculate_hash(&(root_index, counter))
+        };
+
+        let attributes = convert_attributes(record.attributes);
+        let creation_time = record.creation_time.unwrap_or(root_stat.creation_time);
+        let last_access_time = record
+            .last_access_time
+            .unwrap_or(root_stat.last_access_time);
+        let last_write_time = record.last_write_time.unwrap_or(root_stat.last_write_time);
+        let index = calculate_hash(&(root_index, u64::MAX - record_idx as u64));
+
+        if record.is_dir {
+            // Update implicitly created folders with actual metadata
+            let folder = get_or_create_folder(&mut root, &components, make_index, &mut warnings);
+            folder.index = index;
+            folder.attributes = attributes;
+            folder.creation_time = creation_time;
+            folder.last_access_time = last_access_time;
+            folder.last_write_time = last_write_time;
+        } else {
+            let (data, size) = match data {
+                Some((data, size)) => (Some(data), size),
+                None => (None, 0),
+            };
+            let file = SevenZipEntry::File(SevenZipFileEntry {
+                data,
+                size,
+                index,
+                attributes,
+                creation_time,
+                last_access_time,
+                last_write_time,
+            });
+            let cur_dir = get_or_create_folder(&mut root, parents, make_index, &mut warnings);
+            insert_entry(cur_dir, filename, file, path, &mut warnings);
+        }
+    }
+
+    Ok((root, warnings.finish()))
+}
+
+// (folder, chunk index)
+type SevenZipChunkKey = (usize, u64);
+
+// Checks whether the file starts with the 7z signature
+pub(super) fn sniff(file: &dyn crate::fs_provider::File) -> bool {
+    let mut signature = [0u8; SIGNATURE.len()];
+    file.read_at_exact(0, &mut signature).is_ok() && signature == SIGNATURE
+}
+
+pub struct SevenZipArchive<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    streams: SevenZipStreamsInfo,
+    root: SevenZipFolderEntry,
+    decoders: LiveDecoderPool<'a, BoxedReader<'a>>,
+    // Last decoded chunk, only kept if the cache is disabled
+    last_chunk: Mutex<Option<(SevenZipChunkKey, Arc<[u8]>)>>,
+    // Decoded chunks of solid blocks
+    cache: Option<ArchiveCacheHandle<'a>>,
+    index_size: u64,
+    // Entries which were renamed or skipped while building the tree
+    warnings: Vec<ContainerWarning>,
+}
+
+impl<'a> SevenZipArchive<'a> {
+    pub(super) fn new(open_ctx: ArchiveHandlerOpenContext<'a>) -> FileSystemResult<Self> {
+        // We only handle files instead of folders
+        if open_ctx.get_is_dir() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let file = open_ctx.get_file();
+        let file_stat = file.get_stat()?;
+
+        let mut streams = SevenZipStreamsInfo::default();
+        let mut records = Vec::new();
+        if let Some(header) = read_header(file)? {
+            let mut reader = HeaderReader { data: &header };
+            reader.expect(ID_HEADER)?;
+            let mut id = reader.read_number()?;
+            if id == ID_ARCHIVE_PROPERTIES {
+                skip_archive_properties(&mut reader)?;
+                id = reader.read_number()?;
+            }
+            if id == ID_ADDITIONAL_STREAMS_INFO {
+                read_streams_info(&mut reader)?;
+                id = reader.read_number()?;
+            }
+            if id == ID_MAIN_STREAMS_INFO {
+                streams = read_streams_info(&mut reader)?;
+                id = reader.read_number()?;
+            }
+            if id == ID_FILES_INFO {
+                records = read_files_info(&mut reader)?;
+                id = reader.read_number()?;
+            }
+            if id != ID_END {
+                return Err(anyhow::anyhow!("unexpected 7z header property {id:#x}").into());
+            }
+        }
+        // NOTE: Rough estimate, names are counted twice as they also key the tree
+        let index_size = records
+            .iter()
+            .map(|x| (std::mem::size_of::<SevenZipFileRecord>() + x.name.len() * 2) as u64)
+            .sum::<u64>()
+            + (streams.folders.len() * std::mem::size_of::<SevenZipFolder>()) as u64;
+        let (root, warnings) = build_file_tree(records, &streams, &file_stat)?;
+
+        Ok(SevenZipArchive {
+            file,
+            streams,
+            root,
+            decoders: LiveDecoderPool::new(open_ctx.get_cache()),
+            last_chunk: Mutex::new(None),
+            cache: open_ctx.get_cache(),
+            index_size,
+            warnings,
+        })
+    }
+    fn find_entry(&self, path: SegPath) -> FileSystemResult<BorrowedSevenZipEntry<'_>> {
+        let mut cur_dir = &self.root;
+        let mut it = path.into_iter().peekable();
+        while let Some(segment) = it.next() {
+            let entry = cur_dir.children.get(CaselessStr::new(segment));
+            match (entry, it.peek()) {
+                (Some(entry), None) => return Ok(entry.as_borrowed()),
+                (Some(SevenZipEntry::Folder(folder)), Some(_)) => cur_dir = folder,
+                (None, None) => return Err(FileSystemError::ObjectNameNotFound),
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            }
+        }
+        Ok(BorrowedSevenZipEntry::Folder(cur_dir))
+    }
+    fn get_cached_chunk(&self, key: SevenZipChunkKey) -> Option<Arc<[u8]>> {
+        match self.cache {
+            Some(cache) => cache.get(key.0 as _, key.1),
+            None => self
+                .last_chunk
+                .lock()
+                .unwrap()
+                .as_ref()
+                .filter(|x| x.0 == key)
+                .map(|x| x.1.clone()),
+        }
+    }
+    fn insert_cached_chunk(&self, key: SevenZipChunkKey, data: Arc<[u8]>) {
+        match self.cache {
+            Some(cache) => cache.insert(key.0 as _, key.1, data),
+            None => *self.last_chunk.lock().unwrap() = Some((key, data)),
+        }
+    }
+    // Returns the decoded chunk `chunk` of folder `folder_idx`, decoding (and
+    // caching) everything before it when necessary
+    // NOTE: Decoding happens without holding any lock, so that reads of other folders
+    //       (or of cached data) are not held up
+    fn get_chunk(&self, folder_idx: usize, chunk: u64) -> anyhow::Result<Arc<[u8]>> {
+        if let Some(data) = self.get_cached_chunk((folder_idx, chunk)) {
+            return Ok(data);
+        }
+
+        let folder = &self.streams.folders[folder_idx];
+        let folder_size = folder.get_unpack_size();
+        let (mut decoder, mut out_pos) =
+            match self.decoders.take(folder_idx, chunk * SOLID_CHUNK_SIZE) {
+                Some(live) => live,
+                None => (
+                    open_folder(self.file, folder, &self.streams.pack_streams)?,
+                    0,
+                ),
+            };
+        let result = loop {
+            let cur_chunk = out_pos / SOLID_CHUNK_SIZE;
+            let len = SOLID_CHUNK_SIZE.min(folder_size - out_pos);
+            let mut data = vec![0u8; len as usize];
+            decoder.read_exact(&mut data)?;
+            out_pos += len;
+            let data: Arc<[u8]> = data.into();
+            self.insert_cached_chunk((folder_idx, cur_chunk), data.clone());
+            if cur_chunk == chunk {
+                break data;
+            }
+        };
+
+        if out_pos < folder_size {
+            let memory_size = get_decoder_memory_size(folder);
+            self.decoders.put(folder_idx, decoder, out_pos, memory_size);
+        }
+        Ok(result)
+    }
+    fn read_folder(
+        &self,
+        folder_idx: usize,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> anyhow::Result<u64> {
+        let folder_size = self.streams.folders[folder_idx].get_unpack_size();
+        let mut total = 0;
+        while total < buffer.len() {
+            let pos = offset + total as u64;
+            if pos >= folder_size {
+                break;
+            }
+            let chunk = self.get_chunk(folder_idx, pos / SOLID_CHUNK_SIZE)?;
+            let chunk_offset = (pos % SOLID_CHUNK_SIZE) as usize;
+            let len = (buffer.len() - total).min(chunk.len() - chunk_offset);
+            buffer[total..total + len].copy_from_slice(&chunk[chunk_offset..chunk_offset + len]);
+            total += len;
+        }
+        Ok(total as _)
+    }
+}
+
+impl super::ArchiveHandler for SevenZipArchive<'_> {
+    fn open_file(
+        &self,
+        filename: SegPath,
+    ) -> FileSystemResult<super::ArchiveHandlerOpenFileInfo<'_>> {
+        let entry = self.find_entry(filename)?;
+        Ok(super::ArchiveHandlerOpenFileInfo {
+            context: Box::new(SevenZipFile { root: self, entry }),
+            is_dir: entry.is_dir(),
+        })
+    }
+    fn get_index_size(&self) -> u64 {
+        self.index_size
+    }
+    fn get_warnings(&self) -> Vec<ContainerWarning> {
+        self.warnings.clone()
+    }
+    fn on_idle(&self) {
+        self.decoders.clear();
+    }
+}
+
+// NOTE: The archive lifetime is kept separate, as the archive is invariant over it
+pub struct SevenZipFile<'a, 'b> {
+    root: &'a SevenZipArchive<'b>,
+    entry: BorrowedSevenZipEntry<'a>,
+}
+
+impl super::ArchiveFile for SevenZipFile<'_, '_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let entry = match self.entry {
+            BorrowedSevenZipEntry::File(e) => e,
+            BorrowedSevenZipEntry::Folder(_) => return Err(FileSystemError::FileIsADirectory),
+        };
+        let Some(data) = entry.data else {
+            return Ok(0);
+        };
+        let read_len = buffer.len().min(entry.size.saturating_sub(offset) as _);
+        Ok(self
+            .root
+            .read_folder(data.folder, data.offset + offset, &mut buffer[..read_len])?)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(self.entry.get_file_stat_info())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn crate::fs_provider::FilePattern,
+        filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let entry = match self.entry {
+            BorrowedSevenZipEntry::Folder(e) => e,
+            BorrowedSevenZipEntry::File(_) => return Err(FileSystemError::NotADirectory),
+        };
+        for (name, child) in entry
+            .children
+            .iter()
+            .filter(|(name, _)| pattern.check_name(name.as_str()))
+        {
+            if filler
+                .fill_data(name.as_str(), &child.get_file_stat_info())
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,981 @@
+// Random access to single compressed streams (gzip, bzip2, xz and zstd)
+// NOTE: When opened, the stream is split into parts which can be decoded on
+//       their own (gzip members, bzip2 and xz blocks, zstd frames), and gzip
+//       members are further indexed with deflate checkpoints. Reads then only
+//       decode from the closest such point instead of from the very beginning.
+// NOTE: gzip and bzip2 don't record the decompressed size, so opening those
+//       decodes the whole stream once
+
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom},
+    sync::Mutex,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{
+    fs_provider::{
+        CursorFile, FileAttributes, FileStatInfo, FileSystemError, FileSystemResult, SegPath,
+    },
+    util::{calculate_hash, CaselessStr, CaselessString},
+};
+
+use super::{
+    cache::{read_cached_blocks, ArchiveCacheHandle},
+    inflate::{InflateCheckpoint, InflateFormat, InflateIndex, Inflater},
+    ArchiveHandlerOpenContext,
+};
+
+const SCAN_BUFFER_SIZE: usize = 64 * 1024;
+// Granularity of decompressed data kept in the cache
+const CACHE_BLOCK_SIZE: u64 = 256 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_FLAG_HCRC: u8 = 0x02;
+const GZIP_FLAG_EXTRA: u8 = 0x04;
+const GZIP_FLAG_NAME: u8 = 0x08;
+const GZIP_FLAG_COMMENT: u8 = 0x10;
+
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+const BZIP2_BLOCK_MAGIC: u64 = 0x314159265359;
+const BZIP2_EOS_MAGIC: u64 = 0x177245385090;
+const BZIP2_MAGIC_BITS: u64 = 48;
+
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+const XZ_FOOTER_MAGIC: [u8; 2] = *b"YZ";
+const XZ_HEADER_SIZE: u64 = 12;
+const XZ_FOOTER_SIZE: u64 = 12;
+
+const ZSTD_MAGIC: u32 = 0xfd2fb528;
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d2a50;
+const ZSTD_SKIPPABLE_MAGIC_MASK: u32 = 0xfffffff0;
+const ZSTD_SEEK_TABLE_MAGIC: u32 = 0x184d2a5e;
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8f92eab1;
+const ZSTD_SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl StreamFormat {
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&GZIP_MAGIC) {
+            return Some(Self::Gzip);
+        }
+        if header.starts_with(&BZIP2_MAGIC) && header.get(3).is_some_and(u8::is_ascii_digit) {
+            return Some(Self::Bzip2);
+        }
+        if header.starts_with(&XZ_MAGIC) {
+            return Some(Self::Xz);
+        }
+        let magic = u32::from_le_bytes(header.get(..4)?.try_into().ok()?);
+        if magic == ZSTD_MAGIC || magic & ZSTD_SKIPPABLE_MAGIC_MASK == ZSTD_SKIPPABLE_MAGIC {
+            return Some(Self::Zstd);
+        }
+        None
+    }
+    // Suffixes and what they are replaced with, the same as the command line tools
+    fn get_suffixes(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Gzip => &[(".gz", ""), (".tgz", ".tar"), (".taz", ".tar"), (".z", "")],
+            Self::Bzip2 => &[
+                (".bz2", ""),
+                (".bz", ""),
+                (".tbz2", ".tar"),
+                (".tbz", ".tar"),
+            ],
+            Self::Xz => &[(".xz", ""), (".txz", ".tar")],
+            Self::Zstd => &[(".zst", ""), (".zstd", ""), (".tzst", ".tar")],
+        }
+    }
+    /// Returns the name of the decompressed file for a compressed file named `name`.
+    pub fn strip_suffix(self, name: &str) -> String {
+        for &(suffix, replacement) in self.get_suffixes() {
+            let Some(stem_len) = name.len().checked_sub(suffix.len()) else {
+                continue;
+            };
+            if stem_len == 0 || !name.is_char_boundary(stem_len) {
+                continue;
+            }
+            if name[stem_len..].eq_ignore_ascii_case(suffix) {
+                return format!("{}{replacement}", &name[..stem_len]);
+            }
+        }
+        name.to_owned()
+    }
+}
+
+enum SegmentData {
+    // Raw deflate data of a gzip member
+    Deflate {
+        start: u64,
+        index: InflateIndex,
+    },
+    // Bit range of a block (starting with its magic), plus the block size level
+    Bzip2Block {
+        start_bit: u64,
+        end_bit: u64,
+        level: u8,
+    },
+    XzBlock {
+        stream_header: [u8; XZ_HEADER_SIZE as usize],
+        start: u64,
+        unpadded_size: u64,
+    },
+    ZstdFrame {
+        start: u64,
+        size: u64,
+    },
+}
+
+struct StreamSegment {
+    out_start: u64,
+    out_size: u64,
+    data: SegmentData,
+}
+
+fn push_segment(segments: &mut Vec<StreamSegment>, out_size: u64, data: SegmentData) {
+    let out_start = segments.last().map_or(0, |x| x.out_start + x.out_size);
+    segments.push(StreamSegment {
+        out_start,
+        out_size,
+        data,
+    });
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn scan_gzip_members(
+    file: &dyn crate::fs_provider::File,
+    file_size: u64,
+    segments: &mut Vec<StreamSegment>,
+) -> anyhow::Result<()> {
+    let mut buffer = vec![0u8; SCAN_BUFFER_SIZE];
+    let mut pos = 0;
+    while pos < file_size {
+        let mut reader = CursorFile::with_position(file, pos);
+        let mut magic = [0u8; 2];
+        // NOTE: Trailing bytes too short for a header are treated as padding too
+        let has_magic = file_size - pos >= 2 && {
+            reader.read_exact(&mut magic)?;
+            magic == GZIP_MAGIC
+        };
+        if !has_magic {
+            // NOTE: Some tools pad the file with zeros after the last member
+            if segments.is_empty() {
+                anyhow::bail!("invalid gzip header");
+            }
+            break;
+        }
+        let method = reader.read_u8()?;
+        if method != GZIP_METHOD_DEFLATE {
+            anyhow::bail!("unsupported gzip compression method {method}");
+        }
+        let flags = reader.read_u8()?;
+        // Modification time, extra flags and OS
+        reader.seek(SeekFrom::Current(6))?;
+        if flags & GZIP_FLAG_EXTRA != 0 {
+            let len = reader.read_u16::<LittleEndian>()?;
+            reader.seek(SeekFrom::Current(len as _))?;
+        }
+        for flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT] {
+            if flags & flag != 0 {
+                while reader.read_u8()? != 0 {}
+            }
+        }
+        if flags & GZIP_FLAG_HCRC != 0 {
+            reader.read_u16::<LittleEndian>()?;
+        }
+        let start = reader.get_position();
+
+        let mut inflater = Inflater::new(
+            CursorFile::with_position(file, start),
+            InflateFormat::Deflate,
+        );
+        let mut index = InflateIndex::new();
+        let mut crc = flate2::Crc::new();
+        loop {
+            let len = inflater.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            crc.update(&buffer[..len]);
+            index.update(&inflater);
+        }
+        let out_size = inflater.get_out_pos();
+
+        let trailer_pos = start + inflater.get_in_byte_pos();
+        let mut trailer = CursorFile::with_position(file, trailer_pos);
+        let expected_crc = trailer.read_u32::<LittleEndian>()?;
+        let expected_size = trailer.read_u32::<LittleEndian>()?;
+        if expected_crc != crc.sum() || expected_size != out_size as u32 {
+            anyhow::bail!("gzip member at {pos} is corrupted");
+        }
+
+        push_segment(segments, out_size, SegmentData::Deflate { start, index });
+        pos = trailer.get_position();
+    }
+    Ok(())
+}
+
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u8,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn new(data: Vec<u8>) -> Self {
+        BitWriter {
+            data,
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+    // Appends the lowest `count` bits of `value`, most significant bit first
+    fn push_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.bits = (self.bits << 1) | ((value >> i) & 1) as u8;
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.data.push(self.bits);
+                self.bits = 0;
+                self.bit_count = 0;
+            }
+        }
+    }
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.data.push(self.bits << (8 - self.bit_count));
+        }
+        self.data
+    }
+}
+
+// Rebuilds a standalone stream containing only the given block
+// NOTE: The combined CRC of a single block stream equals the block CRC
+fn read_bzip2_block(
+    file: &dyn crate::fs_provider::File,
+    start_bit: u64,
+    end_bit: u64,
+    level: u8,
+) -> anyhow::Result<Vec<u8>> {
+    let start_byte = start_bit / 8;
+    let mut data = vec![0u8; ((end_bit + 7) / 8 - start_byte) as usize];
+    CursorFile::with_position(file, start_byte).read_exact(&mut data)?;
+    let get_bit = |bit: u64| {
+        let bit = bit - start_byte * 8;
+        ((data[(bit / 8) as usize] >> (7 - bit % 8)) & 1) as u64
+    };
+
+    let mut writer = BitWriter::new(Vec::with_capacity(data.len() + 16));
+    writer.data.extend_from_slice(&BZIP2_MAGIC);
+    writer.data.push(level);
+    let mut block_crc = 0;
+    for bit in start_bit..end_bit {
+        let bit_value = get_bit(bit);
+        if (BZIP2_MAGIC_BITS..BZIP2_MAGIC_BITS + 32).contains(&(bit - start_bit)) {
+            block_crc = (block_crc << 1) | bit_value;
+        }
+        writer.push_bits(bit_value, 1);
+    }
+    writer.push_bits(BZIP2_EOS_MAGIC, BZIP2_MAGIC_BITS as _);
+    writer.push_bits(block_crc, 32);
+    Ok(writer.finish())
+}
+
+fn scan_bzip2_blocks(
+    file: &dyn crate::fs_provider::File,
+    file_size: u64,
+    segments: &mut Vec<StreamSegment>,
+) -> anyhow::Result<()> {
+    // NOTE: Blocks are not byte aligned and carry no length, so the data is
+    //       scanned bit by bit for block and end of stream markers
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < file_size {
+        let mut header = [0u8; 4];
+        CursorFile::with_position(file, pos).read_exact(&mut header)?;
+        if StreamFormat::detect(&header) != Some(StreamFormat::Bzip2) {
+            if pos == 0 {
+                anyhow::bail!("invalid bzip2 header");
+            }
+            break;
+        }
+        let level = header[3];
+
+        let mut reader = std::io::BufReader::with_capacity(
+            SCAN_BUFFER_SIZE,
+            CursorFile::with_position(file, pos + header.len() as u64),
+        );
+        let mut bit_pos = (pos + header.len() as u64) * 8;
+        let mut window = 0u64;
+        let mut block_start = None;
+        let stream_end_bit = 'scan: loop {
+            let byte = match reader.read_u8() {
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    anyhow::bail!("unexpected end of bzip2 stream")
+                }
+                result => result?,
+            };
+            for i in (0..8).rev() {
+                window = ((window << 1) | ((byte >> i) & 1) as u64) & ((1 << BZIP2_MAGIC_BITS) - 1);
+                bit_pos += 1;
+                if window != BZIP2_BLOCK_MAGIC && window != BZIP2_EOS_MAGIC {
+                    continue;
+                }
+                let marker_start = bit_pos - BZIP2_MAGIC_BITS;
+                if let Some(start_bit) = block_start.take() {
+                    blocks.push((start_bit, marker_start, level));
+                }
+                if window == BZIP2_EOS_MAGIC {
+                    break 'scan bit_pos;
+                }
+                block_start = Some(marker_start);
+            }
+        };
+        // Skip the combined CRC and padding up to the next byte
+        pos = (stream_end_bit + 32 + 7) / 8;
+    }
+
+    for (start_bit, end_bit, level) in blocks {
+        let block = read_bzip2_block(file, start_bit, end_bit, level)?;
+        let mut decoder = bzip2::read::BzDecoder::new(Cursor::new(block));
+        let out_size = std::io::copy(&mut decoder, &mut std::io::sink())?;
+        push_segment(
+            segments,
+            out_size,
+            SegmentData::Bzip2Block {
+                start_bit,
+                end_bit,
+                level,
+            },
+        );
+    }
+    Ok(())
+}
+
+fn read_xz_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let Some(&byte) = data.get(*pos) else {
+            anyhow::bail!("unexpected end of xz index");
+        };
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("invalid xz variable length integer")
+}
+
+fn write_xz_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn scan_xz_blocks(
+    file: &dyn crate::fs_provider::File,
+    file_size: u64,
+    segments: &mut Vec<StreamSegment>,
+) -> anyhow::Result<()> {
+    // NOTE: Block sizes are only listed in the index at the end of each
+    //       stream, so streams are walked backwards from the end of file
+    let mut streams = Vec::new();
+    let mut end = file_size;
+    while end > 0 {
+        if end < XZ_HEADER_SIZE + XZ_FOOTER_SIZE {
+            anyhow::bail!("truncated xz stream");
+        }
+        let mut footer = [0u8; XZ_FOOTER_SIZE as usize];
+        CursorFile::with_position(file, end - XZ_FOOTER_SIZE).read_exact(&mut footer)?;
+        // Skip stream padding
+        if footer[8..] == [0; 4] {
+            end -= 4;
+            continue;
+        }
+        if footer[10..] != XZ_FOOTER_MAGIC {
+            anyhow::bail!("invalid xz stream footer");
+        }
+        let backward_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+        let index_start = (end - XZ_FOOTER_SIZE)
+            .checked_sub(backward_size)
+            .ok_or_else(|| anyhow::anyhow!("invalid xz index size"))?;
+        let mut index = vec![0u8; backward_size as usize];
+        CursorFile::with_position(file, index_start).read_exact(&mut index)?;
+        if index[0] != 0 {
+            anyhow::bail!("invalid xz index indicator");
+        }
+        let mut index_pos = 1;
+        let record_count = read_xz_varint(&index, &mut index_pos)?;
+        let mut records = Vec::new();
+        let mut blocks_size = 0u64;
+        for _ in 0..record_count {
+            let unpadded_size = read_xz_varint(&index, &mut index_pos)?;
+            let out_size = read_xz_varint(&index, &mut index_pos)?;
+            records.push((blocks_size, unpadded_size, out_size));
+            blocks_size += (unpadded_size + 3) & !3;
+        }
+
+        let stream_start = index_start
+            .checked_sub(blocks_size + XZ_HEADER_SIZE)
+            .ok_or_else(|| anyhow::anyhow!("invalid xz block sizes"))?;
+        let mut stream_header = [0u8; XZ_HEADER_SIZE as usize];
+        CursorFile::with_position(file, stream_start).read_exact(&mut stream_header)?;
+        if stream_header[..6] != XZ_MAGIC || stream_header[6..8] != footer[8..10] {
+            anyhow::bail!("invalid xz stream header");
+        }
+        streams.push((stream_header, stream_start + XZ_HEADER_SIZE, records));
+        end = stream_start;
+    }
+
+    for (stream_header, blocks_start, records) in streams.into_iter().rev() {
+        for (offset, unpadded_size, out_size) in records {
+            push_segment(
+                segments,
+                out_size,
+                SegmentData::XzBlock {
+                    stream_header,
+                    start: blocks_start + offset,
+                    unpadded_size,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+// Wraps a block into a standalone single block stream by appending a
+// matching index and footer
+fn open_xz_block<'a>(
+    file: &'a dyn crate::fs_provider::File,
+    stream_header: &[u8; XZ_HEADER_SIZE as usize],
+    start: u64,
+    unpadded_size: u64,
+    out_size: u64,
+) -> Box<dyn Read + Send + 'a> {
+    let mut tail = vec![0u8];
+    write_xz_varint(&mut tail, 1);
+    write_xz_varint(&mut tail, unpadded_size);
+    write_xz_varint(&mut tail, out_size);
+    while tail.len() % 4 != 0 {
+        tail.push(0);
+    }
+    tail.extend_from_slice(&crc32(&tail).to_le_bytes());
+    let mut footer = ((tail.len() / 4 - 1) as u32).to_le_bytes().to_vec();
+    footer.extend_from_slice(&stream_header[6..8]);
+    tail.extend_from_slice(&crc32(&footer).to_le_bytes());
+    tail.extend_from_slice(&footer);
+    tail.extend_from_slice(&XZ_FOOTER_MAGIC);
+
+    let reader = Cursor::new(*stream_header)
+        .chain(CursorFile::with_position(file, start).take((unpadded_size + 3) & !3))
+        .chain(Cursor::new(tail));
+    Box::new(xz2::read::XzDecoder::new(reader))
+}
+
+// Returns the frames (start, compressed size and decompressed size) listed in
+// the seek table of the zstd seekable format, if there is one
+fn read_zstd_seek_table(
+    file: &dyn crate::fs_provider::File,
+    file_size: u64,
+) -> anyhow::Result<Option<Vec<(u64, u64, u64)>>> {
+    let Some(footer_pos) = file_size.checked_sub(ZSTD_SEEK_TABLE_FOOTER_SIZE) else {
+        return Ok(None);
+    };
+    let mut reader = CursorFile::with_position(file, footer_pos);
+    let frame_count = reader.read_u32::<LittleEndian>()? as u64;
+    let descriptor = reader.read_u8()?;
+    if reader.read_u32::<LittleEndian>()? != ZSTD_SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    let entry_size = if descriptor & 0x80 != 0 { 12 } else { 8 };
+    let table_size = frame_count * entry_size + ZSTD_SEEK_TABLE_FOOTER_SIZE;
+    let Some(table_start) = file_size.checked_sub(table_size + 8) else {
+        anyhow::bail!("invalid zstd seek table size");
+    };
+    let mut reader = CursorFile::with_position(file, table_start);
+    if reader.read_u32::<LittleEndian>()? != ZSTD_SEEK_TABLE_MAGIC
+        || reader.read_u32::<LittleEndian>()? as u64 != table_size
+    {
+        anyhow::bail!("invalid zstd seek table header");
+    }
+    let mut frames = Vec::with_capacity(frame_count as _);
+    let mut start = 0;
+    for _ in 0..frame_count {
+        let size = reader.read_u32::<LittleEndian>()? as u64;
+        let out_size = reader.read_u32::<LittleEndian>()? as u64;
+        if entry_size == 12 {
+            reader.read_u32::<LittleEndian>()?;
+        }
+        frames.push((start, size, out_size));
+        start += size;
+    }
+    Ok(Some(frames))
+}
+
+fn open_zstd_frame<'a>(
+    file: &'a dyn crate::fs_provider::File,
+    start: u64,
+    size: u64,
+) -> anyhow::Result<Box<dyn Read + Send + 'a>> {
+    let reader = CursorFile::with_position(file, start).take(size);
+    Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))
+}
+
+fn scan_zstd_frames(
+    file: &dyn crate::fs_provider::File,
+    file_size: u64,
+    segments: &mut Vec<StreamSegment>,
+) -> anyhow::Result<()> {
+    if let Some(frames) = read_zstd_seek_table(file, file_size)? {
+        for (start, size, out_size) in frames {
+            push_segment(segments, out_size, SegmentData::ZstdFrame { start, size });
+        }
+        return Ok(());
+    }
+
+    // Walk the frame and block headers to find where each frame ends
+    let mut pos = 0;
+    while pos < file_size {
+        let mut reader = CursorFile::with_position(file, pos);
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic & ZSTD_SKIPPABLE_MAGIC_MASK == ZSTD_SKIPPABLE_MAGIC {
+            pos += 8 + reader.read_u32::<LittleEndian>()? as u64;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            anyhow::bail!("invalid zstd frame magic at {pos}");
+        }
+        let descriptor = reader.read_u8()?;
+        let is_single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        if !is_single_segment {
+            // Window descriptor
+            reader.read_u8()?;
+        }
+        let dict_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        reader.seek(SeekFrom::Current(dict_id_size))?;
+        let content_size = match (descriptor >> 6, is_single_segment) {
+            (0, false) => None,
+            (0, true) => Some(reader.read_u8()? as u64),
+            (1, _) => Some(reader.read_u16::<LittleEndian>()? as u64 + 256),
+            (2, _) => Some(reader.read_u32::<LittleEndian>()? as u64),
+            _ => Some(reader.read_u64::<LittleEndian>()?),
+        };
+        loop {
+            let header = reader.read_u24::<LittleEndian>()?;
+            let block_size = (header >> 3) as i64;
+            let data_size = match (header >> 1) & 0x03 {
+                // Raw and compressed blocks
+                0 | 2 => block_size,
+                // RLE blocks store a single byte
+                1 => 1,
+                _ => anyhow::bail!("reserved zstd block type"),
+            };
+            reader.seek(SeekFrom::Current(data_size))?;
+            if header & 1 != 0 {
+                break;
+            }
+        }
+        if has_checksum {
+            reader.seek(SeekFrom::Current(4))?;
+        }
+        let size = reader.get_position() - pos;
+        let out_size = match content_size {
+            Some(x) => x,
+            None => std::io::copy(&mut open_zstd_frame(file, pos, size)?, &mut std::io::sink())?,
+        };
+        push_segment(
+            segments,
+            out_size,
+            SegmentData::ZstdFrame { start: pos, size },
+        );
+        pos += size;
+    }
+    Ok(())
+}
+
+enum SegmentDecoder<'a> {
+    Inflate(Box<Inflater<CursorFile<&'a dyn crate::fs_provider::File>>>),
+    Stream {
+        decoder: Box<dyn Read + Send + 'a>,
+        out_pos: u64,
+    },
+}
+
+impl SegmentDecoder<'_> {
+    fn get_out_pos(&self) -> u64 {
+        match self {
+            Self::Inflate(inflater) => inflater.get_out_pos(),
+            Self::Stream { out_pos, .. } => *out_pos,
+        }
+    }
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        match self {
+            Self::Inflate(inflater) => inflater.read(buffer),
+            Self::Stream { decoder, out_pos } => {
+                let len = decoder.read(buffer)?;
+                *out_pos += len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+struct StreamReadState<'a> {
+    segment: usize,
+    decoder: SegmentDecoder<'a>,
+}
+
+pub struct CompressedStream<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    format: StreamFormat,
+    segments: Vec<StreamSegment>,
+    size: u64,
+    state: Mutex<Option<StreamReadState<'a>>>,
+    cache: Option<ArchiveCacheHandle<'a>>,
+}
+
+impl<'a> CompressedStream<'a> {
+    pub fn new(
+        file: &'a dyn crate::fs_provider::File,
+        cache: Option<ArchiveCacheHandle<'a>>,
+    ) -> anyhow::Result<Self> {
+        let file_size = file.get_stat()?.size;
+        let mut header = [0u8; 6];
+        let header_len = file.read_at(0, &mut header)? as usize;
+        let Some(format) = StreamFormat::detect(&header[..header_len]) else {
+            anyhow::bail!("unrecognized compressed stream format");
+        };
+
+        let mut segments = Vec::new();
+        match format {
+            StreamFormat::Gzip => scan_gzip_members(file, file_size, &mut segments)?,
+            StreamFormat::Bzip2 => scan_bzip2_blocks(file, file_size, &mut segments)?,
+            StreamFormat::Xz => scan_xz_blocks(file, file_size, &mut segments)?,
+            StreamFormat::Zstd => scan_zstd_frames(file, file_size, &mut segments)?,
+        }
+        let size = segments.last().map_or(0, |x| x.out_start + x.out_size);
+        Ok(CompressedStream {
+            file,
+            format,
+            segments,
+            size,
+            state: Mutex::new(None),
+            cache,
+        })
+    }
+    pub fn get_format(&self) -> StreamFormat {
+        self.format
+    }
+    // Drops the decoder kept for reading on, which may hold a large dictionary
+    pub fn release_decoder(&self) {
+        *self.state.lock().unwrap() = None;
+    }
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+    fn open_segment(
+        &self,
+        segment: &StreamSegment,
+        checkpoint: Option<&InflateCheckpoint>,
+    ) -> anyhow::Result<SegmentDecoder<'a>> {
+        let file = self.file;
+        let decoder = match &segment.data {
+            SegmentData::Deflate { start, .. } => {
+                return Ok(SegmentDecoder::Inflate(Box::new(match checkpoint {
+                    Some(c) => Inflater::with_checkpoint(
+                        CursorFile::with_position(file, start + c.get_in_byte_pos()),
+                        c,
+                    )?,
+                    None => Inflater::new(
+                        CursorFile::with_position(file, *start),
+                        InflateFormat::Deflate,
+                    ),
+                })));
+            }
+            &SegmentData::Bzip2Block {
+                start_bit,
+                end_bit,
+                level,
+            } => {
+                let block = read_bzip2_block(file, start_bit, end_bit, level)?;
+                Box::new(bzip2::read::BzDecoder::new(Cursor::new(block)))
+            }
+            SegmentData::XzBlock {
+                stream_header,
+                start,
+                unpadded_size,
+            } => open_xz_block(
+                file,
+                stream_header,
+                *start,
+                *unpadded_size,
+                segment.out_size,
+            ),
+            &SegmentData::ZstdFrame { start, size } => open_zstd_frame(file, start, size)?,
+        };
+        Ok(SegmentDecoder::Stream {
+            decoder,
+            out_pos: 0,
+        })
+    }
+    // Fills `buffer` with data at `offset` within the given segment
+    fn read_segment(
+        &self,
+        state: &mut Option<StreamReadState<'a>>,
+        segment_idx: usize,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> anyhow::Result<()> {
+        let segment = &self.segments[segment_idx];
+
+        // Resume from the closest point, which is either the current decoder
+        // position or a checkpoint before the requested offset
+        let checkpoint = match &segment.data {
+            SegmentData::Deflate { index, .. } => index.find(offset),
+            _ => None,
+        };
+        let can_continue = state.as_ref().is_some_and(|x| {
+            let out_pos = x.decoder.get_out_pos();
+            x.segment == segment_idx
+                && out_pos <= offset
+                && checkpoint.map_or(true, |c| c.get_out_pos() <= out_pos)
+        });
+        let state = match state {
+            Some(state) if can_continue => state,
+            state => state.insert(StreamReadState {
+                segment: segment_idx,
+                decoder: self.open_segment(segment, checkpoint)?,
+            }),
+        };
+        let decoder = &mut state.decoder;
+
+        let mut scratch = [0u8; 4096];
+        while decoder.get_out_pos() < offset {
+            let len = scratch.len().min((offset - decoder.get_out_pos()) as usize);
+            if decoder.read(&mut scratch[..len])? == 0 {
+                anyhow::bail!("unexpected end of compressed data");
+            }
+        }
+        let mut total = 0;
+        while total < buffer.len() {
+            let len = decoder.read(&mut buffer[total..])?;
+            if len == 0 {
+                anyhow::bail!("unexpected end of compressed data");
+            }
+            total += len;
+        }
+        Ok(())
+    }
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> anyhow::Result<u64> {
+        match self.cache {
+            // NOTE: There is only one stream per archive, so it always uses item 0
+            Some(cache) => read_cached_blocks(
+                cache,
+                0,
+                self.size,
+                CACHE_BLOCK_SIZE,
+                offset,
+                buffer,
+                |offset, buffer| self.read_at_uncached(offset, buffer),
+            ),
+            None => self.read_at_uncached(offset, buffer),
+        }
+    }
+    fn read_at_uncached(&self, offset: u64, buffer: &mut [u8]) -> anyhow::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let mut total = 0;
+        while total < buffer.len() {
+            let pos = offset + total as u64;
+            if pos >= self.size {
+                break;
+            }
+            // NOTE: Empty segments are skipped over here
+            let segment_idx = self
+                .segments
+                .partition_point(|x| x.out_start + x.out_size <= pos);
+            let segment = &self.segments[segment_idx];
+            let len = (buffer.len() - total).min((segment.out_start + segment.out_size - pos) as _);
+            self.read_segment(
+                &mut state,
+                segment_idx,
+                pos - segment.out_start,
+                &mut buffer[total..total + len],
+            )?;
+            total += len;
+        }
+        Ok(total as _)
+    }
+}
+
+// NOTE: Allows the decompressed data to be consumed by other handlers (e.g. tar)
+impl crate::fs_provider::File for CompressedStream<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        Ok(CompressedStream::read_at(self, offset, buffer)?)
+    }
+    fn write_at(
+        &self,
+        _offset: Option<u64>,
+        _buffer: &[u8],
+        _constrain_size: bool,
+    ) -> FileSystemResult<u64> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn flush_buffers(&self) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        let stat = self.file.get_stat()?;
+        Ok(FileStatInfo {
+            size: self.size,
+            ..stat
+        })
+    }
+    fn set_end_of_file(&self, _offset: u64) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_file_times(
+        &self,
+        _creation_time: std::time::SystemTime,
+        _last_access_time: std::time::SystemTime,
+        _last_write_time: std::time::SystemTime,
+    ) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_delete(&self, _delete_on_close: bool) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn move_to(&self, _new_path: SegPath, _replace_if_exists: bool) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn find_files_with_pattern(
+        &self,
+        _pattern: &dyn crate::fs_provider::FilePattern,
+        _filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        Err(FileSystemError::NotADirectory)
+    }
+}
+
+// Checks whether the file starts with the magic of a supported format
+pub(super) fn sniff(file: &dyn crate::fs_provider::File) -> bool {
+    let mut header = [0u8; XZ_MAGIC.len()];
+    file.read_at_exact(0, &mut header).is_ok() && StreamFormat::detect(&header).is_some()
+}
+
+// A lone compressed file, shown as a folder containing the decompressed file
+pub struct CompressedArchive<'a> {
+    stream: CompressedStream<'a>,
+    name: CaselessString,
+    archive_stat: FileStatInfo,
+}
+
+impl<'a> CompressedArchive<'a> {
+    pub(super) fn new(open_ctx: ArchiveHandlerOpenContext<'a>) -> FileSystemResult<Self> {
+        // We only handle files instead of folders
+        if open_ctx.get_is_dir() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let file = open_ctx.get_file();
+        let stream = CompressedStream::new(file, open_ctx.get_cache())?;
+        let archive_name = open_ctx.get_path().rsplit(['\\', '/']).next().unwrap_or("");
+        let name = stream.get_format().strip_suffix(archive_name);
+        Ok(CompressedArchive {
+            stream,
+            name: name.into(),
+            archive_stat: file.get_stat()?,
+        })
+    }
+    fn get_file_stat_info(&self, is_dir: bool) -> FileStatInfo {
+        let stat = &self.archive_stat;
+        if is_dir {
+            FileStatInfo {
+                size: 0,
+                is_dir: true,
+                attributes: FileAttributes::DirectoryFile,
+                ..*stat
+            }
+        } else {
+            FileStatInfo {
+                index: calculate_hash(&(stat.index, self.name.as_str())),
+                size: self.stream.get_size(),
+                is_dir: false,
+                attributes: FileAttributes::empty(),
+                ..*stat
+            }
+        }
+    }
+}
+
+impl super::ArchiveHandler for CompressedArchive<'_> {
+    fn open_file(
+        &self,
+        filename: SegPath,
+    ) -> FileSystemResult<super::ArchiveHandlerOpenFileInfo<'_>> {
+        let mut it = filename.into_iter();
+        let is_dir = match (it.next(), it.next()) {
+            (None, _) => true,
+            (Some(name), None) if CaselessStr::new(name) == &*self.name => false,
+            (Some(_), None) => return Err(FileSystemError::ObjectNameNotFound),
+            _ => return Err(FileSystemError::ObjectPathNotFound),
+        };
+        Ok(super::ArchiveHandlerOpenFileInfo {
+            context: Box::new(CompressedArchiveFile { root: self, is_dir }),
+            is_dir,
+        })
+    }
+    fn on_idle(&self) {
+        self.stream.release_decoder();
+    }
+}
+
+// NOTE: The archive lifetime is kept separate, as the archive is invariant over it
+pub struct CompressedArchiveFile<'a, 'b> {
+    root: &'a CompressedArchive<'b>,
+    is_dir: bool,
+}
+
+impl super::ArchiveFile for CompressedArchiveFile<'_, '_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        if self.is_dir {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        Ok(self.root.stream.read_at(offset, buffer)?)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(self.root.get_file_stat_info(self.is_dir))
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn crate::fs_provider::FilePattern,
+        filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        if !self.is_dir {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let name = self.root.name.as_str();
+        if pattern.check_name(name)
+            && filler
+                .fill_data(name, &self.root.get_file_stat_info(false))
+                .is_err()
+        {
+            log::warn!("Failed to fill object data");
+        }
+        Ok(())
+    }
+}
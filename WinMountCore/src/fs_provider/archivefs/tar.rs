@@ -0,0 +1,992 @@
+// Tar family (v7, ustar, pax and GNU) archive handler
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    fs_provider::{
+        ContainerWarning, CursorFile, FileAttributes, FileStatInfo, FileSystemError,
+        FileSystemResult, SegPath,
+    },
+    util::{calculate_hash, CaselessStr, CaselessString},
+};
+
+use super::{
+    sanitize::split_entry_path,
+    stream::CompressedStream,
+    tree::{get_or_create_folder, insert_entry, normalize_entry_path, TreeFolder, TreeWarnings},
+    ArchiveHandlerOpenContext, ArchiveNonUnicodeCompatConfig,
+};
+
+const TAR_BLOCK_SIZE: u64 = 512;
+
+// NOTE: Extended headers are loaded into memory, so guard against bogus sizes
+const TAR_MAX_EXTENDED_HEADER_SIZE: u64 = 16 * 1024 * 1024;
+
+const TAR_TYPE_REGULAR: u8 = b'0';
+const TAR_TYPE_REGULAR_OLD: u8 = b'\0';
+const TAR_TYPE_HARD_LINK: u8 = b'1';
+const TAR_TYPE_CONTIGUOUS: u8 = b'7';
+const TAR_TYPE_DIRECTORY: u8 = b'5';
+const TAR_TYPE_PAX_LOCAL: u8 = b'x';
+const TAR_TYPE_PAX_GLOBAL: u8 = b'g';
+const TAR_TYPE_GNU_LONG_NAME: u8 = b'L';
+const TAR_TYPE_GNU_LONG_LINK: u8 = b'K';
+const TAR_TYPE_GNU_SPARSE: u8 = b'S';
+const TAR_TYPE_GNU_DUMPDIR: u8 = b'D';
+
+const TAR_MAGIC_USTAR: &[u8] = b"ustar\0";
+
+// Raw header block, with accessors for the fields we care about
+struct TarHeader([u8; TAR_BLOCK_SIZE as usize]);
+
+impl TarHeader {
+    fn field(&self, start: usize, len: usize) -> &[u8] {
+        &self.0[start..start + len]
+    }
+    fn name(&self) -> &[u8] {
+        trim_nul(self.field(0, 100))
+    }
+    fn size(&self) -> anyhow::Result<u64> {
+        parse_number(self.field(124, 12))
+    }
+    fn mtime(&self) -> anyhow::Result<u64> {
+        parse_number(self.field(136, 12))
+    }
+    fn checksum(&self) -> anyhow::Result<u64> {
+        parse_number(self.field(148, 8))
+    }
+    fn typeflag(&self) -> u8 {
+        self.0[156]
+    }
+    fn linkname(&self) -> &[u8] {
+        trim_nul(self.field(157, 100))
+    }
+    fn is_ustar(&self) -> bool {
+        self.field(257, 6) == TAR_MAGIC_USTAR
+    }
+    // NOTE: GNU headers store other fields where ustar keeps the prefix
+    fn prefix(&self) -> &[u8] {
+        if self.is_ustar() {
+            trim_nul(self.field(345, 155))
+        } else {
+            &[]
+        }
+    }
+    fn gnu_sparse_is_extended(&self) -> bool {
+        self.0[482] != 0
+    }
+    fn gnu_sparse_real_size(&self) -> anyhow::Result<u64> {
+        parse_number(self.field(483, 12))
+    }
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|&x| x == 0)
+    }
+    fn verify_checksum(&self) -> anyhow::Result<bool> {
+        let expected = self.checksum()?;
+        let actual: u64 = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| if (148..156).contains(&i) { b' ' } else { x } as u64)
+            .sum();
+        Ok(expected == actual)
+    }
+}
+
+fn trim_nul(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&x| x == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+// Parses an octal (or GNU base-256) numeric field
+fn parse_number(field: &[u8]) -> anyhow::Result<u64> {
+    if field.first().is_some_and(|&x| x & 0x80 != 0) {
+        if field[0] == 0xff {
+            // Negative values are meaningless for us
+            return Ok(0);
+        }
+        let mut value = (field[0] & 0x7f) as u64;
+        for &x in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .and_then(|v| v.checked_add(x as u64))
+                .ok_or_else(|| anyhow::anyhow!("numeric field overflow in tar header"))?;
+        }
+        return Ok(value);
+    }
+    let s = std::str::from_utf8(trim_nul(field))?.trim_matches(' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(s, 8)?)
+}
+
+fn parse_decimal(value: &[u8]) -> anyhow::Result<u64> {
+    Ok(std::str::from_utf8(value)?.trim().parse()?)
+}
+
+// NOTE: Base-256 and pax fields can hold times `SystemTime` can't represent,
+//       which are clamped to the epoch
+fn unix_time(secs: u64, nanos: u32) -> SystemTime {
+    SystemTime::UNIX_EPOCH
+        .checked_add(Duration::new(secs, nanos))
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+// Parses pax timestamps such as `1690000000.123456789`
+fn parse_pax_time(value: &[u8]) -> anyhow::Result<SystemTime> {
+    let s = std::str::from_utf8(value)?.trim();
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    if secs.starts_with('-') {
+        return Ok(SystemTime::UNIX_EPOCH);
+    }
+    let mut nanos = 0u32;
+    for (i, c) in frac.chars().take(9).enumerate() {
+        nanos += c.to_digit(10).unwrap_or(0) * 10u32.pow(8 - i as u32);
+    }
+    Ok(unix_time(secs.parse()?, nanos))
+}
+
+// Pax records in their original order, as GNU sparse 0.0 relies on repeated keys
+fn parse_pax_records(mut data: &[u8]) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() && data[0] != 0 {
+        let space = data
+            .iter()
+            .position(|&x| x == b' ')
+            .ok_or_else(|| anyhow::anyhow!("malformed pax record"))?;
+        let len = parse_decimal(&data[..space])? as usize;
+        if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+            anyhow::bail!("malformed pax record");
+        }
+        let record = &data[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|&x| x == b'=')
+            .ok_or_else(|| anyhow::anyhow!("malformed pax record"))?;
+        records.push((
+            String::from_utf8_lossy(&record[..eq]).into_owned(),
+            record[eq + 1..].to_vec(),
+        ));
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+fn read_exact_at(
+    file: &dyn crate::fs_provider::File,
+    offset: u64,
+    buf: &mut [u8],
+) -> anyhow::Result<()> {
+    CursorFile::with_position(file, offset).read_exact(buf)?;
+    Ok(())
+}
+
+// Offset following `size` bytes of data stored from `pos`, padded to a block
+fn skip_data(pos: u64, size: u64) -> anyhow::Result<u64> {
+    size.checked_next_multiple_of(TAR_BLOCK_SIZE)
+        .and_then(|x| pos.checked_add(x))
+        .ok_or_else(|| anyhow::anyhow!("invalid tar entry size"))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TarSparseChunk {
+    // Offset within the expanded file
+    offset: u64,
+    size: u64,
+    // Offset of the stored data within the archive
+    data_offset: u64,
+}
+
+#[derive(Clone)]
+enum TarFileData {
+    Regular { start: u64 },
+    Sparse { chunks: Vec<TarSparseChunk> },
+}
+
+enum TarRecordKind {
+    File { data: TarFileData, size: u64 },
+    Folder,
+    HardLink { target: TarRecordName },
+}
+
+#[derive(Clone)]
+struct TarRecordName {
+    bytes: Vec<u8>,
+    // Names from pax headers are always UTF-8
+    is_utf8: bool,
+}
+
+struct TarRecord {
+    name: TarRecordName,
+    kind: TarRecordKind,
+    modify_time: SystemTime,
+    header_offset: u64,
+}
+
+// Lays out the sparse map `(offset, size)` over data stored from `data_start`
+fn make_sparse_chunks(map: &[(u64, u64)], data_start: u64) -> anyhow::Result<Vec<TarSparseChunk>> {
+    let mut data_offset = data_start;
+    let mut chunks = Vec::with_capacity(map.len());
+    for &(offset, size) in map {
+        if offset.checked_add(size).is_none() {
+            anyhow::bail!("malformed tar sparse map");
+        }
+        chunks.push(TarSparseChunk {
+            offset,
+            size,
+            data_offset,
+        });
+        data_offset = data_offset
+            .checked_add(size)
+            .ok_or_else(|| anyhow::anyhow!("malformed tar sparse map"))?;
+    }
+    Ok(chunks)
+}
+
+// Format 1.0 keeps the sparse map in front of the data, as decimal numbers
+// on separate lines (count first) padded to a block boundary
+fn read_sparse_map_v1(
+    file: &dyn crate::fs_provider::File,
+    data_start: u64,
+    size: u64,
+) -> anyhow::Result<(Vec<(u64, u64)>, u64)> {
+    let mut numbers = Vec::new();
+    // Count of numbers including the first one, known once that one is read
+    let mut total = None;
+    let mut line = Vec::new();
+    let mut block = [0u8; TAR_BLOCK_SIZE as usize];
+    let mut map_size = 0;
+    while total.is_none_or(|x| (numbers.len() as u64) < x) {
+        if map_size >= size.min(TAR_MAX_EXTENDED_HEADER_SIZE) {
+            anyhow::bail!("malformed pax sparse map");
+        }
+        read_exact_at(file, data_start + map_size, &mut block)?;
+        map_size += TAR_BLOCK_SIZE;
+        for &x in &block {
+            if x != b'\n' {
+                line.push(x);
+                continue;
+            }
+            numbers.push(parse_decimal(&line)?);
+            line.clear();
+            if total.is_none() {
+                let count = numbers[0]
+                    .checked_mul(2)
+                    .and_then(|x| x.checked_add(1))
+                    .ok_or_else(|| anyhow::anyhow!("malformed pax sparse map"))?;
+                total = Some(count);
+            }
+            if Some(numbers.len() as u64) == total {
+                break;
+            }
+        }
+    }
+    let map = numbers[1..].chunks(2).map(|x| (x[0], x[1])).collect();
+    Ok((map, map_size))
+}
+
+fn parse_records(
+    file: &dyn crate::fs_provider::File,
+    file_len: u64,
+) -> anyhow::Result<Vec<TarRecord>> {
+    let mut records = Vec::new();
+    let mut pos = 0u64;
+    let mut global_pax = Vec::new();
+    let mut local_pax: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut long_link: Option<Vec<u8>> = None;
+
+    let read_extended = |offset: u64, size: u64| -> anyhow::Result<Vec<u8>> {
+        if size > TAR_MAX_EXTENDED_HEADER_SIZE {
+            anyhow::bail!("tar extended header too large");
+        }
+        let mut buf = vec![0u8; size as usize];
+        read_exact_at(file, offset, &mut buf)?;
+        Ok(buf)
+    };
+
+    while file_len.saturating_sub(pos) >= TAR_BLOCK_SIZE {
+        let header_offset = pos;
+        let mut header = TarHeader([0; TAR_BLOCK_SIZE as usize]);
+        read_exact_at(file, pos, &mut header.0)?;
+        pos += TAR_BLOCK_SIZE;
+        if header.is_zero() {
+            // End of archive
+            break;
+        }
+        if !header.verify_checksum()? {
+            anyhow::bail!("bad tar header checksum at offset {header_offset}");
+        }
+
+        let typeflag = header.typeflag();
+        let mut size = header.size()?;
+        let data_start = pos;
+
+        match typeflag {
+            TAR_TYPE_PAX_LOCAL | TAR_TYPE_PAX_GLOBAL => {
+                let pax = parse_pax_records(&read_extended(data_start, size)?)?;
+                if typeflag == TAR_TYPE_PAX_GLOBAL {
+                    global_pax.extend(pax);
+                } else {
+                    local_pax = pax;
+                }
+                pos = skip_data(pos, size)?;
+                continue;
+            }
+            TAR_TYPE_GNU_LONG_NAME | TAR_TYPE_GNU_LONG_LINK => {
+                let mut data = read_extended(data_start, size)?;
+                data.truncate(trim_nul(&data).len());
+                if typeflag == TAR_TYPE_GNU_LONG_NAME {
+                    long_name = Some(data);
+                } else {
+                    long_link = Some(data);
+                }
+                pos = skip_data(pos, size)?;
+                continue;
+            }
+            _ => (),
+        }
+
+        // Resolve attributes, with later (local) pax records taking precedence
+        let pax = global_pax.iter().chain(local_pax.iter());
+        let mut name = TarRecordName {
+            bytes: match long_name.take() {
+                Some(x) => x,
+                None if !header.prefix().is_empty() => {
+                    [header.prefix(), b"/", header.name()].concat()
+                }
+                None => header.name().to_vec(),
+            },
+            is_utf8: false,
+        };
+        let mut link_name = TarRecordName {
+            bytes: long_link
+                .take()
+                .unwrap_or_else(|| header.linkname().to_vec()),
+            is_utf8: false,
+        };
+        let mut modify_time = unix_time(header.mtime()?, 0);
+        let mut sparse_map = Vec::new();
+        let mut sparse_real_size = None;
+        let mut sparse_major = None;
+        let mut pending_sparse_offset = None;
+        for (key, value) in pax {
+            match key.as_str() {
+                "path" => {
+                    name = TarRecordName {
+                        bytes: value.clone(),
+                        is_utf8: true,
+                    }
+                }
+                "linkpath" => {
+                    link_name = TarRecordName {
+                        bytes: value.clone(),
+                        is_utf8: true,
+                    }
+                }
+                "size" => size = parse_decimal(value)?,
+                "mtime" => modify_time = parse_pax_time(value)?,
+                "GNU.sparse.name" => {
+                    name = TarRecordName {
+                        bytes: value.clone(),
+                        is_utf8: true,
+                    }
+                }
+                "GNU.sparse.size" | "GNU.sparse.realsize" => {
+                    sparse_real_size = Some(parse_decimal(value)?)
+                }
+                "GNU.sparse.major" => sparse_major = Some(parse_decimal(value)?),
+                // Format 0.0
+                "GNU.sparse.offset" => pending_sparse_offset = Some(parse_decimal(value)?),
+                "GNU.sparse.numbytes" => {
+                    let offset = pending_sparse_offset
+                        .take()
+                        .ok_or_else(|| anyhow::anyhow!("malformed pax sparse map"))?;
+                    sparse_map.push((offset, parse_decimal(value)?));
+                }
+                // Format 0.1
+                "GNU.sparse.map" => {
+                    let values = std::str::from_utf8(value)?
+                        .split(',')
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.trim().parse::<u64>())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if values.len() % 2 != 0 {
+                        anyhow::bail!("malformed pax sparse map");
+                    }
+                    sparse_map = values.chunks(2).map(|x| (x[0], x[1])).collect();
+                }
+                _ => (),
+            }
+        }
+        local_pax.clear();
+        pos = skip_data(pos, size)?;
+
+        let kind = match typeflag {
+            TAR_TYPE_REGULAR | TAR_TYPE_REGULAR_OLD | TAR_TYPE_CONTIGUOUS => {
+                if sparse_major == Some(1) {
+                    let (map, map_size) = read_sparse_map_v1(file, data_start, size)?;
+                    TarRecordKind::File {
+                        data: TarFileData::Sparse {
+                            chunks: make_sparse_chunks(&map, data_start + map_size)?,
+                        },
+                        size: sparse_real_size.unwrap_or(size.saturating_sub(map_size)),
+                    }
+                } else if let Some(real_size) = sparse_real_size {
+                    TarRecordKind::File {
+                        data: TarFileData::Sparse {
+                            chunks: make_sparse_chunks(&sparse_map, data_start)?,
+                        },
+                        size: real_size,
+                    }
+                } else if name.bytes.ends_with(b"/") {
+                    // Old archives mark directories with a trailing slash only
+                    TarRecordKind::Folder
+                } else {
+                    TarRecordKind::File {
+                        data: TarFileData::Regular { start: data_start },
+                        size,
+                    }
+                }
+            }
+            TAR_TYPE_GNU_SPARSE => {
+                let mut map = Vec::new();
+                let mut push_entries = |block: &[u8], count: usize| -> anyhow::Result<()> {
+                    for i in 0..count {
+                        let entry = &block[i * 24..(i + 1) * 24];
+                        if entry[0] == 0 {
+                            break;
+                        }
+                        map.push((parse_number(&entry[..12])?, parse_number(&entry[12..])?));
+                    }
+                    Ok(())
+                };
+                push_entries(header.field(386, 96), 4)?;
+                let mut is_extended = header.gnu_sparse_is_extended();
+                let mut ext_pos = data_start;
+                while is_extended {
+                    let mut block = [0u8; TAR_BLOCK_SIZE as usize];
+                    read_exact_at(file, ext_pos, &mut block)?;
+                    push_entries(&block[..504], 21)?;
+                    is_extended = block[504] != 0;
+                    ext_pos += TAR_BLOCK_SIZE;
+                }
+                // Extension blocks precede the data and are not part of the size
+                pos = skip_data(ext_pos, size)?;
+                TarRecordKind::File {
+                    data: TarFileData::Sparse {
+                        chunks: make_sparse_chunks(&map, ext_pos)?,
+                    },
+                    size: header.gnu_sparse_real_size()?,
+                }
+            }
+            TAR_TYPE_DIRECTORY | TAR_TYPE_GNU_DUMPDIR => TarRecordKind::Folder,
+            TAR_TYPE_HARD_LINK => TarRecordKind::HardLink { target: link_name },
+            _ => {
+                // TODO: Support symbolic links
+                log::debug!("tar: skipping entry of type {typeflag:#x}");
+                continue;
+            }
+        };
+
+        records.push(TarRecord {
+            name,
+            kind,
+            modify_time,
+            header_offset,
+        });
+    }
+
+    Ok(records)
+}
+
+struct TarFolderEntry {
+    children: BTreeMap<CaselessString, TarEntry>,
+    index: u64,
+    modify_time: SystemTime,
+}
+
+struct TarFileEntry {
+    data: TarFileData,
+    size: u64,
+    index: u64,
+    modify_time: SystemTime,
+}
+
+enum TarEntry {
+    Folder(TarFolderEntry),
+    File(TarFileEntry),
+}
+
+impl TarFolderEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: 0,
+            is_dir: true,
+            attributes: FileAttributes::DirectoryFile,
+            creation_time: self.modify_time,
+            last_access_time: self.modify_time,
+            last_write_time: self.modify_time,
+        }
+    }
+}
+
+impl TarFileEntry {
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        FileStatInfo {
+            index: self.index,
+            size: self.size,
+            is_dir: false,
+            attributes: FileAttributes::empty(),
+            creation_time: self.modify_time,
+            last_access_time: self.modify_time,
+            last_write_time: self.modify_time,
+        }
+    }
+}
+
+impl TarEntry {
+    fn as_borrowed(&self) -> BorrowedTarEntry<'_> {
+        match self {
+            Self::Folder(e) => BorrowedTarEntry::Folder(e),
+            Self::File(e) => BorrowedTarEntry::File(e),
+        }
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        self.as_borrowed().get_file_stat_info()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum BorrowedTarEntry<'a> {
+    Folder(&'a TarFolderEntry),
+    File(&'a TarFileEntry),
+}
+
+impl BorrowedTarEntry<'_> {
+    fn is_dir(&self) -> bool {
+        matches!(self, Self::Folder(_))
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        match self {
+            Self::Folder(e) => e.get_file_stat_info(),
+            Self::File(e) => e.get_file_stat_info(),
+        }
+    }
+}
+
+impl TreeFolder for TarFolderEntry {
+    type Entry = TarEntry;
+
+    fn children_mut(&mut self) -> &mut BTreeMap<CaselessString, TarEntry> {
+        &mut self.children
+    }
+    fn new_implied_child(&self, index: u64) -> TarEntry {
+        TarEntry::Folder(TarFolderEntry {
+            children: BTreeMap::new(),
+            index,
+            modify_time: SystemTime::UNIX_EPOCH,
+        })
+    }
+    fn entry_as_folder_mut(entry: &mut TarEntry) -> Option<&mut Self> {
+        match entry {
+            TarEntry::Folder(e) => Some(e),
+            TarEntry::File(_) => None,
+        }
+    }
+    fn entry_is_folder(entry: &TarEntry) -> bool {
+        matches!(entry, TarEntry::Folder(_))
+    }
+}
+
+fn build_file_tree(
+    records: Vec<TarRecord>,
+    root_index: u64,
+    non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    converter: &super::EncodingConverter,
+    root_modify_time: SystemTime,
+) -> anyhow::Result<(TarFolderEntry, Vec<ContainerWarning>)> {
+    let convert_name = |name: &TarRecordName| -> String {
+        if name.is_utf8 {
+            return String::from_utf8_lossy(&name.bytes).into_owned();
+        }
+        let path = if non_unicode_compat.allow_utf8_mix {
+            simdutf8::basic::from_utf8(&name.bytes)
+                .map(Cow::Borrowed)
+                .unwrap_or_else(|_| converter.convert(&name.bytes))
+        } else {
+            converter.convert(&name.bytes)
+        };
+        path.into_owned()
+    };
+
+    let mut root = TarFolderEntry {
+        children: BTreeMap::new(),
+        index: root_index,
+        modify_time: root_modify_time,
+    };
+    // NOTE: Used for "unique" index generation
+    let mut counter: u64 = 0;
+    // Regular files by their normalized path, for resolving hard links
+    let mut files_by_path: HashMap<String, (TarFileData, u64)> = HashMap::new();
+    let mut warnings = TreeWarnings::new("tar");
+
+    for record in records {
+        let path = convert_name(&record.name);
+        // NOTE: Paths are normalized, so that entries never escape the archive
+        let Some(components) = normalize_entry_path(&path, &mut warnings) else {
+            continue;
+        };
+        let (filename, parents) = components.split_last().unwrap();
+
+        let (data, size) = match record.kind {
+            TarRecordKind::File { data, size } => (Some(data), size),
+            TarRecordKind::Folder => (None, 0),
+            TarRecordKind::HardLink { target } => {
+                let target = split_entry_path(&convert_name(&target)).join("/");
+                match files_by_path.get(&target) {
+                    Some((data, size)) => (Some(data.clone()), *size),
+                    None => {
+                        warnings.add(&path, "is skipped as its link target is missing".to_owned());
+                        continue;
+                    }
+                }
+            }
+        };
+        let make_index = || {
+            counter += 1;
+            calculate_hash(&(root_index, counter))
+        };
+        let index = calculate_hash(&(root_index, record.header_offset));
+        let Some(data) = data else {
+            // The folder record describes the folder itself
+            let folder = get_or_create_folder(&mut root, &components, make_index, &mut warnings);
+            folder.index = index;
+            folder.modify_time = record.modify_time;
+            continue;
+        };
+        files_by_path.insert(components.join("/"), (data.clone(), size));
+
+        let cur_dir = get_or_create_folder(&mut root, parents, make_index, &mut warnings);
+        let file = TarEntry::File(TarFileEntry {
+            data,
+            size,
+            index,
+            modify_time: record.modify_time,
+        });
+        insert_entry(cur_dir, filename, file, &path, &mut warnings);
+    }
+
+    Ok((root, warnings.finish()))
+}
+
+// Backing data of a tarball, which may need to be decompressed first
+enum TarSource<'a> {
+    File(&'a dyn crate::fs_provider::File),
+    Compressed(Box<CompressedStream<'a>>),
+}
+
+impl TarSource<'_> {
+    fn as_file(&self) -> &dyn crate::fs_provider::File {
+        match self {
+            Self::File(file) => *file,
+            Self::Compressed(stream) => stream.as_ref(),
+        }
+    }
+}
+
+// Checks whether the file starts with a valid tar header
+pub(super) fn sniff(file: &dyn crate::fs_provider::File) -> bool {
+    let mut header = TarHeader([0; TAR_BLOCK_SIZE as usize]);
+    read_exact_at(file, 0, &mut header.0).is_ok()
+        && !header.is_zero()
+        && header.verify_checksum().unwrap_or(false)
+}
+
+pub struct TarArchive<'a> {
+    source: TarSource<'a>,
+    root: TarFolderEntry,
+    index_size: u64,
+    // Entries which were renamed or skipped while building the tree
+    warnings: Vec<ContainerWarning>,
+}
+
+impl<'a> TarArchive<'a> {
+    pub(super) fn new(
+        open_ctx: ArchiveHandlerOpenContext<'a>,
+        non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    ) -> FileSystemResult<Self> {
+        // We only handle files instead of folders
+        if open_ctx.get_is_dir() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        Self::with_source(
+            TarSource::File(open_ctx.get_file()),
+            open_ctx,
+            non_unicode_compat,
+        )
+    }
+    pub(super) fn new_compressed(
+        open_ctx: ArchiveHandlerOpenContext<'a>,
+        non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    ) -> FileSystemResult<Self> {
+        // We only handle files instead of folders
+        if open_ctx.get_is_dir() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let stream = CompressedStream::new(open_ctx.get_file(), open_ctx.get_cache())?;
+        Self::with_source(
+            TarSource::Compressed(Box::new(stream)),
+            open_ctx,
+            non_unicode_compat,
+        )
+    }
+    fn with_source(
+        source: TarSource<'a>,
+        open_ctx: ArchiveHandlerOpenContext<'a>,
+        non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+    ) -> FileSystemResult<Self> {
+        let file = source.as_file();
+        let file_stat = file.get_stat()?;
+        let records = parse_records(file, file_stat.size)?;
+        // NOTE: Rough estimate, names are counted twice as they also key the tree
+        let index_size = records
+            .iter()
+            .map(|x| (std::mem::size_of::<TarRecord>() + x.name.bytes.len() * 2) as u64)
+            .sum();
+        let converter = open_ctx.get_name_converter(
+            &non_unicode_compat.encoding_override,
+            records
+                .iter()
+                .filter(|x| !x.name.is_utf8)
+                .map(|x| &x.name.bytes[..]),
+        )?;
+        let (root, warnings) = build_file_tree(
+            records,
+            file_stat.index,
+            non_unicode_compat,
+            &converter,
+            file_stat.last_write_time,
+        )?;
+        Ok(TarArchive {
+            source,
+            root,
+            index_size,
+            warnings,
+        })
+    }
+    fn find_entry(&self, path: SegPath) -> FileSystemResult<BorrowedTarEntry<'_>> {
+        let mut cur_dir = &self.root;
+        let mut it = path.into_iter().peekable();
+        while let Some(segment) = it.next() {
+            let entry = cur_dir.children.get(CaselessStr::new(segment));
+            match (entry, it.peek()) {
+                (Some(entry), None) => return Ok(entry.as_borrowed()),
+                (Some(TarEntry::Folder(folder)), Some(_)) => cur_dir = folder,
+                (None, None) => return Err(FileSystemError::ObjectNameNotFound),
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            }
+        }
+        Ok(BorrowedTarEntry::Folder(cur_dir))
+    }
+}
+
+impl super::ArchiveHandler for TarArchive<'_> {
+    fn open_file(
+        &self,
+        filename: SegPath,
+    ) -> FileSystemResult<super::ArchiveHandlerOpenFileInfo<'_>> {
+        let entry = self.find_entry(filename)?;
+        Ok(super::ArchiveHandlerOpenFileInfo {
+            context: Box::new(TarFile { root: self, entry }),
+            is_dir: entry.is_dir(),
+        })
+    }
+    fn get_index_size(&self) -> u64 {
+        self.index_size
+    }
+    fn get_warnings(&self) -> Vec<ContainerWarning> {
+        self.warnings.clone()
+    }
+    fn on_idle(&self) {
+        if let TarSource::Compressed(stream) = &self.source {
+            stream.release_decoder();
+        }
+    }
+}
+
+// NOTE: The archive lifetime is kept separate, as the archive is invariant over it
+pub struct TarFile<'a, 'b> {
+    root: &'a TarArchive<'b>,
+    entry: BorrowedTarEntry<'a>,
+}
+
+impl super::ArchiveFile for TarFile<'_, '_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let entry = match self.entry {
+            BorrowedTarEntry::File(e) => e,
+            BorrowedTarEntry::Folder(_) => return Err(FileSystemError::FileIsADirectory),
+        };
+        let file = self.root.source.as_file();
+        let read_len = buffer.len().min(entry.size.saturating_sub(offset) as _);
+        let buffer = &mut buffer[..read_len];
+        match &entry.data {
+            TarFileData::Regular { start } => file.read_at(start + offset, buffer),
+            TarFileData::Sparse { chunks } => {
+                // Fill holes with zeros and copy stored data in between
+                let mut total = 0;
+                let mut idx = chunks.partition_point(|x| x.offset + x.size <= offset);
+                while total < buffer.len() {
+                    let pos = offset + total as u64;
+                    let remaining = &mut buffer[total..];
+                    let len = match chunks.get(idx) {
+                        Some(chunk) if chunk.offset <= pos => {
+                            let len = remaining.len().min((chunk.offset + chunk.size - pos) as _);
+                            let data_pos = chunk.data_offset + (pos - chunk.offset);
+                            let len = file.read_at(data_pos, &mut remaining[..len])? as usize;
+                            if len == 0 {
+                                break;
+                            }
+                            if pos + len as u64 == chunk.offset + chunk.size {
+                                idx += 1;
+                            }
+                            len
+                        }
+                        next => {
+                            let hole_end = next.map_or(entry.size, |x| x.offset);
+                            let len = remaining.len().min((hole_end - pos) as _);
+                            remaining[..len].fill(0);
+                            len
+                        }
+                    };
+                    total += len;
+                }
+                Ok(total as _)
+            }
+        }
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(self.entry.get_file_stat_info())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn crate::fs_provider::FilePattern,
+        filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let entry = match self.entry {
+            BorrowedTarEntry::Folder(e) => e,
+            BorrowedTarEntry::File(_) => return Err(FileSystemError::NotADirectory),
+        };
+        for (name, child) in entry
+            .children
+            .iter()
+            .filter(|(name, _)| pattern.check_name(name.as_str()))
+        {
+            if filler
+                .fill_data(name.as_str(), &child.get_file_stat_info())
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_util::MemFile, EncodingConverter};
+    use super::*;
+
+    // A header block with a valid checksum and no data
+    fn make_header(name: &str, typeflag: u8, size: usize, mtime: &[u8; 12]) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK_SIZE as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+        header[136..148].copy_from_slice(mtime);
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(TAR_MAGIC_USTAR);
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|&x| x as u64).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        header
+    }
+
+    fn pad_block(data: &mut Vec<u8>) {
+        data.resize(data.len().next_multiple_of(TAR_BLOCK_SIZE as usize), 0);
+    }
+
+    #[test]
+    fn clamps_out_of_range_times() {
+        // Base-256 mtime of u64::MAX
+        let mut max_mtime = [0xffu8; 12];
+        max_mtime[..4].copy_from_slice(&[0x80, 0, 0, 0]);
+        let mut data = make_header("base256.txt", TAR_TYPE_REGULAR, 0, &max_mtime);
+
+        let pax = b"32 mtime=18446744073709551615.5\n";
+        let zero_mtime = *b"00000000000\0";
+        data.extend(make_header(
+            "pax",
+            TAR_TYPE_PAX_LOCAL,
+            pax.len(),
+            &zero_mtime,
+        ));
+        data.extend_from_slice(pax);
+        pad_block(&mut data);
+        data.extend(make_header("pax.txt", TAR_TYPE_REGULAR, 0, &zero_mtime));
+        data.resize(data.len() + 2 * TAR_BLOCK_SIZE as usize, 0);
+
+        let file = MemFile(data);
+        let records = parse_records(&file, file.0.len() as _).unwrap();
+        assert_eq!(records.len(), 2);
+        for record in records {
+            assert_eq!(record.modify_time, SystemTime::UNIX_EPOCH);
+        }
+    }
+    #[test]
+    fn reports_renamed_and_skipped_entries() {
+        let zero_mtime = *b"00000000000\0";
+        let mut data = Vec::new();
+        for name in [
+            "a.txt",
+            "A.TXT",
+            "../up.txt",
+            "d",
+            "d/x.txt",
+            "./",
+            "con.txt",
+        ] {
+            data.extend(make_header(name, TAR_TYPE_REGULAR, 0, &zero_mtime));
+        }
+        data.resize(data.len() + 2 * TAR_BLOCK_SIZE as usize, 0);
+
+        let file = MemFile(data);
+        let records = parse_records(&file, file.0.len() as _).unwrap();
+        let (root, warnings) = build_file_tree(
+            records,
+            0,
+            &ArchiveNonUnicodeCompatConfig::default(),
+            &EncodingConverter::with_encoding(encoding_rs::WINDOWS_1252),
+            SystemTime::UNIX_EPOCH,
+        )
+        .unwrap();
+        let names: Vec<_> = root.children.keys().map(|x| x.as_str()).collect();
+        assert_eq!(
+            names,
+            ["_con.txt", "A (1).TXT", "a.txt", "d", "d (1)", "up.txt"]
+        );
+        let Some(TarEntry::Folder(folder)) = root.children.get(CaselessStr::new("d")) else {
+            panic!("`d` is not a folder");
+        };
+        assert!(folder.children.contains_key(CaselessStr::new("x.txt")));
+        let paths: Vec<_> = warnings.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, ["A.TXT", "../up.txt", "d", "./", "con.txt"]);
+    }
+}
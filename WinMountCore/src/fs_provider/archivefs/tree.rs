@@ -0,0 +1,162 @@
+// Helpers shared by handlers which keep the entries of an archive in a tree of
+// folders built when the archive is opened
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    fs_provider::ContainerWarning,
+    util::{CaselessStr, CaselessString},
+};
+
+use super::sanitize::{make_unique_name, split_entry_path};
+
+// A folder of such a tree, along with the entry type of its children
+pub(super) trait TreeFolder: Sized {
+    type Entry;
+
+    fn children_mut(&mut self) -> &mut BTreeMap<CaselessString, Self::Entry>;
+    // Makes a folder not recorded in the archive, implied by a path inside it
+    fn new_implied_child(&self, index: u64) -> Self::Entry;
+    fn entry_as_folder_mut(entry: &mut Self::Entry) -> Option<&mut Self>;
+    fn entry_is_folder(entry: &Self::Entry) -> bool;
+}
+
+// Entries which were renamed or skipped while building a tree
+pub(super) struct TreeWarnings {
+    handler: &'static str,
+    warnings: Vec<ContainerWarning>,
+}
+
+impl TreeWarnings {
+    pub fn new(handler: &'static str) -> Self {
+        TreeWarnings {
+            handler,
+            warnings: Vec::new(),
+        }
+    }
+    pub fn add(&mut self, path: &str, msg: String) {
+        log::debug!("{}: entry `{path}` {msg}", self.handler);
+        self.warnings.push(ContainerWarning {
+            path: path.to_owned(),
+            msg,
+        });
+    }
+    pub fn finish(self) -> Vec<ContainerWarning> {
+        if !self.warnings.is_empty() {
+            log::warn!(
+                "{}: {} entries have been renamed or skipped",
+                self.handler,
+                self.warnings.len()
+            );
+        }
+        self.warnings
+    }
+}
+
+pub(super) fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+// Splits an entry path with `split_entry_path`, reporting paths which had to be
+// changed and skipping ones where nothing is left
+// NOTE: Backslashes alone are not reported, as some archivers use them as separators
+pub(super) fn normalize_entry_path<'p>(
+    path: &'p str,
+    warnings: &mut TreeWarnings,
+) -> Option<Vec<Cow<'p, str>>> {
+    let components = split_entry_path(path);
+    if components.is_empty() {
+        warnings.add(path, "is skipped as its path is empty".to_owned());
+        return None;
+    }
+    let normalized_path = components.join("/");
+    if normalized_path != path.replace('\\', "/").trim_end_matches('/') {
+        warnings.add(path, format!("is normalized to `{normalized_path}`"));
+    }
+    Some(components)
+}
+
+// Walks down `parents` from `root`, creating missing folders on the way
+// NOTE: Folders may hold many entries, so files in the way are rather renamed
+pub(super) fn get_or_create_folder<'t, F: TreeFolder>(
+    root: &'t mut F,
+    parents: &[impl AsRef<str>],
+    mut make_index: impl FnMut() -> u64,
+    warnings: &mut TreeWarnings,
+) -> &'t mut F {
+    let mut cur_dir = root;
+    let mut cur_path = String::new();
+    for segment in parents {
+        let segment = segment.as_ref();
+        let key = CaselessStr::new(segment);
+        let children = cur_dir.children_mut();
+        if children.get(key).is_some_and(|x| !F::entry_is_folder(x)) {
+            let (old_key, file) = children.remove_entry(key).unwrap();
+            let new_name = make_unique_name(old_key.as_str(), |x| {
+                children.contains_key(CaselessStr::new(x))
+            });
+            warnings.add(
+                &join_path(&cur_path, old_key.as_str()),
+                format!("is renamed to `{new_name}` as a folder has the same name"),
+            );
+            children.insert(CaselessString::new(new_name), file);
+        }
+        if !cur_dir.children_mut().contains_key(key) {
+            let entry = cur_dir.new_implied_child(make_index());
+            cur_dir
+                .children_mut()
+                .insert(CaselessString::new(segment.to_owned()), entry);
+        }
+        let entry = cur_dir.children_mut().get_mut(key).unwrap();
+        cur_dir = F::entry_as_folder_mut(entry).unwrap();
+        cur_path = join_path(&cur_path, segment);
+    }
+    cur_dir
+}
+
+// Adds `entry` to `folder` as `name`. A file of the exact same name is replaced,
+// as when extracting, while other collisions get a unique name instead.
+pub(super) fn insert_entry<F: TreeFolder>(
+    folder: &mut F,
+    name: &str,
+    entry: F::Entry,
+    path: &str,
+    warnings: &mut TreeWarnings,
+) {
+    let children = folder.children_mut();
+    let mut key = CaselessString::new(name.to_owned());
+    if let Some((old_key, old)) = children.get_key_value(&key) {
+        if old_key.as_str() == name && !F::entry_is_folder(old) && !F::entry_is_folder(&entry) {
+            warnings.add(
+                path,
+                "replaces an earlier entry of the same path".to_owned(),
+            );
+        } else {
+            let new_name = make_unique_name(name, |x| children.contains_key(CaselessStr::new(x)));
+            warnings.add(
+                path,
+                format!("is renamed to `{new_name}` as its name collides"),
+            );
+            key = CaselessString::new(new_name);
+        }
+    }
+    children.insert(key, entry);
+}
+
+pub(super) fn filetime_to_system_time(filetime: u64) -> SystemTime {
+    // FILETIME counts 100ns intervals since 1601-01-01
+    const FILETIME_UNIX_EPOCH_DIFF: u64 = 11644473600;
+    let since_1601 = Duration::new(filetime / 10_000_000, (filetime % 10_000_000) as u32 * 100);
+    SystemTime::UNIX_EPOCH
+        .checked_add(since_1601)
+        .and_then(|x| x.checked_sub(Duration::from_secs(FILETIME_UNIX_EPOCH_DIFF)))
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
@@ -0,0 +1,155 @@
+// Multi-volume archives, stitched into a single seekable stream
+// NOTE: Volumes other than the archive file itself are opened as dependent files
+//       of the archive, so they are kept open as long as the archive is
+
+use crate::fs_provider::{FileStatInfo, FileSystemError, FileSystemResult, SegPath};
+
+use super::{ArchiveHandlerOpenContext, ArchiveOpenDepFileGuard};
+
+pub(super) enum VolumeFile<'a> {
+    // The file the archive was opened from
+    Main(&'a dyn crate::fs_provider::File),
+    Sibling(ArchiveOpenDepFileGuard<'a>),
+}
+
+struct Volume<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    start: u64,
+    size: u64,
+}
+
+pub(super) struct MultiVolumeFile<'a> {
+    volumes: Vec<Volume<'a>>,
+    // Stat of the main volume, reported for the whole set
+    main_stat: FileStatInfo,
+    size: u64,
+    // NOTE: Guards are type-erased so that the archive stays covariant over 'a
+    _guards: Vec<Box<dyn Send + Sync + 'a>>,
+}
+
+impl<'a> MultiVolumeFile<'a> {
+    /// Stitches `files` in order, which must contain exactly one main volume.
+    pub fn new(files: Vec<VolumeFile<'a>>) -> FileSystemResult<Self> {
+        let mut volumes = Vec::with_capacity(files.len());
+        let mut guards: Vec<Box<dyn Send + Sync + 'a>> = Vec::new();
+        let mut main_stat = None;
+        let mut size: u64 = 0;
+        for file in files {
+            let file = match file {
+                VolumeFile::Main(file) => {
+                    main_stat = Some(file.get_stat()?);
+                    file
+                }
+                VolumeFile::Sibling(guard) => {
+                    let file = guard.get_file();
+                    guards.push(Box::new(guard));
+                    file
+                }
+            };
+            let stat = file.get_stat()?;
+            if stat.is_dir {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            volumes.push(Volume {
+                file,
+                start: size,
+                size: stat.size,
+            });
+            size = size
+                .checked_add(stat.size)
+                .ok_or(FileSystemError::FileCorruptError)?;
+        }
+        Ok(Self {
+            volumes,
+            main_stat: main_stat.ok_or(FileSystemError::FileCorruptError)?,
+            size,
+            _guards: guards,
+        })
+    }
+    pub fn get_volume_count(&self) -> usize {
+        self.volumes.len()
+    }
+    /// Returns the offset of a volume within the stitched stream.
+    pub fn get_volume_start(&self, index: usize) -> Option<u64> {
+        self.volumes.get(index).map(|x| x.start)
+    }
+}
+
+/// Opens the sibling volumes named by `names` in order, stopping at the first missing one.
+pub(super) fn open_sibling_volumes<'a>(
+    open_ctx: ArchiveHandlerOpenContext<'a>,
+    names: impl Iterator<Item = String>,
+) -> FileSystemResult<Vec<VolumeFile<'a>>> {
+    let mut volumes = Vec::new();
+    for name in names {
+        match open_ctx.open_sibling_file(&name) {
+            Ok(info) => volumes.push(VolumeFile::Sibling(info.context)),
+            Err(FileSystemError::ObjectNameNotFound | FileSystemError::ObjectPathNotFound) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(volumes)
+}
+
+impl crate::fs_provider::File for MultiVolumeFile<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        // Find the last volume starting at or before offset
+        let mut index = self.volumes.partition_point(|x| x.start <= offset);
+        let mut offset = offset;
+        let mut total = 0;
+        while index > 0 && index <= self.volumes.len() && total < buffer.len() {
+            let volume = &self.volumes[index - 1];
+            let volume_offset = offset - volume.start;
+            if volume_offset < volume.size {
+                let len = (buffer.len() - total).min((volume.size - volume_offset) as usize);
+                let buf = &mut buffer[total..total + len];
+                volume.file.read_at_exact(volume_offset, buf)?;
+                total += len;
+                offset += len as u64;
+            }
+            index += 1;
+        }
+        Ok(total as _)
+    }
+    fn write_at(
+        &self,
+        _offset: Option<u64>,
+        _buffer: &[u8],
+        _constrain_size: bool,
+    ) -> FileSystemResult<u64> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn flush_buffers(&self) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(FileStatInfo {
+            size: self.size,
+            ..self.main_stat
+        })
+    }
+    fn set_end_of_file(&self, _offset: u64) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_file_times(
+        &self,
+        _creation_time: std::time::SystemTime,
+        _last_access_time: std::time::SystemTime,
+        _last_write_time: std::time::SystemTime,
+    ) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_delete(&self, _delete_on_close: bool) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn move_to(&self, _new_path: SegPath, _replace_if_exists: bool) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn find_files_with_pattern(
+        &self,
+        _pattern: &dyn crate::fs_provider::FilePattern,
+        _filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        Err(FileSystemError::NotADirectory)
+    }
+}
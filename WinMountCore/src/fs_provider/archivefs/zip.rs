@@ -3,41 +3,83 @@ use std::{
     collections::BTreeMap,
     io::{BufReader, Read, Seek},
     mem::MaybeUninit,
-    sync::RwLock,
+    sync::{Arc, Mutex},
     time::SystemTime,
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     fs_provider::{
-        CursorFile, FileAttributes, FileStatInfo, FileSystemError, FileSystemResult, OwnedFile,
-        PathDelimiter, SegPath,
+        ContainerWarning, CursorFile, FileAttributes, FileStatInfo, FileSystemError,
+        FileSystemResult, OwnedFile, PathDelimiter, SegPath,
     },
     util::{calculate_hash, CaselessStr, CaselessString},
 };
 
-use super::{ArchiveHandlerOpenContext, ArchiveNonUnicodeCompatConfig, ArchiveNonUnicodeEncoding};
+use super::{
+    cache::{read_cached_blocks, ArchiveCacheHandle},
+    crypto::{
+        ZipAesDecryptor, ZipAesMac, ZipCryptoKeys, ZIP_AES_MAC_SIZE, ZIP_AES_VERIFIER_SIZE,
+        ZIP_CRYPTO_HEADER_SIZE,
+    },
+    index_cache::ArchiveIndexKey,
+    inflate::{InflateFormat, InflateIndex, Inflater},
+    sanitize::{make_unique_name, split_entry_path},
+    volume::{open_sibling_volumes, MultiVolumeFile, VolumeFile},
+    ArchiveCrcCheckMode, ArchiveHandlerOpenContext, ArchiveNonUnicodeCompatConfig,
+    ArchiveWriteConfig,
+};
+
+mod write;
 
 const ZIP_COMPRESSION_STORE: u16 = 0;
 const ZIP_COMPRESSION_DEFLATE: u16 = 8;
+const ZIP_COMPRESSION_DEFLATE64: u16 = 9;
+const ZIP_COMPRESSION_BZIP2: u16 = 12;
+const ZIP_COMPRESSION_LZMA: u16 = 14;
+const ZIP_COMPRESSION_ZSTD: u16 = 93;
+// WinZip AES, the actual method is stored in the extra field
+const ZIP_COMPRESSION_AES: u16 = 99;
 
 const ZIP_GPFLAGS_ENCRYPTED: u16 = 0x1;
+// For LZMA, the stream is terminated with an end-of-stream marker
+const ZIP_GPFLAGS_LZMA_EOS: u16 = 0x2;
+const ZIP_GPFLAGS_DATA_DESCRIPTOR: u16 = 0x8;
+const ZIP_GPFLAGS_STRONG_ENCRYPTION: u16 = 0x40;
 // Language encoding flag
 const ZIP_GPFLAGS_EFS: u16 = 0x800;
 
+// Host systems (upper byte of `made_by_ver`) keeping Unix modes in external attributes
+const ZIP_HOST_UNIX: u8 = 3;
+const ZIP_HOST_OSX: u8 = 19;
+
+// MS-DOS attributes, kept in the lower byte of external attributes
+const ZIP_DOS_ATTRIBUTE_READONLY: u32 = 0x01;
+const ZIP_DOS_ATTRIBUTE_HIDDEN: u32 = 0x02;
+const ZIP_DOS_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+
+const UNIX_S_IFMT: u32 = 0o170000;
+const UNIX_S_IFDIR: u32 = 0o040000;
+const UNIX_S_IFLNK: u32 = 0o120000;
+const UNIX_WRITE_BITS: u32 = 0o222;
+
+// NOTE: Fields are widened to hold Zip64 values as well
 #[derive(Debug)]
 struct ZipEndOfCentralDirRecord {
-    num_disk: u16,
-    num_disk_central_dir_start: u16,
-    total_central_dir_records_this_disk: u16,
-    total_central_dir_records: u16,
-    size_central_dir: u32,
-    offset_central_dir: u32,
+    num_disk: u32,
+    num_disk_central_dir_start: u32,
+    total_central_dir_records_this_disk: u64,
+    total_central_dir_records: u64,
+    size_central_dir: u64,
+    offset_central_dir: u64,
     comment: Vec<u8>,
+    is_zip64: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ZipCentralDirRecord {
     made_by_ver: u16,
     min_extract_ver: u16,
@@ -46,15 +88,17 @@ struct ZipCentralDirRecord {
     last_modify_time: u16,
     last_modify_date: u16,
     uncompressed_data_crc32: u32,
-    compressed_size: u32,
-    uncompressed_size: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
     file_name: Vec<u8>,
     extra_data: Vec<u8>,
     comment: Vec<u8>,
-    num_disk_start: u16,
+    num_disk_start: u32,
     internal_file_attributes: u16,
     external_file_attributes: u32,
-    local_file_header_offset: u32,
+    local_file_header_offset: u64,
+    // Why the Zip64 extra field could not be applied, leaving sizes and offset unusable
+    zip64_error: Option<String>,
 }
 
 #[derive(Debug)]
@@ -65,8 +109,8 @@ struct ZipLocalFileRecord {
     last_modify_time: u16,
     last_modify_date: u16,
     uncompressed_data_crc32: u32,
-    compressed_size: u32,
-    uncompressed_size: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
     file_name: Vec<u8>,
     extra_data: Vec<u8>,
 }
@@ -74,23 +118,88 @@ struct ZipLocalFileRecord {
 // NOTE: All these sizes only account for static fields (e.g. file names are not included)
 const ZIP_LOCAL_FILE_HEADER_SIZE: u32 = 30;
 
+// Granularity of decompressed data kept in the cache
+const ZIP_CACHE_BLOCK_SIZE: u64 = 256 * 1024;
+
+// Attributes and times of an entry, as found in its record
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ZipEntryStat {
+    // Bits of FileAttributes
+    attributes: u32,
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+}
+
+impl ZipEntryStat {
+    fn new(time: SystemTime) -> Self {
+        Self {
+            attributes: 0,
+            creation_time: time,
+            last_access_time: time,
+            last_write_time: time,
+        }
+    }
+    fn get_attributes(&self) -> FileAttributes {
+        FileAttributes::from_bits_truncate(self.attributes)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 struct ZipFolderEntry {
     children: BTreeMap<CaselessString, ZipEntry>,
     index: u64,
-    dos_modify_time: SystemTime,
+    stat: ZipEntryStat,
+    // Directory record of the folder, kept for writing it back
+    record: Option<ZipCentralDirRecord>,
 }
 
+#[derive(Serialize, Deserialize)]
 struct ZipFileEntry {
     data: ZipCentralDirRecord,
     index: u64,
-    dos_modify_time: SystemTime,
+    stat: ZipEntryStat,
 }
 
+#[derive(Serialize, Deserialize)]
 enum ZipEntry {
     Folder(ZipFolderEntry),
     File(ZipFileEntry),
 }
 
+// Parsed index of an archive, as kept in the on-disk index cache
+#[derive(Serialize, Deserialize)]
+struct ZipIndex {
+    cd: ZipFolderEntry,
+    data_start: u64,
+    index_size: u64,
+    // Records left out of the tree, which are still written back on commit
+    unplaced: Vec<ZipCentralDirRecord>,
+    warnings: Vec<ContainerWarning>,
+}
+
+// Decodes names and comments, which are UTF-8 if flagged so and otherwise use the
+// non-Unicode encoding of the archive
+struct ZipTextDecoder {
+    converter: super::EncodingConverter,
+    ignore_utf8_flags: bool,
+    allow_utf8_mix: bool,
+}
+
+impl ZipTextDecoder {
+    fn decode<'b>(&self, bytes: &'b [u8], has_utf8_flag: bool) -> Cow<'b, str> {
+        if has_utf8_flag && !self.ignore_utf8_flags {
+            String::from_utf8_lossy(bytes)
+        } else if self.allow_utf8_mix {
+            simdutf8::basic::from_utf8(bytes)
+                .map(Cow::Borrowed)
+                .unwrap_or_else(|_| self.converter.convert(bytes))
+        } else {
+            self.converter.convert(bytes)
+        }
+    }
+}
+
 const ZERO_TIME: std::time::SystemTime = std::time::SystemTime::UNIX_EPOCH;
 
 impl ZipFolderEntry {
@@ -99,10 +208,10 @@ impl ZipFolderEntry {
             index: self.index,
             size: 0,
             is_dir: true,
-            attributes: FileAttributes::DirectoryFile,
-            creation_time: self.dos_modify_time,
-            last_access_time: self.dos_modify_time,
-            last_write_time: self.dos_modify_time,
+            attributes: self.stat.get_attributes() | FileAttributes::DirectoryFile,
+            creation_time: self.stat.creation_time,
+            last_access_time: self.stat.last_access_time,
+            last_write_time: self.stat.last_write_time,
         }
     }
 }
@@ -113,10 +222,10 @@ impl ZipFileEntry {
             index: self.index,
             size: self.data.uncompressed_size as _,
             is_dir: false,
-            attributes: FileAttributes::empty(),
-            creation_time: self.dos_modify_time,
-            last_access_time: self.dos_modify_time,
-            last_write_time: self.dos_modify_time,
+            attributes: self.stat.get_attributes(),
+            creation_time: self.stat.creation_time,
+            last_access_time: self.stat.last_access_time,
+            last_write_time: self.stat.last_write_time,
         }
     }
 }
@@ -160,19 +269,102 @@ impl BorrowedZipEntry<'_> {
     }
 }
 
+enum ZipSource<'a> {
+    File(&'a dyn crate::fs_provider::File),
+    // Spanned archive (`.z01`, `.z02`, ..., `.zip`), one disk per volume
+    Spanned(Box<MultiVolumeFile<'a>>),
+    // Single archive cut into pieces (`.zip.001`, `.zip.002`, ...)
+    Split(Box<MultiVolumeFile<'a>>),
+}
+
+impl<'a> ZipSource<'a> {
+    fn open(open_ctx: ArchiveHandlerOpenContext<'a>) -> FileSystemResult<Self> {
+        let file = open_ctx.get_file();
+        let name = open_ctx.get_file_name();
+
+        if let Some(stem) = name.strip_suffix("001").and_then(|x| x.strip_suffix('.')) {
+            let names = (2..).map(|i| format!("{stem}.{i:03}"));
+            let mut volumes = vec![VolumeFile::Main(file)];
+            volumes.extend(open_sibling_volumes(open_ctx, names)?);
+            return Ok(Self::Split(Box::new(MultiVolumeFile::new(volumes)?)));
+        }
+
+        // Spanned archives keep the central directory in the last volume, which
+        // is the one named `.zip`
+        let (eocd, eocd_offset) = parse_eocd_record(file, file.get_stat()?.size)?;
+        let num_disk = match parse_zip64_eocd_locator(file, eocd_offset)? {
+            Some(locator) => locator.total_disks.saturating_sub(1),
+            None => eocd.num_disk,
+        };
+        if num_disk == 0 {
+            return Ok(Self::File(file));
+        }
+        let Some((stem, ext)) = name.rsplit_once('.') else {
+            return Err(anyhow::anyhow!("cannot locate volumes of spanned zip archive").into());
+        };
+        let z = if ext.starts_with('Z') { 'Z' } else { 'z' };
+        let names = (1..=num_disk).map(|i| format!("{stem}.{z}{i:02}"));
+        let mut volumes = open_sibling_volumes(open_ctx, names)?;
+        if volumes.len() != num_disk as usize {
+            return Err(anyhow::anyhow!(
+                "spanned zip archive has {} volumes, but only {} are found",
+                num_disk + 1,
+                volumes.len() + 1
+            )
+            .into());
+        }
+        volumes.push(VolumeFile::Main(file));
+        Ok(Self::Spanned(Box::new(MultiVolumeFile::new(volumes)?)))
+    }
+    fn as_file(&self) -> &dyn crate::fs_provider::File {
+        match self {
+            Self::File(file) => *file,
+            Self::Spanned(file) | Self::Split(file) => file.as_ref(),
+        }
+    }
+    fn get_disk_count(&self) -> u32 {
+        match self {
+            Self::Spanned(file) => file.get_volume_count() as _,
+            _ => 1,
+        }
+    }
+    // Translates an offset relative to a disk into one within the whole stream
+    fn get_stream_offset(&self, disk: u32, offset: u64) -> anyhow::Result<u64> {
+        let start = match self {
+            Self::Spanned(file) => file.get_volume_start(disk as _),
+            _ if disk == 0 => Some(0),
+            _ => None,
+        };
+        start
+            .and_then(|x| x.checked_add(offset))
+            .ok_or_else(|| anyhow::anyhow!("invalid zip disk number {disk}"))
+    }
+}
+
 pub struct ZipArchive<'a> {
-    file: &'a dyn crate::fs_provider::File,
+    source: ZipSource<'a>,
     file_index: u64,
     eocd: ZipEndOfCentralDirRecord,
     cd: ZipFolderEntry,
+    // Candidates tried in order for encrypted entries
+    passwords: Vec<String>,
+    // NOTE: Entries live here instead of `cd` if the archive is writable
+    edits: Option<write::ZipEdits>,
+    crc_check: ArchiveCrcCheckMode,
+    cache: Option<ArchiveCacheHandle<'a>>,
+    index_size: u64,
+    // Entries which were renamed or skipped while building the tree
+    warnings: Vec<ContainerWarning>,
+    decoder: ZipTextDecoder,
+    // Generated `entries.json` of the info folder, along with the revision of edits
+    info_entries: Mutex<Option<(u64, Arc<[u8]>)>>,
 }
 
-// TODO: Support Zip64
-
+// Returns the record along with its offset
 fn parse_eocd_record(
     file: &dyn crate::fs_provider::File,
     file_len: u64,
-) -> Result<ZipEndOfCentralDirRecord, FileSystemError> {
+) -> Result<(ZipEndOfCentralDirRecord, u64), FileSystemError> {
     const END_SIGNATURE: u32 = 0x06054b50;
     const END_MIN_SIZE: u64 = 22;
     const END_MAX_SIZE: u64 = END_MIN_SIZE + u16::MAX as u64;
@@ -197,32 +389,21 @@ fn parse_eocd_record(
         let size_central_dir = buf.read_u32::<LittleEndian>()?;
         let offset_central_dir = buf.read_u32::<LittleEndian>()?;
         let size_comment = buf.read_u16::<LittleEndian>()?;
-        match buf.len().cmp(&(size_comment as _)) {
-            std::cmp::Ordering::Greater => {
-                let len = buf.len();
-                log::warn!(
-                    "zip: excessive data after EOCD record ({} / {} byte{})",
-                    size_comment,
-                    len,
-                    if len == 1 { "" } else { "s" }
-                );
-                // Shrink buffer
-                buf = &buf[..size_comment as _];
-            }
-            std::cmp::Ordering::Less => {
-                anyhow::bail!("EOCD record has bad comment data");
-            }
-            _ => (),
+        if buf.len() < size_comment as _ {
+            anyhow::bail!("EOCD record has bad comment data");
         }
+        // Shrink buffer, data after the comment is checked by the caller
+        buf = &buf[..size_comment as _];
         let comment = buf.to_owned();
         Ok(ZipEndOfCentralDirRecord {
-            num_disk,
-            num_disk_central_dir_start,
-            total_central_dir_records_this_disk,
-            total_central_dir_records,
-            size_central_dir,
-            offset_central_dir,
+            num_disk: num_disk as _,
+            num_disk_central_dir_start: num_disk_central_dir_start as _,
+            total_central_dir_records_this_disk: total_central_dir_records_this_disk as _,
+            total_central_dir_records: total_central_dir_records as _,
+            size_central_dir: size_central_dir as _,
+            offset_central_dir: offset_central_dir as _,
             comment,
+            is_zip64: false,
         })
     }
 
@@ -232,7 +413,7 @@ fn parse_eocd_record(
     let mut buf = [0; END_MIN_SIZE as _];
     file.read_at_exact(file_len - END_MIN_SIZE, &mut buf)?;
     if let Ok(record) = parse_exact(&buf) {
-        return Ok(record);
+        return Ok((record, file_len - END_MIN_SIZE));
     }
 
     // Try again with full data
@@ -240,20 +421,185 @@ fn parse_eocd_record(
         let len = (file_len - offset_lower_bound) as _;
         let mut full_vec = vec![0; len];
         file.read_at_exact(offset_lower_bound, &mut full_vec[..len])?;
-        full_vec.extend_from_slice(&buf);
         full_vec
     };
-    let mut buf_view = &buf[..];
-    while buf_view.len() > END_MIN_SIZE as usize {
-        if &buf_view[..4] == END_SIGNATURE.to_le_bytes() {
-            if let Ok(record) = parse_exact(buf_view) {
-                return Ok(record);
-            }
+    // NOTE: Scan backwards, as commits of writable archives leave the previous
+    //       record in front of the one they append. Signatures may also show up in
+    //       stored data or comments, so records whose comment does not end the file
+    //       are only taken if no other one does, and if their central directory
+    //       ends before them.
+    let mut fallback = None;
+    for pos in (0..=buf.len() - END_MIN_SIZE as usize).rev() {
+        let buf_view = &buf[pos..];
+        if buf_view[..4] != END_SIGNATURE.to_le_bytes() {
+            continue;
+        }
+        let Ok(record) = parse_exact(buf_view) else {
+            continue;
+        };
+        let offset = offset_lower_bound + pos as u64;
+        let excess_len = buf_view.len() - END_MIN_SIZE as usize - record.comment.len();
+        if excess_len == 0 {
+            return Ok((record, offset));
+        }
+        // NOTE: Values saturated for Zip64 are only known later on
+        let is_saturated = record.offset_central_dir == u32::MAX as u64
+            || record.size_central_dir == u32::MAX as u64;
+        let central_dir_end = record.offset_central_dir + record.size_central_dir;
+        if fallback.is_none() && (is_saturated || central_dir_end <= offset) {
+            fallback = Some((record, offset, excess_len));
         }
-        buf_view = &buf_view[1..];
     }
+    match fallback {
+        Some((record, offset, excess_len)) => {
+            log::warn!(
+                "zip: excessive data after EOCD record ({} byte{})",
+                excess_len,
+                if excess_len == 1 { "" } else { "s" }
+            );
+            Ok((record, offset))
+        }
+        None => Err(anyhow::anyhow!("could not find EOCD record").into()),
+    }
+}
+
+const ZIP64_LOCATOR_SIZE: u64 = 20;
 
-    Err(anyhow::anyhow!("could not find EOCD record").into())
+struct ZipZip64EndOfCentralDirLocator {
+    num_disk_end64: u32,
+    offset_end64: u64,
+    total_disks: u32,
+}
+
+fn parse_zip64_eocd_locator(
+    file: &dyn crate::fs_provider::File,
+    eocd_offset: u64,
+) -> Result<Option<ZipZip64EndOfCentralDirLocator>, FileSystemError> {
+    const LOCATOR_SIGNATURE: u32 = 0x07064b50;
+
+    if eocd_offset < ZIP64_LOCATOR_SIZE {
+        return Ok(None);
+    }
+    let mut buf = [0; ZIP64_LOCATOR_SIZE as _];
+    file.read_at_exact(eocd_offset - ZIP64_LOCATOR_SIZE, &mut buf)?;
+    let mut buf_view = buf.as_slice();
+    if buf_view.read_u32::<LittleEndian>().unwrap() != LOCATOR_SIGNATURE {
+        // Not a Zip64 archive
+        return Ok(None);
+    }
+    Ok(Some(ZipZip64EndOfCentralDirLocator {
+        num_disk_end64: buf_view.read_u32::<LittleEndian>().unwrap(),
+        offset_end64: buf_view.read_u64::<LittleEndian>().unwrap(),
+        total_disks: buf_view.read_u32::<LittleEndian>().unwrap(),
+    }))
+}
+
+// Overrides values saturated in the classic EOCD record with the Zip64 ones, if any,
+// returning the offset of the Zip64 EOCD record
+fn parse_zip64_eocd_record(
+    source: &ZipSource,
+    eocd_offset: u64,
+    eocd: &mut ZipEndOfCentralDirRecord,
+) -> Result<Option<u64>, FileSystemError> {
+    const END64_SIGNATURE: u32 = 0x06064b50;
+    const END64_MIN_SIZE: u64 = 56;
+
+    let file = source.as_file();
+    let Some(locator) = parse_zip64_eocd_locator(file, eocd_offset)? else {
+        return Ok(None);
+    };
+    if locator.total_disks != source.get_disk_count() {
+        return Err(anyhow::anyhow!("zip disk count mismatches with Zip64 locator").into());
+    }
+    let locator_offset = eocd_offset - ZIP64_LOCATOR_SIZE;
+    let read_record_fn = |offset: u64| -> FileSystemResult<Option<[u8; END64_MIN_SIZE as _]>> {
+        if offset.saturating_add(END64_MIN_SIZE) > locator_offset {
+            return Ok(None);
+        }
+        let mut buf = [0; END64_MIN_SIZE as _];
+        file.read_at_exact(offset, &mut buf)?;
+        Ok((buf[..4] == END64_SIGNATURE.to_le_bytes()).then_some(buf))
+    };
+
+    let mut offset_end64 =
+        source.get_stream_offset(locator.num_disk_end64, locator.offset_end64)?;
+    let mut record = read_record_fn(offset_end64)?;
+    // NOTE: Offsets are off if data was prepended to the archive, in which case the
+    //       record is expected right in front of the locator
+    if record.is_none() && !matches!(source, ZipSource::Spanned(_)) {
+        if let Some(offset) = locator_offset.checked_sub(END64_MIN_SIZE) {
+            offset_end64 = offset;
+            record = read_record_fn(offset_end64)?;
+        }
+    }
+    let Some(buf) = record else {
+        return Err(anyhow::anyhow!("invalid Zip64 EOCD record").into());
+    };
+    let mut buf_view = &buf[4..];
+    let _size_record = buf_view.read_u64::<LittleEndian>().unwrap();
+    let _made_by_ver = buf_view.read_u16::<LittleEndian>().unwrap();
+    let _min_extract_ver = buf_view.read_u16::<LittleEndian>().unwrap();
+    eocd.num_disk = buf_view.read_u32::<LittleEndian>().unwrap();
+    eocd.num_disk_central_dir_start = buf_view.read_u32::<LittleEndian>().unwrap();
+    eocd.total_central_dir_records_this_disk = buf_view.read_u64::<LittleEndian>().unwrap();
+    eocd.total_central_dir_records = buf_view.read_u64::<LittleEndian>().unwrap();
+    eocd.size_central_dir = buf_view.read_u64::<LittleEndian>().unwrap();
+    eocd.offset_central_dir = buf_view.read_u64::<LittleEndian>().unwrap();
+    eocd.is_zip64 = true;
+    Ok(Some(offset_end64))
+}
+
+// Returns the size of data prepended to the archive without adjusting its offsets
+// (e.g. SFX stubs or junk), as the central directory should end at `cd_end`
+fn get_prefix_size(
+    source: &ZipSource,
+    eocd: &ZipEndOfCentralDirRecord,
+    cd_end: u64,
+) -> FileSystemResult<u64> {
+    const CD_HEADER_SIGNATURE: u32 = 0x02014b50;
+
+    // NOTE: Volumes of spanned archives are never prefixed
+    if matches!(source, ZipSource::Spanned(_)) {
+        return Ok(0);
+    }
+    let expected_cd_end = eocd
+        .offset_central_dir
+        .saturating_add(eocd.size_central_dir);
+    let prefix = match cd_end.checked_sub(expected_cd_end) {
+        Some(prefix) if prefix > 0 => prefix,
+        _ => return Ok(0),
+    };
+    // Make sure the central directory really starts there, in case there is just
+    // junk in front of the EOCD record
+    if eocd.size_central_dir > 0 {
+        let mut buf = [0; 4];
+        source
+            .as_file()
+            .read_at_exact(eocd.offset_central_dir + prefix, &mut buf)?;
+        if u32::from_le_bytes(buf) != CD_HEADER_SIGNATURE {
+            return Ok(0);
+        }
+    }
+    Ok(prefix)
+}
+
+// Checks whether the file looks like a zip archive, which may have data in front
+pub(super) fn sniff(file: &dyn crate::fs_provider::File) -> bool {
+    const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+    const SPANNING_SIGNATURE: u32 = 0x08074b50;
+
+    let mut buf = [0; 4];
+    if file.read_at_exact(0, &mut buf).is_ok()
+        && matches!(
+            u32::from_le_bytes(buf),
+            LOCAL_HEADER_SIGNATURE | SPANNING_SIGNATURE
+        )
+    {
+        return true;
+    }
+    file.get_stat()
+        .and_then(|stat| parse_eocd_record(file, stat.size))
+        .is_ok()
 }
 
 fn parse_central_dir_record_list(
@@ -277,21 +623,31 @@ fn parse_central_dir_record_list(
         let last_modify_time = file.read_u16::<LittleEndian>()?;
         let last_modify_date = file.read_u16::<LittleEndian>()?;
         let uncompressed_data_crc32 = file.read_u32::<LittleEndian>()?;
-        let compressed_size = file.read_u32::<LittleEndian>()?;
-        let uncompressed_size = file.read_u32::<LittleEndian>()?;
+        let mut compressed_size = file.read_u32::<LittleEndian>()? as u64;
+        let mut uncompressed_size = file.read_u32::<LittleEndian>()? as u64;
         let len_file_name = file.read_u16::<LittleEndian>()?;
         let len_extra_data = file.read_u16::<LittleEndian>()?;
         let len_comment = file.read_u16::<LittleEndian>()?;
-        let num_disk_start = file.read_u16::<LittleEndian>()?;
+        let mut num_disk_start = file.read_u16::<LittleEndian>()? as u32;
         let internal_file_attributes = file.read_u16::<LittleEndian>()?;
         let external_file_attributes = file.read_u32::<LittleEndian>()?;
-        let local_file_header_offset = file.read_u32::<LittleEndian>()?;
+        let mut local_file_header_offset = file.read_u32::<LittleEndian>()? as u64;
         let mut file_name = vec![0; len_file_name as _];
         file.read_exact(&mut file_name)?;
         let mut extra_data = vec![0; len_extra_data as _];
         file.read_exact(&mut extra_data)?;
         let mut comment = vec![0; len_comment as _];
         file.read_exact(&mut comment)?;
+        // NOTE: A broken Zip64 field only affects this entry, which is skipped later
+        let zip64_error = apply_zip64_extra_field(
+            &extra_data,
+            &mut uncompressed_size,
+            &mut compressed_size,
+            Some(&mut local_file_header_offset),
+            Some(&mut num_disk_start),
+        )
+        .err()
+        .map(|e| e.to_string());
 
         Ok(ZipCentralDirRecord {
             made_by_ver,
@@ -310,10 +666,13 @@ fn parse_central_dir_record_list(
             internal_file_attributes,
             external_file_attributes,
             local_file_header_offset,
+            zip64_error,
         })
     }
 
-    let mut records = Vec::with_capacity(eocd.total_central_dir_records as _);
+    // NOTE: Do not trust the record count blindly when reserving memory
+    let max_records = eocd.size_central_dir / CD_HEADER_SIZE as u64;
+    let mut records = Vec::with_capacity(eocd.total_central_dir_records.min(max_records) as _);
     for _ in 0..eocd.total_central_dir_records {
         records.push(parse_one(&mut file)?);
     }
@@ -333,14 +692,21 @@ fn parse_local_file_record(file: &mut impl std::io::Read) -> anyhow::Result<ZipL
     let last_modify_time = file.read_u16::<LittleEndian>()?;
     let last_modify_date = file.read_u16::<LittleEndian>()?;
     let uncompressed_data_crc32 = file.read_u32::<LittleEndian>()?;
-    let compressed_size = file.read_u32::<LittleEndian>()?;
-    let uncompressed_size = file.read_u32::<LittleEndian>()?;
+    let mut compressed_size = file.read_u32::<LittleEndian>()? as u64;
+    let mut uncompressed_size = file.read_u32::<LittleEndian>()? as u64;
     let len_file_name = file.read_u16::<LittleEndian>()?;
     let len_extra_data = file.read_u16::<LittleEndian>()?;
     let mut file_name = vec![0; len_file_name as _];
     file.read_exact(&mut file_name)?;
     let mut extra_data = vec![0; len_extra_data as _];
     file.read_exact(&mut extra_data)?;
+    apply_zip64_extra_field(
+        &extra_data,
+        &mut uncompressed_size,
+        &mut compressed_size,
+        None,
+        None,
+    )?;
 
     Ok(ZipLocalFileRecord {
         min_extract_ver,
@@ -389,6 +755,14 @@ fn skip_local_file_record_with_central_no_verify(
 
 const ZIP_LOCAL_EXTRA_HID_ZIP64: u16 = 0x0001;
 const ZIP_LOCAL_EXTRA_HID_NTFS: u16 = 0x000a;
+const ZIP_LOCAL_EXTRA_HID_EXTENDED_TIMESTAMP: u16 = 0x5455;
+// Info-ZIP Unix extra of old, with times in front of owner ids
+const ZIP_LOCAL_EXTRA_HID_INFOZIP_UNIX_OLD: u16 = 0x5855;
+// NOTE: The current Info-ZIP Unix extra (0x7875) only holds owner ids, which have no
+//       counterpart in FileStatInfo or Windows, so it is not parsed. Records which are
+//       only updated on commit keep it as is.
+const ZIP_LOCAL_EXTRA_HID_UNICODE_PATH: u16 = 0x7075;
+const ZIP_LOCAL_EXTRA_HID_AES: u16 = 0x9901;
 
 struct ZipLocalFileNtfsExtraField {
     last_modify_time: SystemTime,
@@ -396,10 +770,74 @@ struct ZipLocalFileNtfsExtraField {
     creation_time: SystemTime,
 }
 
+// Unix times, where central directory records usually only keep the modify time
+struct ZipLocalFileTimestampExtraField {
+    last_modify_time: Option<SystemTime>,
+    last_access_time: Option<SystemTime>,
+    creation_time: Option<SystemTime>,
+}
+
+struct ZipLocalFileUnicodePathExtraField {
+    // CRC-32 of the header name, which must match for the field to be used
+    name_crc32: u32,
+    name: Vec<u8>,
+}
+
 enum ZipLocalFileExtraField {
-    // TODO: More ZipLocalFileExtraField variants
-    // Zip64(),
     NTFS(ZipLocalFileNtfsExtraField),
+    Timestamp(ZipLocalFileTimestampExtraField),
+    UnicodePath(ZipLocalFileUnicodePathExtraField),
+}
+
+fn find_extra_field(mut extra: &[u8], header_id: u16) -> anyhow::Result<Option<&[u8]>> {
+    while !extra.is_empty() {
+        let cur_header_id = extra.read_u16::<LittleEndian>()?;
+        let data_size = extra.read_u16::<LittleEndian>()? as usize;
+        if extra.len() < data_size {
+            anyhow::bail!("extra field exceeds extra data");
+        }
+        let (data, rest) = extra.split_at(data_size);
+        if cur_header_id == header_id {
+            return Ok(Some(data));
+        }
+        extra = rest;
+    }
+    Ok(None)
+}
+
+// NOTE: A field is only present in the Zip64 extra field if its header
+//       counterpart is saturated, and the order of fields is fixed
+fn apply_zip64_extra_field(
+    extra: &[u8],
+    uncompressed_size: &mut u64,
+    compressed_size: &mut u64,
+    local_file_header_offset: Option<&mut u64>,
+    num_disk_start: Option<&mut u32>,
+) -> anyhow::Result<()> {
+    let Some(mut data) = find_extra_field(extra, ZIP_LOCAL_EXTRA_HID_ZIP64)? else {
+        return Ok(());
+    };
+    let mut read_u64_fn = |value: &mut u64| -> anyhow::Result<()> {
+        if *value == u32::MAX as u64 {
+            *value = data
+                .read_u64::<LittleEndian>()
+                .map_err(|_| anyhow::anyhow!("Zip64 extra field too small"))?;
+        }
+        Ok(())
+    };
+    read_u64_fn(uncompressed_size)?;
+    read_u64_fn(compressed_size)?;
+    if let Some(local_file_header_offset) = local_file_header_offset {
+        read_u64_fn(local_file_header_offset)?;
+    }
+    if let Some(num_disk_start) = num_disk_start {
+        if *num_disk_start == u16::MAX as u32 {
+            *num_disk_start = data
+                .read_u32::<LittleEndian>()
+                .map_err(|_| anyhow::anyhow!("Zip64 extra field too small"))?;
+        }
+    }
+    Ok(())
 }
 
 fn parse_local_file_extra_data(mut extra: &[u8]) -> anyhow::Result<Vec<ZipLocalFileExtraField>> {
@@ -435,24 +873,68 @@ fn parse_local_file_extra_data(mut extra: &[u8]) -> anyhow::Result<Vec<ZipLocalF
         Ok(result)
     }
 
-    let mut result = Vec::new();
+    fn parse_timestamp(mut extra: &[u8]) -> std::io::Result<ZipLocalFileTimestampExtraField> {
+        let flags = extra.read_u8()?;
+        // NOTE: Flags tell which times the local field has, the central one may
+        //       stop after the modify time
+        let mut read_time_fn = |flag: u8| {
+            if flags & flag == 0 {
+                return None;
+            }
+            extra
+                .read_i32::<LittleEndian>()
+                .ok()
+                .map(unix_time_to_system_time)
+        };
+        Ok(ZipLocalFileTimestampExtraField {
+            last_modify_time: read_time_fn(0x1),
+            last_access_time: read_time_fn(0x2),
+            creation_time: read_time_fn(0x4),
+        })
+    }
+    fn parse_infozip_unix_old(
+        mut extra: &[u8],
+    ) -> std::io::Result<ZipLocalFileTimestampExtraField> {
+        let last_access_time = unix_time_to_system_time(extra.read_i32::<LittleEndian>()?);
+        let last_modify_time = unix_time_to_system_time(extra.read_i32::<LittleEndian>()?);
+        Ok(ZipLocalFileTimestampExtraField {
+            last_modify_time: Some(last_modify_time),
+            last_access_time: Some(last_access_time),
+            creation_time: None,
+        })
+    }
+    fn parse_unicode_path(mut extra: &[u8]) -> anyhow::Result<ZipLocalFileUnicodePathExtraField> {
+        let version = extra.read_u8()?;
+        if version != 1 {
+            anyhow::bail!("unsupported Unicode path extra field version {version}");
+        }
+        Ok(ZipLocalFileUnicodePathExtraField {
+            name_crc32: extra.read_u32::<LittleEndian>()?,
+            name: extra.to_vec(),
+        })
+    }
 
-    // TODO: Do not allocate, read subslice instead
-    let mut buf = Vec::with_capacity(u16::MAX as _);
+    let mut result = Vec::new();
 
     while !extra.is_empty() {
         let header_id = extra.read_u16::<LittleEndian>()?;
-        let data_size = extra.read_u16::<LittleEndian>()?;
-        // SAFETY: &[u8].read() does not read from buffer
-        unsafe {
-            extra.read_exact(std::slice::from_raw_parts_mut(
-                buf.as_mut_ptr(),
-                data_size as _,
-            ))?;
-            buf.set_len(data_size as _);
+        let data_size = extra.read_u16::<LittleEndian>()? as usize;
+        if extra.len() < data_size {
+            anyhow::bail!("extra field exceeds extra data");
         }
+        let (buf, rest) = extra.split_at(data_size);
+        extra = rest;
         result.push(match header_id {
-            ZIP_LOCAL_EXTRA_HID_NTFS => ZipLocalFileExtraField::NTFS(parse_ntfs(&buf)?),
+            ZIP_LOCAL_EXTRA_HID_NTFS => ZipLocalFileExtraField::NTFS(parse_ntfs(buf)?),
+            ZIP_LOCAL_EXTRA_HID_EXTENDED_TIMESTAMP => {
+                ZipLocalFileExtraField::Timestamp(parse_timestamp(buf)?)
+            }
+            ZIP_LOCAL_EXTRA_HID_INFOZIP_UNIX_OLD => {
+                ZipLocalFileExtraField::Timestamp(parse_infozip_unix_old(buf)?)
+            }
+            ZIP_LOCAL_EXTRA_HID_UNICODE_PATH => {
+                ZipLocalFileExtraField::UnicodePath(parse_unicode_path(buf)?)
+            }
             // Unrecognized type, skip
             _ => continue,
         })
@@ -461,199 +943,543 @@ fn parse_local_file_extra_data(mut extra: &[u8]) -> anyhow::Result<Vec<ZipLocalF
     Ok(result)
 }
 
+fn unix_time_to_system_time(time: i32) -> SystemTime {
+    let duration = std::time::Duration::from_secs(time.unsigned_abs() as _);
+    if time >= 0 {
+        SystemTime::UNIX_EPOCH + duration
+    } else {
+        SystemTime::UNIX_EPOCH
+            .checked_sub(duration)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+}
+
+fn get_compression_method_name(method: u16) -> &'static str {
+    match method {
+        ZIP_COMPRESSION_STORE => "store",
+        ZIP_COMPRESSION_DEFLATE => "deflate",
+        ZIP_COMPRESSION_DEFLATE64 => "deflate64",
+        ZIP_COMPRESSION_BZIP2 => "bzip2",
+        ZIP_COMPRESSION_LZMA => "lzma",
+        ZIP_COMPRESSION_ZSTD => "zstd",
+        ZIP_COMPRESSION_AES => "aes",
+        _ => "unknown",
+    }
+}
+
+fn dos_time_to_system_time(date: u16, time: u16) -> SystemTime {
+    use bitstream_io::BitRead;
+    let date = date.to_le_bytes();
+    let time = time.to_le_bytes();
+    let mut date_reader = bitstream_io::BitReader::endian(&date[..], bitstream_io::LittleEndian);
+    let mut time_reader = bitstream_io::BitReader::endian(&time[..], bitstream_io::LittleEndian);
+    let day = date_reader.read::<u8>(5).unwrap();
+    let month = date_reader.read::<u8>(4).unwrap();
+    let year = date_reader.read::<u16>(7).unwrap() + 1980;
+    let second = time_reader.read::<u8>(5).unwrap() * 2;
+    let minute = time_reader.read::<u8>(6).unwrap();
+    let hour = time_reader.read::<u8>(5).unwrap();
+    // NOTE: DOS time is zone-unaware, so we use the local timezone
+    //       as our best-effort guess
+    use chrono::TimeZone;
+    chrono::Local
+        .with_ymd_and_hms(
+            year as _,
+            month as _,
+            day as _,
+            hour as _,
+            minute as _,
+            second as _,
+        )
+        .single()
+        .map(|t| t.into())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+// Unix mode of records made on Unix-like systems, if any
+fn get_unix_mode(record: &ZipCentralDirRecord) -> Option<u32> {
+    let host = (record.made_by_ver >> 8) as u8;
+    let mode = record.external_file_attributes >> 16;
+    (matches!(host, ZIP_HOST_UNIX | ZIP_HOST_OSX) && mode != 0).then_some(mode)
+}
+
+// Whether a record stands for a folder, even without a trailing slash
+fn is_dir_record(record: &ZipCentralDirRecord) -> bool {
+    match get_unix_mode(record) {
+        Some(mode) => mode & UNIX_S_IFMT == UNIX_S_IFDIR,
+        None => {
+            record.external_file_attributes & ZIP_DOS_ATTRIBUTE_DIRECTORY != 0
+                && record.uncompressed_size == 0
+        }
+    }
+}
+
+// Takes the most precise times available, in order of NTFS, Unix and MS-DOS ones
+fn get_record_stat(
+    record: &ZipCentralDirRecord,
+    extra_fields: &[ZipLocalFileExtraField],
+) -> ZipEntryStat {
+    let mut stat = ZipEntryStat::new(dos_time_to_system_time(
+        record.last_modify_date,
+        record.last_modify_time,
+    ));
+    for field in extra_fields {
+        if let ZipLocalFileExtraField::Timestamp(field) = field {
+            stat.last_write_time = field.last_modify_time.unwrap_or(stat.last_write_time);
+            stat.last_access_time = field.last_access_time.unwrap_or(stat.last_write_time);
+            stat.creation_time = field.creation_time.unwrap_or(stat.last_write_time);
+        }
+    }
+    for field in extra_fields {
+        if let ZipLocalFileExtraField::NTFS(field) = field {
+            stat.last_write_time = field.last_modify_time;
+            stat.last_access_time = field.last_access_time;
+            stat.creation_time = field.creation_time;
+        }
+    }
+
+    let mut attributes = FileAttributes::empty();
+    match get_unix_mode(record) {
+        // NOTE: Symbolic links always have all permission bits set
+        Some(mode) => {
+            if mode & UNIX_WRITE_BITS == 0 {
+                attributes |= FileAttributes::Readonly;
+            }
+        }
+        None => {
+            let dos_attributes = record.external_file_attributes;
+            if dos_attributes & ZIP_DOS_ATTRIBUTE_READONLY != 0 {
+                attributes |= FileAttributes::Readonly;
+            }
+            if dos_attributes & ZIP_DOS_ATTRIBUTE_HIDDEN != 0 {
+                attributes |= FileAttributes::Hidden;
+            }
+        }
+    }
+    stat.attributes = attributes.bits();
+    stat
+}
+
+// Checksums the end of central directory record and the start of the central
+// directory, which change with almost any modification of the archive
+fn hash_headers(
+    file: &dyn crate::fs_provider::File,
+    eocd: &ZipEndOfCentralDirRecord,
+    eocd_offset: u64,
+    file_len: u64,
+) -> FileSystemResult<u32> {
+    const MAX_HASHED_CD_SIZE: u64 = 64 * 1024;
+    let mut crc = flate2::Crc::new();
+    let mut buf = vec![0; file_len.saturating_sub(eocd_offset) as usize];
+    file.read_at_exact(eocd_offset, &mut buf)?;
+    crc.update(&buf);
+    let mut buf = vec![0; eocd.size_central_dir.min(MAX_HASHED_CD_SIZE) as usize];
+    file.read_at_exact(eocd.offset_central_dir, &mut buf)?;
+    crc.update(&buf);
+    Ok(crc.sum())
+}
+
 impl<'a> ZipArchive<'a> {
     pub(super) fn new(
         open_ctx: ArchiveHandlerOpenContext<'a>,
         non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+        passwords: Vec<String>,
+        write_config: Option<&ArchiveWriteConfig>,
+        crc_check: ArchiveCrcCheckMode,
     ) -> FileSystemResult<Self> {
-        use std::collections::btree_map::Entry::*;
-
         fn check_end_of_central_dir_record(
             record: &ZipEndOfCentralDirRecord,
+            disk_count: u32,
         ) -> anyhow::Result<()> {
-            if record.num_disk != record.num_disk_central_dir_start {
-                anyhow::bail!("multi-disk zip archive not supported");
+            if record.num_disk.checked_add(1) != Some(disk_count) {
+                anyhow::bail!("zip archive expects {} disks", record.num_disk as u64 + 1);
             }
-            if record.total_central_dir_records_this_disk != record.total_central_dir_records {
-                anyhow::bail!("multi-disk zip archive not supported");
+            if record.num_disk_central_dir_start > record.num_disk {
+                anyhow::bail!("invalid disk number for central directory");
             }
-            if record.num_disk == 0xffff {
-                anyhow::bail!("Zip64 archive not supported")
+            // NOTE: The central directory may span disks in a spanned archive
+            if disk_count == 1
+                && record.total_central_dir_records_this_disk != record.total_central_dir_records
+            {
+                anyhow::bail!("inconsistent record count in EOCD record");
             }
             Ok(())
         }
 
+        fn join_path(parent: &str, name: &str) -> String {
+            if parent.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{parent}/{name}")
+            }
+        }
+
         fn build_file_tree(
             cd: Vec<ZipCentralDirRecord>,
             root_index: u64,
-            non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+            decoder: &ZipTextDecoder,
             root_modify_time: SystemTime,
-        ) -> anyhow::Result<ZipFolderEntry> {
-            let mut cd_tree = BTreeMap::new();
-
-            let encoding = match &non_unicode_compat.encoding_override {
-                ArchiveNonUnicodeEncoding::AutoDetect => {
-                    let mut detector = chardetng::EncodingDetector::new();
-                    // NOTE: Some archives are mixing UTF-8 and non-UTF-8 entries together,
-                    //       so we need to filter out UTF-8 ones
-                    for record in cd
-                        .iter()
-                        .filter(|x| (x.general_purpose_bitflag & ZIP_GPFLAGS_EFS) == 0)
-                    {
-                        detector.feed(&record.file_name, false);
-                    }
-                    detector.feed(&[], true);
-                    let encoding = detector.guess(None, false);
-
-                    // log::debug!("Guessing encoding: picked {encoding:?}");
-
-                    ArchiveNonUnicodeEncoding::Specified(encoding.name().to_owned())
-                }
-                x => x.clone(),
+        ) -> (ZipFolderEntry, Vec<ZipCentralDirRecord>, Vec<ContainerWarning>) {
+            let mut root = ZipFolderEntry {
+                children: BTreeMap::new(),
+                index: root_index,
+                stat: ZipEntryStat::new(root_modify_time),
+                record: None,
+            };
+            let mut unplaced = Vec::new();
+            let mut warnings = Vec::new();
+            let mut add_warning = |path: &str, msg: String| {
+                log::debug!("zip: entry `{path}` {msg}");
+                warnings.push(ContainerWarning {
+                    path: path.to_owned(),
+                    msg,
+                });
             };
-            let converter = super::EncodingConverter::new(&encoding);
 
             // NOTE: Used for "unique" index generation
             let mut counter: u64 = 0;
 
             for record in cd {
-                let has_utf8_flag = (record.general_purpose_bitflag & ZIP_GPFLAGS_EFS) != 0;
-                let path = if has_utf8_flag && !non_unicode_compat.ignore_utf8_flags {
-                    String::from_utf8_lossy(&record.file_name)
-                } else {
-                    if non_unicode_compat.allow_utf8_mix {
-                        simdutf8::basic::from_utf8(&record.file_name)
-                            .map(|x| Cow::Borrowed(x))
-                            .unwrap_or_else(|_| converter.convert(&record.file_name))
-                    } else {
-                        converter.convert(&record.file_name)
+                // NOTE: Broken extra fields are not worth failing the whole archive
+                let extra_fields =
+                    parse_local_file_extra_data(&record.extra_data).unwrap_or_default();
+                // Info-ZIP Unicode path is only valid while the header name is unchanged
+                let unicode_path = extra_fields.iter().find_map(|x| match x {
+                    ZipLocalFileExtraField::UnicodePath(field) => {
+                        let mut crc = flate2::Crc::new();
+                        crc.update(&record.file_name);
+                        (crc.sum() == field.name_crc32)
+                            .then(|| simdutf8::basic::from_utf8(&field.name).ok())
+                            .flatten()
                     }
+                    _ => None,
+                });
+                let has_utf8_flag = (record.general_purpose_bitflag & ZIP_GPFLAGS_EFS) != 0;
+                let path = match unicode_path {
+                    Some(path) => Cow::Borrowed(path),
+                    None => decoder.decode(&record.file_name, has_utf8_flag),
                 };
 
                 // log::debug!("Filename: {:?} -> `{path}`", &record.file_name);
 
-                if path.contains('\0') || path.starts_with("/") {
-                    // Bad file name
+                // Directory records only create the folder (with its parents)
+                // NOTE: Some archivers leave out the trailing slash, so also check the
+                //       attributes
+                let is_dir_record = path.ends_with(['/', '\\']) || is_dir_record(&record);
+                if let Some(err) = record.zip64_error.as_ref().filter(|_| !is_dir_record) {
+                    add_warning(
+                        &path,
+                        format!("is skipped as it has a broken Zip64 field: {err}"),
+                    );
+                    unplaced.push(record);
                     continue;
                 }
-                if path.contains("../") || path.contains("./") {
-                    // TODO: Sanitize path
-                    continue;
+                let stat = get_record_stat(&record, &extra_fields);
+                if !is_dir_record
+                    && get_unix_mode(&record).is_some_and(|x| x & UNIX_S_IFMT == UNIX_S_IFLNK)
+                {
+                    // NOTE: Symbolic links can't be represented, so they are kept as
+                    //       files holding the link target, like Info-ZIP does on Windows
+                    log::debug!("zip: keeping symbolic link `{path}` as a file");
                 }
-                if path.ends_with('/') {
-                    // TODO: Handle directory files (S_IFDIR?)
+
+                // NOTE: Paths are normalized, so that entries never escape the archive
+                let components = split_entry_path(&path);
+                let Some((filename, parents)) = components.split_last() else {
+                    add_warning(&path, "is skipped as its path is empty".to_owned());
+                    unplaced.push(record);
                     continue;
+                };
+                let normalized_path = components.join("/");
+                if normalized_path != path.trim_end_matches('/') {
+                    add_warning(&path, format!("is normalized to `{normalized_path}`"));
                 }
 
                 // Insert file
-                // SAFETY: Path is checked to contain no nul bytes
-                let path = unsafe { SegPath::new_unchecked(&path, PathDelimiter::Slash) };
-                let mut cur_dir_children = &mut cd_tree;
-                let mut iter = path.iter().peekable();
-                let mut filename = "";
-                while let Some(path) = iter.next() {
-                    if iter.peek().is_none() {
-                        filename = path;
-                        break;
+                let folders = if is_dir_record {
+                    &components[..]
+                } else {
+                    parents
+                };
+                let mut cur_dir = &mut root;
+                let mut cur_path = String::new();
+                for name in folders {
+                    let key = CaselessString::new(name.to_string());
+                    if let Some(ZipEntry::File(_)) = cur_dir.children.get(&key) {
+                        // NOTE: Folders may hold many entries, so rather move the file
+                        //       out of the way
+                        let (old_key, file) = cur_dir.children.remove_entry(&key).unwrap();
+                        let new_name = make_unique_name(old_key.as_str(), |x| {
+                            cur_dir.children.contains_key(CaselessStr::new(x))
+                        });
+                        add_warning(
+                            &join_path(&cur_path, old_key.as_str()),
+                            format!("is renamed to `{new_name}` as a folder has the same name"),
+                        );
+                        cur_dir.children.insert(CaselessString::new(new_name), file);
                     }
-
-                    counter += 1;
-
-                    let key = CaselessString::new(path.to_owned());
-                    cur_dir_children = match cur_dir_children.entry(key) {
-                        Occupied(e) => match e.into_mut() {
-                            ZipEntry::File(_) => {
-                                anyhow::bail!("file name collides with folder in zip archive")
-                            }
-                            ZipEntry::Folder(e) => &mut e.children,
-                        },
-                        Vacant(e) => match e.insert(ZipEntry::Folder(ZipFolderEntry {
+                    let folder = cur_dir.children.entry(key).or_insert_with(|| {
+                        counter += 1;
+                        ZipEntry::Folder(ZipFolderEntry {
                             children: BTreeMap::new(),
                             index: calculate_hash(&(root_index, counter)),
-                            // TODO: Change to correct time
-                            dos_modify_time: SystemTime::UNIX_EPOCH,
-                        })) {
-                            ZipEntry::Folder(e) => &mut e.children,
-                            _ => unreachable!(),
-                        },
+                            // NOTE: Folders without a record of their own have no times
+                            stat: ZipEntryStat::new(SystemTime::UNIX_EPOCH),
+                            record: None,
+                        })
+                    });
+                    cur_dir = match folder {
+                        ZipEntry::Folder(e) => e,
+                        _ => unreachable!(),
                     };
+                    cur_path = join_path(&cur_path, name);
                 }
-                if filename == "" {
-                    // Bad file name
+                if is_dir_record {
+                    // The directory record describes the folder itself
+                    cur_dir.stat = stat;
+                    // NOTE: Records of duplicate folders (and ones with unknown offsets)
+                    //       are still kept as they are
+                    if record.zip64_error.is_some() {
+                        unplaced.push(record);
+                    } else if let Some(old) = cur_dir.record.replace(record) {
+                        unplaced.push(old);
+                    }
                     continue;
                 }
-                let key = CaselessString::new(filename.to_owned());
-                let dos_modify_time = {
-                    use bitstream_io::BitRead;
-                    let date = record.last_modify_date.to_le_bytes();
-                    let time = record.last_modify_time.to_le_bytes();
-                    let mut date_reader =
-                        bitstream_io::BitReader::endian(&date[..], bitstream_io::LittleEndian);
-                    let mut time_reader =
-                        bitstream_io::BitReader::endian(&time[..], bitstream_io::LittleEndian);
-                    let day = date_reader.read::<u8>(5).unwrap();
-                    let month = date_reader.read::<u8>(4).unwrap();
-                    let year = date_reader.read::<u16>(7).unwrap() + 1980;
-                    let second = time_reader.read::<u8>(5).unwrap() * 2;
-                    let minute = time_reader.read::<u8>(6).unwrap();
-                    let hour = time_reader.read::<u8>(5).unwrap();
-                    // NOTE: DOS time is zone-unaware, so we use the local timezone
-                    //       as our best-effort guess
-                    use chrono::TimeZone;
-                    chrono::Local
-                        .with_ymd_and_hms(
-                            year as _,
-                            month as _,
-                            day as _,
-                            hour as _,
-                            minute as _,
-                            second as _,
-                        )
-                        .single()
-                        .map(|t| t.into())
-                        .unwrap_or(SystemTime::UNIX_EPOCH)
-                };
-                match cur_dir_children.entry(key) {
-                    Occupied(_) => anyhow::bail!("file name collides in zip archive"),
-                    Vacant(e) => e.insert(ZipEntry::File(ZipFileEntry {
+                let mut key = CaselessString::new(filename.to_string());
+                if cur_dir.children.contains_key(&key) {
+                    let new_name = make_unique_name(filename, |x| {
+                        cur_dir.children.contains_key(CaselessStr::new(x))
+                    });
+                    add_warning(
+                        &path,
+                        format!(
+                            "is renamed to `{}` as its name collides",
+                            join_path(&cur_path, &new_name)
+                        ),
+                    );
+                    key = CaselessString::new(new_name);
+                }
+                cur_dir.children.insert(
+                    key,
+                    ZipEntry::File(ZipFileEntry {
                         index: calculate_hash(&(root_index, record.uncompressed_data_crc32)),
                         data: record,
-                        dos_modify_time,
-                    })),
-                };
+                        stat,
+                    }),
+                );
             }
 
-            Ok(ZipFolderEntry {
-                children: cd_tree,
-                index: root_index,
-                dos_modify_time: root_modify_time,
+            (root, unplaced, warnings)
+        }
+
+        // Parses the central directory and builds the file tree from it
+        fn parse_index(
+            open_ctx: ArchiveHandlerOpenContext,
+            source: &ZipSource,
+            eocd: &ZipEndOfCentralDirRecord,
+            prefix: u64,
+            file_stat: &FileStatInfo,
+            non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+        ) -> FileSystemResult<(ZipIndex, ZipTextDecoder)> {
+            let file = source.as_file();
+            let mut cd = parse_central_dir_record_list(file, eocd)?;
+            // NOTE: From now on, local header offsets are within the whole stream
+            for record in cd.iter_mut().filter(|x| x.zip64_error.is_none()) {
+                record.local_file_header_offset = source
+                    .get_stream_offset(record.num_disk_start, record.local_file_header_offset)?
+                    + prefix;
+            }
+
+            // NOTE: Data in front of the first local record (e.g. SFX stubs) is kept
+            //       when writing
+            let data_start = cd
+                .iter()
+                .filter(|x| x.zip64_error.is_none())
+                .map(|x| x.local_file_header_offset)
+                .min()
+                .unwrap_or(eocd.offset_central_dir);
+
+            // NOTE: Rough estimate, names are counted twice as they also key the tree
+            let index_size = cd
+                .iter()
+                .map(|x| {
+                    (std::mem::size_of::<ZipCentralDirRecord>()
+                        + x.file_name.len() * 2
+                        + x.extra_data.len()
+                        + x.comment.len()) as u64
+                })
+                .sum();
+
+            // NOTE: Some archives are mixing UTF-8 and non-UTF-8 entries together,
+            //       so we need to filter out UTF-8 ones
+            let decoder = make_text_decoder(open_ctx, non_unicode_compat, cd.iter())?;
+            let (cd_tree, unplaced, warnings) =
+                build_file_tree(cd, file_stat.index, &decoder, file_stat.last_write_time);
+            let index = ZipIndex {
+                cd: cd_tree,
+                data_start,
+                index_size,
+                unplaced,
+                warnings,
+            };
+            Ok((index, decoder))
+        }
+
+        // NOTE: Some archives are mixing UTF-8 and non-UTF-8 entries together,
+        //       so only non-UTF-8 names are used to detect the encoding
+        fn make_text_decoder<'r>(
+            open_ctx: ArchiveHandlerOpenContext,
+            non_unicode_compat: &ArchiveNonUnicodeCompatConfig,
+            records: impl Iterator<Item = &'r ZipCentralDirRecord>,
+        ) -> anyhow::Result<ZipTextDecoder> {
+            let converter = open_ctx.get_name_converter(
+                &non_unicode_compat.encoding_override,
+                records
+                    .filter(|x| (x.general_purpose_bitflag & ZIP_GPFLAGS_EFS) == 0)
+                    .map(|x| &x.file_name[..]),
+            )?;
+            Ok(ZipTextDecoder {
+                converter,
+                ignore_utf8_flags: non_unicode_compat.ignore_utf8_flags,
+                allow_utf8_mix: non_unicode_compat.allow_utf8_mix,
             })
         }
 
+        fn collect_tree_records<'r>(
+            folder: &'r ZipFolderEntry,
+            records: &mut Vec<&'r ZipCentralDirRecord>,
+        ) {
+            records.extend(&folder.record);
+            for entry in folder.children.values() {
+                match entry {
+                    ZipEntry::Folder(e) => collect_tree_records(e, records),
+                    ZipEntry::File(e) => records.push(&e.data),
+                }
+            }
+        }
+
         // We only handle files instead of folders
         if open_ctx.get_is_dir() {
             return Err(FileSystemError::FileCorruptError);
         }
 
-        let file = open_ctx.get_file();
+        let source = ZipSource::open(open_ctx)?;
+        let file = source.as_file();
         let file_stat = file.get_stat()?;
-        let eocd = parse_eocd_record(file, file_stat.size)?;
-        check_end_of_central_dir_record(&eocd)?;
+        let (mut eocd, eocd_offset) = parse_eocd_record(file, file_stat.size)?;
+        let end64_offset = parse_zip64_eocd_record(&source, eocd_offset, &mut eocd)?;
+        check_end_of_central_dir_record(&eocd, source.get_disk_count())?;
+        eocd.offset_central_dir =
+            source.get_stream_offset(eocd.num_disk_central_dir_start, eocd.offset_central_dir)?;
+        // NOTE: From now on, the central directory offset accounts for any prefix
+        let prefix = get_prefix_size(&source, &eocd, end64_offset.unwrap_or(eocd_offset))?;
+        if prefix > 0 {
+            log::debug!(
+                "zip: `{}` has {prefix} bytes of data in front",
+                open_ctx.get_path()
+            );
+            eocd.offset_central_dir += prefix;
+        }
 
-        let cd = parse_central_dir_record_list(file, &eocd)?;
+        // Large archives may have their parsed index cached on disk
+        let index_cache = open_ctx
+            .get_index_cache()
+            .filter(|x| x.should_cache(eocd.total_central_dir_records));
+        let index_key = match index_cache {
+            Some(_) => Some(ArchiveIndexKey {
+                path: open_ctx.get_path().to_owned(),
+                size: file_stat.size,
+                last_write_time: file_stat.last_write_time,
+                file_index: file_stat.index,
+                header_hash: hash_headers(file, &eocd, eocd_offset, file_stat.size)?,
+                settings: open_ctx.get_name_settings(non_unicode_compat),
+            }),
+            None => None,
+        };
+        let cached_index = index_cache
+            .zip(index_key.as_ref())
+            .and_then(|(cache, key)| cache.load::<ZipIndex>("zip", key));
+        let (index, decoder) = match cached_index {
+            Some(index) => {
+                log::debug!("zip: using cached index of `{}`", open_ctx.get_path());
+                // NOTE: Names of the cached index are already decoded, but comments are
+                //       decoded later on, so the encoding is detected again if needed
+                let mut records = index.unplaced.iter().collect::<Vec<_>>();
+                collect_tree_records(&index.cd, &mut records);
+                let decoder = make_text_decoder(open_ctx, non_unicode_compat, records.into_iter())?;
+                (index, decoder)
+            }
+            None => {
+                let (index, decoder) = parse_index(
+                    open_ctx,
+                    &source,
+                    &eocd,
+                    prefix,
+                    &file_stat,
+                    non_unicode_compat,
+                )?;
+                if let Some((cache, key)) = index_cache.zip(index_key) {
+                    cache.store("zip", key, &index);
+                }
+                (index, decoder)
+            }
+        };
+        let ZipIndex {
+            cd: mut cd_tree,
+            data_start,
+            index_size,
+            unplaced,
+            warnings,
+        } = index;
+        if !warnings.is_empty() {
+            log::warn!(
+                "zip: {} entries of `{}` have been renamed or skipped",
+                warnings.len(),
+                open_ctx.get_path()
+            );
+        }
 
-        let cd_tree = build_file_tree(
-            cd,
-            file_stat.index,
-            non_unicode_compat,
-            file_stat.last_write_time,
-        )?;
+        // NOTE: Only single-file archives can be modified in place, and records with
+        //       unknown offsets could not be kept when committing
+        let edits = match write_config {
+            Some(_) if !matches!(source, ZipSource::File(_)) => {
+                log::warn!(
+                    "zip: multi-volume archive `{}` is opened as read-only",
+                    open_ctx.get_file_name()
+                );
+                None
+            }
+            Some(_) if unplaced.iter().any(|x| x.zip64_error.is_some()) => {
+                log::warn!(
+                    "zip: `{}` has entries with broken Zip64 fields and is opened as read-only",
+                    open_ctx.get_file_name()
+                );
+                None
+            }
+            Some(config) => Some(write::ZipEdits::new(
+                &mut cd_tree,
+                unplaced,
+                &eocd,
+                data_start,
+                config,
+            )),
+            None => None,
+        };
 
         Ok(ZipArchive {
-            file,
+            source,
             file_index: file_stat.index,
             eocd,
             cd: cd_tree,
+            passwords,
+            edits,
+            crc_check,
+            cache: open_ctx.get_cache(),
+            index_size,
+            warnings,
+            decoder,
+            info_entries: Mutex::new(None),
         })
     }
 }
@@ -692,6 +1518,300 @@ impl ZipArchive<'_> {
     }
 }
 
+impl ZipArchive<'_> {
+    // Sets up decryption for `data`, which initially covers the whole raw data
+    // of the entry, and resolves the actual compression method
+    fn open_encrypted_data(
+        &self,
+        record: &ZipCentralDirRecord,
+        data: &mut ZipEntryData,
+        method: &mut u16,
+    ) -> FileSystemResult<()> {
+        if (record.general_purpose_bitflag & ZIP_GPFLAGS_STRONG_ENCRYPTION) != 0 {
+            log::warn!("zip: strong encryption is not supported");
+            return Err(FileSystemError::NotImplemented);
+        }
+        if self.passwords.is_empty() {
+            log::warn!("zip: entry is encrypted but no password is configured");
+            return Err(FileSystemError::WrongPassword);
+        }
+
+        let decryptor = if *method == ZIP_COMPRESSION_AES {
+            let Some(mut field) = find_extra_field(&record.extra_data, ZIP_LOCAL_EXTRA_HID_AES)?
+            else {
+                return Err(FileSystemError::FileCorruptError);
+            };
+            let _vendor_version = field
+                .read_u16::<LittleEndian>()
+                .map_err(anyhow::Error::from)?;
+            let _vendor_id = field
+                .read_u16::<LittleEndian>()
+                .map_err(anyhow::Error::from)?;
+            let strength = field.read_u8().map_err(anyhow::Error::from)?;
+            *method = field
+                .read_u16::<LittleEndian>()
+                .map_err(anyhow::Error::from)?;
+            let key_len =
+                ZipAesDecryptor::get_key_len(strength).ok_or(FileSystemError::FileCorruptError)?;
+
+            let salt_len = ZipAesDecryptor::get_salt_len(key_len) as u64;
+            let overhead = salt_len + ZIP_AES_VERIFIER_SIZE + ZIP_AES_MAC_SIZE;
+            if data.size < overhead {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            let mut header = vec![0u8; (salt_len + ZIP_AES_VERIFIER_SIZE) as usize];
+            CursorFile::with_position(self.source.as_file(), data.start)
+                .read_exact(&mut header)
+                .map_err(anyhow::Error::from)?;
+            let (salt, verifier) = header.split_at(salt_len as _);
+            let verifier = [verifier[0], verifier[1]];
+            let (decryptor, mac) = self
+                .passwords
+                .iter()
+                .find_map(|x| ZipAesDecryptor::new(x.as_bytes(), key_len, salt, verifier))
+                .ok_or(FileSystemError::WrongPassword)?;
+            data.start += salt_len + ZIP_AES_VERIFIER_SIZE;
+            data.size -= overhead;
+
+            // NOTE: The encrypted data is authenticated while it is read
+            let mut expected = [0u8; ZIP_AES_MAC_SIZE as usize];
+            CursorFile::with_position(self.source.as_file(), data.start + data.size)
+                .read_exact(&mut expected)
+                .map_err(anyhow::Error::from)?;
+            let verifier = ZipMacVerifier {
+                expected,
+                start: data.start,
+                size: data.size,
+                initial: mac.clone(),
+                state: Mutex::new(ZipMacState::Hashing { mac, pos: 0 }),
+            };
+            // NOTE: Empty data is authenticated right away
+            verifier.update(0, &[]);
+            verifier.check()?;
+            ZipDecryptor::Aes(decryptor, Arc::new(verifier))
+        } else {
+            if data.size < ZIP_CRYPTO_HEADER_SIZE {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            let mut header = [0u8; ZIP_CRYPTO_HEADER_SIZE as usize];
+            CursorFile::with_position(self.source.as_file(), data.start)
+                .read_exact(&mut header)
+                .map_err(anyhow::Error::from)?;
+            // NOTE: Entries with a data descriptor are checked against the
+            //       modify time instead, as CRC may not be known in advance
+            let check_byte = if (record.general_purpose_bitflag & ZIP_GPFLAGS_DATA_DESCRIPTOR) != 0
+            {
+                (record.last_modify_time >> 8) as u8
+            } else {
+                (record.uncompressed_data_crc32 >> 24) as u8
+            };
+            let keys = self
+                .passwords
+                .iter()
+                .find_map(|x| ZipCryptoKeys::with_header(x.as_bytes(), &header, check_byte))
+                .ok_or(FileSystemError::WrongPassword)?;
+            data.start += ZIP_CRYPTO_HEADER_SIZE;
+            data.size -= ZIP_CRYPTO_HEADER_SIZE;
+            ZipDecryptor::ZipCrypto(keys)
+        };
+        data.decryptor = Some(decryptor);
+        Ok(())
+    }
+}
+
+impl ZipArchive<'_> {
+    // Parses the local header of an entry and sets up the reader of its data
+    fn open_entry_reader(
+        &self,
+        record: &ZipCentralDirRecord,
+        crc_check: ArchiveCrcCheckMode,
+    ) -> FileSystemResult<ZipFileReader<'_>> {
+        let mut cursor_file = CursorFile::with_position(
+            self.source.as_file(),
+            record.local_file_header_offset as _,
+        );
+        // let local_record = parse_local_file_record(&mut cursor_file)?;
+        skip_local_file_record_with_central_no_verify(&mut cursor_file, record)?;
+        let mut data = ZipEntryData {
+            start: cursor_file.get_position(),
+            size: record.compressed_size,
+            decryptor: None,
+        };
+        let mut method = record.compression_method;
+        if (record.general_purpose_bitflag & ZIP_GPFLAGS_ENCRYPTED) != 0 {
+            self.open_encrypted_data(record, &mut data, &mut method)?;
+        }
+        // NOTE: AE-2 entries leave CRC-32 zeroed, as the authentication code covers them
+        let is_crc_unused = record.compression_method == ZIP_COMPRESSION_AES
+            && find_extra_field(&record.extra_data, ZIP_LOCAL_EXTRA_HID_AES)?
+                .is_some_and(|x| x.starts_with(&2u16.to_le_bytes()));
+        let crc = (crc_check != ArchiveCrcCheckMode::Off && !is_crc_unused).then(|| {
+            ZipCrcVerifier {
+                mode: crc_check,
+                expected: record.uncompressed_data_crc32,
+                size: record.uncompressed_size,
+                state: Mutex::new(ZipCrcState::Hashing {
+                    crc: flate2::Crc::new(),
+                    pos: 0,
+                }),
+            }
+        });
+        let mac = match &data.decryptor {
+            Some(ZipDecryptor::Aes(_, mac)) => Some(Arc::clone(mac)),
+            _ => None,
+        };
+        let decoder = match method {
+            // NOTE: ZipCrypto can't be decrypted at arbitrary offsets
+            ZIP_COMPRESSION_STORE
+                if matches!(data.decryptor, Some(ZipDecryptor::ZipCrypto(_))) =>
+            {
+                ZipFileDecoder::Stream(ZipFileStreamReader {
+                    method: ZipStreamMethod::Store,
+                    data,
+                    uncompressed_size: record.uncompressed_size,
+                    state: Mutex::new(None),
+                })
+            }
+            ZIP_COMPRESSION_STORE => ZipFileDecoder::Store(ZipFileStoreReader {
+                data,
+                size: record.uncompressed_size as _,
+            }),
+            method @ (ZIP_COMPRESSION_DEFLATE | ZIP_COMPRESSION_DEFLATE64) => {
+                // Decompression is very expensive, so delay the operation
+                ZipFileDecoder::Deflate(ZipFileDeflateReader {
+                    format: if method == ZIP_COMPRESSION_DEFLATE64 {
+                        InflateFormat::Deflate64
+                    } else {
+                        InflateFormat::Deflate
+                    },
+                    data,
+                    state: Mutex::new(ZipFileDeflateState {
+                        inflater: None,
+                        index: InflateIndex::new(),
+                    }),
+                })
+            }
+            method @ (ZIP_COMPRESSION_BZIP2
+            | ZIP_COMPRESSION_LZMA
+            | ZIP_COMPRESSION_ZSTD) => ZipFileDecoder::Stream(ZipFileStreamReader {
+                method: match method {
+                    ZIP_COMPRESSION_BZIP2 => ZipStreamMethod::Bzip2,
+                    ZIP_COMPRESSION_LZMA => ZipStreamMethod::Lzma {
+                        has_eos_marker: (record.general_purpose_bitflag
+                            & ZIP_GPFLAGS_LZMA_EOS)
+                            != 0,
+                    },
+                    _ => ZipStreamMethod::Zstd,
+                },
+                data,
+                uncompressed_size: record.uncompressed_size,
+                state: Mutex::new(None),
+            }),
+            method => {
+                log::warn!("zip: unsupported compression method {method}");
+                return Err(FileSystemError::NotImplemented);
+            }
+        };
+        // NOTE: Stored data is read directly from the archive, so only cache decoded data.
+        //       AES entries are not shared either, as data is authenticated while read.
+        let cache = match decoder {
+            ZipFileDecoder::Deflate(_) | ZipFileDecoder::Stream(_) if mac.is_none() => self.cache,
+            _ => None,
+        };
+        let reader = ZipFileReader {
+            decoder,
+            crc,
+            mac,
+            cache,
+            cache_item: record.local_file_header_offset,
+            size: record.uncompressed_size,
+        };
+        Ok(reader)
+    }
+    // Calls `f` with the path and record of every stored file entry
+    fn for_each_record(&self, mut f: impl FnMut(String, &ZipCentralDirRecord)) {
+        match &self.edits {
+            Some(edits) => {
+                for (path, record) in edits.get_stored_records() {
+                    f(path, &record);
+                }
+            }
+            None => {
+                let mut folders = vec![(String::new(), &self.cd)];
+                while let Some((path, folder)) = folders.pop() {
+                    for (name, entry) in &folder.children {
+                        let entry_path = if path.is_empty() {
+                            name.as_str().to_owned()
+                        } else {
+                            format!("{path}/{}", name.as_str())
+                        };
+                        match entry {
+                            ZipEntry::Folder(e) => folders.push((entry_path, e)),
+                            ZipEntry::File(e) => f(entry_path, &e.data),
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // Returns `entries.json` of the info folder, generated again only after edits
+    fn get_info_entries(&self) -> FileSystemResult<Arc<[u8]>> {
+        let revision = self.edits.as_ref().map_or(0, |x| x.get_revision());
+        let mut cached = self.info_entries.lock().unwrap();
+        if let Some((cached_revision, data)) = &*cached {
+            if *cached_revision == revision {
+                return Ok(Arc::clone(data));
+            }
+        }
+        let mut entries = Vec::new();
+        self.for_each_record(|path, record| {
+            let extra_fields = parse_local_file_extra_data(&record.extra_data).unwrap_or_default();
+            let stat = get_record_stat(record, &extra_fields);
+            entries.push(serde_json::json!({
+                "path": path,
+                "compressed_size": record.compressed_size,
+                "uncompressed_size": record.uncompressed_size,
+                "method": get_compression_method_name(record.compression_method),
+                "method_id": record.compression_method,
+                "crc32": format!("{:08x}", record.uncompressed_data_crc32),
+                "encrypted": (record.general_purpose_bitflag & ZIP_GPFLAGS_ENCRYPTED) != 0,
+                "last_write_time": chrono::DateTime::<chrono::Local>::from(stat.last_write_time)
+                    .to_rfc3339(),
+                "local_header_offset": record.local_file_header_offset,
+                "comment": self.decoder.decode(
+                    &record.comment,
+                    (record.general_purpose_bitflag & ZIP_GPFLAGS_EFS) != 0,
+                ),
+            }));
+        });
+        let data: Arc<[u8]> = serde_json::to_vec_pretty(&entries)
+            .map_err(anyhow::Error::from)?
+            .into();
+        *cached = Some((revision, Arc::clone(&data)));
+        Ok(data)
+    }
+    // Reads an entry through with strict CRC-32 verification
+    fn verify_entry(&self, record: &ZipCentralDirRecord) -> FileSystemResult<()> {
+        let mut reader = self.open_entry_reader(record, ArchiveCrcCheckMode::Strict)?;
+        // Data is only read once here, so keep it from flooding the cache
+        reader.cache = None;
+        let file = self.source.as_file();
+        let mut buf = vec![0; 256 * 1024];
+        let mut offset = 0;
+        loop {
+            let len = reader.read_at(file, offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            offset += len;
+        }
+        if offset != record.uncompressed_size {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        Ok(())
+    }
+}
+
 impl super::ArchiveHandler for ZipArchive<'_> {
     fn open_file(
         &self,
@@ -699,6 +1819,17 @@ impl super::ArchiveHandler for ZipArchive<'_> {
     ) -> FileSystemResult<super::ArchiveHandlerOpenFileInfo<'_>> {
         // log::debug!("zip: Opening `{}`...", filename.get_path());
 
+        if let Some(edits) = &self.edits {
+            return edits
+                .create_file(
+                    self,
+                    filename,
+                    crate::fs_provider::FileCreateDisposition::OpenExisting,
+                    crate::fs_provider::FileCreateOptions::empty(),
+                )
+                .map(|x| x.0);
+        }
+
         let (parent, filename) = self.resolve_path(filename)?;
 
         let entry = if let Some(parent) = parent {
@@ -711,46 +1842,9 @@ impl super::ArchiveHandler for ZipArchive<'_> {
             BorrowedZipEntry::Folder(&self.cd)
         };
 
-        let mut extra_ntfs = None;
-
-        // Parse local header
         let data_reader = match entry {
-            BorrowedZipEntry::File(e) => {
-                if (e.data.general_purpose_bitflag & ZIP_GPFLAGS_ENCRYPTED) != 0 {
-                    // TODO: Support entrypted archives
-                    return Err(FileSystemError::FileCorruptError);
-                }
-                let mut cursor_file =
-                    CursorFile::with_position(&self.file, e.data.local_file_header_offset as _);
-                // let local_record = parse_local_file_record(&mut cursor_file)?;
-                skip_local_file_record_with_central_no_verify(&mut cursor_file, &e.data)?;
-                let extra_fields = parse_local_file_extra_data(&e.data.extra_data)?;
-                for i in extra_fields {
-                    match i {
-                        ZipLocalFileExtraField::NTFS(field) => extra_ntfs = Some(field),
-                        _ => (),
-                    }
-                }
-                let data_start = cursor_file.get_position();
-                match e.data.compression_method {
-                    ZIP_COMPRESSION_STORE => ZipFileReader::Store(ZipFileStoreReader {
-                        start: data_start,
-                        size: e.data.uncompressed_size as _,
-                    }),
-                    ZIP_COMPRESSION_DEFLATE => {
-                        // Decompression is very expensive, so delay the operation
-                        ZipFileReader::Deflate(ZipFileDeflateReader {
-                            start: data_start,
-                            data: RwLock::new(None),
-                        })
-                    }
-                    _ => {
-                        log::warn!("zip: unsupported compression method");
-                        return Err(FileSystemError::FileCorruptError);
-                    }
-                }
-            }
-            BorrowedZipEntry::Folder(e) => ZipFileReader::Null,
+            BorrowedZipEntry::File(e) => self.open_entry_reader(&e.data, self.crc_check)?,
+            BorrowedZipEntry::Folder(_) => ZipFileReader::null(),
         };
 
         Ok(super::ArchiveHandlerOpenFileInfo {
@@ -758,27 +1852,540 @@ impl super::ArchiveHandler for ZipArchive<'_> {
                 root: self,
                 entry,
                 reader: data_reader,
-                extra_ntfs,
             }),
             is_dir: entry.is_dir(),
         })
     }
+    fn create_file(
+        &self,
+        filename: SegPath,
+        create_disposition: crate::fs_provider::FileCreateDisposition,
+        create_options: crate::fs_provider::FileCreateOptions,
+    ) -> FileSystemResult<(super::ArchiveHandlerOpenFileInfo<'_>, bool)> {
+        match &self.edits {
+            Some(edits) => edits.create_file(self, filename, create_disposition, create_options),
+            None => self.open_file(filename).map(|x| (x, false)),
+        }
+    }
+    fn has_pending_changes(&self) -> bool {
+        self.edits.as_ref().is_some_and(|x| x.has_pending_changes())
+    }
+    fn commit(&self, is_final: bool) -> FileSystemResult<()> {
+        match &self.edits {
+            Some(edits) => edits.commit(self, is_final),
+            None => Err(FileSystemError::AccessDenied),
+        }
+    }
+    fn verify_integrity(&self) -> FileSystemResult<Vec<crate::fs_provider::IntegrityIssue>> {
+        let mut issues = Vec::new();
+        // NOTE: Modifications not yet committed are not verified
+        self.for_each_record(|path, record| {
+            if let Err(error) = self.verify_entry(record) {
+                log::warn!("zip: entry `{path}` failed verification: {error}");
+                issues.push(crate::fs_provider::IntegrityIssue { path, error });
+            }
+        });
+        Ok(issues)
+    }
+    fn get_index_size(&self) -> u64 {
+        self.index_size
+    }
+    fn get_warnings(&self) -> Vec<ContainerWarning> {
+        self.warnings.clone()
+    }
+    fn get_info_file_names(&self) -> &'static [&'static str] {
+        &["archive.json", "entries.json"]
+    }
+    fn get_info_file(&self, name: &str) -> FileSystemResult<Option<Arc<[u8]>>> {
+        let value = match name {
+            "archive.json" => {
+                let mut entry_count = 0u64;
+                self.for_each_record(|_, _| entry_count += 1);
+                serde_json::json!({
+                    "format": "zip",
+                    "comment": self.decoder.decode(&self.eocd.comment, false),
+                    "entry_count": entry_count,
+                    "disk_count": self.eocd.num_disk as u64 + 1,
+                    "writable": self.edits.is_some(),
+                })
+            }
+            "entries.json" => return self.get_info_entries().map(Some),
+            _ => return Ok(None),
+        };
+        let data = serde_json::to_vec_pretty(&value).map_err(anyhow::Error::from)?;
+        Ok(Some(data.into()))
+    }
 }
 
-struct ZipFileStoreReader {
+#[derive(Clone)]
+enum ZipDecryptor {
+    // Keys right after the encryption header
+    ZipCrypto(ZipCryptoKeys),
+    Aes(ZipAesDecryptor, Arc<ZipMacVerifier>),
+}
+
+// Location of the compressed data of an entry, excluding encryption headers
+#[derive(Clone)]
+struct ZipEntryData {
     start: u64,
     size: u64,
+    decryptor: Option<ZipDecryptor>,
 }
 
-struct ZipFileDeflateReader {
-    start: u64,
-    data: RwLock<Option<Vec<u8>>>,
+struct ZipDataSource<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    data: ZipEntryData,
+    pos: u64,
+}
+
+impl Read for ZipDataSource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.data.size.saturating_sub(self.pos) as _);
+        let buf = &mut buf[..len];
+        let len = self.file.read_at(self.data.start + self.pos, buf)? as usize;
+        match &mut self.data.decryptor {
+            None => (),
+            Some(ZipDecryptor::ZipCrypto(keys)) => keys.decrypt(&mut buf[..len]),
+            Some(ZipDecryptor::Aes(decryptor, mac)) => {
+                mac.update(self.pos, &buf[..len]);
+                decryptor.decrypt_at(self.pos, &mut buf[..len]);
+            }
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+// Opens the compressed data of an entry, starting at `offset` within it
+fn open_data_source<'a>(
+    file: &'a dyn crate::fs_provider::File,
+    data: &ZipEntryData,
+    offset: u64,
+) -> std::io::Result<ZipDataSource<'a>> {
+    let mut source = ZipDataSource {
+        file,
+        data: data.clone(),
+        pos: offset,
+    };
+    if let Some(ZipDecryptor::ZipCrypto(_)) = &data.decryptor {
+        // Key state depends on all previous bytes, so decrypt from the start
+        source.pos = 0;
+        std::io::copy(&mut (&mut source).take(offset), &mut std::io::sink())?;
+    }
+    Ok(source)
+}
+
+struct ZipFileStoreReader {
+    data: ZipEntryData,
+    size: u64,
+}
+
+struct ZipFileDeflateState<'a> {
+    inflater: Option<Inflater<ZipDataSource<'a>>>,
+    index: InflateIndex,
+}
+
+// NOTE: Every handle keeps its own decoder and checkpoint index, so memory
+//       stays bounded (one window plus sparse snapshots) even for huge entries
+struct ZipFileDeflateReader<'a> {
+    format: InflateFormat,
+    data: ZipEntryData,
+    state: Mutex<ZipFileDeflateState<'a>>,
+}
+
+impl<'a> ZipFileDeflateReader<'a> {
+    fn open_source(
+        &self,
+        file: &'a dyn crate::fs_provider::File,
+        offset: u64,
+    ) -> std::io::Result<ZipDataSource<'a>> {
+        open_data_source(file, &self.data, offset)
+    }
+    fn read_at(
+        &self,
+        file: &'a dyn crate::fs_provider::File,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> anyhow::Result<u64> {
+        let mut guard = self.state.lock().unwrap();
+        let ZipFileDeflateState { inflater, index } = &mut *guard;
+
+        // Resume from the closest point, which is either the current decoder
+        // position or a checkpoint before the requested offset
+        let checkpoint = index.find(offset);
+        let can_continue = inflater.as_ref().is_some_and(|x| {
+            x.get_out_pos() <= offset
+                && checkpoint.map_or(true, |c| c.get_out_pos() <= x.get_out_pos())
+        });
+        if !can_continue {
+            *inflater = Some(match checkpoint {
+                Some(c) => {
+                    Inflater::with_checkpoint(self.open_source(file, c.get_in_byte_pos())?, c)?
+                }
+                None => Inflater::new(self.open_source(file, 0)?, self.format),
+            });
+        }
+        // SAFETY: Decoder is already initialized
+        let inflater = unsafe { inflater.as_mut().unwrap_unchecked() };
+
+        let mut scratch = [0u8; 4096];
+        while inflater.get_out_pos() < offset {
+            let len = scratch.len().min((offset - inflater.get_out_pos()) as _);
+            if inflater.read(&mut scratch[..len])? == 0 {
+                return Ok(0);
+            }
+            index.update(inflater);
+        }
+        let mut total = 0;
+        while total < buffer.len() {
+            let len = inflater.read(&mut buffer[total..])?;
+            if len == 0 {
+                break;
+            }
+            total += len;
+            index.update(inflater);
+        }
+        Ok(total as _)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ZipStreamMethod {
+    Store,
+    Bzip2,
+    Lzma { has_eos_marker: bool },
+    Zstd,
+}
+
+struct ZipFileStreamState<'a> {
+    decoder: Box<dyn Read + 'a>,
+    out_pos: u64,
+}
+
+// NOTE: These codecs have no cheap way to resume mid-stream, so they are
+//       decoded sequentially and seeking backwards restarts from the beginning
+struct ZipFileStreamReader<'a> {
+    method: ZipStreamMethod,
+    data: ZipEntryData,
+    uncompressed_size: u64,
+    state: Mutex<Option<ZipFileStreamState<'a>>>,
+}
+
+impl<'a> ZipFileStreamReader<'a> {
+    fn open_decoder(
+        &self,
+        file: &'a dyn crate::fs_provider::File,
+    ) -> anyhow::Result<Box<dyn Read + 'a>> {
+        let mut source = open_data_source(file, &self.data, 0)?;
+        Ok(match self.method {
+            ZipStreamMethod::Store => Box::new(source),
+            ZipStreamMethod::Bzip2 => Box::new(bzip2::read::BzDecoder::new(source)),
+            ZipStreamMethod::Lzma { has_eos_marker } => {
+                // Zip stores a small header (version, properties size and
+                // properties) in front of the raw stream; rebuild it into the
+                // legacy .lzma header which liblzma understands
+                let _version = source.read_u16::<LittleEndian>()?;
+                let props_size = source.read_u16::<LittleEndian>()?;
+                if props_size != 5 {
+                    anyhow::bail!("unexpected LZMA properties size {props_size}");
+                }
+                let mut header = [0u8; 13];
+                source.read_exact(&mut header[..5])?;
+                let unpacked_size = if has_eos_marker {
+                    u64::MAX
+                } else {
+                    self.uncompressed_size
+                };
+                header[5..].copy_from_slice(&unpacked_size.to_le_bytes());
+                let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)?;
+                Box::new(xz2::read::XzDecoder::new_stream(
+                    std::io::Cursor::new(header).chain(source),
+                    stream,
+                ))
+            }
+            ZipStreamMethod::Zstd => Box::new(zstd::stream::read::Decoder::new(source)?),
+        })
+    }
+    fn read_at(
+        &self,
+        file: &'a dyn crate::fs_provider::File,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> anyhow::Result<u64> {
+        if offset >= self.uncompressed_size {
+            return Ok(0);
+        }
+        let buffer_len = buffer.len().min(
+            (self.uncompressed_size - offset)
+                .try_into()
+                .unwrap_or(usize::MAX),
+        );
+        let buffer = &mut buffer[..buffer_len];
+
+        let mut guard = self.state.lock().unwrap();
+        let state = match &mut *guard {
+            Some(state) if state.out_pos <= offset => state,
+            state => state.insert(ZipFileStreamState {
+                decoder: self.open_decoder(file)?,
+                out_pos: 0,
+            }),
+        };
+
+        let skip_len = offset - state.out_pos;
+        state.out_pos += std::io::copy(
+            &mut (&mut state.decoder).take(skip_len),
+            &mut std::io::sink(),
+        )?;
+        if state.out_pos < offset {
+            return Ok(0);
+        }
+        let mut total = 0;
+        while total < buffer.len() {
+            let len = match state.decoder.read(&mut buffer[total..]) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // Decoder state is unknown now, start over next time
+                    *guard = None;
+                    return Err(e.into());
+                }
+            };
+            total += len;
+            state.out_pos += len as u64;
+        }
+        Ok(total as _)
+    }
 }
 
-enum ZipFileReader {
+enum ZipFileDecoder<'a> {
     Null,
     Store(ZipFileStoreReader),
-    Deflate(ZipFileDeflateReader),
+    Deflate(ZipFileDeflateReader<'a>),
+    Stream(ZipFileStreamReader<'a>),
+}
+
+impl<'a> ZipFileDecoder<'a> {
+    fn read_at(
+        &self,
+        file: &'a dyn crate::fs_provider::File,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<u64> {
+        match self {
+            // Directories are handled by callers, so return FileCorruptError here
+            ZipFileDecoder::Null => Err(FileSystemError::FileCorruptError),
+            ZipFileDecoder::Store(r) => {
+                let read_len = buffer.len().min(r.size.saturating_sub(offset) as _);
+                if r.data.decryptor.is_none() {
+                    return file.read_at(r.data.start + offset, &mut buffer[..read_len]);
+                }
+                let mut source =
+                    open_data_source(file, &r.data, offset).map_err(anyhow::Error::from)?;
+                Ok(source
+                    .read(&mut buffer[..read_len])
+                    .map_err(anyhow::Error::from)? as _)
+            }
+            ZipFileDecoder::Deflate(r) => Ok(r.read_at(file, offset, buffer)?),
+            ZipFileDecoder::Stream(r) => Ok(r.read_at(file, offset, buffer)?),
+        }
+    }
+}
+
+enum ZipCrcState {
+    // Data is being hashed in order, with `pos` bytes so far
+    Hashing { crc: flate2::Crc, pos: u64 },
+    // Some data was never read, so the checksum is unknown
+    Skipped,
+    Verified,
+    Mismatched,
+}
+
+// Verifies CRC-32 of an entry once it has been read through from the start
+// NOTE: Random access only gets verified if every byte is read in order at some point
+struct ZipCrcVerifier {
+    mode: ArchiveCrcCheckMode,
+    expected: u32,
+    size: u64,
+    state: Mutex<ZipCrcState>,
+}
+
+impl ZipCrcVerifier {
+    fn update(&self, offset: u64, data: &[u8]) -> FileSystemResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if offset == 0 && matches!(*state, ZipCrcState::Skipped) {
+            // Reading from the start again
+            *state = ZipCrcState::Hashing {
+                crc: flate2::Crc::new(),
+                pos: 0,
+            };
+        }
+        let ZipCrcState::Hashing { crc, pos } = &mut *state else {
+            if matches!(*state, ZipCrcState::Mismatched) && self.mode == ArchiveCrcCheckMode::Strict
+            {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            return Ok(());
+        };
+        if offset > *pos {
+            *state = ZipCrcState::Skipped;
+            return Ok(());
+        }
+        let overlap = (*pos - offset).min(data.len() as _) as usize;
+        crc.update(&data[overlap..]);
+        *pos += (data.len() - overlap) as u64;
+        if *pos < self.size {
+            return Ok(());
+        }
+        if crc.sum() == self.expected {
+            *state = ZipCrcState::Verified;
+            return Ok(());
+        }
+        log::warn!(
+            "zip: CRC-32 mismatch, expected {:08x} but found {:08x}",
+            self.expected,
+            crc.sum()
+        );
+        *state = ZipCrcState::Mismatched;
+        match self.mode {
+            ArchiveCrcCheckMode::Strict => Err(FileSystemError::FileCorruptError),
+            _ => Ok(()),
+        }
+    }
+}
+
+enum ZipMacState {
+    // Encrypted data is being hashed in order, with `pos` bytes so far
+    Hashing { mac: ZipAesMac, pos: u64 },
+    // Some data was never read, so it is not authenticated
+    Skipped,
+    Verified,
+    Mismatched,
+}
+
+// Authenticates encrypted data of an AES entry once it has been read through
+// NOTE: Unlike CRC-32, a mismatch is always reported
+struct ZipMacVerifier {
+    expected: [u8; ZIP_AES_MAC_SIZE as usize],
+    start: u64,
+    size: u64,
+    // MAC before any data, for reading from the start again
+    initial: ZipAesMac,
+    state: Mutex<ZipMacState>,
+}
+
+impl ZipMacVerifier {
+    // Called with encrypted data starting at `offset`, before it is decrypted
+    fn update(&self, offset: u64, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if offset == 0 && matches!(*state, ZipMacState::Skipped) {
+            *state = ZipMacState::Hashing {
+                mac: self.initial.clone(),
+                pos: 0,
+            };
+        }
+        let ZipMacState::Hashing { mac, pos } = &mut *state else {
+            return;
+        };
+        if offset > *pos {
+            *state = ZipMacState::Skipped;
+            return;
+        }
+        let overlap = (*pos - offset).min(data.len() as _) as usize;
+        mac.update(&data[overlap..]);
+        *pos += (data.len() - overlap) as u64;
+        if *pos < self.size {
+            return;
+        }
+        *state = if mac.clone().finalize().into_bytes()[..self.expected.len()] == self.expected {
+            ZipMacState::Verified
+        } else {
+            log::warn!("zip: authentication of encrypted entry failed");
+            ZipMacState::Mismatched
+        };
+    }
+    fn check(&self) -> FileSystemResult<()> {
+        match *self.state.lock().unwrap() {
+            ZipMacState::Mismatched => Err(FileSystemError::FileCorruptError),
+            _ => Ok(()),
+        }
+    }
+    // Hashes what decoding left unread, once the end of the entry is reached
+    // NOTE: Tampered data may well end the compressed stream early
+    fn finish(&self, file: &dyn crate::fs_provider::File) -> FileSystemResult<()> {
+        let state = self.state.lock().unwrap();
+        let ZipMacState::Hashing { mut pos, .. } = *state else {
+            return match *state {
+                ZipMacState::Mismatched => Err(FileSystemError::FileCorruptError),
+                _ => Ok(()),
+            };
+        };
+        drop(state);
+        let mut buf = vec![0u8; 64 * 1024];
+        while pos < self.size {
+            let len = buf.len().min((self.size - pos) as _);
+            let len = file.read_at(self.start + pos, &mut buf[..len])?;
+            if len == 0 {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            self.update(pos, &buf[..len as usize]);
+            pos += len;
+        }
+        self.check()
+    }
+}
+
+struct ZipFileReader<'a> {
+    decoder: ZipFileDecoder<'a>,
+    crc: Option<ZipCrcVerifier>,
+    mac: Option<Arc<ZipMacVerifier>>,
+    // Decoded blocks are shared between handles, keyed by the local header offset
+    cache: Option<ArchiveCacheHandle<'a>>,
+    cache_item: u64,
+    size: u64,
+}
+
+impl<'a> ZipFileReader<'a> {
+    fn null() -> Self {
+        Self {
+            decoder: ZipFileDecoder::Null,
+            crc: None,
+            mac: None,
+            cache: None,
+            cache_item: 0,
+            size: 0,
+        }
+    }
+    fn read_at(
+        &self,
+        file: &'a dyn crate::fs_provider::File,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<u64> {
+        let len = match self.cache {
+            Some(cache) => read_cached_blocks(
+                cache,
+                self.cache_item,
+                self.size,
+                ZIP_CACHE_BLOCK_SIZE,
+                offset,
+                buffer,
+                |offset, buffer| self.decoder.read_at(file, offset, buffer),
+            )?,
+            None => self.decoder.read_at(file, offset, buffer)?,
+        };
+        if let Some(mac) = &self.mac {
+            if len == 0 || offset + len >= self.size {
+                mac.finish(file)?;
+            } else {
+                mac.check()?;
+            }
+        }
+        if let Some(crc) = &self.crc {
+            crc.update(offset, &buffer[..len as usize])?;
+        }
+        Ok(len)
+    }
 }
 
 pub struct ZipFile<'a> {
@@ -787,96 +2394,19 @@ pub struct ZipFile<'a> {
     // is_dir: bool,
     // entry: Option<&'a ZipEntry>,
     entry: BorrowedZipEntry<'a>,
-    reader: ZipFileReader,
-    extra_ntfs: Option<ZipLocalFileNtfsExtraField>,
+    reader: ZipFileReader<'a>,
 }
 
 impl super::ArchiveFile for ZipFile<'_> {
     fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
-        let entry = match self.entry {
-            BorrowedZipEntry::File(e) => e,
-            BorrowedZipEntry::Folder(_) => return Err(FileSystemError::FileIsADirectory),
-        };
-        let file = &self.root.file;
-        match &self.reader {
-            // We already handled the directory case, so return FileCorruptError here
-            ZipFileReader::Null => Err(FileSystemError::FileCorruptError),
-            ZipFileReader::Store(r) => {
-                let read_len = buffer.len().min(r.size as _);
-                file.read_at(r.start + offset, &mut buffer[..read_len])
-            }
-            ZipFileReader::Deflate(r) => {
-                let data = loop {
-                    {
-                        let guard = r.data.read().unwrap();
-                        if guard.is_some() {
-                            break guard;
-                        }
-                    }
-
-                    // Actually start reading file content
-                    match *r.data.write().unwrap() {
-                        ref mut data @ None => {
-                            *data = Some({
-                                // TODO: Use own deflate implementation to support better random access,
-                                //       so that we just need ~32KB for every file handle
-                                let mut cursor_file =
-                                    CursorFile::with_position(&self.root.file, r.start);
-                                let source_len = entry.data.compressed_size as _;
-                                let mut source = Vec::with_capacity(source_len);
-                                unsafe {
-                                    cursor_file
-                                        .read_exact(std::slice::from_raw_parts_mut(
-                                            source.as_mut_ptr(),
-                                            source_len,
-                                        ))
-                                        .map_err(anyhow::Error::from)?;
-                                    source.set_len(source_len);
-                                }
-                                let data_len = entry.data.uncompressed_size as _;
-                                let mut data = Vec::with_capacity(data_len);
-                                let mut decompressor = libdeflater::Decompressor::new();
-                                unsafe {
-                                    decompressor
-                                        .deflate_decompress(
-                                            &source,
-                                            std::slice::from_raw_parts_mut(
-                                                data.as_mut_ptr(),
-                                                data_len,
-                                            ),
-                                        )
-                                        .map_err(anyhow::Error::from)?;
-                                    data.set_len(data_len);
-                                }
-                                data
-                            })
-                        }
-                        _ => (),
-                    }
-                    break r.data.read().unwrap();
-                };
-                // SAFETY: Reader is already initialized
-                let data = unsafe { data.as_ref().unwrap_unchecked() };
-
-                if offset as usize >= data.len() {
-                    Ok(0)
-                } else {
-                    let mut src = &data[offset as _..];
-                    src.read(buffer)
-                        .map(|x| x as _)
-                        .map_err(|e| FileSystemError::Other(e.into()))
-                }
-            }
+        if let BorrowedZipEntry::Folder(_) = self.entry {
+            return Err(FileSystemError::FileIsADirectory);
         }
+        self.reader
+            .read_at(self.root.source.as_file(), offset, buffer)
     }
     fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
-        let mut stat = self.entry.get_file_stat_info();
-        if let Some(field) = &self.extra_ntfs {
-            stat.last_write_time = field.last_modify_time;
-            stat.last_access_time = field.last_access_time;
-            stat.creation_time = field.creation_time;
-        }
-        Ok(stat)
+        Ok(self.entry.get_file_stat_info())
     }
     fn find_files_with_pattern(
         &self,
@@ -902,3 +2432,247 @@ impl super::ArchiveFile for ZipFile<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use super::super::{test_util::MemFile, ArchiveFsProvider};
+    use super::*;
+    use crate::fs_provider::{
+        stdlocal::StdLocalFsProvider, AcceptAllFilePattern, CreateFileInfo, FileCreateDisposition,
+        FileCreateOptions, FileDesiredAccess, FileShareAccess, FileSystemCreationContext,
+        FileSystemCreationError, FileSystemHandler, FindFilesDataFiller, FsProvider,
+    };
+
+    fn make_eocd(offset_central_dir: u32, comment: &[u8]) -> Vec<u8> {
+        let mut record = 0x06054b50u32.to_le_bytes().to_vec();
+        record.resize(16, 0);
+        record.extend(offset_central_dir.to_le_bytes());
+        record.extend((comment.len() as u16).to_le_bytes());
+        record.extend(comment);
+        record
+    }
+
+    #[test]
+    fn skips_eocd_records_not_ending_the_file() {
+        // An empty archive whose comment holds another record, followed by more text
+        let mut comment = make_eocd(0, b"");
+        comment.extend(b"tail");
+        let file = MemFile(make_eocd(0, &comment));
+        let (record, offset) = parse_eocd_record(&file, file.0.len() as _).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(record.comment, comment);
+    }
+
+    struct LocalFsContext;
+
+    impl FileSystemCreationContext for LocalFsContext {
+        fn get_or_run_fs(
+            &mut self,
+            _: &Uuid,
+            _: &str,
+        ) -> Result<Arc<dyn FileSystemHandler>, FileSystemCreationError> {
+            StdLocalFsProvider::new().construct(serde_json::Value::Null, &mut LocalFsContext)
+        }
+    }
+
+    struct NameCollector(Vec<String>);
+
+    impl FindFilesDataFiller for NameCollector {
+        fn fill_data(&mut self, name: &str, _: &FileStatInfo) -> Result<(), ()> {
+            self.0.push(name.to_owned());
+            Ok(())
+        }
+    }
+
+    fn open_fs(dir: &std::path::Path, writable: bool) -> Arc<dyn FileSystemHandler> {
+        let mut config = ArchiveFsProvider::new().get_template_config();
+        config["input_path"]["path"] = dir.to_str().unwrap().into();
+        if writable {
+            config["archive_rules"][0]["writable"] = serde_json::json!({});
+        }
+        ArchiveFsProvider::new()
+            .construct(config, &mut LocalFsContext)
+            .unwrap()
+    }
+
+    fn open<'h>(
+        fs: &'h dyn FileSystemHandler,
+        path: &str,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> CreateFileInfo<'h> {
+        fs.create_file(
+            SegPath::new(path, PathDelimiter::BackSlash),
+            FileDesiredAccess::ReadWrite,
+            FileAttributes::empty(),
+            FileShareAccess::all(),
+            create_disposition,
+            create_options,
+        )
+        .unwrap()
+    }
+
+    fn write_new(fs: &dyn FileSystemHandler, path: &str, data: &[u8]) {
+        let file = open(
+            fs,
+            path,
+            FileCreateDisposition::CreateNew,
+            FileCreateOptions::NonDirectoryFile,
+        );
+        file.context.write_at(Some(0), data, false).unwrap();
+    }
+
+    fn read_all(fs: &dyn FileSystemHandler, path: &str) -> Vec<u8> {
+        let file = open(
+            fs,
+            path,
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::empty(),
+        );
+        let mut data = vec![0; file.context.get_stat().unwrap().size as _];
+        file.context.read_at_exact(0, &mut data).unwrap();
+        data
+    }
+
+    fn list(fs: &dyn FileSystemHandler, path: &str) -> Vec<String> {
+        let folder = open(
+            fs,
+            path,
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::empty(),
+        );
+        let mut names = NameCollector(Vec::new());
+        folder
+            .context
+            .find_files_with_pattern(&AcceptAllFilePattern::new(), &mut names)
+            .unwrap();
+        names.0.sort();
+        names.0
+    }
+
+    // Builds an archive of empty stored entries, given as (name, comment, flags)
+    fn make_zip(entries: &[(&[u8], &[u8], u16)], comment: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut cd = Vec::new();
+        for &(name, entry_comment, flags) in entries {
+            let offset = data.len() as u32;
+            data.extend(0x04034b50u32.to_le_bytes());
+            data.extend(20u16.to_le_bytes());
+            data.extend(flags.to_le_bytes());
+            data.extend([0; 18]);
+            data.extend((name.len() as u16).to_le_bytes());
+            data.extend(0u16.to_le_bytes());
+            data.extend(name);
+
+            cd.extend(0x02014b50u32.to_le_bytes());
+            cd.extend(20u16.to_le_bytes());
+            cd.extend(20u16.to_le_bytes());
+            cd.extend(flags.to_le_bytes());
+            cd.extend([0; 18]);
+            cd.extend((name.len() as u16).to_le_bytes());
+            cd.extend(0u16.to_le_bytes());
+            cd.extend((entry_comment.len() as u16).to_le_bytes());
+            cd.extend([0; 8]);
+            cd.extend(offset.to_le_bytes());
+            cd.extend(name);
+            cd.extend(entry_comment);
+        }
+        let mut eocd = make_eocd(data.len() as u32, comment);
+        eocd[8..10].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        eocd[10..12].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        eocd[12..16].copy_from_slice(&(cd.len() as u32).to_le_bytes());
+        data.extend(cd);
+        data.extend(eocd);
+        data
+    }
+
+    #[test]
+    fn decodes_comments_with_name_encoding() {
+        let sjis = |s: &str| encoding_rs::SHIFT_JIS.encode(s).0.into_owned();
+        let dir = tempfile::tempdir().unwrap();
+        let zip = make_zip(
+            &[
+                (&sjis("名前.txt"), &sjis("説明"), 0),
+                ("utf8.txt".as_bytes(), "説明".as_bytes(), ZIP_GPFLAGS_EFS),
+            ],
+            &sjis("コメント"),
+        );
+        std::fs::write(dir.path().join("a.zip"), zip).unwrap();
+
+        let mut config = ArchiveFsProvider::new().get_template_config();
+        config["input_path"]["path"] = dir.path().to_str().unwrap().into();
+        config["non_unicode_compat"]["encoding"] = "shift_jis".into();
+        config["info_folder"] = ".info".into();
+        let fs = ArchiveFsProvider::new()
+            .construct(config, &mut LocalFsContext)
+            .unwrap();
+
+        assert_eq!(list(&*fs, "\\a.zip"), [".info", "utf8.txt", "名前.txt"]);
+        let archive: serde_json::Value =
+            serde_json::from_slice(&read_all(&*fs, "\\a.zip\\.info\\archive.json")).unwrap();
+        assert_eq!(archive["comment"], "コメント");
+        let entries: serde_json::Value =
+            serde_json::from_slice(&read_all(&*fs, "\\a.zip\\.info\\entries.json")).unwrap();
+        let mut comments: Vec<_> = entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| (x["path"].as_str().unwrap(), x["comment"].as_str().unwrap()))
+            .collect();
+        comments.sort();
+        assert_eq!(comments, [("utf8.txt", "説明"), ("名前.txt", "説明")]);
+    }
+
+    #[test]
+    fn writes_back_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.zip"), make_eocd(0, b"")).unwrap();
+
+        {
+            let fs = open_fs(dir.path(), true);
+            write_new(&*fs, "\\a.zip\\keep.txt", b"keep");
+            write_new(&*fs, "\\a.zip\\gone.txt", b"gone");
+            write_new(&*fs, "\\a.zip\\old.txt", &vec![b'o'; 100000]);
+            open(
+                &*fs,
+                "\\a.zip\\dir",
+                FileCreateDisposition::CreateNew,
+                FileCreateOptions::DirectoryFile,
+            );
+        }
+        {
+            let fs = open_fs(dir.path(), true);
+            assert_eq!(
+                list(&*fs, "\\a.zip"),
+                ["dir", "gone.txt", "keep.txt", "old.txt"]
+            );
+            let file = open(
+                &*fs,
+                "\\a.zip\\old.txt",
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::empty(),
+            );
+            let new_path = SegPath::new("\\a.zip\\dir\\new.txt", PathDelimiter::BackSlash);
+            file.context.move_to(new_path, false).unwrap();
+            drop(file);
+            open(
+                &*fs,
+                "\\a.zip\\gone.txt",
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::DeleteOnClose,
+            );
+            write_new(&*fs, "\\a.zip\\dir\\added.txt", b"added");
+        }
+
+        let fs = open_fs(dir.path(), false);
+        assert_eq!(list(&*fs, "\\a.zip"), ["dir", "keep.txt"]);
+        assert_eq!(list(&*fs, "\\a.zip\\dir"), ["added.txt", "new.txt"]);
+        assert_eq!(read_all(&*fs, "\\a.zip\\keep.txt"), b"keep");
+        assert_eq!(read_all(&*fs, "\\a.zip\\dir\\added.txt"), b"added");
+        assert_eq!(read_all(&*fs, "\\a.zip\\dir\\new.txt"), vec![b'o'; 100000]);
+    }
+}
@@ -0,0 +1,1588 @@
+// Writable zip archives
+// NOTE: Modifications are kept in an in-memory tree (much like MemFs) until they are
+//       committed. Committing appends local records of new or moved entries and a new
+//       central directory after the end of the file, so unchanged data is never
+//       copied and the previous central directory stays valid until then. Appended
+//       data is only moved down to where the previous one started afterwards.
+// WARN: A crash while appending leaves data behind the previous end record, which
+//       then has to be truncated before readers find it again
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::SystemTime,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    fs_provider::{
+        FileAttributes, FileCreateDisposition, FileCreateOptions, FileStatInfo, FileSystemError,
+        FileSystemResult, SegPath,
+    },
+    util::{CaselessStr, CaselessString, TempFile},
+};
+
+use super::{
+    super::{ArchiveFile, ArchiveHandlerOpenFileInfo, ArchiveWriteConfig},
+    find_extra_field, ZipArchive, ZipCentralDirRecord, ZipEndOfCentralDirRecord, ZipEntry,
+    ZipEntryStat, ZipFileReader, ZipFolderEntry, ZIP_COMPRESSION_DEFLATE, ZIP_COMPRESSION_STORE,
+    ZIP_DOS_ATTRIBUTE_DIRECTORY, ZIP_DOS_ATTRIBUTE_HIDDEN, ZIP_DOS_ATTRIBUTE_READONLY,
+    ZIP_GPFLAGS_DATA_DESCRIPTOR, ZIP_GPFLAGS_EFS, ZIP_GPFLAGS_ENCRYPTED,
+    ZIP_LOCAL_EXTRA_HID_EXTENDED_TIMESTAMP, ZIP_LOCAL_EXTRA_HID_NTFS,
+    ZIP_LOCAL_EXTRA_HID_UNICODE_PATH, ZIP_LOCAL_EXTRA_HID_ZIP64, ZIP_LOCAL_FILE_HEADER_SIZE,
+};
+
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const ZIP_CD_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP_DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const ZIP_END_SIGNATURE: u32 = 0x06054b50;
+const ZIP_END64_SIGNATURE: u32 = 0x06064b50;
+const ZIP_END64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+
+const ZIP_VERSION_DEFAULT: u16 = 20;
+const ZIP_VERSION_ZIP64: u16 = 45;
+
+// NOTE: Entries are written with MS-DOS as the host system
+const ZIP_DOS_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+// Modified data larger than this is staged in a temporary file
+const MAX_MEMORY_BUFFER_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct ZipEditStat {
+    attributes: FileAttributes,
+    creation_time: SystemTime,
+    last_access_time: SystemTime,
+    last_write_time: SystemTime,
+    // NOTE: Like on NTFS, entries pending deletion are only deleted once their last
+    //       handle is closed
+    open_count: u32,
+    delete_on_close: bool,
+}
+
+impl ZipEditStat {
+    fn new(time: SystemTime) -> Self {
+        Self {
+            attributes: FileAttributes::empty(),
+            creation_time: time,
+            last_access_time: time,
+            last_write_time: time,
+            open_count: 0,
+            delete_on_close: false,
+        }
+    }
+}
+
+impl From<&ZipEntryStat> for ZipEditStat {
+    fn from(stat: &ZipEntryStat) -> Self {
+        Self {
+            attributes: stat.get_attributes(),
+            creation_time: stat.creation_time,
+            last_access_time: stat.last_access_time,
+            last_write_time: stat.last_write_time,
+            open_count: 0,
+            delete_on_close: false,
+        }
+    }
+}
+
+enum ZipEditData {
+    // Data of a record in the archive file
+    Stored(Arc<ZipCentralDirRecord>),
+    // Data modified since the last commit
+    Buffer(ZipEditBuffer),
+}
+
+enum ZipEditBuffer {
+    Memory(Vec<u8>),
+    File { file: TempFile, size: u64 },
+}
+
+impl Default for ZipEditBuffer {
+    fn default() -> Self {
+        Self::Memory(Vec::new())
+    }
+}
+
+impl ZipEditBuffer {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Memory(data) => data.len() as _,
+            Self::File { size, .. } => *size,
+        }
+    }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let len = buffer.len().min(self.len().saturating_sub(offset) as _);
+        match self {
+            Self::Memory(data) => {
+                let offset = offset as usize;
+                buffer[..len].copy_from_slice(&data[offset..offset + len]);
+                Ok(len)
+            }
+            Self::File { file, .. } => file.read_at(offset, &mut buffer[..len]),
+        }
+    }
+    // Moves the data into a temporary file, once it grows too large for memory
+    fn reserve(&mut self, size: u64) -> std::io::Result<()> {
+        if let Self::Memory(data) = self {
+            if size > MAX_MEMORY_BUFFER_SIZE {
+                let file = TempFile::new()?;
+                file.write_all_at(0, data)?;
+                *self = Self::File {
+                    file,
+                    size: data.len() as _,
+                };
+            }
+        }
+        Ok(())
+    }
+    // NOTE: Writing past the end also clears the hole between old data and new data
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> std::io::Result<()> {
+        let end = offset
+            .checked_add(buffer.len() as _)
+            .ok_or(std::io::ErrorKind::InvalidInput)?;
+        self.reserve(end)?;
+        match self {
+            Self::Memory(data) => {
+                let (offset, end) = (offset as usize, end as usize);
+                if end > data.len() {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(buffer);
+            }
+            Self::File { file, size } => {
+                file.write_all_at(offset, buffer)?;
+                *size = (*size).max(end);
+            }
+        }
+        Ok(())
+    }
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.reserve(len)?;
+        match self {
+            Self::Memory(data) => data.resize(len as _, 0),
+            Self::File { file, size } => {
+                file.set_len(len)?;
+                *size = len;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ZipEditFileEntry {
+    stat: ZipEditStat,
+    parent: Weak<RwLock<ZipEditFolderEntry>>,
+    data: ZipEditData,
+    // Path the stored record was written with, the record is kept as long as the
+    // entry stays there
+    record_path: Option<String>,
+    times_changed: bool,
+}
+
+struct ZipEditFolderEntry {
+    stat: ZipEditStat,
+    parent: Weak<RwLock<ZipEditFolderEntry>>,
+    children: BTreeMap<CaselessString, ZipEditEntry>,
+    // Directory record and the path it was written with, only written for empty
+    // folders and ones having times of their own
+    record: Option<(String, Arc<ZipCentralDirRecord>)>,
+    times_changed: bool,
+}
+
+#[derive(Clone)]
+enum ZipEditEntry {
+    File(Arc<RwLock<ZipEditFileEntry>>),
+    Folder(Arc<RwLock<ZipEditFolderEntry>>),
+}
+
+impl ZipEditEntry {
+    fn is_dir(&self) -> bool {
+        matches!(self, Self::Folder(_))
+    }
+    fn as_ptr(&self) -> usize {
+        match self {
+            Self::File(f) => Arc::as_ptr(f) as _,
+            Self::Folder(f) => Arc::as_ptr(f) as _,
+        }
+    }
+    fn modify_stat<R>(&self, modify_fn: impl FnOnce(&mut ZipEditStat) -> R) -> R {
+        match self {
+            Self::File(f) => modify_fn(&mut f.write().unwrap().stat),
+            Self::Folder(f) => modify_fn(&mut f.write().unwrap().stat),
+        }
+    }
+    fn set_parent(&self, parent: &Arc<RwLock<ZipEditFolderEntry>>) {
+        match self {
+            Self::File(f) => f.write().unwrap().parent = Arc::downgrade(parent),
+            Self::Folder(f) => f.write().unwrap().parent = Arc::downgrade(parent),
+        }
+    }
+    fn get_file_stat_info(&self) -> FileStatInfo {
+        match self {
+            Self::File(f) => {
+                let index = Arc::as_ptr(f) as _;
+                let f = f.read().unwrap();
+                FileStatInfo {
+                    index,
+                    size: match &f.data {
+                        ZipEditData::Stored(record) => record.uncompressed_size,
+                        ZipEditData::Buffer(data) => data.len(),
+                    },
+                    is_dir: false,
+                    attributes: f.stat.attributes,
+                    creation_time: f.stat.creation_time,
+                    last_access_time: f.stat.last_access_time,
+                    last_write_time: f.stat.last_write_time,
+                }
+            }
+            Self::Folder(f) => {
+                let index = Arc::as_ptr(f) as _;
+                let f = f.read().unwrap();
+                FileStatInfo {
+                    index,
+                    size: 0,
+                    is_dir: true,
+                    attributes: f.stat.attributes | FileAttributes::DirectoryFile,
+                    creation_time: f.stat.creation_time,
+                    last_access_time: f.stat.last_access_time,
+                    last_write_time: f.stat.last_write_time,
+                }
+            }
+        }
+    }
+}
+
+fn remove_child(
+    children: &mut BTreeMap<CaselessString, ZipEditEntry>,
+    child_ptr: usize,
+) -> Option<ZipEditEntry> {
+    let key = children
+        .iter()
+        .find(|(_, v)| v.as_ptr() == child_ptr)
+        .map(|(k, _)| k.clone())?;
+    children.remove(&key)
+}
+
+struct ZipCommitState {
+    // Where data in front of the first local record (e.g. SFX stubs) ends
+    data_start: u64,
+    // Where local records end, which is also where the central directory starts
+    data_end: u64,
+    comment: Vec<u8>,
+    // Records left out of the tree (e.g. with empty paths), kept as they are
+    unplaced: Vec<Arc<ZipCentralDirRecord>>,
+}
+
+pub(super) struct ZipEdits {
+    root: Arc<RwLock<ZipEditFolderEntry>>,
+    config: ArchiveWriteConfig,
+    dirty: AtomicBool,
+    // Bumped whenever stored records may have changed, for invalidating what was
+    // derived from them
+    revision: AtomicU64,
+    // Whether commits left unreferenced data behind, which is only tracked if it is
+    // going to be reclaimed on unmount
+    uncompacted: AtomicBool,
+    // NOTE: Held by structural changes (creation, moving and deletion) and commits,
+    //       so that entries are always locked from parents to children
+    state: Mutex<ZipCommitState>,
+}
+
+impl ZipEdits {
+    /// Takes over entries of `cd`, which is left empty.
+    pub fn new(
+        cd: &mut ZipFolderEntry,
+        unplaced: Vec<ZipCentralDirRecord>,
+        eocd: &ZipEndOfCentralDirRecord,
+        data_start: u64,
+        config: &ArchiveWriteConfig,
+    ) -> Self {
+        fn convert_folder(
+            folder: ZipFolderEntry,
+            parent: Weak<RwLock<ZipEditFolderEntry>>,
+            path: &str,
+        ) -> Arc<RwLock<ZipEditFolderEntry>> {
+            Arc::new_cyclic(|this| {
+                let mut children = BTreeMap::new();
+                for (name, entry) in folder.children {
+                    let entry_path = if path.is_empty() {
+                        name.as_str().to_owned()
+                    } else {
+                        format!("{path}/{}", name.as_str())
+                    };
+                    let entry = match entry {
+                        ZipEntry::Folder(e) => ZipEditEntry::Folder(convert_folder(
+                            e,
+                            this.clone(),
+                            &entry_path,
+                        )),
+                        ZipEntry::File(e) => {
+                            ZipEditEntry::File(Arc::new(RwLock::new(ZipEditFileEntry {
+                                stat: ZipEditStat::from(&e.stat),
+                                parent: this.clone(),
+                                data: ZipEditData::Stored(Arc::new(e.data)),
+                                record_path: Some(entry_path),
+                                times_changed: false,
+                            })))
+                        }
+                    };
+                    children.insert(name, entry);
+                }
+                RwLock::new(ZipEditFolderEntry {
+                    stat: ZipEditStat::from(&folder.stat),
+                    parent,
+                    children,
+                    record: folder.record.map(|x| (path.to_owned(), Arc::new(x))),
+                    times_changed: false,
+                })
+            })
+        }
+
+        let folder = ZipFolderEntry {
+            children: std::mem::take(&mut cd.children),
+            index: cd.index,
+            stat: cd.stat,
+            record: cd.record.take(),
+        };
+        let root = convert_folder(folder, Weak::new(), "");
+        Self {
+            root,
+            config: config.clone(),
+            dirty: AtomicBool::new(false),
+            revision: AtomicU64::new(0),
+            uncompacted: AtomicBool::new(false),
+            state: Mutex::new(ZipCommitState {
+                data_start,
+                data_end: eocd.offset_central_dir,
+                comment: eocd.comment.clone(),
+                unplaced: unplaced.into_iter().map(Arc::new).collect(),
+            }),
+        }
+    }
+    // NOTE: Pending compaction counts as well, which keeps the archive open until
+    //       unmount
+    pub fn has_pending_changes(&self) -> bool {
+        self.dirty.load(Ordering::Acquire) || self.uncompacted.load(Ordering::Acquire)
+    }
+    fn set_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+        self.revision.fetch_add(1, Ordering::AcqRel);
+    }
+    pub fn get_revision(&self) -> u64 {
+        self.revision.load(Ordering::Acquire)
+    }
+    // Returns records of all committed entries
+    pub fn get_stored_records(&self) -> Vec<(String, Arc<ZipCentralDirRecord>)> {
+        fn collect_records(
+            folder: &ZipEditFolderEntry,
+            path: &str,
+            records: &mut Vec<(String, Arc<ZipCentralDirRecord>)>,
+        ) {
+            for (name, entry) in &folder.children {
+                let entry_path = if path.is_empty() {
+                    name.as_str().to_owned()
+                } else {
+                    format!("{path}/{}", name.as_str())
+                };
+                match entry {
+                    ZipEditEntry::File(f) => {
+                        if let ZipEditData::Stored(record) = &f.read().unwrap().data {
+                            records.push((entry_path, Arc::clone(record)));
+                        }
+                    }
+                    ZipEditEntry::Folder(f) => {
+                        collect_records(&f.read().unwrap(), &entry_path, records)
+                    }
+                }
+            }
+        }
+
+        let _state = self.state.lock().unwrap();
+        let mut records = Vec::new();
+        collect_records(&self.root.read().unwrap(), "", &mut records);
+        records
+    }
+    fn resolve_path<'s>(
+        &self,
+        path: SegPath<'s>,
+    ) -> FileSystemResult<(Option<Arc<RwLock<ZipEditFolderEntry>>>, &'s str)> {
+        let mut cur_dir = Arc::clone(&self.root);
+        let mut it = path.into_iter().peekable();
+        let mut non_empty = false;
+        let mut filename = "";
+        while let Some(path) = it.next() {
+            non_empty = true;
+            if it.peek().is_none() {
+                // Find in last level
+                filename = path;
+                break;
+            }
+            // Find the next folder
+            let next_dir = match cur_dir.read().unwrap().children.get(CaselessStr::new(path)) {
+                Some(ZipEditEntry::Folder(folder)) => Arc::clone(folder),
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            };
+            cur_dir = next_dir;
+        }
+        Ok((non_empty.then_some(cur_dir), filename))
+    }
+}
+
+// NOTE: Zip paths use slashes as delimiters
+fn check_entry_name(name: &str) -> FileSystemResult<()> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FileSystemError::ObjectNameInvalid);
+    }
+    Ok(())
+}
+
+impl ZipEdits {
+    pub fn create_file<'a>(
+        &'a self,
+        archive: &'a ZipArchive<'a>,
+        filename: SegPath,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> FileSystemResult<(ArchiveHandlerOpenFileInfo<'a>, bool)> {
+        use FileCreateDisposition::*;
+
+        let expects_dir = create_options.contains(FileCreateOptions::DirectoryFile);
+        let expects_nondir = create_options.contains(FileCreateOptions::NonDirectoryFile);
+        let delete_on_close = create_options.contains(FileCreateOptions::DeleteOnClose);
+
+        let _state = self.state.lock().unwrap();
+        let (parent, filename) = self.resolve_path(filename)?;
+
+        let mut new_file_created = false;
+        let entry = if let Some(parent) = parent {
+            let mut parent_guard = parent.write().unwrap();
+            match parent_guard.children.get(CaselessStr::new(filename)) {
+                Some(ZipEditEntry::File(file)) => {
+                    if expects_dir {
+                        return Err(FileSystemError::NotADirectory);
+                    }
+                    match create_disposition {
+                        CreateAlways | TruncateExisting => {
+                            let mut file = file.write().unwrap();
+                            file.data = ZipEditData::Buffer(Default::default());
+                            file.stat.last_write_time = SystemTime::now();
+                            self.set_dirty();
+                        }
+                        OpenAlways | OpenExisting => (),
+                        CreateNew => return Err(FileSystemError::ObjectNameCollision),
+                    }
+                    ZipEditEntry::File(Arc::clone(file))
+                }
+                Some(ZipEditEntry::Folder(folder)) => {
+                    if expects_nondir {
+                        return Err(FileSystemError::FileIsADirectory);
+                    }
+                    if matches!(create_disposition, CreateNew) {
+                        return Err(FileSystemError::ObjectNameCollision);
+                    }
+                    if delete_on_close && !folder.read().unwrap().children.is_empty() {
+                        return Err(FileSystemError::DirectoryNotEmpty);
+                    }
+                    ZipEditEntry::Folder(Arc::clone(folder))
+                }
+                None => match create_disposition {
+                    CreateAlways | CreateNew | OpenAlways => {
+                        check_entry_name(filename)?;
+                        new_file_created = true;
+                        let stat = ZipEditStat::new(SystemTime::now());
+                        let entry = if expects_dir {
+                            ZipEditEntry::Folder(Arc::new(RwLock::new(ZipEditFolderEntry {
+                                stat,
+                                parent: Arc::downgrade(&parent),
+                                children: BTreeMap::new(),
+                                record: None,
+                                times_changed: false,
+                            })))
+                        } else {
+                            ZipEditEntry::File(Arc::new(RwLock::new(ZipEditFileEntry {
+                                stat,
+                                parent: Arc::downgrade(&parent),
+                                data: ZipEditData::Buffer(Default::default()),
+                                record_path: None,
+                                times_changed: false,
+                            })))
+                        };
+                        parent_guard
+                            .children
+                            .insert(filename.to_owned().into(), entry.clone());
+                        self.set_dirty();
+                        entry
+                    }
+                    _ => return Err(FileSystemError::ObjectNameNotFound),
+                },
+            }
+        } else {
+            // Root folder
+            if expects_nondir {
+                return Err(FileSystemError::FileIsADirectory);
+            }
+            if delete_on_close {
+                return Err(FileSystemError::AccessDenied);
+            }
+            if matches!(create_disposition, CreateNew) {
+                return Err(FileSystemError::ObjectNameCollision);
+            }
+            ZipEditEntry::Folder(Arc::clone(&self.root))
+        };
+
+        entry.modify_stat(|stat| {
+            stat.open_count += 1;
+            stat.delete_on_close |= delete_on_close;
+        });
+
+        let is_dir = entry.is_dir();
+        Ok((
+            ArchiveHandlerOpenFileInfo {
+                context: Box::new(ZipEditFile {
+                    root: archive,
+                    edits: self,
+                    entry,
+                    reader: Mutex::new(None),
+                }),
+                is_dir,
+            },
+            new_file_created,
+        ))
+    }
+}
+
+pub(super) struct ZipEditFile<'a> {
+    root: &'a ZipArchive<'a>,
+    edits: &'a ZipEdits,
+    entry: ZipEditEntry,
+    // Reader of the stored data, reopened once the entry is committed elsewhere
+    reader: Mutex<Option<(Arc<ZipCentralDirRecord>, ZipFileReader<'a>)>>,
+}
+
+impl ZipEditFile<'_> {
+    // Turns stored data into a buffer, so that it can be modified
+    fn load_buffer<'e>(
+        &self,
+        file: &'e mut ZipEditFileEntry,
+    ) -> FileSystemResult<&'e mut ZipEditBuffer> {
+        if let ZipEditData::Stored(record) = &file.data {
+            let size = record.uncompressed_size;
+            let reader = self.root.open_entry_reader(record, self.root.crc_check)?;
+            let mut data = ZipEditBuffer::default();
+            data.reserve(size).map_err(anyhow::Error::from)?;
+            let mut buf = vec![0; COPY_CHUNK_SIZE.min(size as _)];
+            let mut pos = 0;
+            while pos < size {
+                let len = reader.read_at(self.root.source.as_file(), pos, &mut buf)?;
+                if len == 0 {
+                    return Err(FileSystemError::FileCorruptError);
+                }
+                data.write_at(pos, &buf[..len as usize])
+                    .map_err(anyhow::Error::from)?;
+                pos += len;
+            }
+            file.data = ZipEditData::Buffer(data);
+            file.record_path = None;
+        }
+        match &mut file.data {
+            ZipEditData::Buffer(data) => Ok(data),
+            ZipEditData::Stored(_) => unreachable!(),
+        }
+    }
+}
+
+impl ArchiveFile for ZipEditFile<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let ZipEditEntry::File(f) = &self.entry else {
+            return Err(FileSystemError::FileIsADirectory);
+        };
+        let f = f.read().unwrap();
+        match &f.data {
+            ZipEditData::Buffer(data) => {
+                Ok(data.read_at(offset, buffer).map_err(anyhow::Error::from)? as _)
+            }
+            ZipEditData::Stored(record) => {
+                let mut reader = self.reader.lock().unwrap();
+                let reader = match &mut *reader {
+                    Some((r, reader)) if Arc::ptr_eq(r, record) => reader,
+                    reader => {
+                        let new_reader =
+                            self.root.open_entry_reader(record, self.root.crc_check)?;
+                        &mut reader.insert((Arc::clone(record), new_reader)).1
+                    }
+                };
+                reader.read_at(self.root.source.as_file(), offset, buffer)
+            }
+        }
+    }
+    fn get_stat(&self) -> FileSystemResult<FileStatInfo> {
+        Ok(self.entry.get_file_stat_info())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn crate::fs_provider::FilePattern,
+        filler: &mut dyn crate::fs_provider::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let ZipEditEntry::Folder(f) = &self.entry else {
+            return Err(FileSystemError::NotADirectory);
+        };
+        let f = f.read().unwrap();
+        for (name, child) in f
+            .children
+            .iter()
+            .filter(|(name, _)| pattern.check_name(name.as_str()))
+        {
+            if filler
+                .fill_data(name.as_str(), &child.get_file_stat_info())
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> FileSystemResult<u64> {
+        let ZipEditEntry::File(f) = &self.entry else {
+            return Err(FileSystemError::FileIsADirectory);
+        };
+        let mut f = f.write().unwrap();
+        let data = self.load_buffer(&mut f)?;
+        let offset = offset.unwrap_or(data.len());
+        let len = if constrain_size {
+            if offset >= data.len() {
+                return Ok(0);
+            }
+            buffer.len().min((data.len() - offset) as _)
+        } else {
+            buffer.len()
+        };
+        offset
+            .checked_add(len as _)
+            .ok_or(FileSystemError::InvalidParameter)?;
+        data.write_at(offset, &buffer[..len])
+            .map_err(anyhow::Error::from)?;
+        f.stat.last_write_time = SystemTime::now();
+        self.edits.set_dirty();
+        Ok(len as _)
+    }
+    fn set_end_of_file(&self, offset: u64) -> FileSystemResult<()> {
+        let ZipEditEntry::File(f) = &self.entry else {
+            return Err(FileSystemError::FileIsADirectory);
+        };
+        let mut f = f.write().unwrap();
+        let data = self.load_buffer(&mut f)?;
+        data.set_len(offset).map_err(anyhow::Error::from)?;
+        f.stat.last_write_time = SystemTime::now();
+        self.edits.set_dirty();
+        Ok(())
+    }
+    fn set_file_times(
+        &self,
+        creation_time: SystemTime,
+        last_access_time: SystemTime,
+        last_write_time: SystemTime,
+    ) -> FileSystemResult<()> {
+        let modify_fn = |stat: &mut ZipEditStat| {
+            let zero_t = unsafe { std::mem::zeroed() };
+            let mut changed = false;
+            if creation_time != zero_t {
+                stat.creation_time = creation_time;
+                changed = true;
+            }
+            if last_access_time != zero_t {
+                stat.last_access_time = last_access_time;
+                changed = true;
+            }
+            if last_write_time != zero_t {
+                stat.last_write_time = last_write_time;
+                changed = true;
+            }
+            changed
+        };
+        let changed = match &self.entry {
+            ZipEditEntry::File(f) => {
+                let mut f = f.write().unwrap();
+                let changed = modify_fn(&mut f.stat);
+                f.times_changed |= changed;
+                changed
+            }
+            ZipEditEntry::Folder(f) => {
+                let mut f = f.write().unwrap();
+                let changed = modify_fn(&mut f.stat);
+                f.times_changed |= changed;
+                changed
+            }
+        };
+        if changed {
+            self.edits.set_dirty();
+        }
+        Ok(())
+    }
+    fn set_delete(&self, delete_on_close: bool) -> FileSystemResult<()> {
+        match &self.entry {
+            ZipEditEntry::File(f) => {
+                f.write().unwrap().stat.delete_on_close = delete_on_close;
+            }
+            ZipEditEntry::Folder(f) => {
+                if Arc::ptr_eq(f, &self.edits.root) {
+                    return Err(FileSystemError::AccessDenied);
+                }
+                let mut f = f.write().unwrap();
+                if delete_on_close && !f.children.is_empty() {
+                    return Err(FileSystemError::DirectoryNotEmpty);
+                }
+                f.stat.delete_on_close = delete_on_close;
+            }
+        }
+        Ok(())
+    }
+    fn move_to(&self, new_path: SegPath, replace_if_exists: bool) -> FileSystemResult<()> {
+        let _state = self.edits.state.lock().unwrap();
+
+        let (new_parent, filename) = self.edits.resolve_path(new_path)?;
+        let new_parent = new_parent.ok_or(FileSystemError::AccessDenied)?;
+        check_entry_name(filename)?;
+        let old_parent = match &self.entry {
+            ZipEditEntry::File(f) => f.read().unwrap().parent.upgrade(),
+            ZipEditEntry::Folder(f) => {
+                // Folders cannot be moved into themselves
+                let mut cur_dir = Some(Arc::clone(&new_parent));
+                while let Some(dir) = cur_dir {
+                    if Arc::ptr_eq(&dir, f) {
+                        return Err(FileSystemError::InvalidParameter);
+                    }
+                    cur_dir = dir.read().unwrap().parent.upgrade();
+                }
+                f.read().unwrap().parent.upgrade()
+            }
+        };
+        // NOTE: The root folder has no parent
+        let old_parent = old_parent.ok_or(FileSystemError::AccessDenied)?;
+
+        let child_ptr = self.entry.as_ptr();
+        let check_target_fn = |children: &BTreeMap<CaselessString, ZipEditEntry>| {
+            match children.get(CaselessStr::new(filename)) {
+                // Renaming to itself, possibly with a different case
+                Some(target) if target.as_ptr() == child_ptr => Ok(()),
+                Some(_) if !replace_if_exists => Err(FileSystemError::ObjectNameCollision),
+                Some(target) if target.is_dir() => Err(FileSystemError::AccessDenied),
+                _ => Ok(()),
+            }
+        };
+        let insert_fn = |children: &mut BTreeMap<CaselessString, ZipEditEntry>, entry| {
+            // NOTE: Remove first, as inserting keeps the key of an existing item
+            children.remove(CaselessStr::new(filename));
+            children.insert(filename.to_owned().into(), entry);
+        };
+        if Arc::ptr_eq(&old_parent, &new_parent) {
+            // Movement inside the same folder
+            let mut parent = new_parent.write().unwrap();
+            check_target_fn(&parent.children)?;
+            let entry = remove_child(&mut parent.children, child_ptr)
+                .ok_or(FileSystemError::AccessDenied)?;
+            insert_fn(&mut parent.children, entry);
+        } else {
+            // Movement between different folders
+            let mut old_parent = old_parent.write().unwrap();
+            let mut new_parent = new_parent.write().unwrap();
+            check_target_fn(&new_parent.children)?;
+            let entry = remove_child(&mut old_parent.children, child_ptr)
+                .ok_or(FileSystemError::AccessDenied)?;
+            insert_fn(&mut new_parent.children, entry);
+        }
+        self.entry.set_parent(&new_parent);
+        self.edits.set_dirty();
+        Ok(())
+    }
+}
+
+impl Drop for ZipEditFile<'_> {
+    fn drop(&mut self) {
+        // NOTE: Opening takes the state lock as well, so the entry cannot be opened
+        //       again while it is being deleted
+        let _state = self.edits.state.lock().unwrap();
+        let is_last_handle = self.entry.modify_stat(|stat| {
+            stat.open_count -= 1;
+            stat.open_count == 0
+        });
+        if !is_last_handle {
+            return;
+        }
+        let (delete_on_close, parent) = match &self.entry {
+            ZipEditEntry::File(f) => {
+                let mut f = f.write().unwrap();
+                let delete_on_close = std::mem::take(&mut f.stat.delete_on_close);
+                (delete_on_close, f.parent.upgrade())
+            }
+            ZipEditEntry::Folder(f) => {
+                let mut f = f.write().unwrap();
+                // NOTE: Entries may have been created inside meanwhile
+                let delete_on_close =
+                    std::mem::take(&mut f.stat.delete_on_close) && f.children.is_empty();
+                (delete_on_close, f.parent.upgrade())
+            }
+        };
+        if !delete_on_close {
+            return;
+        }
+        let Some(parent) = parent else {
+            return;
+        };
+        if remove_child(&mut parent.write().unwrap().children, self.entry.as_ptr()).is_some() {
+            self.edits.set_dirty();
+        }
+    }
+}
+
+// Writes sequentially into the archive file
+struct ZipRecordWriter<'a> {
+    file: &'a dyn crate::fs_provider::File,
+    pos: u64,
+}
+
+impl Write for ZipRecordWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.file.write_at(Some(self.pos), buf, false)?;
+        self.pos += len;
+        Ok(len as _)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn saturate_u32(value: u64) -> u32 {
+    value.min(u32::MAX as _) as _
+}
+
+// Removes all extra fields with the given header IDs
+fn strip_extra_fields(mut extra: &[u8], header_ids: &[u16]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(extra.len());
+    while extra.len() >= 4 {
+        let header_id = u16::from_le_bytes([extra[0], extra[1]]);
+        let data_size = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        let Some(field) = extra.get(..4 + data_size) else {
+            // Malformed, drop the rest
+            break;
+        };
+        if !header_ids.contains(&header_id) {
+            result.extend_from_slice(field);
+        }
+        extra = &extra[field.len()..];
+    }
+    result
+}
+
+fn make_ntfs_extra_field(stat: &ZipEditStat) -> Vec<u8> {
+    // SAFETY: SystemTime is FILETIME
+    let filetime = |t: SystemTime| unsafe { std::mem::transmute::<SystemTime, u64>(t) };
+    let mut field = Vec::with_capacity(36);
+    field.write_u16::<LittleEndian>(ZIP_LOCAL_EXTRA_HID_NTFS).unwrap();
+    field.write_u16::<LittleEndian>(32).unwrap();
+    // Reserved
+    field.write_u32::<LittleEndian>(0).unwrap();
+    // Tag 1 holds the times
+    field.write_u16::<LittleEndian>(0x0001).unwrap();
+    field.write_u16::<LittleEndian>(24).unwrap();
+    field
+        .write_u64::<LittleEndian>(filetime(stat.last_write_time))
+        .unwrap();
+    field
+        .write_u64::<LittleEndian>(filetime(stat.last_access_time))
+        .unwrap();
+    field
+        .write_u64::<LittleEndian>(filetime(stat.creation_time))
+        .unwrap();
+    field
+}
+
+// Returns DOS time and date, in the local timezone like the reader expects
+fn make_dos_time(t: SystemTime) -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+    let t = chrono::DateTime::<chrono::Local>::from(t);
+    if t.year() < 1980 {
+        // 1980-01-01 00:00:00
+        return (0, (1 << 5) | 1);
+    }
+    let time = (t.hour() << 11) | (t.minute() << 5) | (t.second() / 2);
+    let date = (((t.year() - 1980).min(127) as u32) << 9) | (t.month() << 5) | t.day();
+    (time as _, date as _)
+}
+
+fn write_local_header(
+    w: &mut impl Write,
+    record: &ZipCentralDirRecord,
+    extra: &[u8],
+) -> std::io::Result<()> {
+    let has_descriptor = (record.general_purpose_bitflag & ZIP_GPFLAGS_DATA_DESCRIPTOR) != 0;
+    let is_zip64 =
+        record.compressed_size >= u32::MAX as u64 || record.uncompressed_size >= u32::MAX as u64;
+    // NOTE: Sizes follow the data in a descriptor if present
+    let (crc32, compressed_size, uncompressed_size) = if has_descriptor {
+        (0, 0, 0)
+    } else {
+        (
+            record.uncompressed_data_crc32,
+            record.compressed_size,
+            record.uncompressed_size,
+        )
+    };
+    let mut extra = strip_extra_fields(extra, &[ZIP_LOCAL_EXTRA_HID_ZIP64]);
+    let min_extract_ver = if is_zip64 {
+        let mut field = Vec::with_capacity(20 + extra.len());
+        field.write_u16::<LittleEndian>(ZIP_LOCAL_EXTRA_HID_ZIP64)?;
+        field.write_u16::<LittleEndian>(16)?;
+        field.write_u64::<LittleEndian>(uncompressed_size)?;
+        field.write_u64::<LittleEndian>(compressed_size)?;
+        field.append(&mut extra);
+        extra = field;
+        record.min_extract_ver.max(ZIP_VERSION_ZIP64)
+    } else {
+        record.min_extract_ver
+    };
+    let saturate_fn = |value: u64| if is_zip64 { u32::MAX } else { value as u32 };
+
+    let mut buf = Vec::with_capacity(
+        ZIP_LOCAL_FILE_HEADER_SIZE as usize + record.file_name.len() + extra.len(),
+    );
+    buf.write_u32::<LittleEndian>(ZIP_LOCAL_HEADER_SIGNATURE)?;
+    buf.write_u16::<LittleEndian>(min_extract_ver)?;
+    buf.write_u16::<LittleEndian>(record.general_purpose_bitflag)?;
+    buf.write_u16::<LittleEndian>(record.compression_method)?;
+    buf.write_u16::<LittleEndian>(record.last_modify_time)?;
+    buf.write_u16::<LittleEndian>(record.last_modify_date)?;
+    buf.write_u32::<LittleEndian>(crc32)?;
+    buf.write_u32::<LittleEndian>(saturate_fn(compressed_size))?;
+    buf.write_u32::<LittleEndian>(saturate_fn(uncompressed_size))?;
+    buf.write_u16::<LittleEndian>(record.file_name.len() as _)?;
+    buf.write_u16::<LittleEndian>(extra.len() as _)?;
+    buf.extend_from_slice(&record.file_name);
+    buf.extend_from_slice(&extra);
+    w.write_all(&buf)
+}
+
+fn write_central_header(w: &mut impl Write, record: &ZipCentralDirRecord) -> std::io::Result<()> {
+    // NOTE: A field only goes into the Zip64 extra field if it is saturated
+    let mut zip64_field = Vec::new();
+    for value in [
+        record.uncompressed_size,
+        record.compressed_size,
+        record.local_file_header_offset,
+    ] {
+        if value >= u32::MAX as u64 {
+            zip64_field.write_u64::<LittleEndian>(value)?;
+        }
+    }
+    let mut extra = Vec::new();
+    let (made_by_ver, min_extract_ver) = if zip64_field.is_empty() {
+        (record.made_by_ver, record.min_extract_ver)
+    } else {
+        extra.write_u16::<LittleEndian>(ZIP_LOCAL_EXTRA_HID_ZIP64)?;
+        extra.write_u16::<LittleEndian>(zip64_field.len() as _)?;
+        extra.append(&mut zip64_field);
+        // NOTE: The upper byte of made_by_ver is the host system
+        let made_by_ver = (record.made_by_ver & 0xff00)
+            | (record.made_by_ver & 0xff).max(ZIP_VERSION_ZIP64);
+        (made_by_ver, record.min_extract_ver.max(ZIP_VERSION_ZIP64))
+    };
+    extra.extend(strip_extra_fields(
+        &record.extra_data,
+        &[ZIP_LOCAL_EXTRA_HID_ZIP64],
+    ));
+
+    w.write_u32::<LittleEndian>(ZIP_CD_HEADER_SIGNATURE)?;
+    w.write_u16::<LittleEndian>(made_by_ver)?;
+    w.write_u16::<LittleEndian>(min_extract_ver)?;
+    w.write_u16::<LittleEndian>(record.general_purpose_bitflag)?;
+    w.write_u16::<LittleEndian>(record.compression_method)?;
+    w.write_u16::<LittleEndian>(record.last_modify_time)?;
+    w.write_u16::<LittleEndian>(record.last_modify_date)?;
+    w.write_u32::<LittleEndian>(record.uncompressed_data_crc32)?;
+    w.write_u32::<LittleEndian>(saturate_u32(record.compressed_size))?;
+    w.write_u32::<LittleEndian>(saturate_u32(record.uncompressed_size))?;
+    w.write_u16::<LittleEndian>(record.file_name.len() as _)?;
+    w.write_u16::<LittleEndian>(extra.len() as _)?;
+    w.write_u16::<LittleEndian>(record.comment.len() as _)?;
+    // Single-file archives only have disk 0
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(record.internal_file_attributes)?;
+    w.write_u32::<LittleEndian>(record.external_file_attributes)?;
+    w.write_u32::<LittleEndian>(saturate_u32(record.local_file_header_offset))?;
+    w.write_all(&record.file_name)?;
+    w.write_all(&extra)?;
+    w.write_all(&record.comment)
+}
+
+fn write_end_of_central_dir(
+    w: &mut impl Write,
+    cd_offset: u64,
+    cd_size: u64,
+    count: u64,
+    comment: &[u8],
+) -> std::io::Result<()> {
+    let is_zip64 =
+        count >= u16::MAX as u64 || cd_size >= u32::MAX as u64 || cd_offset >= u32::MAX as u64;
+    if is_zip64 {
+        let end64_offset = cd_offset + cd_size;
+        w.write_u32::<LittleEndian>(ZIP_END64_SIGNATURE)?;
+        // Size of the remaining record
+        w.write_u64::<LittleEndian>(44)?;
+        w.write_u16::<LittleEndian>(ZIP_VERSION_ZIP64)?;
+        w.write_u16::<LittleEndian>(ZIP_VERSION_ZIP64)?;
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u64::<LittleEndian>(count)?;
+        w.write_u64::<LittleEndian>(count)?;
+        w.write_u64::<LittleEndian>(cd_size)?;
+        w.write_u64::<LittleEndian>(cd_offset)?;
+
+        w.write_u32::<LittleEndian>(ZIP_END64_LOCATOR_SIGNATURE)?;
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u64::<LittleEndian>(end64_offset)?;
+        w.write_u32::<LittleEndian>(1)?;
+    }
+    let count = count.min(u16::MAX as _) as u16;
+    w.write_u32::<LittleEndian>(ZIP_END_SIGNATURE)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(0)?;
+    w.write_u16::<LittleEndian>(count)?;
+    w.write_u16::<LittleEndian>(count)?;
+    w.write_u32::<LittleEndian>(saturate_u32(cd_size))?;
+    w.write_u32::<LittleEndian>(saturate_u32(cd_offset))?;
+    w.write_u16::<LittleEndian>(comment.len() as _)?;
+    w.write_all(comment)
+}
+
+// Returns the central directory holding `records`, including its end records
+fn make_central_dir(
+    records: &[Arc<ZipCentralDirRecord>],
+    cd_offset: u64,
+    comment: &[u8],
+) -> FileSystemResult<Vec<u8>> {
+    let mut cd = Vec::new();
+    for record in records {
+        write_central_header(&mut cd, record).map_err(anyhow::Error::from)?;
+    }
+    let cd_size = cd.len() as u64;
+    write_end_of_central_dir(&mut cd, cd_offset, cd_size, records.len() as _, comment)
+        .map_err(anyhow::Error::from)?;
+    Ok(cd)
+}
+
+// Writes the central directory at `cd_offset`, which also becomes the end of the file
+fn write_central_dir(
+    file: &dyn crate::fs_provider::File,
+    cd_offset: u64,
+    cd: &[u8],
+) -> FileSystemResult<()> {
+    let mut w = ZipRecordWriter {
+        file,
+        pos: cd_offset,
+    };
+    w.write_all(cd).map_err(anyhow::Error::from)?;
+    // NOTE: Only drop what follows once the new central directory is on disk
+    file.flush_buffers()?;
+    file.set_end_of_file(w.pos)?;
+    file.flush_buffers()
+}
+
+struct ZipLocalRecordInfo {
+    // Size of the header, including the file name and extra data
+    header_size: u64,
+    extra: Vec<u8>,
+}
+
+fn read_local_record_info(
+    file: &dyn crate::fs_provider::File,
+    record: &ZipCentralDirRecord,
+) -> FileSystemResult<ZipLocalRecordInfo> {
+    let mut buf = [0; ZIP_LOCAL_FILE_HEADER_SIZE as _];
+    file.read_at_exact(record.local_file_header_offset, &mut buf)?;
+    let mut buf_view = buf.as_slice();
+    if buf_view.read_u32::<LittleEndian>().unwrap() != ZIP_LOCAL_HEADER_SIGNATURE {
+        return Err(anyhow::anyhow!("invalid signature for Local File record").into());
+    }
+    let len_file_name = u16::from_le_bytes([buf[26], buf[27]]) as u64;
+    let len_extra_data = u16::from_le_bytes([buf[28], buf[29]]) as u64;
+    let mut extra = vec![0; len_extra_data as _];
+    file.read_at_exact(
+        record.local_file_header_offset + ZIP_LOCAL_FILE_HEADER_SIZE as u64 + len_file_name,
+        &mut extra,
+    )?;
+    Ok(ZipLocalRecordInfo {
+        header_size: ZIP_LOCAL_FILE_HEADER_SIZE as u64 + len_file_name + len_extra_data,
+        extra,
+    })
+}
+
+// Returns the size of the data descriptor following the data, if any
+fn get_data_descriptor_size(
+    file: &dyn crate::fs_provider::File,
+    record: &ZipCentralDirRecord,
+    info: &ZipLocalRecordInfo,
+) -> FileSystemResult<u64> {
+    if (record.general_purpose_bitflag & ZIP_GPFLAGS_DATA_DESCRIPTOR) == 0 {
+        return Ok(0);
+    }
+    // NOTE: The signature is optional
+    let mut signature = [0; 4];
+    file.read_at_exact(
+        record.local_file_header_offset + info.header_size + record.compressed_size,
+        &mut signature,
+    )?;
+    let signature_size = if u32::from_le_bytes(signature) == ZIP_DATA_DESCRIPTOR_SIGNATURE {
+        4
+    } else {
+        0
+    };
+    let is_zip64 = find_extra_field(&info.extra, ZIP_LOCAL_EXTRA_HID_ZIP64)
+        .map_err(FileSystemError::from)?
+        .is_some();
+    Ok(signature_size + if is_zip64 { 20 } else { 12 })
+}
+
+fn copy_within_file(
+    file: &dyn crate::fs_provider::File,
+    w: &mut ZipRecordWriter,
+    offset: u64,
+    size: u64,
+) -> FileSystemResult<()> {
+    let mut buf = vec![0; COPY_CHUNK_SIZE.min(size as _)];
+    let mut copied = 0;
+    while copied < size {
+        let len = buf.len().min((size - copied) as _);
+        file.read_at_exact(offset + copied, &mut buf[..len])?;
+        w.write_all(&buf[..len]).map_err(anyhow::Error::from)?;
+        copied += len as u64;
+    }
+    Ok(())
+}
+
+// Writes a new local record for data of an existing one
+fn write_moved_record(
+    file: &dyn crate::fs_provider::File,
+    w: &mut ZipRecordWriter,
+    record: &ZipCentralDirRecord,
+    path: &str,
+) -> FileSystemResult<ZipCentralDirRecord> {
+    let info = read_local_record_info(file, record)?;
+    // NOTE: Moved entries always get UTF-8 names
+    let mut new_record = record.clone();
+    new_record.file_name = path.as_bytes().to_vec();
+    new_record.general_purpose_bitflag |= ZIP_GPFLAGS_EFS;
+    new_record.extra_data =
+        strip_extra_fields(&record.extra_data, &[ZIP_LOCAL_EXTRA_HID_UNICODE_PATH]);
+    new_record.local_file_header_offset = w.pos;
+    let extra = strip_extra_fields(&info.extra, &[ZIP_LOCAL_EXTRA_HID_UNICODE_PATH]);
+    write_local_header(w, &new_record, &extra).map_err(anyhow::Error::from)?;
+    copy_within_file(
+        file,
+        w,
+        record.local_file_header_offset + info.header_size,
+        record.compressed_size,
+    )?;
+    if (record.general_purpose_bitflag & ZIP_GPFLAGS_DATA_DESCRIPTOR) != 0 {
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.write_u32::<LittleEndian>(ZIP_DATA_DESCRIPTOR_SIGNATURE).unwrap();
+        descriptor.write_u32::<LittleEndian>(record.uncompressed_data_crc32).unwrap();
+        if record.compressed_size >= u32::MAX as u64 || record.uncompressed_size >= u32::MAX as u64
+        {
+            descriptor.write_u64::<LittleEndian>(record.compressed_size).unwrap();
+            descriptor.write_u64::<LittleEndian>(record.uncompressed_size).unwrap();
+        } else {
+            descriptor.write_u32::<LittleEndian>(record.compressed_size as _).unwrap();
+            descriptor.write_u32::<LittleEndian>(record.uncompressed_size as _).unwrap();
+        }
+        w.write_all(&descriptor).map_err(anyhow::Error::from)?;
+    }
+    Ok(new_record)
+}
+
+// MS-DOS attributes of a new record, so that ones of the entry are kept
+fn make_dos_attributes(attributes: FileAttributes) -> u32 {
+    let mut dos_attributes = 0;
+    if attributes.contains(FileAttributes::Readonly) {
+        dos_attributes |= ZIP_DOS_ATTRIBUTE_READONLY;
+    }
+    if attributes.contains(FileAttributes::Hidden) {
+        dos_attributes |= ZIP_DOS_ATTRIBUTE_HIDDEN;
+    }
+    dos_attributes
+}
+
+// Writes a new local record with `data`, compressed if it helps
+// NOTE: New records are made by MS-DOS, so Unix modes and owner ids (0x7875) of
+//       the record replaced are not carried over
+fn write_new_record(
+    w: &mut ZipRecordWriter,
+    path: &str,
+    data: Option<&ZipEditBuffer>,
+    stat: &ZipEditStat,
+) -> FileSystemResult<ZipCentralDirRecord> {
+    let (last_modify_time, last_modify_date) = make_dos_time(stat.last_write_time);
+    let mut record = ZipCentralDirRecord {
+        made_by_ver: ZIP_VERSION_DEFAULT,
+        min_extract_ver: ZIP_VERSION_DEFAULT,
+        general_purpose_bitflag: ZIP_GPFLAGS_EFS,
+        compression_method: ZIP_COMPRESSION_STORE,
+        last_modify_time,
+        last_modify_date,
+        uncompressed_data_crc32: 0,
+        compressed_size: 0,
+        uncompressed_size: 0,
+        file_name: path.as_bytes().to_vec(),
+        extra_data: make_ntfs_extra_field(stat),
+        comment: Vec::new(),
+        num_disk_start: 0,
+        internal_file_attributes: 0,
+        external_file_attributes: ZIP_DOS_ATTRIBUTE_ARCHIVE | make_dos_attributes(stat.attributes),
+        local_file_header_offset: w.pos,
+        zip64_error: None,
+    };
+    let Some(data) = data else {
+        // Directory record
+        record.file_name.push(b'/');
+        record.external_file_attributes =
+            ZIP_DOS_ATTRIBUTE_DIRECTORY | make_dos_attributes(stat.attributes);
+        write_local_header(w, &record, &record.extra_data).map_err(anyhow::Error::from)?;
+        return Ok(record);
+    };
+
+    // NOTE: Data may be staged in a temporary file, so it is compressed in chunks and
+    //       the local header is written again afterwards. Deflated data is only kept
+    //       if it is smaller, so the header keeps its size.
+    let header_pos = w.pos;
+    record.uncompressed_size = data.len();
+    record.compressed_size = data.len();
+    write_local_header(w, &record, &record.extra_data).map_err(anyhow::Error::from)?;
+    let data_pos = w.pos;
+    let mut crc = flate2::Crc::new();
+    let mut encoder = flate2::write::DeflateEncoder::new(&mut *w, flate2::Compression::default());
+    for_each_buffer_chunk(data, |chunk| {
+        crc.update(chunk);
+        encoder.write_all(chunk)
+    })?;
+    encoder.finish().map_err(anyhow::Error::from)?;
+    if w.pos - data_pos < data.len() {
+        record.compression_method = ZIP_COMPRESSION_DEFLATE;
+        record.compressed_size = w.pos - data_pos;
+    } else {
+        w.pos = data_pos;
+        for_each_buffer_chunk(data, |chunk| w.write_all(chunk))?;
+    }
+    record.uncompressed_data_crc32 = crc.sum();
+    let mut header_w = ZipRecordWriter {
+        file: w.file,
+        pos: header_pos,
+    };
+    write_local_header(&mut header_w, &record, &record.extra_data)
+        .map_err(anyhow::Error::from)?;
+    Ok(record)
+}
+
+fn for_each_buffer_chunk(
+    data: &ZipEditBuffer,
+    mut chunk_fn: impl FnMut(&[u8]) -> std::io::Result<()>,
+) -> FileSystemResult<()> {
+    let mut buf = vec![0; COPY_CHUNK_SIZE.min(data.len() as _)];
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data.read_at(pos, &mut buf).map_err(anyhow::Error::from)?;
+        if len == 0 {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        chunk_fn(&buf[..len]).map_err(anyhow::Error::from)?;
+        pos += len as u64;
+    }
+    Ok(())
+}
+
+// Returns a copy of `record` with times from `stat`
+fn update_record_times(record: &ZipCentralDirRecord, stat: &ZipEditStat) -> ZipCentralDirRecord {
+    let mut record = record.clone();
+    // NOTE: ZipCrypto may check the password against the DOS time
+    if (record.general_purpose_bitflag & ZIP_GPFLAGS_ENCRYPTED) == 0 {
+        (record.last_modify_time, record.last_modify_date) = make_dos_time(stat.last_write_time);
+    }
+    // NOTE: Unix times would take precedence over the DOS time elsewhere
+    record.extra_data = strip_extra_fields(
+        &record.extra_data,
+        &[
+            ZIP_LOCAL_EXTRA_HID_NTFS,
+            ZIP_LOCAL_EXTRA_HID_EXTENDED_TIMESTAMP,
+        ],
+    );
+    record.extra_data.extend(make_ntfs_extra_field(stat));
+    record
+}
+
+impl ZipEdits {
+    pub fn commit(&self, archive: &ZipArchive, is_final: bool) -> FileSystemResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let compact = is_final && self.uncompacted.load(Ordering::Acquire);
+        if !self.dirty.swap(false, Ordering::AcqRel) && !compact {
+            return Ok(());
+        }
+        let result = self.commit_locked(archive, &mut state, compact);
+        self.revision.fetch_add(1, Ordering::AcqRel);
+        match &result {
+            Ok(()) if compact => self.uncompacted.store(false, Ordering::Release),
+            Ok(()) => {
+                if self.config.compact_on_unmount {
+                    self.uncompacted.store(true, Ordering::Release);
+                }
+            }
+            Err(e) => {
+                log::warn!("zip: commit failed: {e}");
+                self.set_dirty();
+            }
+        }
+        result
+    }
+    fn commit_locked(
+        &self,
+        archive: &ZipArchive,
+        state: &mut ZipCommitState,
+        compact: bool,
+    ) -> FileSystemResult<()> {
+        fn collect_entries(
+            folder: &ZipEditFolderEntry,
+            path: &str,
+            entries: &mut Vec<(String, ZipEditEntry)>,
+        ) {
+            for (name, entry) in &folder.children {
+                let entry_path = if path.is_empty() {
+                    name.as_str().to_owned()
+                } else {
+                    format!("{path}/{}", name.as_str())
+                };
+                match entry {
+                    ZipEditEntry::File(_) => entries.push((entry_path, entry.clone())),
+                    ZipEditEntry::Folder(f) => {
+                        let f = f.read().unwrap();
+                        // NOTE: Non-empty folders only need a record of their own
+                        //       for keeping their times
+                        if f.children.is_empty() || f.record.is_some() || f.times_changed {
+                            entries.push((entry_path.clone(), entry.clone()));
+                        }
+                        collect_entries(&f, &entry_path, entries);
+                    }
+                }
+            }
+        }
+
+        let file = archive.source.as_file();
+        let mut entries = Vec::new();
+        collect_entries(&self.root.read().unwrap(), "", &mut entries);
+
+        // Write local records of new and moved entries
+        // NOTE: They go after the end of the file, so that the current central
+        //       directory stays intact until the new one is written
+        let old_data_end = state.data_end;
+        let append_start = file.get_stat()?.size.max(state.data_end);
+        let mut records = Vec::with_capacity(entries.len());
+        let mut w = ZipRecordWriter {
+            file,
+            pos: append_start,
+        };
+        for (path, entry) in &entries {
+            let record = match entry {
+                ZipEditEntry::File(f) => {
+                    let mut f = f.write().unwrap();
+                    let mut record = match &f.data {
+                        ZipEditData::Stored(record) if f.record_path.as_ref() == Some(path) => {
+                            Arc::clone(record)
+                        }
+                        ZipEditData::Stored(record) => {
+                            Arc::new(write_moved_record(file, &mut w, record, path)?)
+                        }
+                        ZipEditData::Buffer(data) => {
+                            Arc::new(write_new_record(&mut w, path, Some(data), &f.stat)?)
+                        }
+                    };
+                    if f.times_changed {
+                        record = Arc::new(update_record_times(&record, &f.stat));
+                        f.times_changed = false;
+                    }
+                    f.data = ZipEditData::Stored(Arc::clone(&record));
+                    f.record_path = Some(path.clone());
+                    record
+                }
+                ZipEditEntry::Folder(f) => {
+                    let mut f = f.write().unwrap();
+                    let mut record = match &f.record {
+                        Some((record_path, record)) if record_path == path => Arc::clone(record),
+                        Some((_, record)) => Arc::new(write_moved_record(
+                            file,
+                            &mut w,
+                            record,
+                            &format!("{path}/"),
+                        )?),
+                        None => Arc::new(write_new_record(&mut w, path, None, &f.stat)?),
+                    };
+                    if f.times_changed {
+                        record = Arc::new(update_record_times(&record, &f.stat));
+                        f.times_changed = false;
+                    }
+                    f.record = Some((path.clone(), Arc::clone(&record)));
+                    record
+                }
+            };
+            // NOTE: Written records are referenced from now on, so never overwrite them
+            state.data_end = w.pos;
+            records.push(record);
+        }
+        state.data_end = w.pos;
+        records.extend(state.unplaced.iter().cloned());
+        let cd = make_central_dir(&records, state.data_end, &state.comment)?;
+        write_central_dir(file, state.data_end, &cd)?;
+        log::debug!(
+            "zip: committed {} entries, central directory at {}",
+            records.len(),
+            state.data_end
+        );
+
+        // Now that the archive is complete again, space in front of the appended data
+        // can be reclaimed
+        let is_moved = if compact {
+            let is_moved = self.compact(file, state, &entries, &mut records)?;
+            state.unplaced = records[entries.len()..].to_vec();
+            is_moved
+        } else {
+            self.move_appended(
+                file,
+                state,
+                &entries,
+                &mut records,
+                old_data_end,
+                append_start,
+            )?
+        };
+        if is_moved {
+            let cd = make_central_dir(&records, state.data_end, &state.comment)?;
+            write_central_dir(file, state.data_end, &cd)?;
+            // Offsets of moved records may now belong to other entries
+            if let Some(cache) = archive.cache {
+                cache.clear();
+            }
+            log::debug!("zip: moved central directory to {}", state.data_end);
+        }
+        Ok(())
+    }
+    // Moves records appended by a commit down to where the previous central directory
+    // started, if they fit in there without touching the appended data
+    fn move_appended(
+        &self,
+        file: &dyn crate::fs_provider::File,
+        state: &mut ZipCommitState,
+        entries: &[(String, ZipEditEntry)],
+        records: &mut [Arc<ZipCentralDirRecord>],
+        old_data_end: u64,
+        append_start: u64,
+    ) -> FileSystemResult<bool> {
+        let shift = append_start - old_data_end;
+        let appended_size = state.data_end - append_start;
+        let mut moved_records = records.to_vec();
+        for record in &mut moved_records {
+            if record.local_file_header_offset >= append_start {
+                let mut moved = (**record).clone();
+                moved.local_file_header_offset -= shift;
+                *record = Arc::new(moved);
+            }
+        }
+        let new_data_end = old_data_end + appended_size;
+        let cd = make_central_dir(&moved_records, new_data_end, &state.comment)?;
+        if shift == 0 || new_data_end + cd.len() as u64 > append_start {
+            return Ok(false);
+        }
+        let mut w = ZipRecordWriter {
+            file,
+            pos: old_data_end,
+        };
+        copy_within_file(file, &mut w, append_start, appended_size)?;
+        records.clone_from_slice(&moved_records);
+        sync_tree_records(entries, records);
+        state.data_end = new_data_end;
+        Ok(true)
+    }
+    // Moves live local records towards the start to reclaim unreferenced space,
+    // returning whether anything was moved
+    // WARN: Readers of stored data are invalidated, so only do this when no file
+    //       inside the archive is open. Records are overwritten in place, so a crash
+    //       meanwhile leaves them damaged.
+    fn compact(
+        &self,
+        file: &dyn crate::fs_provider::File,
+        state: &mut ZipCommitState,
+        entries: &[(String, ZipEditEntry)],
+        records: &mut [Arc<ZipCentralDirRecord>],
+    ) -> FileSystemResult<bool> {
+        let mut spans = Vec::with_capacity(records.len());
+        for (index, record) in records.iter().enumerate() {
+            let info = read_local_record_info(file, record)?;
+            let size = info.header_size
+                + record.compressed_size
+                + get_data_descriptor_size(file, record, &info)?;
+            spans.push((record.local_file_header_offset, size, index));
+        }
+        let live_size: u64 = spans.iter().map(|x| x.1).sum();
+        if live_size >= state.data_end - state.data_start {
+            return Ok(false);
+        }
+        log::debug!(
+            "zip: compacting {} bytes of records into {live_size} bytes",
+            state.data_end - state.data_start
+        );
+
+        // NOTE: Records only move towards the start, so copying them in order never
+        //       overwrites data yet to be copied
+        spans.sort_unstable_by_key(|x| x.0);
+        let mut w = ZipRecordWriter {
+            file,
+            pos: state.data_start,
+        };
+        for (offset, size, index) in spans {
+            let new_offset = w.pos;
+            if offset != new_offset {
+                copy_within_file(file, &mut w, offset, size)?;
+                let mut record = (*records[index]).clone();
+                record.local_file_header_offset = new_offset;
+                records[index] = Arc::new(record);
+            } else {
+                w.pos += size;
+            }
+        }
+        state.data_end = w.pos;
+        sync_tree_records(entries, records);
+        Ok(true)
+    }
+}
+
+// Keeps the tree in sync with records moved within the archive
+// NOTE: Records of unplaced entries follow those of the tree
+fn sync_tree_records(entries: &[(String, ZipEditEntry)], records: &[Arc<ZipCentralDirRecord>]) {
+    for ((_, entry), record) in entries.iter().zip(records.iter()) {
+        match entry {
+            ZipEditEntry::File(f) => {
+                f.write().unwrap().data = ZipEditData::Stored(Arc::clone(record))
+            }
+            ZipEditEntry::Folder(f) => {
+                if let Some((_, r)) = &mut f.write().unwrap().record {
+                    *r = Arc::clone(record);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,424 @@
+// Read-only ext2/3/4 disk image provider
+// NOTE: Images may either be a bare volume or a whole disk with an MBR / GPT partition
+//       table. Symbolic links are exposed as regular files holding their target, while
+//       devices, FIFOs and sockets show up as empty files.
+// TODO: Replay the journal of volumes that were not cleanly unmounted
+
+mod dir;
+mod inode;
+mod volume;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::{uuid, Uuid};
+
+use super::{
+    FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
+    FileSystemError, FileSystemHandler, FileSystemResult, FsWithPathConfig, SegPath,
+};
+
+use dir::{DirBlockEntries, HtreeParams};
+use inode::{DataLayout, Inode, INODE_BLOCK_SIZE};
+use volume::ExtVolume;
+
+pub const EXTFS_ID: Uuid = uuid!("C3B1E6F2-7D49-4A8E-9B05-2E6D8F1A4C73");
+
+const ROOT_INO: u32 = 2;
+// NOTE: Listings are built from whole directory files, larger ones are deemed corrupt
+const MAX_DIRECTORY_SIZE: u64 = 256 * 1024 * 1024;
+
+type DirListing = BTreeMap<String, u32>;
+
+struct ExtFsHandler {
+    volume: ExtVolume,
+    htree: HtreeParams,
+    // NOTE: Filled as directories get enumerated, keyed by inode number
+    listings: RwLock<HashMap<u32, Arc<DirListing>>>,
+    // NOTE: Backs the image file of `volume`, see `partition::open_image`
+    _image_fs: Arc<dyn FileSystemHandler>,
+}
+
+struct ExtFsFile<'h> {
+    handler: &'h ExtFsHandler,
+    ino: u32,
+    inode: Inode,
+    // NOTE: Only loaded for files with data
+    layout: Option<DataLayout>,
+}
+
+fn get_file_stat_info(ino: u32, inode: &Inode) -> super::FileStatInfo {
+    let is_dir = inode.is_dir();
+    super::FileStatInfo {
+        index: ino as u64,
+        size: if is_dir || !inode.has_data() {
+            0
+        } else {
+            inode.size
+        },
+        is_dir,
+        attributes: if is_dir {
+            FileAttributes::DirectoryFile
+        } else {
+            FileAttributes::empty()
+        },
+        creation_time: inode.creation_time,
+        last_access_time: inode.last_access_time,
+        last_write_time: inode.last_write_time,
+    }
+}
+
+impl ExtFsHandler {
+    fn new(
+        image_fs: Arc<dyn FileSystemHandler>,
+        image_path: &str,
+        partition: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        // SAFETY: `image_fs` ends up in the handler, declared after `volume`
+        let image = unsafe { super::partition::open_image(image_fs.as_ref(), image_path, false)? };
+        let image_size = image.get_stat()?.size;
+        let is_volume = |offset: u64, _size: u64| volume::is_ext_volume(image.as_ref(), offset);
+        let (offset, size) =
+            super::partition::select_volume(image.as_ref(), image_size, partition, is_volume)?;
+
+        let volume = ExtVolume::new(image, offset, size)?;
+        let sb = &volume.sb;
+        log::debug!(
+            "Found ext volume `{}` with {} blocks of {} bytes",
+            sb.volume_name,
+            sb.blocks_count,
+            sb.block_size
+        );
+        if sb.needs_recovery() {
+            log::warn!("ext journal needs recovery, recent changes may be missing");
+        }
+        if !Inode::parse(&volume.read_inode(ROOT_INO)?)?.is_dir() {
+            anyhow::bail!("root inode is not a directory");
+        }
+        let htree = HtreeParams {
+            seed: sb.hash_seed,
+            unsigned_hash: sb.unsigned_hash,
+            largedir: sb.has_largedir(),
+        };
+        Ok(Self {
+            volume,
+            htree,
+            listings: RwLock::new(HashMap::new()),
+            _image_fs: image_fs,
+        })
+    }
+
+    fn read_inode(&self, ino: u32) -> FileSystemResult<Inode> {
+        Inode::parse(&self.volume.read_inode(ino)?)
+    }
+
+    fn load_listing(&self, ino: u32, inode: &Inode) -> FileSystemResult<Arc<DirListing>> {
+        if let Some(listing) = self.listings.read().unwrap().get(&ino) {
+            return Ok(listing.clone());
+        }
+        if inode.size > MAX_DIRECTORY_SIZE {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let layout = DataLayout::load(&self.volume, inode)?;
+        let mut data = vec![0; inode.size as usize];
+        layout.read(&self.volume, 0, &mut data)?;
+        // NOTE: Inline directories start with the parent inode number, and continue
+        //       in the extended attribute area
+        let chunks: Vec<&[u8]> = match layout {
+            DataLayout::Inline(_) => {
+                let split = INODE_BLOCK_SIZE.min(data.len());
+                vec![data.get(4..split).unwrap_or_default(), &data[split..]]
+            }
+            DataLayout::Blocks(_) => data.chunks(self.volume.sb.block_size as usize).collect(),
+        };
+        let mut listing = DirListing::new();
+        for chunk in chunks {
+            for entry in DirBlockEntries::new(chunk) {
+                let (name, child) = match entry {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::warn!("Skipping corrupted block of directory {ino}: {e}");
+                        break;
+                    }
+                };
+                let name = String::from_utf8_lossy(name);
+                if name.contains(['/', '\\', '\0']) {
+                    log::warn!("Skipping entry with invalid name `{name}`");
+                    continue;
+                }
+                if listing.contains_key(name.as_ref()) {
+                    log::warn!("Skipping duplicate entry `{name}`");
+                    continue;
+                }
+                listing.insert(name.into_owned(), child);
+            }
+        }
+        let listing = Arc::new(listing);
+        self.listings.write().unwrap().insert(ino, listing.clone());
+        Ok(listing)
+    }
+
+    fn lookup(&self, dir: u32, dir_inode: &Inode, name: &str) -> FileSystemResult<Option<u32>> {
+        // NOTE: Use the htree index for directories that have not been listed yet.
+        //       Casefolded directories hash differently, and lossily decoded names
+        //       cannot be hashed at all.
+        let cached = self.listings.read().unwrap().get(&dir).cloned();
+        if cached.is_none()
+            && dir_inode.is_indexed()
+            && self.volume.sb.has_dir_index()
+            && !dir_inode.is_casefolded()
+            && !dir_inode.has_inline_data()
+            && !name.contains(char::REPLACEMENT_CHARACTER)
+        {
+            let layout = DataLayout::load(&self.volume, dir_inode)?;
+            let block_size = self.volume.sb.block_size as usize;
+            let result = dir::htree_lookup(name.as_bytes(), &self.htree, block_size, |block, buf| {
+                let offset = block as u64 * block_size as u64;
+                if offset >= dir_inode.size {
+                    return Err(FileSystemError::FileCorruptError);
+                }
+                layout.read(&self.volume, offset, buf)
+            });
+            match result {
+                Ok(x) => return Ok(x),
+                Err(e) => log::debug!("htree lookup in directory {dir} failed: {e}"),
+            }
+        }
+        let listing = match cached {
+            Some(x) => x,
+            None => self.load_listing(dir, dir_inode)?,
+        };
+        Ok(listing.get(name).copied())
+    }
+
+    fn resolve_path(&self, path: SegPath) -> FileSystemResult<(u32, Inode)> {
+        let mut cur = (ROOT_INO, self.read_inode(ROOT_INO)?);
+        let mut it = path.into_iter().peekable();
+        while let Some(segment) = it.next() {
+            let is_last = it.peek().is_none();
+            let child = match self.lookup(cur.0, &cur.1, segment)? {
+                Some(x) => x,
+                None if is_last => return Err(FileSystemError::ObjectNameNotFound),
+                None => return Err(FileSystemError::ObjectPathNotFound),
+            };
+            let inode = self.read_inode(child)?;
+            if !is_last && !inode.is_dir() {
+                return Err(FileSystemError::ObjectPathNotFound);
+            }
+            cur = (child, inode);
+        }
+        Ok(cur)
+    }
+}
+
+impl FileSystemHandler for ExtFsHandler {
+    fn create_file(
+        &self,
+        filename: SegPath,
+        desired_access: FileDesiredAccess,
+        _file_attributes: FileAttributes,
+        _share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> FileSystemResult<super::CreateFileInfo<'_>> {
+        use FileCreateDisposition::*;
+
+        const UNWANTED_ACCESS: FileDesiredAccess =
+            FileDesiredAccess::Full.union(FileDesiredAccess::Write);
+
+        let (ino, inode) = match self.resolve_path(filename) {
+            Ok(x) => x,
+            Err(FileSystemError::ObjectNameNotFound)
+                if matches!(create_disposition, CreateNew | CreateAlways | OpenAlways) =>
+            {
+                return Err(FileSystemError::AccessDenied);
+            }
+            Err(e) => return Err(e),
+        };
+        let is_dir = inode.is_dir();
+        if create_options.contains(FileCreateOptions::DirectoryFile) && !is_dir {
+            return Err(FileSystemError::NotADirectory);
+        }
+        if create_options.contains(FileCreateOptions::NonDirectoryFile) && is_dir {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        match create_disposition {
+            CreateNew => return Err(FileSystemError::ObjectNameCollision),
+            CreateAlways | TruncateExisting => return Err(FileSystemError::AccessDenied),
+            OpenExisting | OpenAlways => {}
+        }
+        if desired_access.intersects(UNWANTED_ACCESS)
+            || create_options.contains(FileCreateOptions::DeleteOnClose)
+        {
+            return Err(FileSystemError::AccessDenied);
+        }
+        // TODO: Support encrypted files once keys can be configured
+        if inode.is_encrypted() && !is_dir {
+            return Err(FileSystemError::AccessDenied);
+        }
+
+        let layout = match !is_dir && inode.has_data() {
+            true => Some(DataLayout::load(&self.volume, &inode)?),
+            false => None,
+        };
+        Ok(super::CreateFileInfo {
+            context: Box::new(ExtFsFile {
+                handler: self,
+                ino,
+                inode,
+                layout,
+            }),
+            is_dir,
+            new_file_created: false,
+        })
+    }
+    fn get_fs_free_space(&self) -> FileSystemResult<super::FileSystemSpaceInfo> {
+        let sb = &self.volume.sb;
+        Ok(super::FileSystemSpaceInfo {
+            bytes_count: sb.blocks_count * sb.block_size,
+            free_bytes_count: sb.free_blocks_count * sb.block_size,
+            available_bytes_count: sb
+                .free_blocks_count
+                .saturating_sub(sb.reserved_blocks_count)
+                * sb.block_size,
+        })
+    }
+    fn get_fs_characteristics(&self) -> FileSystemResult<super::FileSystemCharacteristics> {
+        Ok(super::FileSystemCharacteristics::ReadOnly
+            | super::FileSystemCharacteristics::CaseSensitive)
+    }
+}
+
+impl super::File for ExtFsFile<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        if self.inode.is_dir() {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        let Some(layout) = &self.layout else {
+            return Ok(0);
+        };
+        let size = self.inode.size;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+        layout.read(&self.handler.volume, offset, &mut buffer[..len])?;
+        Ok(len as u64)
+    }
+    fn write_at(
+        &self,
+        _offset: Option<u64>,
+        _buffer: &[u8],
+        _constrain_size: bool,
+    ) -> FileSystemResult<u64> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn flush_buffers(&self) -> FileSystemResult<()> {
+        Ok(())
+    }
+    fn get_stat(&self) -> FileSystemResult<super::FileStatInfo> {
+        Ok(get_file_stat_info(self.ino, &self.inode))
+    }
+    fn set_end_of_file(&self, _offset: u64) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_file_times(
+        &self,
+        _creation_time: std::time::SystemTime,
+        _last_access_time: std::time::SystemTime,
+        _last_write_time: std::time::SystemTime,
+    ) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn set_delete(&self, _delete_on_close: bool) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn move_to(&self, _new_path: SegPath, _replace_if_exists: bool) -> FileSystemResult<()> {
+        Err(FileSystemError::AccessDenied)
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn super::FilePattern,
+        filler: &mut dyn super::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        if !self.inode.is_dir() {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let listing = self.handler.load_listing(self.ino, &self.inode)?;
+        for (name, &child) in listing.iter() {
+            if !pattern.check_name(name) {
+                continue;
+            }
+            let inode = match self.handler.read_inode(child) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("Skipping entry `{name}` with unreadable inode {child}: {e}");
+                    continue;
+                }
+            };
+            if filler
+                .fill_data(name, &get_file_stat_info(child, &inode))
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExtFsConfig {
+    /// The disk or volume image to open.
+    image_path: FsWithPathConfig,
+    /// Index of the partition to use, in partition table order. If not set, the image
+    /// is used as a bare volume, or else the first ext2/3/4 partition is picked.
+    #[serde(default)]
+    partition: Option<usize>,
+}
+
+pub struct ExtFsProvider {}
+impl super::FsProvider for ExtFsProvider {
+    fn get_id(&self) -> Uuid {
+        EXTFS_ID
+    }
+    fn get_name(&self) -> &'static str {
+        "ExtFS"
+    }
+    fn get_version(&self) -> (u32, u32, u32) {
+        (0, 1, 0)
+    }
+    fn construct(
+        &self,
+        config: serde_json::Value,
+        ctx: &mut dyn super::FileSystemCreationContext,
+    ) -> Result<Arc<dyn FileSystemHandler>, super::FileSystemCreationError> {
+        let mut config: ExtFsConfig = serde_json::from_value(config)
+            .map_err(|e| super::FileSystemCreationError::Other(e.into()))?;
+        // Translate slashes
+        super::make_uniform_path(&mut config.image_path.path);
+        let image_fs = ctx.get_or_run_fs(&config.image_path.id, "")?;
+        let handler = ExtFsHandler::new(image_fs, &config.image_path.path, config.partition)
+            .map_err(|e| {
+                log::warn!("Open ext image `{}` failed: {e}", config.image_path.path);
+                super::FileSystemCreationError::InvalidFileSystem
+            })?;
+        Ok(Arc::new(handler))
+    }
+    fn get_template_config(&self) -> serde_json::Value {
+        serde_json::to_value(ExtFsConfig {
+            image_path: Default::default(),
+            partition: None,
+        })
+        .unwrap()
+    }
+}
+
+impl ExtFsProvider {
+    pub fn new() -> Self {
+        ExtFsProvider {}
+    }
+}
@@ -0,0 +1,314 @@
+// Directories of ext2/3/4 volumes: linear entry blocks and htree indexes
+
+use crate::fs_provider::{
+    bytes::{le_u16, le_u32},
+    FileSystemError, FileSystemResult,
+};
+
+const DIR_ENTRY_HEADER_SIZE: usize = 8;
+
+const DX_ROOT_INFO_OFFSET: usize = 24;
+const DX_ENTRY_SIZE: usize = 8;
+const DX_BLOCK_MASK: u32 = 0x0FFFFFFF;
+// NOTE: Without the largedir feature, indexes are at most two levels deep
+const DX_MAX_LEVELS: u8 = 2;
+const DX_MAX_LEVELS_LARGEDIR: u8 = 3;
+
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+const DX_HASH_EOF: u32 = 0x7FFFFFFF;
+
+fn decode_rec_len(value: u16, block_size: usize) -> usize {
+    // NOTE: Blocks of 64KiB and above encode the length in the low bits as well
+    match value {
+        0 | 65535 if block_size >= 65536 => block_size,
+        x => (x as usize & 65532) | ((x as usize & 3) << 16),
+    }
+}
+
+/// Iterates over the entries of a directory block, yielding names and inode
+/// numbers. Empty slots and the `.` / `..` entries are skipped.
+pub(super) struct DirBlockEntries<'a> {
+    block: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> DirBlockEntries<'a> {
+    pub fn new(block: &'a [u8]) -> Self {
+        Self { block, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for DirBlockEntries<'a> {
+    type Item = FileSystemResult<(&'a [u8], u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.block;
+        while self.pos + DIR_ENTRY_HEADER_SIZE <= block.len() {
+            let entry = &block[self.pos..];
+            let ino = le_u32(entry, 0);
+            let rec_len = decode_rec_len(le_u16(entry, 4), block.len());
+            let name_len = entry[6] as usize;
+            if rec_len < DIR_ENTRY_HEADER_SIZE || rec_len & 3 != 0 || rec_len > entry.len() {
+                self.pos = block.len();
+                return Some(Err(FileSystemError::FileCorruptError));
+            }
+            self.pos += rec_len;
+            if ino == 0 || name_len == 0 || DIR_ENTRY_HEADER_SIZE + name_len > rec_len {
+                continue;
+            }
+            let name = &entry[DIR_ENTRY_HEADER_SIZE..DIR_ENTRY_HEADER_SIZE + name_len];
+            if name == b"." || name == b".." {
+                continue;
+            }
+            return Some(Ok((name, ino)));
+        }
+        None
+    }
+}
+
+fn hash_char(c: u8, signed: bool) -> u32 {
+    match signed {
+        true => c as i8 as i32 as u32,
+        false => c as u32,
+    }
+}
+
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12A3FE2Du32, 0x37ABE8F9u32);
+    for &c in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ hash_char(c, signed).wrapping_mul(7152373));
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7FFFFFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+// Packs the name into words, padding with its length
+fn str_to_hash_buf<const N: usize>(msg: &[u8], signed: bool) -> [u32; N] {
+    let mut pad = msg.len() as u32 | (msg.len() as u32) << 8;
+    pad |= pad << 16;
+    let mut out = [pad; N];
+    let mut val = pad;
+    let mut count = 0;
+    for (i, &c) in msg.iter().take(N * 4).enumerate() {
+        val = hash_char(c, signed).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            out[count] = val;
+            count += 1;
+            val = pad;
+        }
+    }
+    if count < N {
+        out[count] = val;
+    }
+    out
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    let (mut sum, mut b0, mut b1) = (0u32, buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9E3779B9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s)
+        };
+    }
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    for (x, y) in buf.iter_mut().zip([a, b, c, d]) {
+        *x = x.wrapping_add(y);
+    }
+}
+
+/// Computes the major htree hash of a name. Returns `None` for unknown hash versions.
+fn dx_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<u32> {
+    let mut buf = match seed.iter().any(|&x| x != 0) {
+        true => *seed,
+        false => [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476],
+    };
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => {
+            dx_hack_hash(name, version == DX_HASH_LEGACY)
+        }
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let mut rest = name;
+            while !rest.is_empty() {
+                half_md4_transform(&mut buf, &str_to_hash_buf(rest, version == DX_HASH_HALF_MD4));
+                rest = &rest[rest.len().min(32)..];
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let mut rest = name;
+            while !rest.is_empty() {
+                tea_transform(&mut buf, &str_to_hash_buf(rest, version == DX_HASH_TEA));
+                rest = &rest[rest.len().min(16)..];
+            }
+            buf[0]
+        }
+        _ => return None,
+    };
+    let hash = hash & !1;
+    Some(match hash == DX_HASH_EOF << 1 {
+        true => (DX_HASH_EOF - 1) << 1,
+        false => hash,
+    })
+}
+
+pub(super) struct HtreeParams {
+    pub seed: [u32; 4],
+    pub unsigned_hash: bool,
+    pub largedir: bool,
+}
+
+// Returns the entries of an index node, the first of which overlaps its count and limit
+fn dx_entries(node: &[u8], offset: usize) -> FileSystemResult<&[u8]> {
+    let limit = node.get(offset..offset + 4).ok_or(FileSystemError::FileCorruptError)?;
+    let count = le_u16(limit, 2) as usize;
+    if count == 0 || count > le_u16(limit, 0) as usize {
+        return Err(FileSystemError::FileCorruptError);
+    }
+    node.get(offset..offset + count * DX_ENTRY_SIZE)
+        .ok_or(FileSystemError::FileCorruptError)
+}
+
+/// Looks up a name through the htree index of a directory. `read_block` reads a
+/// logical block of the directory.
+///
+/// Returns an error if the index is malformed or uses an unknown hash, in which
+/// case the directory should be scanned linearly instead.
+pub(super) fn htree_lookup(
+    name: &[u8],
+    params: &HtreeParams,
+    block_size: usize,
+    mut read_block: impl FnMut(u32, &mut [u8]) -> FileSystemResult<()>,
+) -> FileSystemResult<Option<u32>> {
+    let mut node = vec![0; block_size];
+    read_block(0, &mut node)?;
+    let info = node
+        .get(DX_ROOT_INFO_OFFSET..DX_ROOT_INFO_OFFSET + 8)
+        .ok_or(FileSystemError::FileCorruptError)?;
+    let (version, info_len, levels) = (info[4], info[5] as usize, info[6]);
+    let max_levels = match params.largedir {
+        true => DX_MAX_LEVELS_LARGEDIR,
+        false => DX_MAX_LEVELS,
+    };
+    if le_u32(info, 0) != 0 || levels >= max_levels {
+        return Err(FileSystemError::FileCorruptError);
+    }
+    let version = match params.unsigned_hash && version <= DX_HASH_TEA {
+        true => version + DX_HASH_LEGACY_UNSIGNED,
+        false => version,
+    };
+    let hash = dx_hash(name, version, &params.seed).ok_or(FileSystemError::FileCorruptError)?;
+
+    let entry_hash = |entries: &[u8], i: usize| le_u32(entries, i * DX_ENTRY_SIZE);
+    let entry_block =
+        |entries: &[u8], i: usize| le_u32(entries, i * DX_ENTRY_SIZE + 4) & DX_BLOCK_MASK;
+    // NOTE: Entries are sorted by hash, and the first one has an implicit hash of 0
+    let find_entry = |entries: &[u8]| {
+        (1..entries.len() / DX_ENTRY_SIZE)
+            .filter(|&i| entry_hash(entries, i) <= hash)
+            .count()
+    };
+
+    // Index nodes from the root down, with the offset of their entries and the one taken
+    let mut path = Vec::with_capacity(levels as usize + 1);
+    let mut offset = DX_ROOT_INFO_OFFSET + info_len;
+    let mut index = find_entry(dx_entries(&node, offset)?);
+    // Whether the first entries are taken, when following a hash collision
+    let mut continued = false;
+    loop {
+        let block = entry_block(dx_entries(&node, offset)?, index);
+        path.push((node, offset, index));
+        node = vec![0; block_size];
+        read_block(block, &mut node)?;
+        if path.len() <= levels as usize {
+            // Index nodes start with an empty entry spanning the whole block
+            if le_u32(&node, 0) != 0 || decode_rec_len(le_u16(&node, 4), block_size) != block_size {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            offset = DIR_ENTRY_HEADER_SIZE;
+            index = match continued {
+                true => 0,
+                false => find_entry(dx_entries(&node, offset)?),
+            };
+            continue;
+        }
+
+        for entry in DirBlockEntries::new(&node) {
+            let (entry_name, ino) = entry?;
+            if entry_name == name {
+                return Ok(Some(ino));
+            }
+        }
+        // On hash collisions, the low bit marks a continuation in the next leaf, which
+        // may hang off the next entry of any index node along the path
+        loop {
+            let Some((parent, parent_offset, parent_index)) = path.pop() else {
+                return Ok(None);
+            };
+            let entries = dx_entries(&parent, parent_offset)?;
+            if parent_index + 1 < entries.len() / DX_ENTRY_SIZE {
+                if entry_hash(entries, parent_index + 1) & !1 != hash {
+                    return Ok(None);
+                }
+                (node, offset, index) = (parent, parent_offset, parent_index + 1);
+                continued = true;
+                break;
+            }
+        }
+    }
+}
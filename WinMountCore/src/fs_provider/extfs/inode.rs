@@ -0,0 +1,336 @@
+// Inodes of ext2/3/4 volumes and the mapping of their data to blocks
+
+use std::time::{Duration, SystemTime};
+
+use crate::fs_provider::{
+    bytes::{le_u16, le_u32},
+    FileSystemError, FileSystemResult,
+};
+
+use super::volume::ExtVolume;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xA000;
+
+const INODE_FLAG_ENCRYPT: u32 = 0x800;
+const INODE_FLAG_INDEX: u32 = 0x1000;
+const INODE_FLAG_EXTENTS: u32 = 0x80000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x10000000;
+const INODE_FLAG_CASEFOLD: u32 = 0x40000000;
+
+pub(super) const INODE_BLOCK_SIZE: usize = 60;
+const INODE_EXTRA_OFFSET: usize = 128;
+// NOTE: Direct block pointers, followed by single, double and triple indirect ones
+const DIRECT_BLOCKS: usize = 12;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+const EXTENT_ENTRY_SIZE: usize = 12;
+// NOTE: Lengths above this mark uninitialized extents, which read as zeros
+const EXTENT_INIT_MAX_LEN: u16 = 32768;
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+const XATTR_MAGIC: u32 = 0xEA020000;
+const XATTR_INDEX_SYSTEM: u8 = 7;
+const XATTR_ENTRY_SIZE: usize = 16;
+
+fn decode_time(seconds: u32, extra: Option<u32>) -> SystemTime {
+    // The extra field holds two epoch bits extending the seconds, and nanoseconds
+    let (epoch, nanos) = extra.map_or((0, 0), |x| ((x & 3) as i64, x >> 2));
+    let seconds = seconds as i32 as i64 + (epoch << 32);
+    let t = if seconds >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+    };
+    t + Duration::from_nanos(nanos.min(999_999_999) as u64)
+}
+
+pub(super) struct Inode {
+    pub mode: u16,
+    pub flags: u32,
+    pub size: u64,
+    pub creation_time: SystemTime,
+    pub last_access_time: SystemTime,
+    pub last_write_time: SystemTime,
+    block: [u8; INODE_BLOCK_SIZE],
+    // In-inode extended attribute area, which holds the rest of inline data
+    xattr_area: Vec<u8>,
+}
+
+impl Inode {
+    pub fn parse(data: &[u8]) -> FileSystemResult<Self> {
+        let extra_size = match data.len() > INODE_EXTRA_OFFSET {
+            true => le_u16(data, INODE_EXTRA_OFFSET) as usize,
+            false => 0,
+        };
+        if INODE_EXTRA_OFFSET + extra_size > data.len() {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        // NOTE: Extra fields only exist if the inode is large enough to hold them
+        let extra = |offset: usize| {
+            (offset + 4 <= INODE_EXTRA_OFFSET + extra_size).then(|| le_u32(data, offset))
+        };
+        let mode = le_u16(data, 0x00);
+        let changed = decode_time(le_u32(data, 0x0C), extra(0x84));
+        Ok(Self {
+            mode,
+            flags: le_u32(data, 0x20),
+            size: (le_u32(data, 0x6C) as u64) << 32 | le_u32(data, 0x04) as u64,
+            // NOTE: Without a recorded creation time, fall back to the change time
+            creation_time: extra(0x90).map_or(changed, |x| decode_time(x, extra(0x94))),
+            last_access_time: decode_time(le_u32(data, 0x08), extra(0x8C)),
+            last_write_time: decode_time(le_u32(data, 0x10), extra(0x88)),
+            block: data[0x28..0x28 + INODE_BLOCK_SIZE].try_into().unwrap(),
+            xattr_area: data[INODE_EXTRA_OFFSET + extra_size..].to_vec(),
+        })
+    }
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+    /// Whether the inode has data at all, as opposed to devices, FIFOs and sockets.
+    pub fn has_data(&self) -> bool {
+        matches!(self.mode & S_IFMT, S_IFDIR | S_IFREG | S_IFLNK)
+    }
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & INODE_FLAG_ENCRYPT != 0
+    }
+    pub fn is_indexed(&self) -> bool {
+        self.flags & INODE_FLAG_INDEX != 0
+    }
+    pub fn is_casefolded(&self) -> bool {
+        self.flags & INODE_FLAG_CASEFOLD != 0
+    }
+    pub fn has_inline_data(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE_DATA != 0
+    }
+    /// Returns the part of inline data stored in the `system.data` extended attribute.
+    pub fn inline_data_xattr(&self) -> FileSystemResult<&[u8]> {
+        let area = &self.xattr_area;
+        if area.len() < 4 || le_u32(area, 0) != XATTR_MAGIC {
+            return Ok(&[]);
+        }
+        // NOTE: Value offsets are relative to the first entry
+        let entries = &area[4..];
+        let mut pos = 0;
+        while pos + XATTR_ENTRY_SIZE <= entries.len() && le_u32(entries, pos) != 0 {
+            let name_len = entries[pos] as usize;
+            let name_index = entries[pos + 1];
+            let value_offset = le_u16(entries, pos + 2) as usize;
+            let value_size = le_u32(entries, pos + 8) as usize;
+            let name = entries
+                .get(pos + XATTR_ENTRY_SIZE..pos + XATTR_ENTRY_SIZE + name_len)
+                .ok_or(FileSystemError::FileCorruptError)?;
+            if name_index == XATTR_INDEX_SYSTEM && name == b"data" {
+                return entries
+                    .get(value_offset..value_offset + value_size)
+                    .ok_or(FileSystemError::FileCorruptError);
+            }
+            pos += (XATTR_ENTRY_SIZE + name_len).next_multiple_of(4);
+        }
+        Ok(&[])
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct Extent {
+    /// First logical block covered by the extent.
+    pub logical: u64,
+    pub len: u64,
+    /// First physical block, or `None` for uninitialized extents.
+    pub physical: Option<u64>,
+}
+
+pub(super) enum DataLayout {
+    Inline(Vec<u8>),
+    Blocks(Vec<Extent>),
+}
+
+impl DataLayout {
+    pub fn load(volume: &ExtVolume, inode: &Inode) -> FileSystemResult<Self> {
+        if inode.has_inline_data() {
+            let mut data = inode.block.to_vec();
+            data.extend_from_slice(inode.inline_data_xattr()?);
+            data.truncate(inode.size as usize);
+            return Ok(DataLayout::Inline(data));
+        }
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            let mut extents = Vec::new();
+            read_extent_node(volume, &inode.block, None, &mut extents)?;
+            extents.sort_by_key(|x| x.logical);
+            return Ok(DataLayout::Blocks(extents));
+        }
+        // Fast symlinks keep their target inside the inode
+        if inode.is_symlink() && inode.size < INODE_BLOCK_SIZE as u64 {
+            return Ok(DataLayout::Inline(inode.block[..inode.size as usize].to_vec()));
+        }
+        let block_count = inode.size.div_ceil(volume.sb.block_size);
+        let mut extents = Vec::new();
+        let mut logical = 0;
+        for (i, pointer) in inode.block.chunks_exact(4).enumerate() {
+            let pointer = le_u32(pointer, 0) as u64;
+            let level = i.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            let covered = (volume.sb.block_size / 4).pow(level);
+            if logical >= block_count {
+                break;
+            }
+            if pointer != 0 {
+                map_indirect(volume, pointer, level, logical, block_count, &mut extents)?;
+            }
+            logical += covered;
+        }
+        Ok(DataLayout::Blocks(extents))
+    }
+
+    /// Reads file data, treating holes and uninitialized extents as zeros.
+    pub fn read(&self, volume: &ExtVolume, offset: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        let extents = match self {
+            DataLayout::Inline(data) => {
+                let start = (offset as usize).min(data.len());
+                let len = buffer.len().min(data.len() - start);
+                buffer[..len].copy_from_slice(&data[start..start + len]);
+                buffer[len..].fill(0);
+                return Ok(());
+            }
+            DataLayout::Blocks(x) => x,
+        };
+        let block_size = volume.sb.block_size;
+        let mut done = 0;
+        while done < buffer.len() {
+            let pos = offset + done as u64;
+            let block = pos / block_size;
+            let remaining = buffer.len() - done;
+            let index = extents.partition_point(|x| x.logical + x.len <= block);
+            let len = match extents.get(index) {
+                Some(x) if x.logical <= block => {
+                    let extent_end = (x.logical + x.len) * block_size;
+                    let len = remaining.min((extent_end - pos) as usize);
+                    let dest = &mut buffer[done..done + len];
+                    match x.physical {
+                        Some(physical) => {
+                            let start = (physical + block - x.logical)
+                                .checked_mul(block_size)
+                                .ok_or(FileSystemError::FileCorruptError)?;
+                            volume.read_exact(start + pos % block_size, dest)?;
+                        }
+                        None => dest.fill(0),
+                    }
+                    len
+                }
+                // Hole up to the next extent
+                next => {
+                    let hole_end = next.map_or(u64::MAX, |x| x.logical * block_size);
+                    let len = remaining.min((hole_end - pos).min(usize::MAX as u64) as usize);
+                    buffer[done..done + len].fill(0);
+                    len
+                }
+            };
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+fn push_extent(extents: &mut Vec<Extent>, extent: Extent) {
+    if let Some(last) = extents.last_mut() {
+        if last.logical + last.len == extent.logical {
+            match (last.physical, extent.physical) {
+                (Some(a), Some(b)) if a + last.len == b => {
+                    last.len += extent.len;
+                    return;
+                }
+                (None, None) => {
+                    last.len += extent.len;
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+    extents.push(extent);
+}
+
+fn read_extent_node(
+    volume: &ExtVolume,
+    node: &[u8],
+    expected_depth: Option<u16>,
+    extents: &mut Vec<Extent>,
+) -> FileSystemResult<()> {
+    if node.len() < EXTENT_ENTRY_SIZE || le_u16(node, 0) != EXTENT_MAGIC {
+        return Err(FileSystemError::FileCorruptError);
+    }
+    let count = le_u16(node, 2) as usize;
+    let depth = le_u16(node, 6);
+    if depth > MAX_EXTENT_DEPTH
+        || expected_depth.is_some_and(|x| x != depth)
+        || (count + 1) * EXTENT_ENTRY_SIZE > node.len()
+    {
+        return Err(FileSystemError::FileCorruptError);
+    }
+    let mut child = Vec::new();
+    for i in 0..count {
+        let entry = &node[(i + 1) * EXTENT_ENTRY_SIZE..][..EXTENT_ENTRY_SIZE];
+        let logical = le_u32(entry, 0) as u64;
+        if depth == 0 {
+            let len = le_u16(entry, 4);
+            let physical = (le_u16(entry, 6) as u64) << 32 | le_u32(entry, 8) as u64;
+            let (len, physical) = match len > EXTENT_INIT_MAX_LEN {
+                true => (len - EXTENT_INIT_MAX_LEN, None),
+                false => (len, Some(physical)),
+            };
+            push_extent(
+                extents,
+                Extent {
+                    logical,
+                    len: len as u64,
+                    physical,
+                },
+            );
+        } else {
+            let leaf = (le_u16(entry, 8) as u64) << 32 | le_u32(entry, 4) as u64;
+            child.resize(volume.sb.block_size as usize, 0);
+            volume.read_block(leaf, &mut child)?;
+            read_extent_node(volume, &child, Some(depth - 1), extents)?;
+        }
+    }
+    Ok(())
+}
+
+fn map_indirect(
+    volume: &ExtVolume,
+    pointer: u64,
+    level: u32,
+    logical: u64,
+    block_count: u64,
+    extents: &mut Vec<Extent>,
+) -> FileSystemResult<()> {
+    if level == 0 {
+        push_extent(
+            extents,
+            Extent {
+                logical,
+                len: 1,
+                physical: Some(pointer),
+            },
+        );
+        return Ok(());
+    }
+    let mut block = vec![0; volume.sb.block_size as usize];
+    volume.read_block(pointer, &mut block)?;
+    let covered = (volume.sb.block_size / 4).pow(level - 1);
+    for (i, child) in block.chunks_exact(4).enumerate() {
+        let child_logical = logical + i as u64 * covered;
+        if child_logical >= block_count {
+            break;
+        }
+        let child = le_u32(child, 0) as u64;
+        if child != 0 {
+            map_indirect(volume, child, level - 1, child_logical, block_count, extents)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+// Superblock and block group descriptors of ext2/3/4 volumes
+
+use crate::fs_provider::{
+    bytes::{le_u16, le_u32},
+    FileSystemError, FileSystemResult, OwnedFile,
+};
+
+pub(super) const SUPERBLOCK_OFFSET: u64 = 1024;
+pub(super) const SUPERBLOCK_SIZE: usize = 1024;
+const EXT_MAGIC: u16 = 0xEF53;
+
+const FEATURE_COMPAT_DIR_INDEX: u32 = 0x20;
+
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const FEATURE_INCOMPAT_RECOVER: u32 = 0x4;
+const FEATURE_INCOMPAT_META_BG: u32 = 0x10;
+const FEATURE_INCOMPAT_EXTENTS: u32 = 0x40;
+const FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+const FEATURE_INCOMPAT_MMP: u32 = 0x100;
+const FEATURE_INCOMPAT_FLEX_BG: u32 = 0x200;
+const FEATURE_INCOMPAT_EA_INODE: u32 = 0x400;
+const FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const FEATURE_INCOMPAT_LARGEDIR: u32 = 0x4000;
+const FEATURE_INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const FEATURE_INCOMPAT_ENCRYPT: u32 = 0x10000;
+const FEATURE_INCOMPAT_CASEFOLD: u32 = 0x20000;
+// NOTE: Compression, journal devices and dirdata are not supported
+const FEATURE_INCOMPAT_SUPPORTED: u32 = FEATURE_INCOMPAT_FILETYPE
+    | FEATURE_INCOMPAT_RECOVER
+    | FEATURE_INCOMPAT_META_BG
+    | FEATURE_INCOMPAT_EXTENTS
+    | FEATURE_INCOMPAT_64BIT
+    | FEATURE_INCOMPAT_MMP
+    | FEATURE_INCOMPAT_FLEX_BG
+    | FEATURE_INCOMPAT_EA_INODE
+    | FEATURE_INCOMPAT_CSUM_SEED
+    | FEATURE_INCOMPAT_LARGEDIR
+    | FEATURE_INCOMPAT_INLINE_DATA
+    | FEATURE_INCOMPAT_ENCRYPT
+    | FEATURE_INCOMPAT_CASEFOLD;
+
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const MIN_DESC_SIZE: u64 = 32;
+const MIN_DESC_SIZE_64BIT: u64 = 64;
+
+fn hi_lo(hi: u32, lo: u32) -> u64 {
+    (hi as u64) << 32 | lo as u64
+}
+
+pub(super) struct Superblock {
+    pub block_size: u64,
+    pub blocks_count: u64,
+    pub free_blocks_count: u64,
+    pub reserved_blocks_count: u64,
+    pub first_data_block: u64,
+    pub blocks_per_group: u64,
+    pub inodes_per_group: u64,
+    pub inodes_count: u32,
+    pub inode_size: u64,
+    desc_size: u64,
+    first_meta_bg: u64,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+    pub hash_seed: [u32; 4],
+    pub unsigned_hash: bool,
+    pub volume_name: String,
+}
+
+impl Superblock {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if le_u16(data, 0x38) != EXT_MAGIC {
+            anyhow::bail!("bad ext superblock magic");
+        }
+        let log_block_size = le_u32(data, 0x18);
+        if log_block_size > 6 {
+            anyhow::bail!("invalid block size shift {log_block_size}");
+        }
+        let block_size = 1024u64 << log_block_size;
+        let rev_level = le_u32(data, 0x4C);
+        let feature_incompat = if rev_level >= 1 { le_u32(data, 0x60) } else { 0 };
+        let is_64bit = feature_incompat & FEATURE_INCOMPAT_64BIT != 0;
+        let hi = |offset: usize| if is_64bit { le_u32(data, offset) } else { 0 };
+        let inode_size = if rev_level >= 1 {
+            le_u16(data, 0x58) as u64
+        } else {
+            GOOD_OLD_INODE_SIZE
+        };
+        let desc_size = if is_64bit {
+            le_u16(data, 0xFE) as u64
+        } else {
+            MIN_DESC_SIZE
+        };
+        let sb = Self {
+            block_size,
+            blocks_count: hi_lo(hi(0x150), le_u32(data, 0x04)),
+            free_blocks_count: hi_lo(hi(0x158), le_u32(data, 0x0C)),
+            reserved_blocks_count: hi_lo(hi(0x154), le_u32(data, 0x08)),
+            first_data_block: le_u32(data, 0x14) as u64,
+            blocks_per_group: le_u32(data, 0x20) as u64,
+            inodes_per_group: le_u32(data, 0x28) as u64,
+            inodes_count: le_u32(data, 0x00),
+            inode_size,
+            desc_size,
+            first_meta_bg: le_u32(data, 0x104) as u64,
+            feature_compat: if rev_level >= 1 { le_u32(data, 0x5C) } else { 0 },
+            feature_incompat,
+            feature_ro_compat: if rev_level >= 1 { le_u32(data, 0x64) } else { 0 },
+            hash_seed: std::array::from_fn(|i| le_u32(data, 0xEC + i * 4)),
+            unsigned_hash: le_u32(data, 0x160) & FLAGS_UNSIGNED_HASH != 0,
+            volume_name: String::from_utf8_lossy(&data[0x78..0x88])
+                .trim_end_matches('\0')
+                .to_owned(),
+        };
+        let unsupported = sb.feature_incompat & !FEATURE_INCOMPAT_SUPPORTED;
+        if unsupported != 0 {
+            anyhow::bail!("unsupported incompatible features {unsupported:#x}");
+        }
+        if sb.blocks_per_group == 0
+            || sb.inodes_per_group == 0
+            || sb.first_data_block >= sb.blocks_count
+            || sb.inode_size < GOOD_OLD_INODE_SIZE
+            || !sb.inode_size.is_power_of_two()
+            || sb.inode_size > block_size
+            || sb.desc_size < MIN_DESC_SIZE
+            || (is_64bit && sb.desc_size < MIN_DESC_SIZE_64BIT)
+            || !sb.desc_size.is_power_of_two()
+        {
+            anyhow::bail!("invalid ext superblock geometry");
+        }
+        Ok(sb)
+    }
+    pub fn group_count(&self) -> u64 {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+    pub fn needs_recovery(&self) -> bool {
+        self.feature_incompat & FEATURE_INCOMPAT_RECOVER != 0
+    }
+    pub fn has_dir_index(&self) -> bool {
+        self.feature_compat & FEATURE_COMPAT_DIR_INDEX != 0
+    }
+    pub fn has_largedir(&self) -> bool {
+        self.feature_incompat & FEATURE_INCOMPAT_LARGEDIR != 0
+    }
+    fn has_super(&self, group: u64) -> bool {
+        if self.feature_ro_compat & FEATURE_RO_COMPAT_SPARSE_SUPER == 0 || group <= 1 {
+            return true;
+        }
+        // NOTE: With sparse_super, backups live in groups that are powers of 3, 5 and 7
+        [3, 5, 7].into_iter().any(|base| {
+            let mut x = base;
+            while x < group {
+                x *= base;
+            }
+            x == group
+        })
+    }
+    fn descriptor_block(&self, index: u64) -> u64 {
+        let gdt_start = self.first_data_block + 1;
+        if self.feature_incompat & FEATURE_INCOMPAT_META_BG == 0 || index < self.first_meta_bg {
+            return gdt_start + index;
+        }
+        // With meta_bg, each descriptor block is stored in the first group it describes
+        let group = index * (self.block_size / self.desc_size);
+        self.first_data_block + group * self.blocks_per_group + self.has_super(group) as u64
+    }
+}
+
+pub(super) struct ExtVolume {
+    image: OwnedFile<'static>,
+    offset: u64,
+    size: u64,
+    pub sb: Superblock,
+    // Location of the inode table of each block group
+    inode_tables: Vec<u64>,
+}
+
+impl ExtVolume {
+    pub fn new(image: OwnedFile<'static>, offset: u64, size: u64) -> anyhow::Result<Self> {
+        let mut data = [0; SUPERBLOCK_SIZE];
+        image.read_at_exact(offset + SUPERBLOCK_OFFSET, &mut data)?;
+        let sb = Superblock::parse(&data)?;
+        if sb.blocks_count.saturating_mul(sb.block_size) > size {
+            log::warn!("ext volume is larger than its partition");
+        }
+        let mut volume = Self {
+            image,
+            offset,
+            size,
+            sb,
+            inode_tables: Vec::new(),
+        };
+        volume.load_group_descriptors()?;
+        Ok(volume)
+    }
+    fn load_group_descriptors(&mut self) -> anyhow::Result<()> {
+        let group_count = self.sb.group_count();
+        let descs_per_block = self.sb.block_size / self.sb.desc_size;
+        let mut block = vec![0; self.sb.block_size as usize];
+        let mut inode_tables = Vec::with_capacity(group_count.min(1 << 20) as usize);
+        for index in 0..group_count.div_ceil(descs_per_block) {
+            self.read_block(self.sb.descriptor_block(index), &mut block)?;
+            let count = descs_per_block.min(group_count - index * descs_per_block);
+            for desc in block.chunks_exact(self.sb.desc_size as usize).take(count as usize) {
+                let hi = if self.sb.desc_size >= MIN_DESC_SIZE_64BIT {
+                    le_u32(desc, 0x28)
+                } else {
+                    0
+                };
+                inode_tables.push(hi_lo(hi, le_u32(desc, 0x08)));
+            }
+        }
+        self.inode_tables = inode_tables;
+        Ok(())
+    }
+    pub fn read_exact(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        match offset.checked_add(buffer.len() as u64) {
+            Some(end) if end <= self.size => {}
+            _ => return Err(FileSystemError::FileCorruptError),
+        }
+        self.image.read_at_exact(self.offset + offset, buffer)
+    }
+    pub fn read_block(&self, block: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        let offset = block
+            .checked_mul(self.sb.block_size)
+            .ok_or(FileSystemError::FileCorruptError)?;
+        self.read_exact(offset, buffer)
+    }
+    /// Reads the raw on-disk inode.
+    pub fn read_inode(&self, ino: u32) -> FileSystemResult<Vec<u8>> {
+        if ino == 0 || ino > self.sb.inodes_count {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let index = (ino - 1) as u64;
+        let table = self
+            .inode_tables
+            .get((index / self.sb.inodes_per_group) as usize)
+            .ok_or(FileSystemError::FileCorruptError)?;
+        let offset = table
+            .checked_mul(self.sb.block_size)
+            .ok_or(FileSystemError::FileCorruptError)?
+            + (index % self.sb.inodes_per_group) * self.sb.inode_size;
+        let mut data = vec![0; self.sb.inode_size as usize];
+        self.read_exact(offset, &mut data)?;
+        Ok(data)
+    }
+}
+
+/// Checks for an ext superblock, used to pick the volume out of a disk image.
+pub(super) fn is_ext_volume(image: &dyn crate::fs_provider::File, offset: u64) -> bool {
+    let mut data = [0; SUPERBLOCK_SIZE];
+    image
+        .read_at_exact(offset + SUPERBLOCK_OFFSET, &mut data)
+        .is_ok()
+        && Superblock::parse(&data).is_ok()
+}
@@ -0,0 +1,1045 @@
+// FAT12/16/32 and exFAT disk image provider
+// NOTE: Images may either be a bare volume or a whole disk with an MBR / GPT partition
+//       table. Writes are opt-in and go straight to the image, updating the FATs, the
+//       exFAT allocation bitmap and directory entries in place.
+
+mod dirent;
+mod volume;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::{uuid, Uuid};
+
+use crate::util::{CaselessStr, CaselessString};
+
+use super::{
+    FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
+    FileSystemError, FileSystemHandler, FileSystemResult, FsWithPathConfig, SegPath,
+};
+
+use dirent::{
+    EntryFields, EntryTimes, ParsedEntry, UpcaseTable, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN,
+    ATTR_READ_ONLY, DIR_ENTRY_SIZE,
+};
+use volume::{DataStorage, FatKind, FatTable, FatVolume, RootDirLocation, VolumeIo};
+
+pub const FATFS_ID: Uuid = uuid!("5E0C1D7A-92B4-4C6F-8A3E-F17B2D94C085");
+
+// NOTE: Node ids double as file indices, so avoid 0
+const ROOT_ID: u64 = 1;
+// NOTE: Directories are loaded into memory, so guard against bogus sizes
+const MAX_DIRECTORY_SIZE: u64 = 256 * 1024 * 1024;
+// NOTE: FAT directories are limited to 65536 entries
+const MAX_FAT_DIRECTORY_SIZE: u64 = 65536 * DIR_ENTRY_SIZE as u64;
+const MAX_FAT_FILE_SIZE: u64 = u32::MAX as u64;
+
+struct FatNode {
+    name: String,
+    // NOTE: FAT only
+    short_name: [u8; 11],
+    fields: EntryFields,
+    storage: DataStorage,
+    parent: Option<u64>,
+    // Location of the entry set inside the parent directory
+    entry_pos: u64,
+    entry_slots: u32,
+    // NOTE: Loaded on first access
+    children: Option<BTreeMap<CaselessString, u64>>,
+    open_count: u32,
+    delete_on_close: bool,
+}
+
+impl FatNode {
+    fn is_dir(&self) -> bool {
+        self.fields.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn get_file_stat_info(&self, index: u64) -> super::FileStatInfo {
+        let mut attributes = FileAttributes::empty();
+        if self.fields.attributes & ATTR_READ_ONLY != 0 {
+            attributes |= FileAttributes::Readonly;
+        }
+        if self.fields.attributes & ATTR_HIDDEN != 0 {
+            attributes |= FileAttributes::Hidden;
+        }
+        if self.is_dir() {
+            attributes |= FileAttributes::DirectoryFile;
+        }
+        super::FileStatInfo {
+            index,
+            size: if self.is_dir() { 0 } else { self.fields.size },
+            is_dir: self.is_dir(),
+            attributes,
+            creation_time: self.fields.times.creation_time,
+            last_access_time: self.fields.times.last_access_time,
+            last_write_time: self.fields.times.last_write_time,
+        }
+    }
+}
+
+struct FatTree {
+    nodes: HashMap<u64, FatNode>,
+    next_id: u64,
+    fat: FatTable,
+}
+
+impl FatTree {
+    fn node(&self, id: u64) -> FileSystemResult<&FatNode> {
+        self.nodes.get(&id).ok_or(FileSystemError::NoSuchFile)
+    }
+    fn node_mut(&mut self, id: u64) -> FileSystemResult<&mut FatNode> {
+        self.nodes.get_mut(&id).ok_or(FileSystemError::NoSuchFile)
+    }
+    // WARN: Children of the directory must be loaded
+    fn find_child(&self, dir: u64, name: &str) -> FileSystemResult<Option<u64>> {
+        Ok(self
+            .node(dir)?
+            .children
+            .as_ref()
+            .and_then(|x| x.get(CaselessStr::new(name)))
+            .copied())
+    }
+    fn short_name_exists(&self, dir: u64, short_name: &[u8; 11], except: u64) -> bool {
+        let Some(children) = self.nodes.get(&dir).and_then(|x| x.children.as_ref()) else {
+            return false;
+        };
+        children.values().any(|&x| {
+            x != except
+                && self
+                    .nodes
+                    .get(&x)
+                    .is_some_and(|x| &x.short_name == short_name)
+        })
+    }
+}
+
+struct FatFsHandler {
+    tree: RwLock<FatTree>,
+    volume: FatVolume,
+    upcase: UpcaseTable,
+    writable: bool,
+    // Whether the exFAT VolumeDirty flag was set on mount, and is cleared on unmount
+    clears_exfat_dirty: bool,
+    // NOTE: The image file borrows from this filesystem, so it must be dropped last
+    _image_fs: Arc<dyn FileSystemHandler>,
+}
+
+struct FatFsFile<'h> {
+    handler: &'h FatFsHandler,
+    id: u64,
+}
+
+impl FatFsHandler {
+    fn new(
+        image_fs: Arc<dyn FileSystemHandler>,
+        image_path: &str,
+        partition: Option<usize>,
+        writable: bool,
+    ) -> anyhow::Result<Self> {
+        // SAFETY: The image filesystem is owned by the handler and outlives the file
+        let image =
+            unsafe { super::partition::open_image(image_fs.as_ref(), image_path, writable)? };
+        let image_stat = image.get_stat()?;
+        let is_volume = |offset: u64, size: u64| {
+            let mut sector = [0; 512];
+            image.read_at_exact(offset, &mut sector).is_ok()
+                && volume::parse_boot_sector(&sector, size).is_ok()
+        };
+        let (offset, size) =
+            super::partition::select_volume(image.as_ref(), image_stat.size, partition, is_volume)?;
+
+        let io = VolumeIo::new(image, offset, size);
+        let mut sector = [0; 512];
+        io.read_exact(0, &mut sector)?;
+        let geometry = volume::parse_boot_sector(&sector, size)?;
+        log::debug!(
+            "Found {:?} volume with {} clusters of {} bytes",
+            geometry.kind,
+            geometry.cluster_count,
+            geometry.cluster_size
+        );
+        let volume = FatVolume { io, geometry };
+        let mut fat = FatTable::load(&volume)?;
+
+        let storage = match volume.geometry.root {
+            RootDirLocation::Fixed { offset, size } => DataStorage::Fixed { offset, size },
+            RootDirLocation::Cluster(cluster) => {
+                DataStorage::Clusters(fat.chain_runs(&volume, cluster)?)
+            }
+        };
+        let root = FatNode {
+            name: String::new(),
+            short_name: [b' '; 11],
+            fields: EntryFields {
+                attributes: ATTR_DIRECTORY,
+                first_cluster: storage.first_cluster(),
+                size: 0,
+                valid_size: 0,
+                no_fat_chain: false,
+                times: EntryTimes {
+                    creation_time: image_stat.creation_time,
+                    last_access_time: image_stat.last_access_time,
+                    last_write_time: image_stat.last_write_time,
+                },
+            },
+            storage,
+            parent: None,
+            entry_pos: 0,
+            entry_slots: 0,
+            children: None,
+            open_count: 0,
+            delete_on_close: false,
+        };
+
+        let mut upcase = UpcaseTable::ascii();
+        if volume.geometry.kind == FatKind::ExFat {
+            // The allocation bitmap and up-case table are described in the root directory
+            let mut data = vec![0; root.storage.allocated_size(&volume.geometry) as usize];
+            volume.read_data(&root.storage, 0, &mut data)?;
+            let (mut bitmap, mut upcase_data) = (None, None);
+            dirent::parse_exfat_directory(&data, |slot| match slot[0] {
+                dirent::EXFAT_ENTRY_BITMAP => {
+                    bitmap.get_or_insert(dirent::parse_exfat_allocation(slot));
+                }
+                dirent::EXFAT_ENTRY_UPCASE => {
+                    upcase_data.get_or_insert(dirent::parse_exfat_allocation(slot));
+                }
+                _ => {}
+            });
+            let (cluster, size) =
+                bitmap.ok_or_else(|| anyhow::anyhow!("missing exFAT allocation bitmap"))?;
+            fat.load_bitmap(&volume, cluster, size)?;
+            match upcase_data {
+                Some((cluster, size)) => {
+                    let storage = DataStorage::Clusters(fat.chain_runs(&volume, cluster)?);
+                    let mut data = vec![0; size.min(storage.allocated_size(&volume.geometry)) as usize];
+                    volume.read_data(&storage, 0, &mut data)?;
+                    upcase = UpcaseTable::decode(&data);
+                }
+                None => log::warn!("Missing exFAT up-case table, assuming ASCII"),
+            }
+        }
+
+        // NOTE: A volume found dirty is left dirty, so that it still gets checked
+        let mut clears_exfat_dirty = false;
+        if writable && volume.geometry.kind == FatKind::ExFat {
+            if volume.io.set_exfat_dirty(true)? {
+                log::warn!("exFAT volume was not cleanly unmounted");
+            } else {
+                clears_exfat_dirty = true;
+            }
+        }
+
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT_ID, root);
+        Ok(Self {
+            tree: RwLock::new(FatTree {
+                nodes,
+                next_id: ROOT_ID + 1,
+                fat,
+            }),
+            volume,
+            upcase,
+            writable,
+            clears_exfat_dirty,
+            _image_fs: image_fs,
+        })
+    }
+
+    fn is_exfat(&self) -> bool {
+        self.volume.geometry.kind == FatKind::ExFat
+    }
+
+    fn ensure_writable(&self) -> FileSystemResult<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(FileSystemError::AccessDenied)
+        }
+    }
+
+    fn read_dir_data(&self, node: &FatNode) -> FileSystemResult<Vec<u8>> {
+        let size = node.storage.allocated_size(&self.volume.geometry);
+        if size > MAX_DIRECTORY_SIZE {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let mut data = vec![0; size as usize];
+        self.volume.read_data(&node.storage, 0, &mut data)?;
+        Ok(data)
+    }
+
+    fn add_node(&self, tree: &mut FatTree, parent: u64, entry: ParsedEntry) -> FileSystemResult<u64> {
+        let fields = entry.fields;
+        let runs = if fields.no_fat_chain {
+            let cluster_count = fields.size.div_ceil(self.volume.geometry.cluster_size);
+            let cluster_count =
+                u32::try_from(cluster_count).map_err(|_| FileSystemError::FileCorruptError)?;
+            tree.fat
+                .contiguous_runs(&self.volume, fields.first_cluster, cluster_count)?
+        } else {
+            tree.fat.chain_runs(&self.volume, fields.first_cluster)?
+        };
+        let id = tree.next_id;
+        tree.next_id += 1;
+        tree.nodes.insert(
+            id,
+            FatNode {
+                name: entry.name,
+                short_name: entry.short_name,
+                fields,
+                storage: DataStorage::Clusters(runs),
+                parent: Some(parent),
+                entry_pos: entry.pos,
+                entry_slots: entry.slots,
+                children: None,
+                open_count: 0,
+                delete_on_close: false,
+            },
+        );
+        Ok(id)
+    }
+
+    fn load_children(&self, tree: &mut FatTree, id: u64) -> FileSystemResult<()> {
+        let node = tree.node(id)?;
+        if node.children.is_some() || !node.is_dir() {
+            return Ok(());
+        }
+        let data = self.read_dir_data(node)?;
+        let entries = if self.is_exfat() {
+            dirent::parse_exfat_directory(&data, |_| {})
+        } else {
+            dirent::parse_fat_directory(&data)
+        };
+        let mut children = BTreeMap::new();
+        for entry in entries {
+            if entry.name.is_empty() || entry.name.contains(['/', '\\', '\0']) {
+                log::warn!("Skipping entry with invalid name `{}`", entry.name);
+                continue;
+            }
+            if children.contains_key(CaselessStr::new(&entry.name)) {
+                log::warn!("Skipping duplicate entry `{}`", entry.name);
+                continue;
+            }
+            let name = entry.name.clone();
+            match self.add_node(tree, id, entry) {
+                Ok(child) => {
+                    children.insert(name.into(), child);
+                }
+                Err(e) => log::warn!("Skipping entry `{name}`: {e}"),
+            }
+        }
+        tree.node_mut(id)?.children = Some(children);
+        Ok(())
+    }
+
+    fn resolve_path<'s>(
+        &self,
+        tree: &mut FatTree,
+        path: SegPath<'s>,
+    ) -> FileSystemResult<(Option<u64>, &'s str)> {
+        let mut cur_dir = ROOT_ID;
+        let mut it = path.into_iter().peekable();
+        while let Some(segment) = it.next() {
+            self.load_children(tree, cur_dir)?;
+            if it.peek().is_none() {
+                // Find in last level
+                return Ok((Some(cur_dir), segment));
+            }
+            // Find the next folder
+            cur_dir = match tree.find_child(cur_dir, segment)? {
+                Some(child) if tree.node(child)?.is_dir() => child,
+                _ => return Err(FileSystemError::ObjectPathNotFound),
+            };
+        }
+        Ok((None, ""))
+    }
+
+    fn read_entry_set(&self, tree: &FatTree, dir: u64, pos: u64, slots: u32) -> FileSystemResult<Vec<u8>> {
+        let mut set = vec![0; slots as usize * DIR_ENTRY_SIZE];
+        self.volume.read_data(&tree.node(dir)?.storage, pos, &mut set)?;
+        Ok(set)
+    }
+
+    /// Writes the fields of a node back into its directory entry.
+    fn write_entry(&self, tree: &FatTree, id: u64) -> FileSystemResult<()> {
+        let node = tree.node(id)?;
+        let Some(parent) = node.parent else {
+            // The root directory has no entry
+            return Ok(());
+        };
+        let mut set = self.read_entry_set(tree, parent, node.entry_pos, node.entry_slots)?;
+        if self.is_exfat() {
+            dirent::patch_exfat_entry_set(&mut set, &node.fields);
+        } else {
+            dirent::patch_fat_entry_set(&mut set, &node.fields);
+        }
+        self.volume
+            .write_data(&tree.node(parent)?.storage, node.entry_pos, &set)
+    }
+
+    fn remove_entry_set(&self, tree: &FatTree, dir: u64, pos: u64, slots: u32) -> FileSystemResult<()> {
+        let mut set = self.read_entry_set(tree, dir, pos, slots)?;
+        dirent::delete_entry_set(&mut set, self.is_exfat());
+        self.volume.write_data(&tree.node(dir)?.storage, pos, &set)
+    }
+
+    /// Writes a new entry set into a directory, growing it if there is no room.
+    /// Returns the position of the set.
+    fn insert_entry_set(&self, tree: &mut FatTree, dir: u64, set: &[u8]) -> FileSystemResult<u64> {
+        let is_exfat = self.is_exfat();
+        let slots = set.len() / DIR_ENTRY_SIZE;
+        let data = self.read_dir_data(tree.node(dir)?)?;
+        let total = data.len() / DIR_ENTRY_SIZE;
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for (i, slot) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            if !dirent::is_free_slot(slot, is_exfat) {
+                run_len = 0;
+                continue;
+            }
+            if run_len == 0 {
+                run_start = i;
+            }
+            if dirent::is_end_slot(slot) {
+                // Everything past the end marker is free
+                run_len += total - i;
+                break;
+            }
+            run_len += 1;
+            if run_len >= slots {
+                break;
+            }
+        }
+        let start = if run_len >= slots {
+            run_start
+        } else {
+            // Grow the directory, continuing the trailing free slots if any
+            let start = if run_len > 0 { run_start } else { total };
+            let new_size = ((start + slots) * DIR_ENTRY_SIZE) as u64;
+            if !is_exfat && new_size > MAX_FAT_DIRECTORY_SIZE {
+                return Err(anyhow::anyhow!("the directory is full").into());
+            }
+            if let DataStorage::Fixed { .. } = tree.node(dir)?.storage {
+                return Err(anyhow::anyhow!("the root directory is full").into());
+            }
+            self.resize_allocation(tree, dir, new_size)?;
+            let node = tree.node_mut(dir)?;
+            let new_size = node.storage.allocated_size(&self.volume.geometry);
+            self.volume
+                .zero_data(&node.storage, data.len() as u64, new_size - data.len() as u64)?;
+            if is_exfat && dir != ROOT_ID {
+                node.fields.size = new_size;
+                node.fields.valid_size = new_size;
+                self.write_entry(tree, dir)?;
+            }
+            start
+        };
+        let pos = (start * DIR_ENTRY_SIZE) as u64;
+        self.volume.write_data(&tree.node(dir)?.storage, pos, set)?;
+        Ok(pos)
+    }
+
+    fn build_entry_set(
+        &self,
+        tree: &FatTree,
+        dir: u64,
+        name: &str,
+        fields: &EntryFields,
+        except: u64,
+    ) -> ([u8; 11], Vec<u8>) {
+        if self.is_exfat() {
+            let set = dirent::build_exfat_entry_set(name, fields, &self.upcase);
+            ([b' '; 11], set)
+        } else {
+            let short_name =
+                dirent::generate_short_name(name, |x| tree.short_name_exists(dir, x, except));
+            let set = dirent::build_fat_entry_set(name, &short_name, fields);
+            (short_name, set)
+        }
+    }
+
+    /// Changes the number of clusters allocated to a node.
+    fn resize_allocation(&self, tree: &mut FatTree, id: u64, size: u64) -> FileSystemResult<()> {
+        let cluster_count = u32::try_from(size.div_ceil(self.volume.geometry.cluster_size))
+            .map_err(|_| FileSystemError::InvalidParameter)?;
+        let FatTree { nodes, fat, .. } = tree;
+        let node = nodes.get_mut(&id).ok_or(FileSystemError::NoSuchFile)?;
+        let DataStorage::Clusters(runs) = &mut node.storage else {
+            return Err(FileSystemError::InvalidParameter);
+        };
+        fat.resize(
+            &self.volume,
+            runs,
+            &mut node.fields.no_fat_chain,
+            cluster_count,
+        )?;
+        if cluster_count == 0 {
+            // NOTE: Allow exFAT to start over with a contiguous allocation
+            node.fields.no_fat_chain = self.is_exfat();
+        }
+        node.fields.first_cluster = node.storage.first_cluster();
+        Ok(())
+    }
+
+    fn set_file_size(&self, tree: &mut FatTree, id: u64, size: u64) -> FileSystemResult<()> {
+        if !self.is_exfat() && size > MAX_FAT_FILE_SIZE {
+            return Err(anyhow::anyhow!("file is too large for a FAT volume").into());
+        }
+        let old_size = tree.node(id)?.fields.size;
+        self.resize_allocation(tree, id, size)?;
+        let node = tree.node_mut(id)?;
+        if self.is_exfat() {
+            // NOTE: Data past the valid size reads as zeros, so there is nothing to clear
+            node.fields.valid_size = node.fields.valid_size.min(size);
+        } else {
+            if size > old_size {
+                self.volume
+                    .zero_data(&node.storage, old_size, size - old_size)?;
+            }
+            node.fields.valid_size = size;
+        }
+        node.fields.size = size;
+        Ok(())
+    }
+
+    fn create_node(
+        &self,
+        tree: &mut FatTree,
+        parent: u64,
+        name: &str,
+        is_dir: bool,
+    ) -> FileSystemResult<u64> {
+        if !dirent::is_valid_name(name) {
+            return Err(FileSystemError::ObjectNameInvalid);
+        }
+        let mut fields = EntryFields {
+            attributes: if is_dir { ATTR_DIRECTORY } else { ATTR_ARCHIVE },
+            first_cluster: 0,
+            size: 0,
+            valid_size: 0,
+            no_fat_chain: self.is_exfat(),
+            times: EntryTimes::now(),
+        };
+        let mut runs = Vec::new();
+        if is_dir {
+            // Directories always own at least one cleared cluster
+            tree.fat
+                .resize(&self.volume, &mut runs, &mut fields.no_fat_chain, 1)?;
+            let storage = DataStorage::Clusters(runs);
+            let cluster_size = self.volume.geometry.cluster_size;
+            self.volume.zero_data(&storage, 0, cluster_size)?;
+            fields.first_cluster = storage.first_cluster();
+            if self.is_exfat() {
+                fields.size = cluster_size;
+                fields.valid_size = cluster_size;
+            } else {
+                // NOTE: `..` refers to the root directory as cluster 0, even on FAT32
+                let parent_cluster = match parent {
+                    ROOT_ID => 0,
+                    _ => tree.node(parent)?.fields.first_cluster,
+                };
+                let dots = dirent::build_fat_dot_entries(fields.first_cluster, parent_cluster, &fields);
+                self.volume.write_data(&storage, 0, &dots)?;
+            }
+            let DataStorage::Clusters(x) = storage else {
+                unreachable!()
+            };
+            runs = x;
+        }
+        let (short_name, set) = self.build_entry_set(tree, parent, name, &fields, 0);
+        let pos = match self.insert_entry_set(tree, parent, &set) {
+            Ok(x) => x,
+            Err(e) => {
+                // Release the directory cluster again
+                let _ = tree
+                    .fat
+                    .resize(&self.volume, &mut runs, &mut fields.no_fat_chain, 0);
+                return Err(e);
+            }
+        };
+        let id = tree.next_id;
+        tree.next_id += 1;
+        tree.nodes.insert(
+            id,
+            FatNode {
+                name: name.to_owned(),
+                short_name,
+                fields,
+                storage: DataStorage::Clusters(runs),
+                parent: Some(parent),
+                entry_pos: pos,
+                entry_slots: (set.len() / DIR_ENTRY_SIZE) as u32,
+                children: is_dir.then(BTreeMap::new),
+                open_count: 0,
+                delete_on_close: false,
+            },
+        );
+        if let Some(children) = &mut tree.node_mut(parent)?.children {
+            children.insert(name.into(), id);
+        }
+        Ok(id)
+    }
+
+    fn delete_node(&self, tree: &mut FatTree, id: u64) -> FileSystemResult<()> {
+        let node = tree.node(id)?;
+        let parent = node.parent.ok_or(FileSystemError::CannotDelete)?;
+        if node.is_dir() {
+            self.load_children(tree, id)?;
+            if tree.node(id)?.children.as_ref().is_some_and(|x| !x.is_empty()) {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+        }
+        // NOTE: Remove the entry before freeing clusters, so that an interrupted
+        //       deletion leaks clusters instead of cross-linking them
+        let node = tree.node(id)?;
+        self.remove_entry_set(tree, parent, node.entry_pos, node.entry_slots)?;
+        self.resize_allocation(tree, id, 0)?;
+        let node = tree.nodes.remove(&id).unwrap();
+        if let Some(children) = &mut tree.node_mut(parent)?.children {
+            children.remove(CaselessStr::new(&node.name));
+        }
+        Ok(())
+    }
+
+    fn move_node(
+        &self,
+        tree: &mut FatTree,
+        id: u64,
+        new_path: SegPath,
+        replace_if_exists: bool,
+    ) -> FileSystemResult<()> {
+        let (new_parent, new_name) = self.resolve_path(tree, new_path)?;
+        let new_parent = new_parent.ok_or(FileSystemError::AccessDenied)?;
+        let old_parent = tree.node(id)?.parent.ok_or(FileSystemError::AccessDenied)?;
+        if !dirent::is_valid_name(new_name) {
+            return Err(FileSystemError::ObjectNameInvalid);
+        }
+        // A folder cannot be moved into itself
+        let mut cur = Some(new_parent);
+        while let Some(x) = cur {
+            if x == id {
+                return Err(FileSystemError::InvalidParameter);
+            }
+            cur = tree.node(x)?.parent;
+        }
+        match tree.find_child(new_parent, new_name)? {
+            Some(existing) if existing != id => {
+                if !replace_if_exists {
+                    return Err(FileSystemError::ObjectNameCollision);
+                }
+                let existing_node = tree.node(existing)?;
+                if existing_node.is_dir() || existing_node.open_count > 0 {
+                    return Err(FileSystemError::AccessDenied);
+                }
+                self.delete_node(tree, existing)?;
+            }
+            _ => {}
+        }
+
+        // Write the new entry first, then retire the old one
+        let node = tree.node(id)?;
+        let (fields, old_pos, old_slots) = (node.fields, node.entry_pos, node.entry_slots);
+        let old_name = node.name.clone();
+        let (short_name, set) = self.build_entry_set(tree, new_parent, new_name, &fields, id);
+        let pos = self.insert_entry_set(tree, new_parent, &set)?;
+        self.remove_entry_set(tree, old_parent, old_pos, old_slots)?;
+
+        if let Some(children) = &mut tree.node_mut(old_parent)?.children {
+            children.remove(CaselessStr::new(&old_name));
+        }
+        if let Some(children) = &mut tree.node_mut(new_parent)?.children {
+            children.insert(new_name.into(), id);
+        }
+        let node = tree.node_mut(id)?;
+        node.name = new_name.to_owned();
+        node.short_name = short_name;
+        node.parent = Some(new_parent);
+        node.entry_pos = pos;
+        node.entry_slots = (set.len() / DIR_ENTRY_SIZE) as u32;
+
+        if !self.is_exfat() && fields.attributes & ATTR_DIRECTORY != 0 && old_parent != new_parent
+        {
+            // Point `..` at the new parent
+            let parent_cluster = match new_parent {
+                ROOT_ID => 0,
+                _ => tree.node(new_parent)?.fields.first_cluster,
+            };
+            let storage = &tree.node(id)?.storage;
+            let mut slot = [0; DIR_ENTRY_SIZE];
+            self.volume
+                .read_data(storage, DIR_ENTRY_SIZE as u64, &mut slot)?;
+            if &slot[..2] == b".." {
+                dirent::set_fat_entry_cluster(&mut slot, parent_cluster);
+                self.volume
+                    .write_data(storage, DIR_ENTRY_SIZE as u64, &slot)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FileSystemHandler for FatFsHandler {
+    fn create_file(
+        &self,
+        filename: SegPath,
+        _desired_access: FileDesiredAccess,
+        _file_attributes: FileAttributes,
+        _share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> FileSystemResult<super::CreateFileInfo<'_>> {
+        use FileCreateDisposition::*;
+
+        let expects_dir = create_options.contains(FileCreateOptions::DirectoryFile);
+        let expects_nondir = create_options.contains(FileCreateOptions::NonDirectoryFile);
+        let delete_on_close = create_options.contains(FileCreateOptions::DeleteOnClose);
+
+        let mut tree = self.tree.write().unwrap();
+        let tree = &mut *tree;
+        let (parent, filename) = self.resolve_path(tree, filename)?;
+
+        let mut new_file_created = false;
+        let id = if let Some(parent) = parent {
+            match tree.find_child(parent, filename)? {
+                Some(id) => {
+                    if tree.node(id)?.is_dir() {
+                        if expects_nondir {
+                            return Err(FileSystemError::FileIsADirectory);
+                        }
+                        if let CreateNew = create_disposition {
+                            return Err(FileSystemError::ObjectNameCollision);
+                        }
+                    } else {
+                        if expects_dir {
+                            return Err(FileSystemError::NotADirectory);
+                        }
+                        match create_disposition {
+                            CreateAlways | TruncateExisting => {
+                                self.ensure_writable()?;
+                                self.set_file_size(tree, id, 0)?;
+                                self.write_entry(tree, id)?;
+                            }
+                            OpenAlways | OpenExisting => {}
+                            CreateNew => return Err(FileSystemError::ObjectNameCollision),
+                        }
+                    }
+                    id
+                }
+                None => match create_disposition {
+                    CreateAlways | CreateNew | OpenAlways => {
+                        self.ensure_writable()?;
+                        new_file_created = true;
+                        self.create_node(tree, parent, filename, expects_dir)?
+                    }
+                    _ => return Err(FileSystemError::ObjectNameNotFound),
+                },
+            }
+        } else {
+            // Root folder
+            if expects_nondir {
+                return Err(FileSystemError::FileIsADirectory);
+            }
+            if delete_on_close {
+                return Err(FileSystemError::AccessDenied);
+            }
+            if let CreateNew = create_disposition {
+                return Err(FileSystemError::ObjectNameCollision);
+            }
+            ROOT_ID
+        };
+
+        if delete_on_close {
+            self.ensure_writable()?;
+        }
+        let is_dir = tree.node(id)?.is_dir();
+        if is_dir {
+            // NOTE: Load children now, so that listing only takes a read lock
+            self.load_children(tree, id)?;
+        }
+        let node = tree.node_mut(id)?;
+        node.open_count += 1;
+        node.delete_on_close |= delete_on_close;
+
+        Ok(super::CreateFileInfo {
+            context: Box::new(FatFsFile { handler: self, id }),
+            is_dir,
+            new_file_created,
+        })
+    }
+    fn get_fs_free_space(&self) -> FileSystemResult<super::FileSystemSpaceInfo> {
+        let tree = self.tree.read().unwrap();
+        let cluster_size = self.volume.geometry.cluster_size;
+        let free = tree.fat.free_count() as u64 * cluster_size;
+        Ok(super::FileSystemSpaceInfo {
+            bytes_count: self.volume.geometry.cluster_count as u64 * cluster_size,
+            free_bytes_count: free,
+            available_bytes_count: free,
+        })
+    }
+    fn get_fs_characteristics(&self) -> FileSystemResult<super::FileSystemCharacteristics> {
+        Ok(if self.writable {
+            super::FileSystemCharacteristics::empty()
+        } else {
+            super::FileSystemCharacteristics::ReadOnly
+        })
+    }
+}
+
+impl super::File for FatFsFile<'_> {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<u64> {
+        let tree = self.handler.tree.read().unwrap();
+        let node = tree.node(self.id)?;
+        if node.is_dir() {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        let size = node.fields.size;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+        let buffer = &mut buffer[..len];
+        // NOTE: Data past the valid size (exFAT) reads as zeros
+        let valid_len = node.fields.valid_size.saturating_sub(offset).min(len as u64) as usize;
+        self.handler
+            .volume
+            .read_data(&node.storage, offset, &mut buffer[..valid_len])?;
+        buffer[valid_len..].fill(0);
+        Ok(len as u64)
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> FileSystemResult<u64> {
+        self.handler.ensure_writable()?;
+        let mut tree = self.handler.tree.write().unwrap();
+        let tree = &mut *tree;
+        let node = tree.node(self.id)?;
+        if node.is_dir() {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        let size = node.fields.size;
+        let offset = offset.unwrap_or(size);
+        let len = if constrain_size {
+            if offset >= size {
+                return Ok(0);
+            }
+            buffer.len().min((size - offset) as usize)
+        } else {
+            buffer.len()
+        };
+        let end = offset + len as u64;
+        if end > size {
+            self.handler.set_file_size(tree, self.id, end)?;
+        }
+        let node = tree.node_mut(self.id)?;
+        let volume = &self.handler.volume;
+        if node.fields.valid_size < offset {
+            volume.zero_data(
+                &node.storage,
+                node.fields.valid_size,
+                offset - node.fields.valid_size,
+            )?;
+        }
+        volume.write_data(&node.storage, offset, &buffer[..len])?;
+        node.fields.valid_size = node.fields.valid_size.max(end);
+        node.fields.times.last_write_time = SystemTime::now();
+        node.fields.attributes |= ATTR_ARCHIVE;
+        self.handler.write_entry(tree, self.id)?;
+        Ok(len as u64)
+    }
+    fn flush_buffers(&self) -> FileSystemResult<()> {
+        self.handler.ensure_writable()?;
+        self.handler.volume.io.flush()
+    }
+    fn get_stat(&self) -> FileSystemResult<super::FileStatInfo> {
+        let tree = self.handler.tree.read().unwrap();
+        Ok(tree.node(self.id)?.get_file_stat_info(self.id))
+    }
+    fn set_end_of_file(&self, offset: u64) -> FileSystemResult<()> {
+        self.handler.ensure_writable()?;
+        let mut tree = self.handler.tree.write().unwrap();
+        let tree = &mut *tree;
+        if tree.node(self.id)?.is_dir() {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        self.handler.set_file_size(tree, self.id, offset)?;
+        let node = tree.node_mut(self.id)?;
+        node.fields.times.last_write_time = SystemTime::now();
+        node.fields.attributes |= ATTR_ARCHIVE;
+        self.handler.write_entry(tree, self.id)
+    }
+    fn set_file_times(
+        &self,
+        creation_time: SystemTime,
+        last_access_time: SystemTime,
+        last_write_time: SystemTime,
+    ) -> FileSystemResult<()> {
+        self.handler.ensure_writable()?;
+        let mut tree = self.handler.tree.write().unwrap();
+        let times = &mut tree.node_mut(self.id)?.fields.times;
+        let zero_t = unsafe { std::mem::zeroed() };
+        if creation_time != zero_t {
+            times.creation_time = creation_time;
+        }
+        if last_access_time != zero_t {
+            times.last_access_time = last_access_time;
+        }
+        if last_write_time != zero_t {
+            times.last_write_time = last_write_time;
+        }
+        self.handler.write_entry(&tree, self.id)
+    }
+    fn set_delete(&self, delete_on_close: bool) -> FileSystemResult<()> {
+        log::trace!("Set delete: {delete_on_close}");
+        self.handler.ensure_writable()?;
+        if self.id == ROOT_ID {
+            return Err(FileSystemError::CannotDelete);
+        }
+        let mut tree = self.handler.tree.write().unwrap();
+        let tree = &mut *tree;
+        if delete_on_close && tree.node(self.id)?.is_dir() {
+            self.handler.load_children(tree, self.id)?;
+            if tree.node(self.id)?.children.as_ref().is_some_and(|x| !x.is_empty()) {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+        }
+        tree.node_mut(self.id)?.delete_on_close = delete_on_close;
+        Ok(())
+    }
+    fn move_to(&self, new_path: SegPath, replace_if_exists: bool) -> FileSystemResult<()> {
+        log::trace!(
+            "Move to: {}, replace: {replace_if_exists}",
+            new_path.raw_path
+        );
+        self.handler.ensure_writable()?;
+        let mut tree = self.handler.tree.write().unwrap();
+        self.handler
+            .move_node(&mut tree, self.id, new_path, replace_if_exists)
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn super::FilePattern,
+        filler: &mut dyn super::FindFilesDataFiller,
+    ) -> FileSystemResult<()> {
+        let tree = self.handler.tree.read().unwrap();
+        let node = tree.node(self.id)?;
+        if !node.is_dir() {
+            return Err(FileSystemError::NotADirectory);
+        }
+        // NOTE: Children are loaded when directories are opened
+        let children = node.children.as_ref().ok_or(FileSystemError::FileCorruptError)?;
+        for &child in children.values() {
+            let Ok(child_node) = tree.node(child) else {
+                continue;
+            };
+            if !pattern.check_name(&child_node.name) {
+                continue;
+            }
+            if filler
+                .fill_data(&child_node.name, &child_node.get_file_stat_info(child))
+                .is_err()
+            {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FatFsHandler {
+    fn drop(&mut self) {
+        if self.clears_exfat_dirty {
+            if let Err(e) = self.volume.io.set_exfat_dirty(false) {
+                log::warn!("Failed to clear exFAT VolumeDirty flag: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for FatFsFile<'_> {
+    fn drop(&mut self) {
+        let mut tree = self.handler.tree.write().unwrap();
+        let tree = &mut *tree;
+        let Ok(node) = tree.node_mut(self.id) else {
+            return;
+        };
+        node.open_count -= 1;
+        if node.open_count > 0 || !node.delete_on_close {
+            return;
+        }
+        log::trace!("Removing file `{}`...", node.name);
+        node.delete_on_close = false;
+        if let Err(e) = self.handler.delete_node(tree, self.id) {
+            log::warn!("Failed to delete file: {e}");
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FatFsConfig {
+    /// The disk or volume image to open.
+    image_path: FsWithPathConfig,
+    /// Index of the partition to use, in partition table order. If not set, the image
+    /// is used as a bare volume, or else the first FAT / exFAT partition is picked.
+    #[serde(default)]
+    partition: Option<usize>,
+    /// Whether changes are written back to the image.
+    #[serde(default)]
+    writable: bool,
+}
+
+pub struct FatFsProvider {}
+impl super::FsProvider for FatFsProvider {
+    fn get_id(&self) -> Uuid {
+        FATFS_ID
+    }
+    fn get_name(&self) -> &'static str {
+        "FatFS"
+    }
+    fn get_version(&self) -> (u32, u32, u32) {
+        (0, 1, 0)
+    }
+    fn construct(
+        &self,
+        config: serde_json::Value,
+        ctx: &mut dyn super::FileSystemCreationContext,
+    ) -> Result<Arc<dyn FileSystemHandler>, super::FileSystemCreationError> {
+        let mut config: FatFsConfig = serde_json::from_value(config)
+            .map_err(|e| super::FileSystemCreationError::Other(e.into()))?;
+        // Translate slashes
+        super::make_uniform_path(&mut config.image_path.path);
+        let image_fs = ctx.get_or_run_fs(&config.image_path.id, "")?;
+        let handler = FatFsHandler::new(
+            image_fs,
+            &config.image_path.path,
+            config.partition,
+            config.writable,
+        )
+        .map_err(|e| {
+            log::warn!("Open FAT image `{}` failed: {e}", config.image_path.path);
+            super::FileSystemCreationError::InvalidFileSystem
+        })?;
+        Ok(Arc::new(handler))
+    }
+    fn get_template_config(&self) -> serde_json::Value {
+        serde_json::to_value(FatFsConfig {
+            image_path: Default::default(),
+            partition: None,
+            writable: false,
+        })
+        .unwrap()
+    }
+}
+
+impl FatFsProvider {
+    pub fn new() -> Self {
+        FatFsProvider {}
+    }
+}
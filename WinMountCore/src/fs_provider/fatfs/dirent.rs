@@ -0,0 +1,656 @@
+// Directory entries of FAT (8.3 names with VFAT long names) and exFAT (entry sets) volumes
+
+use std::time::{Duration, SystemTime};
+
+use crate::fs_provider::bytes::{le_u16, le_u32, le_u64};
+
+pub(super) const DIR_ENTRY_SIZE: usize = 32;
+
+pub(super) const ATTR_READ_ONLY: u16 = 0x01;
+pub(super) const ATTR_HIDDEN: u16 = 0x02;
+const ATTR_VOLUME_ID: u16 = 0x08;
+pub(super) const ATTR_DIRECTORY: u16 = 0x10;
+pub(super) const ATTR_ARCHIVE: u16 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+const FAT_ENTRY_END: u8 = 0x00;
+const FAT_ENTRY_DELETED: u8 = 0xE5;
+// NOTE: A leading 0xE5 of a short name is stored as 0x05
+const FAT_ENTRY_KANJI_E5: u8 = 0x05;
+const FAT_LFN_LAST: u8 = 0x40;
+const FAT_LFN_CHARS: usize = 13;
+const FAT_LFN_CHAR_OFFSETS: [usize; FAT_LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const FAT_CASE_LOWER_BASE: u8 = 0x08;
+const FAT_CASE_LOWER_EXT: u8 = 0x10;
+
+pub(super) const EXFAT_ENTRY_BITMAP: u8 = 0x81;
+pub(super) const EXFAT_ENTRY_UPCASE: u8 = 0x82;
+const EXFAT_ENTRY_FILE: u8 = 0x85;
+const EXFAT_ENTRY_STREAM: u8 = 0xC0;
+const EXFAT_ENTRY_NAME: u8 = 0xC1;
+const EXFAT_ENTRY_IN_USE: u8 = 0x80;
+const EXFAT_STREAM_ALLOCATION_POSSIBLE: u8 = 0x01;
+const EXFAT_STREAM_NO_FAT_CHAIN: u8 = 0x02;
+const EXFAT_NAME_CHARS: usize = 15;
+
+pub(super) const MAX_NAME_LENGTH: usize = 255;
+
+// Upper half of code page 437, the default OEM code page for short names
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}";
+
+#[derive(Clone, Copy)]
+pub(super) struct EntryTimes {
+    pub creation_time: SystemTime,
+    pub last_access_time: SystemTime,
+    pub last_write_time: SystemTime,
+}
+
+impl EntryTimes {
+    pub fn now() -> Self {
+        let now = SystemTime::now();
+        Self {
+            creation_time: now,
+            last_access_time: now,
+            last_write_time: now,
+        }
+    }
+}
+
+/// Fields of a directory entry that can change over the lifetime of a file.
+#[derive(Clone, Copy)]
+pub(super) struct EntryFields {
+    pub attributes: u16,
+    pub first_cluster: u32,
+    pub size: u64,
+    // NOTE: exFAT only; data past the valid size reads as zeros
+    pub valid_size: u64,
+    // NOTE: exFAT only; the allocation is contiguous and not recorded in the FAT
+    pub no_fat_chain: bool,
+    pub times: EntryTimes,
+}
+
+pub(super) struct ParsedEntry {
+    pub name: String,
+    // NOTE: FAT only
+    pub short_name: [u8; 11],
+    pub fields: EntryFields,
+    /// Byte offset of the entry set inside the directory.
+    pub pos: u64,
+    pub slots: u32,
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn dos_time_to_system_time<Tz: chrono::TimeZone>(
+    tz: &Tz,
+    date: u16,
+    time: u16,
+    centis: u8,
+) -> Option<SystemTime> {
+    if date == 0 {
+        return None;
+    }
+    let year = 1980 + (date >> 9) as i32;
+    let (month, day) = ((date >> 5) as u32 & 0xF, date as u32 & 0x1F);
+    let (hour, minute, second) = ((time >> 11) as u32, (time >> 5) as u32 & 0x3F, (time as u32 & 0x1F) * 2);
+    tz.with_ymd_and_hms(year, month, day, hour, minute, second)
+        .earliest()
+        .map(|t| SystemTime::from(t) + Duration::from_millis(centis.min(199) as u64 * 10))
+}
+
+// Returns (date, time, 10ms increments)
+fn system_time_to_dos_time<Tz: chrono::TimeZone>(t: &chrono::DateTime<Tz>) -> (u16, u16, u8) {
+    use chrono::{Datelike, Timelike};
+    match t.year() {
+        ..=1979 => return (0x21, 0, 0),
+        2108.. => return (0xFF9F, 0xBF7D, 199),
+        _ => {}
+    }
+    let date = ((t.year() - 1980) as u16) << 9 | (t.month() as u16) << 5 | t.day() as u16;
+    let time = (t.hour() as u16) << 11 | (t.minute() as u16) << 5 | (t.second() as u16 / 2);
+    let centis = (t.second() % 2) as u8 * 100 + (t.nanosecond() / 10_000_000).min(99) as u8;
+    (date, time, centis)
+}
+
+// NOTE: FAT timestamps carry no time zone and are in local time by convention
+fn fat_time(date: u16, time: u16, centis: u8) -> Option<SystemTime> {
+    dos_time_to_system_time(&chrono::Local, date, time, centis)
+}
+
+fn exfat_time(timestamp: u32, centis: u8, utc_offset: u8) -> Option<SystemTime> {
+    let (date, time) = ((timestamp >> 16) as u16, timestamp as u16);
+    if utc_offset & 0x80 != 0 {
+        // Signed 7-bit offset in 15 minute increments
+        let offset = (((utc_offset << 1) as i8) >> 1) as i32 * 15 * 60;
+        dos_time_to_system_time(&chrono::FixedOffset::east_opt(offset)?, date, time, centis)
+    } else {
+        dos_time_to_system_time(&chrono::Local, date, time, centis)
+    }
+}
+
+// Returns (timestamp, 10ms increments, UTC offset)
+fn system_time_to_exfat_time(t: SystemTime) -> (u32, u8, u8) {
+    use chrono::Offset;
+    let t = chrono::DateTime::<chrono::Local>::from(t);
+    let (date, time, centis) = system_time_to_dos_time(&t);
+    let offset = t.offset().fix().local_minus_utc() / (15 * 60);
+    ((date as u32) << 16 | time as u32, centis, 0x80 | (offset as u8 & 0x7F))
+}
+
+fn make_times(
+    creation_time: Option<SystemTime>,
+    last_access_time: Option<SystemTime>,
+    last_write_time: Option<SystemTime>,
+) -> EntryTimes {
+    let last_write_time = last_write_time.unwrap_or(SystemTime::UNIX_EPOCH);
+    EntryTimes {
+        creation_time: creation_time.unwrap_or(last_write_time),
+        last_access_time: last_access_time.unwrap_or(last_write_time),
+        last_write_time,
+    }
+}
+
+fn decode_oem(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0..=0x7F => b as char,
+            _ => CP437_HIGH.chars().nth(b as usize - 0x80).unwrap(),
+        })
+        .collect()
+}
+
+pub(super) fn decode_short_name(short_name: &[u8; 11], case_flags: u8) -> String {
+    let mut base = short_name[..8].to_vec();
+    if base[0] == FAT_ENTRY_KANJI_E5 {
+        base[0] = FAT_ENTRY_DELETED;
+    }
+    let base_len = base.iter().rposition(|&x| x != b' ').map_or(0, |x| x + 1);
+    let ext_len = short_name[8..]
+        .iter()
+        .rposition(|&x| x != b' ')
+        .map_or(0, |x| x + 1);
+    let mut name = decode_oem(&base[..base_len]);
+    if case_flags & FAT_CASE_LOWER_BASE != 0 {
+        name.make_ascii_lowercase();
+    }
+    if ext_len > 0 {
+        let mut ext = decode_oem(&short_name[8..8 + ext_len]);
+        if case_flags & FAT_CASE_LOWER_EXT != 0 {
+            ext.make_ascii_lowercase();
+        }
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+pub(super) fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Parses a FAT directory, skipping the volume label, dot entries and deleted entries.
+pub(super) fn parse_fat_directory(data: &[u8]) -> Vec<ParsedEntry> {
+    struct LongName {
+        parts: Vec<[u16; FAT_LFN_CHARS]>,
+        next_ord: u8,
+        checksum: u8,
+        start_slot: usize,
+    }
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for (i, slot) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        match slot[0] {
+            FAT_ENTRY_END => break,
+            FAT_ENTRY_DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = slot[11];
+        if attributes & 0x3F == ATTR_LONG_NAME {
+            let ord = slot[0] & 0x1F;
+            let chars = FAT_LFN_CHAR_OFFSETS.map(|x| le_u16(slot, x));
+            if slot[0] & FAT_LFN_LAST != 0 && ord != 0 {
+                let mut parts = vec![[0; FAT_LFN_CHARS]; ord as usize];
+                parts[ord as usize - 1] = chars;
+                long_name = Some(LongName {
+                    parts,
+                    next_ord: ord - 1,
+                    checksum: slot[13],
+                    start_slot: i,
+                });
+            } else {
+                long_name = match long_name.take() {
+                    Some(mut x) if x.next_ord == ord && ord != 0 && x.checksum == slot[13] => {
+                        x.parts[ord as usize - 1] = chars;
+                        x.next_ord -= 1;
+                        Some(x)
+                    }
+                    _ => None,
+                };
+            }
+            continue;
+        }
+        let long_name = long_name.take();
+        if attributes as u16 & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short_name: [u8; 11] = slot[..11].try_into().unwrap();
+        if short_name[0] == b'.' {
+            // Skip `.` and `..`
+            continue;
+        }
+        let long_name = long_name
+            .filter(|x| x.next_ord == 0 && x.checksum == lfn_checksum(&short_name))
+            .map(|x| {
+                let units: Vec<u16> = x.parts.iter().flatten().copied().collect();
+                let len = units.iter().position(|&c| c == 0).unwrap_or(units.len());
+                (String::from_utf16_lossy(&units[..len]), x.start_slot)
+            })
+            .filter(|x| !x.0.is_empty());
+        let (name, start_slot) =
+            long_name.unwrap_or_else(|| (decode_short_name(&short_name, slot[12]), i));
+        let first_cluster = (le_u16(slot, 20) as u32) << 16 | le_u16(slot, 26) as u32;
+        let size = le_u32(slot, 28) as u64;
+        entries.push(ParsedEntry {
+            name,
+            short_name,
+            fields: EntryFields {
+                attributes: attributes as u16,
+                first_cluster,
+                size,
+                valid_size: size,
+                no_fat_chain: false,
+                times: make_times(
+                    fat_time(le_u16(slot, 16), le_u16(slot, 14), slot[13]),
+                    fat_time(le_u16(slot, 18), 0, 0),
+                    fat_time(le_u16(slot, 24), le_u16(slot, 22), 0),
+                ),
+            },
+            pos: (start_slot * DIR_ENTRY_SIZE) as u64,
+            slots: (i - start_slot + 1) as u32,
+        });
+    }
+    entries
+}
+
+/// Updates the short entry (the last slot) of a FAT entry set.
+pub(super) fn patch_fat_entry_set(set: &mut [u8], fields: &EntryFields) {
+    let slot = set.len() - DIR_ENTRY_SIZE;
+    let slot = &mut set[slot..];
+    slot[11] = fields.attributes as u8;
+    let creation_time = chrono::DateTime::<chrono::Local>::from(fields.times.creation_time);
+    let (date, time, centis) = system_time_to_dos_time(&creation_time);
+    slot[13] = centis;
+    put_u16(slot, 14, time);
+    put_u16(slot, 16, date);
+    let last_access_time = chrono::DateTime::<chrono::Local>::from(fields.times.last_access_time);
+    put_u16(slot, 18, system_time_to_dos_time(&last_access_time).0);
+    put_u16(slot, 20, (fields.first_cluster >> 16) as u16);
+    let last_write_time = chrono::DateTime::<chrono::Local>::from(fields.times.last_write_time);
+    let (date, time, _) = system_time_to_dos_time(&last_write_time);
+    put_u16(slot, 22, time);
+    put_u16(slot, 24, date);
+    put_u16(slot, 26, fields.first_cluster as u16);
+    // NOTE: Directories always record a size of 0
+    let size = if fields.attributes & ATTR_DIRECTORY != 0 {
+        0
+    } else {
+        fields.size as u32
+    };
+    put_u32(slot, 28, size);
+}
+
+/// Builds a FAT entry set, with long name slots if the short name does not
+/// represent the name exactly.
+pub(super) fn build_fat_entry_set(
+    name: &str,
+    short_name: &[u8; 11],
+    fields: &EntryFields,
+) -> Vec<u8> {
+    let mut set = Vec::new();
+    if decode_short_name(short_name, 0) != name {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let count = units.len().div_ceil(FAT_LFN_CHARS);
+        let checksum = lfn_checksum(short_name);
+        // NOTE: Long name slots are stored in reverse order
+        for ord in (1..=count).rev() {
+            let mut slot = [0; DIR_ENTRY_SIZE];
+            slot[0] = ord as u8 | if ord == count { FAT_LFN_LAST } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (i, offset) in FAT_LFN_CHAR_OFFSETS.into_iter().enumerate() {
+                let index = (ord - 1) * FAT_LFN_CHARS + i;
+                let c = match index.cmp(&units.len()) {
+                    std::cmp::Ordering::Less => units[index],
+                    std::cmp::Ordering::Equal => 0,
+                    std::cmp::Ordering::Greater => 0xFFFF,
+                };
+                put_u16(&mut slot, offset, c);
+            }
+            set.extend_from_slice(&slot);
+        }
+    }
+    let mut slot = [0; DIR_ENTRY_SIZE];
+    slot[..11].copy_from_slice(short_name);
+    set.extend_from_slice(&slot);
+    patch_fat_entry_set(&mut set, fields);
+    set
+}
+
+/// Points a FAT short entry (e.g. `..`) at another cluster.
+pub(super) fn set_fat_entry_cluster(slot: &mut [u8], cluster: u32) {
+    put_u16(slot, 20, (cluster >> 16) as u16);
+    put_u16(slot, 26, cluster as u16);
+}
+
+/// Builds the `.` and `..` entries of a new FAT directory.
+pub(super) fn build_fat_dot_entries(
+    self_cluster: u32,
+    parent_cluster: u32,
+    fields: &EntryFields,
+) -> Vec<u8> {
+    let mut fields = *fields;
+    let mut set = vec![b' '; 2 * DIR_ENTRY_SIZE];
+    set[0] = b'.';
+    set[DIR_ENTRY_SIZE..DIR_ENTRY_SIZE + 2].copy_from_slice(b"..");
+    for (slot, cluster) in set
+        .chunks_exact_mut(DIR_ENTRY_SIZE)
+        .zip([self_cluster, parent_cluster])
+    {
+        slot[11..].fill(0);
+        fields.first_cluster = cluster;
+        patch_fat_entry_set(slot, &fields);
+    }
+    set
+}
+
+// Converts a name component into short name characters, returning whether anything was lost
+fn to_short_name_chars(s: &str) -> (Vec<u8>, bool) {
+    let mut lossy = false;
+    let chars = s
+        .chars()
+        .filter_map(|c| match c {
+            ' ' | '.' => {
+                lossy = true;
+                None
+            }
+            c if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) => {
+                Some(c.to_ascii_uppercase() as u8)
+            }
+            _ => {
+                lossy = true;
+                Some(b'_')
+            }
+        })
+        .collect();
+    (chars, lossy)
+}
+
+/// Generates a unique short name for a long name, as Windows does
+/// (e.g. `Long File Name.txt` becomes `LONGFI~1.TXT`).
+pub(super) fn generate_short_name(name: &str, exists: impl Fn(&[u8; 11]) -> bool) -> [u8; 11] {
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+    let (mut base, base_lossy) = to_short_name_chars(base);
+    let (mut ext, ext_lossy) = to_short_name_chars(ext);
+    let mut lossy = base_lossy || ext_lossy || trimmed.len() != name.len();
+    if base.is_empty() {
+        base.push(b'_');
+        lossy = true;
+    }
+    if base.len() > 8 || ext.len() > 3 {
+        lossy = true;
+        ext.truncate(3);
+    }
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + ext.len()].copy_from_slice(&ext);
+    if !lossy {
+        short_name[..base.len()].copy_from_slice(&base);
+        if !exists(&short_name) {
+            return short_name;
+        }
+    }
+    for n in 1..=999999u32 {
+        let tail = format!("~{n}");
+        let keep = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !exists(&short_name) {
+            break;
+        }
+    }
+    short_name
+}
+
+pub(super) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= MAX_NAME_LENGTH
+        && !name
+            .chars()
+            .any(|c| c < ' ' || matches!(c, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|'))
+}
+
+/// The exFAT up-case table, used for name hashes.
+pub(super) struct UpcaseTable(Vec<u16>);
+
+impl UpcaseTable {
+    // NOTE: Used when the volume carries no up-case table
+    pub fn ascii() -> Self {
+        Self((0..=u16::MAX).map(|c| match c {
+            0x61..=0x7A => c - 0x20,
+            _ => c,
+        }).collect())
+    }
+    /// Decodes a (possibly compressed) up-case table.
+    pub fn decode(data: &[u8]) -> Self {
+        let mut table = Vec::with_capacity(0x10000);
+        let mut iter = data.chunks_exact(2).map(|x| u16::from_le_bytes([x[0], x[1]]));
+        while let Some(c) = iter.next() {
+            if table.len() >= 0x10000 {
+                break;
+            }
+            if c == 0xFFFF {
+                // A run of identity mappings
+                let count = iter.next().unwrap_or(0) as usize;
+                let start = table.len();
+                table.extend((start..(start + count).min(0x10000)).map(|x| x as u16));
+            } else {
+                table.push(c);
+            }
+        }
+        let start = table.len();
+        table.extend((start..0x10000).map(|x| x as u16));
+        Self(table)
+    }
+    fn upcase(&self, c: u16) -> u16 {
+        self.0[c as usize]
+    }
+    fn name_hash(&self, units: &[u16]) -> u16 {
+        units
+            .iter()
+            .flat_map(|&c| self.upcase(c).to_le_bytes())
+            .fold(0u16, |hash, b| hash.rotate_right(1).wrapping_add(b as u16))
+    }
+}
+
+fn exfat_set_checksum(set: &[u8]) -> u16 {
+    set.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 2 && *i != 3)
+        .fold(0u16, |sum, (_, &b)| sum.rotate_right(1).wrapping_add(b as u16))
+}
+
+/// Parses an exFAT directory. Critical primary entries other than files
+/// (e.g. the allocation bitmap) are passed to `on_other`.
+pub(super) fn parse_exfat_directory(
+    data: &[u8],
+    mut on_other: impl FnMut(&[u8]),
+) -> Vec<ParsedEntry> {
+    let mut entries = Vec::new();
+    let slot_count = data.len() / DIR_ENTRY_SIZE;
+    let slot_at = |i: usize| &data[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE];
+    let mut i = 0;
+    while i < slot_count {
+        let slot = slot_at(i);
+        match slot[0] {
+            0 => break,
+            x if x & EXFAT_ENTRY_IN_USE == 0 => {
+                i += 1;
+                continue;
+            }
+            EXFAT_ENTRY_FILE => {}
+            _ => {
+                on_other(slot);
+                i += 1;
+                continue;
+            }
+        }
+        let secondary_count = slot[1] as usize;
+        if secondary_count < 2 || i + secondary_count >= slot_count {
+            log::warn!("Invalid exFAT entry set at slot {i}");
+            i += 1;
+            continue;
+        }
+        let set = &data[i * DIR_ENTRY_SIZE..(i + 1 + secondary_count) * DIR_ENTRY_SIZE];
+        let stream = slot_at(i + 1);
+        if exfat_set_checksum(set) != le_u16(slot, 2) || stream[0] != EXFAT_ENTRY_STREAM {
+            log::warn!("Corrupted exFAT entry set at slot {i}");
+            i += 1;
+            continue;
+        }
+        let name_len = stream[3] as usize;
+        let units: Vec<u16> = (i + 2..i + 1 + secondary_count)
+            .map(slot_at)
+            .take_while(|x| x[0] == EXFAT_ENTRY_NAME)
+            .flat_map(|x| (0..EXFAT_NAME_CHARS).map(|j| le_u16(x, 2 + j * 2)))
+            .take(name_len)
+            .collect();
+        let size = le_u64(stream, 24);
+        entries.push(ParsedEntry {
+            name: String::from_utf16_lossy(&units),
+            short_name: [b' '; 11],
+            fields: EntryFields {
+                attributes: le_u16(slot, 4),
+                first_cluster: le_u32(stream, 20),
+                size,
+                valid_size: le_u64(stream, 8).min(size),
+                no_fat_chain: stream[1] & EXFAT_STREAM_NO_FAT_CHAIN != 0,
+                times: make_times(
+                    exfat_time(le_u32(slot, 8), slot[20], slot[22]),
+                    exfat_time(le_u32(slot, 16), 0, slot[24]),
+                    exfat_time(le_u32(slot, 12), slot[21], slot[23]),
+                ),
+            },
+            pos: (i * DIR_ENTRY_SIZE) as u64,
+            slots: (1 + secondary_count) as u32,
+        });
+        i += 1 + secondary_count;
+    }
+    entries
+}
+
+/// Updates the file and stream extension entries of an exFAT entry set.
+pub(super) fn patch_exfat_entry_set(set: &mut [u8], fields: &EntryFields) {
+    put_u16(set, 4, fields.attributes);
+    let (timestamp, centis, utc_offset) = system_time_to_exfat_time(fields.times.creation_time);
+    put_u32(set, 8, timestamp);
+    set[20] = centis;
+    set[22] = utc_offset;
+    let (timestamp, centis, utc_offset) = system_time_to_exfat_time(fields.times.last_write_time);
+    put_u32(set, 12, timestamp);
+    set[21] = centis;
+    set[23] = utc_offset;
+    let (timestamp, _, utc_offset) = system_time_to_exfat_time(fields.times.last_access_time);
+    put_u32(set, 16, timestamp);
+    set[24] = utc_offset;
+    let stream = &mut set[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE];
+    stream[1] = EXFAT_STREAM_ALLOCATION_POSSIBLE
+        | if fields.no_fat_chain {
+            EXFAT_STREAM_NO_FAT_CHAIN
+        } else {
+            0
+        };
+    put_u64(stream, 8, fields.valid_size);
+    put_u32(stream, 20, fields.first_cluster);
+    put_u64(stream, 24, fields.size);
+    let checksum = exfat_set_checksum(set);
+    put_u16(set, 2, checksum);
+}
+
+pub(super) fn build_exfat_entry_set(
+    name: &str,
+    fields: &EntryFields,
+    upcase: &UpcaseTable,
+) -> Vec<u8> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let name_slots = units.len().div_ceil(EXFAT_NAME_CHARS);
+    let mut set = vec![0; (2 + name_slots) * DIR_ENTRY_SIZE];
+    set[0] = EXFAT_ENTRY_FILE;
+    set[1] = (1 + name_slots) as u8;
+    let stream = &mut set[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE];
+    stream[0] = EXFAT_ENTRY_STREAM;
+    stream[3] = units.len() as u8;
+    put_u16(stream, 4, upcase.name_hash(&units));
+    for (i, chunk) in units.chunks(EXFAT_NAME_CHARS).enumerate() {
+        let slot = &mut set[(2 + i) * DIR_ENTRY_SIZE..(3 + i) * DIR_ENTRY_SIZE];
+        slot[0] = EXFAT_ENTRY_NAME;
+        for (j, &c) in chunk.iter().enumerate() {
+            put_u16(slot, 2 + j * 2, c);
+        }
+    }
+    patch_exfat_entry_set(&mut set, fields);
+    set
+}
+
+/// Checks whether a slot can be reused for a new entry set.
+pub(super) fn is_free_slot(slot: &[u8], is_exfat: bool) -> bool {
+    if is_exfat {
+        slot[0] & EXFAT_ENTRY_IN_USE == 0
+    } else {
+        matches!(slot[0], FAT_ENTRY_END | FAT_ENTRY_DELETED)
+    }
+}
+
+/// Checks whether a slot marks the end of the directory.
+pub(super) fn is_end_slot(slot: &[u8]) -> bool {
+    slot[0] == 0
+}
+
+/// Marks all slots of an entry set as deleted.
+pub(super) fn delete_entry_set(set: &mut [u8], is_exfat: bool) {
+    for slot in set.chunks_exact_mut(DIR_ENTRY_SIZE) {
+        if is_exfat {
+            slot[0] &= !EXFAT_ENTRY_IN_USE;
+        } else {
+            slot[0] = FAT_ENTRY_DELETED;
+        }
+    }
+}
+
+/// Reads the location of the allocation bitmap or up-case table from a critical
+/// exFAT entry, returning (first cluster, data length).
+pub(super) fn parse_exfat_allocation(slot: &[u8]) -> (u32, u64) {
+    (le_u32(slot, 20), le_u64(slot, 24))
+}
@@ -0,0 +1,739 @@
+// Low-level access to FAT12/16/32 and exFAT volumes: boot sectors, FATs and cluster allocation
+
+use crate::fs_provider::{
+    bytes::{le_u16, le_u32, le_u64},
+    FileSystemError, FileSystemResult, OwnedFile,
+};
+
+// NOTE: FAT type is determined by cluster count alone, as per the specification
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+const ZERO_CHUNK_SIZE: usize = 64 * 1024;
+
+const FAT32_FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FAT32_FS_INFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+
+const EXFAT_VOLUME_FLAGS_OFFSET: u64 = 106;
+const EXFAT_PERCENT_IN_USE_OFFSET: u64 = 112;
+const EXFAT_VOLUME_DIRTY: u16 = 0x0002;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+}
+
+impl FatKind {
+    fn end_of_chain(&self) -> u32 {
+        match self {
+            FatKind::Fat12 => 0xFFF,
+            FatKind::Fat16 => 0xFFFF,
+            FatKind::Fat32 => 0x0FFFFFFF,
+            FatKind::ExFat => 0xFFFFFFFF,
+        }
+    }
+    // NOTE: Values above this are either bad cluster or end-of-chain markers
+    fn max_cluster_value(&self) -> u32 {
+        match self {
+            FatKind::Fat12 => 0xFF6,
+            FatKind::Fat16 => 0xFFF6,
+            FatKind::Fat32 => 0x0FFFFFF6,
+            FatKind::ExFat => 0xFFFFFFF6,
+        }
+    }
+}
+
+pub(super) struct VolumeIo {
+    image: OwnedFile<'static>,
+    offset: u64,
+    size: u64,
+}
+
+impl VolumeIo {
+    pub fn new(image: OwnedFile<'static>, offset: u64, size: u64) -> Self {
+        Self {
+            image,
+            offset,
+            size,
+        }
+    }
+    fn check_range(&self, offset: u64, len: usize) -> FileSystemResult<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(FileSystemError::FileCorruptError),
+        }
+    }
+    pub fn read_exact(&self, offset: u64, buffer: &mut [u8]) -> FileSystemResult<()> {
+        self.check_range(offset, buffer.len())?;
+        self.image.read_at_exact(self.offset + offset, buffer)
+    }
+    pub fn write_all(&self, offset: u64, buffer: &[u8]) -> FileSystemResult<()> {
+        self.check_range(offset, buffer.len())?;
+        let count = self
+            .image
+            .write_at(Some(self.offset + offset), buffer, true)?;
+        if count != buffer.len() as u64 {
+            return Err(anyhow::anyhow!("short write to volume image").into());
+        }
+        Ok(())
+    }
+    pub fn flush(&self) -> FileSystemResult<()> {
+        self.image.flush_buffers()
+    }
+    // Sets or clears the VolumeDirty flag of an exFAT volume, returning whether it was set
+    // NOTE: VolumeFlags and PercentInUse are left out of the boot checksum, so they are
+    //       updated in place. PercentInUse is not tracked, so it is marked as unknown.
+    pub fn set_exfat_dirty(&self, dirty: bool) -> FileSystemResult<bool> {
+        let mut flags = [0; 2];
+        self.read_exact(EXFAT_VOLUME_FLAGS_OFFSET, &mut flags)?;
+        let flags = u16::from_le_bytes(flags);
+        let new_flags = if dirty {
+            flags | EXFAT_VOLUME_DIRTY
+        } else {
+            flags & !EXFAT_VOLUME_DIRTY
+        };
+        // NOTE: Changes must reach the image before the volume is marked as clean
+        self.flush()?;
+        self.write_all(EXFAT_VOLUME_FLAGS_OFFSET, &new_flags.to_le_bytes())?;
+        self.write_all(EXFAT_PERCENT_IN_USE_OFFSET, &[0xFF])?;
+        self.flush()?;
+        Ok(flags & EXFAT_VOLUME_DIRTY != 0)
+    }
+}
+
+pub(super) enum RootDirLocation {
+    // FAT12 / FAT16 keep the root directory in a fixed region before the data area
+    Fixed { offset: u64, size: u64 },
+    Cluster(u32),
+}
+
+pub(super) struct FatGeometry {
+    pub kind: FatKind,
+    pub cluster_size: u64,
+    /// Byte offsets of the FAT copies that are kept up to date, the first one being
+    /// the one that is read from.
+    pub fat_offsets: Vec<u64>,
+    pub data_offset: u64,
+    pub cluster_count: u32,
+    pub root: RootDirLocation,
+    pub fs_info_offset: Option<u64>,
+}
+
+impl FatGeometry {
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size
+    }
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+}
+
+fn parse_exfat_boot_sector(sector: &[u8], volume_size: u64) -> anyhow::Result<FatGeometry> {
+    let sector_shift = sector[108] as u32;
+    let cluster_shift = sector[109] as u32;
+    if !(9..=12).contains(&sector_shift) || sector_shift + cluster_shift > 25 {
+        anyhow::bail!("invalid exFAT sector / cluster size");
+    }
+    let sector_size = 1u64 << sector_shift;
+    let fat_offset = le_u32(sector, 80) as u64 * sector_size;
+    let fat_size = le_u32(sector, 84) as u64 * sector_size;
+    let data_offset = le_u32(sector, 88) as u64 * sector_size;
+    let cluster_count = le_u32(sector, 92);
+    let root_cluster = le_u32(sector, 96);
+    let volume_flags = le_u16(sector, 106);
+    let fat_count = sector[110];
+    if cluster_count == 0 || fat_count == 0 || fat_count > 2 || fat_size < (cluster_count as u64 + 2) * 4 {
+        anyhow::bail!("invalid exFAT FAT layout");
+    }
+    if le_u64(sector, 72) * sector_size > volume_size {
+        log::warn!("exFAT volume is larger than its partition");
+    }
+    // NOTE: On TexFAT volumes only the active FAT is in use
+    let active_fat = if fat_count == 2 && volume_flags & 1 != 0 {
+        1
+    } else {
+        0
+    };
+    Ok(FatGeometry {
+        kind: FatKind::ExFat,
+        cluster_size: sector_size << cluster_shift,
+        fat_offsets: vec![fat_offset + active_fat * fat_size],
+        data_offset,
+        cluster_count,
+        root: RootDirLocation::Cluster(root_cluster),
+        fs_info_offset: None,
+    })
+}
+
+fn parse_fat_boot_sector(sector: &[u8], volume_size: u64) -> anyhow::Result<FatGeometry> {
+    if !matches!(sector[0], 0xEB | 0xE9) {
+        anyhow::bail!("missing boot sector jump instruction");
+    }
+    let sector_size = le_u16(sector, 11) as u64;
+    let sectors_per_cluster = sector[13] as u64;
+    let reserved_sectors = le_u16(sector, 14) as u64;
+    let fat_count = sector[16] as u64;
+    let root_entry_count = le_u16(sector, 17) as u64;
+    let total_sectors = match le_u16(sector, 19) {
+        0 => le_u32(sector, 32) as u64,
+        x => x as u64,
+    };
+    let fat_sectors = match le_u16(sector, 22) {
+        0 => le_u32(sector, 36) as u64,
+        x => x as u64,
+    };
+    if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sectors == 0
+        || fat_count == 0
+        || fat_sectors == 0
+    {
+        anyhow::bail!("invalid BIOS parameter block");
+    }
+    let root_dir_sectors = (root_entry_count * 32).div_ceil(sector_size);
+    let meta_sectors = reserved_sectors + fat_count * fat_sectors + root_dir_sectors;
+    if total_sectors <= meta_sectors {
+        anyhow::bail!("volume has no data area");
+    }
+    if total_sectors * sector_size > volume_size {
+        log::warn!("FAT volume is larger than its partition");
+    }
+    let cluster_count = ((total_sectors - meta_sectors) / sectors_per_cluster) as u32;
+    if cluster_count == 0 {
+        anyhow::bail!("volume has no clusters");
+    }
+    let kind = if cluster_count <= FAT12_MAX_CLUSTERS {
+        FatKind::Fat12
+    } else if cluster_count <= FAT16_MAX_CLUSTERS {
+        FatKind::Fat16
+    } else {
+        FatKind::Fat32
+    };
+    let fat_size = fat_sectors * sector_size;
+    let fat_bits = match kind {
+        FatKind::Fat12 => 12,
+        FatKind::Fat16 => 16,
+        _ => 32,
+    };
+    if fat_size * 8 < (cluster_count as u64 + 2) * fat_bits {
+        anyhow::bail!("FAT is too small for {cluster_count} clusters");
+    }
+    let fat_offset = reserved_sectors * sector_size;
+    let mut fat_offsets: Vec<_> = (0..fat_count).map(|i| fat_offset + i * fat_size).collect();
+    let (root, fs_info_offset) = if kind == FatKind::Fat32 {
+        let ext_flags = le_u16(sector, 40);
+        if ext_flags & 0x80 != 0 {
+            // FAT mirroring is disabled, only the active FAT is used
+            let active = (ext_flags & 0xF) as usize;
+            if active >= fat_offsets.len() {
+                anyhow::bail!("invalid active FAT {active}");
+            }
+            fat_offsets = vec![fat_offsets[active]];
+        }
+        let fs_info_sector = le_u16(sector, 48) as u64;
+        (
+            RootDirLocation::Cluster(le_u32(sector, 44)),
+            (fs_info_sector != 0 && fs_info_sector < reserved_sectors)
+                .then_some(fs_info_sector * sector_size),
+        )
+    } else {
+        (
+            RootDirLocation::Fixed {
+                offset: fat_offset + fat_count * fat_size,
+                size: root_entry_count * 32,
+            },
+            None,
+        )
+    };
+    Ok(FatGeometry {
+        kind,
+        cluster_size: sector_size * sectors_per_cluster,
+        fat_offsets,
+        data_offset: meta_sectors * sector_size,
+        cluster_count,
+        root,
+        fs_info_offset,
+    })
+}
+
+/// Parses a FAT12/16/32 or exFAT boot sector.
+pub(super) fn parse_boot_sector(sector: &[u8], volume_size: u64) -> anyhow::Result<FatGeometry> {
+    if sector.len() < 512 {
+        anyhow::bail!("boot sector is too small");
+    }
+    if &sector[3..11] == b"EXFAT   " {
+        parse_exfat_boot_sector(sector, volume_size)
+    } else {
+        parse_fat_boot_sector(sector, volume_size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ClusterRun {
+    pub start: u32,
+    pub count: u32,
+}
+
+fn push_cluster(runs: &mut Vec<ClusterRun>, cluster: u32) {
+    match runs.last_mut() {
+        Some(run) if run.start + run.count == cluster => run.count += 1,
+        _ => runs.push(ClusterRun {
+            start: cluster,
+            count: 1,
+        }),
+    }
+}
+
+fn cluster_iter(runs: &[ClusterRun]) -> impl Iterator<Item = u32> + '_ {
+    runs.iter().flat_map(|run| run.start..run.start + run.count)
+}
+
+pub(super) fn runs_cluster_count(runs: &[ClusterRun]) -> u32 {
+    runs.iter().map(|run| run.count).sum()
+}
+
+/// Where the data of a file or directory lives.
+pub(super) enum DataStorage {
+    Fixed { offset: u64, size: u64 },
+    Clusters(Vec<ClusterRun>),
+}
+
+impl DataStorage {
+    pub fn allocated_size(&self, geometry: &FatGeometry) -> u64 {
+        match self {
+            DataStorage::Fixed { size, .. } => *size,
+            DataStorage::Clusters(runs) => runs_cluster_count(runs) as u64 * geometry.cluster_size,
+        }
+    }
+    pub fn first_cluster(&self) -> u32 {
+        match self {
+            DataStorage::Clusters(runs) => runs.first().map(|x| x.start).unwrap_or(0),
+            DataStorage::Fixed { .. } => 0,
+        }
+    }
+}
+
+pub(super) struct FatVolume {
+    pub io: VolumeIo,
+    pub geometry: FatGeometry,
+}
+
+impl FatVolume {
+    // Maps the byte range onto the image, calling `f(disk_offset, buffer_offset, len)`
+    fn map_range(
+        &self,
+        storage: &DataStorage,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, usize, usize) -> FileSystemResult<()>,
+    ) -> FileSystemResult<()> {
+        if offset + len as u64 > storage.allocated_size(&self.geometry) {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        match storage {
+            DataStorage::Fixed {
+                offset: base_offset,
+                ..
+            } => f(base_offset + offset, 0, len),
+            DataStorage::Clusters(runs) => {
+                let cluster_size = self.geometry.cluster_size;
+                let mut run_start = 0;
+                let mut done = 0;
+                for run in runs {
+                    if done == len {
+                        break;
+                    }
+                    let run_len = run.count as u64 * cluster_size;
+                    let cur = offset + done as u64;
+                    if cur < run_start + run_len {
+                        let in_run = cur - run_start;
+                        let count = (run_len - in_run).min((len - done) as u64) as usize;
+                        f(
+                            self.geometry.cluster_offset(run.start) + in_run,
+                            done,
+                            count,
+                        )?;
+                        done += count;
+                    }
+                    run_start += run_len;
+                }
+                Ok(())
+            }
+        }
+    }
+    pub fn read_data(
+        &self,
+        storage: &DataStorage,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<()> {
+        self.map_range(storage, offset, buffer.len(), |disk, start, len| {
+            self.io.read_exact(disk, &mut buffer[start..start + len])
+        })
+    }
+    pub fn write_data(
+        &self,
+        storage: &DataStorage,
+        offset: u64,
+        buffer: &[u8],
+    ) -> FileSystemResult<()> {
+        self.map_range(storage, offset, buffer.len(), |disk, start, len| {
+            self.io.write_all(disk, &buffer[start..start + len])
+        })
+    }
+    pub fn zero_data(&self, storage: &DataStorage, offset: u64, len: u64) -> FileSystemResult<()> {
+        let zeros = vec![0; ZERO_CHUNK_SIZE.min(len as usize)];
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(ZERO_CHUNK_SIZE as u64) as usize;
+            self.write_data(storage, offset + done, &zeros[..count])?;
+            done += count as u64;
+        }
+        Ok(())
+    }
+}
+
+// exFAT allocation bitmap; FAT volumes track free clusters in the FAT itself
+struct AllocationBitmap {
+    data: Vec<u8>,
+    storage: DataStorage,
+}
+
+pub(super) struct FatTable {
+    kind: FatKind,
+    // NOTE: Raw bytes of the FAT, so that FAT12 entries can be updated in place
+    data: Vec<u8>,
+    bitmap: Option<AllocationBitmap>,
+    free_count: u32,
+    next_free: u32,
+}
+
+impl FatTable {
+    pub fn load(volume: &FatVolume) -> FileSystemResult<Self> {
+        let geometry = &volume.geometry;
+        let entry_count = geometry.cluster_count as u64 + 2;
+        let size = match geometry.kind {
+            FatKind::Fat12 => (entry_count * 3).div_ceil(2),
+            FatKind::Fat16 => entry_count * 2,
+            FatKind::Fat32 | FatKind::ExFat => entry_count * 4,
+        };
+        let mut data = vec![0; size as usize];
+        volume.io.read_exact(geometry.fat_offsets[0], &mut data)?;
+        let mut table = Self {
+            kind: geometry.kind,
+            data,
+            bitmap: None,
+            free_count: 0,
+            next_free: 2,
+        };
+        if table.kind != FatKind::ExFat {
+            table.free_count = (2..entry_count as u32)
+                .filter(|&x| table.get(x) == 0)
+                .count() as u32;
+        }
+        Ok(table)
+    }
+    /// Attaches the exFAT allocation bitmap, which is stored as a regular cluster chain.
+    pub fn load_bitmap(
+        &mut self,
+        volume: &FatVolume,
+        first_cluster: u32,
+        size: u64,
+    ) -> FileSystemResult<()> {
+        let cluster_count = volume.geometry.cluster_count;
+        if size < (cluster_count as u64).div_ceil(8) {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        let storage = DataStorage::Clusters(self.chain_runs(volume, first_cluster)?);
+        let mut data = vec![0; (cluster_count as usize).div_ceil(8)];
+        volume.read_data(&storage, 0, &mut data)?;
+        self.free_count = (0..cluster_count)
+            .filter(|&x| data[x as usize / 8] & (1 << (x % 8)) == 0)
+            .count() as u32;
+        self.bitmap = Some(AllocationBitmap { data, storage });
+        Ok(())
+    }
+    pub fn free_count(&self) -> u32 {
+        self.free_count
+    }
+    fn get(&self, cluster: u32) -> u32 {
+        let index = cluster as usize;
+        match self.kind {
+            FatKind::Fat12 => {
+                let offset = index + index / 2;
+                let value = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]);
+                if index & 1 == 0 {
+                    (value & 0xFFF) as u32
+                } else {
+                    (value >> 4) as u32
+                }
+            }
+            FatKind::Fat16 => le_u16(&self.data, index * 2) as u32,
+            FatKind::Fat32 => le_u32(&self.data, index * 4) & 0x0FFFFFFF,
+            FatKind::ExFat => le_u32(&self.data, index * 4),
+        }
+    }
+    fn set(&mut self, volume: &FatVolume, cluster: u32, value: u32) -> FileSystemResult<()> {
+        let index = cluster as usize;
+        let (offset, len) = match self.kind {
+            FatKind::Fat12 => {
+                let offset = index + index / 2;
+                let old = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]);
+                let new = if index & 1 == 0 {
+                    (old & 0xF000) | (value as u16 & 0xFFF)
+                } else {
+                    (old & 0x000F) | ((value as u16) << 4)
+                };
+                self.data[offset..offset + 2].copy_from_slice(&new.to_le_bytes());
+                (offset, 2)
+            }
+            FatKind::Fat16 => {
+                self.data[index * 2..index * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                (index * 2, 2)
+            }
+            FatKind::Fat32 => {
+                // NOTE: The upper 4 bits are reserved and must be preserved
+                let value = (le_u32(&self.data, index * 4) & 0xF0000000) | (value & 0x0FFFFFFF);
+                self.data[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+                (index * 4, 4)
+            }
+            FatKind::ExFat => {
+                self.data[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+                (index * 4, 4)
+            }
+        };
+        for fat_offset in &volume.geometry.fat_offsets {
+            volume
+                .io
+                .write_all(fat_offset + offset as u64, &self.data[offset..offset + len])?;
+        }
+        Ok(())
+    }
+    fn is_free(&self, cluster: u32) -> bool {
+        match &self.bitmap {
+            Some(bitmap) => {
+                let bit = cluster - 2;
+                bitmap.data[bit as usize / 8] & (1 << (bit % 8)) == 0
+            }
+            None => self.get(cluster) == 0,
+        }
+    }
+    fn set_bitmap(
+        &mut self,
+        volume: &FatVolume,
+        cluster: u32,
+        allocated: bool,
+    ) -> FileSystemResult<()> {
+        if let Some(bitmap) = &mut self.bitmap {
+            let bit = cluster - 2;
+            let byte = &mut bitmap.data[bit as usize / 8];
+            if allocated {
+                *byte |= 1 << (bit % 8);
+            } else {
+                *byte &= !(1 << (bit % 8));
+            }
+            volume.write_data(&bitmap.storage, bit as u64 / 8, &[*byte])?;
+        }
+        Ok(())
+    }
+    /// Follows a cluster chain from the FAT.
+    pub fn chain_runs(
+        &self,
+        volume: &FatVolume,
+        first_cluster: u32,
+    ) -> FileSystemResult<Vec<ClusterRun>> {
+        let geometry = &volume.geometry;
+        let mut runs = Vec::new();
+        if first_cluster == 0 {
+            return Ok(runs);
+        }
+        let mut cluster = first_cluster;
+        // NOTE: Guards against looping chains
+        for _ in 0..geometry.cluster_count {
+            if !geometry.is_valid_cluster(cluster) {
+                log::warn!("Invalid cluster {cluster:#x} in chain {first_cluster:#x}");
+                return Err(FileSystemError::FileCorruptError);
+            }
+            push_cluster(&mut runs, cluster);
+            let next = self.get(cluster);
+            if next > self.kind.max_cluster_value() {
+                return Ok(runs);
+            }
+            cluster = next;
+        }
+        log::warn!("Cluster chain {first_cluster:#x} loops");
+        Err(FileSystemError::FileCorruptError)
+    }
+    /// Describes a contiguous allocation which is not recorded in the FAT (exFAT only).
+    pub fn contiguous_runs(
+        &self,
+        volume: &FatVolume,
+        first_cluster: u32,
+        count: u32,
+    ) -> FileSystemResult<Vec<ClusterRun>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let geometry = &volume.geometry;
+        if !geometry.is_valid_cluster(first_cluster)
+            || !geometry.is_valid_cluster(first_cluster + (count - 1))
+        {
+            return Err(FileSystemError::FileCorruptError);
+        }
+        Ok(vec![ClusterRun {
+            start: first_cluster,
+            count,
+        }])
+    }
+    fn find_free(&mut self, volume: &FatVolume, near: Option<u32>, count: u32) -> Vec<u32> {
+        let geometry = &volume.geometry;
+        let end = geometry.cluster_count + 2;
+        let start = near
+            .filter(|&x| geometry.is_valid_cluster(x))
+            .unwrap_or(self.next_free.clamp(2, end - 1));
+        let found: Vec<_> = (start..end)
+            .chain(2..start)
+            .filter(|&x| self.is_free(x))
+            .take(count as usize)
+            .collect();
+        if let Some(last) = found.last() {
+            self.next_free = if last + 1 < end { last + 1 } else { 2 };
+        }
+        found
+    }
+    fn is_range_free(&self, volume: &FatVolume, start: u32, count: u32) -> bool {
+        volume.geometry.is_valid_cluster(start)
+            && volume.geometry.is_valid_cluster(start + (count - 1))
+            && (start..start + count).all(|x| self.is_free(x))
+    }
+    fn find_free_range(&self, volume: &FatVolume, count: u32) -> Option<u32> {
+        let end = volume.geometry.cluster_count + 2;
+        let mut start = 2;
+        let mut len = 0;
+        for cluster in 2..end {
+            if self.is_free(cluster) {
+                if len == 0 {
+                    start = cluster;
+                }
+                len += 1;
+                if len == count {
+                    return Some(start);
+                }
+            } else {
+                len = 0;
+            }
+        }
+        None
+    }
+    /// Grows or shrinks an allocation to the given number of clusters.
+    ///
+    /// On exFAT, `no_fat_chain` marks allocations that are contiguous and not recorded
+    /// in the FAT; it is cleared when the allocation can no longer be kept contiguous.
+    pub fn resize(
+        &mut self,
+        volume: &FatVolume,
+        runs: &mut Vec<ClusterRun>,
+        no_fat_chain: &mut bool,
+        cluster_count: u32,
+    ) -> FileSystemResult<()> {
+        let eoc = self.kind.end_of_chain();
+        let cur_count = runs_cluster_count(runs);
+        if cluster_count > cur_count {
+            let needed = cluster_count - cur_count;
+            if needed > self.free_count {
+                return Err(anyhow::anyhow!("not enough free space on the volume").into());
+            }
+            let last = runs.last().map(|x| x.start + x.count - 1);
+            let new_clusters: Vec<u32> = if *no_fat_chain {
+                match last {
+                    Some(last) if self.is_range_free(volume, last + 1, needed) => {
+                        (last + 1..last + 1 + needed).collect()
+                    }
+                    None => match self.find_free_range(volume, needed) {
+                        Some(start) => (start..start + needed).collect(),
+                        None => self.find_free(volume, None, needed),
+                    },
+                    Some(last) => self.find_free(volume, Some(last + 1), needed),
+                }
+            } else {
+                self.find_free(volume, last.map(|x| x + 1), needed)
+            };
+            if new_clusters.len() != needed as usize {
+                return Err(FileSystemError::FileCorruptError);
+            }
+            if *no_fat_chain {
+                let contiguous = new_clusters.windows(2).all(|x| x[1] == x[0] + 1)
+                    && last.map(|x| new_clusters[0] == x + 1).unwrap_or(true);
+                if !contiguous {
+                    // Record the existing allocation in the FAT before it is extended
+                    let clusters: Vec<_> = cluster_iter(runs).collect();
+                    for pair in clusters.windows(2) {
+                        self.set(volume, pair[0], pair[1])?;
+                    }
+                    if let Some(&last) = clusters.last() {
+                        self.set(volume, last, eoc)?;
+                    }
+                    *no_fat_chain = false;
+                }
+            }
+            for &cluster in &new_clusters {
+                self.set_bitmap(volume, cluster, true)?;
+            }
+            if !*no_fat_chain {
+                // NOTE: Link backwards, so that the chain is never left pointing at free clusters
+                let mut next = eoc;
+                for &cluster in new_clusters.iter().rev() {
+                    self.set(volume, cluster, next)?;
+                    next = cluster;
+                }
+                if let Some(last) = last {
+                    self.set(volume, last, next)?;
+                }
+            }
+            for &cluster in &new_clusters {
+                push_cluster(runs, cluster);
+            }
+            self.free_count -= needed;
+        } else if cluster_count < cur_count {
+            let clusters: Vec<_> = cluster_iter(runs).collect();
+            let (kept, freed) = clusters.split_at(cluster_count as usize);
+            if !*no_fat_chain {
+                if let Some(&last) = kept.last() {
+                    self.set(volume, last, eoc)?;
+                }
+            }
+            for &cluster in freed {
+                if !*no_fat_chain {
+                    self.set(volume, cluster, 0)?;
+                }
+                self.set_bitmap(volume, cluster, false)?;
+            }
+            runs.clear();
+            for &cluster in kept {
+                push_cluster(runs, cluster);
+            }
+            self.free_count += freed.len() as u32;
+        } else {
+            return Ok(());
+        }
+        self.update_fs_info(volume)
+    }
+    fn update_fs_info(&self, volume: &FatVolume) -> FileSystemResult<()> {
+        let Some(offset) = volume.geometry.fs_info_offset else {
+            return Ok(());
+        };
+        let mut sector = [0; 512];
+        volume.io.read_exact(offset, &mut sector)?;
+        if le_u32(&sector, 0) != FAT32_FS_INFO_LEAD_SIGNATURE
+            || le_u32(&sector, 484) != FAT32_FS_INFO_STRUCT_SIGNATURE
+        {
+            return Ok(());
+        }
+        let mut info = [0; 8];
+        info[..4].copy_from_slice(&self.free_count.to_le_bytes());
+        info[4..].copy_from_slice(&self.next_free.to_le_bytes());
+        volume.io.write_all(offset + 488, &info)
+    }
+}
@@ -1,8 +1,14 @@
 mod adbfs;
 mod archivefs;
+mod bytes;
+mod extfs;
+mod fatfs;
 pub mod local;
 pub mod memfs;
 mod overlayfs;
+mod partition;
+pub mod prefixfs;
+pub mod stdlocal;
 
 use std::{sync::Arc, time::SystemTime};
 
@@ -45,6 +51,8 @@ pub enum FileSystemError {
     EndOfFile,
     #[error("a file cannot be opened because the share access flags are incompatible")]
     SharingViolation,
+    #[error("the password is missing or incorrect")]
+    WrongPassword,
 }
 
 impl From<FileSystemError> for std::io::Error {
@@ -331,6 +339,23 @@ pub struct FileStatInfo {
     pub last_write_time: SystemTime,
 }
 
+// An entry which failed integrity verification
+#[derive(Debug)]
+pub struct IntegrityIssue {
+    // Path of the entry inside the verified container
+    pub path: String,
+    pub error: FileSystemError,
+}
+
+// A problem found while loading a container, e.g. an archive entry which had to be
+// renamed or skipped
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ContainerWarning {
+    // Path of the entry as stored in the container
+    pub path: String,
+    pub msg: String,
+}
+
 pub trait FilePattern {
     // Returns true if name matches pattern
     fn check_name(&self, name: &str) -> bool;
@@ -467,6 +492,15 @@ pub trait File: Send + Sync {
         }
         Ok(())
     }
+    // Verifies the whole container the file lives in (e.g. checksums of every entry
+    // of an archive), returning entries which failed
+    fn verify_integrity(&self) -> FileSystemResult<Vec<IntegrityIssue>> {
+        Err(FileSystemError::NotImplemented)
+    }
+    // Returns problems found while loading the container the file lives in
+    fn get_container_warnings(&self) -> FileSystemResult<Vec<ContainerWarning>> {
+        Err(FileSystemError::NotImplemented)
+    }
 }
 
 // TODO: Is this AsRef good enough?
@@ -743,8 +777,13 @@ pub fn init_fs_providers(
 ) -> anyhow::Result<()> {
     let mut reg = |p: Box<dyn FsProvider>| register_fn(p.get_id(), p);
     reg(Box::new(local::LocalFsProvider::new()))?;
+    reg(Box::new(stdlocal::StdLocalFsProvider::new()))?;
     reg(Box::new(memfs::MemFsProvider::new()))?;
     reg(Box::new(archivefs::ArchiveFsProvider::new()))?;
+    reg(Box::new(overlayfs::OverlayFsProvider::new()))?;
+    reg(Box::new(adbfs::AdbFsProvider::new()))?;
+    reg(Box::new(fatfs::FatFsProvider::new()))?;
+    reg(Box::new(extfs::ExtFsProvider::new()))?;
     Ok(())
 }
 
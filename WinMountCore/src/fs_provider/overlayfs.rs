@@ -1 +1,842 @@
-// TODO: overlayfs
+// overlayfs: Union of read-only lower filesystems below a writable upper filesystem
+// NOTE: Deletions are recorded in the upper layer as `.wh.<name>` whiteout files,
+//       while directories which hide everything below them carry a `.wh..wh..opq`
+//       marker. Only the upper layer is checked for such markers; lower layers
+//       are taken as-is.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::{uuid, Uuid};
+
+use crate::util::{CaselessStr, CaselessString};
+
+use super::{
+    FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
+    FileSystemError, FileSystemHandler, FsWithPathConfig, PathDelimiter, SegPath,
+};
+
+pub const OVERLAYFS_ID: Uuid = uuid!("3C0B9D6E-52A4-4E1F-9B3A-7D1C8E2F6A40");
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER_NAME: &str = ".wh..wh..opq";
+const COPY_UP_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Layers are indexed from top to bottom, starting with the upper layer
+const UPPER_LAYER: usize = 0;
+
+const SHARE_ALL: FileShareAccess = FileShareAccess::Read
+    .union(FileShareAccess::Write)
+    .union(FileShareAccess::Delete);
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{parent}\\{name}")
+    }
+}
+
+fn split_parent(path: &str) -> (&str, &str) {
+    path.rsplit_once('\\').unwrap_or(("", path))
+}
+
+fn whiteout_path(path: &str) -> String {
+    let (parent, name) = split_parent(path);
+    join_path(parent, &format!("{WHITEOUT_PREFIX}{name}"))
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    CaselessStr::new(name).starts_with(CaselessStr::new(WHITEOUT_PREFIX))
+}
+
+fn is_not_found(e: &FileSystemError) -> bool {
+    matches!(
+        e,
+        FileSystemError::ObjectNameNotFound
+            | FileSystemError::ObjectPathNotFound
+            | FileSystemError::NoSuchFile
+            | FileSystemError::NotADirectory
+    )
+}
+
+// NOTE: Result path will be `\`-separated, without leading delimiter
+fn normalize_path(path: SegPath) -> super::FileSystemResult<String> {
+    let mut result = String::with_capacity(path.get_path().len());
+    for segment in path.into_iter().filter(|x| !x.is_empty()) {
+        if segment == "." || segment == ".." || is_reserved_name(segment) {
+            return Err(FileSystemError::ObjectNameInvalid);
+        }
+        if !result.is_empty() {
+            result.push('\\');
+        }
+        result.push_str(segment);
+    }
+    Ok(result)
+}
+
+fn to_seg_path(path: &str) -> SegPath<'_> {
+    // SAFETY: Paths are built from validated SegPath segments
+    unsafe { SegPath::new_unchecked(path, PathDelimiter::BackSlash) }
+}
+
+struct CollectingFiller {
+    entries: Vec<(String, super::FileStatInfo)>,
+}
+impl super::FindFilesDataFiller for CollectingFiller {
+    fn fill_data(&mut self, name: &str, stat: &super::FileStatInfo) -> Result<(), ()> {
+        self.entries.push((name.to_owned(), *stat));
+        Ok(())
+    }
+}
+
+// A path as seen through the union
+struct ResolvedPath {
+    // Layers providing the object, topmost first; more than one layer
+    // is only possible for merged directories
+    layers: Vec<usize>,
+    stat: super::FileStatInfo,
+}
+
+pub struct OverlayFsHandler {
+    layers: Vec<Arc<dyn FileSystemHandler>>,
+}
+
+impl OverlayFsHandler {
+    fn open_in_layer(
+        &self,
+        layer: usize,
+        path: &str,
+        desired_access: FileDesiredAccess,
+        file_attributes: FileAttributes,
+        share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<super::CreateFileInfo<'_>> {
+        self.layers[layer].create_file(
+            to_seg_path(path),
+            desired_access,
+            file_attributes,
+            share_access,
+            create_disposition,
+            create_options,
+        )
+    }
+    fn stat_in_layer(
+        &self,
+        layer: usize,
+        path: &str,
+    ) -> super::FileSystemResult<Option<super::FileStatInfo>> {
+        let create_info = match self.open_in_layer(
+            layer,
+            path,
+            FileDesiredAccess::empty(),
+            FileAttributes::empty(),
+            SHARE_ALL,
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::empty(),
+        ) {
+            Ok(x) => x,
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        create_info.context.get_stat().map(Some)
+    }
+    fn is_opaque_in_upper(&self, path: &str) -> super::FileSystemResult<bool> {
+        let marker_path = join_path(path, OPAQUE_MARKER_NAME);
+        Ok(self.stat_in_layer(UPPER_LAYER, &marker_path)?.is_some())
+    }
+    // Returns `None` if only the last path segment does not exist
+    fn resolve(&self, path: &str) -> super::FileSystemResult<Option<ResolvedPath>> {
+        let mut stat = self
+            .stat_in_layer(UPPER_LAYER, "")?
+            .ok_or(FileSystemError::ObjectPathNotFound)?;
+        let mut layers = if self.is_opaque_in_upper("")? {
+            vec![UPPER_LAYER]
+        } else {
+            (0..self.layers.len()).collect()
+        };
+        let mut cur_path = String::new();
+        let mut it = path.split('\\').filter(|x| !x.is_empty()).peekable();
+        while let Some(name) = it.next() {
+            let child_path = join_path(&cur_path, name);
+            let mut next_layers = Vec::new();
+            let mut next_stat = None;
+            for &layer in &layers {
+                let Some(child_stat) = self.stat_in_layer(layer, &child_path)? else {
+                    if layer == UPPER_LAYER
+                        && self
+                            .stat_in_layer(UPPER_LAYER, &whiteout_path(&child_path))?
+                            .is_some()
+                    {
+                        // Whited out, lower layers are hidden
+                        break;
+                    }
+                    continue;
+                };
+                if next_stat.is_none() {
+                    next_stat = Some(child_stat);
+                } else if !child_stat.is_dir {
+                    // Files never merge with directories above them
+                    break;
+                }
+                next_layers.push(layer);
+                if !child_stat.is_dir
+                    || (layer == UPPER_LAYER && self.is_opaque_in_upper(&child_path)?)
+                {
+                    break;
+                }
+            }
+            let is_last = it.peek().is_none();
+            let Some(next_stat) = next_stat else {
+                return if is_last {
+                    Ok(None)
+                } else {
+                    Err(FileSystemError::ObjectPathNotFound)
+                };
+            };
+            if !is_last && !next_stat.is_dir {
+                return Err(FileSystemError::ObjectPathNotFound);
+            }
+            layers = next_layers;
+            stat = next_stat;
+            cur_path = child_path;
+        }
+        Ok(Some(ResolvedPath { layers, stat }))
+    }
+    fn list_layer(
+        &self,
+        layer: usize,
+        path: &str,
+    ) -> super::FileSystemResult<Vec<(String, super::FileStatInfo)>> {
+        let create_info = self.open_in_layer(
+            layer,
+            path,
+            FileDesiredAccess::Read,
+            FileAttributes::empty(),
+            SHARE_ALL,
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::DirectoryFile,
+        )?;
+        let mut filler = CollectingFiller {
+            entries: Vec::new(),
+        };
+        create_info
+            .context
+            .find_files_with_pattern(&super::AcceptAllFilePattern::new(), &mut filler)?;
+        Ok(filler.entries)
+    }
+    fn list_merged(
+        &self,
+        path: &str,
+        layers: &[usize],
+    ) -> super::FileSystemResult<BTreeMap<CaselessString, (String, super::FileStatInfo)>> {
+        let mut result = BTreeMap::new();
+        let mut hidden = BTreeSet::new();
+        for &layer in layers {
+            for (name, stat) in self.list_layer(layer, path)? {
+                if layer == UPPER_LAYER && is_reserved_name(&name) {
+                    if CaselessStr::new(&name) != CaselessStr::new(OPAQUE_MARKER_NAME) {
+                        hidden.insert(CaselessString::from(&name[WHITEOUT_PREFIX.len()..]));
+                    }
+                    continue;
+                }
+                let key = CaselessString::from(name.as_str());
+                if hidden.contains(&key) {
+                    continue;
+                }
+                result.entry(key).or_insert((name, stat));
+            }
+        }
+        Ok(result)
+    }
+    fn is_dir_empty(&self, path: &str) -> super::FileSystemResult<bool> {
+        let resolved = self
+            .resolve(path)?
+            .ok_or(FileSystemError::ObjectNameNotFound)?;
+        let entries = self.list_merged(path, &resolved.layers)?;
+        Ok(entries
+            .values()
+            .all(|(name, _)| name == "." || name == ".."))
+    }
+    // Makes sure every directory along the path exists in the upper layer
+    fn copy_up_dirs(&self, path: &str) -> super::FileSystemResult<()> {
+        let mut cur_path = String::new();
+        for name in path.split('\\').filter(|x| !x.is_empty()) {
+            cur_path = join_path(&cur_path, name);
+            if let Some(stat) = self.stat_in_layer(UPPER_LAYER, &cur_path)? {
+                if !stat.is_dir {
+                    return Err(FileSystemError::NotADirectory);
+                }
+                continue;
+            }
+            let src = self
+                .resolve(&cur_path)?
+                .ok_or(FileSystemError::ObjectPathNotFound)?;
+            if !src.stat.is_dir {
+                return Err(FileSystemError::NotADirectory);
+            }
+            let create_info = self.open_in_layer(
+                UPPER_LAYER,
+                &cur_path,
+                FileDesiredAccess::ReadWrite,
+                FileAttributes::empty(),
+                SHARE_ALL,
+                FileCreateDisposition::OpenAlways,
+                FileCreateOptions::DirectoryFile,
+            )?;
+            create_info.context.set_file_times(
+                src.stat.creation_time,
+                src.stat.last_access_time,
+                src.stat.last_write_time,
+            )?;
+        }
+        Ok(())
+    }
+    // Creates a file in the upper layer, optionally filling it with contents of `src`
+    fn copy_up_file(
+        &self,
+        path: &str,
+        src: &dyn super::File,
+        copy_data: bool,
+    ) -> super::FileSystemResult<super::OwnedFile<'_>> {
+        self.copy_up_dirs(split_parent(path).0)?;
+        let create_info = self.open_in_layer(
+            UPPER_LAYER,
+            path,
+            FileDesiredAccess::ReadWrite,
+            FileAttributes::empty(),
+            SHARE_ALL,
+            FileCreateDisposition::CreateAlways,
+            FileCreateOptions::NonDirectoryFile,
+        )?;
+        let dst = create_info.context;
+        if copy_data {
+            let mut buf = vec![0; COPY_UP_CHUNK_SIZE];
+            let mut offset = 0;
+            loop {
+                let count = src.read_at(offset, &mut buf)?;
+                if count == 0 {
+                    break;
+                }
+                dst.write_at(Some(offset), &buf[..count as usize], false)?;
+                offset += count;
+            }
+        }
+        let stat = src.get_stat()?;
+        dst.set_file_times(
+            stat.creation_time,
+            stat.last_access_time,
+            stat.last_write_time,
+        )?;
+        Ok(dst)
+    }
+    // Materializes the whole subtree in the upper layer
+    fn copy_up_tree(&self, path: &str) -> super::FileSystemResult<()> {
+        self.copy_up_dirs(path)?;
+        let resolved = self
+            .resolve(path)?
+            .ok_or(FileSystemError::ObjectNameNotFound)?;
+        if resolved.layers == [UPPER_LAYER] {
+            return Ok(());
+        }
+        for (name, stat) in self.list_merged(path, &resolved.layers)?.into_values() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child_path = join_path(path, &name);
+            if stat.is_dir {
+                self.copy_up_tree(&child_path)?;
+                continue;
+            }
+            let Some(child) = self.resolve(&child_path)? else {
+                continue;
+            };
+            if child.layers[0] == UPPER_LAYER {
+                continue;
+            }
+            let create_info = self.open_in_layer(
+                child.layers[0],
+                &child_path,
+                FileDesiredAccess::Read,
+                FileAttributes::empty(),
+                SHARE_ALL,
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::NonDirectoryFile,
+            )?;
+            self.copy_up_file(&child_path, create_info.context.as_ref(), true)?;
+        }
+        Ok(())
+    }
+    fn create_upper_marker(&self, path: &str) -> super::FileSystemResult<()> {
+        self.copy_up_dirs(split_parent(path).0)?;
+        self.open_in_layer(
+            UPPER_LAYER,
+            path,
+            FileDesiredAccess::Write,
+            FileAttributes::Hidden,
+            SHARE_ALL,
+            FileCreateDisposition::CreateAlways,
+            FileCreateOptions::NonDirectoryFile,
+        )?;
+        Ok(())
+    }
+    // Returns whether the marker existed
+    fn remove_upper_marker(&self, path: &str) -> super::FileSystemResult<bool> {
+        match self.open_in_layer(
+            UPPER_LAYER,
+            path,
+            FileDesiredAccess::Delete,
+            FileAttributes::empty(),
+            SHARE_ALL,
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::NonDirectoryFile | FileCreateOptions::DeleteOnClose,
+        ) {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    fn clear_upper_markers(&self, path: &str) -> super::FileSystemResult<()> {
+        for (name, _) in self.list_layer(UPPER_LAYER, path)? {
+            if is_reserved_name(&name) {
+                self.remove_upper_marker(&join_path(path, &name))?;
+            }
+        }
+        Ok(())
+    }
+    // Whites out the path if lower layers still provide it
+    fn hide_lower(&self, path: &str) -> super::FileSystemResult<()> {
+        if self.resolve(path)?.is_some() {
+            self.create_upper_marker(&whiteout_path(path))?;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystemHandler for OverlayFsHandler {
+    fn create_file(
+        &self,
+        filename: SegPath,
+        desired_access: FileDesiredAccess,
+        file_attributes: FileAttributes,
+        share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<super::CreateFileInfo<'_>> {
+        use FileCreateDisposition::*;
+
+        let expects_dir = create_options.contains(FileCreateOptions::DirectoryFile);
+        let expects_nondir = create_options.contains(FileCreateOptions::NonDirectoryFile);
+        let delete_on_close = create_options.contains(FileCreateOptions::DeleteOnClose);
+        // NOTE: Deletion is carried out by ourselves instead of the layers
+        let inner_create_options = create_options.difference(FileCreateOptions::DeleteOnClose);
+
+        let path = normalize_path(filename)?;
+        if path.is_empty() && delete_on_close {
+            return Err(FileSystemError::AccessDenied);
+        }
+
+        let (layer, create_info) = if let Some(resolved) = self.resolve(&path)? {
+            let is_dir = resolved.stat.is_dir;
+            if expects_dir && !is_dir {
+                return Err(FileSystemError::NotADirectory);
+            }
+            if expects_nondir && is_dir {
+                return Err(FileSystemError::FileIsADirectory);
+            }
+            if delete_on_close && is_dir && !self.is_dir_empty(&path)? {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+            let top_layer = resolved.layers[0];
+            match create_disposition {
+                CreateNew => return Err(FileSystemError::ObjectNameCollision),
+                CreateAlways | TruncateExisting if !is_dir && top_layer != UPPER_LAYER => {
+                    // Truncation of a lower file is a copy-up without data
+                    let src = self.open_in_layer(
+                        top_layer,
+                        &path,
+                        FileDesiredAccess::Read,
+                        FileAttributes::empty(),
+                        SHARE_ALL,
+                        OpenExisting,
+                        inner_create_options,
+                    )?;
+                    let file = self.copy_up_file(&path, src.context.as_ref(), false)?;
+                    (
+                        UPPER_LAYER,
+                        super::CreateFileInfo {
+                            context: file,
+                            is_dir: false,
+                            new_file_created: false,
+                        },
+                    )
+                }
+                _ if top_layer == UPPER_LAYER => (
+                    UPPER_LAYER,
+                    self.open_in_layer(
+                        UPPER_LAYER,
+                        &path,
+                        desired_access,
+                        file_attributes,
+                        share_access,
+                        create_disposition,
+                        inner_create_options,
+                    )?,
+                ),
+                _ => (
+                    top_layer,
+                    self.open_in_layer(
+                        top_layer,
+                        &path,
+                        FileDesiredAccess::Read,
+                        FileAttributes::empty(),
+                        share_access,
+                        OpenExisting,
+                        inner_create_options,
+                    )?,
+                ),
+            }
+        } else {
+            match create_disposition {
+                CreateAlways | CreateNew | OpenAlways => {}
+                OpenExisting | TruncateExisting => return Err(FileSystemError::ObjectNameNotFound),
+            }
+            self.copy_up_dirs(split_parent(&path).0)?;
+            let was_whited_out = self.remove_upper_marker(&whiteout_path(&path))?;
+            let create_info = self.open_in_layer(
+                UPPER_LAYER,
+                &path,
+                desired_access,
+                file_attributes,
+                share_access,
+                create_disposition,
+                inner_create_options,
+            )?;
+            if create_info.is_dir && was_whited_out {
+                // Keep contents of the deleted lower directory hidden
+                self.create_upper_marker(&join_path(&path, OPAQUE_MARKER_NAME))?;
+            }
+            (UPPER_LAYER, create_info)
+        };
+
+        Ok(super::CreateFileInfo {
+            context: Box::new(OverlayFsFile {
+                fs_handler: self,
+                state: RwLock::new(OverlayFsFileState {
+                    path,
+                    layer,
+                    file: Some(create_info.context),
+                }),
+                is_dir: create_info.is_dir,
+                can_write: desired_access
+                    .intersects(FileDesiredAccess::Write | FileDesiredAccess::Full),
+                delete_on_close: AtomicBool::new(delete_on_close),
+            }),
+            is_dir: create_info.is_dir,
+            new_file_created: create_info.new_file_created,
+        })
+    }
+    fn get_fs_free_space(&self) -> super::FileSystemResult<super::FileSystemSpaceInfo> {
+        self.layers[UPPER_LAYER].get_fs_free_space()
+    }
+    fn get_fs_characteristics(&self) -> super::FileSystemResult<super::FileSystemCharacteristics> {
+        self.layers[UPPER_LAYER].get_fs_characteristics()
+    }
+}
+
+struct OverlayFsFileState<'h> {
+    // NOTE: `\`-separated, without leading delimiter
+    path: String,
+    layer: usize,
+    // NOTE: Only taken away while dropping
+    file: Option<super::OwnedFile<'h>>,
+}
+
+impl<'h> OverlayFsFileState<'h> {
+    fn file(&self) -> &(dyn super::File + 'h) {
+        self.file.as_deref().unwrap()
+    }
+}
+
+// NOTE: Copy-up only affects the current handle; other handles opened
+//       before the copy-up still refer to the lower file
+struct OverlayFsFile<'h> {
+    fs_handler: &'h OverlayFsHandler,
+    state: RwLock<OverlayFsFileState<'h>>,
+    is_dir: bool,
+    can_write: bool,
+    delete_on_close: AtomicBool,
+}
+
+impl<'h> OverlayFsFile<'h> {
+    fn copy_up(&self, state: &mut OverlayFsFileState<'h>) -> super::FileSystemResult<()> {
+        let fs = self.fs_handler;
+        let file = if self.is_dir {
+            fs.copy_up_dirs(&state.path)?;
+            fs.open_in_layer(
+                UPPER_LAYER,
+                &state.path,
+                FileDesiredAccess::ReadWrite,
+                FileAttributes::empty(),
+                SHARE_ALL,
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::DirectoryFile,
+            )?
+            .context
+        } else {
+            fs.copy_up_file(&state.path, state.file(), true)?
+        };
+        state.file = Some(file);
+        state.layer = UPPER_LAYER;
+        Ok(())
+    }
+    fn with_upper<R>(
+        &self,
+        op: impl FnOnce(&dyn super::File) -> super::FileSystemResult<R>,
+    ) -> super::FileSystemResult<R> {
+        {
+            let state = self.state.read().unwrap();
+            if state.layer == UPPER_LAYER {
+                return op(state.file());
+            }
+        }
+        let mut state = self.state.write().unwrap();
+        if state.layer != UPPER_LAYER {
+            self.copy_up(&mut state)?;
+        }
+        op(state.file())
+    }
+    fn delete_now(&self, state: &mut OverlayFsFileState<'h>) -> super::FileSystemResult<()> {
+        let fs = self.fs_handler;
+        let file = state.file.take().unwrap();
+        if state.layer == UPPER_LAYER {
+            if self.is_dir {
+                fs.clear_upper_markers(&state.path)?;
+            }
+            file.set_delete(true)?;
+        }
+        drop(file);
+        fs.hide_lower(&state.path)
+    }
+}
+
+impl super::File for OverlayFsFile<'_> {
+    fn get_path(&self) -> Option<String> {
+        Some(format!("\\{}", self.state.read().unwrap().path))
+    }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> super::FileSystemResult<u64> {
+        self.state.read().unwrap().file().read_at(offset, buffer)
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> super::FileSystemResult<u64> {
+        if self.is_dir {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        if !self.can_write {
+            return Err(FileSystemError::AccessDenied);
+        }
+        self.with_upper(|file| file.write_at(offset, buffer, constrain_size))
+    }
+    fn flush_buffers(&self) -> super::FileSystemResult<()> {
+        let state = self.state.read().unwrap();
+        if state.layer != UPPER_LAYER {
+            return Ok(());
+        }
+        state.file().flush_buffers()
+    }
+    fn get_stat(&self) -> super::FileSystemResult<super::FileStatInfo> {
+        self.state.read().unwrap().file().get_stat()
+    }
+    fn set_end_of_file(&self, offset: u64) -> super::FileSystemResult<()> {
+        if self.is_dir {
+            return Err(FileSystemError::FileIsADirectory);
+        }
+        if !self.can_write {
+            return Err(FileSystemError::AccessDenied);
+        }
+        self.with_upper(|file| file.set_end_of_file(offset))
+    }
+    fn set_file_times(
+        &self,
+        creation_time: SystemTime,
+        last_access_time: SystemTime,
+        last_write_time: SystemTime,
+    ) -> super::FileSystemResult<()> {
+        self.with_upper(|file| {
+            file.set_file_times(creation_time, last_access_time, last_write_time)
+        })
+    }
+    fn set_delete(&self, delete_on_close: bool) -> super::FileSystemResult<()> {
+        if delete_on_close {
+            let state = self.state.read().unwrap();
+            if state.path.is_empty() {
+                return Err(FileSystemError::AccessDenied);
+            }
+            if self.is_dir && !self.fs_handler.is_dir_empty(&state.path)? {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+        }
+        self.delete_on_close
+            .store(delete_on_close, Ordering::Release);
+        Ok(())
+    }
+    fn move_to(&self, new_path: SegPath, replace_if_exists: bool) -> super::FileSystemResult<()> {
+        let fs = self.fs_handler;
+        let new_path = normalize_path(new_path)?;
+        let mut state = self.state.write().unwrap();
+        if state.path.is_empty() || new_path.is_empty() {
+            return Err(FileSystemError::AccessDenied);
+        }
+        let is_same_path = CaselessStr::new(&state.path) == CaselessStr::new(&new_path);
+        let (new_parent, _) = split_parent(&new_path);
+        match fs.resolve(new_parent)? {
+            Some(resolved) if resolved.stat.is_dir => {}
+            _ => return Err(FileSystemError::ObjectPathNotFound),
+        }
+        if !is_same_path {
+            if let Some(dest) = fs.resolve(&new_path)? {
+                if !replace_if_exists {
+                    return Err(FileSystemError::ObjectNameCollision);
+                }
+                if dest.stat.is_dir {
+                    return Err(FileSystemError::AccessDenied);
+                }
+            }
+        }
+
+        // Materialize the source in the upper layer, then move it there
+        if self.is_dir {
+            fs.copy_up_tree(&state.path)?;
+        }
+        if state.layer != UPPER_LAYER {
+            self.copy_up(&mut state)?;
+        }
+        fs.copy_up_dirs(new_parent)?;
+        fs.remove_upper_marker(&whiteout_path(&new_path))?;
+        state
+            .file()
+            .move_to(to_seg_path(&new_path), replace_if_exists)?;
+        let old_path = std::mem::replace(&mut state.path, new_path);
+
+        if self.is_dir {
+            // The moved directory is complete by itself
+            fs.create_upper_marker(&join_path(&state.path, OPAQUE_MARKER_NAME))?;
+        }
+        if !is_same_path {
+            fs.hide_lower(&old_path)?;
+        }
+        Ok(())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn super::FilePattern,
+        filler: &mut dyn super::FindFilesDataFiller,
+    ) -> super::FileSystemResult<()> {
+        if !self.is_dir {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let fs = self.fs_handler;
+        let state = self.state.read().unwrap();
+        let resolved = fs
+            .resolve(&state.path)?
+            .ok_or(FileSystemError::ObjectNameNotFound)?;
+        let entries = fs.list_merged(&state.path, &resolved.layers)?;
+        for (name, stat) in entries
+            .values()
+            .filter(|(name, _)| pattern.check_name(name))
+        {
+            if filler.fill_data(name, stat).is_err() {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OverlayFsFile<'_> {
+    fn drop(&mut self) {
+        if !self.delete_on_close.load(Ordering::Acquire) {
+            return;
+        }
+        let mut state = std::mem::replace(
+            self.state.get_mut().unwrap(),
+            OverlayFsFileState {
+                path: String::new(),
+                layer: UPPER_LAYER,
+                file: None,
+            },
+        );
+        if let Err(e) = self.delete_now(&mut state) {
+            log::warn!("Failed to delete `{}`: {e}", state.path);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OverlayFsConfig {
+    /// The writable layer receiving all modifications.
+    upper_layer: FsWithPathConfig,
+    /// Read-only layers, ordered from top to bottom.
+    lower_layers: Vec<FsWithPathConfig>,
+}
+
+pub struct OverlayFsProvider {}
+impl super::FsProvider for OverlayFsProvider {
+    fn get_id(&self) -> Uuid {
+        OVERLAYFS_ID
+    }
+    fn get_name(&self) -> &'static str {
+        "OverlayFS"
+    }
+    fn get_version(&self) -> (u32, u32, u32) {
+        (0, 1, 0)
+    }
+    fn construct(
+        &self,
+        config: serde_json::Value,
+        ctx: &mut dyn super::FileSystemCreationContext,
+    ) -> Result<Arc<dyn FileSystemHandler>, super::FileSystemCreationError> {
+        let config: OverlayFsConfig = serde_json::from_value(config)
+            .map_err(|e| super::FileSystemCreationError::Other(e.into()))?;
+        let upper = ctx.get_or_run_fs(&config.upper_layer.id, &config.upper_layer.path)?;
+        if upper
+            .get_fs_characteristics()
+            .map_err(|e| super::FileSystemCreationError::Other(e.into()))?
+            .contains(super::FileSystemCharacteristics::ReadOnly)
+        {
+            return Err(super::FileSystemCreationError::InvalidConfig(
+                "the upper layer must be writable".to_owned(),
+            ));
+        }
+        let mut layers = vec![upper];
+        for lower in &config.lower_layers {
+            layers.push(ctx.get_or_run_fs(&lower.id, &lower.path)?);
+        }
+        Ok(Arc::new(OverlayFsHandler { layers }))
+    }
+    fn get_template_config(&self) -> serde_json::Value {
+        serde_json::to_value(OverlayFsConfig {
+            upper_layer: Default::default(),
+            lower_layers: vec![Default::default()],
+        })
+        .unwrap()
+    }
+}
+
+impl OverlayFsProvider {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
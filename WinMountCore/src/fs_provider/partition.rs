@@ -0,0 +1,260 @@
+// Partition table parsing (MBR with extended partitions, and GPT) for disk image providers
+// TODO: Verify GPT header / entry array CRCs and fall back to the backup GPT
+
+use uuid::Uuid;
+
+use super::{
+    bytes::{le_u32, le_u64},
+    File, FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess,
+    FileShareAccess, FileSystemHandler, OwnedFile, PathDelimiter, SegPath,
+};
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+// NOTE: MBR offsets are always expressed in 512-byte sectors
+const MBR_SECTOR_SIZE: u64 = 512;
+// NOTE: Guards against looping extended boot record chains
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const MAX_GPT_ENTRIES: u32 = 1024;
+// NOTE: Entries are 128 bytes in practice, larger ones only add reserved space
+const MAX_GPT_ENTRY_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum PartitionType {
+    Mbr(u8),
+    Gpt(Uuid),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PartitionEntry {
+    /// Offset of the partition in bytes.
+    pub offset: u64,
+    /// Size of the partition in bytes.
+    pub size: u64,
+    pub kind: PartitionType,
+}
+
+// Opens the image file of a disk image provider, detached from the lifetime of `image_fs`
+// SAFETY: The file borrows from `image_fs`, so callers must keep the filesystem alive
+//         until the file is dropped
+pub(super) unsafe fn open_image(
+    image_fs: &dyn FileSystemHandler,
+    image_path: &str,
+    writable: bool,
+) -> anyhow::Result<OwnedFile<'static>> {
+    let super::CreateFileInfo { context, .. } = image_fs.create_file(
+        SegPath::new(image_path, PathDelimiter::BackSlash),
+        if writable {
+            FileDesiredAccess::ReadWrite
+        } else {
+            FileDesiredAccess::Read
+        },
+        FileAttributes::empty(),
+        FileShareAccess::Read,
+        FileCreateDisposition::OpenExisting,
+        FileCreateOptions::NonDirectoryFile,
+    )?;
+    let image: OwnedFile<'static> = std::mem::transmute(context);
+    Ok(image)
+}
+
+fn read_sector(file: &dyn File, offset: u64, size: u64) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; size as usize];
+    file.read_at_exact(offset, &mut buf)?;
+    Ok(buf)
+}
+
+/// Checks whether the sector carries the 0x55AA boot signature.
+/// Note that this holds for both MBRs and most volume boot sectors.
+pub(super) fn has_boot_signature(sector: &[u8]) -> bool {
+    sector.len() >= 512 && sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] == [0x55, 0xAA]
+}
+
+fn parse_mbr_entries(sector: &[u8]) -> impl Iterator<Item = (u8, u64, u64)> + '_ {
+    (0..4).map(move |i| {
+        let entry = &sector[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        (entry[4], le_u32(entry, 8) as u64, le_u32(entry, 12) as u64)
+    })
+}
+
+fn read_gpt(file: &dyn File) -> anyhow::Result<Option<Vec<PartitionEntry>>> {
+    let disk_size = file.get_stat()?.size;
+    for sector_size in GPT_SECTOR_SIZES {
+        let header = match read_sector(file, sector_size, sector_size) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if &header[..8] != GPT_SIGNATURE {
+            continue;
+        }
+        let entries_lba = le_u64(&header, 72);
+        let entry_count = le_u32(&header, 80);
+        let entry_size = le_u32(&header, 84) as u64;
+        if entry_count > MAX_GPT_ENTRIES || !(128..=MAX_GPT_ENTRY_SIZE).contains(&entry_size) {
+            anyhow::bail!("invalid GPT entry array ({entry_count} x {entry_size})");
+        }
+        let entries_offset = entries_lba
+            .checked_mul(sector_size)
+            .ok_or_else(|| anyhow::anyhow!("invalid GPT entry array LBA {entries_lba}"))?;
+        let entries_size = (entry_count as u64)
+            .checked_mul(entry_size)
+            .ok_or_else(|| anyhow::anyhow!("invalid GPT entry array"))?;
+        let entries = read_sector(file, entries_offset, entries_size)?;
+        let partitions = entries
+            .chunks_exact(entry_size as usize)
+            .filter_map(|entry| {
+                let type_guid = Uuid::from_bytes_le(entry[..16].try_into().unwrap());
+                let first_lba = le_u64(entry, 32);
+                let last_lba = le_u64(entry, 40);
+                if type_guid.is_nil() || last_lba < first_lba {
+                    return None;
+                }
+                let offset = first_lba.checked_mul(sector_size);
+                let size = (last_lba - first_lba)
+                    .checked_add(1)
+                    .and_then(|x| x.checked_mul(sector_size));
+                match (offset, size) {
+                    (Some(offset), Some(size))
+                        if offset.checked_add(size).is_some_and(|x| x <= disk_size) =>
+                    {
+                        Some(PartitionEntry {
+                            offset,
+                            size,
+                            kind: PartitionType::Gpt(type_guid),
+                        })
+                    }
+                    _ => {
+                        log::warn!("Skipping GPT partition at LBA {first_lba} past the disk end");
+                        None
+                    }
+                }
+            })
+            .collect();
+        return Ok(Some(partitions));
+    }
+    Ok(None)
+}
+
+fn read_logical_partitions(
+    file: &dyn File,
+    extended_start: u64,
+    partitions: &mut Vec<PartitionEntry>,
+) -> anyhow::Result<()> {
+    let mut ebr_start = extended_start;
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        let sector = read_sector(file, ebr_start * MBR_SECTOR_SIZE, MBR_SECTOR_SIZE)?;
+        if !has_boot_signature(&sector) {
+            anyhow::bail!("invalid extended boot record at sector {ebr_start}");
+        }
+        let mut entries = parse_mbr_entries(&sector);
+        let (kind, start, count) = entries.next().unwrap();
+        if kind != MBR_TYPE_EMPTY && count != 0 {
+            partitions.push(PartitionEntry {
+                offset: (ebr_start + start) * MBR_SECTOR_SIZE,
+                size: count * MBR_SECTOR_SIZE,
+                kind: PartitionType::Mbr(kind),
+            });
+        }
+        // NOTE: Links to the next EBR are relative to the extended partition itself
+        match entries.next().unwrap() {
+            (kind, start, _) if MBR_TYPES_EXTENDED.contains(&kind) && start != 0 => {
+                ebr_start = extended_start + start;
+            }
+            _ => return Ok(()),
+        }
+    }
+    log::warn!("Too many logical partitions, ignoring the rest");
+    Ok(())
+}
+
+/// Reads the partition table of a disk image. Returns `None` if the image
+/// has no recognizable partition table.
+///
+/// Partitions are listed in table order, with MBR logical partitions
+/// following the primary ones. Empty slots are omitted.
+pub(super) fn read_partitions(file: &dyn File) -> anyhow::Result<Option<Vec<PartitionEntry>>> {
+    let sector = read_sector(file, 0, MBR_SECTOR_SIZE)?;
+    if !has_boot_signature(&sector) {
+        return Ok(None);
+    }
+    let entries: Vec<_> = parse_mbr_entries(&sector).collect();
+    if entries.iter().any(|x| x.0 == MBR_TYPE_GPT_PROTECTIVE) {
+        if let Some(partitions) = read_gpt(file)? {
+            return Ok(Some(partitions));
+        }
+        log::warn!("Protective MBR found without a valid GPT header");
+    }
+    // NOTE: Boot indicators must be either 0x00 or 0x80, which rules out most
+    //       volume boot sectors being mistaken for an MBR
+    if (0..4).any(|i| sector[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE] & 0x7F != 0) {
+        return Ok(None);
+    }
+    let mut partitions = Vec::new();
+    let mut extended_start = None;
+    for (kind, start, count) in entries {
+        if kind == MBR_TYPE_EMPTY || count == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&kind) {
+            extended_start.get_or_insert(start);
+            continue;
+        }
+        partitions.push(PartitionEntry {
+            offset: start * MBR_SECTOR_SIZE,
+            size: count * MBR_SECTOR_SIZE,
+            kind: PartitionType::Mbr(kind),
+        });
+    }
+    if let Some(extended_start) = extended_start {
+        if let Err(e) = read_logical_partitions(file, extended_start, &mut partitions) {
+            log::warn!("Failed to read logical partitions: {e}");
+        }
+    }
+    if partitions.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(partitions))
+}
+
+/// Picks the volume to use from a disk image, returning its offset and size.
+///
+/// Without an explicit partition index, the image itself is preferred if it holds a
+/// volume, falling back to the first partition `is_volume` accepts.
+pub(super) fn select_volume(
+    image: &dyn File,
+    image_size: u64,
+    partition: Option<usize>,
+    is_volume: impl Fn(u64, u64) -> bool,
+) -> anyhow::Result<(u64, u64)> {
+    let partitions = match partition {
+        None if is_volume(0, image_size) => return Ok((0, image_size)),
+        _ => read_partitions(image)?.unwrap_or_default(),
+    };
+    let entry = match partition {
+        Some(index) => partitions
+            .get(index)
+            .ok_or_else(|| anyhow::anyhow!("partition {index} does not exist"))?,
+        None => partitions
+            .iter()
+            .find(|x| is_volume(x.offset, x.size))
+            .ok_or_else(|| anyhow::anyhow!("no supported volume found in the image"))?,
+    };
+    log::debug!(
+        "Using partition {:?} at {:#x} ({} bytes)",
+        entry.kind,
+        entry.offset,
+        entry.size
+    );
+    if entry.offset >= image_size {
+        anyhow::bail!("partition lies beyond the end of the image");
+    }
+    Ok((entry.offset, entry.size.min(image_size - entry.offset)))
+}
@@ -0,0 +1,176 @@
+// prefixfs: Re-roots an existing filesystem under a prefix path (chroot-style)
+// NOTE: Instances are created by FileSystemCreationContext::get_or_run_fs
+//       instead of being exposed as a standalone provider
+
+use std::sync::{Arc, RwLock};
+
+use super::{
+    FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
+    FileSystemCreationError, FileSystemError, OwnedSegPath,
+};
+
+fn is_relative_component(segment: &str) -> bool {
+    segment == "." || segment == ".."
+}
+
+/// Normalizes a prefix path into its `\`-separated form without leading or
+/// trailing delimiters, rejecting relative components.
+pub fn normalize_prefix_path(path: &str) -> Result<String, FileSystemCreationError> {
+    let mut result = String::with_capacity(path.len());
+    for segment in path.split(['/', '\\']).filter(|x| !x.is_empty()) {
+        if is_relative_component(segment) {
+            return Err(FileSystemCreationError::InvalidConfig(format!(
+                "prefix path `{path}` must not contain relative components"
+            )));
+        }
+        if segment.contains('\0') {
+            return Err(FileSystemCreationError::InvalidConfig(format!(
+                "prefix path `{path}` must not contain nul bytes"
+            )));
+        }
+        if !result.is_empty() {
+            result.push('\\');
+        }
+        result.push_str(segment);
+    }
+    Ok(result)
+}
+
+// NOTE: Both slash kinds are checked, as paths may be made uniform later on
+fn check_no_escape(path: super::SegPath) -> super::FileSystemResult<()> {
+    if path
+        .get_path()
+        .split(['/', '\\'])
+        .any(is_relative_component)
+    {
+        return Err(FileSystemError::ObjectNameInvalid);
+    }
+    Ok(())
+}
+
+pub struct PrefixFsHandler {
+    handler: Arc<dyn super::FileSystemHandler>,
+    prefix: String,
+}
+
+impl PrefixFsHandler {
+    /// Creates a wrapper from an already normalized prefix (see `normalize_prefix_path`).
+    pub fn new(handler: Arc<dyn super::FileSystemHandler>, prefix: String) -> Self {
+        Self { handler, prefix }
+    }
+    fn to_inner_path(&self, path: super::SegPath) -> super::FileSystemResult<OwnedSegPath> {
+        check_no_escape(path)?;
+        Ok(super::concat_path(&self.prefix, path))
+    }
+}
+
+impl super::FileSystemHandler for PrefixFsHandler {
+    fn create_file(
+        &self,
+        filename: super::SegPath,
+        desired_access: FileDesiredAccess,
+        file_attributes: FileAttributes,
+        share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<super::CreateFileInfo<'_>> {
+        let inner_filename = self.to_inner_path(filename)?;
+        let create_info = self.handler.create_file(
+            inner_filename.as_non_owned(),
+            desired_access,
+            file_attributes,
+            share_access,
+            create_disposition,
+            create_options,
+        )?;
+        let mut path = filename.get_path().to_owned();
+        super::make_uniform_path(&mut path);
+        Ok(super::CreateFileInfo {
+            context: Box::new(PrefixFsFile {
+                fs_handler: self,
+                file: create_info.context,
+                path: RwLock::new(path),
+            }),
+            is_dir: create_info.is_dir,
+            new_file_created: create_info.new_file_created,
+        })
+    }
+    fn get_fs_free_space(&self) -> super::FileSystemResult<super::FileSystemSpaceInfo> {
+        self.handler.get_fs_free_space()
+    }
+    fn get_fs_characteristics(&self) -> super::FileSystemResult<super::FileSystemCharacteristics> {
+        self.handler.get_fs_characteristics()
+    }
+}
+
+struct PrefixFsFile<'h> {
+    fs_handler: &'h PrefixFsHandler,
+    file: super::OwnedFile<'h>,
+    // NOTE: Relative to the prefix, always `\`-separated
+    path: RwLock<String>,
+}
+
+impl super::File for PrefixFsFile<'_> {
+    fn get_path(&self) -> Option<String> {
+        Some(format!("\\{}", self.path.read().unwrap()))
+    }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> super::FileSystemResult<u64> {
+        self.file.read_at(offset, buffer)
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> super::FileSystemResult<u64> {
+        self.file.write_at(offset, buffer, constrain_size)
+    }
+    fn flush_buffers(&self) -> super::FileSystemResult<()> {
+        self.file.flush_buffers()
+    }
+    fn get_stat(&self) -> super::FileSystemResult<super::FileStatInfo> {
+        self.file.get_stat()
+    }
+    fn set_end_of_file(&self, offset: u64) -> super::FileSystemResult<()> {
+        self.file.set_end_of_file(offset)
+    }
+    fn set_file_times(
+        &self,
+        creation_time: std::time::SystemTime,
+        last_access_time: std::time::SystemTime,
+        last_write_time: std::time::SystemTime,
+    ) -> super::FileSystemResult<()> {
+        self.file
+            .set_file_times(creation_time, last_access_time, last_write_time)
+    }
+    fn set_delete(&self, delete_on_close: bool) -> super::FileSystemResult<()> {
+        self.file.set_delete(delete_on_close)
+    }
+    fn move_to(
+        &self,
+        new_path: super::SegPath,
+        replace_if_exists: bool,
+    ) -> super::FileSystemResult<()> {
+        let inner_path = self.fs_handler.to_inner_path(new_path)?;
+        let mut path = self.path.write().unwrap();
+        self.file
+            .move_to(inner_path.as_non_owned(), replace_if_exists)?;
+        *path = new_path.get_path().to_owned();
+        super::make_uniform_path(&mut path);
+        Ok(())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn super::FilePattern,
+        filler: &mut dyn super::FindFilesDataFiller,
+    ) -> super::FileSystemResult<()> {
+        self.file.find_files_with_pattern(pattern, filler)
+    }
+    fn wide_find_files_with_pattern(
+        &self,
+        pattern: &dyn super::WideFilePattern,
+        filler: &mut dyn super::WideFindFilesDataFiller,
+    ) -> super::FileSystemResult<()> {
+        self.file.wide_find_files_with_pattern(pattern, filler)
+    }
+}
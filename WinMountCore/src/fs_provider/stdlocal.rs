@@ -0,0 +1,636 @@
+// stdlocal: Provides access to local filesystem through std::fs
+// NOTE: This is the portable counterpart of `local`, which relies on NT native APIs;
+//       semantics are kept as close to `local` as possible
+
+use std::{
+    fs::{File, Metadata, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::SystemTime,
+};
+
+use uuid::{uuid, Uuid};
+
+use super::{
+    FileAttributes, FileCreateDisposition, FileCreateOptions, FileDesiredAccess, FileShareAccess,
+    FileSystemError,
+};
+
+pub const STDLOCALFS_ID: Uuid = uuid!("1B1E3D87-7E66-416B-927D-0F22F6AA1DC1");
+
+fn io_error_to_fs_error(e: std::io::Error) -> FileSystemError {
+    match e.kind() {
+        ErrorKind::NotFound => FileSystemError::ObjectNameNotFound,
+        ErrorKind::AlreadyExists => FileSystemError::ObjectNameCollision,
+        ErrorKind::PermissionDenied => FileSystemError::AccessDenied,
+        ErrorKind::InvalidInput => FileSystemError::ObjectNameInvalid,
+        ErrorKind::UnexpectedEof => FileSystemError::EndOfFile,
+        _ => FileSystemError::Other(e.into()),
+    }
+}
+
+// NOTE: Segments are re-joined with the native separator; separators of either
+//       kind are rejected inside segments to prevent escaping via `..`
+fn to_native_path(path: super::SegPath) -> super::FileSystemResult<PathBuf> {
+    let mut native_path = String::with_capacity(path.get_path().len() + 1);
+    for (i, segment) in path.into_iter().enumerate() {
+        if segment == "." || segment == ".." || segment.contains(['/', '\\']) {
+            return Err(FileSystemError::ObjectNameInvalid);
+        }
+        if cfg!(windows) {
+            if i != 0 {
+                native_path.push('\\');
+            }
+            native_path.push_str(segment);
+            // Drive letters must be followed by a separator to become absolute
+            if i == 0 && segment.ends_with(':') {
+                native_path.push('\\');
+            }
+        } else {
+            native_path.push('/');
+            native_path.push_str(segment);
+        }
+    }
+    if native_path.is_empty() {
+        if cfg!(windows) {
+            // TODO: Handle root directory (with GetLogicalDrives)?
+            return Err(FileSystemError::ObjectNameInvalid);
+        }
+        native_path.push('/');
+    }
+    Ok(PathBuf::from(native_path))
+}
+
+fn is_name_hidden(path: &Path, metadata: &Metadata) -> bool {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        let _ = path;
+        (metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN) != 0
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = metadata;
+        path.file_name()
+            .map(|x| x.to_string_lossy().starts_with('.'))
+            .unwrap_or(false)
+    }
+}
+
+fn metadata_to_stat(path: &Path, metadata: &Metadata) -> super::FileStatInfo {
+    let mut attributes = FileAttributes::empty();
+    if metadata.permissions().readonly() {
+        attributes |= FileAttributes::Readonly;
+    }
+    if is_name_hidden(path, metadata) {
+        attributes |= FileAttributes::Hidden;
+    }
+    if metadata.is_dir() {
+        attributes |= FileAttributes::DirectoryFile;
+    }
+    #[cfg(unix)]
+    let index = {
+        use std::os::unix::fs::MetadataExt;
+        metadata.ino()
+    };
+    // NOTE: File index is not exposed by std on Windows, so we derive one from the path
+    #[cfg(not(unix))]
+    let index = crate::util::calculate_hash(&path);
+    let last_write_time = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    super::FileStatInfo {
+        index,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        is_dir: metadata.is_dir(),
+        attributes,
+        creation_time: metadata.created().unwrap_or(last_write_time),
+        last_access_time: metadata.accessed().unwrap_or(last_write_time),
+        last_write_time,
+    }
+}
+
+// Opens a directory so that its times can be changed
+fn open_dir_for_times(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+        const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+        options
+            .access_mode(FILE_WRITE_ATTRIBUTES)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS);
+    }
+    #[cfg(not(windows))]
+    options.read(true);
+    options.open(path)
+}
+
+struct StdLocalFsHandler {}
+
+impl super::FileSystemHandler for StdLocalFsHandler {
+    fn create_file(
+        &self,
+        filename: super::SegPath,
+        desired_access: FileDesiredAccess,
+        file_attributes: FileAttributes,
+        share_access: FileShareAccess,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> super::FileSystemResult<super::CreateFileInfo<'_>> {
+        use FileCreateDisposition::*;
+
+        // TODO: Honor share_access (std always shares everything on Windows)
+        let _ = share_access;
+
+        let path = to_native_path(filename)?;
+
+        let expects_dir = create_options.contains(FileCreateOptions::DirectoryFile);
+        let expects_nondir = create_options.contains(FileCreateOptions::NonDirectoryFile);
+        let delete_on_close = create_options.contains(FileCreateOptions::DeleteOnClose);
+        let can_read = desired_access.intersects(
+            FileDesiredAccess::Read | FileDesiredAccess::Execute | FileDesiredAccess::Full,
+        );
+        let can_write =
+            desired_access.intersects(FileDesiredAccess::Write | FileDesiredAccess::Full);
+
+        let open_file_fn = |create: bool, truncate: bool| {
+            let mut options = OpenOptions::new();
+            // NOTE: std requires write access for creation and truncation,
+            //       so we enforce the requested access by ourselves instead
+            let write = can_write || create || truncate;
+            options
+                .read(can_read || !write)
+                .write(write)
+                .truncate(truncate);
+            if create {
+                options.create_new(true);
+            }
+            options.open(&path).map_err(io_error_to_fs_error)
+        };
+
+        let (file, is_dir, new_file_created) = match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                if expects_nondir {
+                    return Err(FileSystemError::FileIsADirectory);
+                }
+                if let CreateNew = create_disposition {
+                    return Err(FileSystemError::ObjectNameCollision);
+                }
+                (None, true, false)
+            }
+            Ok(_) => {
+                if expects_dir {
+                    return Err(FileSystemError::NotADirectory);
+                }
+                let file = match create_disposition {
+                    CreateNew => return Err(FileSystemError::ObjectNameCollision),
+                    CreateAlways | TruncateExisting => open_file_fn(false, true)?,
+                    OpenExisting | OpenAlways => open_file_fn(false, false)?,
+                };
+                (Some(file), false, false)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let parent_exists = path
+                    .parent()
+                    .map(|x| x.as_os_str().is_empty() || x.is_dir())
+                    .unwrap_or(true);
+                if !parent_exists {
+                    return Err(FileSystemError::ObjectPathNotFound);
+                }
+                match create_disposition {
+                    OpenExisting | TruncateExisting => {
+                        return Err(FileSystemError::ObjectNameNotFound)
+                    }
+                    CreateNew | CreateAlways | OpenAlways => {
+                        if expects_dir {
+                            std::fs::create_dir(&path).map_err(io_error_to_fs_error)?;
+                            (None, true, true)
+                        } else {
+                            (Some(open_file_fn(true, false)?), false, true)
+                        }
+                    }
+                }
+            }
+            Err(e) => return Err(io_error_to_fs_error(e)),
+        };
+
+        if new_file_created && file_attributes.contains(FileAttributes::Readonly) {
+            // NOTE: Readonly is applied after creation so that the handle stays writable
+            if let Ok(metadata) = std::fs::metadata(&path) {
+                let mut permissions = metadata.permissions();
+                permissions.set_readonly(true);
+                let _ = std::fs::set_permissions(&path, permissions);
+            }
+        }
+
+        Ok(super::CreateFileInfo {
+            context: Box::new(StdLocalFsFile {
+                path: RwLock::new(path),
+                file,
+                can_write,
+                delete_on_close: AtomicBool::new(delete_on_close),
+            }),
+            is_dir,
+            new_file_created,
+        })
+    }
+    fn get_fs_free_space(&self) -> super::FileSystemResult<super::FileSystemSpaceInfo> {
+        Ok(super::FileSystemSpaceInfo {
+            bytes_count: 1024 * 1024 * 1024 * 1024,
+            free_bytes_count: 1024 * 1024 * 1024 * 1024 * 7 / 8,
+            available_bytes_count: 1024 * 1024 * 1024 * 1024 * 7 / 8,
+        })
+    }
+    fn get_fs_characteristics(&self) -> super::FileSystemResult<super::FileSystemCharacteristics> {
+        Ok(if cfg!(windows) {
+            super::FileSystemCharacteristics::empty()
+        } else {
+            super::FileSystemCharacteristics::CaseSensitive
+        })
+    }
+}
+
+struct StdLocalFsFile {
+    path: RwLock<PathBuf>,
+    // NOTE: Directories are not opened, only their paths are kept
+    file: Option<File>,
+    can_write: bool,
+    delete_on_close: AtomicBool,
+}
+
+impl StdLocalFsFile {
+    fn get_file(&self) -> super::FileSystemResult<&File> {
+        self.file.as_ref().ok_or(FileSystemError::FileIsADirectory)
+    }
+    fn get_writable_file(&self) -> super::FileSystemResult<&File> {
+        let file = self.get_file()?;
+        if !self.can_write {
+            return Err(FileSystemError::AccessDenied);
+        }
+        Ok(file)
+    }
+}
+
+#[cfg(unix)]
+fn file_read_at(file: &File, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+#[cfg(windows)]
+fn file_read_at(file: &File, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+#[cfg(unix)]
+fn file_write_at(file: &File, offset: u64, buffer: &[u8]) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buffer, offset)
+}
+#[cfg(windows)]
+fn file_write_at(file: &File, offset: u64, buffer: &[u8]) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buffer, offset)
+}
+
+impl super::File for StdLocalFsFile {
+    fn get_path(&self) -> Option<String> {
+        Some(self.path.read().unwrap().to_string_lossy().into_owned())
+    }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> super::FileSystemResult<u64> {
+        let file = self.get_file()?;
+        let mut total = 0;
+        // Positioned reads may return early, so keep reading until EOF
+        while total < buffer.len() {
+            match file_read_at(file, offset + total as u64, &mut buffer[total..]) {
+                Ok(0) => break,
+                Ok(count) => total += count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(io_error_to_fs_error(e)),
+            }
+        }
+        Ok(total as _)
+    }
+    fn write_at(
+        &self,
+        offset: Option<u64>,
+        buffer: &[u8],
+        constrain_size: bool,
+    ) -> super::FileSystemResult<u64> {
+        let file = self.get_writable_file()?;
+        let file_len = file.metadata().map_err(io_error_to_fs_error)?.len();
+        let offset = offset.unwrap_or(file_len);
+        let buffer = if constrain_size {
+            if offset >= file_len {
+                return Ok(0);
+            }
+            &buffer[..buffer.len().min((file_len - offset) as usize)]
+        } else {
+            buffer
+        };
+        let mut total = 0;
+        while total < buffer.len() {
+            match file_write_at(file, offset + total as u64, &buffer[total..]) {
+                Ok(0) => break,
+                Ok(count) => total += count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(io_error_to_fs_error(e)),
+            }
+        }
+        Ok(total as _)
+    }
+    fn flush_buffers(&self) -> super::FileSystemResult<()> {
+        match &self.file {
+            Some(file) => file.sync_data().map_err(io_error_to_fs_error),
+            None => Ok(()),
+        }
+    }
+    fn get_stat(&self) -> super::FileSystemResult<super::FileStatInfo> {
+        let path = self.path.read().unwrap();
+        let metadata = match &self.file {
+            Some(file) => file.metadata(),
+            None => std::fs::metadata(&*path),
+        }
+        .map_err(io_error_to_fs_error)?;
+        Ok(metadata_to_stat(&path, &metadata))
+    }
+    fn set_end_of_file(&self, offset: u64) -> super::FileSystemResult<()> {
+        let file = self.get_writable_file()?;
+        file.set_len(offset).map_err(io_error_to_fs_error)
+    }
+    fn set_file_times(
+        &self,
+        creation_time: SystemTime,
+        last_access_time: SystemTime,
+        last_write_time: SystemTime,
+    ) -> super::FileSystemResult<()> {
+        let zero_t: SystemTime = unsafe { std::mem::zeroed() };
+        let mut times = std::fs::FileTimes::new();
+        if last_access_time != zero_t {
+            times = times.set_accessed(last_access_time);
+        }
+        if last_write_time != zero_t {
+            times = times.set_modified(last_write_time);
+        }
+        #[cfg(windows)]
+        if creation_time != zero_t {
+            use std::os::windows::fs::FileTimesExt;
+            times = times.set_created(creation_time);
+        }
+        #[cfg(not(windows))]
+        let _ = creation_time;
+        match &self.file {
+            Some(file) => file.set_times(times),
+            None => open_dir_for_times(&self.path.read().unwrap()).and_then(|x| x.set_times(times)),
+        }
+        .map_err(io_error_to_fs_error)
+    }
+    fn set_delete(&self, delete_on_close: bool) -> super::FileSystemResult<()> {
+        if delete_on_close && self.file.is_none() {
+            let path = self.path.read().unwrap();
+            let mut it = std::fs::read_dir(&*path).map_err(io_error_to_fs_error)?;
+            if it.next().is_some() {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+        }
+        self.delete_on_close
+            .store(delete_on_close, Ordering::Relaxed);
+        Ok(())
+    }
+    fn move_to(
+        &self,
+        new_path: super::SegPath,
+        replace_if_exists: bool,
+    ) -> super::FileSystemResult<()> {
+        let new_path = to_native_path(new_path)?;
+        let mut path = self.path.write().unwrap();
+        match std::fs::symlink_metadata(&new_path) {
+            Ok(_) if !replace_if_exists => return Err(FileSystemError::ObjectNameCollision),
+            Ok(metadata) if metadata.is_dir() => return Err(FileSystemError::AccessDenied),
+            _ => (),
+        }
+        if !new_path.parent().map(|x| x.is_dir()).unwrap_or(true) {
+            return Err(FileSystemError::ObjectPathNotFound);
+        }
+        // TODO: Should we handle movement across different volumes?
+        std::fs::rename(&*path, &new_path).map_err(io_error_to_fs_error)?;
+        *path = new_path;
+        Ok(())
+    }
+    fn find_files_with_pattern(
+        &self,
+        pattern: &dyn super::FilePattern,
+        filler: &mut dyn super::FindFilesDataFiller,
+    ) -> super::FileSystemResult<()> {
+        if self.file.is_some() {
+            return Err(FileSystemError::NotADirectory);
+        }
+        let path = self.path.read().unwrap();
+        for entry in std::fs::read_dir(&*path).map_err(io_error_to_fs_error)? {
+            let entry = entry.map_err(io_error_to_fs_error)?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !pattern.check_name(&name) {
+                continue;
+            }
+            // NOTE: Entries may vanish while listing, so skip them silently
+            let Ok(metadata) = std::fs::metadata(entry.path()).or_else(|_| entry.metadata()) else {
+                continue;
+            };
+            let stat = metadata_to_stat(&entry.path(), &metadata);
+            if filler.fill_data(&name, &stat).is_err() {
+                log::warn!("Failed to fill object data");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StdLocalFsFile {
+    fn drop(&mut self) {
+        if !*self.delete_on_close.get_mut() {
+            return;
+        }
+        // Close the file before deleting it
+        let is_dir = self.file.take().is_none();
+        let path = self.path.get_mut().unwrap();
+        let result = if is_dir {
+            std::fs::remove_dir(&*path)
+        } else {
+            std::fs::remove_file(&*path)
+        };
+        if let Err(e) = result {
+            log::warn!(
+                "stdlocal: failed to delete `{}` on close: {e}",
+                path.display()
+            );
+        }
+    }
+}
+
+impl StdLocalFsHandler {
+    fn new() -> Self {
+        StdLocalFsHandler {}
+    }
+}
+
+pub struct StdLocalFsProvider {}
+impl super::FsProvider for StdLocalFsProvider {
+    fn get_id(&self) -> Uuid {
+        STDLOCALFS_ID
+    }
+    fn get_name(&self) -> &'static str {
+        "local (std)"
+    }
+    fn get_version(&self) -> (u32, u32, u32) {
+        (0, 1, 0)
+    }
+    fn construct(
+        &self,
+        _config: serde_json::Value,
+        _ctx: &mut dyn super::FileSystemCreationContext,
+    ) -> Result<Arc<dyn super::FileSystemHandler>, super::FileSystemCreationError> {
+        Ok(Arc::new(StdLocalFsHandler::new()))
+    }
+    fn get_template_config(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+}
+
+impl StdLocalFsProvider {
+    pub fn new() -> Self {
+        StdLocalFsProvider {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_provider::{
+        AcceptAllFilePattern, CreateFileInfo, FileStatInfo, FileSystemCreationContext,
+        FileSystemCreationError, FileSystemHandler, FileSystemResult, FindFilesDataFiller,
+        FsProvider, PathDelimiter, SegPath,
+    };
+
+    const DELIMITER: PathDelimiter = if cfg!(windows) {
+        PathDelimiter::BackSlash
+    } else {
+        PathDelimiter::Slash
+    };
+
+    struct NoFsCtx;
+
+    impl FileSystemCreationContext for NoFsCtx {
+        fn get_or_run_fs(
+            &mut self,
+            _: &Uuid,
+            _: &str,
+        ) -> Result<Arc<dyn FileSystemHandler>, FileSystemCreationError> {
+            Err(FileSystemCreationError::NotFound)
+        }
+    }
+
+    struct Collect(Vec<(String, bool)>);
+
+    impl FindFilesDataFiller for Collect {
+        fn fill_data(&mut self, name: &str, stat: &FileStatInfo) -> Result<(), ()> {
+            self.0.push((name.to_owned(), stat.is_dir));
+            Ok(())
+        }
+    }
+
+    fn open<'h>(
+        handler: &'h dyn FileSystemHandler,
+        path: &Path,
+        create_disposition: FileCreateDisposition,
+        create_options: FileCreateOptions,
+    ) -> FileSystemResult<CreateFileInfo<'h>> {
+        handler.create_file(
+            SegPath::new(path.to_str().unwrap(), DELIMITER),
+            FileDesiredAccess::ReadWrite,
+            FileAttributes::empty(),
+            FileShareAccess::all(),
+            create_disposition,
+            create_options,
+        )
+    }
+
+    #[test]
+    fn opens_reads_and_lists_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"hello").unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let handler = StdLocalFsProvider::new()
+            .construct(serde_json::Value::Null, &mut NoFsCtx)
+            .unwrap();
+        let handler = &*handler;
+
+        let file = open(
+            handler,
+            &dir.path().join("a.txt"),
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::empty(),
+        )
+        .unwrap();
+        assert!(!file.is_dir && !file.new_file_created);
+        let stat = file.context.get_stat().unwrap();
+        assert_eq!(stat.size, 5);
+        assert!(!stat.is_dir);
+        let mut buffer = [0; 16];
+        assert_eq!(file.context.read_at(1, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer[..4], b"ello");
+        assert!(matches!(
+            open(
+                handler,
+                &dir.path().join("a.txt"),
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::DirectoryFile,
+            ),
+            Err(FileSystemError::NotADirectory)
+        ));
+
+        let file = open(
+            handler,
+            &dir.path().join("b.txt"),
+            FileCreateDisposition::CreateNew,
+            FileCreateOptions::NonDirectoryFile,
+        )
+        .unwrap();
+        assert!(file.new_file_created);
+        assert_eq!(file.context.write_at(Some(0), b"world", false).unwrap(), 5);
+        drop(file);
+        assert_eq!(std::fs::read(dir.path().join("b.txt")).unwrap(), b"world");
+        assert!(matches!(
+            open(
+                handler,
+                &dir.path().join("missing.txt"),
+                FileCreateDisposition::OpenExisting,
+                FileCreateOptions::empty(),
+            ),
+            Err(FileSystemError::ObjectNameNotFound)
+        ));
+
+        let folder = open(
+            handler,
+            dir.path(),
+            FileCreateDisposition::OpenExisting,
+            FileCreateOptions::DirectoryFile,
+        )
+        .unwrap();
+        assert!(folder.is_dir);
+        let mut entries = Collect(Vec::new());
+        folder
+            .context
+            .find_files_with_pattern(&AcceptAllFilePattern::new(), &mut entries)
+            .unwrap();
+        entries.0.sort();
+        assert_eq!(
+            entries.0,
+            [
+                ("a.txt".to_owned(), false),
+                ("b.txt".to_owned(), false),
+                ("sub".to_owned(), true)
+            ]
+        );
+    }
+}
@@ -47,6 +47,7 @@ fn fs_error_to_ntstatus(err: FileSystemError) -> NTSTATUS {
         FileSystemError::FileCorruptError => STATUS_FILE_CORRUPT_ERROR,
         FileSystemError::EndOfFile => STATUS_END_OF_FILE,
         FileSystemError::SharingViolation => STATUS_SHARING_VIOLATION,
+        FileSystemError::WrongPassword => STATUS_WRONG_PASSWORD,
         FileSystemError::Other(e) => {
             log::warn!("Unknown FileSystemError: {e}");
             STATUS_INTERNAL_ERROR
@@ -13,7 +13,7 @@ const DEFAULT_DAEMON_PORT: u16 = 19423;
 use std::{
     collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, Weak},
 };
 
 use anyhow::Context;
@@ -68,6 +68,8 @@ struct FSInfo {
     name: String,
     kind_id: Uuid,
     handler: Option<Arc<dyn fs_provider::FileSystemHandler>>,
+    // Subtree wrappers shared among dependents, keyed by normalized prefix path
+    prefixed_handlers: HashMap<String, Weak<dyn fs_provider::FileSystemHandler>>,
     is_global: bool,
     config: serde_json::Value,
 }
@@ -77,6 +79,7 @@ impl FSInfo {
             name,
             kind_id,
             handler: None,
+            prefixed_handlers: HashMap::new(),
             config: serde_json::Value::Null,
             is_global,
         }
@@ -200,6 +203,7 @@ impl AppContext {
             name,
             kind_id,
             handler: None,
+            prefixed_handlers: HashMap::new(),
             is_global: true,
             config,
         };
@@ -222,6 +226,7 @@ impl AppContext {
             name,
             kind_id,
             handler: None,
+            prefixed_handlers: HashMap::new(),
             is_global: false,
             config,
         };
@@ -273,6 +278,7 @@ impl AppContext {
                 anyhow::bail!("filesystem is still being used by other components");
             }
             fs_info.handler = None;
+            fs_info.prefixed_handlers.clear();
             true
         } else {
             false
@@ -536,14 +542,11 @@ impl FileSystemCreationContext for AppContextForCreation<'_> {
         id: &Uuid,
         prefix_path: &str,
     ) -> Result<Arc<dyn fs_provider::FileSystemHandler>, fs_provider::FileSystemCreationError> {
-        // TODO: prefix_path wrapper
         use fs_provider::FileSystemCreationError;
+        let prefix_path = fs_provider::prefixfs::normalize_prefix_path(prefix_path)?;
         if !self.creation_dep_path.insert(*id) {
             return Err(FileSystemCreationError::CyclicDependency);
         }
-        if !prefix_path.is_empty() {
-            todo!("implement prefix_path wrapper");
-        }
         let mut this = scopeguard::guard(self, |this| {
             this.creation_dep_path.remove(id);
         });
@@ -570,7 +573,27 @@ impl FileSystemCreationContext for AppContextForCreation<'_> {
             fs_info.handler = Some(Arc::clone(&fs_handler));
             fs_handler
         };
-        Ok(fs_handler)
+        if prefix_path.is_empty() {
+            return Ok(fs_handler);
+        }
+        // Share the wrapper among dependents requesting the same subtree
+        fs_info
+            .prefixed_handlers
+            .retain(|_, v| v.strong_count() > 0);
+        if let Some(h) = fs_info
+            .prefixed_handlers
+            .get(&prefix_path)
+            .and_then(Weak::upgrade)
+        {
+            return Ok(h);
+        }
+        let prefixed_handler: Arc<dyn fs_provider::FileSystemHandler> = Arc::new(
+            fs_provider::prefixfs::PrefixFsHandler::new(fs_handler, prefix_path.clone()),
+        );
+        fs_info
+            .prefixed_handlers
+            .insert(prefix_path, Arc::downgrade(&prefixed_handler));
+        Ok(prefixed_handler)
     }
 }
 
@@ -584,11 +607,42 @@ async fn shutdown_signal(shutdown_notify: &tokio::sync::Notify) {
     }
 }
 
-const HIDDEN_FS_PROVIDER_LIST: &[Uuid] = &[fs_provider::local::LOCALFS_ID];
+const HIDDEN_FS_PROVIDER_LIST: &[Uuid] = &[
+    fs_provider::local::LOCALFS_ID,
+    fs_provider::stdlocal::STDLOCALFS_ID,
+];
 
 const GLOBAL_FS_LOCALFS_ID: Uuid = uuid!("96DD6C88-CDB5-4446-8269-104F2DD82ACD");
 
-async fn run_loop(mut app_ctx: AppContext, server_addr: SocketAddr) -> anyhow::Result<()> {
+/// The provider backing the global "local" filesystem.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum LocalFsBackend {
+    /// NT native APIs (Windows only)
+    Native,
+    /// Portable std::fs implementation
+    Std,
+}
+impl LocalFsBackend {
+    fn platform_default() -> Self {
+        if cfg!(windows) {
+            Self::Native
+        } else {
+            Self::Std
+        }
+    }
+    fn provider_id(&self) -> Uuid {
+        match self {
+            Self::Native => fs_provider::local::LOCALFS_ID,
+            Self::Std => fs_provider::stdlocal::STDLOCALFS_ID,
+        }
+    }
+}
+
+async fn run_loop(
+    mut app_ctx: AppContext,
+    server_addr: SocketAddr,
+    local_fs_backend: LocalFsBackend,
+) -> anyhow::Result<()> {
     log::warn!("Starting WinMountCore daemon...");
 
     // Create global filesystems
@@ -600,7 +654,7 @@ async fn run_loop(mut app_ctx: AppContext, server_addr: SocketAddr) -> anyhow::R
     create_global_fs_and_run_fn(
         GLOBAL_FS_LOCALFS_ID,
         "local".to_owned(),
-        fs_provider::local::LOCALFS_ID,
+        local_fs_backend.provider_id(),
         serde_json::Value::Null,
     )?;
 
@@ -645,6 +699,9 @@ enum AppCommands {
         /// Level of verbosity
         #[arg(short, long, action = clap::ArgAction::Count)]
         verbose: u8,
+        /// The provider backing the global local filesystem
+        #[arg(long, value_enum, default_value_t = LocalFsBackend::platform_default())]
+        local_fs: LocalFsBackend,
     },
     /// Stops the running background service
     StopDaemon {},
@@ -735,7 +792,11 @@ fn main() -> anyhow::Result<()> {
     let socket_addr = SocketAddr::new(socket_addr, daemon_port);
 
     match cli.command {
-        AppCommands::Daemon { config, verbose } => {
+        AppCommands::Daemon {
+            config,
+            verbose,
+            local_fs,
+        } => {
             // init_log(3)?;
             init_log(verbose.into())?;
 
@@ -776,7 +837,7 @@ fn main() -> anyhow::Result<()> {
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()?;
-            rt.block_on(run_loop(app_ctx, socket_addr))?;
+            rt.block_on(run_loop(app_ctx, socket_addr, local_fs))?;
         }
         _ => {
             let rt = tokio::runtime::Builder::new_current_thread()
@@ -65,7 +65,8 @@ impl CaselessStr {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
 pub struct CaselessString(String);
 impl PartialEq for CaselessString {
     fn eq(&self, other: &Self) -> bool {
@@ -157,3 +158,39 @@ pub fn calculate_hash(v: &impl std::hash::Hash) -> u64 {
     v.hash(&mut hasher);
     hasher.finish()
 }
+
+// Anonymous temporary file for data too large to keep in memory, which is removed
+// once dropped
+pub struct TempFile(std::fs::File);
+impl TempFile {
+    pub fn new() -> std::io::Result<Self> {
+        tempfile::tempfile().map(Self)
+    }
+    #[cfg(unix)]
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.0, buffer, offset)
+    }
+    #[cfg(windows)]
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.0, buffer, offset)
+    }
+    #[cfg(unix)]
+    pub fn write_all_at(&self, offset: u64, buffer: &[u8]) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(&self.0, buffer, offset)
+    }
+    #[cfg(windows)]
+    pub fn write_all_at(&self, mut offset: u64, mut buffer: &[u8]) -> std::io::Result<()> {
+        while !buffer.is_empty() {
+            let len = std::os::windows::fs::FileExt::seek_write(&self.0, buffer, offset)?;
+            if len == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            buffer = &buffer[len..];
+            offset += len as u64;
+        }
+        Ok(())
+    }
+    pub fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.0.set_len(len)
+    }
+}
@@ -130,6 +130,11 @@ struct FileSystemWithChildrenFileStatData {
     last_access_time: SystemTime,
     last_write_time: SystemTime,
 }
+#[derive(Serialize)]
+struct FileSystemWithChildrenIntegrityIssueData {
+    path: String,
+    msg: String,
+}
 impl FileSystemWithChildren {
     fn new(handler: Arc<dyn crate::fs_provider::FileSystemHandler>) -> Self {
         Self {
@@ -290,6 +295,43 @@ impl FileSystemWithChildren {
         )?;
         Ok(files_list)
     }
+    // Takes the file out, along with the handler it borrows from
+    fn take_file(
+        &mut self,
+        id: u64,
+    ) -> Result<
+        (
+            Box<dyn crate::fs_provider::File>,
+            Arc<dyn crate::fs_provider::FileSystemHandler>,
+        ),
+        FileSystemError,
+    > {
+        let file = self
+            .files
+            .remove(&id)
+            .ok_or(FileSystemError::InvalidParameter)?;
+        let file = unsafe { Box::from_raw(file) };
+        Ok((file, Arc::clone(&self.handler)))
+    }
+    fn put_file(&mut self, id: u64, file: Box<dyn crate::fs_provider::File>) {
+        if let Some(file) = self.files.insert(id, Box::into_raw(file)) {
+            unsafe {
+                Self::drop_file_ptr(file);
+            }
+        }
+    }
+    fn get_file_warnings(
+        &self,
+        id: u64,
+    ) -> Result<Vec<crate::fs_provider::ContainerWarning>, FileSystemError> {
+        let file = unsafe {
+            &**self
+                .files
+                .get(&id)
+                .ok_or(FileSystemError::InvalidParameter)?
+        };
+        file.get_container_warnings()
+    }
     // fn list_files_with_pattern(&self, id: u64, pattern: &str) -> Result<Vec<(String, FileSystemWithChildrenFileStatData)>, FileSystemError> {
     //     let file = unsafe {
     //         &**self
@@ -406,6 +448,45 @@ impl<'a> WsFileSystemContext<'a> {
             .ok_or(FileSystemError::InvalidParameter)?;
         fs.list_files(id)
     }
+    async fn verify_file(
+        &mut self,
+        fs_id: Uuid,
+        id: u64,
+    ) -> Result<Vec<FileSystemWithChildrenIntegrityIssueData>, FileSystemError> {
+        let fs = self
+            .fs
+            .get_mut(&fs_id)
+            .ok_or(FileSystemError::InvalidParameter)?;
+        let (file, handler) = fs.take_file(id)?;
+        // NOTE: Verification reads everything through, so it runs off the async
+        //       workers. Should the request be dropped meanwhile, the file is
+        //       dropped there, before the handler it borrows from.
+        let (file, _handler, result) = tokio::task::spawn_blocking(move || {
+            let result = file.verify_integrity();
+            (file, handler, result)
+        })
+        .await
+        .map_err(|e| FileSystemError::Other(e.into()))?;
+        fs.put_file(id, file);
+        Ok(result?
+            .into_iter()
+            .map(|x| FileSystemWithChildrenIntegrityIssueData {
+                path: x.path,
+                msg: x.error.to_string(),
+            })
+            .collect())
+    }
+    fn get_file_warnings(
+        &self,
+        fs_id: Uuid,
+        id: u64,
+    ) -> Result<Vec<crate::fs_provider::ContainerWarning>, FileSystemError> {
+        let fs = self
+            .fs
+            .get(&fs_id)
+            .ok_or(FileSystemError::InvalidParameter)?;
+        fs.get_file_warnings(id)
+    }
 }
 
 async fn handle_websocket_bin_request<'b>(
@@ -664,6 +745,29 @@ async fn handle_websocket_request(
             let stat_info = fs_ctx.list_files(params.fs_id, params.id)?;
             serde_json::json!(stat_info)
         }
+        // Verifies the whole container (e.g. archive) the file lives in
+        "verify-fs-file" => {
+            #[derive(Deserialize)]
+            struct Params {
+                fs_id: Uuid,
+                id: u64,
+            }
+            let params: Params = serde_json::from_value(params)?;
+            let issues = fs_ctx.verify_file(params.fs_id, params.id).await?;
+            serde_json::json!({ "issues": issues })
+        }
+        // Lists problems found while loading the container (e.g. archive) the file
+        // lives in, such as renamed entries
+        "get-fs-file-warnings" => {
+            #[derive(Deserialize)]
+            struct Params {
+                fs_id: Uuid,
+                id: u64,
+            }
+            let params: Params = serde_json::from_value(params)?;
+            let warnings = fs_ctx.get_file_warnings(params.fs_id, params.id)?;
+            serde_json::json!({ "warnings": warnings })
+        }
         // ----- END Filesystem operations -----
         _ => {
             log::warn!(